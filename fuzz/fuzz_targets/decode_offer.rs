@@ -0,0 +1,20 @@
+//! Offers are sent to us by whoever wants to take our proposal.
+#![no_main]
+use chacha20::{cipher::NewCipher, ChaCha20};
+use gun_wallet::betting::*;
+use libfuzzer_sys::fuzz_target;
+use std::str::FromStr;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(string) = std::str::from_utf8(data) {
+        if let Ok(ciphertext) = Ciphertext::from_str(string) {
+            let mut cipher = ChaCha20::new(&[1u8; 32].into(), &[0u8; 12].into());
+            let _ = ciphertext.decrypt(&mut cipher);
+        }
+    }
+    if let Ok((offer, message)) = compact::decode_offer(data) {
+        let reencoded = compact::encode_offer(&offer, message.as_deref());
+        assert_eq!(compact::decode_offer(&reencoded).unwrap(), (offer, message));
+    }
+    let _ = gun_wallet::encode::deserialize::<Plaintext>(data);
+});
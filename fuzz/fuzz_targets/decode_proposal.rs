@@ -0,0 +1,17 @@
+//! Proposals are posted publicly so anyone can hand us one.
+#![no_main]
+use gun_wallet::{betting::*, encode};
+use libfuzzer_sys::fuzz_target;
+use std::str::FromStr;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(string) = std::str::from_utf8(data) {
+        if let Ok(proposal) = VersionedProposal::from_str(string) {
+            // anything we accept we must be able to write back out the same
+            let reencoded = proposal.to_string();
+            assert_eq!(VersionedProposal::from_str(&reencoded).unwrap(), proposal);
+        }
+        let _ = encode::assemble_chunks(string);
+    }
+    let _ = compact::decode_payload(data);
+});
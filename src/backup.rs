@@ -0,0 +1,170 @@
+use crate::{config::VersionedConfig, secret::Secret};
+use anyhow::anyhow;
+use bdk::bitcoin::hashes::{sha256, sha512, Hash, HashEngine, Hmac, HmacEngine};
+use chacha20::{cipher::*, ChaCha20};
+use rand::RngCore;
+use subtle::ConstantTimeEq;
+use zeroize::{Zeroize, Zeroizing};
+
+const MAGIC: &[u8; 10] = b"gun-backup";
+const KDF_ROUNDS: u32 = 100_000;
+
+/// Everything needed to rebuild a wallet directory.
+///
+/// The BDK wallet tree is left out on purpose since it is just a cache of the blockchain that gets
+/// rebuilt on the next sync.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Backup {
+    pub config: VersionedConfig,
+    /// The raw key-value pairs of the gun database tree (descriptors, protocol secrets, bets, oracles).
+    pub gun_tree: Vec<(Vec<u8>, Vec<u8>)>,
+    /// The contents of seed.txt if the wallet has one.
+    pub seed_words: Option<String>,
+}
+
+/// Leaves out the database (it has the protocol secrets) and the seed words.
+impl core::fmt::Debug for Backup {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Backup")
+            .field("config", &self.config)
+            .field("gun_tree_entries", &self.gun_tree.len())
+            .field("has_seed_words", &self.seed_words.is_some())
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case", tag = "version")]
+pub enum VersionedBackup {
+    #[serde(rename = "1")]
+    V1(Backup),
+}
+
+impl From<VersionedBackup> for Backup {
+    fn from(from: VersionedBackup) -> Self {
+        match from {
+            VersionedBackup::V1(backup) => backup,
+        }
+    }
+}
+
+impl Backup {
+    pub fn into_versioned(self) -> VersionedBackup {
+        VersionedBackup::V1(self)
+    }
+}
+
+/// PBKDF2-HMAC-SHA512 with a single output block.
+fn derive_keys(passphrase: &str, salt: &[u8]) -> (Secret<[u8; 32]>, Secret<[u8; 32]>) {
+    let mut engine = HmacEngine::<sha512::Hash>::new(passphrase.as_bytes());
+    engine.input(salt);
+    engine.input(&1u32.to_be_bytes());
+    let mut u = Zeroizing::new(Hmac::<sha512::Hash>::from_engine(engine).into_inner());
+    let mut output = Zeroizing::new(*u);
+    for _ in 1..KDF_ROUNDS {
+        let mut engine = HmacEngine::<sha512::Hash>::new(passphrase.as_bytes());
+        engine.input(&u[..]);
+        let mut next = Hmac::<sha512::Hash>::from_engine(engine).into_inner();
+        u.copy_from_slice(&next);
+        next.zeroize();
+        for (o, u) in output.iter_mut().zip(u.iter()) {
+            *o ^= u;
+        }
+    }
+    let mut cipher_key = Secret::new([0u8; 32]);
+    let mut mac_key = Secret::new([0u8; 32]);
+    cipher_key
+        .expose_secret_mut()
+        .copy_from_slice(&output[..32]);
+    mac_key.expose_secret_mut().copy_from_slice(&output[32..]);
+    (cipher_key, mac_key)
+}
+
+pub(crate) fn mac(mac_key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut engine = HmacEngine::<sha256::Hash>::new(mac_key);
+    engine.input(data);
+    Hmac::<sha256::Hash>::from_engine(engine).into_inner()
+}
+
+/// Encrypts a backup under a passphrase.
+///
+/// Format: `gun-backup || salt (16) || nonce (12) || ciphertext || hmac-sha256 (32)`.
+pub fn encrypt(backup: Backup, passphrase: &str) -> Vec<u8> {
+    let mut salt = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let (cipher_key, mac_key) = derive_keys(passphrase, &salt);
+    let mut plaintext = Zeroizing::new(serde_json::to_vec(&backup.into_versioned()).unwrap());
+    let mut cipher = ChaCha20::new(&(*cipher_key.expose_secret()).into(), &nonce.into());
+    cipher.apply_keystream(&mut plaintext);
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&salt);
+    bytes.extend_from_slice(&nonce);
+    bytes.extend_from_slice(&plaintext);
+    let tag = mac(mac_key.expose_secret(), &bytes);
+    bytes.extend_from_slice(&tag);
+    bytes
+}
+
+pub fn decrypt(bytes: &[u8], passphrase: &str) -> anyhow::Result<Backup> {
+    let header_len = MAGIC.len() + 16 + 12;
+    if bytes.len() < header_len + 32 || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(anyhow!("this is not a gun backup file"));
+    }
+    let (body, tag) = bytes.split_at(bytes.len() - 32);
+    let salt = &body[MAGIC.len()..MAGIC.len() + 16];
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&body[MAGIC.len() + 16..header_len]);
+    let (cipher_key, mac_key) = derive_keys(passphrase, salt);
+
+    if !bool::from(mac(mac_key.expose_secret(), body)[..].ct_eq(&tag[..])) {
+        return Err(anyhow!(
+            "wrong passphrase or the backup file has been corrupted"
+        ));
+    }
+
+    let mut plaintext = Zeroizing::new(body[header_len..].to_vec());
+    let mut cipher = ChaCha20::new(&(*cipher_key.expose_secret()).into(), &nonce.into());
+    cipher.apply_keystream(&mut plaintext);
+    let backup = serde_json::from_slice::<VersionedBackup>(&plaintext)
+        .map_err(|e| anyhow!("backup was decrypted but could not be decoded: {}", e))?;
+    Ok(backup.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Config;
+    use bdk::bitcoin::Network;
+
+    fn test_backup() -> Backup {
+        Backup {
+            config: Config::default_config(Network::Regtest).into_versioned(),
+            gun_tree: vec![(vec![1, 2, 3], b"{\"hello\":1}".to_vec())],
+            seed_words: Some("abandon abandon about".into()),
+        }
+    }
+
+    #[test]
+    fn encrypt_decrypt_roundtrip() {
+        let encrypted = encrypt(test_backup(), "hunter2");
+        let decrypted = decrypt(&encrypted, "hunter2").unwrap();
+        assert_eq!(decrypted.gun_tree, test_backup().gun_tree);
+        assert_eq!(decrypted.seed_words, test_backup().seed_words);
+    }
+
+    #[test]
+    fn wrong_passphrase_fails() {
+        let encrypted = encrypt(test_backup(), "hunter2");
+        assert!(decrypt(&encrypted, "hunter3").is_err());
+    }
+
+    #[test]
+    fn debug_leaves_out_secrets() {
+        let debug = format!("{:?}", test_backup());
+        assert!(!debug.contains("abandon"), "{}", debug);
+        assert!(!debug.contains("hello"), "{}", debug);
+    }
+}
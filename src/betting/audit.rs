@@ -0,0 +1,491 @@
+//! Audit trails for finished bets (`gun audit`).
+//!
+//! An [`AuditBundle`] has everything needed to check a bet was settled correctly without trusting
+//! either wallet: the oracle's signed announcement and attestation, the public keys the bet output
+//! was made from and the bet and claim transactions along with the outputs they spend. It never has
+//! any secret keys in it so it can be handed to the counterparty or whoever is resolving a dispute.
+//!
+//! The bet keys in it are the randomized ones from the [`JointOutput`]. The keys in the original
+//! proposal and offer aren't kept once the bet has been made.
+use crate::{betting::*, OracleInfo};
+use anyhow::{anyhow, Context};
+use bdk::bitcoin::{
+    self,
+    blockdata::script::Instruction,
+    hashes::Hash,
+    secp256k1::{self, ecdsa, schnorr, Message, Secp256k1, Verification, XOnlyPublicKey},
+    util::sighash::{Prevouts, SigHashCache},
+    Amount, EcdsaSigHashType, Network, OutPoint, PubkeyHash, SchnorrSigHashType, Script,
+    Transaction, TxOut, WPubkeyHash,
+};
+use olivia_core::{Outcome, RawAnnouncement};
+use olivia_secp256k1::{
+    fun::{g, Scalar, G},
+    Secp256k1 as OliviaSecp256k1,
+};
+
+/// One side of the bet.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AuditParty {
+    /// What they put into the bet output
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub value: Amount,
+    /// The coins they spent in the bet transaction
+    pub inputs: Vec<OutPoint>,
+    /// Whether they bet on the second outcome
+    pub choose_right: bool,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AuditBundle {
+    pub network: Network,
+    pub oracle_info: OracleInfo,
+    pub oracle_event: OracleEvent,
+    /// The announcement as the oracle signed it. Missing if the wallet didn't have it cached.
+    #[serde(default)]
+    pub announcement: Option<RawAnnouncement<OliviaSecp256k1>>,
+    pub attestation: Attestation,
+    pub proposer: AuditParty,
+    pub offerer: AuditParty,
+    /// Whether the wallet that made the bundle was the proposer
+    pub we_proposed: bool,
+    pub joint_output: PublicJointOutput,
+    pub bet_tx: Transaction,
+    /// The outputs the bet tx spends in input order
+    pub bet_prevouts: Vec<TxOut>,
+    pub vout: u32,
+    /// The tx that spent the bet output (if the wallet knows it)
+    #[serde(default)]
+    pub claim_tx: Option<Transaction>,
+    /// The outputs the claim tx spends in input order
+    #[serde(default)]
+    pub claim_prevouts: Vec<TxOut>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case", tag = "version")]
+pub enum VersionedAuditBundle {
+    #[serde(rename = "1")]
+    V1(AuditBundle),
+}
+
+impl From<VersionedAuditBundle> for AuditBundle {
+    fn from(from: VersionedAuditBundle) -> Self {
+        match from {
+            VersionedAuditBundle::V1(bundle) => bundle,
+        }
+    }
+}
+
+/// What [`AuditBundle::verify`] found.
+#[derive(Clone, Debug)]
+pub struct AuditReport {
+    pub outcome: Outcome,
+    /// Whether the proposer won
+    pub proposer_won: bool,
+    /// A description of each check that passed in the order they were done
+    pub checks: Vec<String>,
+}
+
+impl AuditBundle {
+    pub fn into_versioned(self) -> VersionedAuditBundle {
+        VersionedAuditBundle::V1(self)
+    }
+
+    /// Check every signature in the bundle and that the attested outcome lets the winner (and only
+    /// the winner) spend the bet output. Errors on the first check that fails.
+    pub fn verify(&self) -> anyhow::Result<AuditReport> {
+        let mut checks = vec![];
+        let event_id = &self.oracle_event.event.id;
+        let oracle_keys = &self.oracle_info.oracle_keys;
+
+        match &self.announcement {
+            Some(announcement) => {
+                let announced = announcement
+                    .verify_against_id(event_id, &oracle_keys.announcement)
+                    .ok_or(anyhow!(
+                        "the oracle's signature on the announcement is invalid"
+                    ))?;
+                if serde_json::to_value(&announced)? != serde_json::to_value(&self.oracle_event)? {
+                    return Err(anyhow!(
+                        "the bet's event isn't the one the oracle announced"
+                    ));
+                }
+                checks.push(format!(
+                    "{} signed the announcement of {}",
+                    self.oracle_info.id, event_id
+                ));
+            }
+            None => checks.push("the announcement isn't in the bundle so wasn't checked".into()),
+        }
+
+        let (outcome, proposer_won) = winning_side(
+            &self.oracle_info,
+            &self.oracle_event,
+            &self.attestation,
+            &self.joint_output,
+            self.proposer.choose_right,
+        )?;
+        checks.push(format!(
+            "{} attested that {}",
+            self.oracle_info.id,
+            olivia_describe::outcome(&outcome).positive
+        ));
+        if self.proposer.choose_right == self.offerer.choose_right {
+            return Err(anyhow!("both sides bet on the same outcome"));
+        }
+        checks.push(format!(
+            "the attestation completes the {}'s key and not the other's",
+            if proposer_won { "proposer" } else { "offerer" }
+        ));
+
+        let bet_txid = self.bet_tx.txid();
+        let bet_output = self
+            .bet_tx
+            .output
+            .get(self.vout as usize)
+            .ok_or(anyhow!("the bet tx has no output {}", self.vout))?;
+        if bet_output.script_pubkey != self.joint_output.descriptor().script_pubkey() {
+            return Err(anyhow!(
+                "the bet output's script isn't made from the bet keys"
+            ));
+        }
+        let stakes = self.proposer.value + self.offerer.value;
+        if bet_output.value != stakes.as_sat() {
+            return Err(anyhow!(
+                "the bet output is worth {} but the stakes add up to {}",
+                Amount::from_sat(bet_output.value),
+                stakes
+            ));
+        }
+        let mut inputs = self
+            .bet_tx
+            .input
+            .iter()
+            .map(|txin| txin.previous_output)
+            .collect::<Vec<_>>();
+        let mut party_inputs = [&self.proposer.inputs[..], &self.offerer.inputs[..]].concat();
+        inputs.sort();
+        party_inputs.sort();
+        if inputs != party_inputs {
+            return Err(anyhow!(
+                "the bet tx doesn't spend exactly the proposer's and offerer's inputs"
+            ));
+        }
+        verify_signatures(&self.bet_tx, &self.bet_prevouts).context("checking the bet tx")?;
+        checks.push(format!(
+            "bet tx {} is signed by both sides and pays {} to the bet output",
+            bet_txid, stakes
+        ));
+
+        match &self.claim_tx {
+            Some(claim_tx) => {
+                let bet_outpoint = OutPoint::new(bet_txid, self.vout);
+                let vin = claim_tx
+                    .input
+                    .iter()
+                    .position(|txin| txin.previous_output == bet_outpoint)
+                    .ok_or(anyhow!("the claim tx doesn't spend the bet output"))?;
+                if self.claim_prevouts.get(vin) != Some(bet_output) {
+                    return Err(anyhow!("the claim tx's prevouts don't match the bet tx"));
+                }
+                let signers = verify_signatures(claim_tx, &self.claim_prevouts)
+                    .context("checking the claim tx")?;
+                let winner_key = secp256k1::PublicKey::from(
+                    self.joint_output.output_keys[if proposer_won { 0 } else { 1 }],
+                );
+                if !signers[vin].contains(&winner_key) {
+                    return Err(anyhow!(
+                        "the bet output wasn't claimed with the winner's key"
+                    ));
+                }
+                checks.push(format!(
+                    "claim tx {} spends the bet output with the winner's key",
+                    claim_tx.txid()
+                ));
+            }
+            None => checks.push("the claim tx isn't in the bundle so wasn't checked".into()),
+        }
+
+        Ok(AuditReport {
+            outcome,
+            proposer_won,
+            checks,
+        })
+    }
+}
+
+/// Check the oracle's attestation and that the attested outcome completes exactly one side's output
+/// key. The output keys are the bet keys plus the attestation point anticipated for the outcome
+/// each side bet on so whoever bet on the attested outcome can work out the secret key for theirs.
+/// Returns the outcome and whether the proposer won.
+pub fn winning_side(
+    oracle_info: &OracleInfo,
+    oracle_event: &OracleEvent,
+    attestation: &Attestation,
+    joint_output: &PublicJointOutput,
+    proposer_chose_right: bool,
+) -> anyhow::Result<(Outcome, bool)> {
+    let oracle_keys = &oracle_info.oracle_keys;
+    attestation
+        .verify_olivia_v1_attestation(oracle_event, oracle_keys)
+        .context("the oracle's attestation is invalid")?;
+    let outcome =
+        Outcome::try_from_id_and_outcome(oracle_event.event.id.clone(), &attestation.outcome)
+            .context("parsing the attested outcome")?;
+
+    let anticipated = oracle_event
+        .anticipate_attestations_olivia_v1(
+            &oracle_keys
+                .olivia_v1
+                .ok_or(anyhow!("the oracle has no olivia-v1 key"))?,
+            0,
+        )
+        .ok_or(anyhow!("the event doesn't have an olivia-v1 announcement"))?;
+    if anticipated.len() < 2 {
+        return Err(anyhow!("the event doesn't have two outcomes"));
+    }
+    let bet_keys = joint_output
+        .bet_keys
+        .ok_or(anyhow!("the bet was made before its bet keys were kept"))?;
+    let choices = [proposer_chose_right, !proposer_chose_right];
+    for ((output_key, bet_key), choice) in
+        joint_output.output_keys.iter().zip(&bet_keys).zip(choices)
+    {
+        let anticipated = anticipated[choice as usize];
+        if g!(bet_key + anticipated) != *output_key {
+            return Err(anyhow!(
+                "an output key isn't its bet key plus the anticipated attestation"
+            ));
+        }
+    }
+
+    let scalars = &attestation
+        .schemes
+        .olivia_v1
+        .as_ref()
+        .ok_or(anyhow!("the attestation is missing olivia-v1"))?
+        .scalars;
+    let attest_scalar = Scalar::from(
+        scalars
+            .get(0)
+            .ok_or(anyhow!("the attestation has no scalars"))?
+            .clone(),
+    );
+    if g!(attest_scalar * G) != anticipated[outcome.value as usize] {
+        return Err(anyhow!(
+            "the attestation isn't the one anticipated for the outcome"
+        ));
+    }
+
+    Ok((
+        outcome.clone(),
+        proposer_chose_right == (outcome.value == 1),
+    ))
+}
+
+/// Check the signatures on every input of `tx`. `prevouts` are the outputs it spends in input
+/// order. Returns the ECDSA keys that signed each input (taproot inputs have none).
+pub fn verify_signatures(
+    tx: &Transaction,
+    prevouts: &[TxOut],
+) -> anyhow::Result<Vec<Vec<secp256k1::PublicKey>>> {
+    if prevouts.len() != tx.input.len() {
+        return Err(anyhow!(
+            "{} has {} inputs but {} prevouts were given",
+            tx.txid(),
+            tx.input.len(),
+            prevouts.len()
+        ));
+    }
+    let secp = Secp256k1::verification_only();
+    let mut cache = SigHashCache::new(tx);
+    let mut signers = vec![];
+
+    for (i, (txin, prevout)) in tx.input.iter().zip(prevouts).enumerate() {
+        let witness = txin.witness.to_vec();
+        let script_pubkey = &prevout.script_pubkey;
+        if script_pubkey.is_v0_p2wpkh() {
+            let (signature, key) = match &witness[..] {
+                [signature, key] => (signature, secp256k1::PublicKey::from_slice(key)?),
+                _ => return Err(anyhow!("input {} should have two witness items", i)),
+            };
+            if Script::new_v0_wpkh(&WPubkeyHash::hash(&key.serialize())) != *script_pubkey {
+                return Err(anyhow!("input {}'s key doesn't match what it spends", i));
+            }
+            let script_code = Script::new_p2pkh(&PubkeyHash::hash(&key.serialize()));
+            check_ecdsa(
+                &secp,
+                &mut cache,
+                i,
+                &script_code,
+                prevout.value,
+                signature,
+                &key,
+            )?;
+            signers.push(vec![key]);
+        } else if script_pubkey.is_v0_p2wsh() {
+            let (witness_script, items) = witness
+                .split_last()
+                .ok_or(anyhow!("input {} has no witness", i))?;
+            let witness_script = Script::from(witness_script.clone());
+            if witness_script.to_v0_p2wsh() != *script_pubkey {
+                return Err(anyhow!("input {}'s witness script doesn't match", i));
+            }
+            let keys = witness_script
+                .instructions()
+                .filter_map(|instruction| match instruction {
+                    Ok(Instruction::PushBytes(bytes)) if bytes.len() == 33 => {
+                        secp256k1::PublicKey::from_slice(bytes).ok()
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            let mut input_signers = vec![];
+            // the short items choose branches rather than being signatures
+            for signature in items.iter().filter(|item| item.len() > 8) {
+                let key = keys
+                    .iter()
+                    .find(|key| {
+                        check_ecdsa(
+                            &secp,
+                            &mut cache,
+                            i,
+                            &witness_script,
+                            prevout.value,
+                            signature,
+                            key,
+                        )
+                        .is_ok()
+                    })
+                    .ok_or(anyhow!("input {} has a signature from none of its keys", i))?;
+                input_signers.push(*key);
+            }
+            if input_signers.is_empty() {
+                return Err(anyhow!("input {} isn't signed", i));
+            }
+            signers.push(input_signers);
+        } else if script_pubkey.is_v1_p2tr() {
+            let signature = match &witness[..] {
+                [signature] => signature,
+                _ => return Err(anyhow!("input {} isn't a taproot key path spend", i)),
+            };
+            let (signature, sighash_type) = match signature.len() {
+                64 => (&signature[..], SchnorrSigHashType::Default),
+                65 => (
+                    &signature[..64],
+                    SchnorrSigHashType::from_u8(signature[64])
+                        .map_err(|_| anyhow!("input {} has an invalid sighash type", i))?,
+                ),
+                _ => return Err(anyhow!("input {}'s signature is the wrong length", i)),
+            };
+            let key = XOnlyPublicKey::from_slice(&script_pubkey.as_bytes()[2..])?;
+            let sighash = cache.taproot_key_spend_signature_hash(
+                i,
+                &Prevouts::All(prevouts),
+                sighash_type,
+            )?;
+            secp.verify_schnorr(
+                &schnorr::Signature::from_slice(signature)?,
+                &Message::from_slice(&sighash[..])?,
+                &key,
+            )
+            .map_err(|_| anyhow!("input {} has an invalid signature", i))?;
+            signers.push(vec![]);
+        } else {
+            return Err(anyhow!(
+                "can't check input {} spending a {} output",
+                i,
+                crate::script_kind::ScriptKind::of(script_pubkey)
+            ));
+        }
+    }
+
+    Ok(signers)
+}
+
+pub(crate) fn check_ecdsa<C: Verification>(
+    secp: &Secp256k1<C>,
+    cache: &mut SigHashCache<&Transaction>,
+    input_index: usize,
+    script_code: &Script,
+    value: u64,
+    signature: &[u8],
+    key: &secp256k1::PublicKey,
+) -> anyhow::Result<()> {
+    let (sighash_type, der) = signature
+        .split_last()
+        .ok_or(anyhow!("input {} has an empty signature", input_index))?;
+    let sighash_type = EcdsaSigHashType::from_u32_standard(*sighash_type as u32)
+        .map_err(|_| anyhow!("input {} has a non-standard sighash type", input_index))?;
+    let mut signature = ecdsa::Signature::from_der(der)?;
+    signature.normalize_s();
+    let sighash = cache.segwit_signature_hash(input_index, script_code, value, sighash_type)?;
+    secp.verify_ecdsa(&Message::from_slice(&sighash[..])?, &signature, key)
+        .map_err(|_| anyhow!("input {} has an invalid signature", input_index))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{keychain::KeyPair, mock_oracle::MockOracle};
+    use olivia_core::EventId;
+    use olivia_secp256k1::fun::s;
+    use std::str::FromStr;
+
+    #[test]
+    fn only_the_winner_gets_a_key() {
+        let oracle = MockOracle::new([42u8; 32]);
+        let oracle_info = oracle.info("oracle.test".into());
+        let event_id = EventId::from_str("/random/2021-01-01T00:00:00/heads_tails.winner").unwrap();
+        let oracle_event = oracle.oracle_event(&event_id).unwrap();
+        let anticipated = oracle_event
+            .anticipate_attestations_olivia_v1(&oracle_info.oracle_keys.olivia_v1.unwrap(), 0)
+            .unwrap();
+        let proposal_key = KeyPair::from_slice(&[1u8; 32]).unwrap();
+        let offer_key = KeyPair::from_slice(&[2u8; 32]).unwrap();
+        let randomize = Randomize {
+            r1: KeyPair::from_slice(&[3u8; 32]).unwrap().secret_key,
+            r2: KeyPair::from_slice(&[4u8; 32]).unwrap().secret_key,
+            swap_points: true,
+        };
+        let r1 = randomize.r1.clone();
+        let joint_output = JointOutput::new(
+            [proposal_key.public_key, offer_key.public_key],
+            Either::Left(proposal_key.secret_key.clone()),
+            [anticipated[0], anticipated[1]],
+            true,
+            randomize,
+            None,
+        );
+        let public = joint_output.public();
+
+        for outcome_value in [0, 1] {
+            let outcome = Outcome {
+                id: event_id.clone(),
+                value: outcome_value,
+            };
+            let attestation = oracle.attest(&outcome);
+            // the offerer chose right so the proposer bet on the left outcome
+            let (attested, proposer_won) =
+                winning_side(&oracle_info, &oracle_event, &attestation, &public, false).unwrap();
+            assert_eq!(attested, outcome);
+            assert_eq!(proposer_won, outcome_value == 0);
+            // lying about which side the proposer took breaks the key math
+            assert!(
+                winning_side(&oracle_info, &oracle_event, &attestation, &public, true).is_err()
+            );
+        }
+
+        let attestation = oracle.attest(&Outcome {
+            id: event_id,
+            value: 0,
+        });
+        let attest_scalar =
+            Scalar::from(attestation.schemes.olivia_v1.as_ref().unwrap().scalars[0].clone());
+        let secret_key = s!(proposal_key.secret_key + r1 + attest_scalar);
+        assert_eq!(g!(secret_key * G), public.output_keys[0]);
+    }
+}
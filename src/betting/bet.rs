@@ -1,4 +1,7 @@
-use crate::betting::*;
+use crate::{
+    betting::*,
+    keychain::{DerivationVersion, KeyOrigin},
+};
 use bdk::bitcoin::{
     self, util::psbt::PartiallySignedTransaction as Psbt, Amount, OutPoint, Transaction, Txid,
 };
@@ -20,6 +23,22 @@ pub struct Bet {
     pub i_chose_right: bool,
     #[serde(default)]
     pub tags: Vec<String>,
+    /// Missing for bets made before fingerprints existed
+    #[serde(default)]
+    pub fingerprint: Option<BetFingerprint>,
+    /// How our bet key was derived from the protocol secret
+    #[serde(default)]
+    pub derivation: DerivationVersion,
+    /// The index of the protocol secret our bet key was derived from
+    #[serde(default)]
+    pub secret: u32,
+    /// Our change that was too small to be worth an output so it went to the fee
+    #[serde(
+        default,
+        with = "bitcoin::util::amount::serde::as_sat::opt",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub dust_fee: Option<Amount>,
 }
 
 impl Bet {
@@ -129,6 +148,14 @@ pub enum BetState {
         secret_key: bitcoin::secp256k1::SecretKey,
         attestation: Attestation,
     },
+    /// The bet output was spent by both parties together (a refund or a mutual cancel) before we
+    /// learned the outcome.
+    Refunded {
+        bet: Bet,
+        txid: Txid,
+        /// None implies the refund tx is in mempool
+        height: Option<u32>,
+    },
     /// There is a tx spending one of the bet inputs that is *not* the bet tx.
     Canceled {
         pre_cancel: BetOrProp,
@@ -186,13 +213,32 @@ impl BetState {
             Claimed {
                 height: Some(_), ..
             } => "claimed",
+            Refunded { height: None, .. } => "refunding",
+            Refunded {
+                height: Some(_), ..
+            } => "refunded",
             Canceled { height: None, .. } => "canceling",
+            Canceled {
+                height: Some(_), ..
+            } if self.canceled_by_counterparty() => "counterparty-canceled",
             Canceled {
                 height: Some(_), ..
             } => "canceled",
         }
     }
 
+    /// Whether the other party spent one of their inputs out from under an offer or bet.
+    pub fn canceled_by_counterparty(&self) -> bool {
+        matches!(
+            self,
+            BetState::Canceled {
+                pre_cancel: BetOrProp::Bet(_) | BetOrProp::OfferedBet { .. },
+                i_intend_cancel: false,
+                ..
+            }
+        )
+    }
+
     pub fn reserved_utxos(&self) -> Vec<OutPoint> {
         use BetState::*;
         match self {
@@ -215,6 +261,21 @@ impl BetState {
         }
     }
 
+    /// The value we could lose if the bet hasn't finished yet. A proposal's value counts since its
+    /// coins are reserved for it.
+    pub fn at_risk(&self) -> Option<Amount> {
+        use BetState::*;
+        match self {
+            Proposed { local_proposal } => Some(local_proposal.proposal.value),
+            Offered {
+                bet: OfferedBet(bet),
+                ..
+            }
+            | Included { bet, .. } => Some(bet.local_value),
+            _ => None,
+        }
+    }
+
     pub fn into_bet_or_prop(self) -> BetOrProp {
         match self {
             BetState::Proposed { local_proposal } => BetOrProp::Proposal(local_proposal),
@@ -229,7 +290,68 @@ impl BetState {
             BetState::Included { bet, .. }
             | BetState::Won { bet, .. }
             | BetState::Lost { bet, .. }
-            | BetState::Claimed { bet, .. } => BetOrProp::Bet(bet),
+            | BetState::Claimed { bet, .. }
+            | BetState::Refunded { bet, .. } => BetOrProp::Bet(bet),
+        }
+    }
+
+    pub fn tags(&self) -> &[String] {
+        match self {
+            BetState::Proposed { local_proposal }
+            | BetState::Canceled {
+                pre_cancel: BetOrProp::Proposal(local_proposal),
+                ..
+            } => &local_proposal.tags,
+            BetState::Offered {
+                bet: OfferedBet(bet),
+                ..
+            }
+            | BetState::Included { bet, .. }
+            | BetState::Won { bet, .. }
+            | BetState::Lost { bet, .. }
+            | BetState::Claimed { bet, .. }
+            | BetState::Refunded { bet, .. }
+            | BetState::Canceled {
+                pre_cancel:
+                    BetOrProp::OfferedBet {
+                        bet: OfferedBet(bet),
+                        ..
+                    }
+                    | BetOrProp::Bet(bet),
+                ..
+            } => &bet.tags,
+        }
+    }
+
+    /// Which protocol secret our key for the bet was derived from and how.
+    pub fn key_origin(&self) -> KeyOrigin {
+        match self {
+            BetState::Proposed { local_proposal }
+            | BetState::Canceled {
+                pre_cancel: BetOrProp::Proposal(local_proposal),
+                ..
+            } => local_proposal.key_origin(),
+            BetState::Offered {
+                bet: OfferedBet(bet),
+                ..
+            }
+            | BetState::Included { bet, .. }
+            | BetState::Won { bet, .. }
+            | BetState::Lost { bet, .. }
+            | BetState::Claimed { bet, .. }
+            | BetState::Refunded { bet, .. }
+            | BetState::Canceled {
+                pre_cancel:
+                    BetOrProp::OfferedBet {
+                        bet: OfferedBet(bet),
+                        ..
+                    }
+                    | BetOrProp::Bet(bet),
+                ..
+            } => KeyOrigin {
+                secret: bet.secret,
+                derivation: bet.derivation,
+            },
         }
     }
 
@@ -248,6 +370,7 @@ impl BetState {
             | BetState::Won { bet, .. }
             | BetState::Lost { bet, .. }
             | BetState::Claimed { bet, .. }
+            | BetState::Refunded { bet, .. }
             | BetState::Canceled {
                 pre_cancel:
                     BetOrProp::OfferedBet {
@@ -15,6 +15,8 @@ pub struct BetArgs<'a, 'b> {
     pub may_overlap: &'a [BetId],
     pub must_overlap: &'b [BetId],
     pub tags: Vec<String>,
+    /// Only used for proposals. See [`Proposal::refund_after`].
+    pub refund_after: Option<u32>,
 }
 
 impl Default for BetArgs<'_, '_> {
@@ -25,6 +27,7 @@ impl Default for BetArgs<'_, '_> {
             may_overlap: &EMPTY,
             must_overlap: &EMPTY,
             tags: vec![],
+            refund_after: None,
         }
     }
 }
@@ -0,0 +1,189 @@
+//! Moving a bet to another device with the same seed (`gun bet export-state` and `import-state`).
+//!
+//! The bundle is encrypted and authenticated with keys derived from the protocol secret the bet's
+//! key came from so only a wallet with that secret can read it or make one it will accept.
+//!
+//! Format: `gun-bet || protocol secret index (4) || nonce (12) || ciphertext || hmac-sha256 (32)`.
+use crate::{
+    backup::mac,
+    betting::{BetOrProp, BetState, BetThread, OfferedBet},
+    keychain::Keychain,
+};
+use anyhow::anyhow;
+use bdk::bitcoin::Network;
+use chacha20::{cipher::*, ChaCha20};
+use rand::RngCore;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+const MAGIC: &[u8; 7] = b"gun-bet";
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct BetBundle {
+    pub network: Network,
+    pub bet_state: BetState,
+    /// The messages exchanged with the counterparty
+    #[serde(default)]
+    pub thread: Option<BetThread>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case", tag = "version")]
+pub enum VersionedBetBundle {
+    #[serde(rename = "1")]
+    V1(BetBundle),
+}
+
+impl From<VersionedBetBundle> for BetBundle {
+    fn from(from: VersionedBetBundle) -> Self {
+        match from {
+            VersionedBetBundle::V1(bundle) => bundle,
+        }
+    }
+}
+
+impl BetBundle {
+    pub fn into_versioned(self) -> VersionedBetBundle {
+        VersionedBetBundle::V1(self)
+    }
+
+    pub fn encrypt(self, keychain: &Keychain) -> anyhow::Result<Vec<u8>> {
+        let secret = self.bet_state.key_origin().secret;
+        let (cipher_key, mac_key) = keychain.bet_bundle_keys(secret)?;
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let mut plaintext = Zeroizing::new(serde_json::to_vec(&self.into_versioned()).unwrap());
+        let mut cipher = ChaCha20::new(&(*cipher_key.expose_secret()).into(), &nonce.into());
+        cipher.apply_keystream(&mut plaintext);
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&secret.to_be_bytes());
+        bytes.extend_from_slice(&nonce);
+        bytes.extend_from_slice(&plaintext);
+        let tag = mac(mac_key.expose_secret(), &bytes);
+        bytes.extend_from_slice(&tag);
+        Ok(bytes)
+    }
+
+    pub fn decrypt(bytes: &[u8], keychain: &Keychain) -> anyhow::Result<Self> {
+        let header_len = MAGIC.len() + 4 + 12;
+        if bytes.len() < header_len + 32 || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(anyhow!("this is not a gun bet bundle"));
+        }
+        let (body, tag) = bytes.split_at(bytes.len() - 32);
+        let mut secret = [0u8; 4];
+        secret.copy_from_slice(&body[MAGIC.len()..MAGIC.len() + 4]);
+        let secret = u32::from_be_bytes(secret);
+        let mut nonce = [0u8; 12];
+        nonce.copy_from_slice(&body[MAGIC.len() + 4..header_len]);
+        let (cipher_key, mac_key) = keychain.bet_bundle_keys(secret)?;
+
+        if !bool::from(mac(mac_key.expose_secret(), body)[..].ct_eq(tag)) {
+            return Err(anyhow!(
+                "the bet bundle wasn't made by a wallet with the same protocol secret or has been corrupted"
+            ));
+        }
+
+        let mut plaintext = Zeroizing::new(body[header_len..].to_vec());
+        let mut cipher = ChaCha20::new(&(*cipher_key.expose_secret()).into(), &nonce.into());
+        cipher.apply_keystream(&mut plaintext);
+        let bundle = serde_json::from_slice::<VersionedBetBundle>(&plaintext)
+            .map_err(|e| anyhow!("bet bundle was decrypted but could not be decoded: {}", e))?;
+        let bundle = BetBundle::from(bundle);
+        if bundle.bet_state.key_origin().secret != secret {
+            return Err(anyhow!(
+                "the bet bundle's header doesn't match its contents"
+            ));
+        }
+        Ok(bundle)
+    }
+
+    /// Whether `other` is the same bet (as a proposal, an offer or once it's been made).
+    pub fn is_same_bet(&self, other: &BetState) -> bool {
+        match (
+            self.bet_state.clone().into_bet_or_prop(),
+            other.clone().into_bet_or_prop(),
+        ) {
+            (BetOrProp::Proposal(mine), BetOrProp::Proposal(theirs)) => {
+                mine.proposal.public_key == theirs.proposal.public_key
+            }
+            (
+                BetOrProp::Bet(mine)
+                | BetOrProp::OfferedBet {
+                    bet: OfferedBet(mine),
+                    ..
+                },
+                BetOrProp::Bet(theirs)
+                | BetOrProp::OfferedBet {
+                    bet: OfferedBet(theirs),
+                    ..
+                },
+            ) => mine.fingerprint.is_some() && mine.fingerprint == theirs.fingerprint,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        betting::{LocalProposal, OracleEvent, Proposal},
+        keychain::DerivationVersion,
+    };
+    use bdk::bitcoin::Amount;
+    use olivia_core::{Event, EventId};
+    use std::str::FromStr;
+
+    fn proposed() -> BetState {
+        let event_id = EventId::from_str("/random/2020-09-25T08:00:00/heads_tails.winner").unwrap();
+        let keychain = Keychain::new([1u8; 64]);
+        let mut proposal = Proposal {
+            oracle: "h00.ooo".into(),
+            event_id: event_id.clone(),
+            value: Amount::from_sat(100_000),
+            inputs: vec![],
+            public_key: crate::placeholder_point(),
+            change_script: None,
+            refund_after: None,
+        };
+        proposal.public_key = keychain.get_key_for_proposal(&proposal).public_key;
+        BetState::Proposed {
+            local_proposal: LocalProposal {
+                proposal,
+                oracle_event: OracleEvent {
+                    event: Event {
+                        id: event_id,
+                        expected_outcome_time: None,
+                    },
+                    schemes: Default::default(),
+                },
+                change: None,
+                tags: vec!["imported".into()],
+                derivation: DerivationVersion::V0,
+                secret: 0,
+                dust_fee: None,
+            },
+        }
+    }
+
+    #[test]
+    fn bundle_roundtrip() {
+        let keychain = Keychain::new([1u8; 64]);
+        let bundle = BetBundle {
+            network: Network::Regtest,
+            bet_state: proposed(),
+            thread: None,
+        };
+        let bytes = bundle.clone().encrypt(&keychain).unwrap();
+        let decrypted = BetBundle::decrypt(&bytes, &keychain).unwrap();
+        assert_eq!(decrypted.bet_state.tags(), &["imported".to_string()]);
+        assert!(decrypted.is_same_bet(&bundle.bet_state));
+
+        assert!(BetBundle::decrypt(&bytes, &Keychain::new([2u8; 64])).is_err());
+        let mut tampered = bytes.clone();
+        let middle = tampered.len() / 2;
+        tampered[middle] ^= 1;
+        assert!(BetBundle::decrypt(&tampered, &keychain).is_err());
+    }
+}
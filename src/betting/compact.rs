@@ -0,0 +1,435 @@
+//! The compact (v2) binary encoding of proposal payloads and offers.
+//!
+//! v1 is bincode of the serde types which spends bytes on things like length prefixes for fixed
+//! size data and whole scripts where a template would do. v2 is hand written:
+//!
+//! - outpoints are the 32 byte txid followed by the vout as a varint
+//! - scripts of standard types are a one byte template and the hash/key
+//! - optional fields are flagged in a bitmap byte at the start rather than a byte each
+//! - an offer's nLockTime is only there when it isn't 0
+//! - the rest of an offer is DEFLATEd when that makes it smaller (i.e. when it has a message)
+use crate::{
+    betting::{Offer, Payload, SignedInput, Witness},
+    change::Change,
+};
+use anyhow::anyhow;
+use bdk::bitcoin::{
+    hashes::Hash, secp256k1, Amount, OutPoint, Script, Txid, WPubkeyHash, WScriptHash,
+};
+use olivia_secp256k1::{ecdsa_fun, fun::Point, schnorr_fun};
+
+const HAS_CHANGE: u8 = 0b0001;
+const CHOOSE_RIGHT: u8 = 0b0010;
+const HAS_MESSAGE: u8 = 0b0100;
+const DEFLATED: u8 = 0b1000;
+const HAS_LOCKTIME: u8 = 0b1_0000;
+const OFFER_FLAGS: u8 = HAS_CHANGE | CHOOSE_RIGHT | HAS_MESSAGE | DEFLATED | HAS_LOCKTIME;
+
+/// Never inflate more than this so a malicious offer can't exhaust our memory.
+const MAX_INFLATED_LEN: usize = 1 << 16;
+
+const SCRIPT_P2WPKH: u8 = 0;
+const SCRIPT_P2WSH: u8 = 1;
+const SCRIPT_P2TR: u8 = 2;
+const SCRIPT_OTHER: u8 = 0xff;
+
+const WITNESS_P2WPKH: u8 = 0;
+const WITNESS_P2TR_KEY_SPEND: u8 = 1;
+
+pub fn encode_payload(payload: &Payload) -> Vec<u8> {
+    let mut flags = 0;
+    if payload.change_script.is_some() {
+        flags |= HAS_CHANGE;
+    }
+    let mut buf = vec![flags];
+    buf.extend_from_slice(payload.public_key.to_xonly().as_bytes());
+    write_varint(&mut buf, payload.inputs.len() as u64);
+    for input in &payload.inputs {
+        write_outpoint(&mut buf, input);
+    }
+    if let Some(change_script) = &payload.change_script {
+        write_script(&mut buf, &Script::from(change_script.clone()));
+    }
+    buf
+}
+
+pub fn decode_payload(bytes: &[u8]) -> anyhow::Result<Payload> {
+    let mut reader = Reader(bytes);
+    let flags = reader.byte()?;
+    if flags & !HAS_CHANGE != 0 {
+        return Err(anyhow!("unknown flags {:#04x} in proposal", flags));
+    }
+    let public_key = Point::from_xonly_bytes(reader.array::<32>()?)
+        .ok_or(anyhow!("invalid public key in proposal"))?;
+    let n_inputs = reader.varint()?;
+    let mut inputs = vec![];
+    for _ in 0..n_inputs {
+        inputs.push(reader.outpoint()?);
+    }
+    let change_script = match flags & HAS_CHANGE {
+        0 => None,
+        _ => Some(reader.script()?.into()),
+    };
+    reader.finish()?;
+    Ok(Payload {
+        public_key,
+        inputs,
+        change_script,
+    })
+}
+
+pub fn encode_offer(offer: &Offer, message: Option<&str>) -> Vec<u8> {
+    let mut flags = 0;
+    if offer.change.is_some() {
+        flags |= HAS_CHANGE;
+    }
+    if offer.choose_right {
+        flags |= CHOOSE_RIGHT;
+    }
+    if message.is_some() {
+        flags |= HAS_MESSAGE;
+    }
+    if offer.locktime != 0 {
+        flags |= HAS_LOCKTIME;
+    }
+
+    let mut body = vec![];
+    write_varint(&mut body, offer.value.as_sat());
+    if offer.locktime != 0 {
+        write_varint(&mut body, offer.locktime as u64);
+    }
+    write_varint(&mut body, offer.inputs.len() as u64);
+    for input in &offer.inputs {
+        write_outpoint(&mut body, &input.outpoint);
+        match &input.witness {
+            Witness::P2wpkh { key, signature } => {
+                body.push(WITNESS_P2WPKH);
+                body.extend_from_slice(&key.serialize());
+                body.extend_from_slice(&signature.to_bytes());
+            }
+            Witness::P2trKeySpend { signature, sighash } => {
+                body.push(WITNESS_P2TR_KEY_SPEND);
+                body.extend_from_slice(&signature.to_bytes());
+                // 0 (SIGHASH_DEFAULT) is never written explicitly so it can stand for none
+                body.push(sighash.unwrap_or(0));
+            }
+        }
+    }
+    if let Some(change) = &offer.change {
+        write_varint(&mut body, change.value().as_sat());
+        write_script(&mut body, change.script());
+    }
+    if let Some(message) = message {
+        write_varint(&mut body, message.len() as u64);
+        body.extend_from_slice(message.as_bytes());
+    }
+
+    let deflated = miniz_oxide::deflate::compress_to_vec(&body, 10);
+    if deflated.len() < body.len() {
+        flags |= DEFLATED;
+        body = deflated;
+    }
+
+    let mut buf = vec![flags];
+    buf.append(&mut body);
+    buf
+}
+
+pub fn decode_offer(bytes: &[u8]) -> anyhow::Result<(Offer, Option<String>)> {
+    let flags = *bytes.first().ok_or(anyhow!("empty offer"))?;
+    if flags & !OFFER_FLAGS != 0 {
+        return Err(anyhow!("unknown flags {:#04x} in offer", flags));
+    }
+    let inflated;
+    let body = match flags & DEFLATED {
+        0 => &bytes[1..],
+        _ => {
+            inflated =
+                miniz_oxide::inflate::decompress_to_vec_with_limit(&bytes[1..], MAX_INFLATED_LEN)
+                    .map_err(|_| anyhow!("offer is not valid DEFLATE data"))?;
+            &inflated[..]
+        }
+    };
+
+    let mut reader = Reader(body);
+    let value = Amount::from_sat(reader.varint()?);
+    let locktime = match flags & HAS_LOCKTIME {
+        0 => 0,
+        _ => u32::try_from(reader.varint()?).map_err(|_| anyhow!("locktime is too large"))?,
+    };
+    let n_inputs = reader.varint()?;
+    let mut inputs = vec![];
+    for _ in 0..n_inputs {
+        let outpoint = reader.outpoint()?;
+        let witness = match reader.byte()? {
+            WITNESS_P2WPKH => Witness::P2wpkh {
+                key: secp256k1::PublicKey::from_slice(&reader.array::<33>()?)
+                    .map_err(|_| anyhow!("invalid public key in offer input"))?,
+                signature: ecdsa_fun::Signature::from_bytes(reader.array::<64>()?)
+                    .ok_or(anyhow!("invalid signature in offer input"))?,
+            },
+            WITNESS_P2TR_KEY_SPEND => Witness::P2trKeySpend {
+                signature: schnorr_fun::Signature::from_bytes(reader.array::<64>()?)
+                    .ok_or(anyhow!("invalid signature in offer input"))?,
+                sighash: match reader.byte()? {
+                    0 => None,
+                    sighash => Some(sighash),
+                },
+            },
+            kind => return Err(anyhow!("unknown witness type {} in offer", kind)),
+        };
+        inputs.push(SignedInput { outpoint, witness });
+    }
+    let change = match flags & HAS_CHANGE {
+        0 => None,
+        _ => {
+            let value = reader.varint()?;
+            Some(Change::new(value, reader.script()?))
+        }
+    };
+    let message = match flags & HAS_MESSAGE {
+        0 => None,
+        _ => {
+            let len = reader.varint()? as usize;
+            Some(
+                String::from_utf8(reader.take(len)?.to_vec())
+                    .map_err(|_| anyhow!("offer message is not UTF-8"))?,
+            )
+        }
+    };
+    reader.finish()?;
+
+    Ok((
+        Offer {
+            inputs,
+            change,
+            choose_right: flags & CHOOSE_RIGHT != 0,
+            value,
+            locktime,
+        },
+        message,
+    ))
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn write_outpoint(buf: &mut Vec<u8>, outpoint: &OutPoint) {
+    buf.extend_from_slice(&outpoint.txid[..]);
+    write_varint(buf, outpoint.vout as u64);
+}
+
+fn write_script(buf: &mut Vec<u8>, script: &Script) {
+    let bytes = script.as_bytes();
+    if script.is_v0_p2wpkh() {
+        buf.push(SCRIPT_P2WPKH);
+        buf.extend_from_slice(&bytes[2..]);
+    } else if script.is_v0_p2wsh() {
+        buf.push(SCRIPT_P2WSH);
+        buf.extend_from_slice(&bytes[2..]);
+    } else if script.is_v1_p2tr() {
+        buf.push(SCRIPT_P2TR);
+        buf.extend_from_slice(&bytes[2..]);
+    } else {
+        buf.push(SCRIPT_OTHER);
+        write_varint(buf, bytes.len() as u64);
+        buf.extend_from_slice(bytes);
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(anyhow!("unexpected end of data"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn varint(&mut self) -> anyhow::Result<u64> {
+        let mut n = 0u64;
+        for i in 0..10 {
+            let byte = self.byte()?;
+            // the tenth byte only has room for the top bit of a u64
+            if i == 9 && byte > 1 {
+                return Err(anyhow!("varint is too large"));
+            }
+            n |= ((byte & 0x7f) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                // a trailing zero byte means there's a shorter encoding of the same number
+                if i > 0 && byte == 0 {
+                    return Err(anyhow!("varint is not minimally encoded"));
+                }
+                return Ok(n);
+            }
+        }
+        Err(anyhow!("varint is too long"))
+    }
+
+    fn outpoint(&mut self) -> anyhow::Result<OutPoint> {
+        let txid = Txid::from_inner(self.array::<32>()?);
+        let vout = u32::try_from(self.varint()?).map_err(|_| anyhow!("vout is too large"))?;
+        Ok(OutPoint { txid, vout })
+    }
+
+    fn script(&mut self) -> anyhow::Result<Script> {
+        let script = match self.byte()? {
+            SCRIPT_P2WPKH => Script::new_v0_wpkh(&WPubkeyHash::from_inner(self.array::<20>()?)),
+            SCRIPT_P2WSH => Script::new_v0_wsh(&WScriptHash::from_inner(self.array::<32>()?)),
+            SCRIPT_P2TR => {
+                let mut bytes = vec![0x51, 0x20];
+                bytes.extend_from_slice(self.take(32)?);
+                Script::from(bytes)
+            }
+            SCRIPT_OTHER => {
+                let len = self.varint()? as usize;
+                Script::from(self.take(len)?.to_vec())
+            }
+            kind => return Err(anyhow!("unknown script template {}", kind)),
+        };
+        Ok(script)
+    }
+
+    fn finish(&self) -> anyhow::Result<()> {
+        if !self.0.is_empty() {
+            return Err(anyhow!("{} unexpected trailing bytes", self.0.len()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use olivia_secp256k1::fun::{marker::*, s, G};
+    use std::str::FromStr;
+
+    fn test_offer() -> Offer {
+        let key = Point::<EvenY>::from_scalar_mul(G, &mut s!(7));
+        Offer {
+            inputs: vec![SignedInput {
+                outpoint: OutPoint {
+                    txid: Txid::from_inner([3u8; 32]),
+                    vout: 300,
+                },
+                witness: Witness::P2wpkh {
+                    key: key.mark::<Normal>().into(),
+                    signature: ecdsa_fun::Signature::from_bytes([43u8; 64]).unwrap(),
+                },
+            }],
+            change: Some(Change::new(
+                5_000,
+                bdk::bitcoin::Address::from_str(
+                    "bc1qwqdg6squsna38e46795at95yu9atm8azzmyvckulcc7kytlcckxswvvzej",
+                )
+                .unwrap()
+                .script_pubkey(),
+            )),
+            choose_right: true,
+            value: Amount::from_sat(100_000),
+            locktime: 700_000,
+        }
+    }
+
+    #[test]
+    fn offer_roundtrip() {
+        let offer = test_offer();
+        let encoded = encode_offer(&offer, None);
+        assert_eq!(decode_offer(&encoded).unwrap(), (offer.clone(), None));
+        assert!(encoded.len() < crate::encode::serialize(&offer).len());
+
+        let message = "a message that repeats a message that repeats a message";
+        let with_message = encode_offer(&offer, Some(message));
+        assert_eq!(with_message[0] & DEFLATED, DEFLATED);
+        assert_eq!(
+            decode_offer(&with_message).unwrap(),
+            (offer, Some(message.to_string()))
+        );
+    }
+
+    #[test]
+    fn p2tr_input_roundtrip() {
+        let mut offer = test_offer();
+        let mut signature = [0u8; 64];
+        signature[..32].copy_from_slice(
+            &Point::<EvenY>::from_scalar_mul(G, &mut s!(3))
+                .to_xonly()
+                .as_bytes()[..],
+        );
+        signature[63] = 1;
+        let witness = Witness::P2trKeySpend {
+            signature: schnorr_fun::Signature::from_bytes(signature).unwrap(),
+            sighash: None,
+        };
+        assert_eq!(
+            Witness::decode_p2tr_key_spend(witness.encode()),
+            Some(witness.clone())
+        );
+        offer.inputs[0].witness = witness;
+        let encoded = encode_offer(&offer, None);
+        assert_eq!(decode_offer(&encoded).unwrap(), (offer, None));
+    }
+
+    #[test]
+    fn payload_roundtrip() {
+        let payload = Payload {
+            public_key: Point::<EvenY>::from_scalar_mul(G, &mut s!(42)),
+            inputs: vec![OutPoint {
+                txid: Txid::from_inner([1u8; 32]),
+                vout: 1,
+            }],
+            change_script: Some(Script::from(vec![0x6a, 0x01, 0x02]).into()),
+        };
+        let encoded = encode_payload(&payload);
+        assert_eq!(decode_payload(&encoded).unwrap(), payload);
+        assert!(encoded.len() < crate::encode::serialize(&payload).len());
+        assert!(decode_payload(&encoded[..encoded.len() - 1]).is_err());
+
+        let mut unknown_flag = encoded.clone();
+        unknown_flag[0] |= CHOOSE_RIGHT;
+        assert!(decode_payload(&unknown_flag).is_err());
+    }
+
+    #[test]
+    fn offer_unknown_flags_rejected() {
+        let mut encoded = encode_offer(&test_offer(), None);
+        encoded[0] |= 0b10_0000;
+        assert!(decode_offer(&encoded).is_err());
+    }
+
+    #[test]
+    fn varint_must_be_minimal() {
+        for n in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut buf = vec![];
+            write_varint(&mut buf, n);
+            let mut reader = Reader(&buf);
+            assert_eq!(reader.varint().unwrap(), n);
+            reader.finish().unwrap();
+        }
+        assert!(Reader(&[0x80, 0x00]).varint().is_err());
+        assert!(Reader(&[0x81, 0x80, 0x00]).varint().is_err());
+        assert_eq!(Reader(&[0x80, 0x01]).varint().unwrap(), 128);
+    }
+}
@@ -0,0 +1,75 @@
+use crate::betting::BetState;
+use bdk::bitcoin::{self, Amount, SignedAmount};
+
+/// What a bet's transactions cost us in fees. Each one is filled in once its transaction confirms.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BetFees {
+    /// Our share of the bet tx's fee
+    #[serde(default, with = "bitcoin::util::amount::serde::as_sat::opt")]
+    pub funding: Option<Amount>,
+    /// The fee of the tx that canceled the bet (zero if the counterparty canceled it)
+    #[serde(default, with = "bitcoin::util::amount::serde::as_sat::opt")]
+    pub cancel: Option<Amount>,
+    /// Our share of the fee of the tx that claimed or refunded the bet output
+    #[serde(default, with = "bitcoin::util::amount::serde::as_sat::opt")]
+    pub claim: Option<Amount>,
+}
+
+impl BetFees {
+    pub fn total(&self) -> Amount {
+        [self.funding, self.cancel, self.claim]
+            .iter()
+            .flatten()
+            .fold(Amount::ZERO, |total, fee| total + *fee)
+    }
+}
+
+/// What we made or lost on the bet after fees. `None` until the bet has finished and the fees of
+/// the transactions that finished it have been recorded.
+pub fn net_profit(bet_state: &BetState, fees: &BetFees) -> Option<SignedAmount> {
+    let signed = |amount: Amount| SignedAmount::from_sat(amount.as_sat() as i64);
+    match bet_state {
+        BetState::Claimed {
+            bet,
+            height: Some(_),
+            ..
+        } => Some(
+            signed(bet.joint_output_value - bet.local_value)
+                - signed(fees.funding?)
+                - signed(fees.claim?),
+        ),
+        BetState::Lost { bet, .. } => Some(-signed(bet.local_value) - signed(fees.funding?)),
+        BetState::Refunded {
+            height: Some(_), ..
+        } => Some(-signed(fees.funding?) - signed(fees.claim?)),
+        // the bet tx never confirmed so only the cancel cost anything
+        BetState::Canceled {
+            height: Some(_), ..
+        } => Some(-signed(fees.cancel?)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn total_fees_and_serde() {
+        let fees = BetFees {
+            funding: Some(Amount::from_sat(300)),
+            cancel: None,
+            claim: Some(Amount::from_sat(150)),
+        };
+        assert_eq!(fees.total(), Amount::from_sat(450));
+        assert_eq!(BetFees::default().total(), Amount::ZERO);
+        let json = serde_json::to_string(&fees).unwrap();
+        assert_eq!(json, r#"{"funding":300,"cancel":null,"claim":150}"#);
+        assert_eq!(serde_json::from_str::<BetFees>(&json).unwrap(), fees);
+        assert_eq!(
+            serde_json::from_str::<BetFees>("{}").unwrap(),
+            BetFees::default()
+        );
+    }
+}
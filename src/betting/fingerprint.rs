@@ -0,0 +1,114 @@
+use crate::betting::{BetId, PublicKey};
+use anyhow::anyhow;
+use bdk::bitcoin::hashes::{hex::FromHex, sha256, Hash, HashEngine};
+use olivia_core::EventId;
+use std::{fmt, str::FromStr};
+
+const TAG: &[u8] = b"gun/bet-fingerprint";
+
+/// An identifier for a bet that both parties compute the same way (unlike [`BetId`] which is
+/// local to each wallet). It's a hash of the proposal's public key, the offer's public key and the
+/// event id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct BetFingerprint(pub [u8; 8]);
+
+impl BetFingerprint {
+    pub fn new(proposal_key: &PublicKey, offer_key: &PublicKey, event_id: &EventId) -> Self {
+        let tag = sha256::Hash::hash(TAG);
+        let mut engine = sha256::Hash::engine();
+        engine.input(&tag[..]);
+        engine.input(&tag[..]);
+        engine.input(proposal_key.to_xonly().as_bytes());
+        engine.input(offer_key.to_xonly().as_bytes());
+        engine.input(event_id.to_string().as_bytes());
+        let hash = sha256::Hash::from_engine(engine);
+        let mut fingerprint = [0u8; 8];
+        fingerprint.copy_from_slice(&hash[..8]);
+        BetFingerprint(fingerprint)
+    }
+}
+
+impl fmt::Display for BetFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// A way of referring to a bet on the command line: its local id or (a prefix of) its fingerprint.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BetRef {
+    Id(BetId),
+    Fingerprint(String),
+}
+
+/// The shortest fingerprint prefix we'll look up.
+pub const MIN_FINGERPRINT_PREFIX: usize = 4;
+
+impl FromStr for BetRef {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> anyhow::Result<Self> {
+        if let Ok(id) = BetId::from_str(string) {
+            return Ok(BetRef::Id(id));
+        }
+        let prefix = string.to_lowercase();
+        if prefix.len() < MIN_FINGERPRINT_PREFIX
+            || prefix.len() > 16
+            || !prefix.chars().all(|c| c.is_ascii_hexdigit())
+        {
+            return Err(anyhow!(
+                "'{}' is neither a bet id nor a bet fingerprint (at least {} hex characters)",
+                string,
+                MIN_FINGERPRINT_PREFIX
+            ));
+        }
+        Ok(BetRef::Fingerprint(prefix))
+    }
+}
+
+impl FromStr for BetFingerprint {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> anyhow::Result<Self> {
+        let bytes = Vec::<u8>::from_hex(string)?;
+        Ok(BetFingerprint(bytes.try_into().map_err(|_| {
+            anyhow!("a bet fingerprint is 16 hex characters")
+        })?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use olivia_secp256k1::fun::{marker::*, s, Point, G};
+
+    #[test]
+    fn fingerprint_is_shared() {
+        let proposal_key = Point::<EvenY>::from_scalar_mul(G, &mut s!(1));
+        let offer_key = Point::<EvenY>::from_scalar_mul(G, &mut s!(2));
+        let event_id = EventId::from_str("/random/2021-08-01T00:00:00/heads_tails.winner").unwrap();
+        let fingerprint = BetFingerprint::new(&proposal_key, &offer_key, &event_id);
+        assert_eq!(
+            BetFingerprint::from_str(&fingerprint.to_string()).unwrap(),
+            fingerprint
+        );
+        assert_ne!(
+            BetFingerprint::new(&offer_key, &proposal_key, &event_id),
+            fingerprint
+        );
+    }
+
+    #[test]
+    fn parse_bet_ref() {
+        assert_eq!(BetRef::from_str("12").unwrap(), BetRef::Id(12));
+        assert_eq!(
+            BetRef::from_str("A1b2c3").unwrap(),
+            BetRef::Fingerprint("a1b2c3".into())
+        );
+        assert!(BetRef::from_str("abc").is_err());
+        assert!(BetRef::from_str("xyzxyz").is_err());
+    }
+}
@@ -1,5 +1,10 @@
 use bdk::{
-    bitcoin::{self, PublicKey},
+    bitcoin::{
+        self,
+        secp256k1::{self, Secp256k1},
+        util::sighash::SigHashCache,
+        PublicKey, Script, Transaction,
+    },
     descriptor::ExtendedDescriptor,
     keys::DescriptorSinglePub,
     miniscript::{descriptor::Wsh, policy::concrete::Policy, Descriptor, DescriptorPublicKey},
@@ -7,7 +12,7 @@ use bdk::{
 use olivia_secp256k1::fun::{g, marker::*, s, Point, Scalar, G};
 use std::convert::{Infallible, TryInto};
 
-use super::randomize::Randomize;
+use super::{audit, randomize::Randomize};
 
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
 pub enum Either<T> {
@@ -36,6 +41,113 @@ pub struct JointOutput {
     pub output_keys: [Point; 2],
     pub my_key: Either<Scalar>,
     pub swapped: bool,
+    #[serde(default)]
+    pub refund: Option<Refund>,
+    /// The proposer's and the offerer's bet keys randomized like the output keys. `my_key` is the
+    /// secret key for ours. Bets made before these were stored don't have them.
+    #[serde(default)]
+    pub bet_keys: Option<[Point; 2]>,
+}
+
+/// The parts of a [`JointOutput`] both parties know i.e. everything but `my_key`.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub struct PublicJointOutput {
+    pub output_keys: [Point; 2],
+    pub swapped: bool,
+    #[serde(default)]
+    pub refund: Option<Refund>,
+    #[serde(default)]
+    pub bet_keys: Option<[Point; 2]>,
+}
+
+/// How many blocks after the refund height either party can take the whole joint output alone.
+pub const REFUND_SWEEP_DELAY: u32 = 1008;
+
+/// A branch of the joint output both parties can spend together without the oracle. They use it
+/// to cancel the bet whenever they both want to or to refund it if the oracle never attests.
+///
+/// The 2-of-2 part has no timelock. A refund is signed with its nLockTime set to `after` so it can
+/// be agreed on in advance but not broadcast before then. So that the coins aren't stuck forever if
+/// the counterparty disappears each party can also spend the output alone from `sweep_after`.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub struct Refund {
+    /// The height the parties agreed a refund can be broadcast after
+    pub after: u32,
+    /// The proposer's and the offerer's refund keys. These are the bet keys randomized like the
+    /// output keys but without the anticipated attestation so `my_key` is the secret key for ours.
+    pub keys: [Point; 2],
+    /// The height from which either refund key can spend the output alone. Bets made before this
+    /// was added don't have it.
+    #[serde(default)]
+    pub sweep_after: Option<u32>,
+}
+
+impl Refund {
+    pub fn new(after: u32, keys: [Point; 2]) -> Self {
+        Refund {
+            after,
+            keys,
+            sweep_after: Some(after.saturating_add(REFUND_SWEEP_DELAY)),
+        }
+    }
+
+    /// Whether input `vin` of `tx` spends the joint output (worth `value`) with a refund key (i.e.
+    /// it was a refund, a mutual cancel or a sweep). The winner's claim spends it too so we check
+    /// which keys signed before deciding the bet was refunded.
+    pub fn is_spent_by(&self, tx: &Transaction, vin: usize, value: u64) -> bool {
+        let input = match tx.input.get(vin) {
+            Some(input) => input,
+            None => return false,
+        };
+        let witness = input.witness.to_vec();
+        let (witness_script, items) = match witness.split_last() {
+            Some(split) => split,
+            None => return false,
+        };
+        let witness_script = Script::from(witness_script.clone());
+        let keys = self
+            .keys
+            .iter()
+            .map(|key| (*key).into())
+            .collect::<Vec<secp256k1::PublicKey>>();
+        let secp = Secp256k1::verification_only();
+        let mut cache = SigHashCache::new(tx);
+        // the short items choose branches rather than being signatures
+        items.iter().filter(|item| item.len() > 8).any(|signature| {
+            keys.iter().any(|key| {
+                audit::check_ecdsa(
+                    &secp,
+                    &mut cache,
+                    vin,
+                    &witness_script,
+                    value,
+                    signature,
+                    key,
+                )
+                .is_ok()
+            })
+        })
+    }
+
+    fn policy(
+        &self,
+        to_policy: impl Fn(&Point) -> Policy<bitcoin::PublicKey>,
+    ) -> Policy<bitcoin::PublicKey> {
+        let together = Policy::Threshold(2, self.keys.iter().map(&to_policy).collect());
+        match self.sweep_after {
+            None => together,
+            Some(sweep_after) => Policy::Or(vec![
+                (9, together),
+                (
+                    1,
+                    Policy::And(vec![
+                        Policy::Threshold(1, self.keys.iter().map(&to_policy).collect()),
+                        Policy::After(sweep_after),
+                    ]),
+                ),
+            ]),
+        }
+    }
 }
 
 impl JointOutput {
@@ -49,6 +161,7 @@ impl JointOutput {
             r2,
             swap_points,
         }: Randomize,
+        refund_after: Option<u32>,
     ) -> Self {
         let (left, right) = (anticipated_signatures[0], anticipated_signatures[1]);
         let (proposal_key, offer_key) = (&public_keys[0], &public_keys[1]);
@@ -75,6 +188,14 @@ impl JointOutput {
             ],
         };
 
+        let bet_keys = [
+            g!(proposal_key + r1 * G)
+                .mark::<(Normal, NonZero)>()
+                .unwrap(),
+            g!(offer_key + r2 * G).mark::<(Normal, NonZero)>().unwrap(),
+        ];
+        let refund = refund_after.map(|after| Refund::new(after, bet_keys));
+
         let my_key = match my_key {
             Either::Left(key) => {
                 debug_assert!(&g!(key * G) == proposal_key, "secret key wasn't correct");
@@ -90,28 +211,35 @@ impl JointOutput {
             output_keys: output_keys.try_into().unwrap(),
             my_key,
             swapped: swap_points,
+            refund,
+            bet_keys: Some(bet_keys),
         }
     }
 
-    pub fn policy(&self) -> Policy<bitcoin::PublicKey> {
-        let keys = &match self.swapped {
-            false => self.output_keys,
-            true => [self.output_keys[1], self.output_keys[0]],
-        };
+    /// Our and the counterparty's bet keys (in that order) if we know them.
+    pub fn my_and_their_bet_keys(&self) -> Option<(Point, Point)> {
+        let keys = self
+            .bet_keys
+            .or_else(|| self.refund.as_ref().map(|refund| refund.keys))?;
+        Some(match self.my_key {
+            Either::Left(_) => (keys[0], keys[1]),
+            Either::Right(_) => (keys[1], keys[0]),
+        })
+    }
 
-        Policy::<bitcoin::PublicKey>::Or(
-            keys.iter()
-                .map(|key| {
-                    (
-                        1,
-                        Policy::Key(PublicKey {
-                            compressed: true,
-                            key: (*key).into(),
-                        }),
-                    )
-                })
-                .collect(),
-        )
+    pub fn public(&self) -> PublicJointOutput {
+        PublicJointOutput {
+            output_keys: self.output_keys,
+            swapped: self.swapped,
+            refund: self.refund.clone(),
+            bet_keys: self
+                .bet_keys
+                .or_else(|| self.refund.as_ref().map(|refund| refund.keys)),
+        }
+    }
+
+    pub fn policy(&self) -> Policy<bitcoin::PublicKey> {
+        self.public().policy()
     }
 
     // pub fn compute_privkey<B: Blockchain>(
@@ -159,3 +287,31 @@ impl JointOutput {
         Descriptor::Wsh(Wsh::new(self.policy().compile().unwrap()).unwrap())
     }
 }
+
+impl PublicJointOutput {
+    pub fn policy(&self) -> Policy<bitcoin::PublicKey> {
+        let keys = &match self.swapped {
+            false => self.output_keys,
+            true => [self.output_keys[1], self.output_keys[0]],
+        };
+
+        let to_policy = |key: &Point| {
+            Policy::Key(PublicKey {
+                compressed: true,
+                key: (*key).into(),
+            })
+        };
+
+        let outcome_policy =
+            Policy::<bitcoin::PublicKey>::Or(keys.iter().map(|key| (1, to_policy(key))).collect());
+
+        match &self.refund {
+            None => outcome_policy,
+            Some(refund) => Policy::Or(vec![(9, outcome_policy), (1, refund.policy(to_policy))]),
+        }
+    }
+
+    pub fn descriptor(&self) -> Descriptor<bitcoin::PublicKey> {
+        Descriptor::Wsh(Wsh::new(self.policy().compile().unwrap()).unwrap())
+    }
+}
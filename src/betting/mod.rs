@@ -1,19 +1,36 @@
+mod audit;
 mod bet;
 mod bet_args;
+mod bundle;
+pub mod compact;
+mod fees;
+mod fingerprint;
 mod joint_output;
 mod offer;
 mod proposal;
 mod randomize;
+mod take_policy;
+mod thread;
 mod wallet_impls;
+mod watch;
 mod witness;
 
+pub use audit::*;
 pub use bet::*;
 pub use bet_args::*;
+pub use bundle::*;
+pub use fees::*;
+pub use fingerprint::*;
 pub use joint_output::*;
 pub use offer::*;
 use olivia_secp256k1::fun::{marker::EvenY, Point};
 pub use proposal::*;
 pub use randomize::*;
+pub use take_policy::*;
+pub use thread::*;
+pub(crate) use wallet_impls::add_bet_output;
+pub use wallet_impls::Prefetched;
+pub use watch::*;
 pub use witness::*;
 
 pub type OracleEvent = olivia_core::OracleEvent<olivia_secp256k1::Secp256k1>;
@@ -14,6 +14,32 @@ pub struct Offer {
     pub choose_right: bool,
     #[serde(with = "bitcoin::util::amount::serde::as_sat")]
     pub value: Amount,
+    /// The bet transaction's nLockTime. Offers from before it was added have 0.
+    #[serde(default)]
+    pub locktime: u32,
+}
+
+/// How an [`Offer`] was bincode encoded in a [`Plaintext::Offerv1`] before the compact encoding.
+/// It has no locktime.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+struct LegacyOffer {
+    inputs: Vec<SignedInput>,
+    change: Option<Change>,
+    choose_right: bool,
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    value: Amount,
+}
+
+impl From<LegacyOffer> for Offer {
+    fn from(offer: LegacyOffer) -> Self {
+        Offer {
+            inputs: offer.inputs,
+            change: offer.change,
+            choose_right: offer.choose_right,
+            value: offer.value,
+            locktime: 0,
+        }
+    }
 }
 
 pub struct ValidatedOffer {
@@ -37,6 +63,10 @@ impl ValidatedOffer {
     }
 }
 
+/// Offers are encrypted in the compact v2 encoding after this byte. It can't be the start of a
+/// bincode encoded [`Plaintext`] since the variant index would have to be over 250.
+const OFFER_V2_TAG: u8 = 0xf0;
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
 pub struct Ciphertext {
     pub public_key: Point<EvenY>,
@@ -49,7 +79,14 @@ impl Ciphertext {
         cipher: &mut impl StreamCipher,
         plaintext: Plaintext,
     ) -> Self {
-        let mut encrypted_bytes = crate::encode::serialize(&plaintext);
+        let mut encrypted_bytes = match &plaintext {
+            Plaintext::Offerv1 { offer, message } => {
+                let mut bytes = vec![OFFER_V2_TAG];
+                bytes.append(&mut compact::encode_offer(offer, message.as_deref()));
+                bytes
+            }
+            _ => crate::encode::serialize(&plaintext),
+        };
         cipher.apply_keystream(&mut encrypted_bytes);
         Self {
             public_key,
@@ -82,10 +119,30 @@ impl Ciphertext {
     pub fn decrypt(&self, cipher: &mut impl StreamCipher) -> anyhow::Result<Plaintext> {
         let mut plaintext = self.encrypted_bytes.clone();
         cipher.apply_keystream(&mut plaintext);
+        if plaintext.first() == Some(&OFFER_V2_TAG) {
+            let (offer, message) = compact::decode_offer(&plaintext[1..])?;
+            return Ok(Plaintext::Offerv1 { offer, message });
+        }
+        // variant 0 is Plaintext::Offerv1 which only older versions encode this way
+        if plaintext.first() == Some(&0) {
+            let (offer, message) =
+                crate::encode::deserialize::<(LegacyOffer, Option<String>)>(&plaintext[1..])?;
+            return Ok(Plaintext::Offerv1 {
+                offer: offer.into(),
+                message,
+            });
+        }
         Ok(crate::encode::deserialize::<Plaintext>(&plaintext)?)
     }
 }
 
+/// Offers we've received for one of our proposals but not taken. They're kept as they were sent
+/// and decrypted again when needed.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ProposalOffers {
+    pub offers: Vec<Ciphertext>,
+}
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
 pub enum Plaintext {
     Offerv1 {
@@ -93,6 +150,18 @@ pub enum Plaintext {
         message: Option<String>,
     },
     Messagev1(String),
+    /// A request to cancel a bet by refunding both parties. The PSBT has the sender's signature.
+    CancelRequestv1 {
+        psbt: Vec<u8>,
+        message: Option<String>,
+    },
+    /// A message in the thread about a bet. `mac` authenticates the `seq` and `text` as coming
+    /// from the counterparty (see [`BetThread`]).
+    BetMessagev1 {
+        seq: u32,
+        text: String,
+        mac: [u8; 32],
+    },
 }
 
 impl Plaintext {
@@ -146,6 +215,7 @@ mod test {
                 change: None,
                 choose_right: false,
                 value: Amount::from_str_with_denomination("1 BTC").unwrap(),
+                locktime: 0,
             },
         )
     }
@@ -198,6 +268,42 @@ mod test {
         }
     }
 
+    #[test]
+    fn json_offer_keeps_locktime() {
+        let (_, mut offer) = test_offer();
+        offer.locktime = 700_000;
+        let json = serde_json::to_string(&offer).unwrap();
+        assert_eq!(serde_json::from_str::<Offer>(&json).unwrap(), offer);
+    }
+
+    #[test]
+    fn v1_offers_still_decrypt() {
+        let (public_key, offer) = test_offer();
+        let mut cipher1 = ChaCha20::new(&[2u8; 32].into(), &[2u8; 12].into());
+        let mut cipher2 = ChaCha20::new(&[2u8; 32].into(), &[2u8; 12].into());
+        let legacy_offer = LegacyOffer {
+            inputs: offer.inputs.clone(),
+            change: offer.change.clone(),
+            choose_right: offer.choose_right,
+            value: offer.value,
+        };
+        let plaintext = Plaintext::Offerv1 {
+            offer,
+            message: Some("hello".into()),
+        };
+        let mut encrypted_bytes = vec![0];
+        encrypted_bytes.append(&mut crate::encode::serialize(&(
+            legacy_offer,
+            Some("hello".to_string()),
+        )));
+        cipher1.apply_keystream(&mut encrypted_bytes);
+        let v1 = Ciphertext {
+            public_key,
+            encrypted_bytes,
+        };
+        assert_eq!(v1.decrypt(&mut cipher2).unwrap(), plaintext);
+    }
+
     #[test]
     pub fn encrypt_decrypt_padded_offer_of_different_sizes() {
         let (public_key, offer) = test_offer();
@@ -3,6 +3,7 @@ use std::str::FromStr;
 use crate::{
     betting::*,
     change::{BinScript, Change},
+    keychain::{DerivationVersion, KeyOrigin},
 };
 use anyhow::anyhow;
 use bdk::bitcoin::{self, Amount};
@@ -30,8 +31,21 @@ pub struct Proposal {
     pub inputs: Vec<bdk::bitcoin::OutPoint>,
     pub public_key: Point<EvenY>,
     pub change_script: Option<BinScript>,
+    /// The height after which the two parties can spend the bet output together if the oracle never
+    /// attests.
+    ///
+    /// This is skipped when it's not there so proposals without it derive the same keys as before.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refund_after: Option<u32>,
 }
 
+/// Marks the payload as being in the compact v2 encoding. It's its own segment before the payload
+/// since a v1 payload is never this short.
+const PAYLOAD_V2: &str = "2";
+
+/// Heights at or above this are interpreted as unix times by `OP_CHECKLOCKTIMEVERIFY`.
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
 impl Proposal {
     pub fn into_versioned(self) -> VersionedProposal {
         VersionedProposal::One(self)
@@ -45,6 +59,28 @@ pub struct LocalProposal {
     pub change: Option<Change>,
     #[serde(default)]
     pub tags: Vec<String>,
+    /// How the proposal's key was derived
+    #[serde(default)]
+    pub derivation: DerivationVersion,
+    /// The index of the protocol secret the proposal's key was derived from
+    #[serde(default)]
+    pub secret: u32,
+    /// Change that was too small to be worth an output so it goes to the bet tx's fee
+    #[serde(
+        default,
+        with = "bitcoin::util::amount::serde::as_sat::opt",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub dust_fee: Option<Amount>,
+}
+
+impl LocalProposal {
+    pub fn key_origin(&self) -> KeyOrigin {
+        KeyOrigin {
+            secret: self.secret,
+            derivation: self.derivation,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
@@ -74,16 +110,22 @@ impl core::fmt::Display for VersionedProposal {
                 };
                 write!(
                     f,
-                    "{}#{}#{}#{}",
+                    "{}#{}#{}#{}#{}",
                     proposal
                         .value
                         .to_string_in(bitcoin::Denomination::Bitcoin)
-                        // FIXME: This looks dangerous?
-                        .trim_end_matches('0'),
+                        // there's always a decimal point so only fractional zeros are trimmed
+                        .trim_end_matches('0')
+                        .trim_end_matches('.'),
                     proposal.oracle,
                     proposal.event_id,
-                    crate::encode::serialize_base2048(&payload)
-                )
+                    PAYLOAD_V2,
+                    base2048::encode(&compact::encode_payload(&payload))
+                )?;
+                if let Some(refund_after) = proposal.refund_after {
+                    write!(f, "#{}", refund_after)?;
+                }
+                Ok(())
             }
         }
     }
@@ -103,11 +145,32 @@ impl FromStr for VersionedProposal {
             .ok_or(anyhow!("missing oralce"))?
             .to_string();
         let event_id = EventId::from_str(segments.next().ok_or(anyhow!("missing event id"))?)?;
-        let base2048_encoded_payload = segments
+        let mut base2048_encoded_payload = segments
             .next()
             .ok_or(anyhow!("missing base2048 encoded data"))?;
 
-        let payload: Payload = crate::encode::deserialize_base2048(base2048_encoded_payload)?;
+        let payload: Payload = if base2048_encoded_payload == PAYLOAD_V2 {
+            base2048_encoded_payload = segments
+                .next()
+                .ok_or(anyhow!("missing base2048 encoded data"))?;
+            let bytes = base2048::decode(base2048_encoded_payload)
+                .ok_or(crate::encode::DecodeError::Base2048)?;
+            compact::decode_payload(&bytes)?
+        } else {
+            crate::encode::deserialize_base2048(base2048_encoded_payload)?
+        };
+
+        let refund_after = match segments.next() {
+            Some(height) => {
+                let height = u32::from_str(height)
+                    .map_err(|_| anyhow!("refund height '{}' is not a block height", height))?;
+                if height >= LOCKTIME_THRESHOLD {
+                    return Err(anyhow!("refund height {} is too large", height));
+                }
+                Some(height)
+            }
+            None => None,
+        };
 
         Ok(VersionedProposal::One(Proposal {
             oracle,
@@ -116,6 +179,7 @@ impl FromStr for VersionedProposal {
             inputs: payload.inputs,
             public_key: payload.public_key,
             change_script: payload.change_script,
+            refund_after,
         }))
     }
 }
@@ -143,6 +207,7 @@ mod test {
             ],
             public_key: forty_two,
             change_script: None,
+            refund_after: None,
         };
 
         let encoded = proposal.clone().into_versioned().to_string();
@@ -154,6 +219,15 @@ mod test {
         let encoded = proposal.clone().into_versioned().to_string();
         let decoded = VersionedProposal::from_str(&encoded).unwrap();
         assert_eq!(proposal, decoded.into());
+
+        proposal.refund_after = Some(800_000);
+
+        let encoded = proposal.clone().into_versioned().to_string();
+        assert!(encoded.ends_with("#800000"));
+        let decoded = VersionedProposal::from_str(&encoded).unwrap();
+        assert_eq!(proposal, decoded.into());
+
+        assert!(VersionedProposal::from_str(&format!("{}0000", encoded)).is_err());
     }
 
     #[test]
@@ -179,11 +253,16 @@ mod test {
                     .script_pubkey()
                     .into(),
             ),
+            refund_after: None,
         });
 
+        // v1 strings are still parsed but we now write them in v2
         let string =  "0.01#h00.ooo#/EPL/match/2021-08-22/ARS_CHE.vs=CHE_win#Ō࿃Ŵઝঢჰ௵ʏఒଊಫ୵ช༨ɽબവഉబ٧থǀചµǃȅАǊëঌฉѝႡѧ८ಜԺȯǱ૭զӌભഅಮਫႫႿÅąउȆѡԯӂՃĵ࿐ৠĺ৷เшრఠՁ༎";
         dbg!(&fixed.to_string());
         assert_eq!(VersionedProposal::from_str(string).unwrap(), fixed);
-        assert_eq!(fixed.to_string(), string);
+        let v2_string = fixed.to_string();
+        assert!(v2_string.contains("#2#"));
+        assert!(v2_string.chars().count() < string.chars().count());
+        assert_eq!(VersionedProposal::from_str(&v2_string).unwrap(), fixed);
     }
 }
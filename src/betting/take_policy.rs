@@ -0,0 +1,138 @@
+use crate::betting::Bet;
+use anyhow::anyhow;
+use bdk::bitcoin::Amount;
+use std::{fmt, str::FromStr};
+
+/// Which offers `gun bet take-from-stdin` takes without asking. An offer is only taken if it
+/// passes every check.
+#[derive(Clone, Debug)]
+pub struct TakePolicy {
+    /// The most we're willing to put into a single bet
+    pub max_risk: Amount,
+    /// The bound on what the counterparty puts in for each sat we put in
+    pub odds: OddsBound,
+    /// Only take bets on events from these oracles (any oracle if empty)
+    pub oracles: Vec<String>,
+    /// Only take bets on events whose id starts with this
+    pub event_prefix: Option<String>,
+}
+
+impl TakePolicy {
+    /// Checks a validated offer against the policy. The error is the reason it was rejected.
+    pub fn check(&self, bet: &Bet) -> Result<(), String> {
+        if bet.local_value > self.max_risk {
+            return Err(format!(
+                "it risks {} which is more than {}",
+                bet.local_value, self.max_risk
+            ));
+        }
+
+        let their_value = bet.joint_output_value - bet.local_value;
+        let odds = their_value.as_sat() as f64 / bet.local_value.as_sat() as f64;
+        if !self.odds.accepts(odds) {
+            return Err(format!("its odds of {:.3} are not {}", odds, self.odds));
+        }
+
+        if !self.oracles.is_empty() && !self.oracles.contains(&bet.oracle_id) {
+            return Err(format!("{} is not an accepted oracle", bet.oracle_id));
+        }
+
+        if let Some(prefix) = &self.event_prefix {
+            let event_id = bet.oracle_event.event.id.to_string();
+            if !event_id.starts_with(prefix.as_str()) {
+                return Err(format!("{} doesn't start with {}", event_id, prefix));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A bound on odds written like `>=1.0`. A bare number means `>=`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OddsBound {
+    pub cmp: OddsCmp,
+    pub value: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OddsCmp {
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl OddsBound {
+    pub fn accepts(&self, odds: f64) -> bool {
+        match self.cmp {
+            OddsCmp::Gt => odds > self.value,
+            OddsCmp::Ge => odds >= self.value,
+            OddsCmp::Lt => odds < self.value,
+            OddsCmp::Le => odds <= self.value,
+        }
+    }
+}
+
+impl FromStr for OddsBound {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> anyhow::Result<Self> {
+        let (cmp, value) = if let Some(value) = string.strip_prefix(">=") {
+            (OddsCmp::Ge, value)
+        } else if let Some(value) = string.strip_prefix("<=") {
+            (OddsCmp::Le, value)
+        } else if let Some(value) = string.strip_prefix('>') {
+            (OddsCmp::Gt, value)
+        } else if let Some(value) = string.strip_prefix('<') {
+            (OddsCmp::Lt, value)
+        } else {
+            (OddsCmp::Ge, string)
+        };
+        let value = f64::from_str(value.trim())
+            .map_err(|_| anyhow!("'{}' is not an odds bound like >=1.0", string))?;
+        if !value.is_finite() || value < 0.0 {
+            return Err(anyhow!("odds must be a positive number"));
+        }
+        Ok(OddsBound { cmp, value })
+    }
+}
+
+impl fmt::Display for OddsBound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cmp = match self.cmp {
+            OddsCmp::Gt => ">",
+            OddsCmp::Ge => ">=",
+            OddsCmp::Lt => "<",
+            OddsCmp::Le => "<=",
+        };
+        write!(f, "{}{}", cmp, self.value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_odds_bound() {
+        let bound = OddsBound::from_str(">=1.0").unwrap();
+        assert_eq!(
+            bound,
+            OddsBound {
+                cmp: OddsCmp::Ge,
+                value: 1.0
+            }
+        );
+        assert!(bound.accepts(1.0));
+        assert!(!bound.accepts(0.99));
+
+        let bound = OddsBound::from_str("<2").unwrap();
+        assert!(bound.accepts(1.5));
+        assert!(!bound.accepts(2.0));
+
+        assert_eq!(OddsBound::from_str("1.5").unwrap().cmp, OddsCmp::Ge);
+        assert!(OddsBound::from_str(">=x").is_err());
+        assert!(OddsBound::from_str("-1").is_err());
+    }
+}
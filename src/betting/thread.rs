@@ -0,0 +1,73 @@
+use bdk::bitcoin::hashes::{sha256, Hash, HashEngine, Hmac, HmacEngine};
+use olivia_secp256k1::fun::Point;
+
+/// The messages sent and received about a bet.
+///
+/// Each direction numbers its messages from zero so the transcript can be put back in order and
+/// gaps and replays noticed. Every message has a MAC over its number and text keyed by a secret
+/// only the two parties of the bet can compute.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BetThread {
+    pub messages: Vec<BetMessage>,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BetMessage {
+    pub seq: u32,
+    /// Whether we sent it
+    pub sent: bool,
+    pub text: String,
+    /// When we sent or received it (unix time)
+    pub time: u64,
+}
+
+impl BetThread {
+    /// The sequence number the next message in a direction should have.
+    pub fn next_seq(&self, sent: bool) -> u32 {
+        self.messages
+            .iter()
+            .filter(|message| message.sent == sent)
+            .map(|message| message.seq + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn has_received(&self, seq: u32) -> bool {
+        self.messages
+            .iter()
+            .any(|message| !message.sent && message.seq == seq)
+    }
+}
+
+/// The MAC for a message sent by the owner of `sender`.
+pub fn bet_message_mac(mac_key: &[u8; 32], sender: &Point, seq: u32, text: &str) -> [u8; 32] {
+    let mut engine = HmacEngine::<sha256::Hash>::new(&mac_key[..]);
+    engine.input(&sender.to_bytes());
+    engine.input(&seq.to_be_bytes());
+    engine.input(text.as_bytes());
+    Hmac::<sha256::Hash>::from_engine(engine).into_inner()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn next_seq_per_direction() {
+        let message = |seq, sent| BetMessage {
+            seq,
+            sent,
+            text: "hi".into(),
+            time: 0,
+        };
+        let mut thread = BetThread::default();
+        assert_eq!(thread.next_seq(true), 0);
+        thread.messages.push(message(0, true));
+        thread.messages.push(message(1, true));
+        thread.messages.push(message(0, false));
+        assert_eq!(thread.next_seq(true), 2);
+        assert_eq!(thread.next_seq(false), 1);
+        assert!(thread.has_received(0));
+        assert!(!thread.has_received(1));
+    }
+}
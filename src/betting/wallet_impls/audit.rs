@@ -0,0 +1,127 @@
+use crate::{
+    betting::*,
+    wallet::{cached_oracle_event, GunWallet},
+    OracleInfo,
+};
+use anyhow::anyhow;
+use bdk::{
+    bitcoin::{Transaction, TxOut},
+    database::Database,
+};
+
+impl GunWallet {
+    /// Put together the audit trail of a bet the oracle has attested to (see [`AuditBundle`]).
+    pub fn audit_bundle(&self, bet_id: BetId) -> anyhow::Result<AuditBundle> {
+        let gun_db = self.gun_db();
+        let bet_state = gun_db
+            .get_entity::<BetState>(bet_id)?
+            .ok_or(anyhow!("Bet {} doesn't exist", bet_id))?;
+        let (bet, attestation, claim_txid) = match bet_state {
+            BetState::Won {
+                bet, attestation, ..
+            }
+            | BetState::Lost { bet, attestation } => (bet, attestation, None),
+            BetState::Claimed {
+                bet,
+                attestation,
+                txid,
+                ..
+            } => (bet, attestation, Some(txid)),
+            bet_state => {
+                return Err(anyhow!(
+                    "bet {} is {} but only bets the oracle has attested to can be audited",
+                    bet_id,
+                    bet_state.name()
+                ))
+            }
+        };
+
+        let oracle_info = gun_db
+            .get_entity::<OracleInfo>(bet.oracle_id.clone())?
+            .ok_or(anyhow!("oracle {} isn't in the database", bet.oracle_id))?;
+        let event_url = gun_db.oracle_event_url(&bet.oracle_id, &bet.oracle_event.event.id);
+        let announcement =
+            cached_oracle_event(gun_db, &event_url, false)?.map(|response| response.announcement);
+
+        let database = self.bdk_wallet().database();
+        let raw_tx =
+            |txid| -> anyhow::Result<Option<Transaction>> { Ok(database.get_raw_tx(&txid)?) };
+        // the wallet's copy has the witnesses of both sides
+        let bet_tx = raw_tx(bet.tx().txid())?.unwrap_or_else(|| bet.tx());
+        let bet_prevouts = bet
+            .psbt
+            .inputs
+            .iter()
+            .enumerate()
+            .map(|(i, input)| {
+                input
+                    .witness_utxo
+                    .clone()
+                    .ok_or(anyhow!("the bet tx's input {} is missing its utxo", i))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let (claim_tx, claim_prevouts) = match claim_txid {
+            Some(txid) => {
+                let claim_tx = raw_tx(txid)?.ok_or(anyhow!(
+                    "the claim tx {} isn't in the wallet. Sync and try again.",
+                    txid
+                ))?;
+                let claim_prevouts = claim_tx
+                    .input
+                    .iter()
+                    .map(|txin| {
+                        let outpoint = txin.previous_output;
+                        let prev_tx = if outpoint.txid == bet_tx.txid() {
+                            Some(bet_tx.clone())
+                        } else {
+                            raw_tx(outpoint.txid)?
+                        };
+                        prev_tx
+                            .and_then(|prev_tx| prev_tx.output.get(outpoint.vout as usize).cloned())
+                            .ok_or(anyhow!(
+                                "can't find the output {} the claim spends",
+                                outpoint
+                            ))
+                    })
+                    .collect::<anyhow::Result<Vec<TxOut>>>()?;
+                (Some(claim_tx), claim_prevouts)
+            }
+            None => (None, vec![]),
+        };
+
+        let we_proposed = matches!(bet.joint_output.my_key, Either::Left(_));
+        let ours = AuditParty {
+            value: bet.local_value,
+            inputs: bet.my_inputs(),
+            choose_right: bet.i_chose_right,
+        };
+        let theirs = AuditParty {
+            value: bet.joint_output_value - bet.local_value,
+            inputs: bet.their_inputs(),
+            choose_right: !bet.i_chose_right,
+        };
+        let (proposer, offerer) = if we_proposed {
+            (ours, theirs)
+        } else {
+            (theirs, ours)
+        };
+
+        Ok(AuditBundle {
+            network: self.bdk_wallet().network(),
+            oracle_info,
+            oracle_event: bet.oracle_event.clone(),
+            announcement,
+            attestation,
+            proposer,
+            offerer,
+            we_proposed,
+            joint_output: bet.joint_output.public(),
+            bet_tx,
+            bet_prevouts,
+            vout: bet.vout,
+            claim_tx,
+            claim_prevouts,
+        })
+    }
+}
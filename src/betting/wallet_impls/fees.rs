@@ -0,0 +1,125 @@
+use super::refund::fee_share;
+use crate::{betting::*, wallet::GunWallet};
+use bdk::{
+    bitcoin::{Amount, Txid},
+    TransactionDetails,
+};
+
+impl GunWallet {
+    fn tx_details(&self, txid: Txid) -> anyhow::Result<Option<TransactionDetails>> {
+        Ok(self
+            .bdk_wallet()
+            .list_transactions(false)?
+            .into_iter()
+            .find(|details| details.txid == txid))
+    }
+
+    /// What we paid in fees for a tx spending bet outputs. `stake` is what we were owed from this
+    /// bet's output and `stakes` the total owed to us from all the bet outputs it spends.
+    fn bet_spend_fee(&self, txid: Txid, stake: u64, stakes: u64) -> anyhow::Result<Option<Amount>> {
+        Ok(self.tx_details(txid)?.map(|details| {
+            let fee = (stakes + details.sent).saturating_sub(details.received);
+            Amount::from_sat(fee_share(fee, stake, stakes))
+        }))
+    }
+
+    /// Record what the bet's transactions cost us once they've confirmed. Fees for transactions
+    /// the wallet hasn't synced yet are left for next time.
+    pub fn record_bet_fees(&self, bet_id: BetId) -> anyhow::Result<()> {
+        let gun_db = self.gun_db();
+        let bet_state = match gun_db.get_entity::<BetState>(bet_id)? {
+            Some(bet_state) => bet_state,
+            None => return Ok(()),
+        };
+        let mut fees = gun_db.get_entity::<BetFees>(bet_id)?.unwrap_or_default();
+        let old_fees = fees.clone();
+
+        let confirmed_bet = match &bet_state {
+            BetState::Included {
+                bet,
+                height: Some(_),
+            }
+            | BetState::Won { bet, .. }
+            | BetState::Lost { bet, .. }
+            | BetState::Claimed { bet, .. }
+            | BetState::Refunded { bet, .. } => Some(bet),
+            _ => None,
+        };
+
+        if let (Some(bet), None) = (confirmed_bet, fees.funding) {
+            // we sent our inputs and got back our change. The rest is our stake and share of the fee.
+            fees.funding = self.tx_details(bet.tx().txid())?.map(|details| {
+                Amount::from_sat(
+                    details
+                        .sent
+                        .saturating_sub(details.received)
+                        .saturating_sub(bet.local_value.as_sat()),
+                )
+            });
+        }
+
+        match &bet_state {
+            BetState::Claimed {
+                bet,
+                txid,
+                height: Some(_),
+                ..
+            } if fees.claim.is_none() => {
+                // more than one bet can be claimed in the same tx
+                let stakes = gun_db
+                    .list_entities_print_error::<BetState>()
+                    .filter_map(|(_, bet_state)| match bet_state {
+                        BetState::Claimed {
+                            bet, txid: other, ..
+                        } if other == *txid => Some(bet.joint_output_value.as_sat()),
+                        _ => None,
+                    })
+                    .sum::<u64>()
+                    .max(bet.joint_output_value.as_sat());
+                fees.claim = self.bet_spend_fee(*txid, bet.joint_output_value.as_sat(), stakes)?;
+            }
+            BetState::Refunded {
+                bet,
+                txid,
+                height: Some(_),
+            } if fees.claim.is_none() => {
+                let stake = bet.local_value.as_sat();
+                fees.claim = self.bet_spend_fee(*txid, stake, stake)?;
+            }
+            BetState::Canceled {
+                cancel_txid,
+                height: Some(_),
+                i_intend_cancel,
+                ..
+            } if fees.cancel.is_none() => {
+                if !i_intend_cancel {
+                    fees.cancel = Some(Amount::ZERO);
+                } else if let Some(fee) = self
+                    .tx_details(*cancel_txid)?
+                    .and_then(|details| details.fee)
+                {
+                    // `gun bet cancel` can cancel several bets with one tx
+                    let n_canceled = gun_db
+                        .list_entities_print_error::<BetState>()
+                        .filter(|(_, bet_state)| match bet_state {
+                            BetState::Canceled {
+                                cancel_txid: other,
+                                i_intend_cancel,
+                                ..
+                            } => other == cancel_txid && *i_intend_cancel,
+                            _ => false,
+                        })
+                        .count()
+                        .max(1) as u64;
+                    fees.cancel = Some(Amount::from_sat(fee / n_canceled));
+                }
+            }
+            _ => {}
+        }
+
+        if fees != old_fees {
+            gun_db.insert_entity(bet_id, fees)?;
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,12 @@
+mod audit;
+mod fees;
 mod offer;
 mod proposal;
+mod refund;
 mod spend_won;
 mod state_machine;
 mod take_offer;
+mod thread;
+
+pub(crate) use spend_won::add_bet_output;
+pub use state_machine::Prefetched;
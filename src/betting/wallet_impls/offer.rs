@@ -1,7 +1,13 @@
-use crate::{betting::*, change::Change, wallet::GunWallet, ValueChoice};
+use crate::{
+    betting::*,
+    change::{Change, MAX_DUST_THRESHOLD},
+    script_kind,
+    wallet::GunWallet,
+    ValueChoice,
+};
 use anyhow::{anyhow, Context};
 use bdk::{
-    bitcoin::Amount,
+    bitcoin::{Amount, Script},
     miniscript::DescriptorTrait,
     wallet::{coin_selection::LargestFirstCoinSelection, tx_builder::TxOrdering, IsDust},
     SignOptions,
@@ -50,6 +56,7 @@ impl GunWallet {
             anticipated_attestations,
             choose_right,
             randomize,
+            proposal.refund_after,
         );
 
         let mut builder = self
@@ -72,22 +79,22 @@ impl GunWallet {
             }
         }
 
-        fee_spec.apply_to_builder(self.bdk_wallet().client(), &mut builder)?;
+        fee_spec.apply_to_builder(self, &mut builder)?;
+        // the proposer learns the locktime from the offer
+        let tx_locktime = self.apply_locktime_policy(&mut builder)?;
 
         args.apply_args(self.gun_db(), &mut builder)?;
 
         let mut input_value = 0;
         for proposal_input in &proposal.inputs {
             let psbt_input = self
-                .p2wpkh_outpoint_to_psbt_input(*proposal_input)
+                .outpoint_to_psbt_input(*proposal_input)
                 .context("retrieving proposal input")?;
-            input_value += psbt_input.witness_utxo.as_ref().unwrap().value;
-            builder.add_foreign_utxo(
-                *proposal_input,
-                psbt_input,
-                // p2wpkh wieght
-                4 + 1 + 73 + 33,
-            )?;
+            let txout = psbt_input.witness_utxo.as_ref().unwrap();
+            input_value += txout.value;
+            let weight = satisfaction_weight(&txout.script_pubkey)
+                .expect("outpoint_to_psbt_input only returns inputs we can spend");
+            builder.add_foreign_utxo(*proposal_input, psbt_input, weight)?;
         }
 
         let proposal_excess = input_value
@@ -99,16 +106,47 @@ impl GunWallet {
             ))?;
 
         if !proposal_excess.is_dust() {
-            let change_script = proposal.change_script.as_ref().ok_or(anyhow!(
-                "proposal had excess coins but did not provide change address"
-            ))?;
-            builder.add_recipient(change_script.clone().into(), proposal_excess);
+            match proposal.change_script.as_ref() {
+                Some(change_script) => {
+                    let change_script = Script::from(change_script.clone());
+                    script_kind::check_recipient(&change_script)
+                        .context("the proposal's change")?;
+                    builder.add_recipient(change_script, proposal_excess);
+                }
+                // the proposer's dust threshold is higher than the default so it goes to the fee
+                None if proposal_excess <= MAX_DUST_THRESHOLD => {}
+                None => {
+                    return Err(anyhow!(
+                        "proposal had excess coins but did not provide change address"
+                    ))
+                }
+            }
         }
 
-        let (psbt, _tx_details) = builder
+        let (mut psbt, _tx_details) = builder
             .finish()
             .context("Unable to create offer transaction")?;
 
+        // bdk only leaves out change below the standard dust limit but ours may be higher
+        let mut dust_fee = None;
+        for (i, txout) in psbt.unsigned_tx.output.iter().enumerate() {
+            if txout.script_pubkey != output_script
+                && self.bdk_wallet().is_mine(&txout.script_pubkey)?
+                && self.is_dust_change(Amount::from_sat(txout.value))
+            {
+                dust_fee = Some((i, Amount::from_sat(txout.value)));
+                break;
+            }
+        }
+        let dust_fee = dust_fee.map(|(i, value)| {
+            psbt.unsigned_tx.output.remove(i);
+            psbt.outputs.remove(i);
+            value
+        });
+
+        fee_spec.check_psbt(&psbt)?;
+        self.record_locktime(&psbt, tx_locktime)?;
+
         // the inputs we own have witnesses
         let my_input_indexes = psbt
             .unsigned_tx
@@ -141,6 +179,14 @@ impl GunWallet {
             joint_output_value,
             i_chose_right: choose_right,
             tags: args.tags,
+            fingerprint: Some(BetFingerprint::new(
+                &proposal.public_key,
+                &local_keypair.public_key,
+                &proposal.event_id,
+            )),
+            derivation: keychain.origin().derivation,
+            secret: keychain.origin().secret,
+            dust_fee,
         };
 
         Ok((bet, local_keypair.public_key, cipher))
@@ -175,10 +221,16 @@ impl GunWallet {
                     .clone()
                     .expect("we added this input so we should have signed it");
 
+                let script_pubkey = &psbt_input
+                    .witness_utxo
+                    .as_ref()
+                    .expect("we added this input so it has the utxo")
+                    .script_pubkey;
+
                 SignedInput {
                     outpoint: txin.previous_output,
-                    witness: Witness::decode_p2wpkh(witness)
-                        .expect("we signed it so it must be p2wpkh"),
+                    witness: Witness::decode(witness, script_pubkey)
+                        .expect("we signed it so it must be p2wpkh or p2tr"),
                 }
             })
             .collect();
@@ -196,6 +248,7 @@ impl GunWallet {
             inputs: signed_inputs,
             choose_right: bet.i_chose_right,
             value: bet.local_value,
+            locktime: bet.psbt.unsigned_tx.lock_time,
         };
 
         let encrypted_offer = Ciphertext::create(
@@ -206,10 +259,14 @@ impl GunWallet {
                 message,
             },
         );
+        let fingerprint = bet.fingerprint;
         let bet_id = self.gun_db().insert_bet(BetState::Offered {
             bet: OfferedBet(bet),
             encrypted_offer: encrypted_offer.clone(),
         })?;
+        if let Some(fingerprint) = fingerprint {
+            self.gun_db().index_bet_fingerprint(fingerprint, bet_id)?;
+        }
         Ok((bet_id, encrypted_offer, offer))
     }
 }
@@ -1,20 +1,75 @@
-use crate::{betting::*, change::Change, keychain::Keychain, wallet::GunWallet, ValueChoice};
+use crate::{
+    betting::*,
+    change::{Change, DEFAULT_DUST_THRESHOLD},
+    keychain::Keychain,
+    utxo_snapshot::UtxoSnapshot,
+    wallet::GunWallet,
+    ValueChoice,
+};
 use anyhow::{anyhow, Context};
 use bdk::{
     bitcoin::{Amount, Script},
+    database::BatchDatabase,
     wallet::coin_selection::LargestFirstCoinSelection,
-    FeeRate,
+    FeeRate, KeychainKind, Wallet,
 };
 use olivia_core::{OracleEvent, OracleId};
 use olivia_secp256k1::Secp256k1;
 
 impl GunWallet {
+    /// Save a proposal that's going to be published. Its change address is recorded as exposed
+    /// (see [`crate::gap`]).
+    pub fn insert_proposal(&self, local_proposal: LocalProposal) -> anyhow::Result<BetId> {
+        let change = local_proposal.change.clone();
+        let bet_id = self
+            .gun_db()
+            .insert_bet(BetState::Proposed { local_proposal })?;
+        if let Some(change) = change {
+            self.expose_change(bet_id, change.script())?;
+        }
+        Ok(bet_id)
+    }
+
+    /// Make a proposal choosing inputs from the wallet's coins or from `utxo_snapshot` if the
+    /// wallet is offline.
     pub fn make_proposal(
         &self,
         oracle_id: OracleId,
         oracle_event: OracleEvent<Secp256k1>,
         args: BetArgs,
         keychain: &Keychain,
+        utxo_snapshot: Option<&UtxoSnapshot>,
+    ) -> anyhow::Result<LocalProposal> {
+        match utxo_snapshot {
+            Some(utxo_snapshot) => {
+                let bdk_wallet = self.bdk_wallet();
+                let snapshot_wallet = Wallet::new_offline(
+                    &bdk_wallet
+                        .get_descriptor_for_keychain(KeychainKind::External)
+                        .to_string(),
+                    Some(
+                        &bdk_wallet
+                            .get_descriptor_for_keychain(KeychainKind::Internal)
+                            .to_string(),
+                    ),
+                    bdk_wallet.network(),
+                    utxo_snapshot.memory_db()?,
+                )?;
+                self.make_proposal_from(&snapshot_wallet, oracle_id, oracle_event, args, keychain)
+            }
+            None => {
+                self.make_proposal_from(self.bdk_wallet(), oracle_id, oracle_event, args, keychain)
+            }
+        }
+    }
+
+    fn make_proposal_from<D: BatchDatabase>(
+        &self,
+        bdk_wallet: &Wallet<(), D>,
+        oracle_id: OracleId,
+        oracle_event: OracleEvent<Secp256k1>,
+        args: BetArgs,
+        keychain: &Keychain,
     ) -> anyhow::Result<LocalProposal> {
         let event_id = &oracle_event.event.id;
         if event_id.n_outcomes() != 2 {
@@ -24,8 +79,7 @@ impl GunWallet {
             ));
         }
 
-        let mut builder = self
-            .bdk_wallet()
+        let mut builder = bdk_wallet
             .build_tx()
             .coin_selection(LargestFirstCoinSelection);
         // we use a 0 feerate because the offerer will pay the fee
@@ -44,10 +98,11 @@ impl GunWallet {
             .finish()
             .context("Failed to gather proposal outputs")?;
 
+        // The tx fee *should* be nothing but it's possible the bet value is so close to the
+        // UTXO value that it gets added to fee rather than creating a dust output.
+        let mut dust_fee = txdetails.fee.unwrap();
         debug_assert!(
-            // The tx fee *should* be nothing but it's possible the bet value is so close to the
-            // UTXO value that it gets added to fee rather than creating a dust output.
-            txdetails.fee.unwrap() < 546,
+            dust_fee <= DEFAULT_DUST_THRESHOLD,
             "the fee should only be there if it's dust"
         );
 
@@ -67,7 +122,7 @@ impl GunWallet {
                 .value,
         );
 
-        let change = if outputs.len() > 1 {
+        let mut change = if outputs.len() > 1 {
             if outputs.len() != 2 {
                 return Err(anyhow!(
                     "wallet produced psbt with too many outputs: {:?}",
@@ -85,6 +140,15 @@ impl GunWallet {
             None
         };
 
+        // bdk only leaves out change below the standard dust limit but ours may be higher
+        if let Some(dust) = change
+            .as_ref()
+            .filter(|change| self.is_dust_change(change.value()))
+        {
+            dust_fee += dust.value().as_sat();
+            change = None;
+        }
+
         let mut proposal = Proposal {
             oracle: oracle_id,
             event_id: event_id.clone(),
@@ -92,6 +156,7 @@ impl GunWallet {
             inputs: tx_inputs,
             public_key: crate::placeholder_point(),
             change_script: change.as_ref().map(|x| x.binscript().clone()),
+            refund_after: args.refund_after,
         };
 
         let keypair = keychain.get_key_for_proposal(&proposal);
@@ -102,6 +167,9 @@ impl GunWallet {
             oracle_event,
             change,
             tags: args.tags,
+            derivation: keychain.origin().derivation,
+            secret: keychain.origin().secret,
+            dust_fee: Some(Amount::from_sat(dust_fee)).filter(|dust_fee| *dust_fee > Amount::ZERO),
         };
 
         Ok(local_proposal)
@@ -0,0 +1,433 @@
+use super::spend_won::add_bet_output;
+use crate::{betting::*, elog, keychain::KeyPair, wallet::GunWallet, FeeSpec, MAX_SANE_FEERATE};
+use anyhow::{anyhow, Context};
+use bdk::{
+    bitcoin::{
+        consensus::encode, util::psbt::PartiallySignedTransaction as Psbt, Amount, Script,
+        Transaction, TxOut,
+    },
+    blockchain::Blockchain,
+    wallet::AddressIndex,
+    SignOptions,
+};
+use chacha20::ChaCha20;
+
+impl GunWallet {
+    fn refundable_bet(&self, bet_id: BetId) -> anyhow::Result<(Bet, Refund)> {
+        let bet_state = self
+            .gun_db()
+            .get_entity::<BetState>(bet_id)?
+            .ok_or(anyhow!("Bet {} doesn't exist", bet_id))?;
+        let bet = match bet_state {
+            BetState::Included { bet, .. } => bet,
+            bet_state => {
+                return Err(anyhow!(
+                    "Bet {} can't be refunded because it is in the {} state",
+                    bet_id,
+                    bet_state.name()
+                ))
+            }
+        };
+        let refund = bet.joint_output.refund.clone().ok_or(anyhow!(
+            "Bet {} wasn't proposed with a refund height",
+            bet_id
+        ))?;
+        Ok((bet, refund))
+    }
+
+    /// Make a transaction spending the bet output through its refund branch that pays each party
+    /// back their stake (the counterparty's to `their_script`). The fee is split in proportion to
+    /// the stakes. Its nLockTime is the refund height so it can't be broadcast before then.
+    ///
+    /// The PSBT has our signature so the counterparty can add theirs with [`sign_refund`].
+    ///
+    /// [`sign_refund`]: Self::sign_refund
+    pub fn generate_refund(
+        &self,
+        bet_id: BetId,
+        their_script: Script,
+        fee: FeeSpec,
+    ) -> anyhow::Result<Psbt> {
+        self.generate_cooperative_spend(bet_id, their_script, fee, false)
+    }
+
+    /// Spend the whole bet output to ourselves through the refund branch without the counterparty.
+    /// This is for when they've disappeared so it can't be broadcast before the bet's sweep height.
+    pub fn generate_sweep(&self, bet_id: BetId, fee: FeeSpec) -> anyhow::Result<Psbt> {
+        let (bet, refund) = self.refundable_bet(bet_id)?;
+        let sweep_after = refund.sweep_after.ok_or(anyhow!(
+            "Bet {} was made before a refund could be taken without the counterparty",
+            bet_id
+        ))?;
+        let bdk_wallet = self.bdk_wallet();
+        let mut builder = bdk_wallet.build_tx();
+        builder.manually_selected_only().enable_rbf();
+        builder.nlocktime(sweep_after);
+        fee.apply_to_builder(self, &mut builder)?;
+
+        add_bet_output(&mut builder, &bet);
+        builder.drain_to(
+            bdk_wallet
+                .get_change_address(AddressIndex::New)?
+                .script_pubkey(),
+        );
+
+        let (mut psbt, _) = builder.finish()?;
+        fee.check_psbt(&psbt)?;
+
+        if !self.sign_and_finalize_refund(&bet, &mut psbt)? {
+            return Err(anyhow!(
+                "Bet {} can't be taken without the counterparty until height {}",
+                bet_id,
+                sweep_after
+            ));
+        }
+        Ok(psbt)
+    }
+
+    /// Builds [`generate_refund`]'s transaction. For a `mutual_cancel` nLockTime follows the
+    /// wallet's policy instead of being the refund height so it can be broadcast straight away.
+    ///
+    /// [`generate_refund`]: Self::generate_refund
+    fn generate_cooperative_spend(
+        &self,
+        bet_id: BetId,
+        their_script: Script,
+        fee: FeeSpec,
+        mutual_cancel: bool,
+    ) -> anyhow::Result<Psbt> {
+        let (bet, refund) = self.refundable_bet(bet_id)?;
+        let bdk_wallet = self.bdk_wallet();
+        let mut builder = bdk_wallet.build_tx();
+        builder.manually_selected_only().enable_rbf();
+        let tx_locktime = if mutual_cancel {
+            Some(self.apply_locktime_policy(&mut builder)?)
+        } else {
+            builder.nlocktime(refund.after);
+            None
+        };
+
+        fee.apply_to_builder(self, &mut builder)?;
+
+        add_bet_output(&mut builder, &bet);
+        let their_value = bet.joint_output_value - bet.local_value;
+        builder.add_recipient(their_script.clone(), their_value.as_sat());
+        let our_script = bdk_wallet
+            .get_change_address(AddressIndex::New)?
+            .script_pubkey();
+        builder.drain_to(our_script.clone());
+
+        let (mut psbt, _) = builder.finish()?;
+        fee.check_psbt(&psbt)?;
+
+        // We drained to our output so it paid the whole fee. Take their share out of their output.
+        let outputs = &mut psbt.unsigned_tx.output;
+        let fee_paid = bet
+            .joint_output_value
+            .as_sat()
+            .checked_sub(outputs.iter().map(|output| output.value).sum())
+            .ok_or(anyhow!("The refund spends more than the bet output"))?;
+        let their_share = fee_share(
+            fee_paid,
+            their_value.as_sat(),
+            bet.joint_output_value.as_sat(),
+        );
+        let their_index = outputs
+            .iter()
+            .position(|output| output.script_pubkey == their_script)
+            .ok_or(anyhow!("The refund has no output to the counterparty"))?;
+        let our_index = outputs
+            .iter()
+            .position(|output| output.script_pubkey == our_script)
+            .ok_or(anyhow!(
+                "Our stake in bet {} doesn't cover the refund fee of {} sats",
+                bet_id,
+                fee_paid
+            ))?;
+        let their_refund = outputs[their_index].value;
+        outputs[their_index].value = their_refund.checked_sub(their_share).ok_or(anyhow!(
+            "The counterparty's refund of {} sats doesn't cover their share of the fee ({} sats)",
+            their_refund,
+            their_share
+        ))?;
+        outputs[our_index].value += their_share;
+
+        if let Some(tx_locktime) = tx_locktime {
+            self.record_locktime(&psbt, tx_locktime)?;
+        }
+        self.sign_and_finalize_refund(&bet, &mut psbt)?;
+        Ok(psbt)
+    }
+
+    /// Sign a refund of the bet. This checks it only spends the bet output, that we get back at
+    /// least our stake minus our share of the fee and that the fee is within `fee`'s maximum. It
+    /// won't sign once the oracle has attested to the outcome.
+    ///
+    /// Returns whether the PSBT now has both signatures and can be broadcast (it can't be before
+    /// the refund height).
+    pub fn sign_refund(
+        &self,
+        bet_id: BetId,
+        psbt: &mut Psbt,
+        fee: &FeeSpec,
+    ) -> anyhow::Result<bool> {
+        let (_, refund) = self.refundable_bet(bet_id)?;
+        if psbt.unsigned_tx.lock_time != refund.after {
+            return Err(anyhow!(
+                "The refund's locktime is {} but it should be {}",
+                psbt.unsigned_tx.lock_time,
+                refund.after
+            ));
+        }
+        self.sign_cooperative_spend(bet_id, psbt, fee)
+    }
+
+    /// Sign the transaction from a cancel request. The checks are the same as [`sign_refund`] but
+    /// it can have any nLockTime.
+    ///
+    /// [`sign_refund`]: Self::sign_refund
+    pub fn sign_cancel_request(
+        &self,
+        bet_id: BetId,
+        psbt: &mut Psbt,
+        fee: &FeeSpec,
+    ) -> anyhow::Result<bool> {
+        self.sign_cooperative_spend(bet_id, psbt, fee)
+    }
+
+    fn sign_cooperative_spend(
+        &self,
+        bet_id: BetId,
+        psbt: &mut Psbt,
+        fee: &FeeSpec,
+    ) -> anyhow::Result<bool> {
+        let (bet, _) = self.refundable_bet(bet_id)?;
+        // If the counterparty knows they've lost they might ask for their stake back before we find
+        // out we've won.
+        if let Err(e) = self.try_get_outcome(bet_id, bet.clone()) {
+            elog!(@warning "Couldn't check whether the oracle has attested to bet {}: {}", bet_id, e);
+        }
+        let (bet, _) = self.refundable_bet(bet_id)?;
+        let tx = &psbt.unsigned_tx;
+
+        if tx.input.len() != 1 || tx.input[0].previous_output != bet.outpoint() {
+            return Err(anyhow!(
+                "The refund must only spend the bet output {}",
+                bet.outpoint()
+            ));
+        }
+
+        let mut value_to_us = 0;
+        for output in &tx.output {
+            if self.bdk_wallet().is_mine(&output.script_pubkey)? {
+                value_to_us += output.value;
+            }
+        }
+
+        let satisfaction_weight = bet
+            .joint_output
+            .descriptor()
+            .max_satisfaction_weight()
+            .unwrap();
+        let fee_paid = check_cooperative_spend_fee(
+            tx,
+            bet.joint_output_value.as_sat(),
+            satisfaction_weight,
+            fee,
+        )?;
+        let our_share = fee_share(
+            fee_paid,
+            bet.local_value.as_sat(),
+            bet.joint_output_value.as_sat(),
+        );
+        // allow a sat for rounding
+        if value_to_us + our_share + 1 < bet.local_value.as_sat() {
+            return Err(anyhow!(
+                "The refund only pays us {} sats but we put in {} and our share of the fee is {}",
+                value_to_us,
+                bet.local_value.as_sat(),
+                our_share
+            ));
+        }
+
+        // don't sign for whatever value the counterparty says the bet output has
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: bet.joint_output_value.as_sat(),
+            script_pubkey: bet.joint_output.descriptor().script_pubkey(),
+        });
+        self.sign_and_finalize_refund(&bet, psbt)
+    }
+
+    /// Ask the counterparty to cancel the bet by paying both of you back your stakes. Unlike a
+    /// refund this can be broadcast as soon as they sign it. The request has the PSBT (see
+    /// [`generate_refund`]) encrypted to their refund key.
+    ///
+    /// [`generate_refund`]: Self::generate_refund
+    pub fn generate_cancel_request(
+        &self,
+        bet_id: BetId,
+        their_script: Script,
+        fee: FeeSpec,
+        message: Option<String>,
+    ) -> anyhow::Result<(Ciphertext, ChaCha20)> {
+        let psbt = self.generate_cooperative_spend(bet_id, their_script, fee, true)?;
+        let (bet, _) = self.refundable_bet(bet_id)?;
+        let (_, theirs) = bet
+            .joint_output
+            .my_and_their_bet_keys()
+            .expect("bets with a refund have the keys");
+        // a fresh key for each request so no two are encrypted with the same keystream
+        let ephemeral = KeyPair::from_slice(&rand::random::<[u8; 32]>()).expect("won't be zero");
+        let (mut cipher, _) = crate::ecdh::ecdh(&ephemeral, &theirs.into_point_with_even_y().0);
+        let ciphertext = Ciphertext::create(
+            ephemeral.public_key,
+            &mut cipher,
+            Plaintext::CancelRequestv1 {
+                psbt: encode::serialize(&psbt),
+                message,
+            },
+        );
+        Ok((ciphertext, cipher))
+    }
+
+    /// Decrypt a cancel request the counterparty made with [`generate_cancel_request`].
+    ///
+    /// [`generate_cancel_request`]: Self::generate_cancel_request
+    pub fn decrypt_cancel_request(
+        &self,
+        bet_id: BetId,
+        ciphertext: &Ciphertext,
+    ) -> anyhow::Result<(Psbt, Option<String>)> {
+        let (bet, _) = self.refundable_bet(bet_id)?;
+        let my_keypair = KeyPair::from_slice(&bet.joint_output.my_key.unwrap().to_bytes())
+            .expect("won't be zero");
+        let (mut cipher, _) = crate::ecdh::ecdh(&my_keypair, &ciphertext.public_key);
+        match ciphertext
+            .decrypt(&mut cipher)
+            .with_context(|| format!("decrypting cancel request for bet {}", bet_id))?
+        {
+            Plaintext::CancelRequestv1 { psbt, message } => Ok((
+                encode::deserialize(&psbt).context("decoding PSBT in cancel request")?,
+                message,
+            )),
+            _ => Err(anyhow!("This isn't a cancel request")),
+        }
+    }
+
+    fn sign_and_finalize_refund(&self, bet: &Bet, psbt: &mut Psbt) -> anyhow::Result<bool> {
+        // the refund key is the bet key without the oracle's attestation added
+        let secret_key = bet.joint_output.my_key.unwrap().clone().into();
+        self.sign_bet_output(bet, secret_key, psbt)?;
+
+        let current_height = self.blockchain()?.get_height()?;
+        if current_height < psbt.unsigned_tx.lock_time {
+            return Ok(false);
+        }
+
+        Ok(self.bdk_wallet().finalize_psbt(
+            psbt,
+            SignOptions {
+                assume_height: Some(current_height),
+                ..Default::default()
+            },
+        )?)
+    }
+}
+
+/// Checks the fee `tx` pays when spending the bet output worth `input_value` is under `fee`'s
+/// maximum and that its feerate is believable. Returns the fee.
+fn check_cooperative_spend_fee(
+    tx: &Transaction,
+    input_value: u64,
+    satisfaction_weight: usize,
+    fee: &FeeSpec,
+) -> anyhow::Result<u64> {
+    let fee_paid = input_value
+        .checked_sub(tx.output.iter().map(|output| output.value).sum())
+        .ok_or(anyhow!("The refund spends more than the bet output"))?;
+    fee.check_fee(Amount::from_sat(fee_paid))?;
+    let vbytes = (tx.weight() + satisfaction_weight + 3) / 4;
+    let feerate = fee_paid as f32 / vbytes as f32;
+    if feerate > MAX_SANE_FEERATE {
+        return Err(anyhow!(
+            "The refund's feerate of {:.1} sats/vb is unreasonably high",
+            feerate
+        ));
+    }
+    Ok(fee_paid)
+}
+
+/// The part of `fee` owed by someone who put `stake` out of `total` into the bet.
+pub(super) fn fee_share(fee: u64, stake: u64, total: u64) -> u64 {
+    ((fee as u128 * stake as u128) / total as u128) as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FeeTarget;
+    use bdk::bitcoin::{hashes::Hash, OutPoint, TxIn, Txid, WPubkeyHash};
+
+    const SATISFACTION_WEIGHT: usize = 300;
+
+    fn refund_tx(values: &[u64]) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::from_inner([1; 32]), 0),
+                script_sig: Script::new(),
+                sequence: 0xFFFF_FFFD,
+                witness: Default::default(),
+            }],
+            output: values
+                .iter()
+                .map(|value| TxOut {
+                    value: *value,
+                    script_pubkey: Script::new_v0_wpkh(&WPubkeyHash::from_inner([2; 20])),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn fee_comes_from_the_bet_value() {
+        let tx = refund_tx(&[100_000, 199_000]);
+        assert_eq!(
+            check_cooperative_spend_fee(&tx, 300_000, SATISFACTION_WEIGHT, &FeeSpec::default())
+                .unwrap(),
+            1_000
+        );
+        assert!(check_cooperative_spend_fee(
+            &tx,
+            299_000,
+            SATISFACTION_WEIGHT,
+            &FeeSpec::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn fee_over_max_is_refused() {
+        let tx = refund_tx(&[100_000, 190_000]);
+        let fee = FeeSpec {
+            target: FeeTarget::default(),
+            max: Some(Amount::from_sat(5_000)),
+        };
+        assert!(check_cooperative_spend_fee(&tx, 300_000, SATISFACTION_WEIGHT, &fee).is_err());
+        assert!(check_cooperative_spend_fee(&tx, 294_000, SATISFACTION_WEIGHT, &fee).is_ok());
+    }
+
+    #[test]
+    fn absurd_feerate_is_refused() {
+        // a couple of hundred vbytes can't be worth a whole bitcoin
+        let tx = refund_tx(&[1_000, 1_000]);
+        assert!(check_cooperative_spend_fee(
+            &tx,
+            100_002_000,
+            SATISFACTION_WEIGHT,
+            &FeeSpec::default()
+        )
+        .is_err());
+    }
+}
@@ -1,10 +1,19 @@
-use crate::{betting::*, elog, wallet::GunWallet, FeeSpec};
+use crate::{
+    betting::*,
+    chrono,
+    config::AutoClaimPolicy,
+    elog,
+    silent_payments::{self, Destination, SilentPaymentAddress},
+    wallet::GunWallet,
+    FeeSpec,
+};
+use anyhow::anyhow;
 use bdk::{
     bitcoin::{
+        secp256k1::SecretKey,
         util::psbt::{self, PartiallySignedTransaction as Psbt},
-        PrivateKey, TxOut,
+        OutPoint, PrivateKey, TxOut,
     },
-    blockchain::Blockchain,
     database::MemoryDatabase,
     miniscript::DescriptorTrait,
     signer::SignerOrdering,
@@ -18,26 +27,60 @@ impl GunWallet {
         &self,
         fee: FeeSpec,
         bump_claiming: bool,
+    ) -> anyhow::Result<Option<(Vec<BetId>, Psbt)>> {
+        self.claim_to(fee, bump_claiming, None)
+    }
+
+    /// Like [`claim`](Self::claim) but the winnings go to `to` rather than back to the wallet.
+    pub fn claim_to(
+        &self,
+        fee: FeeSpec,
+        bump_claiming: bool,
+        to: Option<&Destination>,
     ) -> anyhow::Result<Option<(Vec<BetId>, Psbt)>> {
         let bdk_wallet = self.bdk_wallet();
         let mut builder = bdk_wallet.build_tx();
         builder.manually_selected_only().enable_rbf();
 
-        fee.apply_to_builder(bdk_wallet.client(), &mut builder)?;
+        fee.apply_to_builder(self, &mut builder)?;
+        let tx_locktime = self.apply_locktime_policy(&mut builder)?;
 
-        let recipient = bdk_wallet
-            .get_change_address(AddressIndex::New)?
-            .script_pubkey();
+        let recipient = match to {
+            Some(to) => to.script_pubkey(),
+            None => bdk_wallet
+                .get_change_address(AddressIndex::New)?
+                .script_pubkey(),
+        };
 
         builder.drain_to(recipient);
 
-        let (mut psbt, claiming_bet_ids) = match self.spend_won_bets(builder, bump_claiming)? {
-            Some(res) => res,
-            None => return Ok(None),
-        };
+        let silent_payment = to.and_then(Destination::silent_payment);
+        if silent_payment.is_some() {
+            if self.claimable_bets(bump_claiming).is_empty() {
+                return Ok(None);
+            }
+            builder.add_utxo(self.silent_payment_input()?)?;
+        }
 
-        let finalized = bdk_wallet.finalize_psbt(&mut psbt, SignOptions::default())?;
+        let (mut psbt, claiming_bet_ids) =
+            match self.spend_won_bets(builder, bump_claiming, silent_payment)? {
+                Some(res) => res,
+                None => return Ok(None),
+            };
+
+        fee.check_psbt(&psbt)?;
+        self.record_locktime(&psbt, tx_locktime)?;
 
+        let finalized = match silent_payment {
+            Some(_) => bdk_wallet.sign(&mut psbt, SignOptions::default())?,
+            None => bdk_wallet.finalize_psbt(&mut psbt, SignOptions::default())?,
+        };
+
+        if silent_payment.is_some() && !finalized {
+            return Err(anyhow!(
+                "the wallet's coin spent with the bets needs more signatures than it can give"
+            ));
+        }
         assert!(
             finalized,
             "since we have signed each input is must be finalized"
@@ -46,18 +89,100 @@ impl GunWallet {
         Ok(Some((claiming_bet_ids, psbt)))
     }
 
+    /// One of the wallet's coins to spend with the bets when claiming to a silent payment address.
+    /// Bet outputs don't count towards working out the silent payment output so there has to be
+    /// an input that does. The smallest free one is used.
+    fn silent_payment_input(&self) -> anyhow::Result<OutPoint> {
+        let in_use = self.gun_db().currently_used_utxos(&[])?;
+        self.bdk_wallet()
+            .list_unspent()?
+            .into_iter()
+            .filter(|utxo| {
+                !in_use.contains(&utxo.outpoint)
+                    && matches!(
+                        silent_payments::is_eligible(&utxo.txout.script_pubkey, None),
+                        Ok(true)
+                    )
+            })
+            .min_by_key(|utxo| utxo.txout.value)
+            .map(|utxo| utxo.outpoint)
+            .ok_or(anyhow!("Claiming to a silent payment address needs one of your coins to be spent with the bets but none are free"))
+    }
+
+    /// Whether the bets we've won should be claimed now according to `policy`.
+    ///
+    /// Bets that are already being claimed don't count. Bumping those is left to `gun bet claim
+    /// --bump-claiming` so we never replace a claim transaction behind the user's back.
+    pub fn auto_claim_due(&self, policy: &AutoClaimPolicy) -> bool {
+        let won = self
+            .gun_db()
+            .list_entities_print_error::<BetState>()
+            .filter_map(|(_, bet_state)| match bet_state {
+                BetState::Won {
+                    bet, attestation, ..
+                } => Some((bet.joint_output_value.as_sat(), attestation.time)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let total: u64 = won.iter().map(|(value, _)| value).sum();
+        let oldest = won.iter().map(|(_, time)| *time).min();
+
+        let enough_value = match policy.min_value {
+            Some(min_value) => !won.is_empty() && total >= min_value,
+            None => false,
+        };
+        let waited_long_enough = match (policy.after_days, oldest) {
+            (Some(after_days), Some(oldest)) => {
+                chrono::Utc::now().naive_utc() - oldest > chrono::Duration::days(after_days.into())
+            }
+            _ => false,
+        };
+        enough_value || waited_long_enough
+    }
+
     pub fn spend_won_bets<
+        B,
         D: bdk::database::BatchDatabase,
-        B: Blockchain,
         Cs: CoinSelectionAlgorithm<D>,
         Ctx: TxBuilderContext,
     >(
         &self,
         mut builder: TxBuilder<'_, B, D, Cs, Ctx>,
         bump_claiming: bool,
+        silent_payment: Option<&SilentPaymentAddress>,
     ) -> anyhow::Result<Option<(Psbt, Vec<BetId>)>> {
-        let claimable_bets = self
-            .gun_db()
+        let claimable_bets = self.claimable_bets(bump_claiming);
+
+        let claimable_bet_ids = claimable_bets
+            .iter()
+            .map(|(bet_id, _, _)| *bet_id)
+            .collect::<Vec<_>>();
+
+        for (_, bet, _) in &claimable_bets {
+            add_bet_output(&mut builder, bet);
+        }
+
+        let (mut psbt, _) = match builder.finish() {
+            Ok(res) => res,
+            Err(bdk::Error::NoUtxosSelected) => return Ok(None),
+            e => e?,
+        };
+
+        // the bet inputs sign the outputs so the silent payment's has to be in place first
+        if let Some(address) = silent_payment {
+            self.set_silent_payment_output(&mut psbt, address)?;
+        }
+
+        for (_, bet, secret_key) in claimable_bets {
+            self.sign_bet_output(&bet, secret_key, &mut psbt)?;
+        }
+
+        Ok(Some((psbt, claimable_bet_ids)))
+    }
+
+    /// The bets we've won (and those being claimed if `bump_claiming`) with their secret keys.
+    fn claimable_bets(&self, bump_claiming: bool) -> Vec<(BetId, Bet, SecretKey)> {
+        self.gun_db()
             .list_entities::<BetState>()
             .filter_map(|result| match result {
                 Ok(ok) => Some(ok),
@@ -78,63 +203,66 @@ impl GunWallet {
                 } if bump_claiming => Some((bet_id, bet, secret_key)),
                 _ => None,
             })
-            .collect::<Vec<_>>();
-
-        let claimable_bet_ids = claimable_bets
-            .iter()
-            .map(|(bet_id, _, _)| *bet_id)
-            .collect::<Vec<_>>();
-
-        for (_, bet, _) in &claimable_bets {
-            let psbt_input = psbt::Input {
-                witness_utxo: Some(TxOut {
-                    value: bet.joint_output_value.as_sat(),
-                    script_pubkey: bet.joint_output.descriptor().script_pubkey(),
-                }),
-                non_witness_utxo: Some(bet.tx()),
-                witness_script: Some(bet.joint_output.descriptor().script_code()),
-                ..Default::default()
-            };
-            builder
-                .add_foreign_utxo(
-                    bet.outpoint(),
-                    psbt_input,
-                    bet.joint_output
-                        .descriptor()
-                        .max_satisfaction_weight()
-                        .unwrap(),
-                )
-                .unwrap();
-        }
+            .collect()
+    }
 
-        let (mut psbt, _) = match builder.finish() {
-            Ok(res) => res,
-            Err(bdk::Error::NoUtxosSelected) => return Ok(None),
-            e => e?,
+    /// Sign the inputs of `psbt` spending the bet's output with `secret_key`.
+    pub(crate) fn sign_bet_output(
+        &self,
+        bet: &Bet,
+        secret_key: SecretKey,
+        psbt: &mut Psbt,
+    ) -> anyhow::Result<()> {
+        let signer = PrivateKey {
+            compressed: true,
+            network: self.bdk_wallet().network(),
+            key: secret_key,
         };
-
-        for (_, bet, secret_key) in claimable_bets {
-            let signer = PrivateKey {
-                compressed: true,
-                network: self.bdk_wallet().network(),
-                key: secret_key,
-            };
-            let output_descriptor = bet.joint_output.wallet_descriptor();
-            let mut tmp_wallet = Wallet::new_offline(
-                output_descriptor,
-                None,
-                self.bdk_wallet().network(),
-                MemoryDatabase::default(),
-            )
-            .expect("nothing can go wrong here");
-            tmp_wallet.add_signer(
-                KeychainKind::External,
-                SignerOrdering::default(),
-                Arc::new(signer),
-            );
-            tmp_wallet.sign(&mut psbt, SignOptions::default())?;
-        }
-
-        Ok(Some((psbt, claimable_bet_ids)))
+        let output_descriptor = bet.joint_output.wallet_descriptor();
+        let mut tmp_wallet = Wallet::new_offline(
+            output_descriptor,
+            None,
+            self.bdk_wallet().network(),
+            MemoryDatabase::default(),
+        )
+        .expect("nothing can go wrong here");
+        tmp_wallet.add_signer(
+            KeychainKind::External,
+            SignerOrdering::default(),
+            Arc::new(signer),
+        );
+        tmp_wallet.sign(psbt, SignOptions::default())?;
+        Ok(())
     }
 }
+
+/// Add the bet's output as an input to the transaction being built.
+pub(crate) fn add_bet_output<
+    D: bdk::database::BatchDatabase,
+    B,
+    Cs: CoinSelectionAlgorithm<D>,
+    Ctx: TxBuilderContext,
+>(
+    builder: &mut TxBuilder<'_, B, D, Cs, Ctx>,
+    bet: &Bet,
+) {
+    let psbt_input = psbt::Input {
+        witness_utxo: Some(TxOut {
+            value: bet.joint_output_value.as_sat(),
+            script_pubkey: bet.joint_output.descriptor().script_pubkey(),
+        }),
+        non_witness_utxo: Some(bet.tx()),
+        witness_script: Some(bet.joint_output.descriptor().script_code()),
+        ..Default::default()
+    };
+    builder
+        .add_foreign_utxo(
+            bet.outpoint(),
+            psbt_input,
+            bet.joint_output
+                .descriptor()
+                .max_satisfaction_weight()
+                .unwrap(),
+        )
+        .unwrap();
+}
@@ -1,7 +1,21 @@
-use crate::{betting::*, elog, wallet::GunWallet};
+use crate::{
+    betting::*,
+    database::GunDatabase,
+    elog,
+    net::Net,
+    wallet::{self, GunWallet},
+};
 use anyhow::{anyhow, Context};
-use bdk::blockchain::{
-    Blockchain, Broadcast, GetInputState, InputState, TransactionState, TxState,
+use bdk::{
+    bitcoin::{OutPoint, Transaction, Txid},
+    blockchain::{
+        Blockchain, Broadcast, EsploraBlockchain, GetInputState, InputState, TransactionState,
+        TxState,
+    },
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
 };
 
 macro_rules! update_bet {
@@ -22,11 +36,51 @@ impl GunWallet {
     /// The `try_learn_outcome` exists so during tests it can be turned off so this doesn't try and contact a non-existent oracle.
     /// TODO: fix this with an oracle trait that can be mocked in tests.
     pub fn take_next_action(&self, bet_id: BetId, try_learn_outcome: bool) -> anyhow::Result<()> {
+        self.take_next_action_prefetched(bet_id, try_learn_outcome, &Prefetched::default())
+    }
+
+    /// Like [`take_next_action`] but answers network lookups from `prefetched` when it can.
+    ///
+    /// [`take_next_action`]: Self::take_next_action
+    pub fn take_next_action_prefetched(
+        &self,
+        bet_id: BetId,
+        try_learn_outcome: bool,
+        prefetched: &Prefetched,
+    ) -> anyhow::Result<()> {
+        let old_state = self
+            .gun_db()
+            .get_entity::<BetState>(bet_id)?
+            .ok_or(anyhow!("Bet {} does not exist", bet_id))?
+            .name();
+        let result = self.progress_bet(bet_id, try_learn_outcome, prefetched);
+        if let Err(e) = self.record_bet_fees(bet_id) {
+            elog!(@recoverable_error "Couldn't record the fees for bet {}: {}", bet_id, e);
+        }
+        if let Some(new_state) = self.gun_db().get_entity::<BetState>(bet_id)? {
+            if new_state.name() != old_state {
+                self.hooks().bet_state_changed(
+                    self.http_client(),
+                    bet_id,
+                    old_state,
+                    new_state.name(),
+                );
+            }
+        }
+        result
+    }
+
+    fn progress_bet(
+        &self,
+        bet_id: BetId,
+        try_learn_outcome: bool,
+        prefetched: &Prefetched,
+    ) -> anyhow::Result<()> {
         let bet_state = self
             .gun_db()
             .get_entity(bet_id)?
             .ok_or(anyhow!("Bet {} does not exist", bet_id))?;
-        let blockchain = self.bdk_wallet().client();
+        let blockchain = self.blockchain()?;
 
         match bet_state {
             BetState::Canceled {
@@ -37,7 +91,9 @@ impl GunWallet {
             } => {
                 match &pre_cancel {
                     BetOrProp::OfferedBet { bet, .. } => {
-                        if let TxState::Present { height } = blockchain.tx_state(&bet.tx())? {
+                        if let TxState::Present { height } =
+                            prefetched.tx_state(blockchain, &bet.tx())?
+                        {
                             if let Some(tx) = blockchain.get_tx(&bet.tx().txid())? {
                                 let bet = bet.clone().add_counterparty_sigs(tx);
                                 update_bet! {
@@ -50,7 +106,9 @@ impl GunWallet {
                         }
                     }
                     BetOrProp::Bet(bet) => {
-                        if let TxState::Present { height } = blockchain.tx_state(&bet.tx())? {
+                        if let TxState::Present { height } =
+                            prefetched.tx_state(blockchain, &bet.tx())?
+                        {
                             update_bet! { self, bet_id, BetState::Canceled { .. } => BetState::Included { bet: bet.clone(), height } }
                         }
                     }
@@ -58,7 +116,7 @@ impl GunWallet {
                 }
 
                 if height.is_none() {
-                    match blockchain.input_state(&pre_cancel.inputs())? {
+                    match prefetched.input_state(blockchain, &pre_cancel.inputs())? {
                         InputState::Spent {
                             index,
                             txid,
@@ -112,7 +170,7 @@ impl GunWallet {
                     txid,
                     vin,
                     height,
-                } = blockchain.input_state(&local_proposal.proposal.inputs)?
+                } = prefetched.input_state(blockchain, &local_proposal.proposal.inputs)?
                 {
                     update_bet! { self, bet_id,
                        BetState::Proposed { local_proposal, .. } => BetState::Canceled {
@@ -127,7 +185,7 @@ impl GunWallet {
                 }
             }
             BetState::Offered { bet, .. } => {
-                match blockchain.tx_state(&bet.0.tx())? {
+                match prefetched.tx_state(blockchain, &bet.0.tx())? {
                     TxState::Present { height } => {
                         if let Ok(Some(tx)) = blockchain.get_tx(&bet.0.tx().txid()) {
                             // when we offer a bet we don't have the full tx with signatures so if it's
@@ -146,25 +204,33 @@ impl GunWallet {
                         vin_target,
                         height,
                     } => {
+                        // If the proposer spent one of their inputs (e.g. they took another offer)
+                        // this offer can never go through so free our coins straight away. If the
+                        // spend leaves mempool we go back to offered.
                         let i_intend_cancel = bet.my_input_indexes.contains(&vin_target);
-                        if height.is_some() || i_intend_cancel {
-                            update_bet! { self, bet_id,
-                               BetState::Offered { bet, encrypted_offer } => BetState::Canceled {
-                                   pre_cancel: BetOrProp::OfferedBet{ bet, encrypted_offer },
-                                   bet_spent_vin: vin_target,
-                                   cancel_txid: txid,
-                                   cancel_vin: vin,
-                                   height,
-                                   i_intend_cancel,
-                               }
-                            }
+                        if !i_intend_cancel {
+                            elog!(
+                                @warning
+                                "The proposer of bet {} spent one of their inputs in {} so your offer can't go through. The coins it was using are free again.",
+                                bet_id, txid
+                            );
+                        }
+                        update_bet! { self, bet_id,
+                           BetState::Offered { bet, encrypted_offer } => BetState::Canceled {
+                               pre_cancel: BetOrProp::OfferedBet{ bet, encrypted_offer },
+                               bet_spent_vin: vin_target,
+                               cancel_txid: txid,
+                               cancel_vin: vin,
+                               height,
+                               i_intend_cancel,
+                           }
                         }
                     }
                     TxState::NotFound => { /* we're waiting for proposer to broadcast */ }
                 }
             }
             BetState::Included { bet, .. } => {
-                match blockchain.tx_state(&bet.tx())? {
+                match prefetched.tx_state(blockchain, &bet.tx())? {
                     // If there's a conflict with the bet tx then we go to canceled
                     TxState::Conflict {
                         txid,
@@ -194,6 +260,30 @@ impl GunWallet {
                         Broadcast::broadcast(blockchain, bet.tx())?
                     }
                 }
+                // The counterparty may have claimed before the attestation got to us so we only
+                // go to refunded if the spend was signed with a refund key (a refund, a mutual
+                // cancel or a sweep). Otherwise we wait to learn the outcome.
+                if let Some(refund) = &bet.joint_output.refund {
+                    if let InputState::Spent {
+                        txid, vin, height, ..
+                    } = prefetched.input_state(blockchain, &[bet.outpoint()])?
+                    {
+                        let spent_by_refund = match blockchain.get_tx(&txid)? {
+                            Some(tx) => refund.is_spent_by(
+                                &tx,
+                                vin as usize,
+                                bet.joint_output_value.as_sat(),
+                            ),
+                            None => false,
+                        };
+                        if spent_by_refund {
+                            update_bet! { self, bet_id,
+                                BetState::Included { bet, .. } => BetState::Refunded { bet, txid, height }
+                            };
+                            return Ok(());
+                        }
+                    }
+                }
                 if try_learn_outcome {
                     self.try_get_outcome(bet_id, bet)?;
                 }
@@ -201,7 +291,7 @@ impl GunWallet {
             BetState::Won { bet, .. } => {
                 // claiming but just in case someone steals your keys somehow we handle it.
                 if let InputState::Spent { txid, height, .. } =
-                    blockchain.input_state(&[bet.outpoint()])?
+                    prefetched.input_state(blockchain, &[bet.outpoint()])?
                 {
                     update_bet! {self, bet_id,
                         BetState::Won { bet, secret_key, attestation } => {
@@ -213,7 +303,7 @@ impl GunWallet {
             // TODO: To be more robust, check if height is below some threshold rather than just None
             BetState::Claimed {
                 bet, height: None, ..
-            } => match blockchain.input_state(&[bet.outpoint()])? {
+            } => match prefetched.input_state(blockchain, &[bet.outpoint()])? {
                 InputState::Spent { txid, height, .. } => update_bet! {self, bet_id,
                    BetState::Claimed { bet, attestation, secret_key, .. } => BetState::Claimed { bet, txid, height, secret_key, attestation}
                 },
@@ -221,23 +311,32 @@ impl GunWallet {
                    BetState::Claimed { bet, secret_key, attestation, .. } => BetState::Won { bet, secret_key, attestation }
                 },
             },
+            BetState::Refunded {
+                bet, height: None, ..
+            } => match prefetched.input_state(blockchain, &[bet.outpoint()])? {
+                InputState::Spent { txid, height, .. } => update_bet! { self, bet_id,
+                    BetState::Refunded { bet, .. } => BetState::Refunded { bet, txid, height }
+                },
+                InputState::Unspent => update_bet! { self, bet_id,
+                    BetState::Refunded { bet, .. } => BetState::Included { bet, height: None }
+                },
+            },
             BetState::Claimed {
                 height: Some(_), ..
             }
+            | BetState::Refunded {
+                height: Some(_), ..
+            }
             | BetState::Lost { .. } => { /* terminal states */ }
         }
         Ok(())
     }
 
-    fn try_get_outcome(&self, bet_id: BetId, bet: Bet) -> anyhow::Result<()> {
-        let event_id = bet.oracle_event.event.id;
-        let event_url = format!("https://{}{}", bet.oracle_id, event_id);
+    pub(super) fn try_get_outcome(&self, bet_id: BetId, bet: Bet) -> anyhow::Result<()> {
+        let event_url = event_url(self.gun_db(), &bet);
         let event_response = self
-            .http_client()
-            .get(&event_url)
-            .call()
-            .with_context(|| format!("trying to outcome for bet {} from {}", bet_id, event_url))?
-            .into_json::<EventResponse>()?;
+            .get_oracle_event(&event_url, true, false)
+            .with_context(|| format!("trying to outcome for bet {} from {}", bet_id, event_url))?;
 
         if let Some(attestation) = event_response.attestation {
             self.learn_outcome(bet_id, attestation)?;
@@ -246,3 +345,140 @@ impl GunWallet {
         Ok(())
     }
 }
+
+fn event_url(gun_db: &GunDatabase, bet: &Bet) -> String {
+    gun_db.oracle_event_url(&bet.oracle_id, &bet.oracle_event.event.id)
+}
+
+enum Lookup {
+    TxState(Transaction),
+    InputState(Vec<OutPoint>),
+    Event(String),
+}
+
+/// The results of the network lookups the state machine is going to make for a set of bets.
+///
+/// Polling bets one at a time means waiting on each request in turn, so when we poke all of them we
+/// make the lookups up front concurrently (bounded by [`Net`]). Each result is used once and anything missing (e.g. the
+/// lookup failed) is looked up again when it's needed so errors get reported against the bet.
+/// Oracle events go straight into the event cache.
+#[derive(Default)]
+pub struct Prefetched {
+    tx_states: Mutex<HashMap<Txid, TxState>>,
+    input_states: Mutex<HashMap<Vec<OutPoint>, InputState>>,
+}
+
+impl Prefetched {
+    pub fn fetch<'a>(
+        net: &Net,
+        blockchain: Arc<EsploraBlockchain>,
+        gun_db: &GunDatabase,
+        bet_states: impl IntoIterator<Item = &'a BetState>,
+        try_learn_outcome: bool,
+    ) -> Self {
+        let mut lookups = vec![];
+        for bet_state in bet_states {
+            match bet_state {
+                BetState::Canceled {
+                    pre_cancel, height, ..
+                } => {
+                    match pre_cancel {
+                        BetOrProp::OfferedBet {
+                            bet: OfferedBet(bet),
+                            ..
+                        }
+                        | BetOrProp::Bet(bet) => lookups.push(Lookup::TxState(bet.tx())),
+                        BetOrProp::Proposal(_) => {}
+                    }
+                    if height.is_none() {
+                        lookups.push(Lookup::InputState(pre_cancel.inputs()));
+                    }
+                }
+                BetState::Proposed { local_proposal } => {
+                    lookups.push(Lookup::InputState(local_proposal.proposal.inputs.clone()))
+                }
+                BetState::Offered { bet, .. } => lookups.push(Lookup::TxState(bet.0.tx())),
+                BetState::Included { bet, .. } => {
+                    lookups.push(Lookup::TxState(bet.tx()));
+                    if bet.joint_output.refund.is_some() {
+                        lookups.push(Lookup::InputState(vec![bet.outpoint()]));
+                    }
+                    let event_url = event_url(gun_db, bet);
+                    if try_learn_outcome
+                        && !matches!(
+                            wallet::cached_oracle_event(gun_db, &event_url, true),
+                            Ok(Some(_))
+                        )
+                    {
+                        lookups.push(Lookup::Event(event_url));
+                    }
+                }
+                BetState::Won { bet, .. }
+                | BetState::Claimed {
+                    bet, height: None, ..
+                }
+                | BetState::Refunded {
+                    bet, height: None, ..
+                } => lookups.push(Lookup::InputState(vec![bet.outpoint()])),
+                BetState::Claimed { .. } | BetState::Refunded { .. } | BetState::Lost { .. } => {}
+            }
+        }
+
+        let prefetched = Prefetched::default();
+        let lookups = lookups.into_iter().map(|lookup| {
+            let blockchain = blockchain.clone();
+            let prefetched = &prefetched;
+            async move {
+                match lookup {
+                    Lookup::TxState(tx) => {
+                        let txid = tx.txid();
+                        if let Ok(Ok(state)) =
+                            net.run_blocking(move || blockchain.tx_state(&tx)).await
+                        {
+                            prefetched.tx_states.lock().unwrap().insert(txid, state);
+                        }
+                    }
+                    Lookup::InputState(inputs) => {
+                        let key = inputs.clone();
+                        if let Ok(Ok(state)) = net
+                            .run_blocking(move || blockchain.input_state(&inputs))
+                            .await
+                        {
+                            prefetched.input_states.lock().unwrap().insert(key, state);
+                        }
+                    }
+                    Lookup::Event(url) => {
+                        let _ = wallet::fetch_oracle_event(net, gun_db, &url).await;
+                    }
+                }
+            }
+        });
+        net.block_on(futures::future::join_all(lookups));
+
+        prefetched
+    }
+
+    fn tx_state(
+        &self,
+        blockchain: &EsploraBlockchain,
+        tx: &Transaction,
+    ) -> anyhow::Result<TxState> {
+        let cached = self.tx_states.lock().unwrap().remove(&tx.txid());
+        match cached {
+            Some(state) => Ok(state),
+            None => Ok(blockchain.tx_state(tx)?),
+        }
+    }
+
+    fn input_state(
+        &self,
+        blockchain: &EsploraBlockchain,
+        inputs: &[OutPoint],
+    ) -> anyhow::Result<InputState> {
+        let cached = self.input_states.lock().unwrap().remove(inputs);
+        match cached {
+            Some(state) => Ok(state),
+            None => Ok(blockchain.input_state(inputs)?),
+        }
+    }
+}
@@ -1,17 +1,97 @@
-use crate::{betting::*, keychain::Keychain, wallet::GunWallet, OracleInfo};
+use crate::psbt_ext::PsbtFeeRate;
+use crate::{
+    betting::*,
+    keychain::{KeyOrigin, Keychain},
+    script_kind,
+    wallet::GunWallet,
+    OracleInfo,
+};
 use anyhow::{anyhow, Context};
 use bdk::{
     bitcoin::{
         util::psbt::{self, PartiallySignedTransaction as Psbt},
-        Amount,
+        Amount, Script, TxOut,
     },
+    blockchain::Blockchain,
     miniscript::DescriptorTrait,
-    wallet::tx_builder::TxOrdering,
-    SignOptions,
+    wallet::{tx_builder::TxOrdering, IsDust},
+    FeeRate, SignOptions,
 };
 use chacha20::ChaCha20Rng;
 use olivia_secp256k1::fun::{marker::EvenY, Point};
-use std::convert::TryInto;
+use std::{collections::HashSet, convert::TryInto};
+
+/// The checks on an offer that can be done before building the bet transaction. `offer_txouts` are
+/// the outputs spent by the offer's inputs (in the same order) and `is_mine` says whether a script
+/// belongs to our wallet. Returns the fee the offer pays.
+pub(crate) fn check_offer(
+    proposal: &Proposal,
+    my_public_key: &Point<EvenY>,
+    offer: &Offer,
+    offer_public_key: &Point<EvenY>,
+    offer_txouts: &[TxOut],
+    is_mine: impl Fn(&Script) -> anyhow::Result<bool>,
+) -> anyhow::Result<Amount> {
+    if offer_public_key == my_public_key {
+        return Err(anyhow!("offer's public key is the same as the proposal's"));
+    }
+    if offer.inputs.is_empty() {
+        return Err(anyhow!("offer has no inputs"));
+    }
+    if offer.value == Amount::ZERO {
+        return Err(anyhow!("offer doesn't bet anything"));
+    }
+    if proposal.value.checked_add(offer.value).is_none() {
+        return Err(anyhow!("offer value {} is too large", offer.value));
+    }
+
+    let mut seen = HashSet::new();
+    let mut input_value = Amount::ZERO;
+    for (input, txout) in offer.inputs.iter().zip(offer_txouts) {
+        if !seen.insert(input.outpoint) {
+            return Err(anyhow!("offer spends {} more than once", input.outpoint));
+        }
+        if proposal.inputs.contains(&input.outpoint) || is_mine(&txout.script_pubkey)? {
+            return Err(anyhow!(
+                "offer input {} belongs to this wallet",
+                input.outpoint
+            ));
+        }
+        if !input.witness.can_spend(&txout.script_pubkey) {
+            return Err(anyhow!(
+                "offer input {} has the wrong witness for the output it spends",
+                input.outpoint
+            ));
+        }
+        if !input.witness.signs_all() {
+            return Err(anyhow!(
+                "offer input {} isn't signed with SIGHASH_ALL",
+                input.outpoint
+            ));
+        }
+        input_value = input_value
+            .checked_add(Amount::from_sat(txout.value))
+            .ok_or(anyhow!("offer input value overflows"))?;
+    }
+
+    let mut fee = input_value
+        .checked_sub(offer.value)
+        .ok_or(anyhow!("offer value is more than input value"))?;
+    if let Some(change) = &offer.change {
+        fee = fee
+            .checked_sub(change.value())
+            .ok_or(anyhow!("too much change requested"))?;
+        if change.value().as_sat().is_dust() {
+            return Err(anyhow!("offer's change of {} is dust", change.value()));
+        }
+        script_kind::check_recipient(change.script()).context("the offer's change")?;
+    }
+    Ok(fee)
+}
+
+/// How many blocks past our tip an offer's locktime can be. The offerer sets it to their tip which
+/// may be ahead of ours.
+const OFFER_LOCKTIME_TOLERANCE: u32 = 3;
 
 impl GunWallet {
     pub fn decrypt_offer(
@@ -20,36 +100,118 @@ impl GunWallet {
         encrypted_offer: Ciphertext,
         keychain: &Keychain,
     ) -> anyhow::Result<(Plaintext, Point<EvenY>, ChaCha20Rng)> {
-        let local_proposal = self
+        let proposal = self.proposal_for_bet(bet_id)?;
+        let keypair =
+            keychain.get_key_for_proposal_with(self.key_origin_for_bet(bet_id)?, &proposal)?;
+        let (mut cipher, rng) = crate::ecdh::ecdh(&keypair, &encrypted_offer.public_key);
+        let plaintext = encrypted_offer.decrypt(&mut cipher)?;
+
+        Ok((plaintext, encrypted_offer.public_key, rng))
+    }
+
+    /// Keep an offer for one of our proposals so it can be compared with others and taken later.
+    /// Returns the offer's number.
+    pub fn add_proposal_offer(
+        &self,
+        bet_id: BetId,
+        encrypted_offer: Ciphertext,
+        keychain: &Keychain,
+    ) -> anyhow::Result<usize> {
+        match self
+            .decrypt_offer(bet_id, encrypted_offer.clone(), keychain)
+            .with_context(|| format!("decrypting offer for bet {}", bet_id))?
+            .0
+        {
+            Plaintext::Offerv1 { .. } => {}
+            _ => return Err(anyhow!("This isn't an offer")),
+        }
+
+        let mut stored = self
             .gun_db()
-            .get_entity(bet_id)?
-            .ok_or(anyhow!("Proposal does not exist"))?;
+            .get_entity::<ProposalOffers>(bet_id)?
+            .unwrap_or_default();
+        if let Some(n) = stored
+            .offers
+            .iter()
+            .position(|offer| offer == &encrypted_offer)
+        {
+            return Ok(n);
+        }
+        stored.offers.push(encrypted_offer);
+        let n = stored.offers.len() - 1;
+        self.gun_db().insert_entity(bet_id, stored)?;
+        Ok(n)
+    }
 
-        match local_proposal {
-            BetState::Proposed { local_proposal } => {
-                let keypair = keychain.get_key_for_proposal(&local_proposal.proposal);
-                let (mut cipher, rng) = crate::ecdh::ecdh(&keypair, &encrypted_offer.public_key);
-                let plaintext = encrypted_offer.decrypt(&mut cipher)?;
+    /// The offers kept for a proposal with [`add_proposal_offer`](Self::add_proposal_offer).
+    pub fn proposal_offers(&self, bet_id: BetId) -> anyhow::Result<Vec<Ciphertext>> {
+        Ok(self
+            .gun_db()
+            .get_entity::<ProposalOffers>(bet_id)?
+            .unwrap_or_default()
+            .offers)
+    }
+
+    /// The proposal we made for a bet whatever state it's in now. We don't keep the proposal once
+    /// an offer is taken so in that case it's rebuilt from the bet.
+    pub fn proposal_for_bet(&self, bet_id: BetId) -> anyhow::Result<Proposal> {
+        let bet_state = self
+            .gun_db()
+            .get_entity::<BetState>(bet_id)?
+            .ok_or(anyhow!("Bet {} doesn't exist", bet_id))?;
+        let not_ours = || {
+            anyhow!(
+                "Bet {} started with an offer you made so it has no proposal of yours",
+                bet_id
+            )
+        };
+        let bet = match bet_state.into_bet_or_prop() {
+            BetOrProp::Proposal(local_proposal) => return Ok(local_proposal.proposal),
+            BetOrProp::OfferedBet { .. } => return Err(not_ours()),
+            BetOrProp::Bet(bet) => bet,
+        };
+        if let Either::Right(_) = bet.joint_output.my_key {
+            return Err(not_ours());
+        }
 
-                Ok((plaintext, encrypted_offer.public_key, rng))
+        let mut change_script = None;
+        for (vout, output) in bet.tx().output.into_iter().enumerate() {
+            if vout as u32 != bet.vout && self.bdk_wallet().is_mine(&output.script_pubkey)? {
+                change_script = Some(output.script_pubkey.into());
             }
-            _ => Err(anyhow!("Offer has been taken for this proposal already")),
         }
+
+        Ok(Proposal {
+            oracle: bet.oracle_id.clone(),
+            event_id: bet.oracle_event.event.id.clone(),
+            value: bet.local_value,
+            // my_input_indexes are in the order of the proposal's inputs
+            inputs: bet.my_inputs(),
+            public_key: crate::placeholder_point(),
+            change_script,
+            refund_after: bet.joint_output.refund.as_ref().map(|refund| refund.after),
+        })
     }
 
-    fn lookup_offer_inputs(&self, offer: &Offer) -> anyhow::Result<(Vec<psbt::Input>, Amount)> {
+    /// Which protocol secret our key for `bet_id` was derived from and how.
+    pub fn key_origin_for_bet(&self, bet_id: BetId) -> anyhow::Result<KeyOrigin> {
+        Ok(self
+            .gun_db()
+            .get_entity::<BetState>(bet_id)?
+            .ok_or(anyhow!("Bet {} doesn't exist", bet_id))?
+            .key_origin())
+    }
+
+    fn lookup_offer_inputs(&self, offer: &Offer) -> anyhow::Result<Vec<psbt::Input>> {
         let mut psbt_inputs = vec![];
-        let mut input_value = 0;
         for input in &offer.inputs {
             let mut psbt_input = self
-                .p2wpkh_outpoint_to_psbt_input(input.outpoint)
+                .outpoint_to_psbt_input(input.outpoint)
                 .context("retrieving offer input")?;
-            input_value += psbt_input.witness_utxo.as_ref().unwrap().value;
             psbt_input.final_script_witness = Some(input.witness.encode());
             psbt_inputs.push(psbt_input);
         }
-
-        Ok((psbt_inputs, Amount::from_sat(input_value)))
+        Ok(psbt_inputs)
     }
 
     pub fn validate_offer(
@@ -60,7 +222,7 @@ impl GunWallet {
         mut rng: ChaCha20Rng,
         keychain: &Keychain,
     ) -> anyhow::Result<ValidatedOffer> {
-        let (offer_psbt_inputs, offer_input_value) = self.lookup_offer_inputs(&offer)?;
+        let offer_psbt_inputs = self.lookup_offer_inputs(&offer)?;
 
         let randomize = Randomize::new(&mut rng);
 
@@ -70,16 +232,35 @@ impl GunWallet {
             .ok_or(anyhow!("Bet {} doesn't exist", bet_id))?;
         let local_proposal = match bet_state {
             BetState::Proposed { local_proposal } => local_proposal,
-            _ => return Err(anyhow!("was not in proposed state")),
+            bet_state => {
+                return Err(anyhow!(
+                    "Bet {} can't take offers anymore because it is {}",
+                    bet_id,
+                    bet_state.name()
+                ))
+            }
         };
 
+        let key_origin = local_proposal.key_origin();
         let LocalProposal {
             oracle_event,
             proposal,
             ..
         } = local_proposal;
 
-        let keypair = keychain.get_key_for_proposal(&proposal);
+        let keypair = keychain.get_key_for_proposal_with(key_origin, &proposal)?;
+        let offer_txouts = offer_psbt_inputs
+            .iter()
+            .map(|psbt_input| psbt_input.witness_utxo.clone().unwrap())
+            .collect::<Vec<_>>();
+        let absolute_fee = check_offer(
+            &proposal,
+            &keypair.public_key,
+            &offer,
+            &offer_public_key,
+            &offer_txouts,
+            |script_pubkey| Ok(self.bdk_wallet().is_mine(script_pubkey)?),
+        )?;
         let oracle_id = &proposal.oracle;
 
         let oracle_info = self
@@ -105,40 +286,50 @@ impl GunWallet {
             anticipated_attestations,
             offer.choose_right,
             randomize,
+            proposal.refund_after,
         );
         let joint_output_value = offer
             .value
             .checked_add(proposal.value)
-            .expect("we've checked the offer value on the chain");
+            .expect("checked by check_offer");
         let joint_output_script_pubkey = joint_output.descriptor().script_pubkey();
 
+        // A locktime in the future would mean the bet tx can't be broadcast now but the offerer
+        // could broadcast it later when we've forgotten about it. Their backend may be a few
+        // blocks ahead of ours though.
+        if offer.locktime != 0
+            && offer.locktime > self.blockchain()?.get_height()? + OFFER_LOCKTIME_TOLERANCE
+        {
+            return Err(anyhow!(
+                "offer's locktime {} is in the future",
+                offer.locktime
+            ));
+        }
+
         let mut builder = self.bdk_wallet().build_tx();
 
         builder
             .manually_selected_only()
             .ordering(TxOrdering::Bip69Lexicographic)
-            .enable_rbf();
+            .enable_rbf()
+            .nlocktime(offer.locktime);
 
         for proposal_input in &proposal.inputs {
             builder.add_utxo(*proposal_input)?;
         }
 
         for (input, psbt_input) in offer.inputs.iter().zip(offer_psbt_inputs) {
-            builder.add_foreign_utxo(input.outpoint, psbt_input, 4 + 1 + 73 + 33)?;
+            let weight =
+                satisfaction_weight(&psbt_input.witness_utxo.as_ref().unwrap().script_pubkey)
+                    .expect("we've checked the offer input's script");
+            builder.add_foreign_utxo(input.outpoint, psbt_input, weight)?;
         }
 
         if let Some(change) = local_proposal.change {
             builder.add_recipient(change.script().clone(), change.value().as_sat());
         }
 
-        let mut absolute_fee = offer_input_value
-            .checked_sub(offer.value)
-            .ok_or(anyhow!("offer value is more than input value"))?;
-
         if let Some(change) = offer.change {
-            absolute_fee = absolute_fee
-                .checked_sub(change.value())
-                .ok_or(anyhow!("too much change requested"))?;
             builder.add_recipient(change.script().clone(), change.value().as_sat());
         }
 
@@ -151,6 +342,15 @@ impl GunWallet {
 
         let (psbt, _tx_details) = builder.finish()?;
 
+        // the offerer pays the whole fee so they could make one that never gets the bet confirmed
+        let (_, feerate, _) = psbt.fee();
+        if feerate.as_sat_vb() < FeeRate::default_min_relay_fee().as_sat_vb() {
+            return Err(anyhow!(
+                "offer's fee rate of {:.2} sat/vb is below the minimum relay fee",
+                feerate.as_sat_vb()
+            ));
+        }
+
         let vout = psbt
             .unsigned_tx
             .output
@@ -190,6 +390,14 @@ impl GunWallet {
             joint_output_value,
             i_chose_right: !offer.choose_right,
             tags: local_proposal.tags,
+            fingerprint: Some(BetFingerprint::new(
+                &keypair.public_key,
+                &offer_public_key,
+                &proposal.event_id,
+            )),
+            derivation: key_origin.derivation,
+            secret: key_origin.secret,
+            dust_fee: local_proposal.dust_fee,
         };
 
         Ok(ValidatedOffer { bet_id, bet })
@@ -212,6 +420,9 @@ impl GunWallet {
         &self,
         ValidatedOffer { bet_id, bet, .. }: ValidatedOffer,
     ) -> anyhow::Result<Psbt> {
+        if let Some(fingerprint) = bet.fingerprint {
+            self.gun_db().index_bet_fingerprint(fingerprint, bet_id)?;
+        }
         self.gun_db()
             .update_bets(&[bet_id], |bet_state, _, _| match bet_state {
                 BetState::Proposed { .. } => Ok(BetState::Included {
@@ -224,3 +435,200 @@ impl GunWallet {
         Ok(bet.psbt)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::change::Change;
+    use bdk::bitcoin::{hashes::Hash, OutPoint, Txid, WPubkeyHash};
+    use olivia_core::EventId;
+    use olivia_secp256k1::{
+        ecdsa_fun,
+        fun::{marker::*, s, G},
+        schnorr_fun,
+    };
+    use std::str::FromStr;
+
+    fn point(secret: u32) -> Point<EvenY> {
+        Point::<EvenY>::from_scalar_mul(G, &mut s!(secret))
+    }
+
+    fn p2wpkh(key: &Point<EvenY>) -> Script {
+        let key: bdk::bitcoin::secp256k1::PublicKey = key.mark::<Normal>().into();
+        Script::new_v0_wpkh(&WPubkeyHash::hash(&key.serialize()))
+    }
+
+    fn outpoint(n: u8) -> OutPoint {
+        OutPoint::new(Txid::from_inner([n; 32]), 0)
+    }
+
+    fn our_script() -> Script {
+        p2wpkh(&point(100))
+    }
+
+    fn proposal() -> Proposal {
+        Proposal {
+            oracle: "h00.ooo".into(),
+            event_id: EventId::from_str("/random/2020-09-25T08:00:00/heads_tails.winner").unwrap(),
+            value: Amount::from_sat(100_000),
+            inputs: vec![outpoint(1)],
+            public_key: point(1),
+            change_script: Some(our_script().into()),
+            refund_after: None,
+        }
+    }
+
+    /// A counterparty that makes an honest offer and then spoils it in one of the ways someone
+    /// trying to steal from the proposer might.
+    struct EvilParty {
+        offer: Offer,
+        public_key: Point<EvenY>,
+        txouts: Vec<TxOut>,
+    }
+
+    impl EvilParty {
+        fn honest() -> Self {
+            let key = point(2);
+            EvilParty {
+                offer: Offer {
+                    inputs: vec![SignedInput {
+                        outpoint: outpoint(2),
+                        witness: Witness::P2wpkh {
+                            key: key.mark::<Normal>().into(),
+                            signature: ecdsa_fun::Signature::from_bytes([43u8; 64]).unwrap(),
+                        },
+                    }],
+                    change: Some(Change::new(99_000, p2wpkh(&point(3)))),
+                    choose_right: false,
+                    value: Amount::from_sat(100_000),
+                    locktime: 0,
+                },
+                public_key: point(4),
+                txouts: vec![TxOut {
+                    value: 200_000,
+                    script_pubkey: p2wpkh(&key),
+                }],
+            }
+        }
+
+        fn check(&self) -> anyhow::Result<Amount> {
+            check_offer(
+                &proposal(),
+                &proposal().public_key,
+                &self.offer,
+                &self.public_key,
+                &self.txouts,
+                |script| Ok(*script == our_script()),
+            )
+        }
+
+        fn assert_rejected(self, attack: &str, expected: &str) {
+            match self.check() {
+                Ok(_) => panic!("{} was accepted", attack),
+                Err(e) => assert!(
+                    e.to_string().contains(expected),
+                    "{} was rejected for the wrong reason: {}",
+                    attack,
+                    e
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn honest_offer_is_accepted() {
+        assert_eq!(
+            EvilParty::honest().check().unwrap(),
+            Amount::from_sat(1_000)
+        );
+    }
+
+    #[test]
+    fn bad_values_are_rejected() {
+        let mut evil = EvilParty::honest();
+        evil.offer.value = Amount::ZERO;
+        evil.assert_rejected("betting nothing", "doesn't bet anything");
+
+        let mut evil = EvilParty::honest();
+        evil.offer.value = Amount::from_sat(u64::MAX);
+        evil.assert_rejected("overflowing the bet value", "too large");
+
+        let mut evil = EvilParty::honest();
+        evil.offer.value = Amount::from_sat(200_001);
+        evil.offer.change = None;
+        evil.assert_rejected("betting more than the inputs", "more than input value");
+    }
+
+    #[test]
+    fn bad_change_is_rejected() {
+        let mut evil = EvilParty::honest();
+        evil.offer.change = Some(Change::new(100_001, p2wpkh(&point(3))));
+        evil.assert_rejected("taking the fee back as change", "too much change");
+
+        let mut evil = EvilParty::honest();
+        evil.offer.change = Some(Change::new(100, p2wpkh(&point(3))));
+        evil.assert_rejected("dust change", "dust");
+
+        let mut evil = EvilParty::honest();
+        let future_segwit = Script::from(vec![0x52, 0x02, 0xab, 0xcd]);
+        evil.offer.change = Some(Change::new(99_000, future_segwit));
+        evil.assert_rejected("change nothing can spend", "the offer's change");
+    }
+
+    #[test]
+    fn stolen_inputs_are_rejected() {
+        let mut evil = EvilParty::honest();
+        evil.offer.inputs[0].outpoint = outpoint(1);
+        evil.assert_rejected("spending the proposal's input", "belongs to this wallet");
+
+        let mut evil = EvilParty::honest();
+        evil.txouts[0].script_pubkey = our_script();
+        evil.assert_rejected("spending another of our coins", "belongs to this wallet");
+
+        let mut evil = EvilParty::honest();
+        evil.offer.inputs.push(evil.offer.inputs[0].clone());
+        evil.txouts.push(evil.txouts[0].clone());
+        evil.assert_rejected("counting an input twice", "more than once");
+    }
+
+    #[test]
+    fn bad_witnesses_are_rejected() {
+        let mut evil = EvilParty::honest();
+        evil.txouts[0].script_pubkey = p2wpkh(&point(5));
+        evil.assert_rejected("signing with the wrong key", "wrong witness");
+
+        let mut evil = EvilParty::honest();
+        let mut signature = [0u8; 64];
+        signature[..32].copy_from_slice(&point(3).to_xonly().as_bytes()[..]);
+        signature[63] = 1;
+        evil.offer.inputs[0].witness = Witness::P2trKeySpend {
+            signature: schnorr_fun::Signature::from_bytes(signature).unwrap(),
+            sighash: None,
+        };
+        let taproot_witness = evil.offer.inputs[0].witness.clone();
+        evil.assert_rejected("a taproot witness for a p2wpkh output", "wrong witness");
+
+        let mut evil = EvilParty::honest();
+        evil.offer.inputs[0].witness = taproot_witness;
+        let mut p2tr = vec![0x51, 0x20];
+        p2tr.extend_from_slice(&point(6).to_xonly().as_bytes()[..]);
+        evil.txouts[0].script_pubkey = Script::from(p2tr);
+        assert!(evil.check().is_ok());
+        evil.offer.inputs[0].witness = Witness::P2trKeySpend {
+            signature: schnorr_fun::Signature::from_bytes(signature).unwrap(),
+            // SIGHASH_NONE
+            sighash: Some(0x02),
+        };
+        evil.assert_rejected(
+            "a signature that doesn't commit to the outputs",
+            "SIGHASH_ALL",
+        );
+    }
+
+    #[test]
+    fn wrong_joint_output_key_is_rejected() {
+        let mut evil = EvilParty::honest();
+        evil.public_key = proposal().public_key;
+        evil.assert_rejected("reusing the proposal's key", "same as the proposal's");
+    }
+}
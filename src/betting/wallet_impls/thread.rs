@@ -0,0 +1,132 @@
+use crate::{betting::*, keychain::KeyPair, wallet::GunWallet};
+use anyhow::{anyhow, Context};
+use chacha20::ChaCha20;
+use olivia_secp256k1::fun::{Point, Scalar};
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+
+impl GunWallet {
+    /// Our secret bet key and both parties' public bet keys (ours first).
+    fn bet_thread_keys(&self, bet_id: BetId) -> anyhow::Result<(Scalar, Point, Point)> {
+        let bet_state = self
+            .gun_db()
+            .get_entity::<BetState>(bet_id)?
+            .ok_or(anyhow!("Bet {} doesn't exist", bet_id))?;
+        let bet = match bet_state.into_bet_or_prop() {
+            BetOrProp::Bet(bet)
+            | BetOrProp::OfferedBet {
+                bet: OfferedBet(bet),
+                ..
+            } => bet,
+            BetOrProp::Proposal(_) => {
+                return Err(anyhow!(
+                    "Bet {} is still a proposal so there's no one to message yet -- use `gun bet reply` to message the proposer",
+                    bet_id
+                ))
+            }
+        };
+        let (mine, theirs) = bet.joint_output.my_and_their_bet_keys().ok_or(anyhow!(
+            "Bet {} was made with an older version of gun that didn't keep the keys needed to message the counterparty",
+            bet_id
+        ))?;
+        Ok((bet.joint_output.my_key.unwrap().clone(), mine, theirs))
+    }
+
+    fn bet_thread(&self, bet_id: BetId) -> anyhow::Result<BetThread> {
+        Ok(self
+            .gun_db()
+            .get_entity::<BetThread>(bet_id)?
+            .unwrap_or_default())
+    }
+
+    /// Encrypt a message to the counterparty of a bet and add it to the thread.
+    pub fn send_bet_message(
+        &self,
+        bet_id: BetId,
+        text: String,
+    ) -> anyhow::Result<(Ciphertext, ChaCha20)> {
+        let (my_secret, mine, theirs) = self.bet_thread_keys(bet_id)?;
+        let mut thread = self.bet_thread(bet_id)?;
+        let seq = thread.next_seq(true);
+        let mac_key = crate::ecdh::bet_thread_mac_key(&my_secret, &theirs);
+        let mac = bet_message_mac(&mac_key, &mine, seq, &text);
+
+        // a fresh key for each message so no two messages are encrypted with the same keystream
+        let ephemeral = KeyPair::from_slice(&rand::random::<[u8; 32]>()).expect("won't be zero");
+        let (mut cipher, _) = crate::ecdh::ecdh(&ephemeral, &theirs.into_point_with_even_y().0);
+        let ciphertext = Ciphertext::create(
+            ephemeral.public_key,
+            &mut cipher,
+            Plaintext::BetMessagev1 {
+                seq,
+                text: text.clone(),
+                mac,
+            },
+        );
+
+        thread.messages.push(BetMessage {
+            seq,
+            sent: true,
+            text,
+            time: now(),
+        });
+        self.gun_db().insert_entity(bet_id, thread)?;
+        Ok((ciphertext, cipher))
+    }
+
+    /// Decrypt and authenticate a message from the counterparty of a bet and add it to the thread.
+    pub fn receive_bet_message(
+        &self,
+        bet_id: BetId,
+        ciphertext: &Ciphertext,
+    ) -> anyhow::Result<BetMessage> {
+        let (my_secret, _, theirs) = self.bet_thread_keys(bet_id)?;
+        let my_keypair = KeyPair::from_slice(&my_secret.to_bytes()).expect("won't be zero");
+        let (mut cipher, _) = crate::ecdh::ecdh(&my_keypair, &ciphertext.public_key);
+        let (seq, text, mac) = match ciphertext
+            .decrypt(&mut cipher)
+            .with_context(|| format!("decrypting message for bet {}", bet_id))?
+        {
+            Plaintext::BetMessagev1 { seq, text, mac } => (seq, text, mac),
+            _ => return Err(anyhow!("This isn't a message about a bet")),
+        };
+
+        let mac_key = crate::ecdh::bet_thread_mac_key(&my_secret, &theirs);
+        if !bool::from(bet_message_mac(&mac_key, &theirs, seq, &text)[..].ct_eq(&mac[..])) {
+            return Err(anyhow!(
+                "The message isn't from the counterparty of bet {} or it has been tampered with",
+                bet_id
+            ));
+        }
+
+        let mut thread = self.bet_thread(bet_id)?;
+        if thread.has_received(seq) {
+            return Err(anyhow!(
+                "Already received message {} for bet {}",
+                seq,
+                bet_id
+            ));
+        }
+        let expected = thread.next_seq(false);
+        if seq > expected {
+            crate::elog!(@warning "Missing messages {} to {} from the counterparty", expected, seq - 1);
+        }
+
+        let message = BetMessage {
+            seq,
+            sent: false,
+            text,
+            time: now(),
+        };
+        thread.messages.push(message.clone());
+        self.gun_db().insert_entity(bet_id, thread)?;
+        Ok(message)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time is after the epoch")
+        .as_secs()
+}
@@ -0,0 +1,114 @@
+//! What a monitoring-only companion needs to keep an eye on the wallet's bets (`gun bet
+//! export-watch`).
+//!
+//! It's meant to be put somewhere always online (e.g. a VPS) that tracks confirmations and oracle
+//! attestations and sends notifications. Everything in it is public: the wallet's descriptors
+//! without private keys and each active bet's txid, joint output descriptor and oracle event. It
+//! can't be used to spend coins or decrypt offers.
+use crate::{
+    betting::{BetId, BetState, OfferedBet},
+    database::StringDescriptor,
+    wallet::GunWallet,
+};
+use bdk::{
+    bitcoin::{self, Amount, Network, OutPoint},
+    KeychainKind,
+};
+use olivia_core::{EventId, OracleId};
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct WatchBundle {
+    pub network: Network,
+    pub external_descriptor: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub internal_descriptor: Option<String>,
+    pub bets: Vec<WatchedBet>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case", tag = "version")]
+pub enum VersionedWatchBundle {
+    #[serde(rename = "1")]
+    V1(WatchBundle),
+}
+
+impl WatchBundle {
+    pub fn into_versioned(self) -> VersionedWatchBundle {
+        VersionedWatchBundle::V1(self)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct WatchedBet {
+    pub id: BetId,
+    /// The bet's state when the bundle was made
+    pub state: String,
+    /// The bet's output (the bet transaction's txid and the output's index)
+    pub outpoint: OutPoint,
+    pub joint_output_descriptor: String,
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub joint_output_value: Amount,
+    pub oracle_id: OracleId,
+    pub event_id: EventId,
+}
+
+impl WatchedBet {
+    /// What there is to watch about a bet that hasn't finished yet.
+    pub fn from_bet_state(id: BetId, bet_state: &BetState) -> Option<Self> {
+        let bet = match bet_state {
+            BetState::Offered {
+                bet: OfferedBet(bet),
+                ..
+            }
+            | BetState::Included { bet, .. }
+            | BetState::Won { bet, .. }
+            | BetState::Claimed {
+                bet, height: None, ..
+            } => bet,
+            _ => return None,
+        };
+        Some(WatchedBet {
+            id,
+            state: bet_state.name().to_string(),
+            outpoint: bet.outpoint(),
+            joint_output_descriptor: bet.joint_output.wallet_descriptor().to_string(),
+            joint_output_value: bet.joint_output_value,
+            oracle_id: bet.oracle_id.clone(),
+            event_id: bet.oracle_event.event.id.clone(),
+        })
+    }
+}
+
+impl GunWallet {
+    pub fn watch_bundle(&self) -> anyhow::Result<WatchBundle> {
+        let bdk_wallet = self.bdk_wallet();
+        // BDK keeps the private keys apart from the descriptors so these are always public
+        let external_descriptor = bdk_wallet
+            .get_descriptor_for_keychain(KeychainKind::External)
+            .to_string();
+        let internal_descriptor = match self
+            .gun_db()
+            .get_entity::<StringDescriptor>(KeychainKind::Internal)?
+        {
+            Some(_) => Some(
+                bdk_wallet
+                    .get_descriptor_for_keychain(KeychainKind::Internal)
+                    .to_string(),
+            ),
+            None => None,
+        };
+        let bets = self
+            .gun_db()
+            .list_entities_print_error::<BetState>()
+            .filter_map(|(id, bet_state)| WatchedBet::from_bet_state(id, &bet_state))
+            .collect();
+        Ok(WatchBundle {
+            network: bdk_wallet.network(),
+            external_descriptor,
+            internal_descriptor,
+            bets,
+        })
+    }
+}
@@ -1,5 +1,5 @@
-use bdk::bitcoin::secp256k1;
-use olivia_secp256k1::ecdsa_fun;
+use bdk::bitcoin::{hashes::Hash, secp256k1, Script, WPubkeyHash};
+use olivia_secp256k1::{ecdsa_fun, schnorr_fun};
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
 pub struct SignedInput {
@@ -14,6 +14,24 @@ pub enum Witness {
         // using ecdsa_fun::Signature here ebcause it serializes to 64 bytes rather than DER
         signature: ecdsa_fun::Signature,
     },
+    /// A taproot key path spend. The key is in the output being spent so only the signature is
+    /// needed.
+    P2trKeySpend {
+        signature: schnorr_fun::Signature,
+        /// The sighash flag if it isn't SIGHASH_DEFAULT
+        sighash: Option<u8>,
+    },
+}
+
+/// The weight of the witness needed to spend an output with this script (if we know how to)
+pub fn satisfaction_weight(script_pubkey: &Script) -> Option<usize> {
+    if script_pubkey.is_v0_p2wpkh() {
+        Some(4 + 1 + 73 + 33)
+    } else if script_pubkey.is_v1_p2tr() {
+        Some(1 + 1 + 65)
+    } else {
+        None
+    }
 }
 
 impl Witness {
@@ -29,6 +47,43 @@ impl Witness {
                 let pk_bytes = key.serialize().to_vec();
                 vec![sig_bytes, pk_bytes]
             }
+            Witness::P2trKeySpend { signature, sighash } => {
+                let mut sig_bytes = signature.to_bytes().to_vec();
+                sig_bytes.extend(sighash);
+                vec![sig_bytes]
+            }
+        }
+    }
+
+    /// Whether this is the kind of witness that spends `script_pubkey` (and for p2wpkh whether it
+    /// has the right key). Signatures aren't checked.
+    pub fn can_spend(&self, script_pubkey: &Script) -> bool {
+        match self {
+            Witness::P2wpkh { key, .. } => {
+                *script_pubkey == Script::new_v0_wpkh(&WPubkeyHash::hash(&key.serialize()))
+            }
+            Witness::P2trKeySpend { .. } => script_pubkey.is_v1_p2tr(),
+        }
+    }
+
+    /// Whether the signature commits to the whole transaction. A signature that doesn't (e.g.
+    /// `SIGHASH_NONE`) would let the bet transaction be changed after we've signed it.
+    pub fn signs_all(&self) -> bool {
+        match self {
+            // we always encode these with SIGHASH_ALL
+            Witness::P2wpkh { .. } => true,
+            Witness::P2trKeySpend { sighash, .. } => matches!(sighash, None | Some(0x01)),
+        }
+    }
+
+    /// Decode the witness of an input spending `script_pubkey`
+    pub fn decode(w: Vec<Vec<u8>>, script_pubkey: &Script) -> Option<Self> {
+        if script_pubkey.is_v0_p2wpkh() {
+            Self::decode_p2wpkh(w)
+        } else if script_pubkey.is_v1_p2tr() {
+            Self::decode_p2tr_key_spend(w)
+        } else {
+            None
         }
     }
 
@@ -42,4 +97,21 @@ impl Witness {
         let key = secp256k1::PublicKey::from_slice(&key_bytes).ok()?;
         Some(Witness::P2wpkh { key, signature })
     }
+
+    pub fn decode_p2tr_key_spend(w: Vec<Vec<u8>>) -> Option<Self> {
+        let sig_bytes = match &w[..] {
+            [sig_bytes] => sig_bytes,
+            _ => return None,
+        };
+        let sighash = match sig_bytes.len() {
+            64 => None,
+            // an explicit SIGHASH_DEFAULT isn't allowed
+            65 if sig_bytes[64] != 0x00 => Some(sig_bytes[64]),
+            _ => return None,
+        };
+        let mut signature = [0u8; 64];
+        signature.copy_from_slice(&sig_bytes[..64]);
+        let signature = schnorr_fun::Signature::from_bytes(signature)?;
+        Some(Witness::P2trKeySpend { signature, sighash })
+    }
 }
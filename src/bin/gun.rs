@@ -3,6 +3,7 @@ use bdk::blockchain::esplora::EsploraBlockchainConfig;
 use gun_wallet::{
     cmd::{self, *},
     elog,
+    lock::WalletLock,
 };
 use std::path::PathBuf;
 use structopt::StructOpt;
@@ -24,6 +25,24 @@ pub struct Opt {
     /// Return outupt in simplified UNIX table (tabs and newlines)
     #[structopt(short, long)]
     tabs: bool,
+    /// How to print errors: text or json (implied by --json)
+    #[structopt(long, default_value = "text")]
+    error_format: ErrorFormat,
+    /// Only show messages at or above this level (error, warn, info, debug or trace)
+    #[structopt(long, default_value = "info", env = "GUN_LOG_LEVEL")]
+    log_level: log::LevelFilter,
+    /// Also write messages to gun.log in the wallet directory (rotated when it gets big)
+    #[structopt(long, env = "GUN_LOG_FILE")]
+    log_file: bool,
+    /// Don't lock the wallet while running (only if you know no other gun process is using it)
+    #[structopt(long)]
+    no_lock: bool,
+    /// Answer yes to every question (e.g. confirming a transaction). Passphrases are still asked for.
+    #[structopt(long, env = "GUN_ASSUME_YES")]
+    assume_yes: bool,
+    /// Allow paying to segwit versions nothing can spend yet (v2 and up or v1 that isn't taproot)
+    #[structopt(long)]
+    allow_future_segwit: bool,
 }
 
 #[derive(StructOpt, Debug, Clone)]
@@ -31,8 +50,12 @@ pub struct Opt {
 pub enum Commands {
     /// Make or take a bet
     Bet(BetOpt),
+    /// 2-of-3 escrow contracts with an arbiter
+    Escrow(EscrowOpt),
+    /// Names for the people you bet with
+    Contact(ContactOpt),
     /// View the balance of the wallet
-    Balance,
+    Balance(BalanceOpt),
     /// Get addresses
     Address(AddressOpt),
     /// View Transactions
@@ -41,12 +64,85 @@ pub enum Commands {
     Utxo(UtxoOpt),
     /// Send funds out of wallet
     Send(SendOpt),
+    /// Send to many recipients in one transaction from a CSV or JSON file
+    SendMany(SendManyOpt),
+    /// Receive silent payments (BIP-352)
+    SilentPayment(SilentPaymentOpt),
     /// Setup a new wallet
     Setup(SetupOpt),
     /// Split coins into evenly sized outputs.
     Split(SplitOpt),
     /// Get/set configuration values
     Config(ConfigOpt),
+    /// Create or restore an encrypted wallet backup
+    Backup(BackupOpt),
+    /// Tools for developers building on gun
+    Dev(DevOpt),
+    /// Check the wallet for inconsistencies
+    Doctor(DoctorOpt),
+    /// Hardware signing devices
+    Signer(SignerOpt),
+    /// Move PSBTs to and from airgapped signers
+    Psbt(PsbtOpt),
+    /// Spend using the wallet's timelocked recovery key
+    Recover(RecoverOpt),
+    /// Derive child seeds and keys from the wallet's seed with BIP85
+    Bip85(Bip85Opt),
+    /// Manage the protocol secrets bet keys are derived from
+    Keychain(KeychainOpt),
+    /// Check that your seed words backup matches the wallet
+    VerifyBackup,
+    /// Sign and verify messages to prove you own an address
+    Message(MessageOpt),
+    /// Export and check the audit trail of a settled bet
+    Audit(AuditOpt),
+    /// Recurring payments
+    Schedule(ScheduleOpt),
+    /// Sweep every coin (including won bets) to cold storage with PSBTs for offline signing
+    Evacuate(EvacuateOpt),
+    /// Check transactions for privacy leaks
+    Privacy(PrivacyOpt),
+    /// Export wallet data for use in other software
+    Export(ExportOpt),
+    /// Show how much is at risk in unfinished bets by oracle and by when their outcomes are due
+    ///
+    /// The expected value uses the `win-probabilities` for each tag in the config.
+    Risk(RiskOpt),
+    /// Forget the cached wallet history and sync it again from scratch
+    Rescan(RescanOpt),
+    /// Check this build reproduces gun's key derivation and encryption test vectors
+    Selftest,
+    /// Print a shell completion script (bash, zsh, fish, powershell or elvish)
+    Completions { shell: structopt::clap::Shell },
+    /// Lists of things for completion scripts to complete
+    #[structopt(setting = structopt::clap::AppSettings::Hidden)]
+    Complete(CompleteOpt),
+}
+
+impl Commands {
+    /// Whether the command can change the wallet's databases or bets when not syncing.
+    fn mutates_wallet(&self) -> bool {
+        use Commands::*;
+        !matches!(
+            self,
+            Balance(_)
+                | Utxo(UtxoOpt::List | UtxoOpt::Show { .. })
+                | Privacy(_)
+                | Export(_)
+                | Risk(_)
+                | Signer(_)
+                | Dev(_)
+                | Bip85(_)
+                | Completions { .. }
+                | Complete(_)
+                | Selftest
+                | Bet(BetOpt::Simulate { .. })
+                | Message(MessageOpt::Verify { .. })
+                | Audit(_)
+                | Psbt(PsbtOpt::ShowQr { .. } | PsbtOpt::ReadQr)
+                | SilentPayment(SilentPaymentOpt::Address | SilentPaymentOpt::List)
+        )
+    }
 }
 
 fn main() -> anyhow::Result<()> {
@@ -57,15 +153,62 @@ fn main() -> anyhow::Result<()> {
         .gun_dir
         .unwrap_or_else(|| dirs::home_dir().unwrap().join(".gun"));
 
+    // before `gun setup` there's no wallet directory to put the log file in
+    let log_file = Some(wallet_dir.join("gun.log")).filter(|_| opt.log_file && wallet_dir.exists());
+    gun_wallet::logging::init(opt.log_level, log_file.as_deref())?;
+    if opt.assume_yes {
+        gun_wallet::interaction::install(std::sync::Arc::new(gun_wallet::interaction::AssumeYes))?;
+    }
+    if opt.allow_future_segwit {
+        gun_wallet::script_kind::allow_future_segwit();
+    }
+
+    let lock = if opt.no_lock || !wallet_dir.exists() {
+        None
+    } else if sync || opt.command.mutates_wallet() {
+        Some(WalletLock::acquire(&wallet_dir)?)
+    } else {
+        WalletLock::check(&wallet_dir);
+        None
+    };
+
     let res = if let Commands::Setup(opt) = opt.command {
         cmd::run_setup(&wallet_dir, opt)
+    } else if let Commands::Backup(opt) = opt.command {
+        cmd::run_backup_cmd(&wallet_dir, opt)
+    } else if let Commands::Dev(opt) = opt.command {
+        cmd::run_dev_cmd(&wallet_dir, opt)
+    } else if let Commands::Bip85(opt) = opt.command {
+        cmd::run_bip85_cmd(&wallet_dir, opt)
+    } else if let Commands::Selftest = opt.command {
+        cmd::run_selftest()
+    } else if let Commands::Bet(BetOpt::Simulate { against_self }) = opt.command {
+        cmd::run_simulate(against_self)
+    } else if let Commands::Completions { shell } = opt.command {
+        cmd::run_completions(Opt::clap(), shell)
+    } else if let Commands::Complete(opt) = opt.command {
+        cmd::run_complete_cmd(&wallet_dir, opt)
+    } else if let Commands::Rescan(opt) = opt.command {
+        cmd::run_rescan(&wallet_dir, opt)
+    } else if let Commands::Signer(opt) = opt.command {
+        cmd::run_signer_cmd(opt)
+    } else if let Commands::Psbt(psbt_opt @ (PsbtOpt::ShowQr { .. } | PsbtOpt::ReadQr)) =
+        opt.command
+    {
+        cmd::run_psbt_cmd(None, psbt_opt)
+    } else if let Commands::Message(message_opt @ MessageOpt::Verify { .. }) = opt.command {
+        cmd::run_message_cmd(None, message_opt)
+    } else if let Commands::Audit(audit_opt @ AuditOpt::Verify { .. }) = opt.command {
+        cmd::run_audit_cmd(None, audit_opt)
     } else {
         let (wallet, keychain, config) = cmd::load_wallet(&wallet_dir)?;
 
         if sync {
             use Commands::*;
 
-            if let Balance | Address(_) | Send(_) | Tx(_) | Utxo(_) = opt.command {
+            if let Balance(_) | Address(_) | Send(_) | SendMany(_) | Tx(_) | Utxo(_) | Doctor(_)
+            | Recover(_) | Schedule(_) | Privacy(_) | Evacuate(_) | Export(_) = opt.command
+            {
                 let EsploraBlockchainConfig {
                     stop_gap,
                     base_url,
@@ -79,40 +222,75 @@ fn main() -> anyhow::Result<()> {
                     stop_gap,
                     concurrency.unwrap_or(1)
                 );
-                wallet.sync()?;
+                let started = std::time::Instant::now();
+                wallet.sync_with_progress(cmd::SyncProgress::default())?;
+                elog!(@info "synced in {:.1}s", started.elapsed().as_secs_f32());
             }
 
             // we poke bets to update balance from bets as well.
-            if let Balance = opt.command {
-                wallet.poke_bets()
+            if let Balance(_) = opt.command {
+                wallet.poke_bets();
+                cmd::auto_claim(&wallet, &config);
             }
         }
 
         match opt.command {
             Commands::Bet(opt) => {
-                let keychain = match keychain {
-                    Some(keychain) => keychain,
-                    None => {
-                        return Err(anyhow!(
-                        "This wallet wasn't set up with a protocol secret so you can't do betting"
-                    ))
-                    }
-                };
-                cmd::run_bet_cmd(&wallet, &keychain, opt, sync)
+                let keychain = cmd::require_keychain(keychain, &config, "do betting")?;
+                if !wallet.is_p2wpkh() {
+                    return Err(anyhow!(
+                        "Betting is only supported for single key (wpkh) wallets"
+                    ));
+                }
+                cmd::run_bet_cmd(&wallet, &keychain, &config, opt, sync)
             }
-            Commands::Balance => cmd::run_balance(&wallet, sync),
-            Commands::Address(opt) => cmd::get_address(&wallet, opt),
-            Commands::Send(opt) => cmd::run_send(&wallet, opt),
+            Commands::Escrow(opt) => {
+                let keychain = cmd::require_keychain(keychain, &config, "use escrows")?;
+                cmd::run_escrow_cmd(&wallet, &keychain, &config, opt)
+            }
+            Commands::Keychain(opt) => {
+                let keychain = cmd::require_keychain(keychain, &config, "use the keychain")?;
+                cmd::run_keychain_cmd(&wallet_dir, &wallet, &keychain, &config, opt)
+            }
+            Commands::Contact(opt) => cmd::run_contact_cmd(&wallet, opt),
+            Commands::Balance(opt) => cmd::run_balance(&wallet, &config, sync, opt),
+            Commands::Address(opt) => cmd::get_address(&wallet, &config, opt),
+            Commands::Send(opt) => cmd::run_send(&wallet, &config, opt),
+            Commands::SendMany(opt) => cmd::run_send_many(&wallet, &config, opt),
+            Commands::SilentPayment(opt) => cmd::run_silent_payment_cmd(&wallet, &config, opt),
             Commands::Setup(_) => unreachable!("we handled setup already"),
-            Commands::Tx(opt) => cmd::run_transaction_cmd(&wallet, opt),
+            Commands::Backup(_) => unreachable!("we handled backup already"),
+            Commands::Dev(_) => unreachable!("we handled dev already"),
+            Commands::Signer(_) => unreachable!("we handled signer already"),
+            Commands::Bip85(_) => unreachable!("we handled bip85 already"),
+            Commands::Psbt(opt) => cmd::run_psbt_cmd(Some((&wallet, &config)), opt),
+            Commands::Recover(opt) => cmd::run_recover_cmd(&wallet, &config, opt),
+            Commands::VerifyBackup => cmd::run_verify_backup(&wallet_dir, &wallet),
+            Commands::Message(opt) => cmd::run_message_cmd(Some(&wallet), opt),
+            Commands::Audit(opt) => cmd::run_audit_cmd(Some(&wallet), opt),
+            Commands::Schedule(opt) => cmd::run_schedule_cmd(&wallet, &config, opt),
+            Commands::Privacy(opt) => cmd::run_privacy_cmd(&wallet, opt),
+            Commands::Evacuate(opt) => cmd::run_evacuate(&wallet, opt),
+            Commands::Export(opt) => cmd::run_export_cmd(&wallet, &config, opt),
+            Commands::Risk(opt) => cmd::run_risk(&wallet, &config, opt),
+            Commands::Rescan(_) => unreachable!("we handled rescan already"),
+            Commands::Completions { .. } | Commands::Complete(_) => {
+                unreachable!("we handled completions already")
+            }
+            Commands::Selftest => unreachable!("we handled selftest already"),
+            Commands::Tx(opt) => cmd::run_transaction_cmd(&wallet, &config, opt),
             Commands::Utxo(opt) => cmd::run_utxo_cmd(&wallet, opt),
-            Commands::Split(opt) => cmd::run_split_cmd(&wallet, opt),
+            Commands::Split(opt) => cmd::run_split_cmd(&wallet, &config, opt),
+            Commands::Doctor(opt) => cmd::run_doctor(&wallet, opt),
             Commands::Config(opt) => {
                 cmd::run_config_cmd(&wallet_dir, &wallet, &wallet_dir.join("config.json"), opt)
             }
         }
     };
 
+    // process::exit below won't run destructors
+    drop(lock);
+
     match res {
         Ok(output) => {
             if opt.json {
@@ -128,15 +306,17 @@ fn main() -> anyhow::Result<()> {
             }
         }
         Err(e) => {
-            if opt.json {
-                let err_json = serde_json::json!({
-                    "error" : format!("{}", e),
-                });
-                println!("{}", serde_json::to_string_pretty(&err_json).unwrap());
-                std::process::exit(1)
+            if opt.json || opt.error_format == ErrorFormat::Json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&cmd::error_json(&e)).unwrap()
+                );
             } else {
-                return Err(e);
+                eprintln!("Error: {:?}", e);
             }
+            log::error!(target: "gun::error", "{:?}", e);
+            log::logger().flush();
+            std::process::exit(ErrorKind::of(&e).exit_code())
         }
     }
 
@@ -1,26 +1,83 @@
-use bdk::bitcoin::{
-    hashes::{sha512, Hash, HashEngine, Hmac, HmacEngine},
-    secp256k1::{Secp256k1, Signing},
-    util::bip32::{ChildNumber, DerivationPath, ExtendedPrivKey},
+use bdk::{
+    bitcoin::{
+        hashes::{sha512, Hash, HashEngine, Hmac, HmacEngine},
+        secp256k1::{self, Secp256k1, Signing},
+        util::bip32::{ChainCode, ChildNumber, DerivationPath, ExtendedPrivKey},
+    },
+    keys::bip39::{Language, Mnemonic},
 };
 
-pub fn get_bip85_bytes<S: Signing, const L: usize>(
+const BIP85_PURPOSE: u32 = 83696968;
+const APP_BIP39: u32 = 39;
+const APP_XPRV: u32 = 32;
+const APP_HEX: u32 = 128169;
+
+/// The largest index a BIP85 derivation can use (indexes are hardened so they must be below 2^31).
+pub const MAX_INDEX: u32 = (1 << 31) - 1;
+
+/// Derive the 64 bytes of BIP85 entropy for an application path (everything after the purpose).
+fn derive_entropy<S: Signing>(
     xpriv: ExtendedPrivKey,
-    index: u32,
+    app_path: &[u32],
     secp: &Secp256k1<S>,
-) -> [u8; L] {
-    let path = DerivationPath::from(vec![
-        ChildNumber::Hardened { index: 83696968 },
-        ChildNumber::Hardened { index: 128169 },
-        ChildNumber::Hardened { index: L as u32 },
-        ChildNumber::Hardened { index },
-    ]);
-    let bip85_key = xpriv.derive_priv(secp, &path).unwrap();
+) -> anyhow::Result<[u8; 64]> {
+    let path = std::iter::once(BIP85_PURPOSE)
+        .chain(app_path.iter().cloned())
+        .map(|index| {
+            ChildNumber::from_hardened_idx(index)
+                .map_err(|_| anyhow::anyhow!("BIP85 index {} is more than {}", index, MAX_INDEX))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let bip85_key = xpriv.derive_priv(secp, &DerivationPath::from(path))?;
 
     let mut engine = HmacEngine::<sha512::Hash>::new("bip-entropy-from-k".as_bytes());
     engine.input(&bip85_key.private_key.serialize_secret());
-    let hash = Hmac::<sha512::Hash>::from_engine(engine).into_inner();
-    hash[..L].try_into().unwrap()
+    Ok(Hmac::<sha512::Hash>::from_engine(engine).into_inner())
+}
+
+pub fn get_bip85_bytes<S: Signing, const L: usize>(
+    xpriv: ExtendedPrivKey,
+    index: u32,
+    secp: &Secp256k1<S>,
+) -> anyhow::Result<[u8; L]> {
+    let entropy = derive_entropy(xpriv, &[APP_HEX, L as u32, index], secp)?;
+    Ok(entropy[..L].try_into().unwrap())
+}
+
+/// Derive a child BIP39 mnemonic (English). `n_words` must be 12, 18 or 24.
+pub fn get_bip85_mnemonic<S: Signing>(
+    xpriv: ExtendedPrivKey,
+    n_words: u32,
+    index: u32,
+    secp: &Secp256k1<S>,
+) -> anyhow::Result<Mnemonic> {
+    let n_bytes = match n_words {
+        12 => 16,
+        18 => 24,
+        24 => 32,
+        _ => return Err(anyhow::anyhow!("only 12, 18 or 24 words are supported")),
+    };
+    // English is language 0
+    let entropy = derive_entropy(xpriv, &[APP_BIP39, 0, n_words, index], secp)?;
+    Mnemonic::from_entropy_in(Language::English, &entropy[..n_bytes])
+        .map_err(|e| anyhow::anyhow!("{}", e))
+}
+
+/// Derive a child master extended private key.
+pub fn get_bip85_xprv<S: Signing>(
+    xpriv: ExtendedPrivKey,
+    index: u32,
+    secp: &Secp256k1<S>,
+) -> anyhow::Result<ExtendedPrivKey> {
+    let entropy = derive_entropy(xpriv, &[APP_XPRV, index], secp)?;
+    Ok(ExtendedPrivKey {
+        network: xpriv.network,
+        depth: 0,
+        parent_fingerprint: Default::default(),
+        child_number: ChildNumber::from_normal_idx(0).unwrap(),
+        chain_code: ChainCode::from(&entropy[..32]),
+        private_key: secp256k1::SecretKey::from_slice(&entropy[32..])?,
+    })
 }
 
 #[cfg(test)]
@@ -37,7 +94,7 @@ mod tests {
             hex::decode("ea3ceb0b02ee8e587779c63f4b7b3a21e950a213f1ec53cab608d13e8796e6dc")
                 .expect("reading in expected test bytes");
         assert_eq!(
-            get_bip85_bytes::<_, 32>(xpriv, 0, &secp).to_vec(),
+            get_bip85_bytes::<_, 32>(xpriv, 0, &secp).unwrap().to_vec(),
             expected_hex
         );
     }
@@ -50,8 +107,37 @@ mod tests {
             hex::decode("492db4698cf3b73a5a24998aa3e9d7fa96275d85724a91e71aa2d645442f878555d078fd1f1f67e368976f04137b1f7a0d19232136ca50c44614af72b5582a5c")
                 .expect("reading in expected test bytes");
         assert_eq!(
-            get_bip85_bytes::<_, 64>(xpriv, 0, &secp).to_vec(),
+            get_bip85_bytes::<_, 64>(xpriv, 0, &secp).unwrap().to_vec(),
             expected_hex
         );
     }
+
+    #[test]
+    fn test_vector_bip39_12_words() {
+        let secp = Secp256k1::signing_only();
+        let xpriv = ExtendedPrivKey::from_str("xprv9s21ZrQH143K2LBWUUQRFXhucrQqBpKdRRxNVq2zBqsx8HVqFk2uYo8kmbaLLHRdqtQpUm98uKfu3vca1LqdGhUtyoFnCNkfmXRyPXLjbKb").expect("reading extended private key");
+        assert_eq!(
+            get_bip85_mnemonic(xpriv, 12, 0, &secp).unwrap().to_string(),
+            "girl mad pet galaxy egg matter matrix prison refuse sense ordinary nose"
+        );
+    }
+
+    #[test]
+    fn test_vector_xprv() {
+        let secp = Secp256k1::signing_only();
+        let xpriv = ExtendedPrivKey::from_str("xprv9s21ZrQH143K2LBWUUQRFXhucrQqBpKdRRxNVq2zBqsx8HVqFk2uYo8kmbaLLHRdqtQpUm98uKfu3vca1LqdGhUtyoFnCNkfmXRyPXLjbKb").expect("reading extended private key");
+        assert_eq!(
+            get_bip85_xprv(xpriv, 0, &secp).unwrap().to_string(),
+            "xprv9s21ZrQH143K2srSbCSg4m4kLvPMzcWydgmKEnMmoZUurYuBuYG46c6P71UGXMzmriLzCCBvKQWBUv3vPB3m1SATMhp3uEjXHJ42jFg7myX"
+        );
+    }
+
+    #[test]
+    fn index_out_of_range() {
+        let secp = Secp256k1::signing_only();
+        let xpriv = ExtendedPrivKey::from_str("xprv9s21ZrQH143K2LBWUUQRFXhucrQqBpKdRRxNVq2zBqsx8HVqFk2uYo8kmbaLLHRdqtQpUm98uKfu3vca1LqdGhUtyoFnCNkfmXRyPXLjbKb").expect("reading extended private key");
+        assert!(get_bip85_xprv(xpriv, MAX_INDEX, &secp).is_ok());
+        assert!(get_bip85_xprv(xpriv, MAX_INDEX + 1, &secp).is_err());
+        assert!(get_bip85_bytes::<_, 64>(xpriv, u32::MAX, &secp).is_err());
+    }
 }
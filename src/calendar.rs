@@ -0,0 +1,237 @@
+//! When the events of unfinished bets are expected to have an outcome (`gun bet calendar`).
+//!
+//! The outcome time comes from the oracle's event so it's only an expectation (a match can be
+//! delayed) but it's when you need to be around to claim. The calendar can be exported as
+//! iCalendar ([RFC 5545]) to put the times in your calendar app.
+//!
+//! [RFC 5545]: https://www.rfc-editor.org/rfc/rfc5545
+use crate::{
+    betting::{BetId, BetOrProp, BetState, OfferedBet},
+    chrono::{NaiveDate, NaiveDateTime},
+};
+use bdk::bitcoin::Amount;
+use olivia_core::EventId;
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CalendarEntry {
+    pub bet_id: BetId,
+    pub state: &'static str,
+    pub time: NaiveDateTime,
+    pub oracle: String,
+    pub event_id: EventId,
+    pub risk: Amount,
+    pub tags: Vec<String>,
+}
+
+impl CalendarEntry {
+    pub fn summary(&self) -> String {
+        format!(
+            "gun bet {}: {}",
+            self.bet_id,
+            olivia_describe::event_short(&self.event_id)
+        )
+    }
+}
+
+/// The bets that are waiting for an outcome (proposed, offered or in the chain) expected at or
+/// after `from`, soonest first.
+pub fn entries(
+    bets: impl IntoIterator<Item = (BetId, BetState)>,
+    from: NaiveDateTime,
+) -> Vec<CalendarEntry> {
+    let mut entries = bets
+        .into_iter()
+        .filter(|(_, bet_state)| {
+            matches!(
+                bet_state,
+                BetState::Proposed { .. } | BetState::Offered { .. } | BetState::Included { .. }
+            )
+        })
+        .filter_map(|(bet_id, bet_state)| {
+            let state = bet_state.name();
+            let tags = bet_state.tags().to_vec();
+            let (oracle_event, oracle, risk) = match bet_state.into_bet_or_prop() {
+                BetOrProp::Proposal(local_proposal) => (
+                    local_proposal.oracle_event,
+                    local_proposal.proposal.oracle,
+                    local_proposal.proposal.value,
+                ),
+                BetOrProp::Bet(bet)
+                | BetOrProp::OfferedBet {
+                    bet: OfferedBet(bet),
+                    ..
+                } => (bet.oracle_event, bet.oracle_id, bet.local_value),
+            };
+            let time = oracle_event.event.expected_outcome_time?;
+            Some(CalendarEntry {
+                bet_id,
+                state,
+                time,
+                oracle,
+                event_id: oracle_event.event.id,
+                risk,
+                tags,
+            })
+        })
+        .filter(|entry| entry.time >= from)
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| (entry.time, entry.bet_id));
+    entries
+}
+
+/// `entries` grouped by the (UTC) day of their outcome time.
+pub fn by_day(entries: &[CalendarEntry]) -> BTreeMap<NaiveDate, Vec<&CalendarEntry>> {
+    let mut days = BTreeMap::<NaiveDate, Vec<&CalendarEntry>>::new();
+    for entry in entries {
+        days.entry(entry.time.date()).or_default().push(entry);
+    }
+    days
+}
+
+fn ical_time(time: NaiveDateTime) -> String {
+    time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Escape TEXT values (RFC 5545 section 3.3.11).
+fn ical_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ';' => escaped.push_str("\\;"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Write a content line folded so no line is longer than 75 octets (RFC 5545 section 3.1).
+fn push_line(ical: &mut String, line: &str) {
+    let mut len = 0;
+    for c in line.chars() {
+        if len + c.len_utf8() > 75 {
+            ical.push_str("\r\n ");
+            // the space starting the continuation counts towards its length
+            len = 1;
+        }
+        ical.push(c);
+        len += c.len_utf8();
+    }
+    ical.push_str("\r\n");
+}
+
+/// Serialize `entries` as an iCalendar with an event for each. `now` is the event's `DTSTAMP`.
+///
+/// The `UID` only depends on the bet so importing a newer export updates the events rather than
+/// duplicating them.
+pub fn to_ical(entries: &[CalendarEntry], now: NaiveDateTime) -> String {
+    let mut ical = String::new();
+    push_line(&mut ical, "BEGIN:VCALENDAR");
+    push_line(&mut ical, "VERSION:2.0");
+    push_line(&mut ical, "PRODID:-//gun//bet calendar//EN");
+    push_line(&mut ical, "CALSCALE:GREGORIAN");
+    for entry in entries {
+        let mut description = format!(
+            "Bet {} ({}) risking {} on {}{}",
+            entry.bet_id, entry.state, entry.risk, entry.oracle, entry.event_id
+        );
+        if !entry.tags.is_empty() {
+            description.push_str(&format!("\nTags: {}", entry.tags.join(", ")));
+        }
+        push_line(&mut ical, "BEGIN:VEVENT");
+        push_line(&mut ical, &format!("UID:gun-bet-{}@gun", entry.bet_id));
+        push_line(&mut ical, &format!("DTSTAMP:{}", ical_time(now)));
+        push_line(&mut ical, &format!("DTSTART:{}", ical_time(entry.time)));
+        push_line(
+            &mut ical,
+            &format!("SUMMARY:{}", ical_escape(&entry.summary())),
+        );
+        push_line(
+            &mut ical,
+            &format!("DESCRIPTION:{}", ical_escape(&description)),
+        );
+        push_line(&mut ical, "END:VEVENT");
+    }
+    push_line(&mut ical, "END:VCALENDAR");
+    ical
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    fn entry(bet_id: BetId, time: &str) -> CalendarEntry {
+        CalendarEntry {
+            bet_id,
+            state: "confirmed",
+            time: NaiveDateTime::from_str(time).unwrap(),
+            oracle: "h00.ooo".into(),
+            event_id: EventId::from_str("/EPL/match/2021-08-22/ARS_CHE.vs=CHE_win").unwrap(),
+            risk: Amount::from_sat(100_000),
+            tags: vec!["football".into(), "a;b".into()],
+        }
+    }
+
+    #[test]
+    fn grouped_by_day() {
+        let entries = vec![
+            entry(1, "2021-08-22T15:30:00"),
+            entry(2, "2021-08-22T23:59:59"),
+            entry(3, "2021-08-23T00:00:00"),
+        ];
+        let days = by_day(&entries);
+        assert_eq!(days.len(), 2);
+        let day = |s| NaiveDate::from_str(s).unwrap();
+        assert_eq!(
+            days[&day("2021-08-22")]
+                .iter()
+                .map(|entry| entry.bet_id)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(days[&day("2021-08-23")][0].bet_id, 3);
+    }
+
+    #[test]
+    fn escapes_text() {
+        assert_eq!(ical_escape("a\\b;c,d\r\ne"), "a\\\\b\\;c\\,d\\ne");
+    }
+
+    #[test]
+    fn folds_long_lines() {
+        let mut ical = String::new();
+        let line = format!("DESCRIPTION:{}", "é".repeat(100));
+        push_line(&mut ical, &line);
+        let lines = ical
+            .split("\r\n")
+            .filter(|l| !l.is_empty())
+            .collect::<Vec<_>>();
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|l| l.len() <= 75));
+        assert!(lines[1..].iter().all(|l| l.starts_with(' ')));
+        let unfolded = lines
+            .iter()
+            .enumerate()
+            .map(|(i, l)| if i == 0 { *l } else { &l[1..] })
+            .collect::<String>();
+        assert_eq!(unfolded, line);
+    }
+
+    #[test]
+    fn ical_events() {
+        let now = NaiveDateTime::from_str("2021-08-20T10:00:00").unwrap();
+        let ical = to_ical(&[entry(7, "2021-08-22T15:30:00")], now);
+        assert!(ical.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(ical.ends_with("END:VCALENDAR\r\n"));
+        assert!(ical.contains("\r\nUID:gun-bet-7@gun\r\n"));
+        assert!(ical.contains("\r\nDTSTAMP:20210820T100000Z\r\n"));
+        assert!(ical.contains("\r\nDTSTART:20210822T153000Z\r\n"));
+        assert!(ical.contains("a\\;b"));
+        assert!(!ical.replace("\r\n", "").contains('\n'));
+    }
+}
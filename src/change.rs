@@ -1,6 +1,11 @@
 use bdk::bitcoin::{Amount, Script};
 use olivia_secp256k1::fun::hex;
 
+/// Change this small isn't given an output by default (what bdk's `IsDust` uses).
+pub const DEFAULT_DUST_THRESHOLD: u64 = 546;
+/// The most change that can be treated as dust. Anything bigger is worth keeping.
+pub const MAX_DUST_THRESHOLD: u64 = 10_000;
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct BinScript(Script);
 
@@ -0,0 +1,333 @@
+//! A high level way to use gun from other programs.
+//!
+//! [`GunClient`] does the same things as the `gun` commands but never reads from stdin and
+//! returns typed results rather than [`CmdOutput`](crate::cmd::CmdOutput). Anything that needs
+//! confirming (e.g. broadcasting a transaction) is asked of the client's [`Interaction`] so a GUI
+//! can show a dialog and a bot can decide for itself.
+use crate::{
+    betting::*,
+    cmd,
+    config::Config,
+    interaction::{self, Interaction},
+    keychain::Keychain,
+    risk, script_kind,
+    wallet::GunWallet,
+    FeeSpec, ValueChoice,
+};
+use anyhow::anyhow;
+use bdk::{
+    bitcoin::{util::psbt::PartiallySignedTransaction as Psbt, Address, Amount, Txid},
+    blockchain::Broadcast,
+    SignOptions,
+};
+use olivia_core::Outcome;
+use std::{path::Path, sync::Arc};
+use url::Url;
+
+#[derive(Clone, Debug)]
+pub struct Proposed {
+    pub bet_id: BetId,
+    /// The proposal to post
+    pub proposal: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Offered {
+    pub bet_id: BetId,
+    /// The encrypted offer to send to the proposer
+    pub offer: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Taken {
+    pub bet_id: BetId,
+    pub txid: Txid,
+}
+
+#[derive(Clone, Debug)]
+pub struct Claimed {
+    pub bet_ids: Vec<BetId>,
+    pub txid: Txid,
+}
+
+pub struct GunClient {
+    wallet: GunWallet,
+    keychain: Option<Keychain>,
+    config: Config,
+    interaction: Arc<dyn Interaction>,
+}
+
+impl GunClient {
+    /// Load the wallet in `wallet_dir` (e.g. `~/.gun`) asking questions through the installed
+    /// interaction (see [`interaction::install`]).
+    pub fn load(wallet_dir: &Path) -> anyhow::Result<Self> {
+        Self::load_with_interaction(wallet_dir, interaction::current())
+    }
+
+    /// Load the wallet in `wallet_dir` asking all questions (including the signers') through
+    /// `interaction`.
+    pub fn load_with_interaction(
+        wallet_dir: &Path,
+        interaction: Arc<dyn Interaction>,
+    ) -> anyhow::Result<Self> {
+        let (wallet, keychain, config) =
+            cmd::load_wallet_with_interaction(wallet_dir, interaction.clone())?;
+        Ok(GunClient {
+            wallet,
+            keychain,
+            config,
+            interaction,
+        })
+    }
+
+    pub fn wallet(&self) -> &GunWallet {
+        &self.wallet
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Sync the wallet and move bets along.
+    pub fn sync(&self) -> anyhow::Result<()> {
+        self.wallet.sync()?;
+        self.wallet.poke_bets();
+        Ok(())
+    }
+
+    fn keychain(&self) -> anyhow::Result<&Keychain> {
+        self.keychain.as_ref().ok_or(anyhow!(
+            "This wallet wasn't set up with a protocol secret so you can't do betting"
+        ))
+    }
+
+    /// Propose a bet on the event at `event_url`. Returns `None` if the user said no.
+    pub fn propose(
+        &self,
+        event_url: Url,
+        value: ValueChoice,
+        tags: Vec<String>,
+    ) -> anyhow::Result<Option<Proposed>> {
+        let keychain = self.keychain()?;
+        let oracle_id = event_url
+            .host_str()
+            .ok_or(anyhow!("url {} missing host", event_url))?
+            .to_string();
+        let (oracle_event, _, is_attested) =
+            cmd::get_oracle_event_from_url(&self.wallet, event_url, false)?;
+        if is_attested {
+            return Err(anyhow!("{} already attested", oracle_event.event.id));
+        }
+        let question = format!(
+            "Propose a bet on the {}",
+            olivia_describe::event_short(&oracle_event.event.id)
+        );
+        let args = BetArgs {
+            value,
+            tags,
+            ..Default::default()
+        };
+        let local_proposal =
+            self.wallet
+                .make_proposal(oracle_id, oracle_event, args, keychain, None)?;
+        if !self.tag_limits_ok(&local_proposal.tags, local_proposal.proposal.value)?
+            || !self.interaction.confirm(&question)
+        {
+            return Ok(None);
+        }
+        let proposal = local_proposal.proposal.clone().into_versioned().to_string();
+        let bet_id = self.wallet.insert_proposal(local_proposal)?;
+        Ok(Some(Proposed { bet_id, proposal }))
+    }
+
+    /// Offer to bet `value` that the proposal's event has the outcome `choice`. Returns `None` if
+    /// the user said no.
+    pub fn offer(
+        &self,
+        proposal: &str,
+        choice: &str,
+        value: ValueChoice,
+        fee: FeeSpec,
+        message: Option<String>,
+    ) -> anyhow::Result<Option<Offered>> {
+        let keychain = self.keychain()?;
+        let proposal: Proposal = proposal
+            .parse::<VersionedProposal>()
+            .map_err(|e| anyhow!("invalid proposal: {}", e))?
+            .into();
+        let event_url = Url::parse(
+            &self
+                .wallet
+                .gun_db()
+                .oracle_event_url(&proposal.oracle, &proposal.event_id),
+        )?;
+        let (oracle_event, oracle_info, is_attested) =
+            cmd::get_oracle_event_from_url(&self.wallet, event_url, false)?;
+        if is_attested {
+            return Err(anyhow!("{} already attested", oracle_event.event.id));
+        }
+        let outcome = Outcome::try_from_id_and_outcome(proposal.event_id.clone(), choice)
+            .map_err(|_| anyhow!("{} is not an outcome of {}", choice, proposal.event_id))?;
+        let (bet, local_public_key, mut cipher) =
+            self.wallet.generate_offer_with_oracle_event(OfferArgs {
+                choose_right: outcome.value == 1,
+                fee_spec: fee,
+                proposal,
+                oracle_event,
+                oracle_info,
+                args: BetArgs {
+                    value,
+                    ..Default::default()
+                },
+                keychain,
+            })?;
+        if !self.fee_ok(&bet.psbt)
+            || !self.tag_limits_ok(&bet.tags, bet.local_value)?
+            || !self
+                .interaction
+                .confirm(&cmd::bet_prompt(&bet, "offer", true))
+        {
+            return Ok(None);
+        }
+        let (bet_id, encrypted_offer, _) =
+            self.wallet
+                .sign_save_and_encrypt_offer(bet, message, local_public_key, &mut cipher)?;
+        Ok(Some(Offered {
+            bet_id,
+            offer: encrypted_offer.to_string(),
+        }))
+    }
+
+    /// Take an offer made to our proposal `bet_id`. Returns `None` if the user said no.
+    pub fn take(&self, bet_id: BetId, encrypted_offer: &str) -> anyhow::Result<Option<Taken>> {
+        let keychain = self.keychain()?;
+        let encrypted_offer = encrypted_offer
+            .parse::<Ciphertext>()
+            .map_err(|e| anyhow!("invalid offer: {}", e))?;
+        let (plaintext, offer_public_key, rng) =
+            self.wallet
+                .decrypt_offer(bet_id, encrypted_offer, keychain)?;
+        let offer = match plaintext {
+            Plaintext::Offerv1 { offer, .. } => offer,
+            Plaintext::Messagev1(_) => {
+                return Err(anyhow!("that's a message not an offer"));
+            }
+            Plaintext::CancelRequestv1 { .. } => {
+                return Err(anyhow!("that's a cancel request not an offer"));
+            }
+            Plaintext::BetMessagev1 { .. } => {
+                return Err(anyhow!("that's a message about a bet not an offer"));
+            }
+        };
+        let mut validated_offer =
+            self.wallet
+                .validate_offer(bet_id, offer, offer_public_key, rng, keychain)?;
+        if !self
+            .interaction
+            .confirm(&cmd::bet_prompt(&validated_offer.bet, "take", false))
+        {
+            return Ok(None);
+        }
+        self.wallet.sign_validated_offer(&mut validated_offer)?;
+        Ok(match self.broadcast(validated_offer.bet.psbt.clone())? {
+            Some(txid) => {
+                self.wallet.set_offer_taken(validated_offer)?;
+                Some(Taken { bet_id, txid })
+            }
+            None => None,
+        })
+    }
+
+    /// Claim all won bets. Returns `None` if there was nothing to claim or the user said no.
+    pub fn claim(&self, fee: FeeSpec, bump_claiming: bool) -> anyhow::Result<Option<Claimed>> {
+        let (bet_ids, psbt) = match self.wallet.claim(fee, bump_claiming)? {
+            Some(claim) => claim,
+            None => return Ok(None),
+        };
+        let txid = match self.broadcast(psbt)? {
+            Some(txid) => txid,
+            None => return Ok(None),
+        };
+        self.wallet
+            .hooks()
+            .claim_broadcast(self.wallet.http_client(), &bet_ids, txid);
+        for bet_id in &bet_ids {
+            self.wallet.take_next_action(*bet_id, false)?;
+        }
+        Ok(Some(Claimed { bet_ids, txid }))
+    }
+
+    /// Send `amount` to `address`. Returns `None` if the user said no.
+    pub fn send(
+        &self,
+        address: Address,
+        amount: Amount,
+        fee: FeeSpec,
+    ) -> anyhow::Result<Option<Txid>> {
+        let network = self.wallet.bdk_wallet().network();
+        script_kind::check_address(&address, network)?;
+        let mut builder = self.wallet.bdk_wallet().build_tx();
+        builder
+            .enable_rbf()
+            .unspendable(self.wallet.gun_db().currently_used_utxos(&[])?)
+            .add_recipient(address.script_pubkey(), amount.as_sat());
+        fee.apply_to_builder(&self.wallet, &mut builder)?;
+        let tx_locktime = self.wallet.apply_locktime_policy(&mut builder)?;
+        let (mut psbt, _) = builder.finish()?;
+        fee.check_psbt(&psbt)?;
+        let finalized = self
+            .wallet
+            .bdk_wallet()
+            .sign(&mut psbt, SignOptions::default())?;
+        if !finalized {
+            return Err(anyhow!("the transaction couldn't be fully signed"));
+        }
+        let txid = self.broadcast(psbt.clone())?;
+        if txid.is_some() {
+            self.wallet.record_locktime(&psbt, tx_locktime)?;
+        }
+        Ok(txid)
+    }
+
+    fn fee_ok(&self, psbt: &Psbt) -> bool {
+        match self.config.fee_limit_exceeded(psbt) {
+            None => true,
+            Some(problem) => self.interaction.confirm(&format!(
+                "WARNING: {}. Are you sure you want to continue",
+                problem
+            )),
+        }
+    }
+
+    fn tag_limits_ok(&self, tags: &[String], value: Amount) -> anyhow::Result<bool> {
+        let at_risk =
+            risk::at_risk_by_tag(self.wallet.gun_db().list_entities_print_error::<BetState>());
+        for breach in risk::tag_limit_breaches(&self.config.tag_limits, &at_risk, tags, value) {
+            if breach.strict {
+                return Err(anyhow!("{} (the limit is strict)", breach));
+            }
+            if !self.interaction.confirm(&format!(
+                "WARNING: {}. Are you sure you want to continue",
+                breach
+            )) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn broadcast(&self, psbt: Psbt) -> anyhow::Result<Option<Txid>> {
+        if !self.fee_ok(&psbt)
+            || !self.interaction.confirm(&format!(
+                "This is the transaction that will be broadcast.\n{}Ok",
+                cmd::display_psbt(self.config.network, &psbt)
+            ))
+        {
+            return Ok(None);
+        }
+        let tx = psbt.extract_tx();
+        let txid = tx.txid();
+        Broadcast::broadcast(self.wallet.blockchain()?, tx)?;
+        Ok(Some(txid))
+    }
+}
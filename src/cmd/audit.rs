@@ -0,0 +1,65 @@
+use super::{Cell, CmdOutput};
+use crate::{
+    betting::{AuditBundle, BetRef, VersionedAuditBundle},
+    elog, item,
+    wallet::GunWallet,
+};
+use anyhow::Context;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum AuditOpt {
+    /// Write the audit trail of a bet the oracle has attested to
+    ///
+    /// The file has the oracle's signed announcement and attestation, the public keys the bet
+    /// output was made from and the bet and claim transactions. It has no secrets in it so you can
+    /// give it to the counterparty or whoever is settling a dispute to check with `gun audit
+    /// verify`.
+    Export {
+        /// The bet to export
+        id: BetRef,
+        /// The file to write it to
+        file: PathBuf,
+    },
+    /// Check every signature in an audit trail and that the right side won
+    Verify {
+        /// The file written by `gun audit export`
+        file: PathBuf,
+    },
+}
+
+pub fn run_audit_cmd(wallet: Option<&GunWallet>, opt: AuditOpt) -> anyhow::Result<CmdOutput> {
+    match opt {
+        AuditOpt::Export { id, file } => {
+            let wallet = wallet.expect("exporting an audit trail needs the wallet");
+            let id = wallet.gun_db().resolve_bet_ref(&id)?;
+            let bundle = wallet.audit_bundle(id)?;
+            if bundle.claim_tx.is_none() {
+                elog!(@info "The bet output hasn't been claimed by this wallet so the audit trail doesn't have a claim tx.");
+            }
+            std::fs::write(
+                &file,
+                serde_json::to_string_pretty(&bundle.into_versioned()).unwrap(),
+            )
+            .with_context(|| format!("writing audit trail to {}", file.display()))?;
+            Ok(item! { "file" => Cell::string(file.display()) })
+        }
+        AuditOpt::Verify { file } => {
+            let json = std::fs::read_to_string(&file)
+                .with_context(|| format!("reading {}", file.display()))?;
+            let bundle: AuditBundle = serde_json::from_str::<VersionedAuditBundle>(&json)
+                .with_context(|| format!("{} is not a gun audit trail", file.display()))?
+                .into();
+            let report = bundle.verify()?;
+            elog!(
+                @celebration
+                "The bet was settled correctly. The {} won.",
+                if report.proposer_won { "proposer" } else { "offerer" }
+            );
+            Ok(CmdOutput::List(
+                report.checks.into_iter().map(Cell::string).collect(),
+            ))
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use crate::{
+    backup::{self, Backup},
+    cmd,
+    config::GunSigner,
+    database::GunDatabase,
+    elog, item, slip39,
+};
+use anyhow::{anyhow, Context};
+use bdk::{keys::bip39::Language, sled};
+use std::{fs, path::PathBuf};
+use structopt::StructOpt;
+use zeroize::Zeroizing;
+
+use super::{Cell, CmdOutput};
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum BackupOpt {
+    /// Create an encrypted backup of the wallet.
+    ///
+    /// The backup contains the config, descriptors, protocol secrets, bets, trusted oracles and seed
+    /// words (if gun is storing them). The blockchain cache is left out since it is rebuilt by
+    /// syncing.
+    Create {
+        /// Where to write the backup file
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Rebuild a wallet directory from a backup file.
+    ///
+    /// The wallet directory (--gun-dir) must not exist yet.
+    Restore {
+        /// The backup file to restore from
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Split the seed words into SLIP-39 shares
+    ///
+    /// Any --threshold of the shares restore the seed words with `gun setup seed --from-slip39`
+    /// but fewer reveal nothing about them. The shares are printed one at a time. If the wallet has
+    /// a passphrase you still need it as well.
+    Shamir {
+        /// How many shares to make (at most 16)
+        #[structopt(long)]
+        shares: u8,
+        /// How many of the shares are needed to restore
+        #[structopt(long)]
+        threshold: u8,
+    },
+}
+
+pub fn run_backup_cmd(wallet_dir: &std::path::Path, opt: BackupOpt) -> anyhow::Result<CmdOutput> {
+    match opt {
+        BackupOpt::Create { path } => {
+            if !wallet_dir.exists() {
+                return Err(anyhow!("No wallet found at {}", wallet_dir.display()));
+            }
+            if path.exists() {
+                return Err(anyhow!(
+                    "{} already exists -- refusing to overwrite it",
+                    path.display()
+                ));
+            }
+            let config = cmd::load_config(&wallet_dir.join("config.json"))?;
+            let database = sled::open(wallet_dir.join("database.sled").to_str().unwrap())
+                .context("opening database.sled")?;
+            let gun_db =
+                GunDatabase::new(database.open_tree("gun").context("opening gun db tree")?);
+            let seed_file = wallet_dir.join("seed.txt");
+            let seed_words = if seed_file.exists() {
+                Some(fs::read_to_string(&seed_file).context("loading seed words")?)
+            } else {
+                None
+            };
+
+            let backup = Backup {
+                config: config.into_versioned(),
+                gun_tree: gun_db.export_raw()?,
+                seed_words,
+            };
+
+            let passphrase = loop {
+                let passphrase = cmd::read_secret("Enter a passphrase for the backup:")?;
+                let confirmation = cmd::read_secret("Enter the passphrase again:")?;
+                if passphrase != confirmation {
+                    elog!(@user_error "Mismatching passphrases. Try again.\n");
+                } else if passphrase.is_empty() {
+                    elog!(@user_error "The passphrase may not be empty. Try again.\n");
+                } else {
+                    break passphrase;
+                }
+            };
+
+            let n_entries = backup.gun_tree.len();
+            fs::write(&path, backup::encrypt(backup, &passphrase))
+                .with_context(|| format!("writing backup to {}", path.display()))?;
+            elog!(@celebration "Wrote backup to {}", path.display());
+            elog!(@warning "You need the passphrase to restore the backup. Don't lose it!");
+
+            Ok(item! {
+                "path" => Cell::string(path.display()),
+                "entries" => Cell::Int(n_entries as u64),
+            })
+        }
+        BackupOpt::Restore { path } => {
+            if wallet_dir.exists() {
+                return Err(anyhow!(
+                    "wallet directory {} already exists -- delete it or set --gun-dir to restore somewhere else",
+                    wallet_dir.display()
+                ));
+            }
+            let bytes =
+                fs::read(&path).with_context(|| format!("reading backup {}", path.display()))?;
+            let passphrase = cmd::read_secret("Enter the backup's passphrase:")?;
+            let Backup {
+                config,
+                gun_tree,
+                seed_words,
+            } = backup::decrypt(&bytes, &passphrase)?;
+
+            fs::create_dir(wallet_dir)?;
+            let restore = || -> anyhow::Result<()> {
+                let gun_db = GunDatabase::new(
+                    sled::open(wallet_dir.join("database.sled").to_str().unwrap())?
+                        .open_tree("gun")?,
+                );
+                gun_db.import_raw(gun_tree)?;
+                cmd::write_config(&wallet_dir.join("config.json"), config.into())?;
+                if let Some(seed_words) = seed_words {
+                    fs::write(wallet_dir.join("seed.txt"), seed_words)?;
+                }
+                Ok(())
+            };
+            if let Err(e) = restore() {
+                // don't leave a half restored wallet in the way of trying again
+                let _ = fs::remove_dir_all(wallet_dir);
+                return Err(e.context(format!("restoring wallet to {}", wallet_dir.display())));
+            }
+
+            elog!(@celebration "Restored wallet to {}", wallet_dir.display());
+            elog!(@suggestion "Run `gun -s balance` to rebuild the wallet's view of the blockchain.");
+            Ok(CmdOutput::None)
+        }
+        BackupOpt::Shamir { shares, threshold } => {
+            if !wallet_dir.exists() {
+                return Err(anyhow!("No wallet found at {}", wallet_dir.display()));
+            }
+            let config = cmd::load_config(&wallet_dir.join("config.json"))?;
+            let mnemonic = cmd::load_seed_words(wallet_dir)?;
+            let entropy = Zeroizing::new(mnemonic.to_entropy());
+            let share_mnemonics =
+                slip39::split_mnemonics(&entropy, threshold, shares, b"", &mut rand::thread_rng())?;
+
+            elog!(@info "Write each share down and keep them in different places. Any {} of the {} shares restore your seed words.", threshold, shares);
+            for (i, share) in share_mnemonics.iter().enumerate() {
+                let printed = Zeroizing::new(
+                    share
+                        .split(' ')
+                        .enumerate()
+                        .map(|(j, word)| format!("{}: {}", j + 1, word))
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                );
+                println!("Share {} of {}\n{}", i + 1, shares, *printed);
+                if !cmd::read_yn(&format!("Have you written down share {}", i + 1)) {
+                    return Err(anyhow!(
+                        "Stopped before all the shares were written down. Throw away the ones you have since they won't work with a new set."
+                    ));
+                }
+            }
+
+            if mnemonic.language() != Language::English {
+                let name = cmd::SEED_LANGUAGES
+                    .iter()
+                    .find(|(_, language)| *language == mnemonic.language())
+                    .map(|(name, _)| *name)
+                    .expect("every language has a name");
+                elog!(@warning "Your seed words are in {}. Restore with `gun setup seed --from-slip39 --language {}` or you'll get different seed words.", name, name);
+            }
+            let uses_passphrase = config.signers.iter().any(|signer| {
+                matches!(
+                    signer,
+                    GunSigner::SeedWordsFile {
+                        passphrase_fingerprint: Some(_),
+                        ..
+                    }
+                )
+            });
+            if uses_passphrase {
+                elog!(@warning "The shares don't include your passphrase. You need it as well to restore.");
+            }
+            elog!(@celebration "Your seed words are backed up in {} shares", shares);
+            Ok(CmdOutput::None)
+        }
+    }
+}
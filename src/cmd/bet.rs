@@ -1,19 +1,30 @@
 use super::{read_input, run_oralce_cmd, Cell};
 use crate::{
+    amount_ext::FromCliStr,
     betting::*,
-    cmd::{self, read_yn, sanitize_str, CmdOutput},
-    database::GunDatabase,
+    calendar,
+    cmd::{self, sanitize_str, CmdOutput, WithErrorKind},
+    config::Config,
+    confirm::Operation,
+    contacts::Contact,
+    database::{FingerprintIndex, GunDatabase},
     elog, item,
     keychain::Keychain,
     psbt_ext::PsbtFeeRate,
+    script_kind,
+    silent_payments::Destination,
+    utxo_snapshot::UtxoSnapshot,
     wallet::GunWallet,
     OracleInfo, Url, ValueChoice,
 };
 use anyhow::{anyhow, Context};
-use bdk::bitcoin::{Address, Amount, Script};
+use bdk::{
+    bitcoin::{util::psbt::PartiallySignedTransaction as Psbt, Address, Amount, Script, Txid},
+    blockchain::Blockchain,
+};
 use chacha20::cipher::StreamCipher;
 use olivia_core::{chrono::Utc, Outcome, OutcomeError};
-use std::str::FromStr;
+use std::{path::PathBuf, str::FromStr};
 use structopt::StructOpt;
 
 #[derive(Clone, Debug, structopt::StructOpt)]
@@ -24,6 +35,9 @@ pub struct BetArgs {
     /// tag the bet with short string
     #[structopt(short, long)]
     pub tags: Vec<String>,
+    /// The contact (from `gun contact`) the bet is with
+    #[structopt(long)]
+    pub contact: Option<String>,
 }
 
 impl BetArgs {
@@ -51,6 +65,17 @@ impl BetArgs {
             ..Default::default()
         }
     }
+
+    /// Check the contact exists before anything is done so linking it afterwards can't fail.
+    fn check_contact(&self, gun_db: &GunDatabase) -> anyhow::Result<()> {
+        match &self.contact {
+            Some(name) if gun_db.get_entity::<Contact>(name.clone())?.is_none() => Err(anyhow!(
+                "There's no contact called {}. Add them with `gun contact add`.",
+                name
+            )),
+            _ => Ok(()),
+        }
+    }
 }
 
 #[derive(StructOpt, Debug, Clone)]
@@ -66,13 +91,30 @@ pub enum BetOpt {
         /// Print the proposal without asking
         #[structopt(long, short)]
         yes: bool,
+        /// Fetch the event from the oracle even if we have it cached
+        #[structopt(long)]
+        refresh: bool,
+        /// Let you and the counterparty spend the bet output together after this block height in
+        /// case the oracle never attests. It has to be well after the outcome time.
+        #[structopt(long)]
+        refund_height: Option<u32>,
+        /// Choose the inputs from a snapshot made with `gun export utxo-snapshot` (for making
+        /// proposals on a machine that's offline)
+        #[structopt(long, parse(from_os_str))]
+        utxo_snapshot: Option<PathBuf>,
+        #[structopt(flatten)]
+        copy_args: cmd::CopyArgs,
     },
     /// Make an offer to a proposal
     Offer {
         #[structopt(flatten)]
         args: BetArgs,
         /// The propsal string
-        proposal: VersionedProposal,
+        #[structopt(required_unless = "paste")]
+        proposal: Option<VersionedProposal>,
+        /// Read the proposal from the clipboard
+        #[structopt(long)]
+        paste: bool,
         /// The outcome to choose
         #[structopt(long, short)]
         choice: Option<String>,
@@ -82,26 +124,68 @@ pub enum BetOpt {
         /// Pad the encrypted offer to a certain number of bytes e.g. 385 for twitter
         #[structopt(long, short, default_value = "385")]
         pad: usize,
+        /// Split the ciphertext into numbered chunks of at most this many characters e.g. 160 for SMS
+        #[structopt(long)]
+        chunk: Option<usize>,
+        #[structopt(flatten)]
+        copy_args: cmd::CopyArgs,
         #[structopt(flatten)]
         fee_args: cmd::FeeArgs,
         /// Attach an additional message to the offer
         #[structopt(long, short)]
         message: Option<String>,
+        /// Fetch the event from the oracle even if we have it cached
+        #[structopt(long)]
+        refresh: bool,
     },
     /// Inspect an offer or proposal string
     Inspect(InspectOpt),
     /// Take on offer made to your proposal
     Take {
         /// The bet id you are taking the bet from
-        id: BetId,
+        id: BetRef,
         /// The offer string (a base20248 string)
-        encrypted_offer: Ciphertext,
+        #[structopt(required_unless_one = &["paste", "offer"])]
+        encrypted_offer: Option<Ciphertext>,
+        /// Read the offer from the clipboard
+        #[structopt(long)]
+        paste: bool,
+        /// Take an offer kept with `gun bet offers add` (its number from `gun bet offers list`)
+        #[structopt(long, conflicts_with_all = &["encrypted-offer", "paste"])]
+        offer: Option<usize>,
         /// Take the offer and broadacast tx without prompting.
         #[structopt(short, long)]
         yes: bool,
         #[structopt(long)]
         /// Print the bet transaction as hex instead of broadcasting it.
         print_tx: bool,
+        /// The contact (from `gun contact`) the offer is from. Defaults to the proposal's.
+        #[structopt(long)]
+        contact: Option<String>,
+    },
+    /// Take offers piped in on stdin that meet some criteria without prompting
+    ///
+    /// Reads lines of `<bet id> <offer>` from stdin and takes each offer that passes every check.
+    /// Offers that fail are skipped with the reason. This is only a filter: gun doesn't go and get
+    /// offers from anywhere so something else has to collect them from wherever they're sent and
+    /// write them to its stdin e.g. `my-offer-fetcher | gun bet take-from-stdin --max-risk
+    /// 0.001BTC`.
+    TakeFromStdin {
+        /// The most value to risk on a single bet e.g. 0.001BTC
+        #[structopt(long, parse(try_from_str = FromCliStr::from_cli_str))]
+        max_risk: Amount,
+        /// What the counterparty must put in for each sat you put in e.g. >=1.0
+        #[structopt(long, default_value = ">=1.0")]
+        odds: OddsBound,
+        /// Only take bets on events from this oracle (can be given more than once)
+        #[structopt(long)]
+        oracle: Vec<String>,
+        /// Only take bets on events whose id starts with this e.g. /random/
+        #[structopt(long)]
+        event_prefix: Option<String>,
+        #[structopt(long)]
+        /// Print the bet transactions as hex instead of broadcasting them.
+        print_tx: bool,
     },
     /// Claim your winnings
     ///
@@ -110,6 +194,10 @@ pub enum BetOpt {
     Claim {
         #[structopt(flatten)]
         fee_args: cmd::FeeArgs,
+        /// Send the winnings here instead of back to the wallet. It can be a silent payment address
+        /// (sp1...) in which case one of your coins is spent with the bets to work out the output.
+        #[structopt(long)]
+        to: Option<Destination>,
         /// Also spend bets that are already in the "claiming" state replacing the previous
         /// transaction.
         #[structopt(long)]
@@ -124,10 +212,39 @@ pub enum BetOpt {
     },
     /// List bets
     List,
+    /// Walk through a whole bet on a private regtest chain without risking anything
+    ///
+    /// Two throwaway wallets bet against each other on an event from a fake oracle. Needs gun to be
+    /// built with `--features simulation` which starts bitcoind and electrs.
+    Simulate {
+        /// The other side of the bet is a second wallet made for the simulation
+        #[structopt(long)]
+        against_self: bool,
+    },
+    /// Show when the outcomes of unfinished bets are expected, grouped by day
+    ///
+    /// These are the times you'll want to be around to claim.
+    Calendar {
+        /// Also write the calendar to this iCalendar file (e.g. bets.ics) to import into a
+        /// calendar app
+        #[structopt(long)]
+        ical: Option<PathBuf>,
+    },
+    /// Follow a bet as it moves through its states until it's finished
+    ///
+    /// Prints a line for every change of state. Exits with an error if the bet is lost or
+    /// canceled.
+    Watch {
+        /// The id of the bet to watch.
+        id: BetRef,
+        /// How many seconds to wait between checks
+        #[structopt(long, default_value = "30")]
+        interval: u64,
+    },
     /// Show details of a particular bet
     Show {
         /// The id of the bet you want to show.
-        id: BetId,
+        id: BetRef,
         /// Show the raw entry in the database
         #[structopt(long, short)]
         raw: bool,
@@ -135,7 +252,7 @@ pub enum BetOpt {
     /// Cancel a bet
     Cancel {
         /// The bets to cancel.
-        ids: Vec<BetId>,
+        ids: Vec<BetRef>,
         #[structopt(flatten)]
         fee_args: cmd::FeeArgs,
         /// Don't prompt for answers just say yes
@@ -146,17 +263,111 @@ pub enum BetOpt {
         #[structopt(long)]
         print_tx: bool,
     },
+    /// Get back what you put into a bet the oracle never attested to
+    ///
+    /// This only works for bets proposed with --refund-height and needs both parties. One of them
+    /// makes the refund with --to set to the other's address and sends them the PSBT it prints.
+    /// The other passes it with --psbt to sign and broadcast it once the refund height has been
+    /// reached. The fee is split in proportion to what each of you put in.
+    ///
+    /// If the counterparty has disappeared --sweep takes the whole bet output without them. This
+    /// can only be done 1008 blocks after the refund height and they can do the same.
+    Refund {
+        /// The bet to refund.
+        id: BetRef,
+        /// The address to pay the counterparty's stake back to
+        #[structopt(long, required_unless_one = &["psbt", "sweep"])]
+        to: Option<Address>,
+        /// The refund PSBT the counterparty made (base64 or a file containing it)
+        #[structopt(long, conflicts_with = "to")]
+        psbt: Option<String>,
+        /// Take the whole bet output without the counterparty
+        #[structopt(long, conflicts_with_all = &["to", "psbt"])]
+        sweep: bool,
+        #[structopt(flatten)]
+        fee_args: cmd::FeeArgs,
+        /// Don't prompt for answers just say yes
+        #[structopt(short, long)]
+        yes: bool,
+        /// Print the refund transaction hex but don't broadcast it
+        #[structopt(long)]
+        print_tx: bool,
+    },
+    /// Ask your counterparty to cancel a bet and give you both back your stakes
+    ///
+    /// This makes an encrypted cancel request to send to the counterparty. They run this command
+    /// with --request to sign and broadcast it straight away. It spends the bet's refund branch so
+    /// it only works for bets proposed with --refund-height. The fee is split in proportion to what
+    /// each of you put in.
+    MutualCancel {
+        /// The bet to cancel.
+        id: BetRef,
+        /// The address to pay the counterparty's stake back to
+        #[structopt(long, required_unless = "request")]
+        to: Option<Address>,
+        /// The cancel request the counterparty sent you
+        #[structopt(long, conflicts_with = "to")]
+        request: Option<Ciphertext>,
+        /// Attach a message to the request
+        #[structopt(long, short)]
+        message: Option<String>,
+        /// Pad the request to a certain number of bytes
+        #[structopt(long, short, default_value = "385")]
+        pad: usize,
+        /// Split the ciphertext into numbered chunks of at most this many characters e.g. 160 for SMS
+        #[structopt(long)]
+        chunk: Option<usize>,
+        #[structopt(flatten)]
+        copy_args: cmd::CopyArgs,
+        #[structopt(flatten)]
+        fee_args: cmd::FeeArgs,
+        /// Don't prompt for answers just say yes
+        #[structopt(short, long)]
+        yes: bool,
+        /// Print the refund transaction hex but don't broadcast it
+        #[structopt(long)]
+        print_tx: bool,
+    },
     /// Delete all memory of the bet.
     ///
     /// Think carefully before using on unfinished bets. It's usually better to use cancel.
     Forget {
         /// The list of bet ids to forget about
-        ids: Vec<BetId>,
+        ids: Vec<BetRef>,
+    },
+    /// Write a bet to a file so another device with the same seed can track, claim or cancel it
+    ///
+    /// The file is encrypted and authenticated with keys derived from the protocol secret so only
+    /// a wallet with the same one can read it. Load it on the other device with `gun bet
+    /// import-state`.
+    ExportState {
+        /// The bet to export
+        id: BetRef,
+        /// The file to write it to
+        file: PathBuf,
+    },
+    /// Add a bet exported from another device with the same seed
+    ImportState {
+        /// The file written by `gun bet export-state`
+        file: PathBuf,
+    },
+    /// Write what a monitoring-only instance needs to track your active bets
+    ///
+    /// The file has the wallet's public descriptors and the txid, joint output descriptor and
+    /// oracle event of each active bet. Nothing in it can spend coins or decrypt offers so it's
+    /// safe to put on an always-online server that watches for confirmations and attestations.
+    ExportWatch {
+        /// The file to write it to
+        file: PathBuf,
     },
     /// Edit list of trusted oracles
     Oracle(crate::cmd::OracleOpt),
     /// Tag a bet
     Tag(TagOpt),
+    /// Send and read encrypted messages with the counterparty of a bet
+    Msg(MsgOpt),
+    /// Keep and compare the offers made to one of your proposals
+    Offers(OffersOpt),
     /// Make a encrypted reply to a proposal
     Reply {
         /// The proposal to send an encrypted message to.
@@ -167,6 +378,11 @@ pub enum BetOpt {
         /// Pad the ciphertext to be at least this length.
         #[structopt(short, long, default_value = "385")]
         pad: usize,
+        /// Split the ciphertext into numbered chunks of at most this many characters e.g. 160 for SMS
+        #[structopt(long)]
+        chunk: Option<usize>,
+        #[structopt(flatten)]
+        copy_args: cmd::CopyArgs,
     },
 }
 
@@ -181,7 +397,7 @@ pub enum InspectOpt {
     /// Inspect an encrypted offer
     Offer {
         /// The bet id the offer is for.
-        id: BetId,
+        id: BetRef,
         /// The encrypted offer as a base2048 string.
         encrypted_offer: Ciphertext,
     },
@@ -192,30 +408,141 @@ pub enum TagOpt {
     /// Add a tag to a bet
     Add {
         /// The bet to attach the tag to.
-        id: BetId,
+        id: BetRef,
         /// The tag.
         tag: String,
     },
     /// Remove a tag from a bet
     Remove {
         /// The bet to remove the tag from.
-        id: BetId,
+        id: BetRef,
         /// The tag to remove.
         tag: String,
     },
 }
 
+#[derive(Clone, Debug, StructOpt)]
+pub enum MsgOpt {
+    /// Encrypt a message to the counterparty of a bet
+    Send {
+        /// The bet the message is about.
+        id: BetRef,
+        /// The message. If not set reads from stdin.
+        text: Option<String>,
+        /// Pad the ciphertext to be at least this length.
+        #[structopt(short, long, default_value = "385")]
+        pad: usize,
+        /// Split the ciphertext into numbered chunks of at most this many characters e.g. 160 for SMS
+        #[structopt(long)]
+        chunk: Option<usize>,
+        #[structopt(flatten)]
+        copy_args: cmd::CopyArgs,
+    },
+    /// Decrypt a message from the counterparty of a bet and add it to the thread
+    Read {
+        /// The bet the message is about.
+        id: BetRef,
+        /// The message as a base2048 string.
+        #[structopt(required_unless = "paste")]
+        ciphertext: Option<Ciphertext>,
+        /// Read the message from the clipboard
+        #[structopt(long)]
+        paste: bool,
+    },
+    /// List the messages sent and received about a bet
+    List {
+        /// The bet whose messages to list.
+        id: BetRef,
+    },
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub enum OffersOpt {
+    /// Keep an offer to one of your proposals so you can compare it with others before taking one
+    Add {
+        /// The bet id of the proposal the offer is for.
+        id: BetRef,
+        /// The encrypted offer as a base2048 string.
+        #[structopt(required_unless = "paste")]
+        encrypted_offer: Option<Ciphertext>,
+        /// Read the offer from the clipboard
+        #[structopt(long)]
+        paste: bool,
+    },
+    /// Compare the offers kept for a proposal
+    List {
+        /// The bet id of the proposal.
+        id: BetRef,
+    },
+}
+
+fn broadcast_claim(
+    wallet: &GunWallet,
+    config: &Config,
+    ids: &[BetId],
+    claim_psbt: Psbt,
+    yes: bool,
+    print_tx: bool,
+) -> anyhow::Result<CmdOutput> {
+    let (output, txid) = cmd::decide_to_broadcast(
+        config,
+        wallet.blockchain()?,
+        claim_psbt,
+        Operation::Claim,
+        yes,
+        print_tx,
+    )?;
+    if let Some(txid) = txid {
+        wallet
+            .hooks()
+            .claim_broadcast(wallet.http_client(), ids, txid);
+        for id in ids {
+            if let Err(e) = wallet.take_next_action(*id, false) {
+                elog!(
+                    @recoverable_error
+                    "Error updating state of bet {} after broadcasting claim tx {}: {}",
+                    id, txid, e
+                );
+            }
+        }
+    }
+    Ok(output)
+}
+
+/// Claim won bets if the `auto-claim` policy in the config says it's time to.
+pub fn auto_claim(wallet: &GunWallet, config: &Config) {
+    let policy = match &config.auto_claim {
+        Some(policy) => policy,
+        None => return,
+    };
+    if !wallet.auto_claim_due(policy) {
+        return;
+    }
+    let fee = policy.fee.clone().unwrap_or_default();
+    let result = wallet.claim(fee, false).and_then(|claim| match claim {
+        Some((ids, claim_psbt)) => {
+            elog!(@info "auto-claiming {} won bet(s)", ids.len());
+            broadcast_claim(wallet, config, &ids, claim_psbt, policy.yes, false).map(|_| ())
+        }
+        None => Ok(()),
+    });
+    if let Err(e) = result {
+        elog!(@recoverable_error "Error auto-claiming won bets: {}", e);
+    }
+}
+
 pub fn run_bet_cmd(
     wallet: &GunWallet,
     keychain: &Keychain,
+    config: &Config,
     cmd: BetOpt,
     sync: bool,
 ) -> anyhow::Result<cmd::CmdOutput> {
-    cmd::ensure_not_watch_only(wallet)?;
     // For now just always do this but we may want to do something more fine grained later.
     if sync {
-        wallet.sync()?;
+        wallet.sync_with_progress(cmd::SyncProgress::default())?;
         wallet.poke_bets();
+        auto_claim(wallet, config);
     }
 
     match cmd {
@@ -223,11 +550,26 @@ pub fn run_bet_cmd(
             args,
             event_url,
             yes,
+            refresh,
+            refund_height,
+            utxo_snapshot,
+            copy_args,
         } => {
+            args.check_contact(wallet.gun_db())?;
+            let utxo_snapshot = utxo_snapshot
+                .map(|path| UtxoSnapshot::from_file(&path))
+                .transpose()?;
+            if let Some(utxo_snapshot) = &utxo_snapshot {
+                utxo_snapshot.check(wallet)?;
+                utxo_snapshot.cache_events(wallet)?;
+                if utxo_snapshot.is_stale() {
+                    elog!(@warning "The utxo snapshot is more than a day old. Some of its coins may have been spent since.");
+                }
+            }
             let oracle_id = event_url.host_str().unwrap().to_string();
             let now = Utc::now().naive_utc();
             let (oracle_event, _, is_attested) =
-                get_oracle_event_from_url(wallet.gun_db(), event_url)?;
+                get_oracle_event_from_url(wallet, event_url, refresh)?;
             if is_attested {
                 return Err(anyhow!("{} already attested", oracle_event.event.id));
             }
@@ -251,9 +593,42 @@ pub fn run_bet_cmd(
                     crate::format_dt_diff_till_now(expected_outcome_time)
                 );
             }
-            question += " Ok";
-            let args = args.prompt_to_core_bet_args(None);
-            let local_proposal = wallet.make_proposal(oracle_id, oracle_event, args, keychain)?;
+            if let Some(refund_height) = refund_height {
+                let height = match &utxo_snapshot {
+                    Some(utxo_snapshot) => utxo_snapshot.height,
+                    None => wallet.blockchain()?.get_height()?,
+                };
+                // assume a block every ten minutes
+                let outcome_height = height
+                    + oracle_event
+                        .event
+                        .expected_outcome_time
+                        .map(|time| (time - now).num_minutes().max(0) as u32 / 10)
+                        .unwrap_or(0);
+                if refund_height <= outcome_height || refund_height >= LOCKTIME_THRESHOLD {
+                    return Err(anyhow!(
+                        "The refund height {} must be after the outcome (which should be around height {})",
+                        refund_height,
+                        outcome_height
+                    ))
+                    .error_kind(cmd::ErrorKind::User);
+                }
+                question += &format!(
+                    "\nIf the oracle never attests you can both get your coins back after block {} (in about {} blocks).",
+                    refund_height,
+                    refund_height - height
+                );
+            }
+            let contact = args.contact.clone();
+            let mut args = args.prompt_to_core_bet_args(None);
+            args.refund_after = refund_height;
+            let local_proposal = wallet.make_proposal(
+                oracle_id,
+                oracle_event,
+                args,
+                keychain,
+                utxo_snapshot.as_ref(),
+            )?;
             if let Some(change) = &local_proposal.change {
                 elog!(
                     @info
@@ -261,16 +636,43 @@ pub fn run_bet_cmd(
                     change.value(), local_proposal.proposal.value
                 );
             }
+            if let Some(dust_fee) = local_proposal.dust_fee {
+                question += &format!(
+                    "\nYour change of {} is too small to be worth an output so it will go to the bet transaction's fee.",
+                    dust_fee
+                );
+            }
+            question += " Ok";
+
+            if !cmd::check_tag_limits(
+                config,
+                wallet.gun_db(),
+                &local_proposal.tags,
+                local_proposal.proposal.value,
+                yes,
+            )? {
+                return Ok(CmdOutput::None);
+            }
 
-            if yes || read_yn(&question) {
+            if cmd::confirm(
+                config,
+                Operation::Propose,
+                yes,
+                local_proposal.proposal.value,
+                &question,
+            ) {
                 let proposal_string = local_proposal.proposal.clone().into_versioned().to_string();
-                let id = wallet
-                    .gun_db()
-                    .insert_bet(BetState::Proposed { local_proposal })?;
+                let id = wallet.insert_proposal(local_proposal)?;
+                if let Some(contact) = &contact {
+                    wallet.gun_db().link_bet_contact(id, contact)?;
+                }
 
                 elog!(@suggestion "Post your proposal and let people make offers to it: ");
                 Ok(CmdOutput::EmphasisedItem {
-                    main: ("proposal", Cell::string(proposal_string)),
+                    main: ("proposal", {
+                        copy_args.copy_if_asked(config, &proposal_string)?;
+                        Cell::string(proposal_string)
+                    }),
                     other: vec![("id", Cell::string(id))],
                 })
             } else {
@@ -284,9 +686,15 @@ pub fn run_bet_cmd(
             fee_args,
             yes,
             pad,
+            chunk,
+            copy_args,
             message,
+            refresh,
+            paste: _,
         } => {
-            let proposal: Proposal = proposal.into();
+            cmd::ensure_not_watch_only(wallet)?;
+            args.check_contact(wallet.gun_db())?;
+            let proposal: Proposal = cmd::arg_or_paste(proposal)?.into();
             let event_id = proposal.event_id.clone();
             let now = Utc::now().naive_utc();
 
@@ -298,11 +706,14 @@ pub fn run_bet_cmd(
                 ));
             }
 
-            let event_url =
-                Url::parse(&format!("https://{}{}", proposal.oracle, proposal.event_id))?;
+            let event_url = Url::parse(
+                &wallet
+                    .gun_db()
+                    .oracle_event_url(&proposal.oracle, &proposal.event_id),
+            )?;
 
             let (oracle_event, oracle_info, is_attested) =
-                get_oracle_event_from_url(wallet.gun_db(), event_url)?;
+                get_oracle_event_from_url(wallet, event_url, refresh)?;
 
             if is_attested {
                 return Err(anyhow!("{} already attested", oracle_event.event.id));
@@ -362,6 +773,7 @@ pub fn run_bet_cmd(
                 ),
             };
 
+            let contact = args.contact.clone();
             let args = args.prompt_to_core_bet_args(Some(proposal.value));
 
             let (bet, local_public_key, mut cipher) =
@@ -375,13 +787,32 @@ pub fn run_bet_cmd(
                     keychain,
                 })?;
 
-            if yes || cmd::read_yn(&bet_prompt(&bet, "offer", true)) {
+            if !cmd::check_fee_limits(config, &bet.psbt, yes)?
+                || !cmd::check_tag_limits(config, wallet.gun_db(), &bet.tags, bet.local_value, yes)?
+            {
+                return Ok(CmdOutput::None);
+            }
+
+            if let Some(contact) = &contact {
+                show_reputation(wallet.gun_db(), contact);
+            }
+
+            if cmd::confirm(
+                config,
+                Operation::Offer,
+                yes,
+                bet.local_value,
+                &bet_prompt(&bet, "offer", true),
+            ) {
                 let (id, encrypted_offer, _) = wallet.sign_save_and_encrypt_offer(
                     bet,
                     message,
                     local_public_key,
                     &mut cipher,
                 )?;
+                if let Some(contact) = &contact {
+                    wallet.gun_db().link_bet_contact(id, contact)?;
+                }
 
                 elog!(@suggestion "Post this offer in reponse to the proposal");
                 let (padded_encrypted_offer, overflow) =
@@ -394,7 +825,10 @@ pub fn run_bet_cmd(
                     );
                 }
                 Ok(CmdOutput::EmphasisedItem {
-                    main: ("offer", Cell::string(padded_encrypted_offer)),
+                    main: (
+                        "offer",
+                        ciphertext_output(padded_encrypted_offer, chunk, &copy_args, config)?,
+                    ),
                     other: vec![("id", Cell::string(id))],
                 })
             } else {
@@ -404,9 +838,39 @@ pub fn run_bet_cmd(
         BetOpt::Take {
             id,
             encrypted_offer,
+            paste: _,
+            offer,
             yes,
             print_tx,
+            contact,
         } => {
+            cmd::ensure_not_watch_only(wallet)?;
+            let id = wallet.gun_db().resolve_bet_ref(&id)?;
+            let contact = match contact {
+                Some(name) => {
+                    if wallet
+                        .gun_db()
+                        .get_entity::<Contact>(name.clone())?
+                        .is_none()
+                    {
+                        return Err(anyhow!(
+                            "There's no contact called {}. Add them with `gun contact add`.",
+                            name
+                        ));
+                    }
+                    Some(name)
+                }
+                None => wallet.gun_db().bet_contact(id),
+            };
+            let encrypted_offer = match offer {
+                Some(n) => wallet
+                    .proposal_offers(id)?
+                    .get(n)
+                    .cloned()
+                    .ok_or(anyhow!("Bet {} has no offer {}", id, n))
+                    .error_kind(cmd::ErrorKind::User)?,
+                None => cmd::arg_or_paste(encrypted_offer)?,
+            };
             let (plaintext, offer_public_key, rng) =
                 wallet.decrypt_offer(id, encrypted_offer, keychain)?;
             match plaintext {
@@ -422,17 +886,30 @@ pub fn run_bet_cmd(
                     }
                     let mut validated_offer =
                         wallet.validate_offer(id, offer, offer_public_key, rng, keychain)?;
-                    if yes || cmd::read_yn(&bet_prompt(&validated_offer.bet, "take", false)) {
+                    if let Some(contact) = &contact {
+                        show_reputation(wallet.gun_db(), contact);
+                    }
+                    if cmd::confirm(
+                        config,
+                        Operation::Take,
+                        yes,
+                        validated_offer.bet.local_value,
+                        &bet_prompt(&validated_offer.bet, "take", false),
+                    ) {
                         wallet.sign_validated_offer(&mut validated_offer)?;
                         let (output, txid) = cmd::decide_to_broadcast(
-                            wallet.bdk_wallet().network(),
-                            wallet.bdk_wallet().client(),
+                            config,
+                            wallet.blockchain()?,
                             validated_offer.bet.psbt.clone(),
+                            Operation::Take,
                             yes,
                             print_tx,
                         )?;
                         if txid.is_some() {
                             wallet.set_offer_taken(validated_offer)?;
+                            if let Some(contact) = &contact {
+                                wallet.gun_db().link_bet_contact(id, contact)?;
+                            }
                         }
                         Ok(output)
                     } else {
@@ -443,76 +920,260 @@ pub fn run_bet_cmd(
                     elog!(@info "The ciphertext contained a secret message: ");
                     Ok(item! { "message" => Cell::string(message) })
                 }
+                Plaintext::CancelRequestv1 { .. } => Err(anyhow!(
+                    "This is a cancel request -- use `gun bet mutual-cancel` to accept it"
+                )),
+                Plaintext::BetMessagev1 { .. } => Err(anyhow!(
+                    "This is a message about a bet -- use `gun bet msg read` to read it"
+                )),
             }
         }
+        BetOpt::TakeFromStdin {
+            max_risk,
+            odds,
+            oracle,
+            event_prefix,
+            print_tx,
+        } => {
+            cmd::ensure_not_watch_only(wallet)?;
+            let policy = TakePolicy {
+                max_risk,
+                odds,
+                oracles: oracle,
+                event_prefix,
+            };
+            let mut rows = vec![];
+            let interaction = crate::interaction::current();
+            while let Some(line) = interaction.read_line("") {
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                let (id, result) =
+                    match take_offer_line(wallet, keychain, config, &policy, line, print_tx) {
+                        Ok((id, result)) => (Cell::Int(id.into()), result),
+                        Err(e) => (Cell::Empty, format!("error: {}", e)),
+                    };
+                elog!(@info "{}", result);
+                rows.push(vec![id, Cell::String(result)]);
+            }
+            Ok(CmdOutput::table(vec!["id", "result"], rows))
+        }
         BetOpt::Claim {
             fee_args,
+            to,
             bump_claiming,
             print_tx,
             yes,
         } => {
-            let bdk_wallet = wallet.bdk_wallet();
-            match wallet.claim(fee_args.fee, bump_claiming)? {
+            if let Some(Destination::Address(address)) = &to {
+                let network = wallet.bdk_wallet().network();
+                script_kind::check_address(address, network).error_kind(cmd::ErrorKind::User)?;
+            }
+            match wallet.claim_to(fee_args.fee, bump_claiming, to.as_ref())? {
                 Some((ids, claim_psbt)) => {
+                    broadcast_claim(wallet, config, &ids, claim_psbt, yes, print_tx)
+                }
+                None => Ok(CmdOutput::None),
+            }
+        }
+        BetOpt::Cancel {
+            ids,
+            fee_args,
+            yes,
+            print_tx,
+        } => {
+            cmd::ensure_not_watch_only(wallet)?;
+            let ids = wallet.gun_db().resolve_bet_refs(&ids)?;
+            Ok(match wallet.generate_cancel_tx(&ids, fee_args.fee)? {
+                Some(psbt) => {
                     let (output, txid) = cmd::decide_to_broadcast(
-                        bdk_wallet.network(),
-                        bdk_wallet.client(),
-                        claim_psbt,
+                        config,
+                        wallet.blockchain()?,
+                        psbt,
+                        Operation::Cancel,
                         yes,
                         print_tx,
                     )?;
+
                     if let Some(txid) = txid {
                         for id in ids {
-                            if let Err(e) = wallet.take_next_action(id, false) {
+                            if let Err(e) = wallet.take_next_action(id, true) {
                                 elog!(
                                     @recoverable_error
-                                    "Error updating state of bet {} after broadcasting claim tx {}: {}",
+                                    "Error updating state of bet {} after broadcasting cancel tx: {}: {}",
                                     id, txid, e
                                 );
                             }
                         }
                     }
-                    Ok(output)
+                    output
                 }
-                None => Ok(CmdOutput::None),
-            }
+                None => {
+                    elog!(@info "No bets needed canceling");
+                    CmdOutput::None
+                }
+            })
         }
-        BetOpt::Cancel {
-            ids,
+        BetOpt::Refund {
+            id,
+            to,
+            psbt,
+            sweep,
             fee_args,
             yes,
             print_tx,
-        } => Ok(match wallet.generate_cancel_tx(&ids, fee_args.fee)? {
-            Some(psbt) => {
+        } => {
+            let id = wallet.gun_db().resolve_bet_ref(&id)?;
+            if sweep {
+                let psbt = wallet.generate_sweep(id, fee_args.fee)?;
                 let (output, txid) = cmd::decide_to_broadcast(
-                    wallet.bdk_wallet().network(),
-                    wallet.bdk_wallet().client(),
+                    config,
+                    wallet.blockchain()?,
                     psbt,
+                    Operation::Refund,
                     yes,
                     print_tx,
                 )?;
-
                 if let Some(txid) = txid {
-                    for id in ids {
-                        if let Err(e) = wallet.take_next_action(id, true) {
+                    if let Err(e) = wallet.take_next_action(id, false) {
+                        elog!(
+                            @recoverable_error
+                            "Error updating state of bet {} after broadcasting sweep tx {}: {}",
+                            id, txid, e
+                        );
+                    }
+                }
+                return Ok(output);
+            }
+            match (to, psbt) {
+                (Some(to), _) => {
+                    let network = wallet.bdk_wallet().network();
+                    script_kind::check_address(&to, network).error_kind(cmd::ErrorKind::User)?;
+                    let psbt = wallet.generate_refund(id, to.script_pubkey(), fee_args.fee)?;
+                    if !cmd::check_fee_limits(config, &psbt, yes)? {
+                        return Ok(CmdOutput::None);
+                    }
+                    elog!(@suggestion "Send this to your counterparty so they can sign and broadcast it with `gun bet refund <their bet id> --psbt <psbt>`");
+                    Ok(CmdOutput::EmphasisedItem {
+                        main: ("psbt", Cell::string(psbt)),
+                        other: vec![],
+                    })
+                }
+                (None, Some(psbt)) => {
+                    let mut psbt = cmd::load_psbt(&psbt)?;
+                    if !wallet.sign_refund(id, &mut psbt, &fee_args.fee)? {
+                        return Err(anyhow!(
+                        "The refund for bet {} can't be broadcast yet. Either it's missing the counterparty's signature or the refund height hasn't been reached.",
+                        id
+                    ));
+                    }
+                    let (output, txid) = cmd::decide_to_broadcast(
+                        config,
+                        wallet.blockchain()?,
+                        psbt,
+                        Operation::Refund,
+                        yes,
+                        print_tx,
+                    )?;
+                    if let Some(txid) = txid {
+                        if let Err(e) = wallet.take_next_action(id, false) {
                             elog!(
                                 @recoverable_error
-                                "Error updating state of bet {} after broadcasting cancel tx: {}: {}",
+                                "Error updating state of bet {} after broadcasting refund tx {}: {}",
                                 id, txid, e
                             );
                         }
                     }
+                    Ok(output)
                 }
-                output
+                (None, None) => Err(anyhow!("You need to give either --to or --psbt"))
+                    .error_kind(cmd::ErrorKind::User),
             }
-            None => {
-                elog!(@info "No bets needed canceling");
-                CmdOutput::None
+        }
+        BetOpt::MutualCancel {
+            id,
+            to,
+            request,
+            message,
+            pad,
+            chunk,
+            copy_args,
+            fee_args,
+            yes,
+            print_tx,
+        } => {
+            let id = wallet.gun_db().resolve_bet_ref(&id)?;
+            match (to, request) {
+                (Some(to), _) => {
+                    let network = wallet.bdk_wallet().network();
+                    script_kind::check_address(&to, network).error_kind(cmd::ErrorKind::User)?;
+                    let (ciphertext, mut cipher) = wallet.generate_cancel_request(
+                        id,
+                        to.script_pubkey(),
+                        fee_args.fee,
+                        message,
+                    )?;
+                    let (ciphertext_str, overflow) = ciphertext.to_string_padded(pad, &mut cipher);
+                    if overflow > 0 && pad != 0 {
+                        elog!(
+                            @warning
+                            "cancel request is longer than {} by {} bytes so it will look unusually big",
+                            pad, overflow
+                        );
+                    }
+                    elog!(@suggestion "Send this to your counterparty so they can accept it with `gun bet mutual-cancel <their bet id> --request <request>`");
+                    Ok(CmdOutput::EmphasisedItem {
+                        main: (
+                            "request",
+                            ciphertext_output(ciphertext_str, chunk, &copy_args, config)?,
+                        ),
+                        other: vec![],
+                    })
+                }
+                (None, Some(request)) => {
+                    let (mut psbt, message) = wallet.decrypt_cancel_request(id, &request)?;
+                    if let Some(mut message) = message {
+                        sanitize_str(&mut message);
+                        elog!(
+                            @info
+                            "This message was attached to the cancel request:\n#### START MESSAGE ####\n{}\n#### END MESSAGE ####",
+                            message
+                        );
+                    }
+                    if !wallet.sign_cancel_request(id, &mut psbt, &fee_args.fee)? {
+                        return Err(anyhow!(
+                            "The cancel request for bet {} can't be broadcast until height {}",
+                            id,
+                            psbt.unsigned_tx.lock_time
+                        ));
+                    }
+                    let (output, txid) = cmd::decide_to_broadcast(
+                        config,
+                        wallet.blockchain()?,
+                        psbt,
+                        Operation::Cancel,
+                        yes,
+                        print_tx,
+                    )?;
+                    if let Some(txid) = txid {
+                        if let Err(e) = wallet.take_next_action(id, false) {
+                            elog!(
+                                @recoverable_error
+                                "Error updating state of bet {} after broadcasting cancel tx {}: {}",
+                                id, txid, e
+                            );
+                        }
+                    }
+                    Ok(output)
+                }
+                (None, None) => Err(anyhow!("You need to give either --to or --request"))
+                    .error_kind(cmd::ErrorKind::User),
             }
-        }),
+        }
         BetOpt::Forget { ids } => {
             let mut to_remove = vec![];
-            for id in ids {
+            for id in wallet.gun_db().resolve_bet_refs(&ids)? {
                 match wallet.gun_db().get_entity::<BetState>(id) {
                     Ok(Some(bet_state)) => match bet_state {
                         BetState::Proposed { local_proposal } => {
@@ -526,7 +1187,7 @@ pub fn run_bet_cmd(
                         BetState::Offered { .. } => if cmd::read_yn(&format!("Forgetting an offer can lead to loss of funds if it has been seen by the proposer. Are you sure you want to forget bet {}", id)) {
                             to_remove.push(id);
                         },
-                        BetState::Won { .. } | BetState::Claimed { height: None, .. } | BetState::Canceled { height: None, .. } | BetState::Included { .. }  => return Err(anyhow!("You may not forget bet {} because it is in the {} state", id, bet_state.name())),
+                        BetState::Won { .. } | BetState::Claimed { height: None, .. } | BetState::Refunded { height: None, .. } | BetState::Canceled { height: None, .. } | BetState::Included { .. }  => return Err(anyhow!("You may not forget bet {} because it is in the {} state", id, bet_state.name())),
                         _ => to_remove.push(id),
                     },
                     Ok(None) => return Err(anyhow!("Bet {} doesn't exist", id)),
@@ -543,13 +1204,115 @@ pub fn run_bet_cmd(
 
             for id in &to_remove {
                 let _ = wallet.gun_db().remove_entity::<BetState>(*id);
+                let _ = wallet.gun_db().remove_entity::<BetThread>(*id);
+                let _ = wallet.gun_db().remove_entity::<ProposalOffers>(*id);
+                let _ = wallet.gun_db().remove_entity::<BetFees>(*id);
+            }
+            for (fingerprint, FingerprintIndex(bet_id)) in wallet
+                .gun_db()
+                .list_entities_print_error::<FingerprintIndex>()
+            {
+                if to_remove.contains(&bet_id) {
+                    let _ = wallet
+                        .gun_db()
+                        .remove_entity::<FingerprintIndex>(fingerprint);
+                }
             }
 
             Ok(CmdOutput::List(
                 to_remove.into_iter().map(Cell::string).collect(),
             ))
         }
+        BetOpt::ExportState { id, file } => {
+            let id = wallet.gun_db().resolve_bet_ref(&id)?;
+            let bet_state = wallet
+                .gun_db()
+                .get_entity::<BetState>(id)?
+                .ok_or(anyhow!("Bet {} doesn't exist", id))?;
+            let bundle = BetBundle {
+                network: wallet.bdk_wallet().network(),
+                bet_state,
+                thread: wallet.gun_db().get_entity::<BetThread>(id)?,
+            };
+            std::fs::write(&file, bundle.encrypt(keychain)?)
+                .with_context(|| format!("writing bet to {}", file.display()))?;
+            elog!(@info "Import it on the other device with `gun bet import-state {}`", file.display());
+            Ok(item! { "file" => Cell::string(file.display()) })
+        }
+        BetOpt::ExportWatch { file } => {
+            let bundle = wallet.watch_bundle()?;
+            let n_bets = bundle.bets.len();
+            std::fs::write(
+                &file,
+                serde_json::to_string_pretty(&bundle.into_versioned()).unwrap(),
+            )
+            .with_context(|| format!("writing watch bundle to {}", file.display()))?;
+            Ok(item! {
+                "file" => Cell::string(file.display()),
+                "bets" => Cell::Int(n_bets as u64),
+            })
+        }
+        BetOpt::ImportState { file } => {
+            let bytes = std::fs::read(&file)
+                .with_context(|| format!("reading bet from {}", file.display()))?;
+            let bundle = BetBundle::decrypt(&bytes, keychain)?;
+            let network = wallet.bdk_wallet().network();
+            if bundle.network != network {
+                return Err(anyhow!(
+                    "the bet is from a {} wallet but this one is {}",
+                    bundle.network,
+                    network
+                ));
+            }
+            let gun_db = wallet.gun_db();
+            if let Some((existing, _)) = gun_db
+                .list_entities_print_error::<BetState>()
+                .find(|(_, bet_state)| bundle.is_same_bet(bet_state))
+            {
+                return Err(anyhow!(
+                    "this wallet already has the bet as bet {}",
+                    existing
+                ));
+            }
+
+            let oracle_id = match bundle.bet_state.clone().into_bet_or_prop() {
+                BetOrProp::Proposal(local_proposal) => local_proposal.proposal.oracle,
+                BetOrProp::Bet(bet)
+                | BetOrProp::OfferedBet {
+                    bet: OfferedBet(bet),
+                    ..
+                } => bet.oracle_id,
+            };
+            let fingerprint = match bundle.bet_state.clone().into_bet_or_prop() {
+                BetOrProp::Bet(bet)
+                | BetOrProp::OfferedBet {
+                    bet: OfferedBet(bet),
+                    ..
+                } => bet.fingerprint,
+                BetOrProp::Proposal(_) => None,
+            };
+            let name = bundle.bet_state.name();
+            let bet_id = gun_db.insert_bet(bundle.bet_state)?;
+            if let Some(fingerprint) = fingerprint {
+                gun_db.index_bet_fingerprint(fingerprint, bet_id)?;
+            }
+            if let Some(thread) = bundle.thread {
+                gun_db.insert_entity(bet_id, thread)?;
+            }
+            if gun_db
+                .get_entity::<OracleInfo>(oracle_id.clone())?
+                .is_none()
+            {
+                elog!(@suggestion "This wallet doesn't know the oracle {}. Add it with `gun bet oracle add https://{}` so the bet can be settled.", oracle_id, oracle_id);
+            }
+            elog!(@info "Run `gun -s bet show {}` to bring it up to date with the chain.", bet_id);
+            Ok(item! {
+                "id" => Cell::Int(bet_id.into()),
+                "state" => Cell::string(name),
+            })
+        }
         BetOpt::Show { id, raw } => {
+            let id = wallet.gun_db().resolve_bet_ref(&id)?;
             let gun_db = wallet.gun_db();
             let bet_state = gun_db
                 .get_entity::<BetState>(id)?
@@ -560,6 +1323,19 @@ pub fn run_bet_cmd(
             }
 
             let name = bet_state.name();
+            let counterparty = gun_db
+                .bet_contact(id)
+                .map(Cell::String)
+                .unwrap_or(Cell::Empty);
+            let fees = gun_db.get_entity::<BetFees>(id)?.unwrap_or_default();
+            let net = net_profit(&bet_state, &fees)
+                .map(Cell::SignedAmount)
+                .unwrap_or(Cell::Empty);
+            let fees = if fees == BetFees::default() {
+                Cell::Empty
+            } else {
+                Cell::Amount(fees.total())
+            };
 
             Ok(match bet_state.clone().into_bet_or_prop() {
                 BetOrProp::Proposal(local_proposal) => item! {
@@ -572,6 +1348,9 @@ pub fn run_bet_cmd(
                     "change-addr" => local_proposal.change.as_ref().and_then(|change| Address::from_script(change.script(), wallet.bdk_wallet().network())).map(Cell::string).unwrap_or(Cell::Empty),
                     "change-value" => local_proposal.change.as_ref().map(|change| Cell::Amount(change.value())).unwrap_or(Cell::Empty),
                     "tags" => Cell::List(local_proposal.tags.iter().map(Cell::string).collect()),
+                    "counterparty" => counterparty,
+                    "fees" => fees,
+                    "net" => net,
                     "string" => Cell::string(local_proposal.proposal.into_versioned()),
                 },
                 BetOrProp::Bet(bet)
@@ -595,6 +1374,11 @@ pub fn run_bet_cmd(
                         BetState::Claimed { txid, .. } => Cell::string(txid),
                         _ => Cell::Empty
                     },
+                    "refund-height" => bet.joint_output.refund.as_ref().map(|refund| Cell::string(refund.after)).unwrap_or(Cell::Empty),
+                    "refund-txid" => match bet_state {
+                        BetState::Refunded { txid, .. } => Cell::string(txid),
+                        _ => Cell::Empty
+                    },
                     "cancel-txid" => match bet_state {
                         BetState::Canceled { cancel_txid, .. } => Cell::string(cancel_txid),
                         _ => Cell::Empty
@@ -607,12 +1391,21 @@ pub fn run_bet_cmd(
                         BetState::Canceled { i_intend_cancel, .. } => Cell::string(i_intend_cancel),
                         _ => Cell::Empty
                     },
-                    "tags" => Cell::List(bet.tags.iter().map(Cell::string).collect())
+                    "tags" => Cell::List(bet.tags.iter().map(Cell::string).collect()),
+                    "counterparty" => counterparty,
+                    "fees" => fees,
+                    "net" => net,
+                    "bet-fingerprint" => bet.fingerprint.map(Cell::string).unwrap_or(Cell::Empty)
                 },
             })
         }
         BetOpt::List => Ok(list_bets(wallet.gun_db())),
-        BetOpt::Oracle(oracle_cmd) => run_oralce_cmd(wallet.gun_db(), oracle_cmd),
+        BetOpt::Calendar { ical } => bet_calendar(wallet.gun_db(), ical),
+        BetOpt::Simulate { .. } => unreachable!("we handled simulate already"),
+        BetOpt::Watch { id, interval } => {
+            watch_bet(wallet, wallet.gun_db().resolve_bet_ref(&id)?, interval)
+        }
+        BetOpt::Oracle(oracle_cmd) => run_oralce_cmd(wallet, oracle_cmd),
         BetOpt::Inspect(inspect_cmd) => Ok(match inspect_cmd {
             InspectOpt::Proposal {
                 proposal:
@@ -623,6 +1416,7 @@ pub fn run_bet_cmd(
                         inputs,
                         public_key,
                         change_script,
+                        refund_after,
                     }),
             } => item! {
                 "oracle" => Cell::string(oracle),
@@ -630,72 +1424,96 @@ pub fn run_bet_cmd(
                 "value" => Cell::Amount(value),
                 "inputs" => Cell::List(inputs.into_iter().map(Cell::string).collect()),
                 "public-key" => Cell::string(public_key),
-                "change-script" => change_script.map(|x| Cell::string(Script::from(x))).unwrap_or(Cell::Empty)
+                "change-script" => change_script.map(|x| Cell::string(Script::from(x))).unwrap_or(Cell::Empty),
+                "refund-height" => refund_after.map(Cell::string).unwrap_or(Cell::Empty)
             },
             InspectOpt::Offer {
                 id,
                 encrypted_offer,
             } => {
+                let id = wallet.gun_db().resolve_bet_ref(&id)?;
                 let bet_state = wallet
                     .gun_db()
                     .get_entity::<BetState>(id)?
                     .ok_or(anyhow!("unknown bet id {}", id))?;
-                match bet_state {
-                    BetState::Proposed { local_proposal } => {
-                        let event_id = &local_proposal.oracle_event.event.id;
-                        let (plaintext, offer_public_key, rng) =
-                            wallet.decrypt_offer(id, encrypted_offer, keychain)?;
-
-                        match plaintext {
-                            Plaintext::Offerv1 { offer, message } => {
-                                let (fee, feerate, valid) = match wallet.validate_offer(
-                                    id,
-                                    offer.clone(),
-                                    offer_public_key,
-                                    rng,
-                                    keychain,
-                                ) {
-                                    Ok(validated_offer) => {
-                                        let (fee, feerate, _) = validated_offer.bet.psbt.fee();
-                                        (Some(fee), Some(feerate), true)
-                                    }
-                                    Err(_) => (None, None, false),
-                                };
-
-                                let Offer {
-                                    inputs,
-                                    change,
-                                    choose_right,
-                                    value,
-                                } = offer;
-
-                                let chosen_outcome = Outcome {
-                                    id: event_id.clone(),
-                                    value: choose_right as u64,
-                                };
-
-                                item! {
-                                    "value" => Cell::Amount(value),
-                                    "their-choice" => Cell::string(chosen_outcome.outcome_string()),
-                                    "public-key" => Cell::string(&offer_public_key),
-                                    "change-script" => change.map(|x| Cell::string(x.script())).unwrap_or(Cell::Empty),
-                                    "inputs" => Cell::List(inputs.into_iter().map(|x| Cell::string(x.outpoint)).collect()),
-                                    "valid" => Cell::string(valid),
-                                    "fee" => fee.map(Cell::Amount).unwrap_or(Cell::Empty),
-                                    "feerate" => feerate.map(|x| Cell::string(x.as_sat_vb())).unwrap_or(Cell::Empty),
-                                    "message" => message.map(Cell::string).unwrap_or(Cell::Empty)
+                let event_id = wallet.proposal_for_bet(id)?.event_id;
+                let (plaintext, offer_public_key, rng) =
+                    wallet.decrypt_offer(id, encrypted_offer, keychain)?;
+
+                match plaintext {
+                    Plaintext::Offerv1 { offer, message } => {
+                        let can_take = matches!(bet_state, BetState::Proposed { .. });
+                        let (fee, feerate, valid) = if can_take {
+                            match wallet.validate_offer(
+                                id,
+                                offer.clone(),
+                                offer_public_key,
+                                rng,
+                                keychain,
+                            ) {
+                                Ok(validated_offer) => {
+                                    let (fee, feerate, _) = validated_offer.bet.psbt.fee();
+                                    (Some(fee), Some(feerate), Cell::string(true))
                                 }
+                                Err(_) => (None, None, Cell::string(false)),
                             }
-                            Plaintext::Messagev1(message) => {
-                                elog!(@info "This ciphertext contained a secret message:");
-                                item! { "message" => Cell::string(message) }
+                        } else {
+                            elog!(@warning "Bet {} is {} so this offer can no longer be taken", id, bet_state.name());
+                            (None, None, Cell::Empty)
+                        };
+                        // whether this is the offer that made the bet
+                        let taken = match bet_state.into_bet_or_prop() {
+                            BetOrProp::Bet(bet) => {
+                                let mut their_inputs = bet.their_inputs();
+                                let mut offer_inputs = offer
+                                    .inputs
+                                    .iter()
+                                    .map(|input| input.outpoint)
+                                    .collect::<Vec<_>>();
+                                their_inputs.sort();
+                                offer_inputs.sort();
+                                their_inputs == offer_inputs
                             }
+                            _ => false,
+                        };
+
+                        let Offer {
+                            inputs,
+                            change,
+                            choose_right,
+                            value,
+                            locktime,
+                        } = offer;
+
+                        let chosen_outcome = Outcome {
+                            id: event_id,
+                            value: choose_right as u64,
+                        };
+
+                        item! {
+                            "value" => Cell::Amount(value),
+                            "their-choice" => Cell::string(chosen_outcome.outcome_string()),
+                            "public-key" => Cell::string(&offer_public_key),
+                            "change-script" => change.map(|x| Cell::string(x.script())).unwrap_or(Cell::Empty),
+                            "inputs" => Cell::List(inputs.into_iter().map(|x| Cell::string(x.outpoint)).collect()),
+                            "locktime" => Cell::Int(locktime.into()),
+                            "can-take" => Cell::string(can_take),
+                            "taken" => Cell::string(taken),
+                            "valid" => valid,
+                            "fee" => fee.map(Cell::Amount).unwrap_or(Cell::Empty),
+                            "feerate" => feerate.map(|x| Cell::string(x.as_sat_vb())).unwrap_or(Cell::Empty),
+                            "message" => message.map(Cell::string).unwrap_or(Cell::Empty)
                         }
                     }
-                    _ => {
-                        return Err(anyhow!(
-                            "inspecting an offer when not in proposed state is not supported yet!"
-                        ))
+                    Plaintext::Messagev1(message) => {
+                        elog!(@info "This ciphertext contained a secret message:");
+                        item! { "message" => Cell::string(message) }
+                    }
+                    Plaintext::CancelRequestv1 { .. } => {
+                        return Err(anyhow!("This is a cancel request not an offer"))
+                    }
+                    Plaintext::BetMessagev1 { .. } => {
+                        return Err(anyhow!("This is a message about a bet not an offer"))
                     }
                 }
             }
@@ -704,6 +1522,7 @@ pub fn run_bet_cmd(
             let gun_db = wallet.gun_db();
             match tagopt {
                 TagOpt::Add { id, tag } => {
+                    let id = gun_db.resolve_bet_ref(&id)?;
                     gun_db.update_bets(&[id], |mut bet_state, _, _| {
                         bet_state.tags_mut().push(tag.clone());
                         Ok(bet_state)
@@ -711,6 +1530,7 @@ pub fn run_bet_cmd(
                     Ok(CmdOutput::None)
                 }
                 TagOpt::Remove { id, tag } => {
+                    let id = gun_db.resolve_bet_ref(&id)?;
                     gun_db.update_bets(&[id], |mut bet_state, _, _| {
                         bet_state
                             .tags_mut()
@@ -721,10 +1541,167 @@ pub fn run_bet_cmd(
                 }
             }
         }
+        BetOpt::Offers(offers_opt) => match offers_opt {
+            OffersOpt::Add {
+                id,
+                encrypted_offer,
+                paste: _,
+            } => {
+                let id = wallet.gun_db().resolve_bet_ref(&id)?;
+                let encrypted_offer = cmd::arg_or_paste(encrypted_offer)?;
+                let n = wallet.add_proposal_offer(id, encrypted_offer, keychain)?;
+                elog!(@suggestion "Compare it with other offers using `gun bet offers list {}` and take one with `gun bet take {} --offer <n>`", id, id);
+                Ok(item! { "n" => Cell::Int(n as u64) })
+            }
+            OffersOpt::List { id } => {
+                let id = wallet.gun_db().resolve_bet_ref(&id)?;
+                let event_id = wallet.proposal_for_bet(id)?.event_id;
+                let mut rows = vec![];
+                for (n, encrypted_offer) in wallet.proposal_offers(id)?.into_iter().enumerate() {
+                    let (plaintext, offer_public_key, rng) =
+                        match wallet.decrypt_offer(id, encrypted_offer, keychain) {
+                            Ok(decrypted) => decrypted,
+                            Err(e) => {
+                                elog!(@recoverable_error "Error decrypting offer {}: {}", n, e);
+                                continue;
+                            }
+                        };
+                    let (offer, message) = match plaintext {
+                        Plaintext::Offerv1 { offer, message } => (offer, message),
+                        _ => continue,
+                    };
+                    let (fee, feerate, odds, valid) = match wallet.validate_offer(
+                        id,
+                        offer.clone(),
+                        offer_public_key,
+                        rng,
+                        keychain,
+                    ) {
+                        Ok(validated_offer) => {
+                            let bet = &validated_offer.bet;
+                            let (fee, feerate, _) = bet.psbt.fee();
+                            let odds = (bet.joint_output_value - bet.local_value).as_sat() as f64
+                                / bet.local_value.as_sat() as f64;
+                            (
+                                Cell::Amount(fee),
+                                Cell::string(feerate.as_sat_vb()),
+                                Cell::string(format!("{:.3}", odds)),
+                                true,
+                            )
+                        }
+                        Err(_) => (Cell::Empty, Cell::Empty, Cell::Empty, false),
+                    };
+                    let chosen_outcome = Outcome {
+                        id: event_id.clone(),
+                        value: offer.choose_right as u64,
+                    };
+                    rows.push(vec![
+                        Cell::Int(n as u64),
+                        Cell::Amount(offer.value),
+                        Cell::string(chosen_outcome.outcome_string()),
+                        odds,
+                        fee,
+                        feerate,
+                        Cell::string(valid),
+                        message
+                            .map(|mut message| {
+                                sanitize_str(&mut message);
+                                Cell::String(message)
+                            })
+                            .unwrap_or(Cell::Empty),
+                    ]);
+                }
+                Ok(CmdOutput::table(
+                    vec![
+                        "n",
+                        "value",
+                        "their-choice",
+                        "odds",
+                        "fee",
+                        "feerate",
+                        "valid",
+                        "message",
+                    ],
+                    rows,
+                ))
+            }
+        },
+        BetOpt::Msg(msg_opt) => match msg_opt {
+            MsgOpt::Send {
+                id,
+                text,
+                pad,
+                chunk,
+                copy_args,
+            } => {
+                let id = wallet.gun_db().resolve_bet_ref(&id)?;
+                let text = text.unwrap_or_else(|| {
+                    use std::io::Read;
+                    let mut words = String::new();
+                    elog!(@suggestion "Type your message and use CTRL-D to finish it.");
+                    std::io::stdin().read_to_string(&mut words).unwrap();
+                    words
+                });
+                let (ciphertext, mut cipher) = wallet.send_bet_message(id, text)?;
+                let (ciphertext_str, overflow) = ciphertext.to_string_padded(pad, &mut cipher);
+                if overflow > 0 && pad != 0 {
+                    elog!(
+                        @warning
+                        "Ciphertext is longer than {} by {} bytes so it will look unusually big",
+                        pad, overflow
+                    );
+                }
+                elog!(@suggestion "Send this to your counterparty so they can read it with `gun bet msg read <their bet id> <ciphertext>`");
+                Ok(
+                    item! { "ciphertext" => ciphertext_output(ciphertext_str, chunk, &copy_args, config)? },
+                )
+            }
+            MsgOpt::Read {
+                id,
+                ciphertext,
+                paste: _,
+            } => {
+                let id = wallet.gun_db().resolve_bet_ref(&id)?;
+                let ciphertext = cmd::arg_or_paste(ciphertext)?;
+                let mut message = wallet.receive_bet_message(id, &ciphertext)?;
+                // remove control characters to prevent tricks.
+                sanitize_str(&mut message.text);
+                Ok(item! {
+                    "seq" => Cell::Int(message.seq.into()),
+                    "message" => Cell::String(message.text),
+                })
+            }
+            MsgOpt::List { id } => {
+                let id = wallet.gun_db().resolve_bet_ref(&id)?;
+                let thread = wallet
+                    .gun_db()
+                    .get_entity::<BetThread>(id)?
+                    .unwrap_or_default();
+                let rows = thread
+                    .messages
+                    .into_iter()
+                    .map(|mut message| {
+                        sanitize_str(&mut message.text);
+                        vec![
+                            Cell::DateTime(message.time),
+                            Cell::string(if message.sent { "sent" } else { "received" }),
+                            Cell::Int(message.seq.into()),
+                            Cell::String(message.text),
+                        ]
+                    })
+                    .collect();
+                Ok(CmdOutput::table(
+                    vec!["time", "direction", "seq", "message"],
+                    rows,
+                ))
+            }
+        },
         BetOpt::Reply {
             proposal,
             message,
             pad,
+            chunk,
+            copy_args,
         } => {
             let message = message.unwrap_or_else(|| {
                 use std::io::Read;
@@ -742,7 +1719,9 @@ pub fn run_bet_cmd(
                     pad, overflow
                 );
             }
-            Ok(item! { "ciphertext" => Cell::string(ciphertext_str) })
+            Ok(
+                item! { "ciphertext" => ciphertext_output(ciphertext_str, chunk, &copy_args, config)? },
+            )
         }
     }
 }
@@ -767,11 +1746,144 @@ fn reply(
     (ciphertext, cipher)
 }
 
+/// The cell for a ciphertext string, split into chunks of at most `chunk` characters if asked.
+/// It's copied to the clipboard (as it's output) if asked.
+fn ciphertext_output(
+    string: String,
+    chunk: Option<usize>,
+    copy_args: &cmd::CopyArgs,
+    config: &Config,
+) -> anyhow::Result<Cell> {
+    match chunk {
+        None => {
+            copy_args.copy_if_asked(config, &string)?;
+            Ok(Cell::String(string))
+        }
+        Some(max_len) => {
+            let chunks = crate::encode::chunk(&string, max_len)
+                .ok_or(anyhow!("--chunk {} is too short to fit any data", max_len))
+                .error_kind(cmd::ErrorKind::User)?;
+            elog!(@info "Split into {} chunks. They can be pasted back together in any order separated by spaces.", chunks.len());
+            copy_args.copy_if_asked(config, &chunks.join(" "))?;
+            Ok(Cell::List(chunks.into_iter().map(Cell::String).collect()))
+        }
+    }
+}
+
+/// Takes the offer on a `<bet id> <offer>` line if it passes the policy. Returns what happened.
+fn take_offer_line(
+    wallet: &GunWallet,
+    keychain: &Keychain,
+    config: &Config,
+    policy: &TakePolicy,
+    line: &str,
+    print_tx: bool,
+) -> anyhow::Result<(BetId, String)> {
+    let (id, encrypted_offer) = line
+        .split_once(char::is_whitespace)
+        .ok_or(anyhow!("expected a line like `<bet id> <offer>`"))?;
+    let id = wallet
+        .gun_db()
+        .resolve_bet_ref(&BetRef::from_str(id).context("invalid bet id")?)?;
+    let encrypted_offer = Ciphertext::from_str(encrypted_offer.trim())?;
+    let (plaintext, offer_public_key, rng) = wallet.decrypt_offer(id, encrypted_offer, keychain)?;
+    let offer = match plaintext {
+        Plaintext::Offerv1 { offer, .. } => offer,
+        _ => return Ok((id, "skipped: not an offer".into())),
+    };
+    let mut validated_offer = wallet.validate_offer(id, offer, offer_public_key, rng, keychain)?;
+    if let Err(reason) = policy.check(&validated_offer.bet) {
+        return Ok((id, format!("skipped: {}", reason)));
+    }
+    wallet.sign_validated_offer(&mut validated_offer)?;
+    let (output, txid) = cmd::decide_to_broadcast(
+        config,
+        wallet.blockchain()?,
+        validated_offer.bet.psbt.clone(),
+        Operation::Take,
+        true,
+        print_tx,
+    )?;
+    Ok(match (txid, output) {
+        (
+            Some(_),
+            CmdOutput::EmphasisedItem {
+                main: (_, Cell::String(tx_hex)),
+                ..
+            },
+        ) => {
+            wallet.set_offer_taken(validated_offer)?;
+            (id, format!("taken: {}", tx_hex))
+        }
+        (Some(txid), _) => {
+            wallet.set_offer_taken(validated_offer)?;
+            (id, format!("taken: {}", txid))
+        }
+        (None, _) => (id, "skipped: not broadcast".into()),
+    })
+}
+
+#[cfg(feature = "simulation")]
+pub fn run_simulate(against_self: bool) -> anyhow::Result<CmdOutput> {
+    if !against_self {
+        return Err(anyhow!("Only --against-self simulations are supported"))
+            .error_kind(cmd::ErrorKind::User);
+    }
+    let parties = crate::simulation::run_against_self()?;
+    Ok(CmdOutput::table(
+        vec![
+            "party",
+            "bet-id",
+            "state",
+            "balance-before",
+            "balance-after",
+        ],
+        parties
+            .into_iter()
+            .map(|party| {
+                vec![
+                    Cell::string(party.name),
+                    Cell::Int(party.bet_id.into()),
+                    Cell::string(party.state),
+                    Cell::Amount(party.balance_before),
+                    Cell::Amount(party.balance_after),
+                ]
+            })
+            .collect(),
+    ))
+}
+
+#[cfg(not(feature = "simulation"))]
+pub fn run_simulate(_against_self: bool) -> anyhow::Result<CmdOutput> {
+    Err(anyhow!(
+        "gun was built without the simulation feature. Build it with `--features simulation` to simulate bets."
+    ))
+}
+
+fn show_reputation(gun_db: &GunDatabase, contact: &str) {
+    let reputation = gun_db.contact_reputation(contact);
+    match reputation.score() {
+        Some(score) if score < 100 => elog!(@warning "Your bets with {}: {}", contact, reputation),
+        _ => elog!(@info "Your bets with {}: {}", contact, reputation),
+    }
+}
+
 fn list_bets(gun_db: &GunDatabase) -> CmdOutput {
     let mut rows = vec![];
 
     for (id, bet_state) in gun_db.list_entities_print_error::<BetState>() {
         let name = String::from(bet_state.name());
+        let counterparty = gun_db
+            .bet_contact(id)
+            .map(Cell::String)
+            .unwrap_or(Cell::Empty);
+        let net = gun_db
+            .get_entity::<BetFees>(id)
+            .ok()
+            .flatten()
+            .and_then(|fees| net_profit(&bet_state, &fees))
+            .map(Cell::SignedAmount)
+            .unwrap_or(Cell::Empty);
         match bet_state.into_bet_or_prop() {
             BetOrProp::Proposal(local_proposal) => rows.push(vec![
                 Cell::Int(id.into()),
@@ -792,10 +1904,13 @@ fn list_bets(gun_db: &GunDatabase) -> CmdOutput {
                 ),
                 Cell::Amount(local_proposal.proposal.value),
                 Cell::Empty,
+                net,
                 Cell::List(local_proposal.tags.iter().map(Cell::string).collect()),
+                counterparty,
                 Cell::string(local_proposal.proposal.oracle),
                 Cell::Empty,
                 Cell::string(local_proposal.proposal.event_id.short_id()),
+                Cell::Empty,
             ]),
             BetOrProp::Bet(bet)
             | BetOrProp::OfferedBet {
@@ -818,10 +1933,13 @@ fn list_bets(gun_db: &GunDatabase) -> CmdOutput {
                 ),
                 Cell::Amount(bet.local_value),
                 Cell::Amount(bet.joint_output_value.checked_sub(bet.local_value).unwrap()),
+                net,
                 Cell::List(bet.tags.iter().map(Cell::string).collect()),
+                counterparty,
                 Cell::string(&bet.oracle_id),
                 Cell::String(bet.my_outcome().outcome_string()),
                 Cell::string(bet.oracle_event.event.id.short_id()),
+                bet.fingerprint.map(Cell::string).unwrap_or(Cell::Empty),
             ]),
         }
     }
@@ -834,31 +1952,124 @@ fn list_bets(gun_db: &GunDatabase) -> CmdOutput {
             "in",
             "risk",
             "reward",
+            "net",
             "tags",
+            "counterparty",
             "oracle",
             "i-bet",
             "short-id",
+            "bet-fingerprint",
         ],
         rows,
     )
 }
 
-fn get_oracle_event_from_url(
-    gun_db: &GunDatabase,
+fn bet_calendar(gun_db: &GunDatabase, ical: Option<PathBuf>) -> anyhow::Result<CmdOutput> {
+    let now = Utc::now().naive_utc();
+    let entries = calendar::entries(gun_db.list_entities_print_error::<BetState>(), now);
+    if let Some(file) = ical {
+        std::fs::write(&file, calendar::to_ical(&entries, now))
+            .with_context(|| format!("writing calendar to {}", file.display()))?;
+        elog!(@info "Wrote {} events to {}", entries.len(), file.display());
+    }
+
+    let mut rows = vec![];
+    for (day, entries) in calendar::by_day(&entries) {
+        for (i, entry) in entries.into_iter().enumerate() {
+            rows.push(vec![
+                if i == 0 {
+                    Cell::String(day.format("%a %Y-%m-%d").to_string())
+                } else {
+                    Cell::Empty
+                },
+                Cell::String(entry.time.format("%H:%M").to_string()),
+                Cell::String(crate::format_dt_diff_till_now(entry.time)),
+                Cell::Int(entry.bet_id.into()),
+                Cell::string(entry.state),
+                Cell::Amount(entry.risk),
+                Cell::string(entry.event_id.short_id()),
+            ]);
+        }
+    }
+
+    Ok(CmdOutput::table(
+        vec!["day", "time(UTC)", "in", "id", "state", "risk", "short-id"],
+        rows,
+    ))
+}
+
+/// The most interesting txid for the bet in its current state.
+fn state_txid(bet_state: &BetState) -> Option<Txid> {
+    match bet_state {
+        BetState::Proposed { .. } => None,
+        BetState::Offered { bet, .. } => Some(bet.0.tx().txid()),
+        BetState::Included { bet, .. } | BetState::Won { bet, .. } | BetState::Lost { bet, .. } => {
+            Some(bet.tx().txid())
+        }
+        BetState::Claimed { txid, .. } | BetState::Refunded { txid, .. } => Some(*txid),
+        BetState::Canceled { cancel_txid, .. } => Some(*cancel_txid),
+    }
+}
+
+fn watch_bet(wallet: &GunWallet, id: BetId, interval: u64) -> anyhow::Result<CmdOutput> {
+    const MAX_BACKOFF: u64 = 10 * 60;
+    let mut last_name = None;
+    let mut wait = interval;
+    loop {
+        match wallet.take_next_action(id, true) {
+            Ok(()) => wait = interval,
+            Err(e) => {
+                elog!(@recoverable_error "checking bet {} failed: {:?}", id, e);
+                wait = (wait * 2).min(MAX_BACKOFF.max(interval));
+            }
+        }
+
+        let bet_state = wallet
+            .gun_db()
+            .get_entity::<BetState>(id)?
+            .ok_or(anyhow!("Bet {} doesn't exist", id))?;
+        let name = bet_state.name();
+        if last_name != Some(name) {
+            let txid = state_txid(&bet_state)
+                .map(|txid| txid.to_string())
+                .unwrap_or_default();
+            println!(
+                "{} {} {}",
+                Utc::now().format("%Y-%m-%d %H:%M:%S"),
+                name,
+                txid
+            );
+            if let BetState::Won { .. } = bet_state {
+                elog!(@suggestion "Claim your winnings with `gun bet claim`");
+            }
+            last_name = Some(name);
+        }
+
+        match bet_state {
+            BetState::Claimed {
+                height: Some(_), ..
+            }
+            | BetState::Refunded {
+                height: Some(_), ..
+            } => return Ok(CmdOutput::None),
+            BetState::Lost { .. } => return Err(anyhow!("bet {} was lost", id)),
+            BetState::Canceled {
+                height: Some(_), ..
+            } => return Err(anyhow!("bet {} was canceled", id)),
+            _ => std::thread::sleep(std::time::Duration::from_secs(wait)),
+        }
+    }
+}
+
+pub(crate) fn get_oracle_event_from_url(
+    wallet: &GunWallet,
     url: Url,
+    refresh: bool,
 ) -> anyhow::Result<(OracleEvent, OracleInfo, bool)> {
+    let gun_db = wallet.gun_db();
     let oracle_id = url.host_str().ok_or(anyhow!("url {} missing host", url))?;
 
-    let event_response = ureq::get(url.as_str())
-        .call()
-        .with_context(|| format!("while getting {}", url))?
-        .into_json::<EventResponse>()
-        .with_context(|| {
-            format!(
-                "while decoding the response from {}. Are you sure this is a valid event url?",
-                url
-            )
-        })?;
+    let event_response = wallet.get_oracle_event(url.as_str(), false, refresh)?;
 
     let oracle_info = gun_db
         .get_entity::<OracleInfo>(oracle_id.to_string())?
@@ -866,7 +2077,8 @@ fn get_oracle_event_from_url(
             "oracle '{}' is not trusted -- run `gun bet oracle add '{}' to trust it",
             oracle_id,
             oracle_id
-        ))?;
+        ))
+        .error_kind(cmd::ErrorKind::User)?;
 
     let event_id = olivia_core::EventId::from_str(url.path())
         .with_context(|| format!("trying to parse the path of {} for ", &url))?;
@@ -874,14 +2086,15 @@ fn get_oracle_event_from_url(
     let oracle_event = event_response
         .announcement
         .verify_against_id(&event_id, &oracle_info.oracle_keys.announcement)
-        .ok_or(anyhow!("Invalid oracle announcement returned from {}", url))?;
+        .ok_or(anyhow!("Invalid oracle announcement returned from {}", url))
+        .error_kind(cmd::ErrorKind::Oracle)?;
 
     let is_attested = event_response.attestation.is_some();
 
     Ok((oracle_event, oracle_info, is_attested))
 }
 
-fn bet_prompt(bet: &Bet, bet_verb: &str, you_paying_fee: bool) -> String {
+pub(crate) fn bet_prompt(bet: &Bet, bet_verb: &str, you_paying_fee: bool) -> String {
     use std::fmt::Write;
     use term_table::{row::Row, Table};
     let mut res = String::new();
@@ -913,6 +2126,12 @@ fn bet_prompt(bet: &Bet, bet_verb: &str, you_paying_fee: bool) -> String {
         if feerate_estimated { "est. fee" } else { "fee" }.into(),
         format!("{} ({:.3} s/vb)", fee, feerate.as_sat_vb()),
     ]));
+    if let Some(dust_fee) = bet.dust_fee {
+        table.add_row(Row::new(vec![
+            "dust-to-fee".into(),
+            format!("{} (your change was too small for an output)", dust_fee),
+        ]));
+    }
 
     if let Some(time) = expected_outcome_time {
         table.add_row(Row::new(vec![
@@ -921,6 +2140,13 @@ fn bet_prompt(bet: &Bet, bet_verb: &str, you_paying_fee: bool) -> String {
         ]));
     }
 
+    if let Some(refund) = &bet.joint_output.refund {
+        table.add_row(Row::new(vec![
+            "refund-height".into(),
+            refund.after.to_string(),
+        ]));
+    }
+
     writeln!(&mut res, "{}", table.render()).unwrap();
     writeln!(
         &mut res,
@@ -966,6 +2192,7 @@ mod test {
                     .script_pubkey()
                     .into(),
             ),
+            refund_after: None,
         });
 
         let (ciphertext, mut pad_cipher) = reply(&keychain, fixed, "a test message".into());
@@ -0,0 +1,99 @@
+use super::{Cell, CmdOutput};
+use crate::{
+    bip85::{get_bip85_mnemonic, get_bip85_xprv, MAX_INDEX},
+    cmd,
+    config::GunSigner,
+    elog, passphrase,
+};
+use anyhow::anyhow;
+use bdk::bitcoin::{secp256k1::Secp256k1, util::bip32::ExtendedPrivKey};
+use std::path::Path;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum Bip85Opt {
+    /// Derive a child BIP39 seed phrase to set up another wallet with.
+    Seed {
+        /// The BIP85 index of the child seed
+        #[structopt(long, parse(try_from_str = parse_index))]
+        index: u32,
+        /// The number of words in the seed phrase (12, 18 or 24)
+        #[structopt(long, default_value = "12")]
+        words: u32,
+    },
+    /// Derive a child master extended private key.
+    Xprv {
+        /// The BIP85 index of the child key
+        #[structopt(long, parse(try_from_str = parse_index))]
+        index: u32,
+    },
+}
+
+fn parse_index(index: &str) -> anyhow::Result<u32> {
+    let index = index.parse::<u32>()?;
+    if index > MAX_INDEX {
+        return Err(anyhow!("the index must be at most {}", MAX_INDEX));
+    }
+    Ok(index)
+}
+
+/// The wallet's master key from its seed words (asking for the passphrase if it has one).
+pub(crate) fn load_master_xpriv(wallet_dir: &Path) -> anyhow::Result<ExtendedPrivKey> {
+    let config = cmd::load_config(&wallet_dir.join("config.json"))?;
+    let (passphrase_fingerprint, passphrase_source) = config
+        .signers
+        .iter()
+        .find_map(|signer| match signer {
+            GunSigner::SeedWordsFile {
+                passphrase_fingerprint,
+                passphrase_source,
+                ..
+            } => Some((*passphrase_fingerprint, passphrase_source.as_ref())),
+            _ => None,
+        })
+        .ok_or(anyhow!(
+            "This wallet wasn't set up with seed words so there's nothing to derive from"
+        ))?;
+    let mnemonic = cmd::load_seed_words(wallet_dir)?;
+
+    match passphrase_fingerprint {
+        None => Ok(ExtendedPrivKey::new_master(
+            config.network,
+            &mnemonic.to_seed(""),
+        )?),
+        Some(fingerprint) => {
+            let passphrase =
+                passphrase::read_passphrase(passphrase_source, "Enter your wallet passphrase: ")?;
+            passphrase::master_xpriv(&mnemonic, config.network, fingerprint, &passphrase).ok_or(
+                anyhow!("Invalid passphrase, derived fingerprint does not match"),
+            )
+        }
+    }
+}
+
+pub fn run_bip85_cmd(wallet_dir: &Path, opt: Bip85Opt) -> anyhow::Result<CmdOutput> {
+    if !cmd::read_yn("This will print out secret key material that can spend coins. Make sure no one can see your screen and that the output isn't being logged. Continue") {
+        return Ok(CmdOutput::None);
+    }
+    let xpriv = load_master_xpriv(wallet_dir)?;
+    let secp = Secp256k1::signing_only();
+
+    let output = match opt {
+        Bip85Opt::Seed { index, words } => {
+            let mnemonic = get_bip85_mnemonic(xpriv, words, index, &secp)?;
+            CmdOutput::EmphasisedItem {
+                main: ("seed", Cell::string(mnemonic)),
+                other: vec![("index", Cell::Int(index.into()))],
+            }
+        }
+        Bip85Opt::Xprv { index } => {
+            let child = get_bip85_xprv(xpriv, index, &secp)?;
+            CmdOutput::EmphasisedItem {
+                main: ("xprv", Cell::string(child)),
+                other: vec![("index", Cell::Int(index.into()))],
+            }
+        }
+    };
+    elog!(@warning "Anyone who sees this can spend coins sent to the child wallet.");
+    Ok(output)
+}
@@ -0,0 +1,66 @@
+//! Putting bet strings on and getting them from the system clipboard.
+//!
+//! This needs gun to be built with the `clipboard` feature. Headless builds can leave it out and
+//! `--copy`/`--paste` become errors.
+use crate::{config::Config, elog};
+use std::str::FromStr;
+
+#[derive(Clone, Debug, structopt::StructOpt)]
+pub struct CopyArgs {
+    /// Put the output on the clipboard (set copy_to_clipboard in the config to always do this)
+    #[structopt(long)]
+    pub copy: bool,
+}
+
+impl CopyArgs {
+    /// Copy `string` if asked to with `--copy` or by the config. Failing to copy is only an error
+    /// if `--copy` was given.
+    pub fn copy_if_asked(&self, config: &Config, string: &str) -> anyhow::Result<()> {
+        if self.copy {
+            copy(string)?;
+            elog!(@info "Copied to the clipboard");
+        } else if config.copy_to_clipboard {
+            match copy(string) {
+                Ok(()) => elog!(@info "Copied to the clipboard"),
+                Err(e) => elog!(@warning "Couldn't copy to the clipboard: {}", e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Use the argument if it was given otherwise parse what's on the clipboard.
+pub fn arg_or_paste<T>(arg: Option<T>) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: Into<anyhow::Error>,
+{
+    match arg {
+        Some(arg) => Ok(arg),
+        None => T::from_str(paste()?.trim()).map_err(|e| {
+            let e: anyhow::Error = e.into();
+            e.context("parsing what was on the clipboard")
+        }),
+    }
+}
+
+#[cfg(feature = "clipboard")]
+pub fn copy(string: &str) -> anyhow::Result<()> {
+    arboard::Clipboard::new()?.set_text(string.to_string())?;
+    Ok(())
+}
+
+#[cfg(feature = "clipboard")]
+pub fn paste() -> anyhow::Result<String> {
+    Ok(arboard::Clipboard::new()?.get_text()?)
+}
+
+#[cfg(not(feature = "clipboard"))]
+pub fn copy(_string: &str) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!("gun was built without clipboard support"))
+}
+
+#[cfg(not(feature = "clipboard"))]
+pub fn paste() -> anyhow::Result<String> {
+    Err(anyhow::anyhow!("gun was built without clipboard support"))
+}
@@ -0,0 +1,70 @@
+use super::{Cell, CmdOutput};
+use crate::{betting::BetState, cmd};
+use std::{collections::BTreeSet, path::Path};
+use structopt::StructOpt;
+
+/// Completes bet ids for the `gun bet` subcommands that take one. Appended to the generated bash
+/// script since clap can't generate dynamic completions itself.
+const BASH_DYNAMIC: &str = r#"
+_gun_dynamic() {
+    local cur="${COMP_WORDS[COMP_CWORD]}"
+    if [[ "${COMP_WORDS[1]}" == "bet" ]]; then
+        case "${COMP_WORDS[2]}" in
+            show|cancel|forget|watch|take|reply)
+                COMPREPLY=($(compgen -W "$(gun complete bet-ids 2>/dev/null)" -- "$cur"))
+                return 0
+                ;;
+            export-state)
+                if [[ "$COMP_CWORD" -eq 3 ]]; then
+                    COMPREPLY=($(compgen -W "$(gun complete bet-ids 2>/dev/null)" -- "$cur"))
+                    return 0
+                fi
+                ;;
+            tag)
+                if [[ "$COMP_CWORD" -eq 4 ]]; then
+                    COMPREPLY=($(compgen -W "$(gun complete bet-ids 2>/dev/null)" -- "$cur"))
+                else
+                    COMPREPLY=($(compgen -W "$(gun complete tags 2>/dev/null)" -- "$cur"))
+                fi
+                return 0
+                ;;
+        esac
+    fi
+    _gun "$@"
+}
+complete -F _gun_dynamic -o bashdefault -o default gun
+"#;
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum CompleteOpt {
+    /// List the ids of all bets
+    BetIds,
+    /// List every tag used on a bet
+    Tags,
+}
+
+/// Write the completion script for `shell` to stdout.
+pub fn run_completions(
+    mut app: structopt::clap::App,
+    shell: structopt::clap::Shell,
+) -> anyhow::Result<CmdOutput> {
+    app.gen_completions_to("gun", shell, &mut std::io::stdout());
+    if let structopt::clap::Shell::Bash = shell {
+        print!("{}", BASH_DYNAMIC);
+    }
+    Ok(CmdOutput::None)
+}
+
+pub fn run_complete_cmd(wallet_dir: &Path, opt: CompleteOpt) -> anyhow::Result<CmdOutput> {
+    let gun_db = cmd::load_gun_db(wallet_dir)?;
+    let bets = gun_db.list_entities_print_error::<BetState>();
+    Ok(match opt {
+        CompleteOpt::BetIds => CmdOutput::List(bets.map(|(id, _)| Cell::string(id)).collect()),
+        CompleteOpt::Tags => {
+            let tags = bets
+                .flat_map(|(_, bet_state)| bet_state.tags().to_vec())
+                .collect::<BTreeSet<_>>();
+            CmdOutput::List(tags.into_iter().map(Cell::string).collect())
+        }
+    })
+}
@@ -1,13 +1,17 @@
 use crate::{
     cmd,
     cmd::Cell,
-    config::GunSigner,
+    config::{Config, GunSigner, SignerOptions},
     database::{ProtocolKind, StringDescriptor},
-    eitem,
+    eitem, elog,
     keychain::ProtocolSecret,
+    passphrase::{self, PassphraseSource},
+    secret::Secret,
     wallet::GunWallet,
 };
+use anyhow::{anyhow, Context};
 use bdk::{blockchain::AnyBlockchainConfig, KeychainKind};
+use serde_json::Value;
 use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
@@ -70,12 +74,35 @@ pub enum BlockchainSettings {
     Concurrency(SetGetUnset<u8>),
     /// How many inactive addresses to give up scanning after
     StopGap(SetGet<usize>),
+    /// How many seconds to wait for the esplora service or an oracle before giving up (default 30)
+    Timeout(SetGetUnset<u64>),
+    /// How many times to retry network requests that fail for a temporary reason (default 2)
+    Retries(SetGetUnset<u32>),
+    /// Check the tip height of base_url and each server in esplora-fallbacks
+    ///
+    /// Add fallbacks with e.g. `gun config set esplora-fallbacks '["https://blockstream.info/api"]'`.
+    Status,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(rename_all = "snake_case")]
+pub enum FeeSettings {
+    /// Ask for confirmation (or fail with --yes) when a transaction's fee is over this many sats.
+    MaxFeeAbs(SetGetUnset<u64>),
+    /// Ask for confirmation (or fail with --yes) when a transaction's feerate is over this many
+    /// sats per vbyte.
+    MaxFeerate(SetGetUnset<f32>),
+    /// Where `in-blocks:<n>` fee estimates come from: "mempool.space" or the url of an API like it
+    /// (esplora's estimates are used when unset).
+    Estimator(SetGetUnset<String>),
 }
 
 #[derive(StructOpt, Debug, Clone)]
 pub enum ConfigOpt {
     /// configure the esplora blockchain client.
     Blockchain(BlockchainSettings),
+    /// Safety limits on transaction fees.
+    Fee(FeeSettings),
     /// Protocol specific configuration options.
     Protocol(Protocol),
     /// The wallet's descriptors.
@@ -86,6 +113,17 @@ pub enum ConfigOpt {
     ///
     /// This can be changed by setting the $GUN_DIR environment variable.
     Dir(Get),
+    /// Get the network the wallet is on.
+    ///
+    /// For signets this includes the challenge script and the network magic nodes on it use.
+    Network(Get),
+    /// Set any value in config.json by its dotted path e.g. `blockchain.base_url`
+    ///
+    /// The value is read as JSON if it can be (so `null` unsets an optional value) and as a
+    /// string otherwise. The result is checked before it's written.
+    Set { key: String, value: String },
+    /// Get a value from config.json by its dotted path (or all of it)
+    Get { key: Option<String> },
 }
 
 #[derive(StructOpt, Debug, Clone)]
@@ -96,6 +134,22 @@ pub enum SignerActions {
     Remove { index: usize },
     /// List existing signers
     List,
+    /// Where the seed words signer gets the wallet passphrase from (shows it if no option is given)
+    ///
+    /// With --command or --keyring nobody needs to type the passphrase so signing can happen
+    /// unattended (e.g. claiming bets from cron). The passphrase is checked against the wallet
+    /// before anything is saved.
+    Passphrase {
+        /// Run this with `sh -c` and use what it prints e.g. "pass show gun"
+        #[structopt(long, conflicts_with_all = &["keyring", "prompt"])]
+        command: Option<String>,
+        /// Save the passphrase in the OS keyring (you'll be asked for it once)
+        #[structopt(long, conflicts_with = "prompt")]
+        keyring: bool,
+        /// Go back to asking for the passphrase every time
+        #[structopt(long)]
+        prompt: bool,
+    },
 }
 
 #[derive(StructOpt, Debug, Clone)]
@@ -107,9 +161,53 @@ pub enum AddSigner {
     Psbt {
         /// The path the signer will write PSBTs to so they can be signed.
         path: PathBuf,
+        #[structopt(flatten)]
+        options: AddSignerOptions,
+    },
+    /// Add a QR code signer for airgapped devices like SeedSigner or Keystone.
+    ///
+    /// The PSBT is shown as animated UR QR codes and the signed PSBT is pasted back in.
+    Qr {
+        #[structopt(flatten)]
+        options: AddSignerOptions,
     },
 }
 
+#[derive(StructOpt, Debug, Clone)]
+pub struct AddSignerOptions {
+    /// Only register the signer for this keychain (external or internal).
+    #[structopt(long, parse(try_from_str = parse_keychain))]
+    keychain: Option<KeychainKind>,
+    /// When to ask this signer to sign relative to the others (lowest first). Defaults to its
+    /// position in the list.
+    #[structopt(long)]
+    order: Option<usize>,
+    /// Carry on signing with the other signers if this one fails.
+    #[structopt(long)]
+    optional: bool,
+}
+
+impl From<AddSignerOptions> for SignerOptions {
+    fn from(options: AddSignerOptions) -> Self {
+        SignerOptions {
+            keychain: options.keychain,
+            order: options.order,
+            required: !options.optional,
+        }
+    }
+}
+
+fn parse_keychain(string: &str) -> anyhow::Result<KeychainKind> {
+    match string {
+        "external" => Ok(KeychainKind::External),
+        "internal" => Ok(KeychainKind::Internal),
+        _ => Err(anyhow::anyhow!(
+            "'{}' is not a keychain (try external or internal)",
+            string
+        )),
+    }
+}
+
 #[derive(StructOpt, Debug, Clone)]
 pub enum Descriptors {
     /// The "external" descriptor (where gun derives receiving addresses from).
@@ -169,6 +267,89 @@ macro_rules! setget {
     };
 }
 
+/// Find the value at a dotted path. Keys match with either `-` or `_` between words.
+fn lookup<'a>(value: &'a mut Value, path: &str) -> Option<&'a mut Value> {
+    path.split('.')
+        .try_fold(value, |value, segment| match value {
+            Value::Object(map) => {
+                let key = matching_key(map, segment)?;
+                map.get_mut(&key)
+            }
+            Value::Array(list) => list.get_mut(segment.parse::<usize>().ok()?),
+            _ => None,
+        })
+}
+
+fn matching_key(map: &serde_json::Map<String, Value>, segment: &str) -> Option<String> {
+    [
+        segment.to_string(),
+        segment.replace('_', "-"),
+        segment.replace('-', "_"),
+    ]
+    .into_iter()
+    .find(|key| map.contains_key(key))
+}
+
+fn get_path(config: &Config, key: &str) -> anyhow::Result<Value> {
+    let mut json = serde_json::to_value(config)?;
+    Ok(lookup(&mut json, key)
+        .ok_or(anyhow!("'{}' is not set", key))?
+        .clone())
+}
+
+fn set_path(config: &Config, key: &str, raw_value: &str) -> anyhow::Result<Config> {
+    let json = serde_json::to_value(config)?;
+    let (parent_path, last) = match key.rsplit_once('.') {
+        Some((parent_path, last)) => (Some(parent_path), last),
+        None => (None, key),
+    };
+
+    let try_set = |name: &str, new_value: Value| -> anyhow::Result<Config> {
+        let mut json = json.clone();
+        let parent = match parent_path {
+            Some(parent_path) => lookup(&mut json, parent_path)
+                .ok_or(anyhow!("'{}' is not a config section", parent_path))?,
+            None => &mut json,
+        };
+        let map = parent
+            .as_object_mut()
+            .ok_or(anyhow!("'{}' is not a config section", key))?;
+        let name = matching_key(map, last).unwrap_or_else(|| name.to_string());
+        map.insert(name, new_value.clone());
+        let new_config = serde_json::from_value::<Config>(json)?;
+
+        // unknown keys are ignored when deserializing so check the value actually landed somewhere
+        let mut check = serde_json::to_value(&new_config)?;
+        if !new_value.is_null() && lookup(&mut check, key).is_none() {
+            return Err(anyhow!("'{}' is not a config option", key));
+        }
+        Ok(new_config)
+    };
+
+    // optional values that aren't set don't appear so we have to guess how the key is spelled
+    let with_value = |new_value: Value| -> anyhow::Result<Config> {
+        let mut result = try_set(last, new_value.clone());
+        for name in [last.replace('_', "-"), last.replace('-', "_")] {
+            if result.is_err() {
+                if let Ok(config) = try_set(&name, new_value.clone()) {
+                    result = Ok(config);
+                }
+            }
+        }
+        result
+    };
+
+    let as_string = Value::String(raw_value.to_string());
+    match serde_json::from_str::<Value>(raw_value) {
+        Ok(parsed) if parsed != as_string => with_value(parsed).or_else(|e| {
+            // e.g. a number given for a string option
+            with_value(as_string).map_err(|_| e)
+        }),
+        _ => with_value(as_string),
+    }
+    .with_context(|| format!("setting {} to {}", key, raw_value))
+}
+
 pub fn run_config_cmd(
     wallet_dir: &Path,
     wallet: &GunWallet,
@@ -187,11 +368,56 @@ pub fn run_config_cmd(
                     setgetunset!(setget, config, config_path, esplora_config, concurrency)
                 }
                 StopGap(setget) => setget!(setget, config, config_path, esplora_config, stop_gap),
+                Timeout(setget) => {
+                    setgetunset!(setget, config, config_path, esplora_config, timeout)
+                }
+                Retries(setget) => setgetunset!(setget, config, config_path, config, retries),
+                Status => {
+                    let statuses = crate::esplora::check_backends(&config);
+                    let chosen =
+                        crate::esplora::choose_backend(&statuses).map(|status| status.url.clone());
+                    let rows = statuses
+                        .into_iter()
+                        .map(|status| {
+                            vec![
+                                Cell::string(&status.url),
+                                match &status.tip {
+                                    Ok(tip) => Cell::Int(*tip as u64),
+                                    Err(_) => Cell::Empty,
+                                },
+                                Cell::string(format!("{}ms", status.latency.as_millis())),
+                                Cell::string(status.tip.err().unwrap_or_default()),
+                                Cell::string(chosen.as_ref() == Some(&status.url)),
+                            ]
+                        })
+                        .collect();
+                    return Ok(CmdOutput::table(
+                        vec!["url", "tip-height", "latency", "error", "selected"],
+                        rows,
+                    ));
+                }
             }
         }
+        ConfigOpt::Fee(prop) => match prop {
+            FeeSettings::MaxFeeAbs(setget) => {
+                setgetunset!(setget, config, config_path, config, max_fee_abs)
+            }
+            FeeSettings::MaxFeerate(setget) => {
+                setgetunset!(setget, config, config_path, config, max_feerate)
+            }
+            FeeSettings::Estimator(setget) => {
+                setgetunset!(setget, config, config_path, config, fee_estimator)
+            }
+        },
         ConfigOpt::Protocol(protocol) => match protocol {
             Protocol::Bet(bet_settings) => match bet_settings {
                 BetSettings::ProtocolSecret(setget) => {
+                    if let Some(path) = &config.protocol_secret_file {
+                        return Err(anyhow!(
+                            "The protocol secret is kept in {} rather than the database",
+                            path.display()
+                        ));
+                    }
                     let db = wallet.gun_db();
                     match setget {
                         SetGet::Get => {
@@ -225,7 +451,13 @@ pub fn run_config_cmd(
         ConfigOpt::Signer(action) => Ok(match action {
             SignerActions::Add(signer) => {
                 match signer {
-                    AddSigner::Psbt { path } => config.signers.push(GunSigner::PsbtDir { path }),
+                    AddSigner::Psbt { path, options } => config.signers.push(GunSigner::PsbtDir {
+                        path,
+                        options: options.into(),
+                    }),
+                    AddSigner::Qr { options } => config.signers.push(GunSigner::Qr {
+                        options: options.into(),
+                    }),
                 }
                 CmdOutput::None
             }
@@ -247,11 +479,124 @@ pub fn run_config_cmd(
                     .collect();
                 return Ok(CmdOutput::table(vec!["index", "signer"], rows));
             }
+            SignerActions::Passphrase {
+                command,
+                keyring,
+                prompt,
+            } => {
+                let network = config.network;
+                let (fingerprint, source) = config
+                    .signers
+                    .iter_mut()
+                    .find_map(|signer| match signer {
+                        GunSigner::SeedWordsFile {
+                            passphrase_fingerprint,
+                            passphrase_source,
+                            ..
+                        } => Some((*passphrase_fingerprint, passphrase_source)),
+                        _ => None,
+                    })
+                    .ok_or(anyhow!("the wallet doesn't have a seed words signer"))?;
+                let fingerprint = fingerprint
+                    .ok_or(anyhow!("the wallet's seed words don't have a passphrase"))?;
+                let new_source = match (command, keyring, prompt) {
+                    (_, _, true) => None,
+                    (Some(command), ..) => Some(PassphraseSource::Command(command)),
+                    (None, true, _) => {
+                        let passphrase =
+                            Secret::new(cmd::read_secret("Enter your wallet passphrase:")?);
+                        let mnemonic = cmd::load_seed_words(wallet_dir)?;
+                        if passphrase::master_xpriv(&mnemonic, network, fingerprint, &passphrase)
+                            .is_none()
+                        {
+                            return Err(anyhow!("that passphrase doesn't match the wallet"));
+                        }
+                        let (service, user) = ("gun".to_string(), fingerprint.to_string());
+                        passphrase::keyring_set(&service, &user, &passphrase)?;
+                        Some(PassphraseSource::Keyring { service, user })
+                    }
+                    (None, false, false) => {
+                        return Ok(
+                            eitem!("passphrase-source" => Cell::maybe_string(source.as_ref())),
+                        )
+                    }
+                };
+                if let Some(new_source) = &new_source {
+                    let mnemonic = cmd::load_seed_words(wallet_dir)?;
+                    let passphrase = new_source.read()?;
+                    if passphrase::master_xpriv(&mnemonic, network, fingerprint, &passphrase)
+                        .is_none()
+                    {
+                        return Err(anyhow!(
+                            "the passphrase from {} doesn't match the wallet",
+                            new_source
+                        ));
+                    }
+                    elog!(@celebration "The wallet passphrase will come from {}", new_source);
+                }
+                *source = new_source;
+                CmdOutput::None
+            }
         }),
         ConfigOpt::Dir(Get::Get) => {
             return Ok(eitem!( "GUN_DIR" => Cell::string(wallet_dir.display())))
         }
+        ConfigOpt::Network(Get::Get) => {
+            let challenge = config.signet_challenge();
+            let magic = challenge.as_ref().map(crate::signet::magic);
+            return Ok(eitem! {
+                "network" => Cell::string(config.network),
+                "signet-challenge" => Cell::maybe_string(challenge.map(|challenge| crate::hex::encode(challenge.as_bytes()))),
+                "signet-magic" => Cell::maybe_string(magic.map(|magic| crate::hex::encode(&magic))),
+            });
+        }
+        ConfigOpt::Set { key, value } => {
+            config = set_path(&config, &key, &value)?;
+            Ok(CmdOutput::None)
+        }
+        ConfigOpt::Get { key } => {
+            return Ok(match key {
+                None => CmdOutput::Json(serde_json::to_value(&config)?),
+                Some(key) => match get_path(&config, &key)? {
+                    Value::String(string) => eitem!("value" => Cell::string(string)),
+                    value => CmdOutput::Json(value),
+                },
+            })
+        }
     };
     cmd::write_config(config_path, config)?;
     output
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bdk::bitcoin::Network;
+
+    #[test]
+    fn set_and_get_by_path() {
+        let config = Config::default_config(Network::Regtest);
+        let config = set_path(&config, "blockchain.stop_gap", "25").unwrap();
+        assert_eq!(config.blockchain_config().stop_gap, 25);
+        let config = set_path(&config, "blockchain.base_url", "http://localhost:3002").unwrap();
+        assert_eq!(config.blockchain_config().base_url, "http://localhost:3002");
+        let config = set_path(&config, "max_fee_abs", "1000").unwrap();
+        assert_eq!(config.max_fee_abs, Some(1000));
+        assert_eq!(
+            get_path(&config, "max-fee-abs").unwrap(),
+            serde_json::json!(1000)
+        );
+        let config = set_path(&config, "max-fee-abs", "null").unwrap();
+        assert_eq!(config.max_fee_abs, None);
+        assert!(get_path(&config, "max-fee-abs").is_err());
+    }
+
+    #[test]
+    fn set_rejects_bad_values() {
+        let config = Config::default_config(Network::Regtest);
+        assert!(set_path(&config, "blockchain.stop_gap", "lots").is_err());
+        assert!(set_path(&config, "blockchain.colour", "blue").is_err());
+        assert!(set_path(&config, "nope.stop_gap", "1").is_err());
+        assert!(set_path(&config, "network", "moon").is_err());
+    }
+}
@@ -0,0 +1,120 @@
+use super::{Cell, CmdOutput};
+use crate::{
+    betting::BetRef,
+    contacts::{npub, parse_contact_key, Contact},
+    database::BetContact,
+    elog,
+    wallet::GunWallet,
+};
+use anyhow::anyhow;
+use olivia_secp256k1::fun::{marker::EvenY, Point};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum ContactOpt {
+    /// Add someone you bet with to the address book
+    Add {
+        /// What to call them
+        name: String,
+        /// Their public key as x-only hex (e.g. from `gun escrow key`) or a nostr npub
+        #[structopt(parse(try_from_str = parse_contact_key))]
+        key: Point<EvenY>,
+    },
+    /// List contacts
+    List,
+    /// Remove a contact (and unlink their bets)
+    Remove { name: String },
+    /// Say which contact bets are with so `gun bet list` and `gun bet show` show their name
+    Link {
+        /// The contact's name
+        name: String,
+        /// The bets (ids or fingerprints)
+        #[structopt(required = true)]
+        bets: Vec<BetRef>,
+    },
+}
+
+pub fn run_contact_cmd(wallet: &GunWallet, opt: ContactOpt) -> anyhow::Result<CmdOutput> {
+    let gun_db = wallet.gun_db();
+    match opt {
+        ContactOpt::Add { name, key } => {
+            if gun_db.get_entity::<Contact>(name.clone())?.is_some() {
+                return Err(anyhow!(
+                    "There's already a contact called {}. Remove them first to change their key.",
+                    name
+                ));
+            }
+            if let Some((existing, _)) = gun_db
+                .list_entities_print_error::<Contact>()
+                .find(|(_, contact)| contact.public_key == key)
+            {
+                elog!(@warning "{} has the same key", existing);
+            }
+            gun_db.insert_entity(name, Contact { public_key: key })?;
+            Ok(CmdOutput::None)
+        }
+        ContactOpt::List => {
+            let links = gun_db
+                .list_entities_print_error::<BetContact>()
+                .collect::<Vec<_>>();
+            let rows = gun_db
+                .list_entities_print_error::<Contact>()
+                .map(|(name, contact)| {
+                    let bets = links
+                        .iter()
+                        .filter(|(_, BetContact(linked))| *linked == name)
+                        .map(|(bet_id, _)| Cell::Int((*bet_id).into()))
+                        .collect();
+                    let reputation = gun_db.contact_reputation(&name);
+                    vec![
+                        Cell::string(&name),
+                        Cell::string(contact.public_key),
+                        Cell::string(npub(&contact.public_key)),
+                        Cell::List(bets),
+                        Cell::Int(reputation.completed.into()),
+                        Cell::Int(reputation.canceled.into()),
+                        Cell::Int(reputation.timed_out.into()),
+                        reputation
+                            .score()
+                            .map(|score| Cell::String(format!("{}%", score)))
+                            .unwrap_or(Cell::Empty),
+                    ]
+                })
+                .collect();
+            Ok(CmdOutput::table(
+                vec![
+                    "name",
+                    "key",
+                    "npub",
+                    "bets",
+                    "completed",
+                    "canceled",
+                    "timed-out",
+                    "score",
+                ],
+                rows,
+            ))
+        }
+        ContactOpt::Remove { name } => {
+            if gun_db.remove_entity::<Contact>(name.clone())?.is_none() {
+                return Err(anyhow!("There's no contact called {}", name));
+            }
+            let linked = gun_db
+                .list_entities_print_error::<BetContact>()
+                .filter(|(_, BetContact(linked))| *linked == name)
+                .map(|(bet_id, _)| bet_id)
+                .collect::<Vec<_>>();
+            for bet_id in linked {
+                gun_db.remove_entity::<BetContact>(bet_id)?;
+            }
+            Ok(CmdOutput::None)
+        }
+        ContactOpt::Link { name, bets } => {
+            let bet_ids = gun_db.resolve_bet_refs(&bets)?;
+            for bet_id in bet_ids {
+                gun_db.link_bet_contact(bet_id, &name)?;
+            }
+            Ok(CmdOutput::None)
+        }
+    }
+}
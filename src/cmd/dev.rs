@@ -0,0 +1,324 @@
+use super::{Cell, CmdOutput, CommonArgs, SetupOpt};
+use crate::{
+    amount_ext::FromCliStr,
+    database::{migrations, GunDatabase},
+    elog, item,
+    mock_oracle::{self, MockOracle, MockOracleState},
+};
+use anyhow::{anyhow, Context};
+use bdk::{
+    bitcoin::{Address, Amount, Network, Script},
+    sled,
+    wallet::AddressIndex,
+};
+use olivia_core::{EventId, Outcome};
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+    time::Duration,
+};
+use structopt::StructOpt;
+
+#[derive(Clone, Debug)]
+pub enum SchemaFormat {
+    JsonSchema,
+}
+
+impl FromStr for SchemaFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> anyhow::Result<Self> {
+        match string {
+            "json-schema" => Ok(SchemaFormat::JsonSchema),
+            _ => Err(anyhow!("'{}' is not a supported schema format", string)),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum DevOpt {
+    /// Print schemas for the bet protocol types.
+    ///
+    /// Emits schemas for proposals, offers, the ciphertext envelope and the bet state records in
+    /// the database along with the protocol versions they apply to.
+    Schema {
+        /// The schema format
+        #[structopt(long, default_value = "json-schema", name = "json-schema")]
+        format: SchemaFormat,
+    },
+    /// Migrate the database to the version this gun uses.
+    ///
+    /// This normally happens automatically when the wallet is loaded.
+    Migrate {
+        /// Only list the migrations that would be run
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Run a local regtest network to try out bets end to end.
+    Regtest(RegtestOpt),
+    /// Run an oracle to try out bets locally.
+    ///
+    /// It announces any event with two outcomes that's asked for. Trust it with `gun bet oracle add
+    /// http://localhost:8765` and decide outcomes with `gun dev oracle attest`.
+    Oracle(MockOracleOpt),
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct MockOracleOpt {
+    /// Where to listen e.g. :8765 or 127.0.0.1:8765
+    #[structopt(long, default_value = ":8765", parse(from_str = listen_addr))]
+    listen: String,
+    /// Where the oracle keeps its keys and outcomes (defaults to a directory in the system temp
+    /// directory)
+    #[structopt(long, parse(from_os_str))]
+    dir: Option<PathBuf>,
+    #[structopt(subcommand)]
+    cmd: Option<MockOracleCmd>,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum MockOracleCmd {
+    /// Attest to an event's outcome
+    Attest {
+        /// The event id e.g. /test/red_blue.winner
+        event: EventId,
+        /// The outcome e.g. red
+        outcome: String,
+    },
+}
+
+fn listen_addr(listen: &str) -> String {
+    match listen.strip_prefix(':') {
+        Some(port) => format!("127.0.0.1:{}", port),
+        None => listen.to_string(),
+    }
+}
+
+/// Drives a local regtest bitcoind and esplora with [nigiri].
+///
+/// Nigiri serves esplora on `http://localhost:3000` which is what regtest wallets use by default.
+/// The `nigiri` binary must be in your `$PATH` or you can point `$GUN_NIGIRI` at it.
+///
+/// [nigiri]: https://github.com/vulpemventures/nigiri
+#[derive(StructOpt, Debug, Clone)]
+pub enum RegtestOpt {
+    /// Start the network and set up a throwaway wallet funded from the faucet.
+    ///
+    /// Use the wallet by passing its directory with -d or $GUN_DIR.
+    Start {
+        /// Where to put the wallet (defaults to a new directory in the system temp directory)
+        #[structopt(long, parse(from_os_str))]
+        wallet_dir: Option<PathBuf>,
+        /// How much to fund the wallet with
+        #[structopt(long, default_value = "1BTC", parse(try_from_str = FromCliStr::from_cli_str))]
+        amount: Amount,
+    },
+    /// Stop the network.
+    Stop {
+        /// Throw away the chain as well so the next start begins from scratch
+        #[structopt(long)]
+        delete: bool,
+    },
+    /// Send coins from the faucet (to the wallet if no address is given).
+    Fund {
+        /// The address to fund
+        address: Option<Address>,
+        /// How much to send
+        #[structopt(long, default_value = "1BTC", parse(try_from_str = FromCliStr::from_cli_str))]
+        amount: Amount,
+    },
+    /// Mine blocks.
+    Mine {
+        /// The number of blocks to mine
+        n_blocks: u32,
+        /// Where the block rewards go (they are burned by default)
+        #[structopt(long)]
+        address: Option<Address>,
+    },
+}
+
+pub fn run_dev_cmd(wallet_dir: &std::path::Path, opt: DevOpt) -> anyhow::Result<CmdOutput> {
+    match opt {
+        DevOpt::Schema {
+            format: SchemaFormat::JsonSchema,
+        } => Ok(CmdOutput::Json(crate::schema::all())),
+        DevOpt::Migrate { dry_run } => {
+            if !wallet_dir.exists() {
+                return Err(anyhow!("No wallet found at {}", wallet_dir.display()));
+            }
+            let database = sled::open(wallet_dir.join("database.sled").to_str().unwrap())
+                .context("opening database.sled")?;
+            let gun_db =
+                GunDatabase::new(database.open_tree("gun").context("opening gun db tree")?);
+            let rows = if dry_run {
+                migrations::migrate(gun_db.tree(), true)?
+            } else {
+                let pending = migrations::migrate(gun_db.tree(), true)?;
+                migrations::migrate_on_load(wallet_dir, &gun_db)?;
+                pending
+            }
+            .into_iter()
+            .map(|migration| {
+                vec![
+                    Cell::Int(migration.from.into()),
+                    Cell::Int((migration.from + 1).into()),
+                    Cell::string(migration.description),
+                ]
+            })
+            .collect();
+            Ok(CmdOutput::table(vec!["from", "to", "description"], rows))
+        }
+        DevOpt::Regtest(opt) => run_regtest_cmd(wallet_dir, opt),
+        DevOpt::Oracle(opt) => run_mock_oracle_cmd(opt),
+    }
+}
+
+fn run_mock_oracle_cmd(opt: MockOracleOpt) -> anyhow::Result<CmdOutput> {
+    let dir = opt
+        .dir
+        .unwrap_or_else(|| std::env::temp_dir().join("gun-dev-oracle"));
+    let mut state = MockOracleState::load_or_create(&dir)?;
+    match opt.cmd {
+        None => {
+            elog!(@info "Serving the oracle at http://{} (its state is in {})", opt.listen, dir.display());
+            mock_oracle::serve(&opt.listen, &dir)?;
+            Ok(CmdOutput::None)
+        }
+        Some(MockOracleCmd::Attest { event, outcome }) => {
+            let outcome = Outcome::try_from_id_and_outcome(event.clone(), &outcome)
+                .map_err(|_| anyhow!("{} is not an outcome of {}", outcome, event))?;
+            MockOracle::new(state.seed).oracle_event(&event)?;
+            state
+                .outcomes
+                .insert(event.to_string(), outcome.outcome_string());
+            state.save(&dir)?;
+            elog!(@celebration "{} is attested to {}", event, outcome.outcome_string());
+            Ok(CmdOutput::None)
+        }
+    }
+}
+
+const REGTEST_ESPLORA: &str = "http://localhost:3000";
+
+fn nigiri_binary() -> String {
+    std::env::var("GUN_NIGIRI").unwrap_or_else(|_| "nigiri".into())
+}
+
+fn run_nigiri(args: &[&str]) -> anyhow::Result<String> {
+    let binary = nigiri_binary();
+    let output = Command::new(&binary)
+        .args(args)
+        .output()
+        .with_context(|| format!("running '{}' (is nigiri installed?)", binary))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "'{} {}' failed: {}",
+            binary,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn wait_for_esplora() -> anyhow::Result<u32> {
+    let url = format!("{}/blocks/tip/height", REGTEST_ESPLORA);
+    for _ in 0..60 {
+        if let Ok(height) = ureq::get(&url)
+            .call()
+            .map_err(anyhow::Error::from)
+            .and_then(|response| Ok(response.into_string()?.trim().parse::<u32>()?))
+        {
+            return Ok(height);
+        }
+        std::thread::sleep(Duration::from_secs(1));
+    }
+    Err(anyhow!("esplora didn't come up at {}", REGTEST_ESPLORA))
+}
+
+fn fund(address: &Address, amount: Amount) -> anyhow::Result<String> {
+    let btc = amount.as_btc().to_string();
+    run_nigiri(&["faucet", &address.to_string(), &btc])
+}
+
+fn wallet_address(wallet_dir: &Path) -> anyhow::Result<Address> {
+    let (wallet, _, config) = super::load_wallet(wallet_dir)?;
+    if config.network != Network::Regtest {
+        return Err(anyhow!(
+            "the wallet at {} is a {} wallet not a regtest one",
+            wallet_dir.display(),
+            config.network
+        ));
+    }
+    Ok(wallet.bdk_wallet().get_address(AddressIndex::New)?.address)
+}
+
+pub fn run_regtest_cmd(wallet_dir: &Path, opt: RegtestOpt) -> anyhow::Result<CmdOutput> {
+    match opt {
+        RegtestOpt::Start { wallet_dir, amount } => {
+            run_nigiri(&["start"])?;
+            let height = wait_for_esplora()?;
+            let wallet_dir = wallet_dir.unwrap_or_else(|| {
+                std::env::temp_dir().join(format!("gun-regtest-{:08x}", rand::random::<u32>()))
+            });
+            super::run_setup(
+                &wallet_dir,
+                SetupOpt::Seed {
+                    common_args: CommonArgs::new(Network::Regtest, Some(height)),
+                    from_existing: None,
+                    from_slip39: false,
+                    n_words: 12,
+                    language: None,
+                    use_passphrase: false,
+                    recovery_key: None,
+                    recovery_after: None,
+                },
+            )?;
+            let address = wallet_address(&wallet_dir)?;
+            fund(&address, amount)?;
+            elog!(@suggestion "export GUN_DIR={} to use the wallet", wallet_dir.display());
+            Ok(item! {
+                "wallet-dir" => Cell::string(wallet_dir.display()),
+                "esplora" => Cell::string(REGTEST_ESPLORA),
+                "funded" => Cell::Amount(amount),
+            })
+        }
+        RegtestOpt::Stop { delete } => {
+            if delete {
+                run_nigiri(&["stop", "--delete"])?;
+            } else {
+                run_nigiri(&["stop"])?;
+            }
+            Ok(CmdOutput::None)
+        }
+        RegtestOpt::Fund { address, amount } => {
+            let address = match address {
+                Some(address) => address,
+                None => wallet_address(wallet_dir)?,
+            };
+            fund(&address, amount)?;
+            Ok(item! {
+                "address" => Cell::string(address),
+                "amount" => Cell::Amount(amount),
+            })
+        }
+        RegtestOpt::Mine { n_blocks, address } => {
+            let address = address
+                .unwrap_or_else(|| Address::p2wsh(&Script::new_op_return(&[]), Network::Regtest));
+            let output = run_nigiri(&[
+                "rpc",
+                "generatetoaddress",
+                &n_blocks.to_string(),
+                &address.to_string(),
+            ])?;
+            let block_hashes = serde_json::from_str::<Vec<String>>(&output)
+                .context("parsing block hashes from nigiri")?;
+            Ok(CmdOutput::List(
+                block_hashes.into_iter().map(Cell::string).collect(),
+            ))
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use super::{Cell, CmdOutput};
+use crate::{betting::*, elog, wallet::GunWallet};
+use bdk::{
+    bitcoin::OutPoint, blockchain::Blockchain, database::Database, wallet::AddressIndex,
+    KeychainKind,
+};
+use olivia_core::chrono::{Duration, Utc};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug, Clone)]
+/// Check the wallet and bet databases for problems
+pub struct DoctorOpt {
+    /// Try to fix the problems that can be fixed safely.
+    ///
+    /// This moves bets along through the normal state machine. The only thing it deletes is a
+    /// proposal or offer that still has a coin in use after that even though the coin was spent.
+    #[structopt(long)]
+    repair: bool,
+}
+
+struct Issue {
+    check: &'static str,
+    subject: String,
+    problem: String,
+    /// The bet we can try to progress to fix the problem.
+    repairable: Option<BetId>,
+    /// The coin the bet has in use that isn't ours anymore.
+    orphaned: Option<OutPoint>,
+}
+
+fn check_database_entries(wallet: &GunWallet, issues: &mut Vec<Issue>) {
+    for entry in wallet.gun_db().list_entities::<BetState>() {
+        if let Err(e) = entry {
+            issues.push(Issue {
+                check: "database",
+                subject: "-".into(),
+                problem: format!("unreadable bet entry: {}", e),
+                repairable: None,
+                orphaned: None,
+            });
+        }
+    }
+}
+
+fn check_descriptors(wallet: &GunWallet, issues: &mut Vec<Issue>) -> anyhow::Result<()> {
+    let bdk_wallet = wallet.bdk_wallet();
+    for keychain in [KeychainKind::External, KeychainKind::Internal] {
+        let expected = match keychain {
+            KeychainKind::External => bdk_wallet.get_address(AddressIndex::Peek(0))?,
+            KeychainKind::Internal => bdk_wallet.get_change_address(AddressIndex::Peek(0))?,
+        };
+        let cached = bdk_wallet
+            .database()
+            .get_script_pubkey_from_path(keychain, 0)?;
+        if let Some(cached) = cached {
+            if cached != expected.script_pubkey() {
+                issues.push(Issue {
+                    check: "descriptor",
+                    subject: format!("{:?}", keychain).to_lowercase(),
+                    problem: "wallet cache was derived from a different descriptor -- delete the wallet tree and resync".into(),
+                    repairable: None,
+                    orphaned: None,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_in_use_utxos(wallet: &GunWallet, issues: &mut Vec<Issue>) -> anyhow::Result<()> {
+    let unspent = wallet
+        .bdk_wallet()
+        .list_unspent()?
+        .into_iter()
+        .map(|utxo| utxo.outpoint)
+        .collect::<Vec<OutPoint>>();
+
+    for (bet_id, bet_state) in wallet.gun_db().list_entities_print_error::<BetState>() {
+        // Once the bet tx is in the chain our inputs are supposed to be spent
+        if !matches!(
+            bet_state,
+            BetState::Proposed { .. } | BetState::Offered { .. }
+        ) {
+            continue;
+        }
+        for outpoint in bet_state.reserved_utxos() {
+            if !unspent.contains(&outpoint) {
+                issues.push(Issue {
+                    check: "in-use",
+                    subject: bet_id.to_string(),
+                    problem: format!(
+                        "{} bet reserves {} which is no longer an unspent output of the wallet",
+                        bet_state.name(),
+                        outpoint
+                    ),
+                    repairable: Some(bet_id),
+                    orphaned: Some(outpoint),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_transactions(wallet: &GunWallet, issues: &mut Vec<Issue>) -> anyhow::Result<()> {
+    let blockchain = wallet.blockchain()?;
+    for (bet_id, bet_state) in wallet.gun_db().list_entities_print_error::<BetState>() {
+        let bet = match &bet_state {
+            BetState::Included { bet, .. }
+            | BetState::Won { bet, .. }
+            | BetState::Lost { bet, .. }
+            | BetState::Claimed { bet, .. }
+            | BetState::Refunded { bet, .. } => bet,
+            _ => continue,
+        };
+        let txid = bet.tx().txid();
+        if blockchain.get_tx(&txid)?.is_none() {
+            issues.push(Issue {
+                check: "missing-tx",
+                subject: bet_id.to_string(),
+                problem: format!(
+                    "{} bet's transaction {} can't be found",
+                    bet_state.name(),
+                    txid
+                ),
+                repairable: Some(bet_id),
+                orphaned: None,
+            });
+        }
+
+        if let BetState::Claimed { txid, .. } = &bet_state {
+            if blockchain.get_tx(txid)?.is_none() {
+                issues.push(Issue {
+                    check: "missing-tx",
+                    subject: bet_id.to_string(),
+                    problem: format!("claim transaction {} can't be found", txid),
+                    repairable: Some(bet_id),
+                    orphaned: None,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_stuck(wallet: &GunWallet, issues: &mut Vec<Issue>) {
+    let now = Utc::now().naive_utc();
+    for (bet_id, bet_state) in wallet.gun_db().list_entities_print_error::<BetState>() {
+        let outcome_time = match &bet_state {
+            BetState::Offered { bet, .. } => bet.oracle_event.event.expected_outcome_time,
+            BetState::Included { bet, .. } => bet.oracle_event.event.expected_outcome_time,
+            _ => None,
+        };
+        if let Some(outcome_time) = outcome_time {
+            if outcome_time + Duration::days(1) < now {
+                issues.push(Issue {
+                    check: "stuck",
+                    subject: bet_id.to_string(),
+                    problem: format!(
+                        "still {} but the outcome was expected at {}",
+                        bet_state.name(),
+                        outcome_time
+                    ),
+                    repairable: Some(bet_id),
+                    orphaned: None,
+                });
+            }
+        }
+    }
+}
+
+pub fn run_doctor(wallet: &GunWallet, opt: DoctorOpt) -> anyhow::Result<CmdOutput> {
+    let mut issues = vec![];
+    check_database_entries(wallet, &mut issues);
+    check_descriptors(wallet, &mut issues)?;
+    check_in_use_utxos(wallet, &mut issues)?;
+    check_transactions(wallet, &mut issues)?;
+    check_stuck(wallet, &mut issues);
+
+    let mut repaired = vec![];
+    if opt.repair {
+        for bet_id in issues.iter().filter_map(|issue| issue.repairable) {
+            if repaired.contains(&bet_id) {
+                continue;
+            }
+            match wallet.take_next_action(bet_id, true) {
+                Ok(()) => repaired.push(bet_id),
+                Err(e) => {
+                    elog!(@recoverable_error "Failed to repair bet {}: {}", bet_id, e);
+                }
+            }
+        }
+        // whatever is still holding onto a coin that's gone is stuck so let the coin go
+        for issue in &issues {
+            if let (Some(bet_id), Some(outpoint)) = (issue.repairable, issue.orphaned) {
+                match super::release_reservation(wallet, bet_id, outpoint, false) {
+                    Ok(true) if !repaired.contains(&bet_id) => repaired.push(bet_id),
+                    Ok(true) => {}
+                    Ok(false) => repaired.retain(|repaired| *repaired != bet_id),
+                    Err(e) => {
+                        repaired.retain(|repaired| *repaired != bet_id);
+                        elog!(@recoverable_error "Failed to release {} from bet {}: {}", outpoint, bet_id, e);
+                    }
+                }
+            }
+        }
+    } else if issues.iter().any(|issue| issue.repairable.is_some()) {
+        elog!(@suggestion "Some of these problems may be fixed by running `gun doctor --repair`");
+    }
+
+    if issues.is_empty() {
+        elog!(@celebration "No problems found");
+    }
+
+    let rows = issues
+        .into_iter()
+        .map(|issue| {
+            vec![
+                Cell::string(issue.check),
+                Cell::string(issue.subject),
+                Cell::string(issue.problem),
+                Cell::string(
+                    issue
+                        .repairable
+                        .map(|bet_id| repaired.contains(&bet_id))
+                        .unwrap_or(false),
+                ),
+            ]
+        })
+        .collect();
+
+    Ok(CmdOutput::table(
+        vec!["check", "subject", "problem", "repaired"],
+        rows,
+    ))
+}
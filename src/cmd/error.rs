@@ -0,0 +1,167 @@
+use bdk::signer::SignerError;
+use std::fmt;
+
+/// The broad reason a command failed. Each has its own exit code so scripts can react without
+/// parsing error messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Anything we don't know how to classify
+    Other,
+    /// The command or its arguments don't make sense
+    User,
+    /// We couldn't talk to the esplora server or some other service
+    Network,
+    /// The oracle gave us something we couldn't use
+    Oracle,
+    /// A signer was canceled
+    SignerCanceled,
+    /// The wallet doesn't have enough coins
+    InsufficientFunds,
+}
+
+impl ErrorKind {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorKind::Other => 1,
+            ErrorKind::User => 2,
+            ErrorKind::Network => 3,
+            ErrorKind::Oracle => 4,
+            ErrorKind::SignerCanceled => 5,
+            ErrorKind::InsufficientFunds => 6,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::Other => "other",
+            ErrorKind::User => "user",
+            ErrorKind::Network => "network",
+            ErrorKind::Oracle => "oracle",
+            ErrorKind::SignerCanceled => "signer-canceled",
+            ErrorKind::InsufficientFunds => "insufficient-funds",
+        }
+    }
+
+    /// Work out what kind of error it is from what's in its chain.
+    pub fn of(error: &anyhow::Error) -> ErrorKind {
+        for cause in error.chain() {
+            if let Some(kinded) = cause.downcast_ref::<KindedError>() {
+                return kinded.kind;
+            }
+            if cause.downcast_ref::<ureq::Error>().is_some()
+                || cause.downcast_ref::<reqwest::Error>().is_some()
+            {
+                return ErrorKind::Network;
+            }
+            if let Some(SignerError::UserCanceled) = cause.downcast_ref::<SignerError>() {
+                return ErrorKind::SignerCanceled;
+            }
+            if let Some(bdk_error) = cause.downcast_ref::<bdk::Error>() {
+                match bdk_error {
+                    bdk::Error::InsufficientFunds { .. } => return ErrorKind::InsufficientFunds,
+                    bdk::Error::Signer(SignerError::UserCanceled) => {
+                        return ErrorKind::SignerCanceled
+                    }
+                    bdk::Error::Esplora(_) => return ErrorKind::Network,
+                    _ => {}
+                }
+            }
+        }
+        ErrorKind::Other
+    }
+}
+
+/// Marks an error as being of a particular kind without changing how it's displayed.
+pub struct KindedError {
+    kind: ErrorKind,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for KindedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl fmt::Debug for KindedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.error, f)
+    }
+}
+
+impl std::error::Error for KindedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+pub trait WithErrorKind<T> {
+    fn error_kind(self, kind: ErrorKind) -> anyhow::Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> WithErrorKind<T> for Result<T, E> {
+    fn error_kind(self, kind: ErrorKind) -> anyhow::Result<T> {
+        self.map_err(|error| {
+            KindedError {
+                kind,
+                error: error.into(),
+            }
+            .into()
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for ErrorFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> anyhow::Result<Self> {
+        match string {
+            "text" => Ok(ErrorFormat::Text),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(anyhow::anyhow!(
+                "'{}' isn't an error format (try text or json)",
+                string
+            )),
+        }
+    }
+}
+
+/// The error as JSON for `--error-format json`.
+pub fn error_json(error: &anyhow::Error) -> serde_json::Value {
+    let kind = ErrorKind::of(error);
+    serde_json::json!({
+        "error": format!("{}", error),
+        "kind": kind.name(),
+        "exit-code": kind.exit_code(),
+        "causes": error.chain().skip(1).map(|cause| cause.to_string()).collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn kind_survives_context() {
+        let error = Err::<(), _>(anyhow!("oracle said no"))
+            .error_kind(ErrorKind::Oracle)
+            .context("getting event")
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&error), ErrorKind::Oracle);
+        assert_eq!(format!("{}", error), "getting event");
+        assert_eq!(ErrorKind::of(&anyhow!("whatever")), ErrorKind::Other);
+    }
+
+    #[test]
+    fn kind_from_bdk_error() {
+        let error = anyhow::Error::from(bdk::Error::Signer(SignerError::UserCanceled));
+        assert_eq!(ErrorKind::of(&error), ErrorKind::SignerCanceled);
+    }
+}
@@ -0,0 +1,208 @@
+use super::Cell;
+use crate::{
+    cmd::{self, CmdOutput, WithErrorKind},
+    config::Config,
+    confirm::Operation,
+    elog,
+    escrow::{parse_public_key, Escrow, EscrowId, EscrowState},
+    item,
+    keychain::Keychain,
+    script_kind,
+    wallet::GunWallet,
+};
+use anyhow::anyhow;
+use bdk::bitcoin::Address;
+use olivia_secp256k1::fun::{marker::*, Point};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum EscrowOpt {
+    /// Show the public key to give to the other party of an escrow
+    Key,
+    /// Make a new 2-of-3 escrow address between you, the other party and an arbiter
+    ///
+    /// The other party runs the same command with your key to get the same address.
+    Open {
+        /// The other party's escrow key (from `gun escrow key`)
+        #[structopt(long)]
+        with: Point<EvenY>,
+        /// The arbiter's public key
+        #[structopt(long)]
+        arbiter: String,
+        /// Use a different number to make another escrow with the same people
+        #[structopt(long, default_value = "0")]
+        nonce: u32,
+    },
+    /// List escrows
+    List,
+    /// Show the details of an escrow
+    Show {
+        /// The escrow's id
+        id: EscrowId,
+    },
+    /// Make a transaction releasing the escrow's coins and sign it
+    ///
+    /// The PSBT needs a signature from the other party or the arbiter before it can be broadcast.
+    Release {
+        /// The escrow's id
+        id: EscrowId,
+        /// Where to send the coins
+        #[structopt(long)]
+        to: Address,
+        #[structopt(flatten)]
+        fee_args: cmd::FeeArgs,
+    },
+    /// Add your signature to a PSBT releasing the escrow and broadcast it if it's complete
+    Sign {
+        /// The escrow's id
+        id: EscrowId,
+        /// The PSBT (base64 or a file containing it)
+        psbt: String,
+        /// Don't prompt for answers just say yes
+        #[structopt(short, long)]
+        yes: bool,
+        /// Print the transaction hex but don't broadcast it
+        #[structopt(long)]
+        print_tx: bool,
+    },
+}
+
+pub fn run_escrow_cmd(
+    wallet: &GunWallet,
+    keychain: &Keychain,
+    config: &Config,
+    cmd: EscrowOpt,
+) -> anyhow::Result<CmdOutput> {
+    let esplora_url = &config.blockchain_config().base_url;
+    match cmd {
+        EscrowOpt::Key => Ok(CmdOutput::EmphasisedItem {
+            main: (
+                "public-key",
+                Cell::string(keychain.escrow_keypair().public_key),
+            ),
+            other: vec![],
+        }),
+        EscrowOpt::Open {
+            with,
+            arbiter,
+            nonce,
+        } => {
+            let arbiter = parse_public_key(&arbiter).error_kind(cmd::ErrorKind::User)?;
+            let escrow = Escrow::new(keychain.escrow_keypair(), &with, arbiter, nonce);
+            let address = escrow.address(wallet.bdk_wallet().network());
+            let id = wallet.gun_db().insert_escrow(escrow)?;
+            elog!(@suggestion "Check the other party got the same address before funding it with `gun send`");
+            Ok(item! {
+                "id" => Cell::Int(id.into()),
+                "address" => Cell::string(address),
+            })
+        }
+        EscrowOpt::List => {
+            let network = wallet.bdk_wallet().network();
+            let mut rows = vec![];
+            for (id, _) in wallet.gun_db().list_entities_print_error::<Escrow>() {
+                if let Err(e) = wallet.poke_escrow(id, esplora_url) {
+                    elog!(@recoverable_error "Error checking escrow {}: {}", id, e);
+                }
+            }
+            for (id, escrow) in wallet.gun_db().list_entities_print_error::<Escrow>() {
+                rows.push(vec![
+                    Cell::Int(id.into()),
+                    Cell::string(escrow.state.name()),
+                    Cell::string(escrow.address(network)),
+                    state_value(&escrow.state),
+                ]);
+            }
+            Ok(CmdOutput::table(
+                vec!["id", "state", "address", "value"],
+                rows,
+            ))
+        }
+        EscrowOpt::Show { id } => {
+            wallet.poke_escrow(id, esplora_url)?;
+            let escrow = wallet
+                .gun_db()
+                .get_entity::<Escrow>(id)?
+                .ok_or(anyhow!("Escrow {} doesn't exist", id))?;
+            let (outpoint, txid, height) = match escrow.state {
+                EscrowState::Open => (Cell::Empty, Cell::Empty, Cell::Empty),
+                EscrowState::Funded { outpoint, .. } => {
+                    (Cell::string(outpoint), Cell::Empty, Cell::Empty)
+                }
+                EscrowState::Released {
+                    outpoint,
+                    txid,
+                    height,
+                    ..
+                } => (
+                    Cell::string(outpoint),
+                    Cell::string(txid),
+                    height.map(|h| Cell::Int(h.into())).unwrap_or(Cell::Empty),
+                ),
+            };
+            Ok(item! {
+                "id" => Cell::Int(id.into()),
+                "state" => Cell::string(escrow.state.name()),
+                "address" => Cell::string(escrow.address(wallet.bdk_wallet().network())),
+                "value" => state_value(&escrow.state),
+                "my-key" => Cell::string(escrow.keys[0]),
+                "their-key" => Cell::string(escrow.keys[1]),
+                "arbiter-key" => Cell::string(escrow.keys[2]),
+                "nonce" => Cell::Int(escrow.nonce.into()),
+                "outpoint" => outpoint,
+                "release-txid" => txid,
+                "release-height" => height,
+            })
+        }
+        EscrowOpt::Release { id, to, fee_args } => {
+            let network = wallet.bdk_wallet().network();
+            script_kind::check_address(&to, network).error_kind(cmd::ErrorKind::User)?;
+            wallet.poke_escrow(id, esplora_url)?;
+            let psbt = wallet.release_escrow(id, to.script_pubkey(), fee_args.fee)?;
+            elog!(@suggestion "Send this to the other party or the arbiter so they can sign and broadcast it with `gun escrow sign <their escrow id> <psbt>`");
+            Ok(CmdOutput::EmphasisedItem {
+                main: ("psbt", Cell::string(psbt)),
+                other: vec![],
+            })
+        }
+        EscrowOpt::Sign {
+            id,
+            psbt,
+            yes,
+            print_tx,
+        } => {
+            wallet.poke_escrow(id, esplora_url)?;
+            let mut psbt = cmd::load_psbt(&psbt)?;
+            if !wallet.sign_escrow(id, &mut psbt)? {
+                elog!(@info "The transaction still needs another signature");
+                return Ok(CmdOutput::EmphasisedItem {
+                    main: ("psbt", Cell::string(psbt)),
+                    other: vec![],
+                });
+            }
+            let (output, txid) = cmd::decide_to_broadcast(
+                config,
+                wallet.blockchain()?,
+                psbt,
+                Operation::Broadcast,
+                yes,
+                print_tx,
+            )?;
+            if txid.is_some() {
+                if let Err(e) = wallet.poke_escrow(id, esplora_url) {
+                    elog!(@recoverable_error "Error updating escrow {} after broadcasting: {}", id, e);
+                }
+            }
+            Ok(output)
+        }
+    }
+}
+
+fn state_value(state: &EscrowState) -> Cell {
+    match state {
+        EscrowState::Open => Cell::Empty,
+        EscrowState::Funded { value, .. } | EscrowState::Released { value, .. } => {
+            Cell::Amount(*value)
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use super::{Cell, CmdOutput};
+use crate::{
+    betting::{add_bet_output, Bet, BetId, BetState},
+    cmd, elog, script_kind,
+    wallet::GunWallet,
+};
+use anyhow::{anyhow, Context};
+use bdk::{
+    bitcoin::{util::psbt::PartiallySignedTransaction as Psbt, Address, OutPoint, Script},
+    database::MemoryDatabase,
+    wallet::AddressIndex,
+    Wallet,
+};
+use std::{path::PathBuf, str::FromStr};
+use structopt::StructOpt;
+
+/// Each transaction can have at most this many inputs by default. A transaction spending this
+/// many p2wpkh inputs is about 35,000 vbytes which is well under the 100,000 vbytes standardness
+/// limit.
+const DEFAULT_MAX_INPUTS: usize = 500;
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct EvacuateOpt {
+    /// The address or descriptor to send everything to. Each transaction pays to the next
+    /// address of a descriptor.
+    destination: String,
+    #[structopt(flatten)]
+    fee_args: cmd::FeeArgs,
+    /// The most inputs to put in one transaction. More coins than this are split across several
+    /// transactions.
+    #[structopt(long, default_value = "500")]
+    max_inputs: usize,
+    /// Write each PSBT to <dir>/evacuate-<n>.psbt instead of printing them.
+    #[structopt(long, parse(from_os_str))]
+    psbt_dir: Option<PathBuf>,
+}
+
+enum Destination {
+    Address(Address),
+    Descriptor(Wallet<(), MemoryDatabase>),
+}
+
+impl Destination {
+    fn parse(wallet: &GunWallet, destination: &str) -> anyhow::Result<Self> {
+        let network = wallet.bdk_wallet().network();
+        if let Ok(address) = Address::from_str(destination) {
+            script_kind::check_address(&address, network)?;
+            return Ok(Destination::Address(address));
+        }
+        let descriptor_wallet =
+            Wallet::new_offline(destination, None, network, MemoryDatabase::default())
+                .context("the destination isn't an address or a descriptor")?;
+        Ok(Destination::Descriptor(descriptor_wallet))
+    }
+
+    fn script_pubkey(&self, index: u32) -> anyhow::Result<Script> {
+        Ok(match self {
+            Destination::Address(address) => address.script_pubkey(),
+            Destination::Descriptor(wallet) => wallet
+                .get_address(AddressIndex::Peek(index))?
+                .address
+                .script_pubkey(),
+        })
+    }
+}
+
+enum Coin {
+    Utxo(OutPoint),
+    WonBet(BetId, Box<Bet>, bdk::bitcoin::secp256k1::SecretKey),
+}
+
+pub fn run_evacuate(wallet: &GunWallet, opt: EvacuateOpt) -> anyhow::Result<CmdOutput> {
+    let EvacuateOpt {
+        destination,
+        fee_args,
+        max_inputs,
+        psbt_dir,
+    } = opt;
+    if max_inputs == 0 {
+        return Err(anyhow!("--max-inputs must be at least 1"));
+    }
+    if max_inputs > DEFAULT_MAX_INPUTS {
+        elog!(@warning "transactions with more than {} inputs might be too big to relay", DEFAULT_MAX_INPUTS);
+    }
+    let destination = Destination::parse(wallet, &destination)?;
+    let bdk_wallet = wallet.bdk_wallet();
+
+    let coins = bdk_wallet
+        .list_unspent()?
+        .into_iter()
+        .map(|utxo| Coin::Utxo(utxo.outpoint))
+        .chain(
+            wallet
+                .gun_db()
+                .list_entities_print_error::<BetState>()
+                .filter_map(|(bet_id, bet_state)| match bet_state {
+                    BetState::Won {
+                        bet, secret_key, ..
+                    } => Some(Coin::WonBet(bet_id, Box::new(bet), secret_key)),
+                    _ => None,
+                }),
+        )
+        .collect::<Vec<_>>();
+
+    if coins.is_empty() {
+        return Err(anyhow!("there's nothing to evacuate"));
+    }
+
+    let mut psbts: Vec<Psbt> = vec![];
+    for (i, chunk) in coins.chunks(max_inputs).enumerate() {
+        let mut builder = bdk_wallet.build_tx();
+        builder
+            .manually_selected_only()
+            .enable_rbf()
+            .drain_to(destination.script_pubkey(i as u32)?);
+        fee_args.fee.apply_to_builder(wallet, &mut builder)?;
+        let tx_locktime = wallet.apply_locktime_policy(&mut builder)?;
+
+        for coin in chunk {
+            match coin {
+                Coin::Utxo(outpoint) => {
+                    builder.add_utxo(*outpoint)?;
+                }
+                Coin::WonBet(_, bet, _) => add_bet_output(&mut builder, bet),
+            }
+        }
+
+        let (mut psbt, _) = builder.finish()?;
+        fee_args.fee.check_psbt(&psbt)?;
+        wallet.record_locktime(&psbt, tx_locktime)?;
+
+        // the offline signer doesn't have the keys to won bets so we sign for them here
+        for coin in chunk {
+            if let Coin::WonBet(bet_id, bet, secret_key) = coin {
+                wallet
+                    .sign_bet_output(bet, *secret_key, &mut psbt)
+                    .with_context(|| format!("signing the output of bet {}", bet_id))?;
+            }
+        }
+        psbts.push(psbt);
+    }
+
+    if psbts.len() > 1 {
+        elog!(@info "evacuating {} coins takes {} transactions", coins.len(), psbts.len());
+    }
+
+    match psbt_dir {
+        Some(psbt_dir) => {
+            let rows = psbts
+                .iter()
+                .enumerate()
+                .map(|(i, psbt)| {
+                    let path = psbt_dir.join(format!("evacuate-{}.psbt", i));
+                    std::fs::write(&path, psbt.to_string())
+                        .with_context(|| format!("writing PSBT to {}", path.display()))?;
+                    Ok(vec![
+                        Cell::string(psbt.unsigned_tx.txid()),
+                        Cell::Int(psbt.unsigned_tx.input.len() as u64),
+                        Cell::string(path.display()),
+                    ])
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Ok(CmdOutput::table(vec!["txid", "inputs", "path"], rows))
+        }
+        None => Ok(CmdOutput::List(
+            psbts.into_iter().map(Cell::string).collect(),
+        )),
+    }
+}
@@ -0,0 +1,151 @@
+use super::{Cell, CmdOutput};
+use crate::{config::Config, item, utxo_snapshot::UtxoSnapshot, wallet::GunWallet};
+use anyhow::{anyhow, Context};
+use bdk::{descriptor::get_checksum, KeychainKind};
+use std::{path::PathBuf, str::FromStr};
+use structopt::StructOpt;
+use url::Url;
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum ExportOpt {
+    /// Export the wallet's public descriptors (with checksums) so another wallet can watch it
+    Descriptors {
+        /// Format the descriptors for importing into other software: "core" (an
+        /// `importdescriptors` request), "sparrow" (Specter/Sparrow wallet JSON) or "bundle" (the
+        /// descriptors and birthday height as JSON).
+        #[structopt(long = "for")]
+        format: Option<ExportFormat>,
+        /// The number of addresses Bitcoin Core should derive
+        #[structopt(long, default_value = "1000")]
+        range: u32,
+    },
+    /// Write the wallet's coins to a file so an offline copy of the wallet can make proposals
+    /// with `gun bet propose --utxo-snapshot`. Sync first.
+    UtxoSnapshot {
+        /// Where to write the snapshot
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// Include the oracle's announcement for an event you're going to propose a bet on
+        /// (can be given more than once)
+        #[structopt(long)]
+        event: Vec<Url>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Core,
+    Sparrow,
+    Bundle,
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> anyhow::Result<Self> {
+        Ok(match string {
+            "core" => ExportFormat::Core,
+            "sparrow" => ExportFormat::Sparrow,
+            "bundle" => ExportFormat::Bundle,
+            _ => {
+                return Err(anyhow!(
+                    "'{}' is not one of core, sparrow or bundle",
+                    string
+                ))
+            }
+        })
+    }
+}
+
+/// The descriptor with its checksum
+fn descriptor_with_checksum(wallet: &GunWallet, keychain: KeychainKind) -> anyhow::Result<String> {
+    let descriptor = wallet
+        .bdk_wallet()
+        .get_descriptor_for_keychain(keychain)
+        .to_string();
+    let descriptor = descriptor.split('#').next().unwrap().to_string();
+    let checksum = get_checksum(&descriptor)?;
+    Ok(format!("{}#{}", descriptor, checksum))
+}
+
+/// The earliest confirmed transaction in the wallet. Other software only needs to scan from here
+/// if we don't know the wallet's birthday.
+fn birthday(wallet: &GunWallet) -> anyhow::Result<Option<(u32, u64)>> {
+    Ok(wallet
+        .bdk_wallet()
+        .list_transactions(false)?
+        .into_iter()
+        .filter_map(|tx| tx.confirmation_time)
+        .map(|time| (time.height, time.timestamp))
+        .min())
+}
+
+pub fn run_export_cmd(
+    wallet: &GunWallet,
+    config: &Config,
+    opt: ExportOpt,
+) -> anyhow::Result<CmdOutput> {
+    match opt {
+        ExportOpt::Descriptors { format, range } => {
+            let external = descriptor_with_checksum(wallet, KeychainKind::External)?;
+            let internal = descriptor_with_checksum(wallet, KeychainKind::Internal)?;
+            let birthday = birthday(wallet)?;
+            let birthday_height = config
+                .birthday_height
+                .or(birthday.map(|(height, _)| height));
+            let network = wallet.bdk_wallet().network();
+
+            Ok(match format {
+                None => item! {
+                    "external" => Cell::String(external),
+                    "internal" => Cell::String(internal),
+                    "birthday-height" => Cell::maybe_string(birthday_height),
+                },
+                Some(ExportFormat::Core) => {
+                    // 0 means scan everything which is what we want if we don't know
+                    let timestamp = birthday.map(|(_, timestamp)| timestamp).unwrap_or(0);
+                    CmdOutput::Json(serde_json::json!([
+                        {
+                            "desc": external,
+                            "timestamp": timestamp,
+                            "active": true,
+                            "internal": false,
+                            "range": [0, range],
+                        },
+                        {
+                            "desc": internal,
+                            "timestamp": timestamp,
+                            "active": true,
+                            "internal": true,
+                            "range": [0, range],
+                        }
+                    ]))
+                }
+                Some(ExportFormat::Sparrow) => CmdOutput::Json(serde_json::json!({
+                    "label": "gun",
+                    "blockheight": birthday_height.unwrap_or(0),
+                    "descriptor": external,
+                    "change_descriptor": internal,
+                })),
+                Some(ExportFormat::Bundle) => CmdOutput::Json(serde_json::json!({
+                    "network": network.to_string(),
+                    "external": external,
+                    "internal": internal,
+                    "birthday_height": birthday_height,
+                    "signet_challenge": config.signet_challenge.as_ref().map(|challenge| crate::hex::encode(challenge.as_bytes())),
+                })),
+            })
+        }
+        ExportOpt::UtxoSnapshot { file, event } => {
+            let event_urls = event.iter().map(Url::to_string).collect::<Vec<_>>();
+            let snapshot = UtxoSnapshot::take(wallet, &event_urls)?;
+            std::fs::write(&file, serde_json::to_string_pretty(&snapshot)?)
+                .with_context(|| format!("writing utxo snapshot to {}", file.display()))?;
+            Ok(item! {
+                "path" => Cell::string(file.display()),
+                "utxos" => Cell::Int(snapshot.utxos.len() as u64),
+                "height" => Cell::Int(snapshot.height as u64),
+            })
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use super::{Cell, CmdOutput};
+use crate::{
+    betting::BetState,
+    bip85::get_bip85_bytes,
+    cmd,
+    config::Config,
+    database::ProtocolKind,
+    elog, item,
+    keychain::{Keychain, ProtocolSecret, PROTOCOL_SECRET_BIP85_INDEX},
+    wallet::GunWallet,
+};
+use anyhow::{anyhow, Context};
+use bdk::bitcoin::secp256k1::Secp256k1;
+use rand::RngCore;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum KeychainOpt {
+    /// Start using a new protocol secret for new bets.
+    ///
+    /// Use this if you think your protocol secret has leaked. The old one is kept in the database
+    /// so the bets using it can carry on as normal.
+    Rotate {
+        /// Generate the new secret randomly rather than deriving it from the seed words with
+        /// BIP85. It can't be recovered from the seed words so you'll need a new backup.
+        #[structopt(long)]
+        random: bool,
+    },
+    /// List the wallet's protocol secrets and how many proposals are waiting on each
+    List,
+    /// Move the current protocol secret out of the database into a file.
+    ///
+    /// Put the file on a different device from the wallet's keys (e.g. a USB stick) so someone who
+    /// gets the wallet can't take part in your bets and someone who gets the protocol secret can't
+    /// spend your coins. Betting only works while the file can be read. The secrets `gun keychain
+    /// rotate` has retired stay in the database.
+    MoveSecret {
+        /// The file to write the protocol secret to. It must not exist yet.
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+}
+
+/// The proposals waiting for offers that were made with each protocol secret.
+fn proposals_by_secret(wallet: &GunWallet) -> std::collections::BTreeMap<u32, usize> {
+    let mut counts = std::collections::BTreeMap::new();
+    for (_, bet_state) in wallet.gun_db().list_entities_print_error::<BetState>() {
+        if bet_state.relies_on_protocol_secret() {
+            *counts.entry(bet_state.key_origin().secret).or_default() += 1;
+        }
+    }
+    counts
+}
+
+pub fn run_keychain_cmd(
+    wallet_dir: &Path,
+    wallet: &GunWallet,
+    keychain: &Keychain,
+    config: &Config,
+    opt: KeychainOpt,
+) -> anyhow::Result<CmdOutput> {
+    match opt {
+        KeychainOpt::Rotate { random } => {
+            if let Some(path) = &config.protocol_secret_file {
+                return Err(anyhow!(
+                    "The protocol secret is kept in {} so it can't be rotated",
+                    path.display()
+                ));
+            }
+            let index = keychain.origin().secret + 1;
+            let waiting = proposals_by_secret(wallet).values().sum::<usize>();
+            if !cmd::read_yn(&format!(
+                "New bets will use a new protocol secret. The {} proposal(s) waiting for offers will keep using the old one. Continue",
+                waiting
+            )) {
+                return Ok(CmdOutput::None);
+            }
+
+            let (secret, derived_with) = if random {
+                let mut bytes = [0u8; 64];
+                rand::thread_rng().fill_bytes(&mut bytes);
+                (bytes, "random".to_string())
+            } else {
+                let xpriv = cmd::load_master_xpriv(wallet_dir)
+                    .context("use --random to rotate to a random protocol secret instead")?;
+                let bip85_index = PROTOCOL_SECRET_BIP85_INDEX + index;
+                (
+                    get_bip85_bytes(xpriv, bip85_index, &Secp256k1::signing_only())?,
+                    format!("bip85 index {}", bip85_index),
+                )
+            };
+
+            let index = wallet
+                .gun_db()
+                .rotate_bet_protocol_secret(ProtocolSecret::Bytes(secret))?;
+            if random {
+                elog!(@warning "The new protocol secret can't be recovered from your seed words. Make a new backup with `gun backup`.");
+            }
+            elog!(@celebration "New bets will use protocol secret {}", index);
+            Ok(item! {
+                "index" => Cell::Int(index.into()),
+                "derived_with" => Cell::string(derived_with),
+            })
+        }
+        KeychainOpt::MoveSecret { file } => {
+            if let Some(path) = &config.protocol_secret_file {
+                return Err(anyhow!(
+                    "The protocol secret is already kept in {}",
+                    path.display()
+                ));
+            }
+            let gun_db = wallet.gun_db();
+            let secret = gun_db
+                .get_entity::<ProtocolSecret>(ProtocolKind::Bet)?
+                .ok_or(anyhow!("The wallet doesn't have a protocol secret to move"))?;
+            if !cmd::read_yn(&format!(
+                "The protocol secret will be written to {} and removed from the wallet's database. Continue",
+                file.display()
+            )) {
+                return Ok(CmdOutput::None);
+            }
+            secret
+                .write_to_file(&file)
+                .with_context(|| format!("writing the protocol secret to {}", file.display()))?;
+            if ProtocolSecret::from_file(&file).ok().as_ref() != Some(&secret) {
+                return Err(anyhow!(
+                    "The protocol secret read back from {} isn't the one written so it was left in the database",
+                    file.display()
+                ));
+            }
+            let config_path = wallet_dir.join("config.json");
+            cmd::write_config(
+                &config_path,
+                Config {
+                    protocol_secret_file: Some(file.clone()),
+                    ..config.clone()
+                },
+            )?;
+            gun_db.remove_entity::<ProtocolSecret>(ProtocolKind::Bet)?;
+            elog!(@celebration "The protocol secret is now kept in {}", file.display());
+            elog!(@warning "`gun backup` doesn't include it anymore so back it up separately.");
+            Ok(CmdOutput::None)
+        }
+        KeychainOpt::List => {
+            let waiting = proposals_by_secret(wallet);
+            let current = keychain.origin().secret;
+            let rows = keychain
+                .secret_indexes()
+                .map(|index| {
+                    vec![
+                        Cell::Int(index.into()),
+                        Cell::string(index == current),
+                        Cell::Int(waiting.get(&index).copied().unwrap_or(0) as u64),
+                    ]
+                })
+                .collect();
+            Ok(CmdOutput::table(
+                vec!["index", "current", "waiting-proposals"],
+                rows,
+            ))
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use super::{Cell, CmdOutput};
+use crate::{elog, item, message, wallet::GunWallet};
+use anyhow::anyhow;
+use bdk::{bitcoin::Address, SignOptions};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum MessageOpt {
+    /// Sign a message with one of the wallet's addresses to prove you own it (BIP-322).
+    Sign {
+        /// The address to sign with
+        #[structopt(long)]
+        address: Address,
+        /// The message to sign
+        message: String,
+    },
+    /// Verify a BIP-322 signed message.
+    Verify {
+        /// The address that signed the message
+        #[structopt(long)]
+        address: Address,
+        /// The base64 encoded signature
+        #[structopt(long)]
+        signature: String,
+        /// The message that was signed
+        message: String,
+    },
+}
+
+pub fn run_message_cmd(wallet: Option<&GunWallet>, opt: MessageOpt) -> anyhow::Result<CmdOutput> {
+    match opt {
+        MessageOpt::Sign { address, message } => {
+            let wallet = wallet.expect("signing messages needs the wallet");
+            let bdk_wallet = wallet.bdk_wallet();
+            if !crate::script_kind::is_for_network(&address, bdk_wallet.network()) {
+                return Err(anyhow!(
+                    "{} is a {} address but the wallet is on {}",
+                    address,
+                    address.network,
+                    bdk_wallet.network()
+                ));
+            }
+            if !bdk_wallet.is_mine(&address.script_pubkey())? {
+                return Err(anyhow!("{} doesn't belong to this wallet", address));
+            }
+
+            let mut psbt = message::to_sign_psbt(&address, message.as_bytes())?;
+            elog!(@info "You'll be asked to sign a zero value transaction that can never be broadcast. This is how the message gets signed.");
+            let finalized = bdk_wallet.sign(
+                &mut psbt,
+                SignOptions {
+                    trust_witness_utxo: true,
+                    ..Default::default()
+                },
+            )?;
+            if !finalized {
+                return Err(anyhow!("the wallet's signers didn't sign the message"));
+            }
+
+            Ok(item! {
+                "address" => Cell::string(address),
+                "signature" => Cell::string(message::simple_signature(&psbt)?),
+            })
+        }
+        MessageOpt::Verify {
+            address,
+            signature,
+            message,
+        } => {
+            message::verify_simple(&address, message.as_bytes(), &signature)?;
+            elog!(@celebration "The message was signed by {}", address);
+            Ok(CmdOutput::None)
+        }
+    }
+}
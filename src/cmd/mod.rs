@@ -1,20 +1,75 @@
+mod audit;
+mod backup;
 mod bet;
+mod bip85;
+mod clipboard;
+mod completions;
 mod config;
+mod contact;
+mod dev;
+mod doctor;
+mod error;
+mod escrow;
+mod evacuate;
+mod export;
+mod keychain;
+mod message;
 mod oracle;
+mod privacy;
+mod psbt;
+mod recover;
+mod rescan;
+mod risk;
+mod schedule;
+mod selftest;
 mod setup;
+mod signer;
+mod silent_payment;
+mod verify_backup;
 mod wallet;
+pub use audit::*;
+pub use backup::*;
 pub use bet::*;
+pub use bip85::*;
+pub use clipboard::*;
+pub use completions::*;
 pub use config::*;
+pub use contact::*;
+pub use dev::*;
+pub use doctor::*;
+pub use error::*;
+pub use escrow::*;
+pub use evacuate::*;
+pub use export::*;
+pub use keychain::*;
+pub use message::*;
 pub use oracle::*;
+pub use privacy::*;
+pub use psbt::*;
+pub use recover::*;
+pub use rescan::*;
+pub use risk::*;
+pub use schedule::*;
+pub use selftest::*;
 pub use setup::*;
+pub use signer::*;
+pub use silent_payment::*;
+pub use verify_backup::*;
 pub use wallet::*;
 
 use crate::{
+    betting::BetState,
     config::GunSigner,
-    database::{ProtocolKind, StringDescriptor},
+    confirm::{self, Operation},
+    database::StringDescriptor,
     elog,
-    keychain::ProtocolSecret,
-    signers::{PsbtDirSigner, PwSeedSigner, XKeySigner},
+    esplora::LazyEsplora,
+    interaction::Interaction,
+    secret::Secret,
+    signers::{
+        HwiSigner, MasterKeySource, OptionalSigner, PsbtDirSigner, PwSeedSigner, QrSigner,
+        XKeySigner,
+    },
     wallet::GunWallet,
 };
 use anyhow::Context;
@@ -26,8 +81,9 @@ use bdk::{
         },
         Address, Amount, Network, SignedAmount, Txid,
     },
-    blockchain::{ConfigurableBlockchain, EsploraBlockchain},
+    blockchain::Progress,
     database::BatchDatabase,
+    keys::bip39::Mnemonic,
     signer::Signer,
     sled,
     wallet::signer::SignerOrdering,
@@ -41,7 +97,7 @@ use crate::{
     chrono::NaiveDateTime,
     config::{Config, VersionedConfig},
     database::GunDatabase,
-    keychain::Keychain,
+    keychain::{Keychain, ProtocolSecret},
     psbt_ext::PsbtFeeRate,
     FeeSpec, ValueChoice,
 };
@@ -51,7 +107,8 @@ use std::{collections::HashMap, fs};
 #[derive(Clone, Debug, structopt::StructOpt)]
 pub struct FeeArgs {
     /// The transaction fee to attach e.g. rate:4.5 (4.5 sats-per-byte), abs:300 (300 sats absolute
-    /// fee), in-blocks:3 (set fee so that it is included in the next three blocks).
+    /// fee), in-blocks:3 (set fee so that it is included in the next three blocks). Add
+    /// ",max:<amount>" to refuse to pay more than that e.g. rate:4.5,max:20000sat.
     #[structopt(default_value, long)]
     fee: FeeSpec,
 }
@@ -89,22 +146,7 @@ pub fn write_config(config_file: &std::path::Path, config: Config) -> anyhow::Re
 }
 
 pub fn read_yn(question: &str) -> bool {
-    use std::io::{self, BufRead};
-    let stdin = io::stdin();
-    let mut lines = stdin.lock().lines();
-    elog!(@question "{} [y/n]? ", question.replace('\n', "\n> "));
-    lines
-        .find_map(
-            |line| match line.unwrap().trim_end().to_lowercase().as_str() {
-                "y" => Some(true),
-                "n" => Some(false),
-                _ => {
-                    eprint!("> [y/n]? ");
-                    None
-                }
-            },
-        )
-        .unwrap_or(false)
+    crate::interaction::current().confirm(question)
 }
 
 pub fn read_input<V>(
@@ -112,30 +154,78 @@ pub fn read_input<V>(
     possible: &str,
     validator: impl Fn(&str) -> anyhow::Result<V>,
 ) -> V {
-    use std::io::{self, BufRead};
-    let stdin = io::stdin();
-    let lines = stdin.lock().lines().map(|x| x.unwrap());
-    eprint!("> {} [{}]? ", prompt.replace('\n', "\n> "), possible);
-    for line in lines {
+    let interaction = crate::interaction::current();
+    let mut prompt = format!("> {} [{}]? ", prompt.replace('\n', "\n> "), possible);
+    while let Some(line) = interaction.read_line(&prompt) {
         match validator(line.trim_end()) {
             Ok(v) => return v,
-            Err(_) => eprintln!("> ‘{}’ isn't valid. Try again [{}]", line, possible),
+            Err(_) => {
+                eprintln!("> ‘{}’ isn't valid. Try again [{}]", line, possible);
+                prompt = String::new();
+            }
         }
     }
     eprintln!("STDIN terminated");
     std::process::exit(2)
 }
 
+/// Read a passphrase (or similar) without showing it.
+pub fn read_secret(prompt: &str) -> anyhow::Result<String> {
+    crate::interaction::current().read_secret(prompt)
+}
+
+/// Load `seed.txt` from the wallet directory. The words can be in any BIP39 language.
+pub fn load_seed_words(wallet_dir: &std::path::Path) -> anyhow::Result<Mnemonic> {
+    let file_path = wallet_dir.join("seed.txt");
+    let seed_words = fs::read_to_string(&file_path).context("loading seed words")?;
+    Mnemonic::parse(&seed_words).map_err(|e| {
+        anyhow!(
+            "parsing seed phrase in '{}' failed: {}",
+            file_path.display(),
+            e
+        )
+    })
+}
+
+/// Logs how far along a sync is on stderr.
+#[derive(Clone, Debug, Default)]
+pub struct SyncProgress {
+    last_logged: Arc<std::sync::atomic::AtomicU32>,
+}
+
+impl Progress for SyncProgress {
+    fn update(&self, progress: f32, message: Option<String>) -> Result<(), bdk::Error> {
+        use std::sync::atomic::Ordering;
+        // only log every 10% so we don't flood the terminal
+        let tenths = (progress / 10.0) as u32;
+        if self.last_logged.swap(tenths, Ordering::Relaxed) == tenths && message.is_none() {
+            return Ok(());
+        }
+        match message {
+            Some(message) => elog!(@info "sync {:.0}% {}", progress, message),
+            None => elog!(@info "sync {:.0}%", progress),
+        }
+        Ok(())
+    }
+}
+
 pub fn load_wallet(
     wallet_dir: &std::path::Path,
 ) -> anyhow::Result<(GunWallet, Option<Keychain>, Config)> {
-    use bdk::keys::bip39::Mnemonic;
+    load_wallet_with_interaction(wallet_dir, crate::interaction::current())
+}
 
+/// Like [`load_wallet`] but the signers ask their questions through `interaction`.
+pub fn load_wallet_with_interaction(
+    wallet_dir: &std::path::Path,
+    interaction: Arc<dyn Interaction>,
+) -> anyhow::Result<(GunWallet, Option<Keychain>, Config)> {
     if !wallet_dir.exists() {
         return Err(anyhow!(
             "No wallet found at {}. Run `gun init` to set a new one up or set --gun-dir.",
             wallet_dir.display()
-        ));
+        ))
+        .error_kind(ErrorKind::User);
     }
 
     let config = load_config(&wallet_dir.join("config.json")).context("loading configuration")?;
@@ -146,9 +236,9 @@ pub fn load_wallet(
         .open_tree("wallet")
         .context("opening wallet tree")?;
 
-    let esplora = EsploraBlockchain::from_config(config.blockchain_config())?;
-
     let gun_db = GunDatabase::new(database.open_tree("gun").context("opening gun db tree")?);
+    crate::database::migrations::migrate_on_load(wallet_dir, &gun_db)
+        .context("migrating database")?;
 
     let external = gun_db
         .get_entity::<StringDescriptor>(KeychainKind::External)?
@@ -157,68 +247,150 @@ pub fn load_wallet(
         ))?;
     let internal = gun_db.get_entity::<StringDescriptor>(KeychainKind::Internal)?;
 
-    let mut wallet = Wallet::new(
+    // we don't connect to esplora until a command needs it
+    let mut wallet = Wallet::new_offline(
         &external.0,
         internal.as_ref().map(|x| &x.0),
         config.network,
         wallet_db,
-        esplora,
     )
     .context("Initializing wallet from descriptors")?;
 
-    for (i, signer) in config.signers.iter().enumerate() {
-        let signer: Arc<dyn Signer> = match signer {
+    let mut master_key = None;
+    for (i, signer_config) in config.signers.iter().enumerate() {
+        let signer: Arc<dyn Signer> = match signer_config {
             GunSigner::PsbtDir {
                 path: psbt_signer_dir,
+                ..
             } => Arc::new(PsbtDirSigner::create(
                 psbt_signer_dir.to_owned(),
                 config.network,
+                interaction.clone(),
+            )),
+            GunSigner::Qr { .. } => Arc::new(QrSigner::new(config.network, interaction.clone())),
+            GunSigner::Hwi { fingerprint, .. } => Arc::new(HwiSigner::new(
+                *fingerprint,
+                config.network,
+                interaction.clone(),
             )),
             GunSigner::SeedWordsFile {
                 passphrase_fingerprint,
+                passphrase_source,
+                ..
             } => {
-                let file_path = wallet_dir.join("seed.txt");
-                let seed_words = fs::read_to_string(&file_path).context("loading seed words")?;
-                let mnemonic = Mnemonic::parse(&seed_words).map_err(|e| {
-                    anyhow!(
-                        "parsing seed phrase in '{}' failed: {}",
-                        file_path.display(),
-                        e
-                    )
-                })?;
+                let mnemonic = load_seed_words(wallet_dir)?;
 
                 match passphrase_fingerprint {
-                    Some(fingerprint) => Arc::new(PwSeedSigner {
-                        mnemonic,
-                        network: config.network,
-                        master_fingerprint: *fingerprint,
-                    }),
-                    None => Arc::new(XKeySigner {
-                        master_xkey: ExtendedPrivKey::new_master(
-                            config.network,
-                            &mnemonic.to_seed(""),
-                        )
-                        .unwrap(),
-                    }),
+                    Some(fingerprint) => {
+                        let signer = Arc::new(PwSeedSigner {
+                            mnemonic,
+                            network: config.network,
+                            master_fingerprint: *fingerprint,
+                            passphrase_source: passphrase_source.clone(),
+                            interaction: interaction.clone(),
+                        });
+                        master_key = Some(MasterKeySource::Passphrase(signer.clone()));
+                        signer
+                    }
+                    None => {
+                        let seed = Secret::new(mnemonic.to_seed(""));
+                        let signer = Arc::new(XKeySigner {
+                            master_xkey: ExtendedPrivKey::new_master(
+                                config.network,
+                                seed.expose_secret(),
+                            )
+                            .unwrap(),
+                        });
+                        master_key = Some(MasterKeySource::Xkey(signer.clone()));
+                        signer
+                    }
                 }
             }
         };
+        let options = signer_config.options();
+        let signer: Arc<dyn Signer> = if options.required {
+            signer
+        } else {
+            Arc::new(OptionalSigner::new(signer, i))
+        };
         wallet.add_signer(
-            KeychainKind::External, //NOTE: will sign internal inputs as well!
-            SignerOrdering(i),
+            //NOTE: when a keychain isn't given the signer will sign internal inputs as well!
+            options.keychain.unwrap_or(KeychainKind::External),
+            SignerOrdering(options.order.unwrap_or(i)),
             signer,
         );
     }
 
-    let keychain = gun_db
-        .get_entity::<ProtocolSecret>(ProtocolKind::Bet)?
-        .map(Keychain::from);
-    let gun_wallet = GunWallet::new(wallet, gun_db);
+    let keychain = match &config.protocol_secret_file {
+        // it's fine if the device it's on isn't plugged in unless the command needs it
+        Some(path) => match ProtocolSecret::from_file(path) {
+            Ok(secret) => Some(gun_db.bet_keychain_with(secret)?),
+            Err(_) => None,
+        },
+        None => gun_db.bet_keychain()?,
+    };
+    let gun_wallet = GunWallet::new(
+        wallet,
+        (external.0, internal.map(|x| x.0)),
+        LazyEsplora::new(config.clone()),
+        gun_db,
+        config.http_client(),
+        config.retries(),
+    )
+    .with_net(config.net())
+    .with_fee_estimator(config.fee_estimator_url())
+    .with_locktime_policy(config.locktime_policy())
+    .with_dust_threshold(config.dust_threshold())
+    .with_tx_seed(config.deterministic_tx_seed)
+    .with_hooks(config.hooks.clone())
+    .with_master_key(master_key);
 
     Ok((gun_wallet, keychain, config))
 }
 
+/// The keychain [`load_wallet`] loaded or an error saying why it couldn't so you can't go on to
+/// `doing`.
+pub fn require_keychain(
+    keychain: Option<Keychain>,
+    config: &Config,
+    doing: &str,
+) -> anyhow::Result<Keychain> {
+    match (keychain, &config.protocol_secret_file) {
+        (Some(keychain), _) => Ok(keychain),
+        (None, Some(path)) => {
+            let reason = ProtocolSecret::from_file(path)
+                .err()
+                .map(|e| e.to_string())
+                .unwrap_or_default();
+            Err(anyhow!(
+                "Couldn't read the protocol secret from {} (is the device it's on plugged in?) so you can't {}: {}",
+                path.display(),
+                doing,
+                reason
+            ))
+        }
+        (None, None) => Err(anyhow!(
+            "This wallet wasn't set up with a protocol secret so you can't {}. Set one with `gun config protocol bet protocol-secret set`.",
+            doing
+        )),
+    }
+}
+
 pub fn load_wallet_db(wallet_dir: &std::path::Path) -> anyhow::Result<impl BatchDatabase> {
+    load_wallet_tree(wallet_dir)
+}
+
+/// Just the gun database (for when we don't need the wallet).
+pub fn load_gun_db(wallet_dir: &std::path::Path) -> anyhow::Result<GunDatabase> {
+    let database = sled::open(wallet_dir.join("database.sled").to_str().unwrap())
+        .context("opening database.sled")?;
+    Ok(GunDatabase::new(
+        database.open_tree("gun").context("opening gun db tree")?,
+    ))
+}
+
+/// The sled tree where BDK keeps the wallet's transactions
+pub fn load_wallet_tree(wallet_dir: &std::path::Path) -> anyhow::Result<sled::Tree> {
     let database = sled::open(wallet_dir.join("database.sled").to_str().unwrap())
         .context("opening database.sled")?;
     database.open_tree("wallet").context("opening wallet tree")
@@ -533,20 +705,93 @@ pub fn display_psbt(network: Network, psbt: &Psbt) -> String {
     table.render()
 }
 
+/// Checks the transaction against the fee limits in the config.
+///
+/// Returns whether we should go ahead with it. Going over a limit with `--yes` is an error since
+/// there's no one to ask.
+pub fn check_fee_limits(config: &Config, psbt: &Psbt, yes: bool) -> anyhow::Result<bool> {
+    match config.fee_limit_exceeded(psbt) {
+        None => Ok(true),
+        Some(problem) if yes => Err(anyhow!("{} -- refusing to continue with --yes", problem)),
+        Some(problem) => Ok(read_yn(&format!(
+            "WARNING: {}. Are you sure you want to continue",
+            problem
+        ))),
+    }
+}
+
+/// Check a new bet risking `value` against the configured tag limits (see [`crate::risk`]).
+///
+/// Returns whether we should go ahead with it. Going over a strict limit is always an error and
+/// going over any limit with `--yes` is too.
+pub fn check_tag_limits(
+    config: &Config,
+    gun_db: &GunDatabase,
+    tags: &[String],
+    value: Amount,
+    yes: bool,
+) -> anyhow::Result<bool> {
+    if config.tag_limits.is_empty() {
+        return Ok(true);
+    }
+    let at_risk = crate::risk::at_risk_by_tag(gun_db.list_entities_print_error::<BetState>());
+    for breach in crate::risk::tag_limit_breaches(&config.tag_limits, &at_risk, tags, value) {
+        if breach.strict {
+            return Err(anyhow!("{} (the limit is strict)", breach)).error_kind(ErrorKind::User);
+        }
+        if yes {
+            return Err(anyhow!("{} -- refusing to continue with --yes", breach));
+        }
+        if !read_yn(&format!(
+            "WARNING: {}. Are you sure you want to continue",
+            breach
+        )) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Whether to go ahead with `operation` (which sends `sending` out of the wallet). Asks `question`
+/// if the config's [`ConfirmPolicy`](crate::confirm::ConfirmPolicy) says to.
+pub fn confirm(
+    config: &Config,
+    operation: Operation,
+    yes: bool,
+    sending: Amount,
+    question: &str,
+) -> bool {
+    if !config.confirm_policy.should_ask(operation, yes, sending) {
+        return true;
+    }
+    if yes {
+        elog!(@info "Asking even though --yes was given because of the confirm-policy in the config");
+    }
+    read_yn(question)
+}
+
 pub fn decide_to_broadcast(
-    network: Network,
+    config: &Config,
     blockchain: &impl bdk::blockchain::Broadcast,
     psbt: Psbt,
+    operation: Operation,
     yes: bool,
     print_tx: bool,
 ) -> anyhow::Result<(CmdOutput, Option<Txid>)> {
     use crate::item;
-    if yes
-        || read_yn(&format!(
+    if !check_fee_limits(config, &psbt, yes)? {
+        return Ok((CmdOutput::None, None));
+    }
+    if confirm(
+        config,
+        operation,
+        yes,
+        confirm::value_leaving(&psbt),
+        &format!(
             "This is the transaction that will be broadcast.\n{}Ok",
-            display_psbt(network, &psbt)
-        ))
-    {
+            display_psbt(config.network, &psbt)
+        ),
+    ) {
         let tx = psbt.extract_tx();
 
         if print_tx {
@@ -574,7 +819,7 @@ pub fn decide_to_broadcast(
 fn ensure_not_watch_only(wallet: &GunWallet) -> anyhow::Result<()> {
     if wallet.is_watch_only() {
         Err(anyhow!(
-            "You cannot do this command because this wallet is watch-only"
+            "You cannot do this command because this wallet is watch-only. Add a signer for it with `gun config signer add`."
         ))
     } else {
         Ok(())
@@ -606,11 +851,12 @@ macro_rules! eitem {
 
 #[macro_export]
 macro_rules! elog {
-    (@warning $($tt:tt)*) => { eprint!("\u{26A0} "); eprintln!($($tt)*);};
-    (@info $($tt:tt)*) => { eprint!("\u{2139} "); eprintln!($($tt)*);};
-    (@celebration $($tt:tt)*) => { eprint!("\u{1F389} "); eprintln!($($tt)*);};
-    (@user_action $($tt:tt)*) => { eprint!("\u{1F449} "); eprintln!($($tt)*);};
-    (@recoverable_error $($tt:tt)*) => { eprint!("\u{1F4A5} "); eprintln!($($tt)*);};
-    (@user_error $($tt:tt)*) => { eprint!("\u{274C}"); eprintln!($($tt)*);};
-    (@question $($tt:tt)*) => { eprint!("\u{2753}"); eprintln!($($tt)*); };
-    (@suggestion $($tt:tt)*) => { eprint!("\u{1F4A1}"); eprintln!($($tt)*); };}
+    (@warning $($tt:tt)*) => { $crate::logging::emit($crate::logging::Kind::Warning, format_args!($($tt)*)) };
+    (@info $($tt:tt)*) => { $crate::logging::emit($crate::logging::Kind::Info, format_args!($($tt)*)) };
+    (@celebration $($tt:tt)*) => { $crate::logging::emit($crate::logging::Kind::Celebration, format_args!($($tt)*)) };
+    (@user_action $($tt:tt)*) => { $crate::logging::emit($crate::logging::Kind::UserAction, format_args!($($tt)*)) };
+    (@recoverable_error $($tt:tt)*) => { $crate::logging::emit($crate::logging::Kind::RecoverableError, format_args!($($tt)*)) };
+    (@user_error $($tt:tt)*) => { $crate::logging::emit($crate::logging::Kind::UserError, format_args!($($tt)*)) };
+    (@question $($tt:tt)*) => { $crate::logging::emit($crate::logging::Kind::Question, format_args!($($tt)*)) };
+    (@suggestion $($tt:tt)*) => { $crate::logging::emit($crate::logging::Kind::Suggestion, format_args!($($tt)*)) };
+}
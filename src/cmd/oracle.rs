@@ -1,4 +1,4 @@
-use crate::{cmd, database::GunDatabase, elog, item, OracleInfo, Url};
+use crate::{cmd, database::OracleUrl, elog, item, wallet::GunWallet, OracleInfo, Url};
 use anyhow::anyhow;
 use olivia_core::{http::RootResponse, OracleId};
 use olivia_secp256k1::Secp256k1;
@@ -31,7 +31,8 @@ pub enum OracleOpt {
     },
 }
 
-pub fn run_oralce_cmd(gun_db: &GunDatabase, cmd: OracleOpt) -> anyhow::Result<CmdOutput> {
+pub fn run_oralce_cmd(wallet: &GunWallet, cmd: OracleOpt) -> anyhow::Result<CmdOutput> {
+    let gun_db = wallet.gun_db();
     match cmd {
         OracleOpt::Add { url, yes } => {
             let url =
@@ -45,8 +46,8 @@ pub fn run_oralce_cmd(gun_db: &GunDatabase, cmd: OracleOpt) -> anyhow::Result<Cm
                     elog!(@info "Oracle {} is already trusted", oracle_id);
                 }
                 None => {
-                    let root_response = ureq::get(url.as_str())
-                        .call()?
+                    let root_response = wallet
+                        .http_get(url.as_str())?
                         .into_json::<RootResponse<Secp256k1>>()?;
                     let oracle_info = OracleInfo {
                         id: oracle_id.clone(),
@@ -56,6 +57,13 @@ pub fn run_oralce_cmd(gun_db: &GunDatabase, cmd: OracleOpt) -> anyhow::Result<Cm
                     println!("{}", serde_json::to_string_pretty(&oracle_info).unwrap());
 
                     if yes || cmd::read_yn("Trust the oracle displayed above") {
+                        // e.g. `gun dev oracle` at http://localhost:8765
+                        if url.scheme() != "https" || url.port().is_some() {
+                            gun_db.insert_entity(
+                                oracle_id.clone(),
+                                OracleUrl(url.origin().ascii_serialization()),
+                            )?;
+                        }
                         gun_db.insert_entity(oracle_id, oracle_info)?;
                     }
                 }
@@ -88,6 +96,7 @@ pub fn run_oralce_cmd(gun_db: &GunDatabase, cmd: OracleOpt) -> anyhow::Result<Cm
             {
                 return Err(anyhow!("oralce '{}' doesn't exist", oracle_id));
             }
+            gun_db.remove_entity::<OracleUrl>(oracle_id)?;
             Ok(CmdOutput::None)
         }
         OracleOpt::Show { oracle_id } => {
@@ -0,0 +1,323 @@
+use super::{
+    wallet::{index_txos, BetLinks},
+    Cell, CmdOutput,
+};
+use crate::{
+    betting::{BetId, BetState},
+    cmd,
+    wallet::GunWallet,
+};
+use anyhow::anyhow;
+use bdk::{
+    bitcoin::{OutPoint, Script, Transaction, TxOut, Txid},
+    blockchain::Blockchain,
+    database::Database,
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    str::FromStr,
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum PrivacyOpt {
+    /// Check a transaction for common privacy leaks
+    ///
+    /// Each check that fails takes its penalty off a score out of 100.
+    Check {
+        /// A txid from the wallet or a PSBT (base64 or a file containing it)
+        tx: String,
+    },
+}
+
+/// What a transaction reveals about the wallet
+struct Finding {
+    check: &'static str,
+    penalty: u32,
+    /// Why the check failed or None if it passed
+    problem: Option<String>,
+}
+
+pub fn run_privacy_cmd(wallet: &GunWallet, opt: PrivacyOpt) -> anyhow::Result<CmdOutput> {
+    match opt {
+        PrivacyOpt::Check { tx } => {
+            let (tx, psbt_prevouts) = match Txid::from_str(&tx) {
+                Ok(txid) => (load_tx(wallet, txid)?, HashMap::new()),
+                Err(_) => {
+                    let psbt = cmd::load_psbt(&tx)?;
+                    let prevouts = psbt
+                        .unsigned_tx
+                        .input
+                        .iter()
+                        .zip(psbt.inputs.iter())
+                        .filter_map(|(txin, input)| {
+                            Some((txin.previous_output, input.witness_utxo.clone()?))
+                        })
+                        .collect();
+                    (psbt.unsigned_tx, prevouts)
+                }
+            };
+
+            let findings = check_tx(wallet, &tx, &psbt_prevouts)?;
+            let score = 100u32.saturating_sub(
+                findings
+                    .iter()
+                    .filter(|finding| finding.problem.is_some())
+                    .map(|finding| finding.penalty)
+                    .sum(),
+            );
+            let mut rows = findings
+                .into_iter()
+                .map(|finding| {
+                    vec![
+                        Cell::string(finding.check),
+                        Cell::string(if finding.problem.is_some() {
+                            "warn"
+                        } else {
+                            "ok"
+                        }),
+                        Cell::Int(finding.penalty.into()),
+                        finding.problem.map(Cell::String).unwrap_or(Cell::Empty),
+                    ]
+                })
+                .collect::<Vec<_>>();
+            rows.push(vec![
+                Cell::string("score"),
+                Cell::Empty,
+                Cell::Int(score.into()),
+                Cell::Empty,
+            ]);
+            Ok(CmdOutput::table(
+                vec!["check", "status", "penalty", "detail"],
+                rows,
+            ))
+        }
+    }
+}
+
+fn load_tx(wallet: &GunWallet, txid: Txid) -> anyhow::Result<Transaction> {
+    let bdk_wallet = wallet.bdk_wallet();
+    if let Some(tx) = bdk_wallet
+        .database()
+        .get_tx(&txid, true)?
+        .and_then(|details| details.transaction)
+    {
+        return Ok(tx);
+    }
+    wallet
+        .blockchain()?
+        .get_tx(&txid)?
+        .ok_or(anyhow!("transaction {} not found", txid))
+}
+
+fn check_tx(
+    wallet: &GunWallet,
+    tx: &Transaction,
+    psbt_prevouts: &HashMap<OutPoint, TxOut>,
+) -> anyhow::Result<Vec<Finding>> {
+    let bdk_wallet = wallet.bdk_wallet();
+    let txid = tx.txid();
+    let raw_txs = bdk_wallet.database().iter_raw_txs()?;
+    let txs_by_id = raw_txs
+        .iter()
+        .map(|tx| (tx.txid(), tx))
+        .collect::<HashMap<_, _>>();
+    let txos = index_txos(&raw_txs);
+    let bet_links = BetLinks::load(wallet);
+    let bet_tags = wallet
+        .gun_db()
+        .list_entities_print_error::<BetState>()
+        .map(|(bet_id, bet_state)| (bet_id, bet_state.tags().to_vec()))
+        .collect::<HashMap<BetId, Vec<String>>>();
+
+    let prevout = |outpoint: &OutPoint| -> Option<TxOut> {
+        psbt_prevouts.get(outpoint).cloned().or_else(|| {
+            txs_by_id
+                .get(&outpoint.txid)?
+                .output
+                .get(outpoint.vout as usize)
+                .cloned()
+        })
+    };
+    // how many transactions other than this one pay to the script
+    let times_paid = |script: &Script| {
+        txos.get(script)
+            .map(|txs| txs.iter().filter(|tx| tx.txid() != txid).count())
+            .unwrap_or(0)
+    };
+
+    let mut my_inputs = vec![];
+    for txin in &tx.input {
+        if let Some(txout) = prevout(&txin.previous_output) {
+            if bdk_wallet.is_mine(&txout.script_pubkey)? {
+                my_inputs.push((txin.previous_output, txout));
+            }
+        }
+    }
+    let mut change = vec![];
+    let mut payments = vec![];
+    for (vout, txout) in tx.output.iter().enumerate() {
+        let is_joint_output = bet_links
+            .joint_outputs
+            .contains_key(&OutPoint::new(txid, vout as u32));
+        if bdk_wallet.is_mine(&txout.script_pubkey)? {
+            change.push((vout, txout));
+        } else if !is_joint_output {
+            payments.push((vout, txout));
+        }
+    }
+
+    let mut findings = vec![];
+
+    let reused = change
+        .iter()
+        .filter(|(_, txout)| times_paid(&txout.script_pubkey) > 0)
+        .map(|(vout, _)| format!("output {}", vout))
+        .chain(
+            my_inputs
+                .iter()
+                .filter(|(_, txout)| times_paid(&txout.script_pubkey) > 1)
+                .map(|(outpoint, _)| format!("input {}", outpoint)),
+        )
+        .collect::<Vec<_>>();
+    findings.push(Finding {
+        check: "address-reuse",
+        penalty: 30,
+        problem: (!reused.is_empty())
+            .then(|| format!("{} use addresses paid more than once", reused.join(", "))),
+    });
+
+    let round_payment_change = match (&payments[..], &change[..]) {
+        (payments, [(vout, change)])
+            if !my_inputs.is_empty()
+                && payments.iter().any(|(_, txout)| is_round(txout.value))
+                && !is_round(change.value) =>
+        {
+            Some(format!(
+                "output {} is probably change since the payment is a round amount and it isn't",
+                vout
+            ))
+        }
+        _ => None,
+    };
+    findings.push(Finding {
+        check: "round-amount-change",
+        penalty: 20,
+        problem: round_payment_change,
+    });
+
+    let change_type_differs = change
+        .iter()
+        .find(|(_, change)| {
+            !payments.is_empty()
+                && payments.iter().all(|(_, payment)| {
+                    script_type(&payment.script_pubkey) != script_type(&change.script_pubkey)
+                })
+        })
+        .map(|(vout, change)| {
+            format!(
+                "output {} looks like change since it's {} and no payment is",
+                vout,
+                script_type(&change.script_pubkey)
+            )
+        });
+    findings.push(Finding {
+        check: "change-script-type",
+        penalty: 10,
+        problem: change_type_differs,
+    });
+
+    // The bet each input came out of (if any)
+    let input_bets = my_inputs
+        .iter()
+        .map(|(outpoint, _)| {
+            bet_links
+                .joint_outputs
+                .get(outpoint)
+                .or_else(|| bet_links.txs.get(&outpoint.txid).map(|(bet_id, _)| bet_id))
+                .copied()
+        })
+        .collect::<Vec<_>>();
+
+    let tag_sets = input_bets
+        .iter()
+        .map(|bet_id| {
+            bet_id
+                .and_then(|bet_id| bet_tags.get(&bet_id))
+                .map(|tags| tags.iter().cloned().collect::<BTreeSet<_>>())
+                .unwrap_or_default()
+        })
+        .collect::<BTreeSet<_>>();
+    findings.push(Finding {
+        check: "mixed-tags",
+        penalty: 20,
+        problem: (tag_sets.len() > 1).then(|| {
+            format!(
+                "inputs come from coins with different tags ({})",
+                tag_sets
+                    .iter()
+                    .map(|tags| {
+                        if tags.is_empty() {
+                            "untagged".to_string()
+                        } else {
+                            tags.iter().cloned().collect::<Vec<_>>().join("+")
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        }),
+    });
+
+    let bets_spent = input_bets.iter().flatten().collect::<BTreeSet<_>>();
+    let bet_linkage = match bets_spent.iter().next() {
+        Some(bet_id) if bets_spent.len() > 1 || input_bets.iter().any(Option::is_none) => {
+            Some(format!(
+                "spending coins from bet {} with other coins links the bet to the rest of your wallet",
+                bet_id
+            ))
+        }
+        _ => None,
+    };
+    findings.push(Finding {
+        check: "bet-change-linkage",
+        penalty: 25,
+        problem: bet_linkage,
+    });
+
+    Ok(findings)
+}
+
+/// Whether an amount looks like someone chose it (a multiple of 10k sats)
+fn is_round(value: u64) -> bool {
+    value != 0 && value % 10_000 == 0
+}
+
+fn script_type(script: &Script) -> &'static str {
+    if script.is_v0_p2wpkh() {
+        "p2wpkh"
+    } else if script.is_v0_p2wsh() {
+        "p2wsh"
+    } else if script.is_v1_p2tr() {
+        "p2tr"
+    } else if script.is_p2sh() {
+        "p2sh"
+    } else if script.is_p2pkh() {
+        "p2pkh"
+    } else {
+        "other"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_amounts() {
+        assert!(is_round(100_000));
+        assert!(is_round(1_230_000));
+        assert!(!is_round(1_234_567));
+        assert!(!is_round(0));
+    }
+}
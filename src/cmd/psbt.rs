@@ -0,0 +1,259 @@
+use super::{Cell, CmdOutput};
+use crate::{cmd, config::Config, confirm::Operation, elog, qr, wallet::GunWallet};
+use anyhow::{anyhow, Context};
+use bdk::{
+    bitcoin::{util::psbt::PartiallySignedTransaction as Psbt, Address, Amount, Script},
+    database::Database,
+    KeychainKind, SignOptions,
+};
+use std::{path::Path, str::FromStr};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum PsbtOpt {
+    /// Show a PSBT as animated UR QR codes for an airgapped signer to scan.
+    ShowQr {
+        /// The PSBT in base64 or a file containing it
+        psbt: String,
+        /// How many bytes of the PSBT to put in each QR code
+        #[structopt(long, default_value = "200")]
+        fragment_len: usize,
+    },
+    /// Read a PSBT in from UR parts (one per line on stdin) and print it as base64.
+    ReadQr,
+    /// Combine PSBTs signed by the cosigners of a multisig wallet and broadcast the result if it
+    /// has enough signatures.
+    Combine {
+        /// The PSBTs in base64 or files containing them
+        #[structopt(required = true, min_values = 2)]
+        psbts: Vec<String>,
+        /// Print the resulting transaction out in hex instead of broadcasting it.
+        #[structopt(long)]
+        print_tx: bool,
+        /// Don't prompt for confirmation before broadcasting.
+        #[structopt(short, long)]
+        yes: bool,
+    },
+    /// Check a PSBT someone else made only spends this wallet's coins the way you expect before
+    /// signing it.
+    ///
+    /// Every input must be one of the wallet's coins, every output must go to a --to address or
+    /// back to the wallet and the fee must be within max-fee-abs and max-feerate. Otherwise it
+    /// fails with a report of what's wrong.
+    Check {
+        /// The PSBT in base64 or a file containing it
+        psbt: String,
+        /// An address you expect the PSBT to pay (can be given more than once)
+        #[structopt(long)]
+        to: Vec<Address>,
+    },
+}
+
+/// Reads a PSBT in base64 or from a file containing it.
+pub fn load_psbt(psbt: &str) -> anyhow::Result<Psbt> {
+    let path = Path::new(psbt);
+    if path.exists() {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("reading PSBT from {}", path.display()))?;
+        Psbt::from_str(contents.trim()).context("parsing PSBT file")
+    } else {
+        Psbt::from_str(psbt.trim()).context("parsing PSBT")
+    }
+}
+
+/// Runs psbt commands. `wallet` is only needed for `combine` and `check`.
+pub fn run_psbt_cmd(
+    wallet: Option<(&GunWallet, &Config)>,
+    opt: PsbtOpt,
+) -> anyhow::Result<CmdOutput> {
+    match opt {
+        PsbtOpt::ShowQr { psbt, fragment_len } => {
+            let psbt = load_psbt(&psbt)?;
+            qr::show_animated(&psbt, fragment_len, crate::interaction::current())?;
+            Ok(CmdOutput::None)
+        }
+        PsbtOpt::ReadQr => {
+            elog!(@suggestion "Paste the UR parts one per line:");
+            let psbt = qr::read_psbt(&*crate::interaction::current())?;
+            Ok(CmdOutput::EmphasisedItem {
+                main: ("psbt", Cell::string(psbt)),
+                other: vec![],
+            })
+        }
+        PsbtOpt::Combine {
+            psbts,
+            print_tx,
+            yes,
+        } => {
+            let (wallet, config) = wallet.expect("combine needs a wallet");
+            let mut psbts = psbts.iter().map(|psbt| load_psbt(psbt));
+            let mut combined = psbts.next().expect("structopt requires at least two")?;
+            for psbt in psbts {
+                combined.merge(psbt?).context("combining PSBTs")?;
+            }
+
+            let finalized = wallet
+                .bdk_wallet()
+                .finalize_psbt(&mut combined, SignOptions::default())?;
+
+            if !finalized {
+                elog!(@info "The combined PSBT still doesn't have enough signatures.");
+                return Ok(CmdOutput::EmphasisedItem {
+                    main: ("psbt", Cell::string(combined)),
+                    other: vec![],
+                });
+            }
+
+            let (output, _) = cmd::decide_to_broadcast(
+                config,
+                wallet.blockchain()?,
+                combined,
+                Operation::Broadcast,
+                yes,
+                print_tx,
+            )?;
+            Ok(output)
+        }
+        PsbtOpt::Check { psbt, to } => {
+            let (wallet, config) = wallet.expect("check needs a wallet");
+            let psbt = load_psbt(&psbt)?;
+            let (rows, problems) = check_psbt(wallet, config, &psbt, &to)?;
+            if !problems.is_empty() {
+                for problem in &problems {
+                    elog!(@user_error "{}", problem);
+                }
+                return Err(anyhow!(
+                    "The PSBT failed {} check(s) so it shouldn't be signed",
+                    problems.len()
+                ));
+            }
+            Ok(CmdOutput::table(
+                vec!["kind", "index", "address", "value", "verdict"],
+                rows,
+            ))
+        }
+    }
+}
+
+/// Goes through each input and output of `psbt` saying what it is. Also returns the problems that
+/// mean it shouldn't be signed.
+fn check_psbt(
+    wallet: &GunWallet,
+    config: &Config,
+    psbt: &Psbt,
+    recipients: &[Address],
+) -> anyhow::Result<(Vec<Vec<Cell>>, Vec<String>)> {
+    let bdk_wallet = wallet.bdk_wallet();
+    let network = bdk_wallet.network();
+    let mut rows = vec![];
+    let mut problems = vec![];
+    // the database only has scripts derived from our descriptors so this checks the derivation
+    let derivation = |script: &Script| -> anyhow::Result<Option<String>> {
+        Ok(bdk_wallet
+            .database()
+            .get_path_from_script_pubkey(script)?
+            .map(|(keychain, index)| match keychain {
+                KeychainKind::External => format!("ours (receive {})", index),
+                KeychainKind::Internal => format!("ours (change {})", index),
+            }))
+    };
+    let address = |script: &Script| {
+        Address::from_script(script, network)
+            .map(Cell::string)
+            .unwrap_or(Cell::Empty)
+    };
+
+    for (i, (txin, psbt_input)) in psbt.unsigned_tx.input.iter().zip(&psbt.inputs).enumerate() {
+        let txout = psbt_input.witness_utxo.clone().or_else(|| {
+            psbt_input
+                .non_witness_utxo
+                .as_ref()
+                .filter(|tx| tx.txid() == txin.previous_output.txid)
+                .and_then(|tx| tx.output.get(txin.previous_output.vout as usize).cloned())
+        });
+        let (address, value, verdict) = match txout {
+            Some(txout) => match derivation(&txout.script_pubkey)? {
+                Some(ours) => (
+                    address(&txout.script_pubkey),
+                    Cell::Amount(Amount::from_sat(txout.value)),
+                    ours,
+                ),
+                None => {
+                    problems.push(format!(
+                        "input {} ({}) isn't one of the wallet's coins",
+                        i, txin.previous_output
+                    ));
+                    (
+                        address(&txout.script_pubkey),
+                        Cell::Amount(Amount::from_sat(txout.value)),
+                        "not ours".into(),
+                    )
+                }
+            },
+            None => {
+                problems.push(format!(
+                    "input {} ({}) doesn't say what coin it spends",
+                    i, txin.previous_output
+                ));
+                (Cell::Empty, Cell::Empty, "unknown".into())
+            }
+        };
+        rows.push(vec![
+            Cell::string("input"),
+            Cell::Int(i as u64),
+            address,
+            value,
+            Cell::String(verdict),
+        ]);
+    }
+
+    for (i, txout) in psbt.unsigned_tx.output.iter().enumerate() {
+        let verdict = match derivation(&txout.script_pubkey)? {
+            Some(ours) => ours,
+            None if recipients
+                .iter()
+                .any(|recipient| recipient.script_pubkey() == txout.script_pubkey) =>
+            {
+                "recipient".into()
+            }
+            None => {
+                problems.push(format!(
+                    "output {} pays {} to {} which isn't one of the --to addresses or the wallet's",
+                    i,
+                    Amount::from_sat(txout.value),
+                    Address::from_script(&txout.script_pubkey, network)
+                        .map(|address| address.to_string())
+                        .unwrap_or_else(|| format!("{:x}", txout.script_pubkey))
+                ));
+                "unexpected".into()
+            }
+        };
+        rows.push(vec![
+            Cell::string("output"),
+            Cell::Int(i as u64),
+            address(&txout.script_pubkey),
+            Cell::Amount(Amount::from_sat(txout.value)),
+            Cell::String(verdict),
+        ]);
+    }
+
+    for recipient in recipients {
+        if !psbt
+            .unsigned_tx
+            .output
+            .iter()
+            .any(|txout| txout.script_pubkey == recipient.script_pubkey())
+        {
+            problems.push(format!("nothing is paid to {}", recipient));
+        }
+    }
+
+    // the fee can't be worked out without knowing the value of every input
+    if psbt.inputs.iter().all(|input| input.witness_utxo.is_some()) {
+        if let Some(problem) = config.fee_limit_exceeded(psbt) {
+            problems.push(problem);
+        }
+    }
+
+    Ok((rows, problems))
+}
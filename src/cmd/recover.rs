@@ -0,0 +1,154 @@
+use super::{Cell, CmdOutput};
+use crate::{cmd, config::Config, confirm::Operation, elog, wallet::GunWallet};
+use anyhow::anyhow;
+use bdk::{
+    bitcoin::Address,
+    blockchain::Blockchain,
+    descriptor::policy::{Policy, SatisfiableItem},
+    KeychainKind, SignOptions,
+};
+use std::collections::BTreeMap;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum RecoverOpt {
+    /// Spend every coin whose timelock has matured with the recovery key.
+    ///
+    /// Only works for wallets set up with `gun setup seed --recovery-key`.
+    Sweep {
+        /// The address to send the coins to
+        to: Address,
+        #[structopt(flatten)]
+        fee_args: cmd::FeeArgs,
+        /// Print the resulting transaction out in hex instead of broadcasting it.
+        #[structopt(long)]
+        print_tx: bool,
+        /// Don't prompt for confirmation before broadcasting.
+        #[structopt(short, long)]
+        yes: bool,
+    },
+}
+
+/// Finds the branch of the top level `or` that has the relative timelock and how many blocks it is.
+fn recovery_path(policy: &Policy) -> Option<(usize, u32)> {
+    let items = match &policy.item {
+        SatisfiableItem::Thresh {
+            items,
+            threshold: 1,
+        } => items,
+        _ => return None,
+    };
+    items
+        .iter()
+        .enumerate()
+        .find_map(|(i, branch)| match &branch.item {
+            SatisfiableItem::Thresh { items, .. } => {
+                items.iter().find_map(|item| match item.item {
+                    SatisfiableItem::RelativeTimelock { value } => Some((i, value)),
+                    _ => None,
+                })
+            }
+            _ => None,
+        })
+}
+
+pub fn run_recover_cmd(
+    wallet: &GunWallet,
+    config: &Config,
+    opt: RecoverOpt,
+) -> anyhow::Result<CmdOutput> {
+    match opt {
+        RecoverOpt::Sweep {
+            to,
+            fee_args,
+            print_tx,
+            yes,
+        } => {
+            let bdk_wallet = wallet.bdk_wallet();
+            let mut builder = bdk_wallet.build_tx();
+            let mut after = None;
+
+            for keychain in [KeychainKind::External, KeychainKind::Internal] {
+                let policy = match bdk_wallet.policies(keychain)? {
+                    Some(policy) => policy,
+                    None => continue,
+                };
+                let (index, blocks) = recovery_path(&policy).ok_or(anyhow!(
+                    "this wallet doesn't have a timelocked recovery path"
+                ))?;
+                let mut path = BTreeMap::new();
+                path.insert(policy.id.clone(), vec![index]);
+                builder.policy_path(path, keychain);
+                after = Some(blocks);
+            }
+
+            let after = after.expect("the external keychain always has a policy");
+            let height = wallet.blockchain()?.get_height()?;
+            let confirmation_heights = bdk_wallet
+                .list_transactions(false)?
+                .into_iter()
+                .filter_map(|tx| Some((tx.txid, tx.confirmation_time?.height)))
+                .collect::<Vec<_>>();
+
+            let (matured, immature): (Vec<_>, Vec<_>) = bdk_wallet
+                .list_unspent()?
+                .into_iter()
+                .map(|utxo| {
+                    let confirmed_at = confirmation_heights
+                        .iter()
+                        .find(|(txid, _)| *txid == utxo.outpoint.txid)
+                        .map(|(_, height)| *height);
+                    (utxo.outpoint, confirmed_at)
+                })
+                // the sweep can go in the next block
+                .partition(|(_, confirmed_at)| {
+                    matches!(confirmed_at, Some(confirmed_at) if (height + 1).saturating_sub(*confirmed_at) >= after)
+                });
+
+            if !immature.is_empty() {
+                elog!(
+                    @info
+                    "{} coin(s) can't be recovered yet because they need to be confirmed for {} blocks",
+                    immature.len(),
+                    after
+                );
+            }
+            if matured.is_empty() {
+                return Err(anyhow!("there are no coins that can be recovered yet"));
+            }
+
+            builder
+                .manually_selected_only()
+                .add_utxos(
+                    &matured
+                        .into_iter()
+                        .map(|(outpoint, _)| outpoint)
+                        .collect::<Vec<_>>(),
+                )?
+                .drain_to(to.script_pubkey());
+            fee_args.fee.apply_to_builder(wallet, &mut builder)?;
+
+            let (mut psbt, _) = builder.finish()?;
+            fee_args.fee.check_psbt(&psbt)?;
+
+            let finalized = bdk_wallet.sign(&mut psbt, SignOptions::default())?;
+            if !finalized {
+                elog!(@info "The recovery key hasn't signed yet. Sign this PSBT with it and broadcast it.");
+                return Ok(CmdOutput::EmphasisedItem {
+                    main: ("psbt", Cell::string(psbt)),
+                    other: vec![],
+                });
+            }
+
+            let (output, _) = cmd::decide_to_broadcast(
+                config,
+                wallet.blockchain()?,
+                psbt,
+                Operation::Send,
+                yes,
+                print_tx,
+            )?;
+            Ok(output)
+        }
+    }
+}
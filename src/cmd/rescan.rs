@@ -0,0 +1,61 @@
+use super::{Cell, CmdOutput};
+use crate::{cmd, config::Config, elog, item};
+use std::path::Path;
+use structopt::StructOpt;
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct RescanOpt {
+    /// Record this block height as the wallet's birthday before rescanning
+    #[structopt(long)]
+    from_height: Option<u32>,
+}
+
+/// Throw away everything BDK has cached about the wallet's transactions and sync from scratch.
+///
+/// Esplora gives us the whole history of each script so there's no way to start from a height.
+/// The wallet's birthday is recorded so we can warn about history older than expected.
+pub fn run_rescan(wallet_dir: &Path, opt: RescanOpt) -> anyhow::Result<CmdOutput> {
+    if let Some(from_height) = opt.from_height {
+        let config_path = wallet_dir.join("config.json");
+        let config = cmd::load_config(&config_path)?;
+        cmd::write_config(
+            &config_path,
+            Config {
+                birthday_height: Some(from_height),
+                ..config
+            },
+        )?;
+    }
+
+    {
+        let wallet_db = cmd::load_wallet_tree(wallet_dir)?;
+        elog!(@info "Clearing {} cached wallet entries", wallet_db.len());
+        wallet_db.clear()?;
+        wallet_db.flush()?;
+    }
+
+    let (wallet, _, config) = cmd::load_wallet(wallet_dir)?;
+    let stop_gap = config.blockchain_config().stop_gap;
+    elog!(@info "Rescanning with {} (stop_gap: {})", config.blockchain_config().base_url, stop_gap);
+    wallet.sync_with_progress(cmd::SyncProgress::default())?;
+    wallet.poke_bets();
+
+    let bdk_wallet = wallet.bdk_wallet();
+    let txs = bdk_wallet.list_transactions(false)?;
+    let earliest = txs
+        .iter()
+        .filter_map(|tx| tx.confirmation_time.as_ref().map(|time| time.height))
+        .min();
+    if let (Some(birthday), Some(earliest)) = (config.birthday_height, earliest) {
+        if earliest < birthday {
+            elog!(@warning "The wallet has a transaction at height {} which is before its birthday ({})", earliest, birthday);
+        }
+    }
+
+    Ok(item! {
+        "transactions" => Cell::Int(txs.len() as u64),
+        "utxos" => Cell::Int(bdk_wallet.list_unspent()?.len() as u64),
+        "earliest-height" => Cell::maybe_string(earliest),
+        "birthday-height" => Cell::maybe_string(config.birthday_height),
+    })
+}
@@ -0,0 +1,71 @@
+use super::{Cell, CmdOutput};
+use crate::{
+    betting::BetState,
+    chrono::Utc,
+    config::Config,
+    risk::{self, Totals, WinProbabilities},
+    wallet::GunWallet,
+};
+use anyhow::anyhow;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct RiskOpt {
+    /// The chance of winning a bet with no tag in `win-probabilities` in the config
+    #[structopt(long, default_value = "0.5")]
+    default_win_probability: f64,
+}
+
+fn check_probability(what: &str, probability: f64) -> anyhow::Result<()> {
+    if (0.0..=1.0).contains(&probability) {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "the win probability for {} is {} but it must be between 0 and 1",
+            what,
+            probability
+        ))
+    }
+}
+
+fn totals_row(by: &str, group: String, totals: &Totals) -> Vec<Cell> {
+    vec![
+        Cell::string(by),
+        Cell::String(group),
+        Cell::Int(totals.bets),
+        Cell::Amount(totals.at_risk),
+        Cell::Amount(totals.to_win),
+        Cell::SignedAmount(totals.expected_value),
+    ]
+}
+
+/// Show what's at risk in unfinished bets. The worst case is losing all of `at-risk` and the best
+/// is winning all of `to-win`.
+pub fn run_risk(wallet: &GunWallet, config: &Config, opt: RiskOpt) -> anyhow::Result<CmdOutput> {
+    check_probability("untagged bets", opt.default_win_probability)?;
+    for (tag, probability) in &config.win_probabilities {
+        check_probability(&format!("‘{}’", tag), *probability)?;
+    }
+    let exposures = risk::exposures(wallet.gun_db().list_entities_print_error::<BetState>());
+    let report = risk::report(
+        &exposures,
+        &WinProbabilities {
+            by_tag: &config.win_probabilities,
+            default: opt.default_win_probability,
+        },
+        Utc::now().naive_utc(),
+    );
+
+    let mut rows = vec![totals_row("total", "all".into(), &report.total)];
+    for (oracle, totals) in &report.by_oracle {
+        rows.push(totals_row("oracle", oracle.clone(), totals));
+    }
+    for (bucket, totals) in &report.by_time {
+        rows.push(totals_row("outcome", bucket.to_string(), totals));
+    }
+
+    Ok(CmdOutput::table(
+        vec!["by", "group", "bets", "at-risk", "to-win", "expected"],
+        rows,
+    ))
+}
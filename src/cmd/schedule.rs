@@ -0,0 +1,175 @@
+use super::{Cell, CmdOutput, FeeArgs, SpendOpt};
+use crate::{
+    amount_ext::FromCliStr,
+    chrono::{NaiveDateTime, Utc},
+    cmd,
+    config::Config,
+    elog, item,
+    schedule::{Interval, Schedule, ScheduleId},
+    script_kind,
+    wallet::GunWallet,
+};
+use anyhow::anyhow;
+use bdk::bitcoin::{Address, Amount};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum ScheduleOpt {
+    /// Add a recurring payment
+    Add {
+        /// The address to pay
+        #[structopt(long)]
+        to: Address,
+        /// How much to pay each time e.g. 0.005BTC
+        #[structopt(long, parse(try_from_str = FromCliStr::from_cli_str))]
+        value: Amount,
+        /// How often to pay e.g. 12h, 30d, 2w
+        #[structopt(long)]
+        every: Interval,
+        /// Make the first payment after this long instead of right away
+        #[structopt(long)]
+        start_in: Option<Interval>,
+        /// A note to remind you what the payment is for
+        #[structopt(long)]
+        label: Option<String>,
+        /// Broadcast the payments without asking when running `gun schedule run`
+        #[structopt(long)]
+        auto_confirm: bool,
+    },
+    /// List recurring payments
+    List,
+    /// Stop a recurring payment
+    Remove { id: ScheduleId },
+    /// Make the payments that are due
+    Run {
+        #[structopt(flatten)]
+        fee_args: FeeArgs,
+        /// Don't prompt for answers just answer yes.
+        #[structopt(long, short)]
+        yes: bool,
+    },
+}
+
+fn now() -> u64 {
+    Utc::now().timestamp() as u64
+}
+
+fn format_time(timestamp: u64) -> String {
+    NaiveDateTime::from_timestamp(timestamp as i64, 0)
+        .format("%Y-%m-%d %H:%M")
+        .to_string()
+}
+
+pub fn run_schedule_cmd(
+    wallet: &GunWallet,
+    config: &Config,
+    opt: ScheduleOpt,
+) -> anyhow::Result<CmdOutput> {
+    let gun_db = wallet.gun_db();
+    match opt {
+        ScheduleOpt::Add {
+            to,
+            value,
+            every,
+            start_in,
+            label,
+            auto_confirm,
+        } => {
+            script_kind::check_address(&to, config.network)?;
+            let schedule = Schedule {
+                to,
+                value,
+                every,
+                next_due: now() + start_in.map(|start_in| start_in.0).unwrap_or(0),
+                label,
+                auto_confirm,
+                payments: vec![],
+            };
+            let next_due = schedule.next_due;
+            let id = gun_db.insert_schedule(schedule)?;
+            Ok(item! {
+                "id" => Cell::Int(id.into()),
+                "next-due" => Cell::string(format_time(next_due)),
+            })
+        }
+        ScheduleOpt::List => {
+            let now = now();
+            let rows = gun_db
+                .list_entities_print_error::<Schedule>()
+                .map(|(id, schedule)| {
+                    vec![
+                        Cell::Int(id.into()),
+                        Cell::string(&schedule.to),
+                        Cell::Amount(schedule.value),
+                        Cell::string(schedule.every),
+                        Cell::string(format_time(schedule.next_due)),
+                        Cell::Int(schedule.payments_due(now)),
+                        Cell::Int(schedule.payments.len() as u64),
+                        Cell::string(if schedule.auto_confirm { "auto" } else { "ask" }),
+                        schedule.label.map(Cell::string).unwrap_or(Cell::Empty),
+                    ]
+                })
+                .collect();
+            Ok(CmdOutput::table(
+                vec![
+                    "id", "to", "value", "every", "next-due", "due", "paid", "confirm", "label",
+                ],
+                rows,
+            ))
+        }
+        ScheduleOpt::Remove { id } => match gun_db.remove_entity::<Schedule>(id)? {
+            Some(_) => Ok(CmdOutput::None),
+            None => Err(anyhow!("schedule {} doesn't exist", id)),
+        },
+        ScheduleOpt::Run { fee_args, yes } => {
+            cmd::ensure_not_watch_only(wallet)?;
+            let now = now();
+            let mut rows = vec![];
+            for (id, mut schedule) in gun_db.list_entities_print_error::<Schedule>() {
+                let due = schedule.payments_due(now);
+                if due == 0 {
+                    continue;
+                }
+                if due > 1 {
+                    elog!(@info "Schedule {} has missed {} payments. Only one will be made now.", id, due - 1);
+                }
+
+                let mut builder = wallet.bdk_wallet().build_tx();
+                builder.add_recipient(schedule.to.script_pubkey(), schedule.value.as_sat());
+                let memo = schedule
+                    .label
+                    .clone()
+                    .unwrap_or_else(|| format!("schedule {}", id));
+                let spend_opt =
+                    SpendOpt::with_fee(fee_args.clone(), yes || schedule.auto_confirm, Some(memo));
+                let status = match spend_opt.spend(wallet, config, builder, None, None) {
+                    Ok((_, Some(txid))) => {
+                        schedule.paid(txid);
+                        gun_db.insert_entity(id, schedule.clone())?;
+                        Cell::string(txid)
+                    }
+                    Ok((_, None)) => Cell::string("not sent"),
+                    Err(e) => {
+                        // e.g. the signing device isn't available. Leave it for next time.
+                        elog!(@recoverable_error "Payment for schedule {} failed: {}", id, e);
+                        Cell::string("failed")
+                    }
+                };
+                rows.push(vec![
+                    Cell::Int(id.into()),
+                    Cell::string(&schedule.to),
+                    Cell::Amount(schedule.value),
+                    status,
+                    Cell::string(format_time(schedule.next_due)),
+                ]);
+            }
+            if rows.is_empty() {
+                elog!(@info "No payments are due");
+            }
+            Ok(CmdOutput::table(
+                vec!["id", "to", "value", "txid", "next-due"],
+                rows,
+            ))
+        }
+    }
+}
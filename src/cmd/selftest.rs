@@ -0,0 +1,33 @@
+use super::{Cell, CmdOutput};
+use crate::{elog, selftest};
+use anyhow::anyhow;
+
+pub fn run_selftest() -> anyhow::Result<CmdOutput> {
+    let checks = selftest::run()?;
+    let failed = checks
+        .iter()
+        .filter_map(|check| {
+            check
+                .result
+                .as_ref()
+                .err()
+                .map(|e| format!("{}: {}", check.name, e))
+        })
+        .collect::<Vec<_>>();
+
+    if !failed.is_empty() {
+        return Err(anyhow!(
+            "This build of gun doesn't reproduce the test vectors so it can't be used to bet with other versions:\n{}",
+            failed.join("\n")
+        ));
+    }
+
+    elog!(@celebration "All {} checks passed", checks.len());
+    Ok(CmdOutput::table(
+        vec!["check", "result"],
+        checks
+            .into_iter()
+            .map(|check| vec![Cell::string(check.name), Cell::string("ok")])
+            .collect(),
+    ))
+}
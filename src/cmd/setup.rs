@@ -1,18 +1,21 @@
 use crate::{
     bip85::get_bip85_bytes,
-    cmd::{self},
+    cmd::{self, WithErrorKind},
     config::{Config, GunSigner},
-    database::{GunDatabase, ProtocolKind, StringDescriptor},
-    elog,
-    keychain::ProtocolSecret,
+    database::{GunDatabase, StringDescriptor},
+    elog, hwi,
+    keychain::{ProtocolSecret, PROTOCOL_SECRET_BIP85_INDEX},
+    secret::Secret,
+    slip39::{self, Share},
 };
 use anyhow::{anyhow, Context};
 use bdk::{
     bitcoin::{
         secp256k1::Secp256k1,
-        util::bip32::{ExtendedPrivKey, ExtendedPubKey, Fingerprint},
-        Network,
+        util::bip32::{DerivationPath, ExtendedPrivKey, ExtendedPubKey, Fingerprint},
+        Network, Script,
     },
+    blockchain::{AnyBlockchainConfig, Blockchain, ConfigurableBlockchain, EsploraBlockchain},
     database::MemoryDatabase,
     descriptor::{ExtendedDescriptor, IntoWalletDescriptor},
     keys::{
@@ -24,16 +27,51 @@ use bdk::{
     template::{Bip84, Bip84Public},
     KeychainKind, Wallet,
 };
-use miniscript::{Descriptor, DescriptorPublicKey, TranslatePk1};
+use miniscript::{
+    descriptor::Wsh, policy::concrete::Policy as ConcretePolicy, Descriptor, DescriptorPublicKey,
+    TranslatePk1,
+};
 use olivia_secp256k1::fun::hex;
 use serde::Deserialize;
 use std::{fs, io, path::PathBuf, str::FromStr};
 use structopt::StructOpt;
+use zeroize::Zeroizing;
 
 use super::CmdOutput;
 
 pub enum NWords {}
 
+/// What `--language` calls each BIP39 word list.
+pub const SEED_LANGUAGES: [(&str, Language); 9] = [
+    ("english", Language::English),
+    ("chinese-simplified", Language::SimplifiedChinese),
+    ("chinese-traditional", Language::TraditionalChinese),
+    ("czech", Language::Czech),
+    ("french", Language::French),
+    ("italian", Language::Italian),
+    ("japanese", Language::Japanese),
+    ("korean", Language::Korean),
+    ("spanish", Language::Spanish),
+];
+
+fn parse_language(name: &str) -> anyhow::Result<Language> {
+    SEED_LANGUAGES
+        .iter()
+        .find(|(language_name, _)| *language_name == name)
+        .map(|(_, language)| *language)
+        .ok_or_else(|| {
+            anyhow!(
+                "'{}' is not a seed word language (try {})",
+                name,
+                SEED_LANGUAGES
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+}
+
 #[derive(Clone, Debug, StructOpt)]
 pub struct CommonArgs {
     /// The network name
@@ -43,6 +81,43 @@ pub struct CommonArgs {
         name = "bitcoin|regtest|testnet|signet"
     )]
     network: Network,
+    /// The block height the wallet's keys were created at. New seeds default to the current
+    /// height.
+    #[structopt(long)]
+    birthday: Option<u32>,
+    /// The challenge script (hex) of a custom signet. Leave it out for the default signet.
+    #[structopt(long, requires = "esplora-url")]
+    signet_challenge: Option<Script>,
+    /// The esplora server to use instead of the default one for the network.
+    #[structopt(long)]
+    esplora_url: Option<String>,
+}
+
+impl CommonArgs {
+    pub fn new(network: Network, birthday: Option<u32>) -> Self {
+        CommonArgs {
+            network,
+            birthday,
+            signet_challenge: None,
+            esplora_url: None,
+        }
+    }
+
+    /// The default config for the network with the esplora server and signet given.
+    fn config(&self) -> anyhow::Result<Config> {
+        if self.signet_challenge.is_some() && self.network != Network::Signet {
+            return Err(anyhow!(
+                "--signet-challenge only makes sense with --network signet"
+            ));
+        }
+        let mut config = Config::default_config(self.network);
+        if let Some(esplora_url) = &self.esplora_url {
+            let AnyBlockchainConfig::Esplora(esplora_config) = &mut config.blockchain;
+            esplora_config.base_url = esplora_url.clone();
+        }
+        config.signet_challenge = self.signet_challenge.clone();
+        Ok(config)
+    }
 }
 
 #[derive(Clone, Debug, StructOpt)]
@@ -54,12 +129,31 @@ pub enum SetupOpt {
         /// Existing BIP39 seed words file. Use "-" to read words from stdin.
         #[structopt(long, name = "FILE")]
         from_existing: Option<String>,
+        /// Restore the seed words from the SLIP-39 shares made by `gun backup shamir`. You'll be
+        /// asked for the shares one at a time.
+        #[structopt(long, conflicts_with = "FILE")]
+        from_slip39: bool,
         #[structopt(long, default_value = "12", name = "[12|24]")]
         /// The number of BIP39 seed words to use
         n_words: usize,
+        /// The language of the seed words: english (the default), chinese-simplified,
+        /// chinese-traditional, czech, french, italian, japanese, korean or spanish. Existing seed
+        /// words can be in any of them if it's not given.
+        #[structopt(long, parse(try_from_str = parse_language))]
+        language: Option<Language>,
         /// Password protect your coins
         #[structopt(long)]
         use_passphrase: bool,
+        /// A key (in [masterfingerprint/hardened'/derivation'/path']xpub format) that can spend the
+        /// coins on its own once they are --recovery-after blocks old.
+        ///
+        /// The wallet uses a wsh descriptor instead of the usual wpkh one so betting is not
+        /// available. Use `gun recover sweep` to spend with the recovery key.
+        #[structopt(long, requires = "recovery-after")]
+        recovery_key: Option<String>,
+        /// How many blocks coins must be confirmed before the recovery key can spend them.
+        #[structopt(long, requires = "recovery-key")]
+        recovery_after: Option<u32>,
     },
     /// Setup using a output descriptors
     ///
@@ -109,6 +203,75 @@ pub enum SetupOpt {
         /// This is necessary for gun to be able to execute protocols which need auxiliary keys (like gun bet).
         #[structopt(long)]
         import_entropy: bool,
+        /// Generate a random protocol secret so you can bet while the keys stay on the Coldcard.
+        /// Unlike --import-entropy it can't be recovered from the Coldcard so back it up with `gun
+        /// backup`.
+        #[structopt(long, conflicts_with = "import-entropy")]
+        random_protocol_secret: bool,
+    },
+    /// Setup with a hardware wallet plugged in over USB.
+    ///
+    /// This uses HWI (https://github.com/bitcoin-core/HWI) to talk to the device so it must be
+    /// installed. Run `gun signer list-devices` to check gun can see your device.
+    Hww {
+        #[structopt(flatten)]
+        common_args: CommonArgs,
+        /// The fingerprint of the device to use (if more than one is plugged in).
+        #[structopt(long)]
+        fingerprint: Option<Fingerprint>,
+        /// Generate a random protocol secret so you can bet while the keys stay on the device. It
+        /// can't be recovered from the device so back it up with `gun backup`.
+        #[structopt(long)]
+        random_protocol_secret: bool,
+    },
+    /// Setup a multisig wallet from the extended public keys of all the cosigners.
+    ///
+    /// Each key must be in [masterfingerprint/hardened'/derivation'/path']xpub format. The wallet
+    /// uses a wsh(sortedmulti(..)) descriptor. Transactions that don't have enough signatures yet
+    /// are printed out as PSBTs so the other cosigners can sign them. Put the signed PSBTs back
+    /// together with `gun psbt combine`.
+    ///
+    /// Betting isn't supported for multisig wallets.
+    Multi {
+        #[structopt(flatten)]
+        common_args: CommonArgs,
+        /// How many of the keys are needed to spend
+        #[structopt(long)]
+        threshold: usize,
+        /// The cosigners' extended public key descriptors
+        #[structopt(name = "xkey-descriptors", required = true, min_values = 2)]
+        xkeys: Vec<String>,
+        /// Sign for our key by writing PSBTs to this directory
+        #[structopt(long, parse(from_os_str), conflicts_with = "hwi")]
+        psbt_dir: Option<PathBuf>,
+        /// Sign for our key with the hardware wallet with this fingerprint (through HWI)
+        #[structopt(long)]
+        hwi: Option<Fingerprint>,
+    },
+    /// Setup a wallet that spends with a miniscript policy.
+    ///
+    /// The policy is compiled into a wsh(..) descriptor. Each key in it must be in
+    /// [masterfingerprint/hardened'/derivation'/path']xpub format and gets /0/* and /1/* added for
+    /// the external and internal descriptors. For example a 2-of-2 that lets one key spend alone
+    /// after 1000 blocks:
+    ///
+    /// or(pk(KEY_A),and(pk(KEY_B),older(1000)))
+    ///
+    /// Like multisig wallets, transactions our signer can't complete on its own are printed out as
+    /// PSBTs for the other key holders.
+    ///
+    /// Betting isn't supported for policy wallets.
+    Policy {
+        #[structopt(flatten)]
+        common_args: CommonArgs,
+        /// The spending policy
+        policy: String,
+        /// Sign for our keys by writing PSBTs to this directory
+        #[structopt(long, parse(from_os_str), conflicts_with = "hwi")]
+        psbt_dir: Option<PathBuf>,
+        /// Sign for our keys with the hardware wallet with this fingerprint (through HWI)
+        #[structopt(long)]
+        hwi: Option<Fingerprint>,
     },
 }
 
@@ -123,6 +286,78 @@ struct BIP84Export {
     xpub: ExtendedPubKey,
 }
 
+impl SetupOpt {
+    fn common_args(&self) -> &CommonArgs {
+        match self {
+            SetupOpt::Seed { common_args, .. }
+            | SetupOpt::Descriptor { common_args, .. }
+            | SetupOpt::XKey { common_args, .. }
+            | SetupOpt::Coldcard { common_args, .. }
+            | SetupOpt::Hww { common_args, .. }
+            | SetupOpt::Multi { common_args, .. }
+            | SetupOpt::Policy { common_args, .. } => common_args,
+        }
+    }
+}
+
+/// The wallet's birthday if we can know it. Freshly generated keys can't have been used before
+/// the current block.
+fn birthday(cmd: &SetupOpt) -> Option<u32> {
+    let common_args = cmd.common_args();
+    match (cmd, common_args.birthday) {
+        (_, Some(birthday)) => Some(birthday),
+        (
+            SetupOpt::Seed {
+                from_existing: None,
+                from_slip39: false,
+                ..
+            },
+            None,
+        ) => {
+            let config = common_args.config().ok()?;
+            match EsploraBlockchain::from_config(config.blockchain_config())
+                .and_then(|esplora| esplora.get_height())
+            {
+                Ok(height) => Some(height),
+                Err(e) => {
+                    elog!(@recoverable_error "Couldn't get the current block height for the wallet's birthday: {}", e);
+                    None
+                }
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Ask for SLIP-39 shares until there are enough to recover the seed words' entropy.
+fn read_slip39_shares() -> anyhow::Result<Zeroizing<Vec<u8>>> {
+    let mut shares: Vec<Share> = vec![];
+    loop {
+        let words = Zeroizing::new(cmd::read_secret(&format!(
+            "Enter share #{}:",
+            shares.len() + 1
+        ))?);
+        let share = match Share::from_mnemonic(&words) {
+            Ok(share) => share,
+            Err(e) => {
+                elog!(@user_error "That share is invalid: {}. Try again.", e);
+                continue;
+            }
+        };
+        shares.push(share);
+        match slip39::combine_shares(&shares, b"") {
+            Ok(Some(entropy)) => return Ok(entropy),
+            Ok(None) => {
+                elog!(@info "Got {} share(s) of the {} needed.", shares.len(), shares[0].member_threshold)
+            }
+            Err(e) => {
+                shares.pop();
+                elog!(@user_error "That share doesn't go with the others: {}. Try again.", e);
+            }
+        }
+    }
+}
+
 pub fn run_setup(wallet_dir: &std::path::Path, cmd: SetupOpt) -> anyhow::Result<CmdOutput> {
     if wallet_dir.exists() {
         return Err(anyhow!(
@@ -131,15 +366,25 @@ pub fn run_setup(wallet_dir: &std::path::Path, cmd: SetupOpt) -> anyhow::Result<
         ));
     }
     let secp = Secp256k1::<bdk::bitcoin::secp256k1::All>::new();
+    let birthday_height = birthday(&cmd);
 
     let (config, protocol_secret, (external, internal), seed_words_file) = match cmd {
         SetupOpt::Seed {
             common_args,
             from_existing,
+            from_slip39,
             n_words,
+            language,
             use_passphrase,
+            recovery_key,
+            recovery_after,
         } => {
             let mnemonic = match from_existing {
+                None if from_slip39 => {
+                    let entropy = read_slip39_shares()?;
+                    Mnemonic::from_entropy_in(language.unwrap_or(Language::English), &entropy)
+                        .context("the shares don't hold valid seed words")?
+                }
                 Some(existing_words_file) => {
                     let seed_words = match existing_words_file.as_str() {
                         "-" => {
@@ -157,7 +402,11 @@ pub fn run_setup(wallet_dir: &std::path::Path, cmd: SetupOpt) -> anyhow::Result<
                             ))?
                         }
                     };
-                    Mnemonic::parse(&seed_words).context("parsing existing seedwords")?
+                    match language {
+                        Some(language) => Mnemonic::parse_in(language, &seed_words),
+                        None => Mnemonic::parse(&seed_words),
+                    }
+                    .context("parsing existing seedwords")?
                 }
                 None => {
                     let seed_words: GeneratedKey<_, Segwitv0> = Mnemonic::generate((
@@ -166,7 +415,7 @@ pub fn run_setup(wallet_dir: &std::path::Path, cmd: SetupOpt) -> anyhow::Result<
                             24 => WordCount::Words24,
                             _ => return Err(anyhow!("Only 12 or 24 words are supported")),
                         },
-                        Language::English,
+                        language.unwrap_or(Language::English),
                     ))
                     .expect("cannot fail");
                     seed_words.into_key()
@@ -177,10 +426,9 @@ pub fn run_setup(wallet_dir: &std::path::Path, cmd: SetupOpt) -> anyhow::Result<
                 elog!(@warning "If you lose or forget your passphrase, you will lose access to your funds.");
                 elog!(@warning "You MUST store your passphrase with your seed words in order to make a complete backup.");
                 loop {
-                    let passphrase =
-                        rpassword::prompt_password_stderr("Enter your wallet passphrase:")?;
+                    let passphrase = cmd::read_secret("Enter your wallet passphrase:")?;
                     let passphrase_confirmation =
-                        rpassword::prompt_password_stderr("Enter your wallet passphrase again:")?;
+                        cmd::read_secret("Enter your wallet passphrase again:")?;
                     if !passphrase.eq(&passphrase_confirmation) {
                         elog!(@user_error "Mismatching passphrases. Try again.\n");
                     } else {
@@ -208,10 +456,12 @@ pub fn run_setup(wallet_dir: &std::path::Path, cmd: SetupOpt) -> anyhow::Result<
                 );
             }
 
-            let seed_bytes = mnemonic.to_seed(passphrase);
-            let xpriv = ExtendedPrivKey::new_master(common_args.network, &seed_bytes).unwrap();
+            let seed_bytes = Secret::new(mnemonic.to_seed(passphrase));
+            let xpriv =
+                ExtendedPrivKey::new_master(common_args.network, seed_bytes.expose_secret())
+                    .unwrap();
 
-            let bip85_bytes: [u8; 64] = get_bip85_bytes(xpriv, 330, &secp);
+            let bip85_bytes: [u8; 64] = get_bip85_bytes(xpriv, PROTOCOL_SECRET_BIP85_INDEX, &secp)?;
 
             let master_fingerprint = xpriv.fingerprint(&secp);
 
@@ -221,19 +471,52 @@ pub fn run_setup(wallet_dir: &std::path::Path, cmd: SetupOpt) -> anyhow::Result<
                 } else {
                     None
                 },
+                passphrase_source: None,
+                options: Default::default(),
             }];
 
-            let (external, _) = Bip84(xpriv, KeychainKind::External)
-                .into_wallet_descriptor(&secp, common_args.network)?;
-            let (internal, _) = Bip84(xpriv, KeychainKind::Internal)
-                .into_wallet_descriptor(&secp, common_args.network)?;
+            let (external, internal) = match (recovery_key, recovery_after) {
+                (Some(recovery_key), Some(recovery_after)) => {
+                    // BIP48 script type 2 is for p2wsh
+                    let path = DerivationPath::from_str(&format!(
+                        "m/48'/{}'/0'/2'",
+                        coin_type(common_args.network)
+                    ))
+                    .unwrap();
+                    let xpub =
+                        ExtendedPubKey::from_private(&secp, &xpriv.derive_priv(&secp, &path)?);
+                    let primary = format!(
+                        "[{}{}]{}",
+                        master_fingerprint,
+                        path.to_string().trim_start_matches('m'),
+                        xpub
+                    );
+                    let descriptor = |branch: u32| {
+                        set_network(
+                            &format!(
+                                "wsh(or_d(pk({}/{}/*),and_v(v:pk({}/{}/*),older({}))))",
+                                primary, branch, recovery_key, branch, recovery_after
+                            ),
+                            common_args.network,
+                        )
+                    };
+                    (descriptor(0)?, descriptor(1)?)
+                }
+                _ => {
+                    let (external, _) = Bip84(xpriv, KeychainKind::External)
+                        .into_wallet_descriptor(&secp, common_args.network)?;
+                    let (internal, _) = Bip84(xpriv, KeychainKind::Internal)
+                        .into_wallet_descriptor(&secp, common_args.network)?;
+                    (external.to_string(), internal.to_string())
+                }
+            };
             (
                 Config {
                     signers,
-                    ..Config::default_config(common_args.network)
+                    ..common_args.config()?
                 },
                 Some(bip85_bytes),
-                (external.to_string(), Some(internal.to_string())),
+                (external, Some(internal)),
                 Some((sw_file, mnemonic.word_iter().collect::<Vec<_>>().join(" "))),
             )
         }
@@ -250,12 +533,7 @@ pub fn run_setup(wallet_dir: &std::path::Path, cmd: SetupOpt) -> anyhow::Result<
                 MemoryDatabase::default(),
             )?;
 
-            (
-                Config::default_config(common_args.network),
-                None,
-                (external, internal),
-                None,
-            )
+            (common_args.config()?, None, (external, internal), None)
         }
         SetupOpt::XKey {
             common_args,
@@ -264,7 +542,7 @@ pub fn run_setup(wallet_dir: &std::path::Path, cmd: SetupOpt) -> anyhow::Result<
             let external = set_network(&format!("wpkh({}/0/*)", xkey), common_args.network)?;
             let internal = set_network(&format!("wpkh({}/1/*)", xkey), common_args.network)?;
             (
-                Config::default_config(common_args.network),
+                common_args.config()?,
                 None,
                 (external, Some(internal)),
                 None,
@@ -274,6 +552,7 @@ pub fn run_setup(wallet_dir: &std::path::Path, cmd: SetupOpt) -> anyhow::Result<
             common_args,
             coldcard_sd_dir,
             import_entropy,
+            random_protocol_secret,
         } => {
             let bip85_bytes = if import_entropy {
                 let entropy_file = coldcard_sd_dir.join("drv-hex-idx330.txt");
@@ -301,6 +580,8 @@ pub fn run_setup(wallet_dir: &std::path::Path, cmd: SetupOpt) -> anyhow::Result<
                 let mut bip85_bytes = [0u8; 64];
                 bip85_bytes.copy_from_slice(&hex_vec[..]);
                 Some(bip85_bytes)
+            } else if random_protocol_secret {
+                Some(generate_protocol_secret())
             } else {
                 None
             };
@@ -333,18 +614,161 @@ pub fn run_setup(wallet_dir: &std::path::Path, cmd: SetupOpt) -> anyhow::Result<
 
             let signers = vec![GunSigner::PsbtDir {
                 path: coldcard_sd_dir,
+                options: Default::default(),
             }];
 
             (
                 Config {
                     signers,
-                    ..Config::default_config(common_args.network)
+                    ..common_args.config()?
                 },
                 bip85_bytes,
                 (external.to_string(), Some(internal.to_string())),
                 None,
             )
         }
+        SetupOpt::Hww {
+            common_args,
+            fingerprint,
+            random_protocol_secret,
+        } => {
+            let device = hwi::find_device(fingerprint)?;
+            let fingerprint = device.fingerprint.ok_or(anyhow!(
+                "HWI didn't tell us the fingerprint of {} ({})",
+                device.model,
+                device.path
+            ))?;
+            elog!(@info "Using {} ({}) with fingerprint {}", device.model, device.path, fingerprint);
+            let path =
+                DerivationPath::from_str(&format!("m/84'/{}'/0'", coin_type(common_args.network)))
+                    .unwrap();
+            let xpub = hwi::get_xpub(fingerprint, common_args.network, &path)?;
+
+            let (external, _) = Bip84Public(xpub, fingerprint, KeychainKind::External)
+                .into_wallet_descriptor(&secp, common_args.network)?;
+            let (internal, _) = Bip84Public(xpub, fingerprint, KeychainKind::Internal)
+                .into_wallet_descriptor(&secp, common_args.network)?;
+
+            let signers = vec![GunSigner::Hwi {
+                fingerprint,
+                options: Default::default(),
+            }];
+
+            (
+                Config {
+                    signers,
+                    ..common_args.config()?
+                },
+                random_protocol_secret.then(generate_protocol_secret),
+                (external.to_string(), Some(internal.to_string())),
+                None,
+            )
+        }
+        SetupOpt::Multi {
+            common_args,
+            threshold,
+            xkeys,
+            psbt_dir,
+            hwi,
+        } => {
+            if threshold == 0 || threshold > xkeys.len() {
+                return Err(anyhow!(
+                    "threshold must be between 1 and the number of keys ({})",
+                    xkeys.len()
+                ));
+            }
+            let multi = |branch: u32| {
+                let keys = xkeys
+                    .iter()
+                    .map(|xkey| format!("{}/{}/*", xkey, branch))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                set_network(
+                    &format!("wsh(sortedmulti({},{}))", threshold, keys),
+                    common_args.network,
+                )
+            };
+            let external = multi(0)?;
+            let internal = multi(1)?;
+
+            let signers = match (psbt_dir, hwi) {
+                (Some(path), _) => vec![GunSigner::PsbtDir {
+                    path,
+                    options: Default::default(),
+                }],
+                (None, Some(fingerprint)) => vec![GunSigner::Hwi {
+                    fingerprint,
+                    options: Default::default(),
+                }],
+                (None, None) => {
+                    elog!(@info "No signer for our key was given so the wallet will be watch-only. Add one later with `gun config signer add`.");
+                    vec![]
+                }
+            };
+
+            (
+                Config {
+                    signers,
+                    ..common_args.config()?
+                },
+                None,
+                (external, Some(internal)),
+                None,
+            )
+        }
+        SetupOpt::Policy {
+            common_args,
+            policy,
+            psbt_dir,
+            hwi,
+        } => {
+            let policy = ConcretePolicy::<DescriptorPublicKey>::from_str(&policy)
+                .context("parsing the policy")
+                .error_kind(cmd::ErrorKind::User)?;
+            let external = compile_policy(&policy, 0, common_args.network)?;
+            let internal = compile_policy(&policy, 1, common_args.network)?;
+
+            let signers = match (psbt_dir, hwi) {
+                (Some(path), _) => {
+                    elog!(@info "gun can't tell which keys the PSBT signer has so make sure it can sign for enough of them");
+                    vec![GunSigner::PsbtDir {
+                        path,
+                        options: Default::default(),
+                    }]
+                }
+                (None, Some(fingerprint)) => {
+                    let ours = |pk: &DescriptorPublicKey| pk.master_fingerprint() == fingerprint;
+                    if !policy.keys().into_iter().any(|pk| ours(pk)) {
+                        return Err(anyhow!(
+                            "None of the keys in the policy are from the signer with fingerprint {}",
+                            fingerprint
+                        ))
+                        .error_kind(cmd::ErrorKind::User);
+                    }
+                    if !can_satisfy(&policy, &ours) {
+                        elog!(@info "The signer with fingerprint {} can't spend on its own so the other key holders will have to sign too. Put their signed PSBTs together with `gun psbt combine`.", fingerprint);
+                    }
+                    vec![GunSigner::Hwi {
+                        fingerprint,
+                        options: Default::default(),
+                    }]
+                }
+                (None, None) => {
+                    elog!(@info "No signer for our keys was given so the wallet will be watch-only. Add one later with `gun config signer add`.");
+                    vec![]
+                }
+            };
+
+            (
+                Config {
+                    signers,
+                    ..common_args.config()?
+                },
+                None,
+                (external, Some(internal)),
+                None,
+            )
+        }
     };
 
     std::fs::create_dir(&wallet_dir)?;
@@ -356,7 +780,7 @@ pub fn run_setup(wallet_dir: &std::path::Path, cmd: SetupOpt) -> anyhow::Result<
     );
 
     if let Some(protocol_secret) = protocol_secret {
-        gun_db.insert_entity(ProtocolKind::Bet, ProtocolSecret::Bytes(protocol_secret))?;
+        gun_db.set_bet_protocol_secret(ProtocolSecret::Bytes(protocol_secret))?;
     }
 
     let _ = ExtendedDescriptor::parse_descriptor(&secp, &external)
@@ -369,7 +793,13 @@ pub fn run_setup(wallet_dir: &std::path::Path, cmd: SetupOpt) -> anyhow::Result<
         gun_db.insert_entity(KeychainKind::Internal, StringDescriptor(internal))?;
     }
 
-    cmd::write_config(&config_file, config)?;
+    cmd::write_config(
+        &config_file,
+        Config {
+            birthday_height,
+            ..config
+        },
+    )?;
 
     if let Some((path, content)) = seed_words_file {
         std::fs::write(path, content)?;
@@ -379,6 +809,23 @@ pub fn run_setup(wallet_dir: &std::path::Path, cmd: SetupOpt) -> anyhow::Result<
     Ok(CmdOutput::None)
 }
 
+/// A protocol secret for a wallet whose keys are somewhere gun can't derive it from.
+fn generate_protocol_secret() -> [u8; 64] {
+    use rand::RngCore;
+    let mut bytes = [0u8; 64];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    elog!(@warning "The protocol secret is random so it can't be recovered from your device. Back it up with `gun backup`.");
+    bytes
+}
+
+/// The BIP44 coin type for the network.
+fn coin_type(network: Network) -> u32 {
+    match network {
+        Network::Bitcoin => 0,
+        _ => 1,
+    }
+}
+
 fn set_network(descriptor: &str, network: Network) -> anyhow::Result<String> {
     let descriptor = Descriptor::<DescriptorPublicKey>::from_str(descriptor)?;
     Ok(descriptor
@@ -391,3 +838,110 @@ fn set_network(descriptor: &str, network: Network) -> anyhow::Result<String> {
         })
         .to_string())
 }
+
+/// Compile the policy into a wsh descriptor for `branch` (0 external, 1 internal).
+fn compile_policy(
+    policy: &ConcretePolicy<DescriptorPublicKey>,
+    branch: u32,
+    network: Network,
+) -> anyhow::Result<String> {
+    let policy = policy.translate_pk(&mut |pk: &DescriptorPublicKey| match pk {
+        DescriptorPublicKey::XPub(_) if !pk.to_string().ends_with("/*") => {
+            DescriptorPublicKey::from_str(&format!("{}/{}/*", pk, branch))
+        }
+        _ => Ok(pk.clone()),
+    })?;
+    let miniscript = policy
+        .compile::<Segwitv0>()
+        .context("compiling the policy")
+        .error_kind(cmd::ErrorKind::User)?;
+    let descriptor = Descriptor::Wsh(Wsh::new(miniscript)?);
+    set_network(&descriptor.to_string(), network)
+}
+
+/// Whether the keys `ours` says we have can spend with the policy without anyone else. Timelocks
+/// count as satisfiable since we only have to wait but hash preimages don't.
+fn can_satisfy(
+    policy: &ConcretePolicy<DescriptorPublicKey>,
+    ours: &impl Fn(&DescriptorPublicKey) -> bool,
+) -> bool {
+    match policy {
+        ConcretePolicy::Trivial | ConcretePolicy::After(_) | ConcretePolicy::Older(_) => true,
+        ConcretePolicy::Key(pk) => ours(pk),
+        ConcretePolicy::And(subs) => subs.iter().all(|sub| can_satisfy(sub, ours)),
+        ConcretePolicy::Or(subs) => subs.iter().any(|(_, sub)| can_satisfy(sub, ours)),
+        ConcretePolicy::Threshold(k, subs) => {
+            subs.iter().filter(|sub| can_satisfy(sub, ours)).count() >= *k
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn seed_words_roundtrip_in_every_language() {
+        let dir = std::env::temp_dir().join(format!("gun-seed-test-{}", rand::random::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, language) in SEED_LANGUAGES {
+            assert_eq!(parse_language(name).unwrap(), language);
+            let mnemonic = Mnemonic::from_entropy_in(language, &[0x5a; 32]).unwrap();
+            // the way setup writes seed.txt
+            let words = mnemonic.word_iter().collect::<Vec<_>>().join(" ");
+            assert_eq!(Mnemonic::parse_in(language, &words).unwrap(), mnemonic);
+
+            fs::write(dir.join("seed.txt"), &words).unwrap();
+            let loaded = cmd::load_seed_words(&dir).unwrap();
+            assert_eq!(loaded.language(), language, "{}", name);
+            assert_eq!(loaded.to_seed("hunter2"), mnemonic.to_seed("hunter2"));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(parse_language("klingon").is_err());
+    }
+
+    #[test]
+    fn policy_compiles_with_branches_and_matches_signers() {
+        let secp = Secp256k1::new();
+        let xkey = |seed: u8| {
+            let xpriv = ExtendedPrivKey::new_master(Network::Testnet, &[seed; 32]).unwrap();
+            let fingerprint = xpriv.fingerprint(&secp);
+            let path = DerivationPath::from_str("m/48'/1'/0'/2'").unwrap();
+            let xpub =
+                ExtendedPubKey::from_private(&secp, &xpriv.derive_priv(&secp, &path).unwrap());
+            (
+                fingerprint,
+                format!("[{}/48'/1'/0'/2']{}", fingerprint, xpub),
+            )
+        };
+        let (alice, alice_key) = xkey(1);
+        let (bob, bob_key) = xkey(2);
+        let policy = ConcretePolicy::<DescriptorPublicKey>::from_str(&format!(
+            "or(pk({}),and(pk({}),older(1000)))",
+            alice_key, bob_key
+        ))
+        .unwrap();
+
+        let external = compile_policy(&policy, 0, Network::Regtest).unwrap();
+        let internal = compile_policy(&policy, 1, Network::Regtest).unwrap();
+        assert!(external.starts_with("wsh("));
+        assert_eq!(external.matches("/0/*").count(), 2);
+        assert_eq!(internal.matches("/1/*").count(), 2);
+        assert!(ExtendedDescriptor::parse_descriptor(&secp, &external).is_ok());
+
+        let signer = |fingerprint: Fingerprint| {
+            move |pk: &DescriptorPublicKey| pk.master_fingerprint() == fingerprint
+        };
+        assert!(can_satisfy(&policy, &signer(alice)));
+        // bob can spend alone once the timelock is up
+        assert!(can_satisfy(&policy, &signer(bob)));
+        let thresh = ConcretePolicy::<DescriptorPublicKey>::from_str(&format!(
+            "thresh(2,pk({}),pk({}))",
+            alice_key, bob_key
+        ))
+        .unwrap();
+        assert!(!can_satisfy(&thresh, &signer(alice)));
+        assert!(can_satisfy(&thresh, &|_: &DescriptorPublicKey| true));
+    }
+}
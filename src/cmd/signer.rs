@@ -0,0 +1,39 @@
+use super::{Cell, CmdOutput};
+use crate::hwi;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum SignerOpt {
+    /// List the hardware wallets that can be used as signers (via HWI).
+    ListDevices,
+}
+
+pub fn run_signer_cmd(opt: SignerOpt) -> anyhow::Result<CmdOutput> {
+    match opt {
+        SignerOpt::ListDevices => {
+            let rows = hwi::enumerate()?
+                .into_iter()
+                .map(|device| {
+                    let status = match &device.error {
+                        Some(error) => error.clone(),
+                        None if device.needs_pin_sent || device.needs_passphrase_sent => {
+                            "locked".into()
+                        }
+                        None => "ready".into(),
+                    };
+                    vec![
+                        Cell::string(device.device_type),
+                        Cell::string(device.model),
+                        Cell::maybe_string(device.fingerprint),
+                        Cell::string(device.path),
+                        Cell::string(status),
+                    ]
+                })
+                .collect();
+            Ok(CmdOutput::table(
+                vec!["type", "model", "fingerprint", "path", "status"],
+                rows,
+            ))
+        }
+    }
+}
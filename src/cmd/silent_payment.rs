@@ -0,0 +1,223 @@
+use super::{Cell, CmdOutput};
+use crate::{
+    cmd::{self, WithErrorKind},
+    config::Config,
+    confirm::Operation,
+    elog, esplora,
+    silent_payments::scan::{spend_found_outputs, FoundOutput, ScanKeys},
+    wallet::GunWallet,
+};
+use anyhow::anyhow;
+use bdk::{
+    bitcoin::{Address, Amount},
+    blockchain::Blockchain,
+    wallet::AddressIndex,
+};
+use structopt::StructOpt;
+
+/// The BIP-352 account the wallet receives silent payments to
+const ACCOUNT: u32 = 0;
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum SilentPaymentOpt {
+    /// Start receiving silent payments
+    ///
+    /// Works out the wallet's silent payment keys and keeps the scan key so blocks can be scanned
+    /// for payments without the wallet's passphrase. Only wallets set up with seed words can do
+    /// it.
+    Enable {
+        /// The first block to scan. Nothing can have been sent to the address before it was made
+        /// so it's the current tip unless you've given the address out before.
+        #[structopt(long)]
+        from_height: Option<u32>,
+    },
+    /// Show the wallet's silent payment address
+    Address,
+    /// Look for silent payments in the blocks since the last scan
+    Scan {
+        /// Stop at this height instead of the tip
+        #[structopt(long)]
+        to_height: Option<u32>,
+    },
+    /// List the silent payments that have been found
+    List,
+    /// Spend all the silent payments that have been found
+    ///
+    /// They're sent to --to or to a new address in the wallet. The wallet can't spend them any
+    /// other way.
+    Spend {
+        /// The address to send them to instead of the wallet
+        #[structopt(long)]
+        to: Option<Address>,
+        #[structopt(flatten)]
+        fee_args: cmd::FeeArgs,
+        /// Print the resulting transaction out in hex instead of broadcasting it.
+        #[structopt(long)]
+        print_tx: bool,
+        /// Don't prompt for confirmation before broadcasting.
+        #[structopt(short, long)]
+        yes: bool,
+    },
+}
+
+/// The silent payments that have been found and not spent.
+pub fn unspent_found_outputs(wallet: &GunWallet) -> Vec<FoundOutput> {
+    wallet
+        .gun_db()
+        .list_entities_print_error::<FoundOutput>()
+        .map(|(_, output)| output)
+        .filter(|output| output.spent_by.is_none())
+        .collect()
+}
+
+fn scan_keys(wallet: &GunWallet) -> anyhow::Result<ScanKeys> {
+    wallet
+        .gun_db()
+        .get_entity::<ScanKeys>(ACCOUNT)?
+        .ok_or(anyhow!(
+            "Silent payments aren't turned on. Run `gun silent-payment enable` first."
+        ))
+        .error_kind(cmd::ErrorKind::User)
+}
+
+fn found_rows(found: impl IntoIterator<Item = FoundOutput>) -> Vec<Vec<Cell>> {
+    found
+        .into_iter()
+        .map(|output| {
+            vec![
+                Cell::string(output.outpoint),
+                Cell::Amount(Amount::from_sat(output.txout.value)),
+                output
+                    .height
+                    .map(|height| Cell::Int(height.into()))
+                    .unwrap_or(Cell::Empty),
+            ]
+        })
+        .collect()
+}
+
+pub fn run_silent_payment_cmd(
+    wallet: &GunWallet,
+    config: &Config,
+    opt: SilentPaymentOpt,
+) -> anyhow::Result<CmdOutput> {
+    let gun_db = wallet.gun_db();
+    match opt {
+        SilentPaymentOpt::Enable { from_height } => {
+            if gun_db.get_entity::<ScanKeys>(ACCOUNT)?.is_some() {
+                return Err(anyhow!("Silent payments are already turned on"))
+                    .error_kind(cmd::ErrorKind::User);
+            }
+            let master_xkey = wallet
+                .master_key()
+                .ok_or(anyhow!(
+                    "Only wallets set up with seed words can receive silent payments"
+                ))
+                .error_kind(cmd::ErrorKind::User)?
+                .master_xkey()?;
+            let from_height = match from_height {
+                Some(from_height) => from_height,
+                None => wallet.blockchain()?.get_height()?,
+            };
+            let keys = ScanKeys::derive(&master_xkey, config.network, from_height)?;
+            let address = keys.address();
+            gun_db.insert_entity(ACCOUNT, keys)?;
+            elog!(@info "Run `gun silent-payment scan` to look for payments to it");
+            Ok(CmdOutput::EmphasisedItem {
+                main: ("address", Cell::string(address)),
+                other: vec![],
+            })
+        }
+        SilentPaymentOpt::Address => Ok(CmdOutput::EmphasisedItem {
+            main: ("address", Cell::string(scan_keys(wallet)?.address())),
+            other: vec![],
+        }),
+        SilentPaymentOpt::Scan { to_height } => {
+            let keys = scan_keys(wallet)?;
+            let to_height = match to_height {
+                Some(to_height) => to_height,
+                None => wallet.blockchain()?.get_height()?,
+            };
+            if keys.next_height > to_height {
+                elog!(@info "Already scanned up to {}", to_height);
+                return Ok(CmdOutput::None);
+            }
+            elog!(@info "Scanning blocks {} to {} for silent payments", keys.next_height, to_height);
+            let mut progress = keys.clone();
+            let mut found_now = vec![];
+            wallet.scan_silent_payments(
+                &esplora::select_url(config),
+                &keys,
+                to_height,
+                |height, found| {
+                    for output in found {
+                        gun_db.insert_entity(output.outpoint, output.clone())?;
+                        found_now.push(output);
+                    }
+                    // save as we go so an interrupted scan picks up where it left off
+                    progress.next_height = height + 1;
+                    gun_db.insert_entity(ACCOUNT, progress.clone())
+                },
+            )?;
+            if found_now.is_empty() {
+                elog!(@info "No silent payments found");
+            }
+            Ok(CmdOutput::table(
+                vec!["outpoint", "value", "height"],
+                found_rows(found_now),
+            ))
+        }
+        SilentPaymentOpt::List => Ok(CmdOutput::table(
+            vec!["outpoint", "value", "height"],
+            found_rows(
+                gun_db
+                    .list_entities_print_error::<FoundOutput>()
+                    .map(|(_, output)| output),
+            ),
+        )),
+        SilentPaymentOpt::Spend {
+            to,
+            fee_args,
+            print_tx,
+            yes,
+        } => {
+            let keys = scan_keys(wallet)?;
+            let to = match to {
+                Some(to) => {
+                    crate::script_kind::check_address(&to, config.network)
+                        .error_kind(cmd::ErrorKind::User)?;
+                    to.script_pubkey()
+                }
+                None => wallet
+                    .bdk_wallet()
+                    .get_address(AddressIndex::New)?
+                    .script_pubkey(),
+            };
+            let found = unspent_found_outputs(wallet);
+            let master_xkey = wallet
+                .master_key()
+                .ok_or(anyhow!(
+                    "Only wallets set up with seed words can spend silent payments"
+                ))
+                .error_kind(cmd::ErrorKind::User)?
+                .master_xkey()?;
+            let spend_secret = ScanKeys::spend_secret(&master_xkey, keys.network)?;
+            let psbt = spend_found_outputs(&found, &spend_secret, to, &fee_args.fee, wallet)?;
+            let (output, txid) = cmd::decide_to_broadcast(
+                config,
+                wallet.blockchain()?,
+                psbt,
+                Operation::Send,
+                yes,
+                print_tx,
+            )?;
+            if let Some(txid) = txid {
+                for mut output in found {
+                    output.spent_by = Some(txid);
+                    gun_db.insert_entity(output.outpoint, output)?;
+                }
+            }
+            Ok(output)
+        }
+    }
+}
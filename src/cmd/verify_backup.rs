@@ -0,0 +1,120 @@
+use super::CmdOutput;
+use crate::{
+    cmd,
+    config::{Config, GunSigner},
+    database::StringDescriptor,
+    elog,
+    wallet::GunWallet,
+};
+use anyhow::anyhow;
+use bdk::{
+    bitcoin::{secp256k1::Secp256k1, util::bip32::ExtendedPrivKey},
+    KeychainKind,
+};
+use olivia_core::chrono::{Duration, NaiveDateTime, Utc};
+use rand::seq::index::sample;
+use std::path::Path;
+use unicode_normalization::UnicodeNormalization;
+
+/// How many words we ask for.
+const N_CHECKED_WORDS: usize = 3;
+
+/// Nag people who haven't verified their backup in this long.
+pub fn backup_verification_overdue(config: &Config) -> bool {
+    let uses_seed_words = config
+        .signers
+        .iter()
+        .any(|signer| matches!(signer, GunSigner::SeedWordsFile { .. }));
+    if !uses_seed_words {
+        return false;
+    }
+    match config.last_backup_verified {
+        Some(last) => {
+            let last = NaiveDateTime::from_timestamp(last as i64, 0);
+            Utc::now().naive_utc() - last > Duration::days(182)
+        }
+        None => true,
+    }
+}
+
+pub fn run_verify_backup(wallet_dir: &Path, wallet: &GunWallet) -> anyhow::Result<CmdOutput> {
+    let config_path = wallet_dir.join("config.json");
+    let mut config = cmd::load_config(&config_path)?;
+    let passphrase_fingerprint = config
+        .signers
+        .iter()
+        .find_map(|signer| match signer {
+            GunSigner::SeedWordsFile {
+                passphrase_fingerprint,
+                ..
+            } => Some(*passphrase_fingerprint),
+            _ => None,
+        })
+        .ok_or(anyhow!(
+            "This wallet doesn't use seed words so there is no seed backup to verify"
+        ))?;
+
+    let mnemonic = cmd::load_seed_words(wallet_dir)?;
+    let secp = Secp256k1::signing_only();
+
+    // Make sure the words we quiz on are actually the ones the wallet uses
+    match passphrase_fingerprint {
+        Some(fingerprint) => loop {
+            let passphrase = cmd::read_secret("Enter your wallet passphrase: ")?;
+            let xpriv = ExtendedPrivKey::new_master(config.network, &mnemonic.to_seed(passphrase))?;
+            if xpriv.fingerprint(&secp) == fingerprint {
+                break;
+            }
+            elog!(@user_error "That passphrase doesn't match the wallet. Try again.");
+        },
+        None => {
+            let fingerprint = ExtendedPrivKey::new_master(config.network, &mnemonic.to_seed(""))?
+                .fingerprint(&secp);
+            let external = wallet
+                .gun_db()
+                .get_entity::<StringDescriptor>(KeychainKind::External)?
+                .ok_or(anyhow!("external descriptor is missing from the database"))?;
+            if !external.0.contains(&format!("[{}", fingerprint)) {
+                return Err(anyhow!(
+                    "The seed words in {} don't match the wallet's descriptor!",
+                    wallet_dir.join("seed.txt").display()
+                ));
+            }
+        }
+    }
+
+    let words = mnemonic.word_iter().collect::<Vec<_>>();
+    let mut positions = sample(&mut rand::thread_rng(), words.len(), N_CHECKED_WORDS).into_vec();
+    positions.sort_unstable();
+
+    elog!(@info "Get out your seed word backup. Your answers won't be shown as you type them.");
+    let mut wrong = vec![];
+    for position in positions {
+        let answer = cmd::read_secret(&format!("Word #{}: ", position + 1))?;
+        // accented words could be typed composed or not
+        if answer
+            .trim()
+            .to_lowercase()
+            .nfkd()
+            .ne(words[position].nfkd())
+        {
+            wrong.push(position + 1);
+        }
+    }
+
+    if !wrong.is_empty() {
+        return Err(anyhow!(
+            "Word(s) {} didn't match. Check your backup carefully!",
+            wrong
+                .iter()
+                .map(|position| format!("#{}", position))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+
+    config.last_backup_verified = Some(Utc::now().timestamp() as u64);
+    cmd::write_config(&config_path, config)?;
+    elog!(@celebration "Your backup matches the wallet's seed words.");
+    Ok(CmdOutput::None)
+}
@@ -1,16 +1,169 @@
 use super::*;
-use crate::{amount_ext::FromCliStr, betting::BetState, cmd, elog, item};
+use crate::{
+    amount_ext::FromCliStr,
+    betting::{Bet, BetId, BetOrProp, BetState, OfferedBet},
+    cmd,
+    database::TxMemo,
+    elog, item,
+    payjoin::PayjoinUri,
+    script_kind,
+    silent_payments::{Destination, SilentPaymentAddress},
+    spv::TxProof,
+};
 use bdk::{
-    bitcoin::{Address, OutPoint, Script, Transaction, Txid},
-    blockchain::EsploraBlockchain,
+    bitcoin::{Address, OutPoint, Script, Transaction, TxOut, Txid},
     database::Database,
-    wallet::{coin_selection::CoinSelectionAlgorithm, tx_builder::TxBuilderContext, AddressIndex},
+    wallet::{
+        coin_selection::{CoinSelectionAlgorithm, LargestFirstCoinSelection},
+        tx_builder::TxBuilderContext,
+        AddressIndex,
+    },
     KeychainKind, LocalUtxo, SignOptions, TxBuilder,
 };
-use std::collections::HashMap;
+use std::{collections::HashMap, path::PathBuf, str::FromStr};
 use structopt::StructOpt;
+use term_table::{row::Row, Table};
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct BalanceOpt {
+    /// Break down the value in bets by "tag" or "state" instead
+    #[structopt(long)]
+    by: Option<BalanceBy>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BalanceBy {
+    Tag,
+    State,
+}
+
+impl FromStr for BalanceBy {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> anyhow::Result<Self> {
+        Ok(match string {
+            "tag" => BalanceBy::Tag,
+            "state" => BalanceBy::State,
+            _ => return Err(anyhow!("'{}' is not one of tag or state", string)),
+        })
+    }
+}
+
+/// How much of our value is in the bet. Won bets are worth the whole output.
+fn bet_state_value(bet_state: &BetState) -> Amount {
+    match bet_state {
+        BetState::Proposed { local_proposal } => local_proposal.proposal.value,
+        BetState::Won { bet, .. } | BetState::Claimed { bet, .. } => bet.joint_output_value,
+        BetState::Offered {
+            bet: OfferedBet(bet),
+            ..
+        }
+        | BetState::Included { bet, .. }
+        | BetState::Lost { bet, .. }
+        | BetState::Refunded { bet, .. } => bet.local_value,
+        BetState::Canceled { pre_cancel, .. } => match pre_cancel {
+            BetOrProp::Proposal(local_proposal) => local_proposal.proposal.value,
+            BetOrProp::Bet(bet)
+            | BetOrProp::OfferedBet {
+                bet: OfferedBet(bet),
+                ..
+            } => bet.local_value,
+        },
+    }
+}
+
+fn balance_by(wallet: &GunWallet, by: BalanceBy) -> CmdOutput {
+    let bets = wallet
+        .gun_db()
+        .list_entities_print_error::<BetState>()
+        .map(|(_, bet_state)| bet_state)
+        .collect::<Vec<_>>();
+
+    match by {
+        BalanceBy::State => {
+            let mut by_state: Vec<(&'static str, u64, Amount)> = vec![];
+            for bet_state in &bets {
+                let value = bet_state_value(bet_state);
+                match by_state
+                    .iter_mut()
+                    .find(|(name, _, _)| *name == bet_state.name())
+                {
+                    Some((_, count, total)) => {
+                        *count += 1;
+                        *total += value;
+                    }
+                    None => by_state.push((bet_state.name(), 1, value)),
+                }
+            }
+            let rows = by_state
+                .into_iter()
+                .map(|(name, count, total)| {
+                    vec![Cell::string(name), Cell::Int(count), Cell::Amount(total)]
+                })
+                .collect();
+            CmdOutput::table(vec!["state", "bets", "value"], rows)
+        }
+        BalanceBy::Tag => {
+            // tag -> (bets, in-use, locked, unclaimed)
+            let mut by_tag: Vec<(String, u64, Amount, Amount, Amount)> = vec![];
+            for bet_state in &bets {
+                let (in_use, locked, unclaimed) = match bet_state {
+                    BetState::Proposed { .. } | BetState::Offered { .. } => {
+                        (bet_state_value(bet_state), Amount::ZERO, Amount::ZERO)
+                    }
+                    BetState::Included { .. } => {
+                        (Amount::ZERO, bet_state_value(bet_state), Amount::ZERO)
+                    }
+                    BetState::Won { .. } => {
+                        (Amount::ZERO, Amount::ZERO, bet_state_value(bet_state))
+                    }
+                    // the rest are finished with
+                    _ => continue,
+                };
+                let tags = match bet_state.tags() {
+                    [] => vec!["(untagged)".to_string()],
+                    tags => tags.to_vec(),
+                };
+                // a bet with several tags counts towards each of them
+                for tag in tags {
+                    match by_tag.iter_mut().find(|(name, ..)| *name == tag) {
+                        Some((_, count, tag_in_use, tag_locked, tag_unclaimed)) => {
+                            *count += 1;
+                            *tag_in_use += in_use;
+                            *tag_locked += locked;
+                            *tag_unclaimed += unclaimed;
+                        }
+                        None => by_tag.push((tag, 1, in_use, locked, unclaimed)),
+                    }
+                }
+            }
+            by_tag.sort_by(|a, b| a.0.cmp(&b.0));
+            let rows = by_tag
+                .into_iter()
+                .map(|(tag, count, in_use, locked, unclaimed)| {
+                    vec![
+                        Cell::String(tag),
+                        Cell::Int(count),
+                        Cell::Amount(in_use),
+                        Cell::Amount(locked),
+                        Cell::Amount(unclaimed),
+                    ]
+                })
+                .collect();
+            CmdOutput::table(vec!["tag", "bets", "in-use", "locked", "unclaimed"], rows)
+        }
+    }
+}
 
-pub fn run_balance(wallet: &GunWallet, sync: bool) -> anyhow::Result<CmdOutput> {
+pub fn run_balance(
+    wallet: &GunWallet,
+    config: &Config,
+    sync: bool,
+    opt: BalanceOpt,
+) -> anyhow::Result<CmdOutput> {
+    if let Some(by) = opt.by {
+        return Ok(balance_by(wallet, by));
+    }
     let (in_bet, unclaimed) = wallet
         .gun_db()
         .list_entities_print_error::<BetState>()
@@ -66,15 +219,28 @@ pub fn run_balance(wallet: &GunWallet, sync: bool) -> anyhow::Result<CmdOutput>
         },
     );
 
-    if !sync && (confirmed + unconfirmed + unclaimed + in_bet + in_use == Amount::ZERO) {
+    // BDK doesn't know about these. They're spent with `gun silent-payment spend`.
+    let silent_payments = super::silent_payment::unspent_found_outputs(wallet)
+        .into_iter()
+        .map(|output| Amount::from_sat(output.txout.value))
+        .fold(Amount::ZERO, |sum, value| sum + value);
+
+    if !sync
+        && (confirmed + unconfirmed + unclaimed + silent_payments + in_bet + in_use == Amount::ZERO)
+    {
         elog!(@suggestion "Remember to sync gun with -s or --sync to ensure balances are up to date. i.e. run `gun -s balance` ");
     }
 
+    if cmd::backup_verification_overdue(config) {
+        elog!(@suggestion "It's been a while since you checked your seed words backup. Run `gun verify-backup` to make sure it's still good.");
+    }
+
     Ok(item! {
         "confirmed" => Cell::Amount(confirmed),
         "unconfirmed" => Cell::Amount(unconfirmed),
         "unclaimed" => Cell::Amount(unclaimed),
-        "available" => Cell::Amount(confirmed + unconfirmed + unclaimed),
+        "silent-payments" => Cell::Amount(silent_payments),
+        "available" => Cell::Amount(confirmed + unconfirmed + unclaimed + silent_payments),
         "locked" => Cell::Amount(in_bet),
         "in-use" => Cell::Amount(in_use),
     })
@@ -97,6 +263,11 @@ pub enum AddressOpt {
     },
     /// Show details of an address
     Show { address: Address },
+    /// Show how far past the last used address each keychain has given addresses out
+    ///
+    /// Syncing stops looking after `stop_gap` unused addresses in a row. Change addresses given
+    /// out in proposals are tracked and looked past automatically.
+    GapStatus,
 }
 
 fn list_keychain_addresses(
@@ -145,7 +316,11 @@ fn list_keychain_addresses(
     Ok(rows)
 }
 
-pub fn get_address(wallet: &GunWallet, addr_opt: AddressOpt) -> anyhow::Result<CmdOutput> {
+pub fn get_address(
+    wallet: &GunWallet,
+    config: &Config,
+    addr_opt: AddressOpt,
+) -> anyhow::Result<CmdOutput> {
     match addr_opt {
         AddressOpt::New => {
             let address = wallet.bdk_wallet().get_address(AddressIndex::New)?;
@@ -217,6 +392,49 @@ pub fn get_address(wallet: &GunWallet, addr_opt: AddressOpt) -> anyhow::Result<C
                 "keychain" => keychain,
             })
         }
+        AddressOpt::GapStatus => {
+            let stop_gap = config.blockchain_config().stop_gap;
+            let exposed = wallet.exposed_change();
+            let mut rows = vec![];
+            for keychain in [KeychainKind::External, KeychainKind::Internal] {
+                let revealed = wallet.bdk_wallet().database().get_last_index(keychain)?;
+                let last_used = wallet.last_used_index(keychain)?;
+                let first_unused = last_used.map(|index| index + 1).unwrap_or(0);
+                let unused = revealed
+                    .map(|revealed| (revealed + 1).saturating_sub(first_unused))
+                    .unwrap_or(0);
+                let (name, exposed_unused, sync_gap) = match keychain {
+                    KeychainKind::External => ("external", Cell::Empty, stop_gap),
+                    KeychainKind::Internal => (
+                        "internal",
+                        Cell::Int(exposed.range(first_unused..).count() as u64),
+                        wallet.internal_sync_gap(stop_gap)?,
+                    ),
+                };
+                if keychain == KeychainKind::External && unused as usize > stop_gap {
+                    elog!(@warning "{} receive addresses in a row haven't been used but syncing stops looking after {}. Coins sent to the later ones won't be found after a restore unless stop_gap is raised.", unused, stop_gap);
+                }
+                rows.push(vec![
+                    Cell::string(name),
+                    Cell::maybe_string(revealed),
+                    Cell::maybe_string(last_used),
+                    Cell::Int(unused.into()),
+                    exposed_unused,
+                    Cell::Int(sync_gap as u64),
+                ]);
+            }
+            Ok(CmdOutput::table(
+                vec![
+                    "keychain",
+                    "revealed",
+                    "last-used",
+                    "unused",
+                    "exposed",
+                    "sync-gap",
+                ],
+                rows,
+            ))
+        }
     }
 }
 
@@ -231,7 +449,7 @@ fn index_utxos(utxos: &[LocalUtxo]) -> HashMap<Script, Vec<LocalUtxo>> {
     map
 }
 
-fn index_txos(transactions: &[Transaction]) -> HashMap<Script, Vec<Transaction>> {
+pub(crate) fn index_txos(transactions: &[Transaction]) -> HashMap<Script, Vec<Transaction>> {
     let mut map: HashMap<_, Vec<_>> = HashMap::new();
     for txn in transactions {
         for out in &txn.output {
@@ -250,8 +468,11 @@ pub struct SendOpt {
     /// or specific amount e.g. All the following mean 1 million satoshis: 1000000sat, 1_000_000,
     /// 0.01btc, 1M, 1_000k, 10_000bit, 10_000uBTC.
     value: ValueChoice,
-    /// The address to send the coins to
-    to: Address,
+    /// The address to send the coins to. It can be a silent payment address (sp1...).
+    to: Destination,
+    /// Do a payjoin (BIP-78) with the receiver using their BIP-21 URI (it must have a pj= endpoint)
+    #[structopt(long)]
+    payjoin: Option<PayjoinUri>,
     #[structopt(flatten)]
     spend_opt: SpendOpt,
 }
@@ -276,14 +497,87 @@ pub struct SpendOpt {
     /// Print the resulting transaction out in hex instead of broadcasting it.
     #[structopt(long)]
     print_tx: bool,
+    /// Don't sign or broadcast anything. Instead show the estimated fee of the transaction at
+    /// several different confirmation targets.
+    #[structopt(long)]
+    preview: bool,
+    /// A note to remember what the transaction is for. It's shown in `gun tx list`.
+    #[structopt(long)]
+    memo: Option<String>,
+    /// Go over the `spending-limit` in the config (you'll be asked to make sure).
+    #[structopt(long)]
+    override_limit: bool,
+    /// Refuse to sign unless the transaction built has this txid. With `deterministic-tx-seed` in
+    /// the config it checks this device built the same transaction as another one.
+    #[structopt(long)]
+    expect_txid: Option<Txid>,
 }
 
+/// The confirmation targets compared by `--preview`.
+const PREVIEW_TARGETS: [u32; 4] = [1, 3, 6, 24];
+
 impl SpendOpt {
+    /// Spend options for when there's no one to ask about anything but the fee and whether to
+    /// prompt.
+    pub(crate) fn with_fee(fee_args: cmd::FeeArgs, yes: bool, memo: Option<String>) -> Self {
+        SpendOpt {
+            fee_args,
+            spend_in_use: false,
+            no_spend_unclaimed: false,
+            bump_claiming: false,
+            yes,
+            print_tx: false,
+            preview: false,
+            memo,
+            override_limit: false,
+            expect_txid: None,
+        }
+    }
+
     pub fn spend_coins<D: BatchDatabase, Cs: CoinSelectionAlgorithm<D>, Ctx: TxBuilderContext>(
         self,
         wallet: &GunWallet,
-        mut builder: TxBuilder<'_, EsploraBlockchain, D, Cs, Ctx>,
+        config: &Config,
+        builder: TxBuilder<'_, (), D, Cs, Ctx>,
+        payjoin: Option<PayjoinUri>,
+        silent_payment: Option<&SilentPaymentAddress>,
     ) -> anyhow::Result<CmdOutput> {
+        Ok(self
+            .spend(wallet, config, builder, payjoin, silent_payment)?
+            .0)
+    }
+
+    /// Like [`spend_coins`](Self::spend_coins) but also returns the txid if the transaction was
+    /// broadcast.
+    pub(crate) fn spend<D: BatchDatabase, Cs: CoinSelectionAlgorithm<D>, Ctx: TxBuilderContext>(
+        self,
+        wallet: &GunWallet,
+        config: &Config,
+        builder: TxBuilder<'_, (), D, Cs, Ctx>,
+        payjoin: Option<PayjoinUri>,
+        silent_payment: Option<&SilentPaymentAddress>,
+    ) -> anyhow::Result<(CmdOutput, Option<Txid>)> {
+        match wallet.tx_seed() {
+            // the default coin selection falls back to picking coins at random
+            Some(_) => self.build_and_send(
+                wallet,
+                config,
+                builder.coin_selection(LargestFirstCoinSelection),
+                payjoin,
+                silent_payment,
+            ),
+            None => self.build_and_send(wallet, config, builder, payjoin, silent_payment),
+        }
+    }
+
+    fn build_and_send<D: BatchDatabase, Cs: CoinSelectionAlgorithm<D>, Ctx: TxBuilderContext>(
+        self,
+        wallet: &GunWallet,
+        config: &Config,
+        mut builder: TxBuilder<'_, (), D, Cs, Ctx>,
+        payjoin: Option<PayjoinUri>,
+        silent_payment: Option<&SilentPaymentAddress>,
+    ) -> anyhow::Result<(CmdOutput, Option<Txid>)> {
         let SpendOpt {
             fee_args,
             spend_in_use,
@@ -291,6 +585,10 @@ impl SpendOpt {
             bump_claiming,
             yes,
             print_tx,
+            preview,
+            memo,
+            override_limit,
+            expect_txid,
         } = self;
 
         builder
@@ -300,42 +598,101 @@ impl SpendOpt {
         let in_use = wallet.gun_db().currently_used_utxos(&[])?;
 
         if !spend_in_use && !in_use.is_empty() {
-            eprintln!(
+            elog!(
+                @info
                 "note that {} utxos are not availble becuase they are in use",
                 in_use.len()
             );
             builder.unspendable(in_use);
         }
 
-        fee_args
-            .fee
-            .apply_to_builder(wallet.bdk_wallet().client(), &mut builder)?;
+        fee_args.fee.apply_to_builder(wallet, &mut builder)?;
+        let tx_locktime = wallet.apply_locktime_policy(&mut builder)?;
 
+        // previews don't need the real silent payment output (or the keys to work it out)
+        let silent_payment = silent_payment.filter(|_| !preview);
         let (mut psbt, claiming_bet_ids) = if !no_spend_unclaimed {
             wallet
-                .spend_won_bets(builder, bump_claiming)
+                .spend_won_bets(builder, bump_claiming, silent_payment)
                 .context("couldn't create transaction")?
                 .expect("Won't be None since builder we pass in is not manually_selected_only")
         } else {
-            let (psbt, _) = builder.finish().context("couldn't create transaction")?;
+            let (mut psbt, _) = builder.finish().context("couldn't create transaction")?;
+            if let Some(address) = silent_payment {
+                wallet.set_silent_payment_output(&mut psbt, address)?;
+            }
             (psbt, vec![])
         };
 
+        fee_args.fee.check_psbt(&psbt)?;
+
+        if preview {
+            return Ok((preview_fees(wallet, &fee_args.fee, &psbt)?, None));
+        }
+        if let Some(expected) = expect_txid {
+            let txid = psbt.unsigned_tx.txid();
+            if txid != expected {
+                return Err(anyhow!(
+                    "The transaction built has txid {} rather than {} so it won't be signed",
+                    txid,
+                    expected
+                ));
+            }
+        }
+        wallet.record_locktime(&psbt, tx_locktime)?;
+
+        let sending = confirm::value_leaving(&psbt) + psbt.fee().0;
+        if !check_spending_limit(wallet, config, sending, override_limit)? {
+            return Ok((CmdOutput::None, None));
+        }
+
         let finalized = wallet
             .bdk_wallet()
             .sign(&mut psbt, SignOptions::default())?;
 
-        assert!(finalized, "transaction must be finalized at this point");
+        if !finalized {
+            assert!(
+                wallet.is_multisig(),
+                "transaction must be finalized at this point"
+            );
+            elog!(@info "The transaction needs more signatures. Pass this PSBT to your cosigners and put their PSBTs together with `gun psbt combine`.");
+            return Ok((
+                CmdOutput::EmphasisedItem {
+                    main: ("psbt", Cell::string(psbt)),
+                    other: vec![],
+                },
+                None,
+            ));
+        }
+
+        if let Some(uri) = payjoin {
+            elog!(@info "Asking the receiver for a payjoin at {}", uri.endpoint);
+            match wallet.payjoin(&uri, &psbt) {
+                Ok(proposal) => {
+                    elog!(@celebration "The receiver added their coins to the transaction");
+                    psbt = proposal;
+                }
+                Err(e) => {
+                    elog!(@recoverable_error "Payjoin failed: {:#}", e);
+                    elog!(@info "Falling back to a regular payment");
+                }
+            }
+        }
 
         let (output, txid) = cmd::decide_to_broadcast(
-            wallet.bdk_wallet().network(),
-            wallet.bdk_wallet().client(),
+            config,
+            wallet.blockchain()?,
             psbt,
+            Operation::Send,
             yes,
             print_tx,
         )?;
 
         if let Some(txid) = txid {
+            wallet.record_spend(txid, sending)?;
+            if let Some(memo) = memo {
+                wallet.gun_db().insert_entity(txid, TxMemo(memo))?;
+            }
             if !print_tx {
                 for bet_id in claiming_bet_ids {
                     if let Err(e) = wallet.take_next_action(bet_id, false) {
@@ -349,17 +706,102 @@ impl SpendOpt {
             }
         }
 
-        Ok(output)
+        Ok((output, txid.filter(|_| !print_tx)))
     }
 }
 
-pub fn run_send(wallet: &GunWallet, send_opt: SendOpt) -> anyhow::Result<CmdOutput> {
+/// Whether sending `sending` is within the config's spending limit or the user has agreed to go over
+/// it with `--override-limit`.
+fn check_spending_limit(
+    wallet: &GunWallet,
+    config: &Config,
+    sending: Amount,
+    override_limit: bool,
+) -> anyhow::Result<bool> {
+    let limit = match &config.spending_limit {
+        Some(limit) => limit,
+        None => return Ok(true),
+    };
+    let breaches = limit.breaches(&wallet.recent_spends()?, sending, crate::unix_now());
+    if breaches.is_empty() {
+        return Ok(true);
+    }
+    for breach in &breaches {
+        elog!(@warning "Sending {} would go over your spending limit: {}", sending, breach);
+    }
+    if !override_limit {
+        return Err(anyhow!(
+            "this transaction would go over your spending limit (use --override-limit to send it anyway)"
+        ))
+        .error_kind(cmd::ErrorKind::User);
+    }
+    // --yes doesn't answer this one
+    Ok(cmd::read_yn(
+        "Are you sure you want to go over your spending limit",
+    ))
+}
+
+fn preview_fees(wallet: &GunWallet, planned: &FeeSpec, psbt: &Psbt) -> anyhow::Result<CmdOutput> {
+    let (fee, feerate, _) = psbt.fee();
+    let (vsize, _) = psbt.vsize();
+    let mut rows = vec![vec![
+        Cell::string(format!("{} (planned)", planned)),
+        Cell::string(format!("{:.3}", feerate.as_sat_vb())),
+        Cell::Amount(fee),
+        Cell::Int(vsize),
+    ]];
+
+    for target in PREVIEW_TARGETS {
+        let spec = FeeSpec::from(crate::FeeTarget::Height(target));
+        let feerate = spec
+            .feerate(wallet)?
+            .expect("height based fees always have a feerate");
+        let fee = Amount::from_sat((feerate.as_sat_vb() * vsize as f32).ceil() as u64);
+        rows.push(vec![
+            Cell::string(spec),
+            Cell::string(format!("{:.3}", feerate.as_sat_vb())),
+            Cell::Amount(fee),
+            Cell::Int(vsize),
+        ]);
+    }
+
+    Ok(CmdOutput::table(
+        vec!["fee-spec", "feerate", "fee", "vsize"],
+        rows,
+    ))
+}
+
+pub fn run_send(
+    wallet: &GunWallet,
+    config: &Config,
+    send_opt: SendOpt,
+) -> anyhow::Result<CmdOutput> {
     cmd::ensure_not_watch_only(wallet)?;
     let SendOpt {
         to,
         value,
+        payjoin,
         spend_opt,
     } = send_opt;
+
+    if let Destination::Address(address) = &to {
+        script_kind::check_address(address, wallet.bdk_wallet().network())
+            .error_kind(cmd::ErrorKind::User)?;
+    }
+    if let Some(uri) = &payjoin {
+        if Destination::Address(uri.address.clone()) != to {
+            return Err(anyhow!("the payjoin URI is for {} not {}", uri.address, to));
+        }
+        if let (Some(uri_amount), ValueChoice::Amount(amount)) = (uri.amount, &value) {
+            if uri_amount != *amount {
+                return Err(anyhow!(
+                    "the payjoin URI asks for {} but you are sending {}",
+                    uri_amount,
+                    amount
+                ));
+            }
+        }
+    }
     let mut builder = wallet.bdk_wallet().build_tx();
 
     match value {
@@ -367,7 +809,158 @@ pub fn run_send(wallet: &GunWallet, send_opt: SendOpt) -> anyhow::Result<CmdOutp
         ValueChoice::Amount(amount) => builder.add_recipient(to.script_pubkey(), amount.as_sat()),
     };
 
-    spend_opt.spend_coins(wallet, builder)
+    spend_opt.spend_coins(wallet, config, builder, payjoin, to.silent_payment())
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct SendManyOpt {
+    /// A CSV file with `address,amount[,label]` on each line or a JSON file with a list of
+    /// `{"address": .., "amount": .., "label": ..}`. Amounts are written like in `gun send`.
+    file: PathBuf,
+    #[structopt(flatten)]
+    spend_opt: SpendOpt,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Payment {
+    pub address: Address,
+    pub amount: Amount,
+    pub label: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct JsonPayment {
+    address: String,
+    amount: String,
+    #[serde(default)]
+    label: Option<String>,
+}
+
+fn parse_payment(
+    address: &str,
+    amount: &str,
+    label: Option<&str>,
+    network: Network,
+) -> anyhow::Result<Payment> {
+    let address = Address::from_str(address.trim())
+        .with_context(|| format!("'{}' is not a valid address", address.trim()))?;
+    script_kind::check_address(&address, network)?;
+    let amount = Amount::from_cli_str(amount.trim())?;
+    if amount == Amount::ZERO {
+        return Err(anyhow!("can't send zero to {}", address));
+    }
+    Ok(Payment {
+        address,
+        amount,
+        label: label
+            .map(|label| label.trim().to_string())
+            .filter(|label| !label.is_empty()),
+    })
+}
+
+/// Parse and validate a list of payments. Each problem is reported with the row it's on.
+pub fn parse_payments(contents: &str, network: Network) -> anyhow::Result<Vec<Payment>> {
+    let payments = if contents.trim_start().starts_with('[') {
+        serde_json::from_str::<Vec<JsonPayment>>(contents)
+            .context("parsing JSON payments")?
+            .iter()
+            .enumerate()
+            .map(|(i, payment)| {
+                parse_payment(
+                    &payment.address,
+                    &payment.amount,
+                    payment.label.as_deref(),
+                    network,
+                )
+                .with_context(|| format!("payment #{}", i + 1))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?
+    } else {
+        let mut payments = vec![];
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = line.splitn(3, ',').collect::<Vec<_>>();
+            if i == 0 && fields[0].trim().eq_ignore_ascii_case("address") {
+                // header
+                continue;
+            }
+            let payment = match &fields[..] {
+                [address, amount] => parse_payment(address, amount, None, network),
+                [address, amount, label] => parse_payment(address, amount, Some(label), network),
+                _ => Err(anyhow!("expected 'address,amount[,label]'")),
+            }
+            .with_context(|| format!("line {}", i + 1))?;
+            payments.push(payment);
+        }
+        payments
+    };
+
+    if payments.is_empty() {
+        return Err(anyhow!("there are no payments in the file"));
+    }
+
+    let mut seen = HashMap::new();
+    for (i, payment) in payments.iter().enumerate() {
+        if let Some(first) = seen.insert(payment.address.script_pubkey(), i) {
+            return Err(anyhow!(
+                "{} is paid more than once (payments #{} and #{})",
+                payment.address,
+                first + 1,
+                i + 1
+            ));
+        }
+    }
+
+    payments
+        .iter()
+        .try_fold(Amount::ZERO, |total, payment| {
+            total.checked_add(payment.amount)
+        })
+        .ok_or(anyhow!(
+            "the payments add up to more than there are bitcoins"
+        ))?;
+
+    Ok(payments)
+}
+
+pub fn run_send_many(
+    wallet: &GunWallet,
+    config: &Config,
+    opt: SendManyOpt,
+) -> anyhow::Result<CmdOutput> {
+    cmd::ensure_not_watch_only(wallet)?;
+    let SendManyOpt { file, spend_opt } = opt;
+    let contents = std::fs::read_to_string(&file)
+        .with_context(|| format!("reading payments from {}", file.display()))?;
+    let payments = parse_payments(&contents, wallet.bdk_wallet().network())?;
+
+    let mut table = Table::new();
+    let mut total = Amount::ZERO;
+    table.add_row(Row::new(vec!["address", "amount", "label"]));
+    for payment in &payments {
+        table.add_row(Row::new(vec![
+            payment.address.to_string(),
+            cmd::format_amount(payment.amount),
+            payment.label.clone().unwrap_or_default(),
+        ]));
+        total += payment.amount;
+    }
+    table.add_row(Row::new(vec![
+        format!("{} payments", payments.len()),
+        cmd::format_amount(total),
+        "".into(),
+    ]));
+    eprintln!("{}", table.render());
+
+    let mut builder = wallet.bdk_wallet().build_tx();
+    for payment in &payments {
+        builder.add_recipient(payment.address.script_pubkey(), payment.amount.as_sat());
+    }
+
+    spend_opt.spend_coins(wallet, config, builder, None, None)
 }
 
 #[derive(StructOpt, Debug, Clone)]
@@ -378,15 +971,151 @@ pub enum TransactionOpt {
     Show {
         /// Transaction id of the transaction you want to inspect.
         txid: Txid,
+        /// Also show the raw transaction in hex
+        #[structopt(long)]
+        raw: bool,
     },
+    /// Attach a note to a transaction. Leave out the memo to remove it.
+    Memo { txid: Txid, memo: Option<String> },
+    /// Get a proof that a transaction is confirmed which can be checked later without trusting
+    /// a block explorer.
+    Proof { txid: Txid },
+    /// Check a proof made with `gun tx proof`.
+    VerifyProof {
+        /// The file containing the proof
+        proof: PathBuf,
+        /// A file of consecutive block headers in hex (one per line) that the proof's block must
+        /// be in.
+        #[structopt(long, requires = "first-height")]
+        headers: Option<PathBuf>,
+        /// The height of the first block in --headers
+        #[structopt(long, requires = "headers")]
+        first_height: Option<u32>,
+    },
+}
+
+/// How the wallet's transactions and outputs relate to bets.
+#[derive(Default)]
+pub(crate) struct BetLinks {
+    /// The bet each transaction belongs to and what it does in it
+    pub txs: HashMap<Txid, (BetId, &'static str)>,
+    /// The outputs that pay to both parties of a bet
+    pub joint_outputs: HashMap<OutPoint, BetId>,
+}
+
+impl BetLinks {
+    pub fn load(wallet: &GunWallet) -> Self {
+        let mut links = BetLinks::default();
+        for (bet_id, bet_state) in wallet.gun_db().list_entities_print_error::<BetState>() {
+            let mut add_bet = |bet: &Bet| {
+                let txid = bet.tx().txid();
+                links.txs.insert(txid, (bet_id, "funding"));
+                links
+                    .joint_outputs
+                    .insert(OutPoint::new(txid, bet.vout), bet_id);
+            };
+            match bet_state {
+                BetState::Proposed { .. } => {}
+                BetState::Offered {
+                    bet: OfferedBet(bet),
+                    ..
+                }
+                | BetState::Included { bet, .. }
+                | BetState::Won { bet, .. }
+                | BetState::Lost { bet, .. } => add_bet(&bet),
+                BetState::Claimed { bet, txid, .. } => {
+                    add_bet(&bet);
+                    links.txs.insert(txid, (bet_id, "claim"));
+                }
+                BetState::Refunded { bet, txid, .. } => {
+                    add_bet(&bet);
+                    links.txs.insert(txid, (bet_id, "refund"));
+                }
+                BetState::Canceled {
+                    cancel_txid,
+                    i_intend_cancel,
+                    ..
+                } => {
+                    if i_intend_cancel {
+                        links.txs.insert(cancel_txid, (bet_id, "cancel"));
+                    }
+                }
+            }
+        }
+        links
+    }
+
+    fn describe_tx(&self, txid: &Txid) -> Option<String> {
+        self.txs.get(txid).map(|(bet_id, role)| match *role {
+            "funding" => format!("bet {}", bet_id),
+            role => format!("bet {} {}", bet_id, role),
+        })
+    }
+}
+
+/// Memos for each transaction. Transactions that belong to bets get one automatically unless
+/// the user has written their own.
+fn tx_memos(wallet: &GunWallet) -> anyhow::Result<HashMap<Txid, String>> {
+    let bet_links = BetLinks::load(wallet);
+    let mut memos = bet_links
+        .txs
+        .keys()
+        .filter_map(|txid| Some((*txid, bet_links.describe_tx(txid)?)))
+        .collect::<HashMap<_, _>>();
+    for memo in wallet.gun_db().list_entities::<TxMemo>() {
+        let (txid, TxMemo(memo)) = memo?;
+        memos.insert(txid, memo);
+    }
+    Ok(memos)
 }
 
-pub fn run_transaction_cmd(wallet: &GunWallet, opt: TransactionOpt) -> anyhow::Result<CmdOutput> {
+/// One line about an input or output: its address, value, whether it's ours and whether it's a
+/// bet output.
+fn describe_txout(
+    wallet: &GunWallet,
+    bet_links: &BetLinks,
+    outpoint: OutPoint,
+    txout: Option<&TxOut>,
+) -> anyhow::Result<Cell> {
+    let mut parts = vec![outpoint.to_string()];
+    if let Some(txout) = txout {
+        parts.push(
+            Address::from_script(&txout.script_pubkey, wallet.bdk_wallet().network())
+                .map(|address| address.to_string())
+                .unwrap_or_else(|| txout.script_pubkey.asm()),
+        );
+        parts.push(cmd::format_amount(Amount::from_sat(txout.value)));
+        if let Some((keychain, index)) = wallet
+            .bdk_wallet()
+            .database()
+            .get_path_from_script_pubkey(&txout.script_pubkey)?
+        {
+            let keychain = match keychain {
+                KeychainKind::External => "external",
+                KeychainKind::Internal => "internal",
+            };
+            parts.push(format!("mine:{}/{}", keychain, index));
+        }
+    } else {
+        parts.push("(unknown)".into());
+    }
+    if let Some(bet_id) = bet_links.joint_outputs.get(&outpoint) {
+        parts.push(format!("bet:{}", bet_id));
+    }
+    Ok(Cell::string(parts.join(" ")))
+}
+
+pub fn run_transaction_cmd(
+    wallet: &GunWallet,
+    config: &Config,
+    opt: TransactionOpt,
+) -> anyhow::Result<CmdOutput> {
     use TransactionOpt::*;
 
     match opt {
         List => {
             let mut txns = wallet.bdk_wallet().list_transactions(false)?;
+            let mut memos = tx_memos(wallet)?;
 
             txns.sort_unstable_by_key(|x| {
                 std::cmp::Reverse(
@@ -413,22 +1142,64 @@ pub fn run_transaction_cmd(wallet: &GunWallet, opt: TransactionOpt) -> anyhow::R
                             .unwrap_or(Cell::Empty),
                         Cell::SignedAmount(SignedAmount::from_sat(bal)),
                         Cell::maybe_string(tx.fee),
+                        memos
+                            .remove(&tx.txid)
+                            .map(Cell::string)
+                            .unwrap_or(Cell::Empty),
                     ]
                 })
                 .collect();
 
             Ok(CmdOutput::table(
-                vec!["txid", "height", "conftime", "value", "fee"],
+                vec!["txid", "height", "conftime", "value", "fee", "memo"],
                 rows,
             ))
         }
-        Show { txid } => {
+        Show { txid, raw } => {
             let tx = wallet
                 .bdk_wallet()
-                .list_transactions(false)?
+                .list_transactions(true)?
                 .into_iter()
                 .find(|tx| tx.txid == txid)
                 .ok_or(anyhow!("Transaction {} not found", txid))?;
+            let memo = tx_memos(wallet)?.remove(&txid);
+            let bet_links = BetLinks::load(wallet);
+            let transaction = tx
+                .transaction
+                .as_ref()
+                .expect("we asked for the raw transaction");
+            let bdk_db = wallet.bdk_wallet().database();
+
+            let mut inputs = vec![];
+            for txin in &transaction.input {
+                let prev_tx = bdk_db.get_raw_tx(&txin.previous_output.txid)?;
+                let prevout = prev_tx
+                    .as_ref()
+                    .and_then(|prev_tx| prev_tx.output.get(txin.previous_output.vout as usize));
+                inputs.push(describe_txout(
+                    wallet,
+                    &bet_links,
+                    txin.previous_output,
+                    prevout,
+                )?);
+            }
+
+            let mut outputs = vec![];
+            for (vout, txout) in transaction.output.iter().enumerate() {
+                outputs.push(describe_txout(
+                    wallet,
+                    &bet_links,
+                    OutPoint::new(txid, vout as u32),
+                    Some(txout),
+                )?);
+            }
+            let raw = if raw {
+                Cell::String(crate::hex::encode(
+                    &bdk::bitcoin::consensus::encode::serialize(transaction),
+                ))
+            } else {
+                Cell::Empty
+            };
 
             Ok(item! {
                 "txid" => Cell::String(tx.txid.to_string()),
@@ -442,7 +1213,65 @@ pub fn run_transaction_cmd(wallet: &GunWallet, opt: TransactionOpt) -> anyhow::R
                             .map(|x| Cell::Int(x.height.into()))
                             .unwrap_or(Cell::Empty),
                 "fee" => tx.fee.map(|x| Cell::Amount(Amount::from_sat(x)))
-                    .unwrap_or(Cell::Empty)
+                    .unwrap_or(Cell::Empty),
+                "memo" => memo.map(Cell::string).unwrap_or(Cell::Empty),
+                "bet" => bet_links.txs.get(&txid).map(|(bet_id, role)| Cell::string(format!("{} ({})", bet_id, role))).unwrap_or(Cell::Empty),
+                "inputs" => Cell::List(inputs),
+                "outputs" => Cell::List(outputs),
+                "raw" => raw,
+            })
+        }
+        Memo { txid, memo } => {
+            let gun_db = wallet.gun_db();
+            match memo {
+                Some(memo) => gun_db.insert_entity(txid, TxMemo(memo))?,
+                None => {
+                    gun_db.remove_entity::<TxMemo>(txid)?;
+                }
+            }
+            Ok(CmdOutput::None)
+        }
+        Proof { txid } => {
+            let proof = TxProof::fetch(
+                wallet.http_client(),
+                &config.blockchain_config().base_url,
+                txid,
+            )?;
+            Ok(CmdOutput::Json(serde_json::to_value(&proof)?))
+        }
+        VerifyProof {
+            proof,
+            headers,
+            first_height,
+        } => {
+            let proof = std::fs::read_to_string(&proof)
+                .with_context(|| format!("reading proof from {}", proof.display()))?;
+            let proof = serde_json::from_str::<TxProof>(&proof).context("parsing proof")?;
+            let (_, header) = proof.verify()?;
+            let block_hash = header.block_hash();
+
+            let confirmations = match headers.zip(first_height) {
+                Some((headers, first_height)) => {
+                    let headers = std::fs::read_to_string(&headers)
+                        .with_context(|| format!("reading headers from {}", headers.display()))?
+                        .lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty())
+                        .map(String::from)
+                        .collect::<Vec<_>>();
+                    Cell::Int(proof.verify_in_chain(&headers, first_height)?.into())
+                }
+                None => {
+                    elog!(@suggestion "The proof is valid but it hasn't been checked against a chain of headers. Pass them with --headers and --first-height.");
+                    Cell::Empty
+                }
+            };
+
+            Ok(item! {
+                "txid" => Cell::string(proof.txid),
+                "block-hash" => Cell::string(block_hash),
+                "height" => Cell::Int(proof.block_height.into()),
+                "confirmations" => confirmations,
             })
         }
     }
@@ -455,23 +1284,38 @@ pub enum UtxoOpt {
     List,
     /// Show details about a particular UTXO
     Show { outpoint: OutPoint },
+    /// Stop a stuck proposal or offer from keeping a UTXO in use
+    ///
+    /// The bet is given a chance to progress first. If it still has the UTXO in use it's
+    /// forgotten. Offers the proposer could still take are never released -- cancel them with `gun
+    /// bet cancel` instead.
+    Release {
+        outpoint: OutPoint,
+        /// Don't ask before forgetting a proposal that could still get offers
+        #[structopt(short, long)]
+        yes: bool,
+    },
 }
 
 pub fn run_utxo_cmd(wallet: &GunWallet, opt: UtxoOpt) -> anyhow::Result<CmdOutput> {
     match opt {
         UtxoOpt::List => {
             let in_use_utxos = wallet.gun_db().currently_used_utxos(&[])?;
-            let wallet = wallet.bdk_wallet();
-            let rows = wallet
+            let bdk_wallet = wallet.bdk_wallet();
+            let is_confirmed = |txid: &Txid| {
+                bdk_wallet
+                    .query_db(|db| db.get_tx(txid, false))
+                    .unwrap_or(None)
+                    .map(|tx| Cell::String(tx.confirmation_time.is_some().to_string()))
+                    .unwrap_or(Cell::Empty)
+            };
+            let mut rows = bdk_wallet
                 .list_unspent()?
                 .into_iter()
                 .map(|utxo| {
-                    let tx = wallet
-                        .query_db(|db| db.get_tx(&utxo.outpoint.txid, false))
-                        .unwrap_or(None);
                     vec![
                         Cell::string(utxo.outpoint),
-                        Address::from_script(&utxo.txout.script_pubkey, wallet.network())
+                        Address::from_script(&utxo.txout.script_pubkey, bdk_wallet.network())
                             .map(|address| Cell::String(address.to_string()))
                             .unwrap_or(Cell::Empty),
                         Cell::Amount(Amount::from_sat(utxo.txout.value)),
@@ -479,14 +1323,41 @@ pub fn run_utxo_cmd(wallet: &GunWallet, opt: UtxoOpt) -> anyhow::Result<CmdOutpu
                             KeychainKind::Internal => "internal",
                             KeychainKind::External => "external",
                         }),
-                        tx.map(|tx| Cell::String(tx.confirmation_time.is_some().to_string()))
-                            .unwrap_or(Cell::Empty),
+                        is_confirmed(&utxo.outpoint.txid),
                         Cell::string(in_use_utxos.contains(&utxo.outpoint)),
+                        Cell::string("wallet"),
                     ]
                 })
-                .collect();
+                .collect::<Vec<_>>();
+
+            // won bets can be spent like any other coin of ours
+            rows.extend(
+                wallet
+                    .gun_db()
+                    .list_entities_print_error::<BetState>()
+                    .filter_map(|(bet_id, bet_state)| match bet_state {
+                        BetState::Won { bet, .. } => Some((bet_id, bet)),
+                        _ => None,
+                    })
+                    .map(|(bet_id, bet)| {
+                        let outpoint = bet.outpoint();
+                        vec![
+                            Cell::string(outpoint),
+                            Address::from_script(
+                                &bet.joint_output.descriptor().script_pubkey(),
+                                bdk_wallet.network(),
+                            )
+                            .map(|address| Cell::String(address.to_string()))
+                            .unwrap_or(Cell::Empty),
+                            Cell::Amount(bet.joint_output_value),
+                            Cell::Empty,
+                            is_confirmed(&outpoint.txid),
+                            Cell::string(in_use_utxos.contains(&outpoint)),
+                            Cell::string(format!("bet {}", bet_id)),
+                        ]
+                    }),
+            );
 
-            // TODO: list won bet utxos
             Ok(CmdOutput::table(
                 vec![
                     "outpoint",
@@ -495,15 +1366,16 @@ pub fn run_utxo_cmd(wallet: &GunWallet, opt: UtxoOpt) -> anyhow::Result<CmdOutpu
                     "keychain",
                     "confirmed",
                     "in-use",
+                    "source",
                 ],
                 rows,
             ))
         }
         UtxoOpt::Show { outpoint } => {
-            let utxo = wallet
-                .bdk_wallet()
-                .query_db(|db| db.get_utxo(&outpoint))?
-                .ok_or(anyhow!("UTXO {} not in wallet database", outpoint))?;
+            let utxo = match wallet.bdk_wallet().query_db(|db| db.get_utxo(&outpoint))? {
+                Some(utxo) => utxo,
+                None => return show_bet_utxo(wallet, outpoint),
+            };
             let script_pubkey = utxo.txout.script_pubkey.clone();
 
             let tx = wallet
@@ -534,7 +1406,6 @@ pub fn run_utxo_cmd(wallet: &GunWallet, opt: UtxoOpt) -> anyhow::Result<CmdOutpu
                 .currently_used_utxos(&[])?
                 .contains(&utxo.outpoint);
 
-            // TODO: show utxos that are associated with won bets
             Ok(item! {
                 "outpoint" => Cell::String(utxo.outpoint.to_string()),
                 "value" => Cell::Amount(Amount::from_sat(utxo.txout.value)),
@@ -550,9 +1421,130 @@ pub fn run_utxo_cmd(wallet: &GunWallet, opt: UtxoOpt) -> anyhow::Result<CmdOutpu
                     KeychainKind::Internal => "internal",
                 }.into()),
                 "in-use" => Cell::string(in_use),
+                "source" => Cell::string("wallet"),
             })
         }
+        UtxoOpt::Release { outpoint, yes } => {
+            let holders = wallet
+                .gun_db()
+                .list_entities_print_error::<BetState>()
+                .filter(|(_, bet_state)| bet_state.reserved_utxos().contains(&outpoint))
+                .map(|(bet_id, _)| bet_id)
+                .collect::<Vec<_>>();
+            if holders.is_empty() {
+                return Err(anyhow!("{} isn't in use by any bet", outpoint));
+            }
+            let mut released = vec![];
+            for bet_id in holders {
+                if release_reservation(wallet, bet_id, outpoint, yes)? {
+                    released.push(bet_id);
+                }
+            }
+            Ok(CmdOutput::table(
+                vec!["released-by"],
+                released
+                    .into_iter()
+                    .map(|bet_id| vec![Cell::Int(bet_id.into())])
+                    .collect(),
+            ))
+        }
+    }
+}
+
+/// Make the proposal or offer `bet_id` stop keeping `outpoint` in use if that's safe. Returns
+/// whether it no longer does.
+pub(crate) fn release_reservation(
+    wallet: &GunWallet,
+    bet_id: BetId,
+    outpoint: OutPoint,
+    yes: bool,
+) -> anyhow::Result<bool> {
+    // the state machine may be able to sort it out properly
+    if let Err(e) = wallet.take_next_action(bet_id, false) {
+        elog!(@recoverable_error "Couldn't progress bet {}: {}", bet_id, e);
+    }
+    let bet_state = match wallet.gun_db().get_entity::<BetState>(bet_id)? {
+        Some(bet_state) => bet_state,
+        None => return Ok(true),
+    };
+    if !bet_state.reserved_utxos().contains(&outpoint) {
+        elog!(@info "Bet {} is {} now so it doesn't keep {} in use", bet_id, bet_state.name(), outpoint);
+        return Ok(true);
+    }
+    let unspent = wallet
+        .bdk_wallet()
+        .list_unspent()?
+        .iter()
+        .any(|utxo| utxo.outpoint == outpoint);
+    match bet_state {
+        BetState::Offered { .. } if unspent => {
+            return Err(anyhow!(
+                "The proposer could still take bet {} and spend {}. Cancel it with `gun bet cancel {}` instead.",
+                bet_id,
+                outpoint,
+                bet_id
+            ))
+        }
+        BetState::Proposed { .. }
+            if unspent
+                && !yes
+                && !cmd::read_yn(&format!(
+                    "Someone could still make an offer on proposal {}. Forget it anyway",
+                    bet_id
+                )) =>
+        {
+            return Ok(false)
+        }
+        _ => {}
     }
+    wallet.gun_db().remove_entity::<BetState>(bet_id)?;
+    elog!(@info "Forgot {} bet {} so {} isn't in use anymore", bet_state.name(), bet_id, outpoint);
+    Ok(true)
+}
+
+/// Show the output of one of our bets as if it were one of the wallet's UTXOs.
+fn show_bet_utxo(wallet: &GunWallet, outpoint: OutPoint) -> anyhow::Result<CmdOutput> {
+    let (bet_id, bet_state) = wallet
+        .gun_db()
+        .list_entities_print_error::<BetState>()
+        .find(|(_, bet_state)| match bet_state {
+            BetState::Included { bet, .. }
+            | BetState::Won { bet, .. }
+            | BetState::Lost { bet, .. }
+            | BetState::Claimed { bet, .. }
+            | BetState::Refunded { bet, .. } => bet.outpoint() == outpoint,
+            _ => false,
+        })
+        .ok_or(anyhow!("UTXO {} not in wallet database", outpoint))?;
+
+    let (bet, secret_key) = match &bet_state {
+        BetState::Included { bet, .. } => (bet, "waiting for the oracle"),
+        BetState::Won { bet, .. } => (bet, "yes"),
+        BetState::Lost { bet, .. } => (bet, "no (the bet was lost)"),
+        BetState::Claimed { bet, .. } => (bet, "yes (already claimed)"),
+        BetState::Refunded { bet, .. } => (bet, "no (the bet was refunded)"),
+        _ => unreachable!("we only found bets with an output"),
+    };
+    let script_pubkey = bet.joint_output.descriptor().script_pubkey();
+    let height = wallet
+        .bdk_wallet()
+        .query_db(|db| db.get_tx(&outpoint.txid, false))?
+        .and_then(|tx| tx.confirmation_time);
+
+    Ok(item! {
+        "outpoint" => Cell::string(outpoint),
+        "value" => Cell::Amount(bet.joint_output_value),
+        "conftime" => height.as_ref().map(|x| Cell::DateTime(x.timestamp)).unwrap_or(Cell::Empty),
+        "height" => height.as_ref().map(|x| Cell::Int(x.height as u64)).unwrap_or(Cell::Empty),
+        "address" => Address::from_script(&script_pubkey, wallet.bdk_wallet().network())
+                    .map(|address| Cell::String(address.to_string()))
+                    .unwrap_or(Cell::Empty),
+        "script-pubkey" => Cell::String(script_pubkey.asm()),
+        "output-descriptor" => Cell::string(bet.joint_output.wallet_descriptor()),
+        "secret-key" => Cell::string(secret_key),
+        "spent" => Cell::string(matches!(bet_state, BetState::Claimed { .. } | BetState::Refunded { .. })),
+        "source" => Cell::string(format!("bet {}", bet_id)),
+    })
 }
 
 #[derive(StructOpt, Debug, Clone)]
@@ -566,7 +1558,11 @@ pub struct SplitOpt {
     spend_opt: SpendOpt,
 }
 
-pub fn run_split_cmd(wallet: &GunWallet, opt: SplitOpt) -> anyhow::Result<CmdOutput> {
+pub fn run_split_cmd(
+    wallet: &GunWallet,
+    config: &Config,
+    opt: SplitOpt,
+) -> anyhow::Result<CmdOutput> {
     cmd::ensure_not_watch_only(wallet)?;
     let SplitOpt {
         output_size,
@@ -588,6 +1584,8 @@ pub fn run_split_cmd(wallet: &GunWallet, opt: SplitOpt) -> anyhow::Result<CmdOut
     let last_unused_index = bdk_wallet
         .get_change_address(AddressIndex::LastUnused)?
         .index;
+    // proposals have given these out already
+    let exposed = wallet.exposed_change();
     let mut added_recipients = 0;
     // Check for unused change addresses up to most recent
     for check_used_index in 0..(last_unused_index + 1) {
@@ -596,7 +1594,8 @@ pub fn run_split_cmd(wallet: &GunWallet, opt: SplitOpt) -> anyhow::Result<CmdOut
             .address
             .script_pubkey();
 
-        let used = txo_map.get(&check_script_pk).is_some();
+        let used =
+            txo_map.get(&check_script_pk).is_some() || exposed.contains_key(&check_used_index);
         // Keep adding unused recipients until we have n
         if !used {
             builder.add_recipient(check_script_pk, output_size.as_sat());
@@ -617,5 +1616,51 @@ pub fn run_split_cmd(wallet: &GunWallet, opt: SplitOpt) -> anyhow::Result<CmdOut
         );
     }
 
-    spend_opt.spend_coins(wallet, builder)
+    spend_opt.spend_coins(wallet, config, builder, None, None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ADDRESS_1: &str = "tb1q6d3a2w975yny0asuvd9a67ner4nks58ff0q8g4";
+    const ADDRESS_2: &str = "tb1qqurswpc8qurswpc8qurswpc8qurswpc800zzll";
+
+    #[test]
+    fn parse_csv_payments() {
+        let csv = format!(
+            "address,amount,label\n{},0.01btc,rent\n\n# bonus\n{},50_000\n",
+            ADDRESS_1, ADDRESS_2
+        );
+        let payments = parse_payments(&csv, Network::Testnet).unwrap();
+        assert_eq!(payments.len(), 2);
+        assert_eq!(payments[0].amount, Amount::from_sat(1_000_000));
+        assert_eq!(payments[0].label.as_deref(), Some("rent"));
+        assert_eq!(payments[1].amount, Amount::from_sat(50_000));
+        assert_eq!(payments[1].label, None);
+    }
+
+    #[test]
+    fn parse_json_payments() {
+        let json = format!(
+            r#"[{{"address": "{}", "amount": "1000sat", "label": "a"}}, {{"address": "{}", "amount": "2k"}}]"#,
+            ADDRESS_1, ADDRESS_2
+        );
+        let payments = parse_payments(&json, Network::Testnet).unwrap();
+        assert_eq!(payments[0].amount, Amount::from_sat(1_000));
+        assert_eq!(payments[1].amount, Amount::from_sat(200_000));
+    }
+
+    #[test]
+    fn reject_bad_payments() {
+        let duplicate = format!("{},1000\n{},2000", ADDRESS_1, ADDRESS_1);
+        assert!(parse_payments(&duplicate, Network::Testnet).is_err());
+        let wrong_network = format!("{},1000", ADDRESS_1);
+        assert!(parse_payments(&wrong_network, Network::Bitcoin).is_err());
+        let zero = format!("{},0", ADDRESS_1);
+        assert!(parse_payments(&zero, Network::Testnet).is_err());
+        let overflow = format!("{},21000000btc\n{},21000000btc", ADDRESS_1, ADDRESS_2);
+        assert!(parse_payments(&overflow, Network::Testnet).is_err());
+        assert!(parse_payments("", Network::Testnet).is_err());
+    }
 }
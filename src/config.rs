@@ -1,8 +1,16 @@
+use crate::{
+    confirm::ConfirmPolicy, hooks::Hooks, locktime::LocktimePolicy, passphrase::PassphraseSource,
+    psbt_ext::PsbtFeeRate, spending_limit::SpendingLimit, FeeSpec,
+};
 use bdk::{
-    bitcoin::{util::bip32::Fingerprint, Network},
+    bitcoin::{
+        util::{bip32::Fingerprint, psbt::PartiallySignedTransaction as Psbt},
+        Network, Script,
+    },
     blockchain::{esplora::EsploraBlockchainConfig, AnyBlockchainConfig},
+    KeychainKind,
 };
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "kebab-case")]
@@ -22,12 +30,77 @@ pub enum GunSigner {
     SeedWordsFile {
         #[serde(skip_serializing_if = "Option::is_none")]
         passphrase_fingerprint: Option<Fingerprint>,
+        /// Where to get the passphrase from instead of asking for it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        passphrase_source: Option<PassphraseSource>,
+        #[serde(flatten)]
+        options: SignerOptions,
     },
     PsbtDir {
         path: PathBuf,
+        #[serde(flatten)]
+        options: SignerOptions,
+    },
+    /// An airgapped signer we pass PSBTs to and from with QR codes.
+    Qr {
+        #[serde(flatten)]
+        options: SignerOptions,
+    },
+    /// A hardware wallet we talk to through HWI.
+    Hwi {
+        fingerprint: Fingerprint,
+        #[serde(flatten)]
+        options: SignerOptions,
     },
 }
 
+impl GunSigner {
+    pub fn options(&self) -> &SignerOptions {
+        match self {
+            GunSigner::SeedWordsFile { options, .. }
+            | GunSigner::PsbtDir { options, .. }
+            | GunSigner::Qr { options }
+            | GunSigner::Hwi { options, .. } => options,
+        }
+    }
+}
+
+/// Settings that apply to every kind of signer.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SignerOptions {
+    /// The keychain the signer is registered for. When unset the signer is registered for the
+    /// external keychain but will sign inputs from either.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keychain: Option<KeychainKind>,
+    /// When the signer is asked to sign relative to the others (lowest first). Defaults to the
+    /// signer's position in the list.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order: Option<usize>,
+    /// Whether the transaction can't go ahead without this signer. If the signer isn't required
+    /// and fails (e.g. you cancel it) gun carries on with the other signers.
+    #[serde(default = "default_required", skip_serializing_if = "is_required")]
+    pub required: bool,
+}
+
+fn default_required() -> bool {
+    true
+}
+
+fn is_required(required: &bool) -> bool {
+    *required
+}
+
+impl Default for SignerOptions {
+    fn default() -> Self {
+        SignerOptions {
+            keychain: None,
+            order: None,
+            required: true,
+        }
+    }
+}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum DerivationBip {
@@ -55,8 +128,106 @@ pub struct Config {
     pub network: Network,
     pub blockchain: AnyBlockchainConfig,
     pub signers: Vec<GunSigner>,
+    /// Transactions paying a fee over this many satoshis need extra confirmation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_fee_abs: Option<u64>,
+    /// Transactions paying a feerate over this (in sats per vbyte) need extra confirmation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_feerate: Option<f32>,
+    /// When the seed words backup was last checked with `gun verify-backup` (unix time).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_backup_verified: Option<u64>,
+    /// The block height the wallet was created at. Nothing before it can involve the wallet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub birthday_height: Option<u32>,
+    /// How many times to retry a network request that failed for a reason that might go away.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
+    /// Other esplora servers to use if `blockchain.base_url` is down or behind.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub esplora_fallbacks: Vec<String>,
+    /// Where to get `in-blocks:<n>` fee estimates: "mempool.space" or the url of an API like it.
+    /// Esplora's estimates are used when unset or when it fails.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fee_estimator: Option<String>,
+    /// Put proposals, offers and other bet strings on the clipboard as if `--copy` was given.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub copy_to_clipboard: bool,
+    /// How to set nLockTime on transactions: "anti-fee-sniping" (the default) or "zero".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locktime_policy: Option<LocktimePolicy>,
+    /// The challenge script (hex) of the signet the wallet is on if it isn't the default one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signet_challenge: Option<Script>,
+    /// Claim won bets when syncing once they are worth enough or have been waiting long enough.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_claim: Option<AutoClaimPolicy>,
+    /// Commands to run or urls to POST to when bets change state.
+    #[serde(default, skip_serializing_if = "Hooks::is_empty")]
+    pub hooks: Hooks,
+    /// Limits on the total value at risk in unfinished bets with a tag (keyed by the tag).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tag_limits: BTreeMap<String, TagLimit>,
+    /// How likely (from 0 to 1) you think you are to win bets with a tag. `gun risk` uses it to
+    /// work out the expected value of your bets.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub win_probabilities: BTreeMap<String, f64>,
+    /// Which operations ask before going ahead (see [`crate::confirm`]).
+    #[serde(default, skip_serializing_if = "ConfirmPolicy::is_empty")]
+    pub confirm_policy: ConfirmPolicy,
+    /// How much `gun send` and friends can send in a day or a week.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spending_limit: Option<SpendingLimit>,
+    /// Load the bet protocol secret from this file instead of the database. Keeping it on a
+    /// different device from the wallet's keys means getting one doesn't give you the other.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol_secret_file: Option<PathBuf>,
+    /// Build transactions the same way every time from the same wallet state so another device
+    /// can build a PSBT itself and compare it (see `--expect-txid`) rather than trusting a file.
+    /// Coins are chosen largest first, inputs and outputs are sorted (BIP69) and nLockTime's
+    /// randomness comes from this seed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deterministic_tx_seed: Option<u64>,
+    /// Change worth this many sats or less goes to the fee instead of getting its own output in
+    /// proposals and offers. It can't be set below 546 (the default) or above 10000.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dust_threshold: Option<u64>,
 }
 
+/// e.g. `"tag-limits": { "sports": { "max-at-risk": 5000000 } }`
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TagLimit {
+    /// The most sats that can be at risk in proposed, offered and unresolved bets with the tag.
+    pub max_at_risk: u64,
+    /// Refuse to make a bet that would go over the limit rather than asking whether to.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub strict: bool,
+}
+
+/// When to claim won bets without being asked. All the won bets are claimed together in one
+/// transaction once either threshold is met.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AutoClaimPolicy {
+    /// Claim once the won bets are worth at least this many sats together.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_value: Option<u64>,
+    /// Claim once any won bet's outcome was attested more than this many days ago.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after_days: Option<u32>,
+    /// The fee to pay for the claim transaction (default in-blocks:1).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fee: Option<FeeSpec>,
+    /// Broadcast the claim without asking first.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub yes: bool,
+}
+
+/// Used when `blockchain.timeout` isn't set so a stalled server can't hang gun forever.
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_RETRIES: u32 = 2;
+
 impl Config {
     pub fn default_config(network: Network) -> Config {
         use Network::*;
@@ -76,15 +247,127 @@ impl Config {
             network,
             blockchain,
             signers: vec![],
+            max_fee_abs: None,
+            max_feerate: None,
+            last_backup_verified: None,
+            birthday_height: None,
+            retries: None,
+            esplora_fallbacks: vec![],
+            fee_estimator: None,
+            copy_to_clipboard: false,
+            locktime_policy: None,
+            signet_challenge: None,
+            auto_claim: None,
+            hooks: Hooks::default(),
+            tag_limits: BTreeMap::new(),
+            win_probabilities: BTreeMap::new(),
+            confirm_policy: ConfirmPolicy::default(),
+            spending_limit: None,
+            protocol_secret_file: None,
+            deterministic_tx_seed: None,
+            dust_threshold: None,
         }
     }
     pub fn into_versioned(self) -> VersionedConfig {
         VersionedConfig::V1(self)
     }
 
+    /// Describes which of the configured fee limits the transaction goes over (if any).
+    pub fn fee_limit_exceeded(&self, psbt: &Psbt) -> Option<String> {
+        let (fee, feerate, _) = psbt.fee();
+        if let Some(max_fee_abs) = self.max_fee_abs {
+            if fee.as_sat() > max_fee_abs {
+                return Some(format!(
+                    "the fee of {} sats is more than max-fee-abs ({} sats)",
+                    fee.as_sat(),
+                    max_fee_abs
+                ));
+            }
+        }
+        if let Some(max_feerate) = self.max_feerate {
+            if feerate.as_sat_vb() > max_feerate {
+                return Some(format!(
+                    "the feerate of {:.3} sats/vb is more than max-feerate ({} sats/vb)",
+                    feerate.as_sat_vb(),
+                    max_feerate
+                ));
+            }
+        }
+        None
+    }
+
     pub fn blockchain_config(&self) -> &EsploraBlockchainConfig {
         match &self.blockchain {
             AnyBlockchainConfig::Esplora(config) => config,
         }
     }
+
+    /// The url of the mempool.space style API to get fee estimates from (if any).
+    pub fn fee_estimator_url(&self) -> Option<String> {
+        use Network::*;
+        match self.fee_estimator.as_deref()? {
+            "mempool.space" => match self.network {
+                Bitcoin => Some("https://mempool.space/api".into()),
+                Testnet => Some("https://mempool.space/testnet/api".into()),
+                Signet => Some("https://mempool.space/signet/api".into()),
+                Regtest => None,
+            },
+            url => Some(url.into()),
+        }
+    }
+
+    /// The main esplora server followed by the fallbacks.
+    pub fn esplora_urls(&self) -> Vec<String> {
+        std::iter::once(self.blockchain_config().base_url.clone())
+            .chain(self.esplora_fallbacks.iter().cloned())
+            .collect()
+    }
+
+    /// The network timeout in seconds for the esplora server and oracles.
+    pub fn timeout(&self) -> u64 {
+        self.blockchain_config()
+            .timeout
+            .unwrap_or(DEFAULT_TIMEOUT_SECS)
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries.unwrap_or(DEFAULT_RETRIES)
+    }
+
+    pub fn dust_threshold(&self) -> u64 {
+        self.dust_threshold
+            .unwrap_or(crate::change::DEFAULT_DUST_THRESHOLD)
+            .clamp(
+                crate::change::DEFAULT_DUST_THRESHOLD,
+                crate::change::MAX_DUST_THRESHOLD,
+            )
+    }
+
+    /// The challenge of the signet the wallet is on (`None` if it isn't on a signet).
+    pub fn signet_challenge(&self) -> Option<Script> {
+        match self.network {
+            Network::Signet => Some(
+                self.signet_challenge
+                    .clone()
+                    .unwrap_or_else(crate::signet::default_challenge),
+            ),
+            _ => None,
+        }
+    }
+
+    pub fn locktime_policy(&self) -> LocktimePolicy {
+        self.locktime_policy.unwrap_or_default()
+    }
+
+    /// The HTTP client we use to talk to hooks and other one-off services.
+    pub fn http_client(&self) -> ureq::Agent {
+        ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(self.timeout()))
+            .build()
+    }
+
+    /// The async HTTP client we use to talk to oracles and to poll bets.
+    pub fn net(&self) -> crate::net::Net {
+        crate::net::Net::new(Duration::from_secs(self.timeout()), self.retries())
+    }
 }
@@ -0,0 +1,114 @@
+//! Which operations ask before going ahead (`confirm-policy` in the config).
+//!
+//! By default everything that moves coins asks unless `--yes` is given. The policy can let an
+//! operation go ahead without asking (e.g. claims, which only ever pay you) or make it ask even
+//! with `--yes` (e.g. cancels). Anything sending more than `always-ask-above` out of the wallet
+//! asks no matter what. `--assume-yes` still answers every question.
+use bdk::bitcoin::{util::psbt::PartiallySignedTransaction as Psbt, Amount};
+use std::collections::BTreeMap;
+
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Operation {
+    /// `gun send`, `gun send-many`, `gun split` and `gun recover sweep`
+    Send,
+    /// Making a proposal
+    Propose,
+    /// Making an offer on someone's proposal
+    Offer,
+    /// Taking an offer on one of your proposals
+    Take,
+    /// Claiming won bets
+    Claim,
+    /// `gun bet cancel` and `gun bet mutual-cancel`
+    Cancel,
+    /// Broadcasting a bet's refund
+    Refund,
+    /// Broadcasting a transaction others have signed too (`gun psbt combine` and escrows)
+    Broadcast,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Confirm {
+    /// Ask unless `--yes` is given (the default)
+    Ask,
+    /// Ask even when `--yes` is given
+    Always,
+    /// Go ahead without asking
+    Never,
+}
+
+/// e.g. `"confirm-policy": { "operations": { "claim": "never", "cancel": "always" },
+/// "always-ask-above": 1000000 }`
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConfirmPolicy {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub operations: BTreeMap<Operation, Confirm>,
+    /// Always ask before sending more than this many sats out of the wallet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub always_ask_above: Option<u64>,
+}
+
+impl ConfirmPolicy {
+    pub fn is_empty(&self) -> bool {
+        self == &ConfirmPolicy::default()
+    }
+
+    /// Whether to ask before doing `operation` which sends `sending` out of the wallet.
+    pub fn should_ask(&self, operation: Operation, yes: bool, sending: Amount) -> bool {
+        if matches!(self.always_ask_above, Some(limit) if sending > Amount::from_sat(limit)) {
+            return true;
+        }
+        match self
+            .operations
+            .get(&operation)
+            .cloned()
+            .unwrap_or(Confirm::Ask)
+        {
+            Confirm::Ask => !yes,
+            Confirm::Always => true,
+            Confirm::Never => false,
+        }
+    }
+}
+
+/// What `psbt` sends out of the wallet i.e. the value of the outputs that aren't ours. BDK gives
+/// the outputs that are ours a key derivation.
+pub fn value_leaving(psbt: &Psbt) -> Amount {
+    psbt.outputs
+        .iter()
+        .zip(&psbt.unsigned_tx.output)
+        .filter(|(psbt_output, _)| psbt_output.bip32_derivation.is_empty())
+        .map(|(_, txout)| Amount::from_sat(txout.value))
+        .fold(Amount::ZERO, |total, value| total + value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn policy_decides_when_to_ask() {
+        let policy = serde_json::from_str::<ConfirmPolicy>(
+            r#"{"operations": {"claim": "never", "cancel": "always"}, "always-ask-above": 1000000}"#,
+        )
+        .unwrap();
+        let small = Amount::from_sat(10_000);
+        let big = Amount::from_sat(1_000_001);
+
+        assert!(policy.should_ask(Operation::Send, false, small));
+        assert!(!policy.should_ask(Operation::Send, true, small));
+        assert!(policy.should_ask(Operation::Send, true, big));
+
+        assert!(!policy.should_ask(Operation::Claim, false, small));
+        assert!(policy.should_ask(Operation::Cancel, true, small));
+        assert!(policy.should_ask(Operation::Claim, true, big));
+
+        assert!(ConfirmPolicy::default().should_ask(Operation::Cancel, false, big));
+        assert!(!ConfirmPolicy::default().should_ask(Operation::Cancel, true, big));
+    }
+}
@@ -0,0 +1,168 @@
+//! The address book (`gun contact`): names for the people you bet with.
+//!
+//! A contact is a name and a public key the person gave you e.g. their `gun escrow key` or nostr
+//! npub. Bets can be linked to a contact so `gun bet list` and `gun bet show` say who they're with.
+//!
+//! Bet keys are new for every proposal and offer so they can't tell you who you've bet with before.
+//! A contact's [`Reputation`] is worked out from the bets linked to them instead.
+use crate::{
+    betting::{BetId, BetState},
+    database::{BetContact, GunDatabase},
+};
+use anyhow::anyhow;
+use bdk::bitcoin::bech32::{self, FromBase32, ToBase32, Variant};
+use olivia_secp256k1::fun::{marker::EvenY, Point};
+use std::str::FromStr;
+
+const NPUB_HRP: &str = "npub";
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Contact {
+    pub public_key: Point<EvenY>,
+}
+
+/// Parses a contact's public key given as 32 byte x-only hex or a nostr npub.
+pub fn parse_contact_key(string: &str) -> anyhow::Result<Point<EvenY>> {
+    if string.starts_with(NPUB_HRP) {
+        let (hrp, data, _) = bech32::decode(string)?;
+        if hrp != NPUB_HRP {
+            return Err(anyhow!("'{}' is not an npub", string));
+        }
+        let bytes = Vec::<u8>::from_base32(&data)?;
+        let mut xonly = [0u8; 32];
+        if bytes.len() != 32 {
+            return Err(anyhow!("'{}' doesn't have a 32 byte key in it", string));
+        }
+        xonly.copy_from_slice(&bytes);
+        Point::from_xonly_bytes(xonly).ok_or(anyhow!("'{}' is not a valid public key", string))
+    } else {
+        Point::<EvenY>::from_str(string)
+            .map_err(|_| anyhow!("'{}' is not an x-only public key or an npub", string))
+    }
+}
+
+/// The key as a nostr npub.
+pub fn npub(public_key: &Point<EvenY>) -> String {
+    bech32::encode(
+        NPUB_HRP,
+        public_key.to_xonly().as_bytes().to_base32(),
+        Variant::Bech32,
+    )
+    .expect("npub is a valid hrp")
+}
+
+/// How the bets with a contact have gone.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Reputation {
+    /// Bets the oracle decided
+    pub completed: u32,
+    /// Bets they spent one of their inputs out from under before it confirmed
+    pub canceled: u32,
+    /// Bets that were refunded because the oracle never attested
+    pub timed_out: u32,
+}
+
+impl Reputation {
+    pub fn record(&mut self, bet_state: &BetState) {
+        match bet_state {
+            BetState::Won { .. } | BetState::Lost { .. } | BetState::Claimed { .. } => {
+                self.completed += 1
+            }
+            BetState::Refunded { .. } => self.timed_out += 1,
+            BetState::Canceled { .. } if bet_state.canceled_by_counterparty() => self.canceled += 1,
+            _ => {}
+        }
+    }
+
+    /// The percentage of bets they followed through on. Timeouts are the oracle's fault so they
+    /// don't count either way. `None` if there's nothing to go on yet.
+    pub fn score(&self) -> Option<u32> {
+        let total = self.completed + self.canceled;
+        if total == 0 {
+            None
+        } else {
+            Some(self.completed * 100 / total)
+        }
+    }
+}
+
+impl core::fmt::Display for Reputation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{} completed, {} canceled by them, {} timed out",
+            self.completed, self.canceled, self.timed_out
+        )?;
+        if let Some(score) = self.score() {
+            write!(f, " ({}% reliable)", score)?;
+        }
+        Ok(())
+    }
+}
+
+impl GunDatabase {
+    /// The name of the contact the bet is with (if it's been linked to one).
+    pub fn bet_contact(&self, bet_id: BetId) -> Option<String> {
+        self.get_entity::<BetContact>(bet_id)
+            .ok()
+            .flatten()
+            .map(|BetContact(name)| name)
+    }
+
+    pub fn link_bet_contact(&self, bet_id: BetId, name: &str) -> anyhow::Result<()> {
+        if self.get_entity::<Contact>(name.to_string())?.is_none() {
+            return Err(anyhow!(
+                "There's no contact called {}. Add them with `gun contact add`.",
+                name
+            ));
+        }
+        self.insert_entity(bet_id, BetContact(name.to_string()))
+    }
+
+    /// The record of every bet linked to the contact.
+    pub fn contact_reputation(&self, name: &str) -> Reputation {
+        let mut reputation = Reputation::default();
+        for (bet_id, _) in self
+            .list_entities_print_error::<BetContact>()
+            .filter(|(_, BetContact(linked))| linked == name)
+        {
+            if let Ok(Some(bet_state)) = self.get_entity::<BetState>(bet_id) {
+                reputation.record(&bet_state);
+            }
+        }
+        reputation
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn npub_and_hex_keys() {
+        // from NIP-19
+        let hex = "7e7e9c42a91bfef19fa929e5fda1b72e0ebc1a4c1141673e2794234d86addf4e";
+        let npub_str = "npub10elfcs4fr0l0r8af98jlmgdh9c8tcxjvz9qkw038js35mp4dma8qzvjptg";
+        let from_hex = parse_contact_key(hex).unwrap();
+        let from_npub = parse_contact_key(npub_str).unwrap();
+        assert_eq!(from_hex, from_npub);
+        assert_eq!(npub(&from_hex), npub_str);
+        assert!(parse_contact_key("npub1qqqqqqqq").is_err());
+        assert!(parse_contact_key("alice").is_err());
+    }
+
+    #[test]
+    fn reputation_score() {
+        assert_eq!(Reputation::default().score(), None);
+        let reputation = Reputation {
+            completed: 3,
+            canceled: 1,
+            timed_out: 5,
+        };
+        assert_eq!(reputation.score(), Some(75));
+        assert_eq!(
+            reputation.to_string(),
+            "3 completed, 1 canceled by them, 5 timed out (75% reliable)"
+        );
+    }
+}
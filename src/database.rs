@@ -1,14 +1,28 @@
-use crate::{betting::*, elog, keychain::ProtocolSecret, OracleInfo};
+use crate::{
+    betting::*,
+    contacts::Contact,
+    elog,
+    escrow::{Escrow, EscrowId},
+    gap::ExposedChange,
+    keychain::{DerivationVersion, Keychain, ProtocolSecret},
+    locktime::TxLocktime,
+    schedule::{Schedule, ScheduleId},
+    silent_payments::scan::{FoundOutput, ScanKeys},
+    spending_limit::OutgoingSpend,
+    OracleInfo,
+};
 use anyhow::{anyhow, Context};
 use bdk::{
-    bitcoin::OutPoint,
+    bitcoin::{OutPoint, Txid},
     sled::{
         self,
         transaction::{ConflictableTransactionError, TransactionalTree},
     },
     KeychainKind,
 };
-use olivia_core::OracleId;
+use olivia_core::{EventId, OracleId};
+
+pub mod migrations;
 
 pub const DB_VERSION: u8 = 0;
 
@@ -19,6 +33,27 @@ pub enum MapKey {
     Bet(BetId),
     ProtocolSecret(ProtocolKind),
     Descriptor(KeychainKind),
+    ScheduleId,
+    Schedule(ScheduleId),
+    TxMemo(Txid),
+    OracleEvent(String),
+    EscrowId,
+    Escrow(EscrowId),
+    BetThread(BetId),
+    ProposalOffers(BetId),
+    BetFingerprint(BetFingerprint),
+    TxLocktime(Txid),
+    DerivationVersion(ProtocolKind),
+    RetiredProtocolSecret(u32),
+    OutgoingSpend(Txid),
+    Contact(String),
+    BetContact(BetId),
+    OracleUrl(OracleId),
+    SilentPaymentKeys(u32),
+    SilentPayment(OutPoint),
+    BetFees(BetId),
+    ExposedChange(u32),
+    SchemaVersion,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
@@ -58,6 +93,27 @@ pub enum KeyKind {
     Bet,
     ProtocolSecret,
     Descriptor,
+    ScheduleId,
+    Schedule,
+    TxMemo,
+    OracleEvent,
+    EscrowId,
+    Escrow,
+    BetThread,
+    ProposalOffers,
+    BetFingerprint,
+    TxLocktime,
+    DerivationVersion,
+    RetiredProtocolSecret,
+    OutgoingSpend,
+    Contact,
+    BetContact,
+    OracleUrl,
+    SilentPaymentKeys,
+    SilentPayment,
+    BetFees,
+    ExposedChange,
+    SchemaVersion,
 }
 
 impl KeyKind {
@@ -120,6 +176,48 @@ impl_entity!(ProtocolKind, ProtocolSecret, ProtocolSecret);
 #[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
 pub struct StringDescriptor(pub String);
 impl_entity!(KeychainKind, StringDescriptor, Descriptor);
+impl_entity!(ScheduleId, Schedule, Schedule);
+/// A note the user attached to a transaction
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct TxMemo(pub String);
+impl_entity!(Txid, TxMemo, TxMemo);
+/// An oracle's response for an event url as we received it.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct CachedEvent {
+    pub json: String,
+    /// When we got it (unix time).
+    pub fetched_at: u64,
+}
+impl_entity!(String, CachedEvent, OracleEvent);
+impl_entity!(EscrowId, Escrow, Escrow);
+impl_entity!(BetId, BetThread, BetThread);
+impl_entity!(BetId, ProposalOffers, ProposalOffers);
+/// The local id of the bet with a fingerprint
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct FingerprintIndex(pub BetId);
+impl_entity!(BetFingerprint, FingerprintIndex, BetFingerprint);
+impl_entity!(Txid, TxLocktime, TxLocktime);
+impl_entity!(ProtocolKind, DerivationVersion, DerivationVersion);
+/// A protocol secret that has been replaced by `gun keychain rotate`. It's kept so the bets using it
+/// can still find their keys.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct RetiredProtocolSecret(pub ProtocolSecret);
+impl_entity!(u32, RetiredProtocolSecret, RetiredProtocolSecret);
+impl_entity!(Txid, OutgoingSpend, OutgoingSpend);
+impl_entity!(String, Contact, Contact);
+/// The name of the contact a bet is with
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct BetContact(pub String);
+impl_entity!(BetId, BetContact, BetContact);
+/// Where to reach an oracle that isn't at `https://<oracle id>` (e.g. `gun dev oracle`)
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct OracleUrl(pub String);
+impl_entity!(OracleId, OracleUrl, OracleUrl);
+// keyed by the BIP-352 account (always 0 for now)
+impl_entity!(u32, ScanKeys, SilentPaymentKeys);
+impl_entity!(OutPoint, FoundOutput, SilentPayment);
+impl_entity!(BetId, BetFees, BetFees);
+impl_entity!(u32, ExposedChange, ExposedChange);
 
 pub struct GunDatabase(sled::Tree);
 
@@ -136,28 +234,86 @@ impl GunDatabase {
         GunDatabase(tree)
     }
 
-    pub fn insert_bet(&self, bet: BetState) -> anyhow::Result<BetId> {
+    /// The url to get an event from the oracle at.
+    pub fn oracle_event_url(&self, oracle_id: &str, event_id: &EventId) -> String {
+        match self.get_entity::<OracleUrl>(oracle_id.to_string()) {
+            Ok(Some(OracleUrl(base))) => format!("{}{}", base, event_id),
+            _ => format!("https://{}{}", oracle_id, event_id),
+        }
+    }
+
+    pub(crate) fn tree(&self) -> &sled::Tree {
+        &self.0
+    }
+
+    /// Increment the counter at `counter` and return the new value.
+    fn next_id(&self, counter: MapKey) -> anyhow::Result<u32> {
         let i = self
             .0
-            .update_and_fetch(
-                VersionedKey::from(MapKey::BetId).to_bytes(),
-                |prev| match prev {
-                    Some(prev) => Some(
-                        (u32::from_be_bytes(<[u8; 4]>::try_from(prev).unwrap()) + 1)
-                            .to_be_bytes()
-                            .to_vec(),
-                    ),
-                    None => Some(0u32.to_be_bytes().to_vec()),
-                },
-            )?
+            .update_and_fetch(VersionedKey::from(counter).to_bytes(), |prev| match prev {
+                Some(prev) => Some(
+                    (u32::from_be_bytes(<[u8; 4]>::try_from(prev).unwrap()) + 1)
+                        .to_be_bytes()
+                        .to_vec(),
+                ),
+                None => Some(0u32.to_be_bytes().to_vec()),
+            })?
             .unwrap();
-        let i = u32::from_be_bytes(<[u8; 4]>::try_from(i.to_vec()).unwrap());
+        Ok(u32::from_be_bytes(<[u8; 4]>::try_from(i.to_vec()).unwrap()))
+    }
 
+    pub fn insert_bet(&self, bet: BetState) -> anyhow::Result<BetId> {
+        let i = self.next_id(MapKey::BetId)?;
         insert(&self.0, MapKey::Bet(i), bet)?;
+        Ok(i)
+    }
+
+    pub fn insert_schedule(&self, schedule: Schedule) -> anyhow::Result<ScheduleId> {
+        let i = self.next_id(MapKey::ScheduleId)?;
+        insert(&self.0, MapKey::Schedule(i), schedule)?;
+        Ok(i)
+    }
 
+    pub fn insert_escrow(&self, escrow: Escrow) -> anyhow::Result<EscrowId> {
+        let i = self.next_id(MapKey::EscrowId)?;
+        insert(&self.0, MapKey::Escrow(i), escrow)?;
         Ok(i)
     }
 
+    pub fn index_bet_fingerprint(
+        &self,
+        fingerprint: BetFingerprint,
+        bet_id: BetId,
+    ) -> anyhow::Result<()> {
+        self.insert_entity(fingerprint, FingerprintIndex(bet_id))
+    }
+
+    /// The local id of a bet given either its id or a prefix of its fingerprint.
+    pub fn resolve_bet_ref(&self, bet_ref: &BetRef) -> anyhow::Result<BetId> {
+        let prefix = match bet_ref {
+            BetRef::Id(id) => return Ok(*id),
+            BetRef::Fingerprint(prefix) => prefix,
+        };
+        let mut matches = self
+            .list_entities_print_error::<FingerprintIndex>()
+            .filter(|(fingerprint, _)| fingerprint.to_string().starts_with(prefix.as_str()));
+        match (matches.next(), matches.next()) {
+            (Some((_, FingerprintIndex(bet_id))), None) => Ok(bet_id),
+            (None, _) => Err(anyhow!("No bet has a fingerprint starting with {}", prefix)),
+            (Some(_), Some(_)) => Err(anyhow!(
+                "More than one bet has a fingerprint starting with {} -- use more of it",
+                prefix
+            )),
+        }
+    }
+
+    pub fn resolve_bet_refs(&self, bet_refs: &[BetRef]) -> anyhow::Result<Vec<BetId>> {
+        bet_refs
+            .iter()
+            .map(|bet_ref| self.resolve_bet_ref(bet_ref))
+            .collect()
+    }
+
     pub fn currently_used_utxos(&self, ignore: &[BetId]) -> anyhow::Result<Vec<OutPoint>> {
         Ok(self
             .list_entities::<BetState>()
@@ -239,6 +395,26 @@ impl GunDatabase {
         })
     }
 
+    /// Dump every raw key-value pair in the tree (used for backups).
+    pub fn export_raw(&self) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.0
+            .iter()
+            .map(|item| {
+                let (key, value) = item?;
+                Ok((key.to_vec(), value.to_vec()))
+            })
+            .collect()
+    }
+
+    /// Insert raw key-value pairs that came from [`export_raw`](Self::export_raw).
+    pub fn import_raw(&self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> anyhow::Result<()> {
+        for (key, value) in entries {
+            self.0.insert(key, value)?;
+        }
+        self.0.flush()?;
+        Ok(())
+    }
+
     pub fn test_new() -> Self {
         GunDatabase::new(
             bdk::sled::Config::new()
@@ -251,6 +427,74 @@ impl GunDatabase {
         )
     }
 
+    /// The derivation version new bets are made with. Protocol secrets stored before versions were
+    /// are [`DerivationVersion::V0`].
+    pub fn bet_derivation_version(&self) -> anyhow::Result<DerivationVersion> {
+        Ok(self
+            .get_entity::<DerivationVersion>(ProtocolKind::Bet)?
+            .unwrap_or_default())
+    }
+
+    /// The keychain for betting if the wallet has a protocol secret.
+    pub fn bet_keychain(&self) -> anyhow::Result<Option<Keychain>> {
+        match self.get_entity::<ProtocolSecret>(ProtocolKind::Bet)? {
+            Some(secret) => Ok(Some(self.bet_keychain_with(secret)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The keychain for betting with `secret` as the current protocol secret (for when it isn't
+    /// kept in the database).
+    pub fn bet_keychain_with(&self, secret: ProtocolSecret) -> anyhow::Result<Keychain> {
+        Ok(Keychain::from_secrets(
+            secret,
+            self.bet_derivation_version()?,
+            self.retired_protocol_secrets()?,
+        ))
+    }
+
+    /// The protocol secrets `gun keychain rotate` has replaced by their index.
+    pub fn retired_protocol_secrets(&self) -> anyhow::Result<Vec<(u32, ProtocolSecret)>> {
+        self.list_entities::<RetiredProtocolSecret>()
+            .map(|entry| entry.map(|(index, RetiredProtocolSecret(secret))| (index, secret)))
+            .collect()
+    }
+
+    /// Make `new_secret` the one new bets use and keep the current one for the bets that are using
+    /// it. Returns the new secret's index.
+    pub fn rotate_bet_protocol_secret(&self, new_secret: ProtocolSecret) -> anyhow::Result<u32> {
+        let current = self
+            .get_entity::<ProtocolSecret>(ProtocolKind::Bet)?
+            .ok_or(anyhow!(
+                "the wallet doesn't have a protocol secret to rotate"
+            ))?;
+        if current == new_secret {
+            return Err(anyhow!(
+                "the new protocol secret is the same as the current one"
+            ));
+        }
+        let retired = self.retired_protocol_secrets()?;
+        if retired.iter().any(|(_, secret)| *secret == new_secret) {
+            return Err(anyhow!("the new protocol secret has already been used"));
+        }
+        let index = retired
+            .iter()
+            .map(|(index, _)| index + 1)
+            .max()
+            .unwrap_or(0);
+        self.insert_entity(index, RetiredProtocolSecret(current))?;
+        self.set_bet_protocol_secret(new_secret)?;
+        Ok(index + 1)
+    }
+
+    /// Set the protocol secret of a wallet that doesn't have any bets yet. New bets will use
+    /// [`DerivationVersion::CURRENT`].
+    pub fn set_bet_protocol_secret(&self, secret: ProtocolSecret) -> anyhow::Result<()> {
+        self.insert_entity(ProtocolKind::Bet, secret)?;
+        self.insert_entity(ProtocolKind::Bet, DerivationVersion::CURRENT)?;
+        Ok(())
+    }
+
     pub fn safely_set_bet_protocol_secret(&self, new_secret: ProtocolSecret) -> anyhow::Result<()> {
         let in_use: Vec<_> = self
             .list_entities::<BetState>()
@@ -258,8 +502,7 @@ impl GunDatabase {
             .filter(|(_, state)| state.relies_on_protocol_secret())
             .collect();
         if in_use.is_empty() {
-            self.insert_entity(ProtocolKind::Bet, new_secret)?;
-            Ok(())
+            self.set_bet_protocol_secret(new_secret)
         } else {
             let in_use = in_use
                 .into_iter()
@@ -313,4 +556,31 @@ mod test {
             vec![(info1.id.clone(), info1), (info2.id.clone(), info2)]
         );
     }
+
+    #[test]
+    fn rotate_protocol_secret() {
+        let db = GunDatabase::test_new();
+        assert!(db
+            .rotate_bet_protocol_secret(ProtocolSecret::Bytes([1u8; 64]))
+            .is_err());
+        db.set_bet_protocol_secret(ProtocolSecret::Bytes([1u8; 64]))
+            .unwrap();
+        assert_eq!(db.bet_keychain().unwrap().unwrap().origin().secret, 0);
+        assert_eq!(
+            db.rotate_bet_protocol_secret(ProtocolSecret::Bytes([2u8; 64]))
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            db.rotate_bet_protocol_secret(ProtocolSecret::Bytes([3u8; 64]))
+                .unwrap(),
+            2
+        );
+        assert!(db
+            .rotate_bet_protocol_secret(ProtocolSecret::Bytes([1u8; 64]))
+            .is_err());
+        let keychain = db.bet_keychain().unwrap().unwrap();
+        assert_eq!(keychain.origin().secret, 2);
+        assert_eq!(keychain.secret_indexes().collect::<Vec<_>>(), vec![0, 1, 2]);
+    }
 }
@@ -0,0 +1,272 @@
+//! Ordered migrations for the gun database tree.
+//!
+//! The tree stores its schema version under [`schema_version_key`]. Wallets created before this
+//! existed have their version inferred from the version byte at the start of each
+//! [`VersionedKey`](super::VersionedKey). Every time [`DB_VERSION`] is bumped a [`Migration`] from the
+//! previous version must be added to [`MIGRATIONS`].
+use super::{GunDatabase, MapKey, ProtocolKind, VersionedKey, DB_VERSION};
+use crate::{
+    elog,
+    keychain::{DerivationVersion, ProtocolSecret},
+};
+use anyhow::{anyhow, Context};
+use bdk::sled;
+use std::path::Path;
+
+/// The key the schema version is stored under. It always has key version 0 so it can be found
+/// before we know what version the rest of the tree is at.
+pub fn schema_version_key() -> Vec<u8> {
+    VersionedKey {
+        version: 0,
+        key: MapKey::SchemaVersion,
+    }
+    .to_bytes()
+}
+
+pub struct Migration {
+    /// The version this migrates from. It leaves the database at `from + 1`.
+    pub from: u8,
+    pub description: &'static str,
+    pub migrate: fn(&sled::Tree) -> anyhow::Result<()>,
+}
+
+pub const MIGRATIONS: &[Migration] = &[];
+
+pub fn schema_version(tree: &sled::Tree) -> anyhow::Result<u8> {
+    if let Some(version) = tree.get(schema_version_key())? {
+        return version
+            .first()
+            .copied()
+            .ok_or(anyhow!("schema version entry in database is empty"));
+    }
+
+    // No explicit version so infer it from the oldest key
+    let mut min_version = None;
+    for item in tree.iter().keys() {
+        let key = item?;
+        if key.as_ref() == schema_version_key().as_slice() {
+            continue;
+        }
+        if let Some(version) = key.first() {
+            min_version = Some(min_version.map_or(*version, |min: u8| min.min(*version)));
+        }
+    }
+    Ok(min_version.unwrap_or(DB_VERSION))
+}
+
+pub fn set_schema_version(tree: &sled::Tree, version: u8) -> anyhow::Result<()> {
+    tree.insert(schema_version_key(), vec![version])?;
+    Ok(())
+}
+
+/// Runs (or if `dry_run` just lists) the migrations needed to get to `target`.
+pub fn migrate_with<'a>(
+    tree: &sled::Tree,
+    migrations: &'a [Migration],
+    target: u8,
+    dry_run: bool,
+) -> anyhow::Result<Vec<&'a Migration>> {
+    let current = schema_version(tree)?;
+    if current > target {
+        return Err(anyhow!(
+            "the database is at version {} but this version of gun only supports up to {} -- you need to upgrade gun",
+            current,
+            target
+        ));
+    }
+
+    let mut pending = vec![];
+    for version in current..target {
+        let migration = migrations
+            .iter()
+            .find(|migration| migration.from == version)
+            .ok_or(anyhow!("no migration from database version {}", version))?;
+        pending.push(migration);
+    }
+
+    if !dry_run {
+        for migration in &pending {
+            (migration.migrate)(tree).with_context(|| {
+                format!(
+                    "migrating database from version {} ({})",
+                    migration.from, migration.description
+                )
+            })?;
+            set_schema_version(tree, migration.from + 1)?;
+        }
+        set_schema_version(tree, target)?;
+        tree.flush()?;
+    }
+
+    Ok(pending)
+}
+
+pub fn migrate(tree: &sled::Tree, dry_run: bool) -> anyhow::Result<Vec<&'static Migration>> {
+    migrate_with(tree, MIGRATIONS, DB_VERSION, dry_run)
+}
+
+/// Called when loading the wallet. Dumps the tree to a file in the wallet directory before
+/// migrating so nothing is lost if a migration goes wrong.
+pub fn migrate_on_load(wallet_dir: &Path, gun_db: &GunDatabase) -> anyhow::Result<()> {
+    let tree = gun_db.tree();
+    let pending = migrate(tree, true)?;
+    if pending.is_empty() {
+        if tree.get(schema_version_key())?.is_none() {
+            set_schema_version(tree, DB_VERSION)?;
+        }
+        return upgrade_on_load(gun_db);
+    }
+
+    let backup_file = wallet_dir.join(format!(
+        "gun-db-pre-migration-v{}-{}.json",
+        schema_version(tree)?,
+        crate::chrono::Utc::now().timestamp()
+    ));
+    std::fs::write(
+        &backup_file,
+        serde_json::to_string(&gun_db.export_raw()?).unwrap(),
+    )
+    .with_context(|| format!("writing pre-migration backup {}", backup_file.display()))?;
+    elog!(@info "Backed up database to {} before migrating", backup_file.display());
+
+    for migration in migrate(tree, false)? {
+        elog!(@info "Migrated database from version {}: {}", migration.from, migration.description);
+    }
+    upgrade_on_load(gun_db)
+}
+
+fn upgrade_on_load(gun_db: &GunDatabase) -> anyhow::Result<()> {
+    if let Some((from, to)) = upgrade_derivation_version(gun_db)? {
+        if from != to {
+            elog!(@info "New bets will derive their keys with {} (existing bets keep using {})", to, from);
+        }
+    }
+    Ok(())
+}
+
+/// Moves new bets onto [`DerivationVersion::CURRENT`]. Bets record the version their keys were
+/// derived with so this doesn't affect the ones in progress. Wallets from before the version was
+/// stored are on [`DerivationVersion::V0`]. Returns the versions it moved from and to if it did
+/// anything.
+pub fn upgrade_derivation_version(
+    gun_db: &GunDatabase,
+) -> anyhow::Result<Option<(DerivationVersion, DerivationVersion)>> {
+    if gun_db
+        .get_entity::<ProtocolSecret>(ProtocolKind::Bet)?
+        .is_none()
+    {
+        return Ok(None);
+    }
+    let stored = gun_db.get_entity::<DerivationVersion>(ProtocolKind::Bet)?;
+    match stored {
+        Some(version) if version >= DerivationVersion::CURRENT => Ok(None),
+        _ => {
+            gun_db.insert_entity(ProtocolKind::Bet, DerivationVersion::CURRENT)?;
+            Ok(Some((
+                stored.unwrap_or_default(),
+                DerivationVersion::CURRENT,
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bump_key_versions(tree: &sled::Tree) -> anyhow::Result<()> {
+        for item in tree.iter() {
+            let (key, value) = item?;
+            if key.first() == Some(&0) && key.as_ref() != schema_version_key().as_slice() {
+                let mut versioned_key = VersionedKey::from_bytes(&key);
+                versioned_key.version = 1;
+                tree.remove(&key)?;
+                tree.insert(versioned_key.to_bytes(), value)?;
+            }
+        }
+        Ok(())
+    }
+
+    const TEST_MIGRATIONS: &[Migration] = &[Migration {
+        from: 0,
+        description: "bump key versions",
+        migrate: bump_key_versions,
+    }];
+
+    #[test]
+    fn migrate_old_keys() {
+        let db = GunDatabase::test_new();
+        let tree = db.tree();
+        tree.insert(VersionedKey::from(MapKey::BetId).to_bytes(), vec![0u8; 4])
+            .unwrap();
+        assert_eq!(schema_version(tree).unwrap(), 0);
+
+        let pending = migrate_with(tree, TEST_MIGRATIONS, 1, true).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(schema_version(tree).unwrap(), 0, "dry run changes nothing");
+
+        migrate_with(tree, TEST_MIGRATIONS, 1, false).unwrap();
+        assert_eq!(schema_version(tree).unwrap(), 1);
+        let key = VersionedKey {
+            version: 1,
+            key: MapKey::BetId,
+        };
+        assert!(tree.get(key.to_bytes()).unwrap().is_some());
+        assert!(migrate_with(tree, TEST_MIGRATIONS, 1, false)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn migrate_with_stored_version() {
+        let db = GunDatabase::test_new();
+        let tree = db.tree();
+        tree.insert(VersionedKey::from(MapKey::BetId).to_bytes(), vec![0u8; 4])
+            .unwrap();
+        set_schema_version(tree, 0).unwrap();
+
+        let done = migrate_with(tree, TEST_MIGRATIONS, 1, false).unwrap();
+        assert_eq!(done.len(), 1);
+        assert_eq!(schema_version(tree).unwrap(), 1);
+        assert_eq!(
+            VersionedKey::from_bytes(&schema_version_key()).key,
+            MapKey::SchemaVersion
+        );
+        for key in tree.iter().keys() {
+            let key = VersionedKey::from_bytes(&key.unwrap());
+            assert!(key.key == MapKey::SchemaVersion || key.version == 1);
+        }
+    }
+
+    #[test]
+    fn unversioned_protocol_secret_is_v0() {
+        let db = GunDatabase::test_new();
+        assert_eq!(upgrade_derivation_version(&db).unwrap(), None);
+        db.insert_entity(ProtocolKind::Bet, ProtocolSecret::Bytes([42u8; 64]))
+            .unwrap();
+        assert_eq!(db.bet_derivation_version().unwrap(), DerivationVersion::V0);
+        assert_eq!(
+            upgrade_derivation_version(&db).unwrap(),
+            Some((DerivationVersion::V0, DerivationVersion::CURRENT))
+        );
+        assert_eq!(upgrade_derivation_version(&db).unwrap(), None);
+        assert_eq!(
+            db.bet_keychain().unwrap().unwrap().version(),
+            DerivationVersion::CURRENT
+        );
+    }
+
+    #[test]
+    fn refuse_newer_database() {
+        let db = GunDatabase::test_new();
+        set_schema_version(db.tree(), DB_VERSION + 1).unwrap();
+        assert!(migrate(db.tree(), true).is_err());
+    }
+
+    #[test]
+    fn missing_migration_is_an_error() {
+        let db = GunDatabase::test_new();
+        set_schema_version(db.tree(), 0).unwrap();
+        assert!(migrate_with(db.tree(), &[], 1, true).is_err());
+    }
+}
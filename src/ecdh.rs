@@ -1,6 +1,6 @@
 use crate::{keychain::KeyPair, rand_core::SeedableRng};
 use chacha20::{cipher::*, ChaCha20, ChaCha20Rng};
-use olivia_secp256k1::schnorr_fun::fun::{g, marker::*, Point};
+use olivia_secp256k1::schnorr_fun::fun::{g, marker::*, Point, Scalar};
 use sha2::{
     digest::{
         generic_array::{sequence::Split, typenum::U32},
@@ -20,3 +20,17 @@ pub fn ecdh(keypair: &KeyPair, remote: &Point<EvenY>) -> (ChaCha20, ChaCha20Rng)
 
     (cipher, rng)
 }
+
+/// The key for authenticating messages between the parties of a bet. Only they can compute it.
+pub fn bet_thread_mac_key(secret_key: &Scalar, remote: &Point) -> [u8; 32] {
+    let x = secret_key;
+    let Y = remote;
+    let XY = g!(x * Y).mark::<Normal>();
+    let (mac_key, _) = Split::<u8, U32>::split(
+        Sha512::default()
+            .chain(XY.to_xonly().as_bytes())
+            .chain(b"gun-bet-thread")
+            .finalize(),
+    );
+    mac_key.into()
+}
@@ -1,4 +1,5 @@
 use bincode::Options;
+use std::str::FromStr;
 
 pub fn serialize_base2048<S: serde::Serialize>(thing: &S) -> String {
     base2048::encode(&serialize(thing))
@@ -19,11 +20,79 @@ pub enum DecodeError {
     Base2048,
     #[error("invalid data: {0}")]
     Bincode(bincode::Error),
+    #[error("invalid chunks: {0}")]
+    Chunks(&'static str),
+}
+
+/// Split a string into numbered chunks like `2/3:<data>` that are each at most `max_len`
+/// characters long (including the number) for transports that limit message length.
+///
+/// Returns `None` if `max_len` is too short to fit any data after the number.
+pub fn chunk(string: &str, max_len: usize) -> Option<Vec<String>> {
+    let chars = string.chars().collect::<Vec<_>>();
+    let mut n_chunks = 1;
+    let per_chunk = loop {
+        let prefix_len = 2 * n_chunks.to_string().len() + 2;
+        let per_chunk = max_len.checked_sub(prefix_len).filter(|len| *len > 0)?;
+        let needed = ((chars.len() + per_chunk - 1) / per_chunk).max(1);
+        if needed <= n_chunks {
+            break per_chunk;
+        }
+        n_chunks = needed;
+    };
+
+    Some(
+        chars
+            .chunks(per_chunk)
+            .enumerate()
+            .map(|(i, data)| format!("{}/{}:{}", i + 1, n_chunks, data.iter().collect::<String>()))
+            .collect(),
+    )
+}
+
+/// Put whitespace separated chunks made by [`chunk`] back together. They can be in any order.
+///
+/// Returns `None` if the string isn't chunked.
+pub fn assemble_chunks(string: &str) -> Option<Result<String, DecodeError>> {
+    let mut chunks = vec![];
+    for piece in string.split_whitespace() {
+        let (numbers, data) = piece.split_once(':')?;
+        let (i, n) = numbers.split_once('/')?;
+        let i = usize::from_str(i).ok()?;
+        let n = usize::from_str(n).ok()?;
+        chunks.push((i, n, data));
+    }
+
+    Some(join_chunks(chunks))
+}
+
+fn join_chunks(mut chunks: Vec<(usize, usize, &str)>) -> Result<String, DecodeError> {
+    let n = chunks.first().ok_or(DecodeError::Chunks("no chunks"))?.1;
+    if chunks
+        .iter()
+        .any(|(i, n_, _)| *n_ != n || *i == 0 || *i > n)
+    {
+        return Err(DecodeError::Chunks("chunk numbers don't match"));
+    }
+    chunks.sort_by_key(|(i, _, _)| *i);
+    chunks.dedup_by_key(|(i, _, _)| *i);
+    if chunks.len() != n {
+        return Err(DecodeError::Chunks("some chunks are missing"));
+    }
+    Ok(chunks.into_iter().map(|(_, _, data)| data).collect())
 }
 
 pub fn deserialize_base2048<D: serde::de::DeserializeOwned>(
     string: &str,
 ) -> Result<D, DecodeError> {
+    let assembled;
+    let string = match assemble_chunks(string) {
+        Some(assembled_string) => {
+            assembled = assembled_string?;
+            &assembled[..]
+        }
+        None => string,
+    };
     let decoded = base2048::decode(string).ok_or(DecodeError::Base2048)?;
     deserialize(&decoded[..]).map_err(DecodeError::Bincode)
 }
@@ -44,4 +113,23 @@ mod test {
         assert_eq!(serialize(&bytes).len(), 12);
         assert_eq!(serialize_base2048(&bytes).chars().count(), 9);
     }
+
+    #[test]
+    fn chunk_and_assemble() {
+        let string = serialize_base2048(&vec![7u8; 300]);
+        let chunks = chunk(&string, 40).unwrap();
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 40));
+        assert!(chunks[1].starts_with(&format!("2/{}:", chunks.len())));
+
+        let mut shuffled = chunks.clone();
+        shuffled.reverse();
+        assert_eq!(
+            assemble_chunks(&shuffled.join(" ")).unwrap().unwrap(),
+            string
+        );
+        assert!(assemble_chunks(&chunks[1..].join("\n")).unwrap().is_err());
+        assert!(assemble_chunks(&string).is_none());
+        assert!(chunk(&string, 4).is_none());
+    }
 }
@@ -0,0 +1,345 @@
+//! 2-of-3 escrow contracts between a buyer, a seller and an arbiter.
+//!
+//! Both parties share their escrow key (`gun escrow key`) and agree on an arbiter. The keys used
+//! in the escrow are the parties' escrow keys tweaked with a secret from their ECDH so different
+//! escrows between the same people (see `nonce`) don't share an address. The arbiter's key is used
+//! as is so they can sign with whatever holds it.
+use crate::{keychain::KeyPair, wallet::GunWallet, FeeSpec};
+use anyhow::{anyhow, Context};
+use bdk::{
+    bitcoin::{
+        self, util::psbt::PartiallySignedTransaction as Psbt, Address, Amount, Network, OutPoint,
+        PrivateKey, PublicKey, Script, Txid,
+    },
+    blockchain::{Blockchain, GetInputState, InputState},
+    database::MemoryDatabase,
+    descriptor::ExtendedDescriptor,
+    keys::DescriptorSinglePub,
+    miniscript::{descriptor::Wsh, Descriptor, DescriptorPublicKey, DescriptorTrait},
+    signer::SignerOrdering,
+    wallet::AddressIndex,
+    KeychainKind, SignOptions, Wallet,
+};
+use olivia_secp256k1::fun::{g, marker::*, s, Point, Scalar, G};
+use sha2::{Digest, Sha512};
+use std::{str::FromStr, sync::Arc};
+
+pub type EscrowId = u32;
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Escrow {
+    /// Our key, the counterparty's key and the arbiter's key
+    pub keys: [Point; 3],
+    /// The secret key for `keys[0]`
+    pub my_key: Scalar,
+    pub nonce: u32,
+    pub state: EscrowState,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "state")]
+pub enum EscrowState {
+    /// Waiting for someone to pay into the escrow address
+    Open,
+    /// Coins have been paid into the escrow address
+    Funded {
+        outpoint: OutPoint,
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        value: Amount,
+    },
+    /// The coins have been spent out of the escrow
+    Released {
+        outpoint: OutPoint,
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        value: Amount,
+        txid: Txid,
+        /// None implies the tx is in mempool
+        height: Option<u32>,
+    },
+}
+
+impl EscrowState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EscrowState::Open => "open",
+            EscrowState::Funded { .. } => "funded",
+            EscrowState::Released { height: None, .. } => "releasing",
+            EscrowState::Released {
+                height: Some(_), ..
+            } => "released",
+        }
+    }
+}
+
+impl Escrow {
+    pub fn new(
+        identity: &KeyPair,
+        their_identity: &Point<EvenY>,
+        arbiter: Point,
+        nonce: u32,
+    ) -> Self {
+        let x = &identity.secret_key;
+        let Y = their_identity;
+        let XY = g!(x * Y).mark::<Normal>();
+        let tweaks = Sha512::default()
+            .chain(XY.to_xonly().as_bytes())
+            .chain(nonce.to_be_bytes())
+            .finalize();
+        let tweak = |bytes: &[u8]| {
+            Scalar::from_slice_mod_order(bytes)
+                .expect("is 32 bytes long")
+                .mark::<NonZero>()
+                .expect("computationally unreachable")
+        };
+        // whoever has the lower key gets the first tweak so both sides agree
+        let (my_tweak, their_tweak) =
+            match identity.public_key.to_bytes() < their_identity.to_bytes() {
+                true => (tweak(&tweaks[..32]), tweak(&tweaks[32..])),
+                false => (tweak(&tweaks[32..]), tweak(&tweaks[..32])),
+            };
+
+        let my_key = s!(x + my_tweak)
+            .mark::<NonZero>()
+            .expect("computationally unreachable");
+        let my_point = g!(my_key * G).mark::<Normal>();
+        let their_point = g!(their_identity + their_tweak * G)
+            .mark::<(Normal, NonZero)>()
+            .expect("computationally unreachable");
+
+        Escrow {
+            keys: [my_point, their_point, arbiter],
+            my_key,
+            nonce,
+            state: EscrowState::Open,
+        }
+    }
+
+    fn public_keys(&self) -> Vec<PublicKey> {
+        self.keys
+            .iter()
+            .map(|key| PublicKey {
+                compressed: true,
+                key: (*key).into(),
+            })
+            .collect()
+    }
+
+    pub fn descriptor(&self) -> Descriptor<PublicKey> {
+        Descriptor::Wsh(Wsh::new_sortedmulti(2, self.public_keys()).unwrap())
+    }
+
+    pub fn wallet_descriptor(&self) -> ExtendedDescriptor {
+        let keys = self
+            .public_keys()
+            .into_iter()
+            .map(|key| DescriptorPublicKey::SinglePub(DescriptorSinglePub { origin: None, key }))
+            .collect();
+        Descriptor::Wsh(Wsh::new_sortedmulti(2, keys).unwrap())
+    }
+
+    pub fn address(&self, network: Network) -> Address {
+        self.descriptor().address(network).unwrap()
+    }
+}
+
+/// An unspent output as esplora's `/address/:address/utxo` returns it
+#[derive(serde::Deserialize)]
+struct EsploraUtxo {
+    txid: Txid,
+    vout: u32,
+    value: u64,
+}
+
+impl GunWallet {
+    fn get_escrow(&self, escrow_id: EscrowId) -> anyhow::Result<Escrow> {
+        self.gun_db()
+            .get_entity::<Escrow>(escrow_id)?
+            .ok_or(anyhow!("Escrow {} doesn't exist", escrow_id))
+    }
+
+    /// Look for the escrow being funded or released and update its state.
+    pub fn poke_escrow(&self, escrow_id: EscrowId, esplora_url: &str) -> anyhow::Result<()> {
+        let mut escrow = self.get_escrow(escrow_id)?;
+        let new_state = match escrow.state {
+            EscrowState::Open => {
+                let address = escrow.address(self.bdk_wallet().network());
+                let url = format!(
+                    "{}/address/{}/utxo",
+                    esplora_url.trim_end_matches('/'),
+                    address
+                );
+                let utxos: Vec<EsploraUtxo> = self
+                    .http_get(&url)?
+                    .into_json()
+                    .with_context(|| format!("decoding response from {}", url))?;
+                match utxos.into_iter().next() {
+                    Some(utxo) => EscrowState::Funded {
+                        outpoint: OutPoint::new(utxo.txid, utxo.vout),
+                        value: Amount::from_sat(utxo.value),
+                    },
+                    None => return Ok(()),
+                }
+            }
+            EscrowState::Funded { outpoint, value }
+            | EscrowState::Released {
+                outpoint,
+                value,
+                height: None,
+                ..
+            } => match self.blockchain()?.input_state(&[outpoint])? {
+                InputState::Spent { txid, height, .. } => EscrowState::Released {
+                    outpoint,
+                    value,
+                    txid,
+                    height,
+                },
+                InputState::Unspent => EscrowState::Funded { outpoint, value },
+            },
+            EscrowState::Released { .. } => return Ok(()),
+        };
+
+        if new_state != escrow.state {
+            escrow.state = new_state;
+            self.gun_db().insert_entity(escrow_id, escrow)?;
+        }
+        Ok(())
+    }
+
+    /// Make a transaction spending the escrow to `to` with our signature on it. Someone else (the
+    /// counterparty or the arbiter) has to sign it too.
+    pub fn release_escrow(
+        &self,
+        escrow_id: EscrowId,
+        to: Script,
+        fee: FeeSpec,
+    ) -> anyhow::Result<Psbt> {
+        let escrow = self.get_escrow(escrow_id)?;
+        let outpoint = match escrow.state {
+            EscrowState::Funded { outpoint, .. } => outpoint,
+            _ => {
+                return Err(anyhow!(
+                    "Escrow {} can't be released because it is {}",
+                    escrow_id,
+                    escrow.state.name()
+                ))
+            }
+        };
+        let funding_tx = self.blockchain()?.get_tx(&outpoint.txid)?.ok_or(anyhow!(
+            "Couldn't find the escrow's transaction {}",
+            outpoint.txid
+        ))?;
+        let descriptor = escrow.descriptor();
+        let psbt_input = bitcoin::util::psbt::Input {
+            witness_utxo: Some(funding_tx.output[outpoint.vout as usize].clone()),
+            non_witness_utxo: Some(funding_tx),
+            witness_script: Some(descriptor.script_code()),
+            ..Default::default()
+        };
+
+        let bdk_wallet = self.bdk_wallet();
+        let mut builder = bdk_wallet.build_tx();
+        builder.manually_selected_only().enable_rbf();
+        fee.apply_to_builder(self, &mut builder)?;
+        let tx_locktime = self.apply_locktime_policy(&mut builder)?;
+        builder.add_foreign_utxo(outpoint, psbt_input, descriptor.max_satisfaction_weight()?)?;
+        builder.drain_to(to);
+
+        let (mut psbt, _) = builder.finish()?;
+        fee.check_psbt(&psbt)?;
+        self.record_locktime(&psbt, tx_locktime)?;
+
+        self.sign_escrow(escrow_id, &mut psbt)?;
+        Ok(psbt)
+    }
+
+    /// Add our signature to a transaction releasing the escrow.
+    ///
+    /// Returns whether the transaction has enough signatures to be broadcast.
+    pub fn sign_escrow(&self, escrow_id: EscrowId, psbt: &mut Psbt) -> anyhow::Result<bool> {
+        let escrow = self.get_escrow(escrow_id)?;
+        let outpoint = match escrow.state {
+            EscrowState::Funded { outpoint, .. }
+            | EscrowState::Released {
+                outpoint,
+                height: None,
+                ..
+            } => outpoint,
+            _ => {
+                return Err(anyhow!(
+                    "Escrow {} can't be released because it is {}",
+                    escrow_id,
+                    escrow.state.name()
+                ))
+            }
+        };
+        let tx = &psbt.unsigned_tx;
+        if tx.input.len() != 1 || tx.input[0].previous_output != outpoint {
+            return Err(anyhow!(
+                "The transaction must only spend the escrow's output {}",
+                outpoint
+            ));
+        }
+
+        let network = self.bdk_wallet().network();
+        let mut tmp_wallet = Wallet::new_offline(
+            escrow.wallet_descriptor(),
+            None,
+            network,
+            MemoryDatabase::default(),
+        )
+        .expect("nothing can go wrong here");
+        // so the wallet knows the escrow's script when finalizing
+        tmp_wallet.get_address(AddressIndex::New)?;
+        tmp_wallet.add_signer(
+            KeychainKind::External,
+            SignerOrdering::default(),
+            Arc::new(PrivateKey {
+                compressed: true,
+                network,
+                key: escrow.my_key.clone().into(),
+            }),
+        );
+        Ok(tmp_wallet.sign(psbt, SignOptions::default())?)
+    }
+}
+
+/// Parses a public key given as 32 byte x-only hex (like `gun escrow key` prints) or 33 byte
+/// compressed hex.
+pub fn parse_public_key(string: &str) -> anyhow::Result<Point> {
+    match Point::<EvenY>::from_str(string) {
+        Ok(point) => Ok(point.mark::<Normal>()),
+        Err(_) => {
+            Point::from_str(string).map_err(|_| anyhow::anyhow!("'{}' is not a public key", string))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn both_parties_get_the_same_address() {
+        let buyer = KeyPair::from_slice(&[1u8; 32]).unwrap();
+        let seller = KeyPair::from_slice(&[2u8; 32]).unwrap();
+        let arbiter = KeyPair::from_slice(&[3u8; 32])
+            .unwrap()
+            .public_key
+            .mark::<Normal>();
+
+        let buyers = Escrow::new(&buyer, &seller.public_key, arbiter, 0);
+        let sellers = Escrow::new(&seller, &buyer.public_key, arbiter, 0);
+        assert_eq!(buyers.keys[0], sellers.keys[1]);
+        assert_eq!(buyers.keys[1], sellers.keys[0]);
+        assert_eq!(
+            buyers.address(Network::Regtest),
+            sellers.address(Network::Regtest)
+        );
+
+        let another = Escrow::new(&buyer, &seller.public_key, arbiter, 1);
+        assert_ne!(
+            buyers.address(Network::Regtest),
+            another.address(Network::Regtest)
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! Picking which of the configured esplora servers to use.
+use crate::{config::Config, elog, net::Net};
+use bdk::blockchain::{
+    esplora::EsploraBlockchainConfig, ConfigurableBlockchain, EsploraBlockchain,
+};
+use once_cell::sync::OnceCell;
+use std::time::{Duration, Instant};
+
+/// A server this many blocks behind the best one we can reach is skipped.
+const MAX_LAG: u32 = 2;
+/// Health checks shouldn't hold things up for as long as a real request might.
+const CHECK_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Clone, Debug)]
+pub struct BackendStatus {
+    pub url: String,
+    pub tip: Result<u32, String>,
+    pub latency: Duration,
+}
+
+/// Ask each configured server for its tip height (concurrently).
+pub fn check_backends(config: &Config) -> Vec<BackendStatus> {
+    let net = Net::new(
+        Duration::from_secs(config.timeout().min(CHECK_TIMEOUT_SECS)),
+        0,
+    );
+    let checks = config.esplora_urls().into_iter().map(|url| {
+        let net = &net;
+        async move {
+            let started = Instant::now();
+            let tip = net
+                .get_text(&format!("{}/blocks/tip/height", url.trim_end_matches('/')))
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|body| body.trim().parse::<u32>().map_err(|e| e.to_string()));
+            BackendStatus {
+                url,
+                tip,
+                latency: started.elapsed(),
+            }
+        }
+    });
+    net.block_on(futures::future::join_all(checks))
+}
+
+/// The first server in the order they're configured that is reachable and not lagging behind
+/// the others.
+pub fn choose_backend(statuses: &[BackendStatus]) -> Option<&BackendStatus> {
+    let best_tip = statuses
+        .iter()
+        .filter_map(|status| status.tip.as_ref().ok())
+        .max()?;
+    statuses.iter().find(|status| match status.tip {
+        Ok(tip) => tip + MAX_LAG >= *best_tip,
+        Err(_) => false,
+    })
+}
+
+/// The url of the esplora server to use. Only checks the servers if there's more than one.
+pub fn select_url(config: &Config) -> String {
+    let primary = config.blockchain_config().base_url.clone();
+    if config.esplora_fallbacks.is_empty() {
+        return primary;
+    }
+    let statuses = check_backends(config);
+    match choose_backend(&statuses) {
+        Some(chosen) => {
+            if chosen.url != primary {
+                let reason = match &statuses[0].tip {
+                    Ok(tip) => format!("is at height {}", tip),
+                    Err(e) => format!("is unreachable ({})", e),
+                };
+                elog!(@recoverable_error "esplora server {} {} -- using {}", primary, reason, chosen.url);
+            }
+            chosen.url.clone()
+        }
+        None => {
+            elog!(@recoverable_error "none of the esplora servers could be reached");
+            primary
+        }
+    }
+}
+
+/// Connects to esplora the first time something needs it so that commands which only look at
+/// what's in the database work offline.
+pub struct LazyEsplora {
+    config: Config,
+    url: OnceCell<String>,
+    blockchain: OnceCell<EsploraBlockchain>,
+}
+
+impl LazyEsplora {
+    pub fn new(config: Config) -> Self {
+        LazyEsplora {
+            config,
+            url: OnceCell::new(),
+            blockchain: OnceCell::new(),
+        }
+    }
+
+    pub fn get(&self) -> anyhow::Result<&EsploraBlockchain> {
+        self.blockchain.get_or_try_init(|| self.connect())
+    }
+
+    /// A new client for the same server as [`get`](Self::get) (for things that need to own one).
+    pub fn connect(&self) -> anyhow::Result<EsploraBlockchain> {
+        self.connect_with_stop_gap(self.stop_gap())
+    }
+
+    /// Like [`connect`](Self::connect) but syncing looks through `stop_gap` unused addresses.
+    pub fn connect_with_stop_gap(&self, stop_gap: usize) -> anyhow::Result<EsploraBlockchain> {
+        let base_url = self.url.get_or_init(|| select_url(&self.config)).clone();
+        Ok(EsploraBlockchain::from_config(&EsploraBlockchainConfig {
+            base_url,
+            timeout: Some(self.config.timeout()),
+            stop_gap,
+            ..self.config.blockchain_config().clone()
+        })?)
+    }
+
+    pub fn stop_gap(&self) -> usize {
+        self.config.blockchain_config().stop_gap
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn status(url: &str, tip: Result<u32, &str>) -> BackendStatus {
+        BackendStatus {
+            url: url.into(),
+            tip: tip.map_err(String::from),
+            latency: Duration::default(),
+        }
+    }
+
+    #[test]
+    fn skips_down_and_lagging_servers() {
+        let statuses = vec![
+            status("a", Err("connection refused")),
+            status("b", Ok(100)),
+            status("c", Ok(110)),
+            status("d", Ok(109)),
+        ];
+        assert_eq!(choose_backend(&statuses).unwrap().url, "c");
+        assert_eq!(choose_backend(&statuses[..2]).unwrap().url, "b");
+        assert!(choose_backend(&statuses[..1]).is_none());
+    }
+}
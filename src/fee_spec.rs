@@ -1,93 +1,233 @@
 use std::str::FromStr;
 
+use crate::psbt_ext::PsbtFeeRate;
 use anyhow::anyhow;
 use bdk::{
-    bitcoin::Amount,
-    blockchain::Blockchain,
+    bitcoin::{util::psbt::PartiallySignedTransaction as Psbt, Amount},
     database::BatchDatabase,
     wallet::{coin_selection::CoinSelectionAlgorithm, tx_builder::TxBuilderContext},
     FeeRate, TxBuilder,
 };
 
 #[derive(Debug, Clone, PartialEq)]
-///Hello
-pub enum FeeSpec {
+pub enum FeeTarget {
     Absolute(Amount),
     Rate(FeeRate),
     Height(u32),
 }
 
-impl Default for FeeSpec {
+impl Default for FeeTarget {
     fn default() -> Self {
-        FeeSpec::Height(1)
+        FeeTarget::Height(1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+/// How to choose the fee of a transaction and optionally a hard ceiling on what we will pay.
+pub struct FeeSpec {
+    pub target: FeeTarget,
+    pub max: Option<Amount>,
+}
+
+impl From<FeeTarget> for FeeSpec {
+    fn from(target: FeeTarget) -> Self {
+        FeeSpec { target, max: None }
+    }
+}
+
+/// Something that can estimate the feerate needed to confirm within a number of blocks.
+pub trait EstimateFee {
+    fn estimate_feerate(&self, in_blocks: u32) -> anyhow::Result<FeeRate>;
+}
+
+/// Anything higher than this (sats per vbyte) from a fee estimator is assumed to be a mistake.
+pub const MAX_SANE_FEERATE: f32 = 5_000.0;
+
+/// The response from mempool.space's `/v1/fees/recommended` (in sats per vbyte).
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecommendedFees {
+    pub fastest_fee: f32,
+    pub half_hour_fee: f32,
+    pub hour_fee: f32,
+    pub economy_fee: f32,
+    pub minimum_fee: f32,
+}
+
+impl RecommendedFees {
+    /// The recommended feerate for confirming within `in_blocks` blocks.
+    pub fn for_blocks(&self, in_blocks: u32) -> anyhow::Result<FeeRate> {
+        let rate = match in_blocks {
+            0 | 1 => self.fastest_fee,
+            2 | 3 => self.half_hour_fee,
+            4..=6 => self.hour_fee,
+            _ => self.economy_fee,
+        }
+        .max(self.minimum_fee)
+        .max(1.0);
+        if !rate.is_finite() || rate > MAX_SANE_FEERATE {
+            return Err(anyhow!(
+                "estimated feerate of {} sats/vb is not believable",
+                rate
+            ));
+        }
+        Ok(FeeRate::from_sat_per_vb(rate))
     }
 }
 
 impl FeeSpec {
     pub fn apply_to_builder<
-        B: Blockchain,
+        E: EstimateFee,
+        B,
         D: BatchDatabase,
         Cs: CoinSelectionAlgorithm<D>,
         Ctx: TxBuilderContext,
     >(
         &self,
-        blockchain: &B,
+        estimator: &E,
         builder: &mut TxBuilder<'_, B, D, Cs, Ctx>,
     ) -> anyhow::Result<()> {
-        use FeeSpec::*;
-        match self {
-            Absolute(fee) => {
+        match &self.target {
+            FeeTarget::Absolute(fee) => {
+                self.check_fee(*fee)?;
                 builder.fee_absolute(fee.as_sat());
             }
-            Rate(rate) => {
-                builder.fee_rate(*rate);
-            }
-            Height(height) => {
-                let feerate = blockchain.estimate_fee(*height as usize)?;
+            _ => {
+                let feerate = self
+                    .feerate(estimator)?
+                    .expect("only absolute fees don't have a feerate");
                 builder.fee_rate(feerate);
             }
         }
         Ok(())
     }
+
+    /// The feerate this spec asks for without building anything.
+    ///
+    /// Returns `None` for absolute fees since their feerate depends on the transaction.
+    pub fn feerate<E: EstimateFee>(&self, estimator: &E) -> anyhow::Result<Option<FeeRate>> {
+        use FeeTarget::*;
+        Ok(match &self.target {
+            Absolute(_) => None,
+            Rate(rate) => Some(*rate),
+            Height(height) => Some(estimator.estimate_feerate(*height)?),
+        })
+    }
+
+    /// Errors if `fee` is over the ceiling set with `max:`.
+    pub fn check_fee(&self, fee: Amount) -> anyhow::Result<()> {
+        match self.max {
+            Some(max) if fee > max => Err(anyhow!(
+                "the transaction fee {} is more than the maximum of {} you set with {}",
+                fee,
+                max,
+                self
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Errors if the fee paid by the psbt is over the ceiling set with `max:`.
+    pub fn check_psbt(&self, psbt: &Psbt) -> anyhow::Result<()> {
+        let (fee, _, _) = psbt.fee();
+        self.check_fee(fee)
+    }
 }
 
 impl FromStr for FeeSpec {
     type Err = anyhow::Error;
 
     fn from_str(string: &str) -> anyhow::Result<Self> {
-        use crate::amount_ext::FromCliStr;
+        let mut parts = string.split(',');
+        let target = FeeTarget::from_str(parts.next().unwrap_or(""))?;
+        let mut max = None;
+
+        for part in parts {
+            match part.strip_prefix("max:") {
+                Some(amount) if max.is_none() => max = Some(parse_amount(amount)?),
+                _ => return Err(anyhow!("'{}' is not a valid fee specification", string)),
+            }
+        }
+
+        // So you can't ask for something impossible
+        if let (FeeTarget::Absolute(fee), Some(max)) = (&target, max) {
+            if *fee > max {
+                return Err(anyhow!(
+                    "the absolute fee {} is more than the maximum {}",
+                    fee,
+                    max
+                ));
+            }
+        }
+
+        Ok(FeeSpec { target, max })
+    }
+}
+
+fn parse_amount(amount: &str) -> anyhow::Result<Amount> {
+    use crate::amount_ext::FromCliStr;
+    Ok(match u64::from_str(amount).ok() {
+        Some(int_amount) => Amount::from_sat(int_amount),
+        None => Amount::from_cli_str(amount)?,
+    })
+}
+
+impl FromStr for FeeTarget {
+    type Err = anyhow::Error;
 
+    fn from_str(string: &str) -> anyhow::Result<Self> {
         if let Some(rate) = string.strip_prefix("rate:") {
             let rate = f32::from_str(rate)?;
-            return Ok(FeeSpec::Rate(FeeRate::from_sat_per_vb(rate)));
+            return Ok(FeeTarget::Rate(FeeRate::from_sat_per_vb(rate)));
         }
 
         if let Some(amount) = string.strip_prefix("abs:") {
-            return Ok(match u64::from_str(amount).ok() {
-                Some(int_amount) => FeeSpec::Absolute(Amount::from_sat(int_amount)),
-                None => FeeSpec::Absolute(Amount::from_cli_str(amount)?),
-            });
+            return Ok(FeeTarget::Absolute(parse_amount(amount)?));
         }
 
         if let Some(in_blocks) = string.strip_prefix("in-blocks:") {
             let in_blocks = u32::from_str(in_blocks)?;
-            return Ok(FeeSpec::Height(in_blocks));
+            return Ok(FeeTarget::Height(in_blocks));
         }
 
         Err(anyhow!("'{}' is not a valid fee specification", string))
     }
 }
 
-impl core::fmt::Display for FeeSpec {
+impl core::fmt::Display for FeeTarget {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            FeeSpec::Rate(rate) => write!(f, "rate:{}", rate.as_sat_vb()),
-            FeeSpec::Absolute(abs) => write!(f, "abs:{}", abs),
-            FeeSpec::Height(height) => write!(f, "in-blocks:{}", height),
+            FeeTarget::Rate(rate) => write!(f, "rate:{}", rate.as_sat_vb()),
+            FeeTarget::Absolute(abs) => write!(f, "abs:{}", abs.as_sat()),
+            FeeTarget::Height(height) => write!(f, "in-blocks:{}", height),
         }
     }
 }
 
+impl core::fmt::Display for FeeSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.target)?;
+        if let Some(max) = self.max {
+            write!(f, ",max:{}sat", max.as_sat())?;
+        }
+        Ok(())
+    }
+}
+
+// In the config we write fee specs the same way as on the command line.
+impl serde::Serialize for FeeSpec {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for FeeSpec {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let string = String::deserialize(deserializer)?;
+        FeeSpec::from_str(&string).map_err(serde::de::Error::custom)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -96,19 +236,74 @@ mod test {
     fn parse_feespec() {
         assert_eq!(
             FeeSpec::from_str("abs:300sat").unwrap(),
-            FeeSpec::Absolute(Amount::from_sat(300))
+            FeeTarget::Absolute(Amount::from_sat(300)).into()
         );
         assert_eq!(
             FeeSpec::from_str("abs:300").unwrap(),
-            FeeSpec::Absolute(Amount::from_sat(300))
+            FeeTarget::Absolute(Amount::from_sat(300)).into()
         );
         assert_eq!(
             FeeSpec::from_str("rate:3.5").unwrap(),
-            FeeSpec::Rate(FeeRate::from_sat_per_vb(3.5))
+            FeeTarget::Rate(FeeRate::from_sat_per_vb(3.5)).into()
         );
         assert_eq!(
             FeeSpec::from_str("in-blocks:5").unwrap(),
-            FeeSpec::Height(5)
+            FeeTarget::Height(5).into()
+        );
+    }
+
+    #[test]
+    fn parse_feespec_with_max() {
+        let spec = FeeSpec::from_str("rate:4.5,max:20000sat").unwrap();
+        assert_eq!(
+            spec,
+            FeeSpec {
+                target: FeeTarget::Rate(FeeRate::from_sat_per_vb(4.5)),
+                max: Some(Amount::from_sat(20_000)),
+            }
+        );
+        assert_eq!(FeeSpec::from_str(&spec.to_string()).unwrap(), spec);
+        assert!(spec.check_fee(Amount::from_sat(20_000)).is_ok());
+        assert!(spec.check_fee(Amount::from_sat(20_001)).is_err());
+
+        assert!(FeeSpec::from_str("abs:300000,max:20000").is_err());
+        assert!(FeeSpec::from_str("rate:1,max:1,max:2").is_err());
+        assert!(FeeSpec::from_str("rate:1,foo:2").is_err());
+    }
+
+    #[test]
+    fn feespec_serde_roundtrip() {
+        let spec = FeeSpec::from_str("in-blocks:3,max:5000sat").unwrap();
+        let json = serde_json::to_string(&spec).unwrap();
+        assert_eq!(json, r#""in-blocks:3,max:5000sat""#);
+        assert_eq!(serde_json::from_str::<FeeSpec>(&json).unwrap(), spec);
+        assert!(serde_json::from_str::<FeeSpec>(r#""fast""#).is_err());
+    }
+
+    #[test]
+    fn recommended_fees_for_blocks() {
+        let fees = serde_json::from_str::<RecommendedFees>(
+            r#"{"fastestFee":30,"halfHourFee":20,"hourFee":12,"economyFee":4,"minimumFee":2}"#,
+        )
+        .unwrap();
+        assert_eq!(fees.for_blocks(1).unwrap(), FeeRate::from_sat_per_vb(30.0));
+        assert_eq!(fees.for_blocks(3).unwrap(), FeeRate::from_sat_per_vb(20.0));
+        assert_eq!(fees.for_blocks(6).unwrap(), FeeRate::from_sat_per_vb(12.0));
+        assert_eq!(fees.for_blocks(144).unwrap(), FeeRate::from_sat_per_vb(4.0));
+
+        let silly = RecommendedFees {
+            fastest_fee: 1e9,
+            ..fees.clone()
+        };
+        assert!(silly.for_blocks(1).is_err());
+        let too_low = RecommendedFees {
+            economy_fee: 0.0,
+            minimum_fee: 0.0,
+            ..fees
+        };
+        assert_eq!(
+            too_low.for_blocks(144).unwrap(),
+            FeeRate::from_sat_per_vb(1.0)
         );
     }
 }
@@ -0,0 +1,104 @@
+//! Keeping track of the change addresses proposals give out.
+//!
+//! A proposal has the change address the proposer's leftover coins go to in it so the address is
+//! public before anything has been paid to it. If the proposal is canceled nothing ever is. Those
+//! indexes are recorded as [`ExposedChange`] so `gun split` doesn't hand them out again and so
+//! syncing looks far enough past the last used change address to find anything after a run of
+//! them (e.g. after restoring from a backup with nothing cached).
+use crate::{betting::BetId, wallet::GunWallet};
+use bdk::{bitcoin::Script, database::Database, wallet::AddressIndex, KeychainKind};
+use std::collections::BTreeMap;
+
+/// A change address given out in a proposal (keyed by its index on the internal keychain).
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ExposedChange {
+    pub bet_id: BetId,
+}
+
+/// How many unused addresses in a row sync has to look through. It's the configured `stop_gap`
+/// past the last used address plus however many exposed addresses come after it.
+pub fn sync_gap(stop_gap: usize, last_used: Option<u32>, last_exposed: Option<u32>) -> usize {
+    let first_unused = last_used.map(|index| index + 1).unwrap_or(0);
+    let exposed_run = last_exposed
+        .map(|index| (index + 1).saturating_sub(first_unused))
+        .unwrap_or(0);
+    stop_gap + exposed_run as usize
+}
+
+impl GunWallet {
+    /// Record that the change address in a proposal has been given out.
+    pub(crate) fn expose_change(&self, bet_id: BetId, script: &Script) -> anyhow::Result<()> {
+        let bdk_wallet = self.bdk_wallet();
+        let index = match bdk_wallet.database().get_path_from_script_pubkey(script)? {
+            Some((KeychainKind::Internal, index)) => index,
+            _ => return Ok(()),
+        };
+        // a proposal made from a utxo snapshot got its change from a copy of the wallet so make
+        // sure ours doesn't give out the same one.
+        let last_index = bdk_wallet
+            .database()
+            .get_last_index(KeychainKind::Internal)?;
+        if last_index
+            .map(|last_index| last_index < index)
+            .unwrap_or(true)
+        {
+            bdk_wallet.get_change_address(AddressIndex::Reset(index))?;
+        }
+        self.gun_db().insert_entity(index, ExposedChange { bet_id })
+    }
+
+    pub fn exposed_change(&self) -> BTreeMap<u32, ExposedChange> {
+        self.gun_db()
+            .list_entities_print_error::<ExposedChange>()
+            .collect()
+    }
+
+    /// The highest index on the keychain that has been paid to.
+    pub fn last_used_index(&self, keychain: KeychainKind) -> anyhow::Result<Option<u32>> {
+        let database = self.bdk_wallet().database();
+        let mut last_used = None;
+        for tx in database.iter_raw_txs()? {
+            for output in &tx.output {
+                if let Some((output_keychain, index)) =
+                    database.get_path_from_script_pubkey(&output.script_pubkey)?
+                {
+                    if output_keychain == keychain {
+                        last_used = last_used.max(Some(index));
+                    }
+                }
+            }
+        }
+        Ok(last_used)
+    }
+
+    /// The `stop_gap` to sync the internal keychain with so exposed change addresses don't hide
+    /// what comes after them.
+    pub fn internal_sync_gap(&self, stop_gap: usize) -> anyhow::Result<usize> {
+        let last_exposed = self.exposed_change().keys().next_back().copied();
+        if last_exposed.is_none() {
+            return Ok(stop_gap);
+        }
+        Ok(sync_gap(
+            stop_gap,
+            self.last_used_index(KeychainKind::Internal)?,
+            last_exposed,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sync_gap_covers_exposed_change() {
+        assert_eq!(sync_gap(20, None, None), 20);
+        assert_eq!(sync_gap(20, Some(10), None), 20);
+        // fresh restore with proposals' change up to index 29
+        assert_eq!(sync_gap(20, None, Some(29)), 50);
+        // 12 to 29 are exposed but unused
+        assert_eq!(sync_gap(20, Some(11), Some(29)), 38);
+        // used since
+        assert_eq!(sync_gap(20, Some(40), Some(29)), 20);
+    }
+}
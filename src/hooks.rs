@@ -0,0 +1,218 @@
+//! Telling the outside world when something happens to a bet.
+//!
+//! Each hook either runs a shell command or POSTs JSON to a url. Commands get the same JSON on
+//! stdin and the most useful fields as `$GUN_*` environment variables so simple scripts don't
+//! have to parse it. A hook failing never stops gun from carrying on.
+use crate::{betting::BetId, elog};
+use bdk::bitcoin::Txid;
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+/// Something to do when an event happens.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Hook {
+    /// Run a command with `sh -c`
+    Command(String),
+    /// POST the event as JSON to a url
+    Webhook(String),
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Hooks {
+    /// When the state machine moves a bet to a new state.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_bet_state_change: Option<Hook>,
+    /// When a bet, claim, refund or cancel transaction is confirmed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_tx_confirmed: Option<Hook>,
+    /// When we broadcast a transaction claiming won bets.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_claim_broadcast: Option<Hook>,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "kebab-case", tag = "event")]
+pub enum HookEvent {
+    BetStateChange {
+        bet_id: BetId,
+        old_state: &'static str,
+        new_state: &'static str,
+    },
+    TxConfirmed {
+        bet_id: BetId,
+        state: &'static str,
+    },
+    ClaimBroadcast {
+        bet_ids: Vec<BetId>,
+        txid: Txid,
+    },
+}
+
+impl HookEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            HookEvent::BetStateChange { .. } => "bet-state-change",
+            HookEvent::TxConfirmed { .. } => "tx-confirmed",
+            HookEvent::ClaimBroadcast { .. } => "claim-broadcast",
+        }
+    }
+
+    fn env_vars(&self) -> Vec<(&'static str, String)> {
+        let mut vars = vec![("GUN_EVENT", self.name().to_string())];
+        match self {
+            HookEvent::BetStateChange {
+                bet_id,
+                old_state,
+                new_state,
+            } => {
+                vars.push(("GUN_BET_ID", bet_id.to_string()));
+                vars.push(("GUN_OLD_STATE", old_state.to_string()));
+                vars.push(("GUN_NEW_STATE", new_state.to_string()));
+            }
+            HookEvent::TxConfirmed { bet_id, state } => {
+                vars.push(("GUN_BET_ID", bet_id.to_string()));
+                vars.push(("GUN_NEW_STATE", state.to_string()));
+            }
+            HookEvent::ClaimBroadcast { bet_ids, txid } => {
+                let bet_ids = bet_ids
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(",");
+                vars.push(("GUN_BET_IDS", bet_ids));
+                vars.push(("GUN_TXID", txid.to_string()));
+            }
+        }
+        vars
+    }
+}
+
+/// Whether going from `old_state` to `new_state` means a transaction got confirmed.
+pub fn is_confirmation(old_state: &str, new_state: &str) -> bool {
+    matches!(
+        (old_state, new_state),
+        ("unconfirmed", "confirmed")
+            | ("claiming", "claimed")
+            | ("refunding", "refunded")
+            | ("canceling", "canceled")
+            | ("canceling", "counterparty-canceled")
+    )
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        self == &Hooks::default()
+    }
+
+    pub fn bet_state_changed(
+        &self,
+        client: &ureq::Agent,
+        bet_id: BetId,
+        old_state: &'static str,
+        new_state: &'static str,
+    ) {
+        if let Some(hook) = &self.on_bet_state_change {
+            hook.run(
+                client,
+                &HookEvent::BetStateChange {
+                    bet_id,
+                    old_state,
+                    new_state,
+                },
+            );
+        }
+        if is_confirmation(old_state, new_state) {
+            if let Some(hook) = &self.on_tx_confirmed {
+                hook.run(
+                    client,
+                    &HookEvent::TxConfirmed {
+                        bet_id,
+                        state: new_state,
+                    },
+                );
+            }
+        }
+    }
+
+    pub fn claim_broadcast(&self, client: &ureq::Agent, bet_ids: &[BetId], txid: Txid) {
+        if let Some(hook) = &self.on_claim_broadcast {
+            hook.run(
+                client,
+                &HookEvent::ClaimBroadcast {
+                    bet_ids: bet_ids.to_vec(),
+                    txid,
+                },
+            );
+        }
+    }
+}
+
+impl Hook {
+    pub fn run(&self, client: &ureq::Agent, event: &HookEvent) {
+        let json = serde_json::to_value(event).expect("events always serialize");
+        let result = match self {
+            Hook::Command(command) => run_command(command, event, &json),
+            Hook::Webhook(url) => client
+                .post(url)
+                .send_json(json)
+                .map(|_| ())
+                .map_err(anyhow::Error::from),
+        };
+        if let Err(e) = result {
+            elog!(@recoverable_error "The {} hook failed: {}", event.name(), e);
+        }
+    }
+}
+
+fn run_command(command: &str, event: &HookEvent, json: &serde_json::Value) -> anyhow::Result<()> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(event.env_vars())
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // the command doesn't have to read it
+        let _ = stdin.write_all(json.to_string().as_bytes());
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(anyhow::anyhow!("'{}' exited with {}", command, status));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hooks_config_format() {
+        let hooks = serde_json::from_str::<Hooks>(
+            r#"{"on-bet-state-change": {"command": "notify-send gun"}, "on-claim-broadcast": {"webhook": "https://example.com/hook"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            hooks.on_bet_state_change,
+            Some(Hook::Command("notify-send gun".into()))
+        );
+        assert_eq!(
+            hooks.on_claim_broadcast,
+            Some(Hook::Webhook("https://example.com/hook".into()))
+        );
+        assert!(hooks.on_tx_confirmed.is_none());
+        assert!(Hooks::default().is_empty());
+    }
+
+    #[test]
+    fn confirmations() {
+        assert!(is_confirmation("unconfirmed", "confirmed"));
+        assert!(is_confirmation("claiming", "claimed"));
+        assert!(!is_confirmation("confirmed", "won"));
+        assert!(!is_confirmation("proposed", "unconfirmed"));
+    }
+}
@@ -0,0 +1,152 @@
+//! Talking to hardware wallets through the [HWI] command line tool.
+//!
+//! HWI knows how to speak to Ledger, Trezor, BitBox, Coldcard (over USB) and friends so rather than
+//! implementing each device's protocol we call out to it and parse the JSON it returns. The `hwi`
+//! binary must be in your `$PATH` or you can point `$GUN_HWI` at it.
+//!
+//! [HWI]: https://github.com/bitcoin-core/HWI
+use anyhow::{anyhow, Context};
+use bdk::bitcoin::{
+    util::{
+        bip32::{DerivationPath, ExtendedPubKey, Fingerprint},
+        psbt::PartiallySignedTransaction as Psbt,
+    },
+    Network,
+};
+use std::{process::Command, str::FromStr};
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct HwiDevice {
+    #[serde(rename = "type")]
+    pub device_type: String,
+    pub model: String,
+    pub path: String,
+    #[serde(default)]
+    pub fingerprint: Option<Fingerprint>,
+    #[serde(default)]
+    pub needs_pin_sent: bool,
+    #[serde(default)]
+    pub needs_passphrase_sent: bool,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+fn hwi_binary() -> String {
+    std::env::var("GUN_HWI").unwrap_or_else(|_| "hwi".into())
+}
+
+fn chain(network: Network) -> &'static str {
+    match network {
+        Network::Bitcoin => "main",
+        Network::Testnet => "test",
+        Network::Signet => "signet",
+        Network::Regtest => "regtest",
+    }
+}
+
+fn run_hwi(args: &[&str]) -> anyhow::Result<serde_json::Value> {
+    let binary = hwi_binary();
+    let output = Command::new(&binary)
+        .args(args)
+        .output()
+        .with_context(|| format!("running '{}' (is HWI installed?)", binary))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "'{} {}' failed: {}",
+            binary,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let value = serde_json::from_slice::<serde_json::Value>(&output.stdout)
+        .context("parsing output from HWI")?;
+
+    if let Some(error) = value.get("error") {
+        return Err(anyhow!("HWI returned an error: {}", error));
+    }
+
+    Ok(value)
+}
+
+fn run_hwi_with_device(
+    fingerprint: Fingerprint,
+    network: Network,
+    args: &[&str],
+) -> anyhow::Result<serde_json::Value> {
+    let fingerprint = fingerprint.to_string();
+    let mut full_args = vec!["--fingerprint", &fingerprint, "--chain", chain(network)];
+    full_args.extend(args);
+    run_hwi(&full_args)
+}
+
+/// List the hardware wallets plugged into this computer.
+pub fn enumerate() -> anyhow::Result<Vec<HwiDevice>> {
+    let devices = run_hwi(&["enumerate"])?;
+    serde_json::from_value(devices).context("parsing device list from HWI")
+}
+
+/// Find the single device we should use. If there's more than one you have to choose with
+/// `fingerprint`.
+pub fn find_device(fingerprint: Option<Fingerprint>) -> anyhow::Result<HwiDevice> {
+    let devices = enumerate()?
+        .into_iter()
+        .filter(|device| fingerprint.is_none() || device.fingerprint == fingerprint)
+        .collect::<Vec<_>>();
+
+    match &devices[..] {
+        [] => Err(anyhow!(
+            "no hardware wallet found{} -- is it plugged in and unlocked?",
+            fingerprint
+                .map(|fingerprint| format!(" with fingerprint {}", fingerprint))
+                .unwrap_or_default()
+        )),
+        [device] => {
+            if let Some(error) = &device.error {
+                return Err(anyhow!("{} ({}): {}", device.model, device.path, error));
+            }
+            if device.needs_pin_sent || device.needs_passphrase_sent {
+                return Err(anyhow!(
+                    "{} ({}) is locked -- unlock it and try again",
+                    device.model,
+                    device.path
+                ));
+            }
+            Ok(device.clone())
+        }
+        _ => Err(anyhow!(
+            "more than one hardware wallet is plugged in. Choose one of {} with --fingerprint",
+            devices
+                .iter()
+                .filter_map(|device| device.fingerprint)
+                .map(|fingerprint| fingerprint.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+pub fn get_xpub(
+    fingerprint: Fingerprint,
+    network: Network,
+    path: &DerivationPath,
+) -> anyhow::Result<ExtendedPubKey> {
+    let path = path.to_string();
+    let value = run_hwi_with_device(fingerprint, network, &["getxpub", &path])?;
+    let xpub = value["xpub"]
+        .as_str()
+        .ok_or(anyhow!("HWI didn't return an xpub"))?;
+    let mut xpub = ExtendedPubKey::from_str(xpub).context("parsing xpub from HWI")?;
+    xpub.network = network;
+    Ok(xpub)
+}
+
+/// Have the device sign the PSBT. This blocks while the user confirms on the device.
+pub fn sign_psbt(fingerprint: Fingerprint, network: Network, psbt: &Psbt) -> anyhow::Result<Psbt> {
+    let value = run_hwi_with_device(fingerprint, network, &["signtx", &psbt.to_string()])?;
+    let psbt = value["psbt"]
+        .as_str()
+        .ok_or(anyhow!("HWI didn't return a PSBT"))?;
+    Psbt::from_str(psbt).context("parsing signed PSBT from HWI")
+}
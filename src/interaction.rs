@@ -0,0 +1,152 @@
+//! Everything gun asks the user goes through an [`Interaction`].
+//!
+//! Library code never reads stdin itself. The `gun` binary installs a [`Terminal`] (or
+//! [`AssumeYes`] with `--assume-yes`) and programs using gun as a library can install their own
+//! or [`Scripted`] answers for tests and automation.
+use anyhow::anyhow;
+use once_cell::sync::OnceCell;
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead},
+    sync::{Arc, Mutex},
+};
+
+pub trait Interaction: Send + Sync + std::fmt::Debug {
+    /// Whether the user agrees to `question`. It's phrased so it can be followed by "?".
+    fn confirm(&self, question: &str) -> bool;
+    /// Read a line after showing `prompt`. `None` means there's nothing more to read.
+    fn read_line(&self, prompt: &str) -> Option<String>;
+    /// Read something that shouldn't be shown as it's typed (e.g. a passphrase).
+    fn read_secret(&self, prompt: &str) -> anyhow::Result<String>;
+    /// Wait until the user says they're ready (e.g. after plugging in an SD card).
+    fn wait(&self) {
+        let _ = self.read_line("");
+    }
+}
+
+/// Asks on the terminal.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Terminal;
+
+impl Interaction for Terminal {
+    fn confirm(&self, question: &str) -> bool {
+        crate::elog!(@question "{} [y/n]? ", question.replace('\n', "\n> "));
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+        lines
+            .find_map(|line| match line.ok()?.trim_end().to_lowercase().as_str() {
+                "y" => Some(true),
+                "n" => Some(false),
+                _ => {
+                    eprint!("> [y/n]? ");
+                    None
+                }
+            })
+            .unwrap_or(false)
+    }
+
+    fn read_line(&self, prompt: &str) -> Option<String> {
+        if !prompt.is_empty() {
+            eprint!("{}", prompt);
+        }
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line.trim_end_matches(&['\r', '\n'][..]).to_string()),
+        }
+    }
+
+    fn read_secret(&self, prompt: &str) -> anyhow::Result<String> {
+        Ok(rpassword::prompt_password_stderr(prompt)?)
+    }
+}
+
+/// Agrees to everything without asking but still reads input and secrets from the terminal.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AssumeYes;
+
+impl Interaction for AssumeYes {
+    fn confirm(&self, question: &str) -> bool {
+        crate::elog!(@info "{}? yes (--assume-yes)", question.replace('\n', "\n> "));
+        true
+    }
+
+    fn read_line(&self, prompt: &str) -> Option<String> {
+        Terminal.read_line(prompt)
+    }
+
+    fn read_secret(&self, prompt: &str) -> anyhow::Result<String> {
+        Terminal.read_secret(prompt)
+    }
+}
+
+/// Gives answers from a list in order. Confirmations take "y" as yes and anything else
+/// (including running out of answers) as no.
+#[derive(Debug, Default)]
+pub struct Scripted {
+    answers: Mutex<VecDeque<String>>,
+}
+
+impl Scripted {
+    pub fn new<S: Into<String>>(answers: impl IntoIterator<Item = S>) -> Self {
+        Scripted {
+            answers: Mutex::new(answers.into_iter().map(Into::into).collect()),
+        }
+    }
+
+    fn next(&self) -> Option<String> {
+        self.answers.lock().unwrap().pop_front()
+    }
+
+    /// The answers that haven't been used yet.
+    pub fn remaining(&self) -> usize {
+        self.answers.lock().unwrap().len()
+    }
+}
+
+impl Interaction for Scripted {
+    fn confirm(&self, _question: &str) -> bool {
+        matches!(self.next(), Some(answer) if answer.trim().eq_ignore_ascii_case("y"))
+    }
+
+    fn read_line(&self, _prompt: &str) -> Option<String> {
+        self.next()
+    }
+
+    fn read_secret(&self, prompt: &str) -> anyhow::Result<String> {
+        self.next()
+            .ok_or(anyhow!("no scripted answer left for ‘{}’", prompt.trim()))
+    }
+}
+
+static INSTALLED: OnceCell<Arc<dyn Interaction>> = OnceCell::new();
+
+/// Set the interaction used by everything that doesn't have one passed to it. It can only be
+/// installed once and has to be done before anything asks the user something.
+pub fn install(interaction: Arc<dyn Interaction>) -> anyhow::Result<()> {
+    INSTALLED
+        .set(interaction)
+        .map_err(|_| anyhow!("an interaction has already been installed"))
+}
+
+/// The installed interaction ([`Terminal`] if none was).
+pub fn current() -> Arc<dyn Interaction> {
+    INSTALLED.get_or_init(|| Arc::new(Terminal)).clone()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scripted_answers_in_order() {
+        let scripted = Scripted::new(["y", "hello", "n", "hunter2"]);
+        assert!(scripted.confirm("first"));
+        assert_eq!(scripted.read_line("> ").as_deref(), Some("hello"));
+        assert!(!scripted.confirm("second"));
+        assert_eq!(scripted.read_secret("passphrase").unwrap(), "hunter2");
+        assert_eq!(scripted.remaining(), 0);
+        assert!(!scripted.confirm("out of answers"));
+        assert!(scripted.read_secret("passphrase").is_err());
+    }
+}
@@ -1,8 +1,40 @@
-use crate::{betting::Proposal, hex};
+//! Deriving bet keys from the protocol secret.
+//!
+//! Bet keys are never stored while a proposal is waiting for offers -- they are derived again from
+//! the protocol secret when an offer arrives. Changing how they are derived would leave those bets
+//! (and anyone restoring from a backup) unable to find their keys so each way of deriving them is a
+//! [`DerivationVersion`]. New bets use [`DerivationVersion::CURRENT`] and record the version they
+//! used so old versions must keep working for as long as there might be bets using them.
+//!
+//! [`DerivationVersion::V0`] (all HMAC-SHA512 keyed with the tag and taking the 64 byte protocol
+//! secret as the message):
+//!
+//! - proposal key: `HMAC(key=HMAC("gun-proposal", secret), msg=proposal)` where `proposal` is the
+//!   proposal encoded with [`encode::serialize`](crate::encode::serialize) with its public key set
+//!   to [`placeholder_point`](crate::placeholder_point).
+//! - offer key: `HMAC(key=HMAC("gun-offer", secret), msg=proposal)` where `proposal` is the
+//!   proposal being offered to (including its public key).
+//! - bet bundle keys: `HMAC("gun-bet-bundle", secret)` split into a cipher key and a mac key.
+//! - escrow key: `HMAC("gun-escrow", secret)` from the current secret. This isn't versioned since
+//!   it's given out once and used to identify you (escrows store their own key).
+//!
+//! For the key pairs the secret key is the first 32 bytes of the output reduced mod the curve order
+//! and negated if needed so the public key has an even y-coordinate. `test_vectors/keys.json` pins
+//! these down (see [`selftest`](crate::selftest)).
+//!
+//! A wallet can have more than one protocol secret. `gun keychain rotate` replaces the one new bets
+//! use but keeps the old ones (numbered from 0) so bets that were made with them can carry on. Bets
+//! record which secret their key came from along with the version as a [`KeyOrigin`].
+use crate::{betting::Proposal, hex, secret::Secret};
+use anyhow::anyhow;
 use bdk::bitcoin::hashes::{sha512, Hash, HashEngine, Hmac, HmacEngine};
 use olivia_secp256k1::schnorr_fun::fun::{marker::*, Point, Scalar, G};
+use std::collections::BTreeMap;
+use zeroize::Zeroize;
 
-#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+/// Wiped when dropped. `Debug` doesn't show it but `Display` does (for `gun config bet
+/// protocol-secret`).
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum ProtocolSecret {
     Bytes(#[serde(with = "crate::serde_hacks::BigArray")] [u8; 64]),
 }
@@ -17,6 +49,56 @@ impl core::str::FromStr for ProtocolSecret {
     }
 }
 
+impl ProtocolSecret {
+    pub fn as_bytes(&self) -> &[u8; 64] {
+        match self {
+            ProtocolSecret::Bytes(bytes) => bytes,
+        }
+    }
+
+    /// Read a protocol secret kept in a file (as hex) rather than the database (see
+    /// `protocol-secret-file` in the config).
+    pub fn from_file(path: &std::path::Path) -> anyhow::Result<Self> {
+        let contents = zeroize::Zeroizing::new(std::fs::read_to_string(path)?);
+        contents.trim().parse().map_err(|e| {
+            anyhow!(
+                "{} doesn't contain a protocol secret: {}",
+                path.display(),
+                e
+            )
+        })
+    }
+
+    /// Write the secret to a new file that only the user can read.
+    pub fn write_to_file(&self, path: &std::path::Path) -> anyhow::Result<()> {
+        use std::io::Write;
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        file.write_all(zeroize::Zeroizing::new(self.to_string()).as_bytes())?;
+        file.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+impl Drop for ProtocolSecret {
+    fn drop(&mut self) {
+        match self {
+            ProtocolSecret::Bytes(bytes) => bytes.zeroize(),
+        }
+    }
+}
+
+impl core::fmt::Debug for ProtocolSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolSecret::Bytes(_) => f.write_str("ProtocolSecret::Bytes(REDACTED)"),
+        }
+    }
+}
+
 impl core::fmt::Display for ProtocolSecret {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -27,18 +109,68 @@ impl core::fmt::Display for ProtocolSecret {
 
 impl From<ProtocolSecret> for Keychain {
     fn from(protocol_secret: ProtocolSecret) -> Self {
-        match protocol_secret {
-            ProtocolSecret::Bytes(bytes) => Keychain::new(bytes),
+        Keychain::from_secret(protocol_secret, DerivationVersion::CURRENT)
+    }
+}
+
+/// The BIP85 (hex) index the first protocol secret is derived at when the wallet has seed words.
+/// `gun keychain rotate` uses the ones after it.
+pub const PROTOCOL_SECRET_BIP85_INDEX: u32 = 330;
+
+/// How bet keys are derived from the protocol secret (see the [module docs](self)).
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+pub enum DerivationVersion {
+    V0,
+}
+
+impl DerivationVersion {
+    /// The version new bets use.
+    pub const CURRENT: Self = DerivationVersion::V0;
+    pub const ALL: [Self; 1] = [DerivationVersion::V0];
+}
+
+/// Protocol secrets and bets from before derivations were versioned used [`DerivationVersion::V0`].
+impl Default for DerivationVersion {
+    fn default() -> Self {
+        DerivationVersion::V0
+    }
+}
+
+impl core::fmt::Display for DerivationVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DerivationVersion::V0 => write!(f, "v0"),
         }
     }
 }
 
+/// Which of the wallet's protocol secrets a bet's key was derived from and how.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyOrigin {
+    /// The index of the protocol secret. The wallet's first one is 0 and each `gun keychain
+    /// rotate` adds one.
+    pub secret: u32,
+    pub derivation: DerivationVersion,
+}
+
+/// Derives bet keys from the wallet's current protocol secret and any it has rotated away from.
 pub struct Keychain {
+    version: DerivationVersion,
+    current: u32,
+    secrets: BTreeMap<u32, DerivationV0>,
+    escrow_keypair: KeyPair,
+}
+
+struct DerivationV0 {
     proposal_hmac: HmacEngine<sha512::Hash>,
     offer_hmac: HmacEngine<sha512::Hash>,
+    bet_bundle_cipher_key: Secret<[u8; 32]>,
+    bet_bundle_mac_key: Secret<[u8; 32]>,
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
+#[derive(Clone, serde::Serialize, serde::Deserialize, PartialEq)]
 pub struct KeyPair {
     pub public_key: Point<EvenY>,
     pub secret_key: Scalar,
@@ -57,43 +189,56 @@ impl KeyPair {
     }
 }
 
-impl Keychain {
-    pub fn new(seed: [u8; 64]) -> Self {
-        let proposal_hmac = {
-            let mut hmac = HmacEngine::<sha512::Hash>::new(b"gun-proposal");
-            hmac.input(&seed[..]);
-            let res = Hmac::from_engine(hmac);
-            HmacEngine::<sha512::Hash>::new(&res[..])
-        };
+impl core::fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyPair")
+            .field("public_key", &self.public_key)
+            .finish_non_exhaustive()
+    }
+}
 
-        let offer_hmac = {
-            let mut hmac = HmacEngine::<sha512::Hash>::new(b"gun-offer");
-            hmac.input(&seed[..]);
-            let res = Hmac::from_engine(hmac);
-            HmacEngine::<sha512::Hash>::new(&res[..])
-        };
+fn hmac_engine(tag: &[u8], seed: &[u8; 64]) -> HmacEngine<sha512::Hash> {
+    let mut hmac = HmacEngine::<sha512::Hash>::new(tag);
+    hmac.input(&seed[..]);
+    let res = Hmac::from_engine(hmac);
+    HmacEngine::<sha512::Hash>::new(&res[..])
+}
 
-        Self {
-            proposal_hmac,
-            offer_hmac,
+impl DerivationV0 {
+    fn new(seed: &[u8; 64]) -> Self {
+        let mut hmac = HmacEngine::<sha512::Hash>::new(b"gun-bet-bundle");
+        hmac.input(&seed[..]);
+        let mut res = Hmac::from_engine(hmac).into_inner();
+        let mut bet_bundle_cipher_key = Secret::new([0u8; 32]);
+        let mut bet_bundle_mac_key = Secret::new([0u8; 32]);
+        bet_bundle_cipher_key
+            .expose_secret_mut()
+            .copy_from_slice(&res[..32]);
+        bet_bundle_mac_key
+            .expose_secret_mut()
+            .copy_from_slice(&res[32..]);
+        res.zeroize();
+        DerivationV0 {
+            proposal_hmac: hmac_engine(b"gun-proposal", seed),
+            offer_hmac: hmac_engine(b"gun-offer", seed),
+            bet_bundle_cipher_key,
+            bet_bundle_mac_key,
         }
     }
 
     /// TODO: use the versioned proposal here
     /// DONOTMERGE LIKE THIS
-    pub fn get_key_for_proposal(&self, proposal: &Proposal) -> KeyPair {
+    fn key_for_proposal(&self, proposal: &Proposal) -> KeyPair {
         let mut proposal = proposal.clone();
         proposal.public_key = crate::placeholder_point();
         let mut proposal_hmac = self.proposal_hmac.clone();
         let bin = crate::encode::serialize(&proposal);
         proposal_hmac.input(&bin[..]);
         let res = Hmac::from_engine(proposal_hmac);
-        let keypair = KeyPair::from_slice(&res[..]).expect("computationally unreachable");
-        proposal.public_key = keypair.public_key;
-        keypair
+        KeyPair::from_slice(&res[..]).expect("computationally unreachable")
     }
 
-    pub fn keypair_for_offer(&self, proposal: &Proposal) -> KeyPair {
+    fn keypair_for_offer(&self, proposal: &Proposal) -> KeyPair {
         let mut offer_hmac = self.offer_hmac.clone();
         let bin = crate::encode::serialize(proposal);
         offer_hmac.input(&bin[..]);
@@ -101,3 +246,237 @@ impl Keychain {
         KeyPair::from_slice(&res[..]).expect("computationally unreachable")
     }
 }
+
+impl Keychain {
+    /// A keychain that uses [`DerivationVersion::CURRENT`] for new bets.
+    pub fn new(seed: [u8; 64]) -> Self {
+        Self::with_version(seed, DerivationVersion::CURRENT)
+    }
+
+    /// A keychain with a single protocol secret that uses `version` for new bets. It can still
+    /// derive keys for bets made with any version.
+    pub fn with_version(seed: [u8; 64], version: DerivationVersion) -> Self {
+        let seed = Secret::new(seed);
+        let seed = seed.expose_secret();
+        let escrow_keypair = {
+            let mut hmac = HmacEngine::<sha512::Hash>::new(b"gun-escrow");
+            hmac.input(&seed[..]);
+            let res = Hmac::from_engine(hmac);
+            KeyPair::from_slice(&res[..]).expect("computationally unreachable")
+        };
+
+        Self {
+            version,
+            current: 0,
+            secrets: vec![(0, DerivationV0::new(seed))].into_iter().collect(),
+            escrow_keypair,
+        }
+    }
+
+    pub fn from_secret(protocol_secret: ProtocolSecret, version: DerivationVersion) -> Self {
+        Keychain::with_version(*protocol_secret.as_bytes(), version)
+    }
+
+    /// A keychain for new bets to use `current` which can also derive the keys of bets made with
+    /// the `retired` secrets (by index). `current`'s index is the one after the last retired one.
+    pub fn from_secrets(
+        current: ProtocolSecret,
+        version: DerivationVersion,
+        retired: impl IntoIterator<Item = (u32, ProtocolSecret)>,
+    ) -> Self {
+        let mut keychain = Keychain::from_secret(current, version);
+        let current_seed = keychain.secrets.remove(&0).expect("just added it");
+        for (index, secret) in retired {
+            keychain
+                .secrets
+                .insert(index, DerivationV0::new(secret.as_bytes()));
+        }
+        keychain.current = keychain
+            .secrets
+            .keys()
+            .next_back()
+            .map(|last| last + 1)
+            .unwrap_or(0);
+        keychain.secrets.insert(keychain.current, current_seed);
+        keychain
+    }
+
+    /// The derivation version new bets are made with.
+    pub fn version(&self) -> DerivationVersion {
+        self.version
+    }
+
+    /// Where the keys of new bets come from.
+    pub fn origin(&self) -> KeyOrigin {
+        KeyOrigin {
+            secret: self.current,
+            derivation: self.version,
+        }
+    }
+
+    /// The indexes of the protocol secrets the keychain has (the last is the current one).
+    pub fn secret_indexes(&self) -> impl Iterator<Item = u32> + '_ {
+        self.secrets.keys().copied()
+    }
+
+    fn secret(&self, index: u32) -> anyhow::Result<&DerivationV0> {
+        self.secrets.get(&index).ok_or(anyhow!(
+            "the wallet doesn't have protocol secret {} any more",
+            index
+        ))
+    }
+
+    /// The cipher and mac keys for moving bets made with protocol secret `secret` between devices
+    /// (see [`BetBundle`](crate::betting::BetBundle)).
+    pub fn bet_bundle_keys(
+        &self,
+        secret: u32,
+    ) -> anyhow::Result<(&Secret<[u8; 32]>, &Secret<[u8; 32]>)> {
+        let secret = self.secret(secret)?;
+        Ok((&secret.bet_bundle_cipher_key, &secret.bet_bundle_mac_key))
+    }
+
+    /// The key you give to people you want to make an escrow with.
+    pub fn escrow_keypair(&self) -> &KeyPair {
+        &self.escrow_keypair
+    }
+
+    /// The key for a new proposal.
+    pub fn get_key_for_proposal(&self, proposal: &Proposal) -> KeyPair {
+        self.get_key_for_proposal_with(self.origin(), proposal)
+            .expect("we always have the current secret")
+    }
+
+    /// The key for a proposal whose key came from `origin`.
+    pub fn get_key_for_proposal_with(
+        &self,
+        origin: KeyOrigin,
+        proposal: &Proposal,
+    ) -> anyhow::Result<KeyPair> {
+        let secret = self.secret(origin.secret)?;
+        Ok(match origin.derivation {
+            DerivationVersion::V0 => secret.key_for_proposal(proposal),
+        })
+    }
+
+    /// The key for a new offer to `proposal`.
+    pub fn keypair_for_offer(&self, proposal: &Proposal) -> KeyPair {
+        self.keypair_for_offer_with(self.origin(), proposal)
+            .expect("we always have the current secret")
+    }
+
+    /// The key for an offer to `proposal` whose key came from `origin`.
+    pub fn keypair_for_offer_with(
+        &self,
+        origin: KeyOrigin,
+        proposal: &Proposal,
+    ) -> anyhow::Result<KeyPair> {
+        let secret = self.secret(origin.secret)?;
+        Ok(match origin.derivation {
+            DerivationVersion::V0 => secret.keypair_for_offer(proposal),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bdk::bitcoin::{Amount, OutPoint, Txid};
+    use olivia_core::EventId;
+    use std::str::FromStr;
+
+    #[test]
+    fn rotated_keychain_keeps_old_keys() {
+        let proposal = Proposal {
+            oracle: "h00.ooo".into(),
+            event_id: EventId::from_str("/random/2020-09-25T08:00:00/heads_tails.winner").unwrap(),
+            value: Amount::from_sat(100_000),
+            inputs: vec![OutPoint::new(Txid::from_slice(&[1u8; 32]).unwrap(), 0)],
+            public_key: crate::placeholder_point(),
+            change_script: None,
+            refund_after: None,
+        };
+        let old = Keychain::new([1u8; 64]);
+        let rotated = Keychain::from_secrets(
+            ProtocolSecret::Bytes([2u8; 64]),
+            DerivationVersion::CURRENT,
+            vec![(0, ProtocolSecret::Bytes([1u8; 64]))],
+        );
+        assert_eq!(rotated.origin().secret, 1);
+        assert_eq!(
+            rotated
+                .get_key_for_proposal_with(old.origin(), &proposal)
+                .unwrap(),
+            old.get_key_for_proposal(&proposal)
+        );
+        assert_ne!(
+            rotated.get_key_for_proposal(&proposal),
+            old.get_key_for_proposal(&proposal)
+        );
+        assert!(rotated
+            .keypair_for_offer_with(
+                KeyOrigin {
+                    secret: 2,
+                    derivation: DerivationVersion::V0
+                },
+                &proposal
+            )
+            .is_err());
+    }
+
+    fn assert_redacted(debug: &str, secret: &[u8]) {
+        assert!(
+            !debug.contains(&hex::encode(secret)) && !debug.contains(&format!("{:?}", secret)),
+            "{} leaks the secret",
+            debug
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "leaks the secret")]
+    fn assert_redacted_catches_leaks() {
+        let secret = [7u8; 32];
+        assert_redacted(&format!("{:?}", secret), &secret);
+    }
+
+    #[test]
+    fn debug_never_shows_secrets() {
+        let protocol_secret = ProtocolSecret::Bytes([0xab; 64]);
+        assert_redacted(
+            &format!("{:?}", protocol_secret),
+            protocol_secret.as_bytes(),
+        );
+
+        let keypair = KeyPair::from_slice(&[0xcd; 32]).unwrap();
+        let debug = format!("{:?}", keypair);
+        assert_redacted(&debug, &keypair.secret_key.to_bytes());
+        assert!(debug.contains(&keypair.public_key.to_string()));
+
+        let keychain = Keychain::from(protocol_secret);
+        let (cipher_key, mac_key) = keychain.bet_bundle_keys(0).unwrap();
+        assert_redacted(&format!("{:?}", cipher_key), cipher_key.expose_secret());
+        assert_redacted(&format!("{:?}", mac_key), mac_key.expose_secret());
+    }
+
+    #[test]
+    fn protocol_secret_file_round_trip() {
+        let path = std::env::temp_dir().join(format!(
+            "gun-protocol-secret-test-{}",
+            rand::random::<u32>()
+        ));
+        let secret = ProtocolSecret::Bytes([0x42; 64]);
+        secret.write_to_file(&path).unwrap();
+        assert_eq!(ProtocolSecret::from_file(&path).unwrap(), secret);
+        assert!(
+            secret.write_to_file(&path).is_err(),
+            "must not overwrite an existing file"
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o077, 0);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -4,16 +4,50 @@ use std::str::FromStr;
 
 use bdk::bitcoin::Amount;
 pub mod amount_ext;
+pub mod backup;
 pub mod betting;
-mod change;
+pub mod calendar;
+pub mod change;
+pub mod client;
 pub mod cmd;
 pub mod config;
+pub mod confirm;
+pub mod contacts;
 pub mod ecdh;
 pub mod encode;
+pub mod escrow;
+pub mod esplora;
 mod fee_spec;
+pub mod gap;
+pub mod hooks;
+pub mod hwi;
+pub mod interaction;
 pub mod keychain;
+pub mod lock;
+pub mod locktime;
+pub mod logging;
+pub mod message;
+pub mod mock_oracle;
+pub mod net;
+pub mod passphrase;
+pub mod payjoin;
 pub mod psbt_ext;
+pub mod qr;
+pub mod risk;
+pub mod schedule;
+pub mod schema;
+pub mod script_kind;
+pub mod secret;
+pub mod selftest;
 pub mod signers;
+pub mod signet;
+pub mod silent_payments;
+#[cfg(feature = "simulation")]
+pub mod simulation;
+pub mod slip39;
+pub mod spending_limit;
+pub mod spv;
+pub mod utxo_snapshot;
 pub use fee_spec::*;
 pub mod bip85;
 pub mod database;
@@ -67,3 +101,8 @@ pub fn format_dt_diff_till_now(dt: chrono::NaiveDateTime) -> String {
         format!("{}d", diff.num_days())
     }
 }
+
+/// The current time as seconds since the unix epoch.
+pub fn unix_now() -> u64 {
+    chrono::Utc::now().timestamp() as u64
+}
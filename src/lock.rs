@@ -0,0 +1,173 @@
+//! Stopping two gun processes from changing the same wallet at once.
+//!
+//! Commands that change the wallet (or sync it) create `gun.lock` in the wallet directory and
+//! remove it when they finish. The file says who holds it so that when gun refuses to run it can
+//! tell you which process is in the way. The lock is advisory: `--no-lock` ignores it.
+use crate::elog;
+use anyhow::anyhow;
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+pub const LOCK_FILE_NAME: &str = "gun.lock";
+
+/// Who holds the lock.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct LockOwner {
+    pub pid: u32,
+    pub command: String,
+    /// unix timestamp of when the lock was taken
+    pub since: u64,
+}
+
+impl LockOwner {
+    fn current() -> Self {
+        LockOwner {
+            pid: std::process::id(),
+            command: std::env::args().collect::<Vec<_>>().join(" "),
+            since: crate::unix_now(),
+        }
+    }
+
+    /// Whether the process that took the lock is still running. If we can't tell we assume it is.
+    fn is_alive(&self) -> bool {
+        if cfg!(target_os = "linux") {
+            Path::new("/proc").join(self.pid.to_string()).exists()
+        } else {
+            true
+        }
+    }
+
+    fn describe(&self) -> String {
+        let since = crate::chrono::NaiveDateTime::from_timestamp(self.since as i64, 0);
+        format!(
+            "process {} (`{}`) which has had it locked since {}",
+            self.pid, self.command, since
+        )
+    }
+}
+
+/// Held while a command is changing the wallet. The lock file is removed when this is dropped.
+#[derive(Debug)]
+pub struct WalletLock {
+    path: PathBuf,
+}
+
+impl WalletLock {
+    pub fn acquire(wallet_dir: &Path) -> anyhow::Result<Self> {
+        let path = wallet_dir.join(LOCK_FILE_NAME);
+        let owner = LockOwner::current();
+        // The lock file has to appear with the owner already in it. Otherwise another process
+        // could read it while it's empty, think it's stale and remove it. So we write the owner to
+        // a file of our own and hard link it into place which fails if the lock exists.
+        let temp_path = wallet_dir.join(format!(
+            "{}.{}.{}",
+            LOCK_FILE_NAME,
+            owner.pid,
+            rand::random::<u32>()
+        ));
+        fs::write(&temp_path, serde_json::to_string(&owner)?)
+            .map_err(|e| anyhow!("creating lock file {}: {}", temp_path.display(), e))?;
+        let result = Self::link_into_place(&temp_path, path);
+        let _ = fs::remove_file(&temp_path);
+        result
+    }
+
+    fn link_into_place(temp_path: &Path, path: PathBuf) -> anyhow::Result<Self> {
+        // two goes: the second is after removing a lock left behind by a process that died
+        for _ in 0..2 {
+            match fs::hard_link(temp_path, &path) {
+                Ok(()) => return Ok(WalletLock { path }),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => match read_owner(&path) {
+                    Some(other) if other.is_alive() => {
+                        return Err(anyhow!(
+                            "The wallet is being used by {}. Wait for it to finish or run with --no-lock if you're sure it's safe. If that process is gone delete {}.",
+                            other.describe(),
+                            path.display()
+                        ))
+                    }
+                    other => {
+                        if let Some(other) = other {
+                            elog!(@info "Removing a stale lock left by process {}", other.pid);
+                        }
+                        fs::remove_file(&path)?;
+                    }
+                },
+                Err(e) => {
+                    return Err(anyhow!("creating lock file {}: {}", path.display(), e))
+                }
+            }
+        }
+        Err(anyhow!(
+            "Couldn't lock the wallet because another gun process keeps taking the lock"
+        ))
+    }
+
+    /// For commands that only read the wallet: warn if someone else is in the middle of changing
+    /// it.
+    pub fn check(wallet_dir: &Path) {
+        if let Some(other) = read_owner(&wallet_dir.join(LOCK_FILE_NAME)) {
+            if other.is_alive() && other.pid != std::process::id() {
+                elog!(@warning "The wallet is being changed by {} so this might be out of date", other.describe());
+            }
+        }
+    }
+}
+
+impl Drop for WalletLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn read_owner(path: &Path) -> Option<LockOwner> {
+    let contents = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_wallet_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gun-lock-test-{}", rand::random::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn only_one_holder() {
+        let dir = temp_wallet_dir();
+        let lock = WalletLock::acquire(&dir).unwrap();
+        let owner = read_owner(&dir.join(LOCK_FILE_NAME)).unwrap();
+        assert_eq!(owner.pid, std::process::id());
+        assert!(WalletLock::acquire(&dir).is_err());
+        // the files the owner was written to before being linked into place are gone
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        drop(lock);
+        assert!(!dir.join(LOCK_FILE_NAME).exists());
+        let _lock = WalletLock::acquire(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn stale_lock_is_taken_over() {
+        let dir = temp_wallet_dir();
+        let stale = LockOwner {
+            pid: u32::MAX,
+            command: "gun -s balance".into(),
+            since: 0,
+        };
+        fs::write(
+            dir.join(LOCK_FILE_NAME),
+            serde_json::to_string(&stale).unwrap(),
+        )
+        .unwrap();
+        let lock = WalletLock::acquire(&dir).unwrap();
+        drop(lock);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+use crate::wallet::GunWallet;
+use bdk::{
+    bitcoin::util::psbt::PartiallySignedTransaction as Psbt,
+    blockchain::Blockchain,
+    database::BatchDatabase,
+    wallet::{
+        coin_selection::CoinSelectionAlgorithm,
+        tx_builder::{TxBuilder, TxBuilderContext},
+    },
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// How nLockTime is set on the transactions we build.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LocktimePolicy {
+    /// Leave it at 0.
+    Zero,
+    /// Set it to the current height like Bitcoin Core does so miners can't profit from
+    /// re-mining the tip to take our fee. One time in ten it's set up to 100 blocks back so
+    /// transactions that were slow to be broadcast don't stand out.
+    AntiFeeSniping,
+}
+
+impl Default for LocktimePolicy {
+    fn default() -> Self {
+        LocktimePolicy::AntiFeeSniping
+    }
+}
+
+impl LocktimePolicy {
+    pub fn choose(&self, height: u32, rng: &mut impl Rng) -> u32 {
+        match self {
+            LocktimePolicy::Zero => 0,
+            LocktimePolicy::AntiFeeSniping => {
+                if rng.gen_range(0..10) == 0 {
+                    height.saturating_sub(rng.gen_range(0..100))
+                } else {
+                    height
+                }
+            }
+        }
+    }
+}
+
+/// How the nLockTime of a transaction we built was chosen.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TxLocktime {
+    pub policy: LocktimePolicy,
+    /// The height of the chain when it was chosen
+    pub height: u32,
+    pub locktime: u32,
+}
+
+impl GunWallet {
+    /// Sets nLockTime on `builder` following the wallet's policy. Pass what it returns to
+    /// [`record_locktime`](Self::record_locktime) once the transaction is built.
+    pub fn apply_locktime_policy<
+        B,
+        D: BatchDatabase,
+        Cs: CoinSelectionAlgorithm<D>,
+        Ctx: TxBuilderContext,
+    >(
+        &self,
+        builder: &mut TxBuilder<'_, B, D, Cs, Ctx>,
+    ) -> anyhow::Result<TxLocktime> {
+        let policy = self.locktime_policy();
+        let height = match policy {
+            LocktimePolicy::Zero => 0,
+            LocktimePolicy::AntiFeeSniping => self.blockchain()?.get_height()?,
+        };
+        let locktime = match self.tx_seed() {
+            // mixing in the height means the seed doesn't pick the same offset every time
+            Some(seed) => {
+                policy.choose(height, &mut StdRng::seed_from_u64(seed ^ u64::from(height)))
+            }
+            None => policy.choose(height, &mut rand::thread_rng()),
+        };
+        builder.nlocktime(locktime);
+        Ok(TxLocktime {
+            policy,
+            height,
+            locktime,
+        })
+    }
+
+    pub fn record_locktime(&self, psbt: &Psbt, tx_locktime: TxLocktime) -> anyhow::Result<()> {
+        debug_assert_eq!(psbt.unsigned_tx.lock_time, tx_locktime.locktime);
+        self.gun_db()
+            .insert_entity(psbt.unsigned_tx.txid(), tx_locktime)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn anti_fee_sniping_is_at_or_below_tip() {
+        let mut rng = rand::thread_rng();
+        let mut saw_tip = false;
+        for _ in 0..100 {
+            let locktime = LocktimePolicy::AntiFeeSniping.choose(700_000, &mut rng);
+            assert!(locktime <= 700_000 && locktime > 700_000 - 100);
+            saw_tip |= locktime == 700_000;
+        }
+        assert!(saw_tip);
+        assert!(LocktimePolicy::AntiFeeSniping.choose(5, &mut rng) <= 5);
+        assert_eq!(LocktimePolicy::Zero.choose(700_000, &mut rng), 0);
+    }
+}
@@ -0,0 +1,193 @@
+//! Where [`elog!`] messages go.
+//!
+//! Messages are printed to stderr with an emoji for people at the terminal and are also passed on
+//! to the [`log`] facade with their kind as the target (e.g. `gun::warning`). When a log file is
+//! set up every message at or above the log level (including ones from BDK) is written to it as a
+//! line of JSON so what happened while gun was running from cron can be looked at afterwards.
+//!
+//! [`elog!`]: crate::elog
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+/// The log file is moved to `gun.log.1` when it gets bigger than this.
+const MAX_LOG_FILE_BYTES: u64 = 5_000_000;
+
+static STDERR_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Warning,
+    Info,
+    Celebration,
+    UserAction,
+    RecoverableError,
+    UserError,
+    Question,
+    Suggestion,
+}
+
+impl Kind {
+    fn prefix(&self) -> &'static str {
+        match self {
+            Kind::Warning => "\u{26A0} ",
+            Kind::Info => "\u{2139} ",
+            Kind::Celebration => "\u{1F389} ",
+            Kind::UserAction => "\u{1F449} ",
+            Kind::RecoverableError => "\u{1F4A5} ",
+            Kind::UserError => "\u{274C}",
+            Kind::Question => "\u{2753}",
+            Kind::Suggestion => "\u{1F4A1}",
+        }
+    }
+
+    fn target(&self) -> &'static str {
+        match self {
+            Kind::Warning => "gun::warning",
+            Kind::Info => "gun::info",
+            Kind::Celebration => "gun::celebration",
+            Kind::UserAction => "gun::user_action",
+            Kind::RecoverableError => "gun::recoverable_error",
+            Kind::UserError => "gun::user_error",
+            Kind::Question => "gun::question",
+            Kind::Suggestion => "gun::suggestion",
+        }
+    }
+
+    pub fn level(&self) -> Level {
+        match self {
+            Kind::UserError => Level::Error,
+            Kind::Warning | Kind::RecoverableError => Level::Warn,
+            Kind::Info
+            | Kind::Celebration
+            | Kind::UserAction
+            | Kind::Question
+            | Kind::Suggestion => Level::Info,
+        }
+    }
+
+    /// Whether the user has to see the message to know what to do next.
+    fn always_shown(&self) -> bool {
+        matches!(self, Kind::Question | Kind::UserAction)
+    }
+}
+
+/// Print a message to stderr (if the log level allows) and pass it on to the logger.
+pub fn emit(kind: Kind, args: std::fmt::Arguments<'_>) {
+    let level = kind.level();
+    if kind.always_shown() || level as usize <= STDERR_LEVEL.load(Ordering::Relaxed) {
+        eprint!("{}", kind.prefix());
+        eprintln!("{}", args);
+    }
+    log::log!(target: kind.target(), level, "{}", args);
+}
+
+/// Writes each log record as a line of JSON.
+pub struct FileLogger {
+    path: PathBuf,
+    file: Mutex<File>,
+    level: LevelFilter,
+}
+
+impl FileLogger {
+    pub fn open(path: &Path, level: LevelFilter) -> std::io::Result<Self> {
+        Ok(FileLogger {
+            path: path.to_path_buf(),
+            file: Mutex::new(OpenOptions::new().create(true).append(true).open(path)?),
+            level,
+        })
+    }
+
+    fn rotate_if_too_big(&self, file: &mut File) -> std::io::Result<()> {
+        if file.metadata()?.len() < MAX_LOG_FILE_BYTES {
+            return Ok(());
+        }
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+        *file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        Ok(())
+    }
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = serde_json::json!({
+            "time": crate::chrono::Utc::now().to_rfc3339(),
+            "level": record.level().to_string(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+        });
+        let mut file = self.file.lock().unwrap();
+        // there's nowhere to report failing to log
+        let _ = self.rotate_if_too_big(&mut file);
+        let _ = writeln!(file, "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().unwrap().flush();
+    }
+}
+
+/// Set the level messages are printed to stderr at and start writing them to `log_file` (if any).
+pub fn init(level: LevelFilter, log_file: Option<&Path>) -> anyhow::Result<()> {
+    STDERR_LEVEL.store(level as usize, Ordering::Relaxed);
+    if let Some(log_file) = log_file {
+        let logger = FileLogger::open(log_file, level)
+            .map_err(|e| anyhow::anyhow!("opening log file {}: {}", log_file.display(), e))?;
+        log::set_boxed_logger(Box::new(logger))?;
+        log::set_max_level(level);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn log_file_lines_are_json() {
+        let path = std::env::temp_dir().join(format!("gun-log-test-{}", rand::random::<u32>()));
+        let logger = FileLogger::open(&path, LevelFilter::Info).unwrap();
+        logger.log(
+            &Record::builder()
+                .args(format_args!("hello"))
+                .level(Level::Warn)
+                .target("gun::warning")
+                .build(),
+        );
+        logger.log(
+            &Record::builder()
+                .args(format_args!("too noisy"))
+                .level(Level::Debug)
+                .target("bdk")
+                .build(),
+        );
+        logger.flush();
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines = contents.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1);
+        let line = serde_json::from_str::<serde_json::Value>(lines[0]).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], "gun::warning");
+        assert_eq!(line["message"], "hello");
+    }
+}
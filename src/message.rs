@@ -0,0 +1,282 @@
+//! [BIP-322] generic signed messages.
+//!
+//! A message is signed by spending a virtual output locked to the address (`to_spend`) with a
+//! virtual transaction (`to_sign`) that can never be broadcast. Since `to_sign` is just a
+//! transaction, any of our signers can sign it as a PSBT. We produce and check the "simple"
+//! signature format (the base64 encoded witness). Messages can be signed with p2wpkh addresses
+//! and signatures from p2wpkh and p2tr (key path) addresses can be checked.
+//!
+//! [BIP-322]: https://github.com/bitcoin/bips/blob/master/bip-0322.mediawiki
+use anyhow::{anyhow, Context};
+use bdk::bitcoin::{
+    base64,
+    blockdata::{opcodes, script::Builder},
+    consensus::encode,
+    hashes::{hash160, sha256, sha256d, Hash, HashEngine},
+    secp256k1::{self, schnorr, Message, Secp256k1, XOnlyPublicKey},
+    util::{
+        psbt::PartiallySignedTransaction as Psbt,
+        sighash::{Prevouts, SigHashCache},
+    },
+    Address, OutPoint, SchnorrSigHashType, Script, Transaction, TxIn, TxOut,
+};
+
+const TAG: &[u8] = b"BIP0322-signed-message";
+
+/// The BIP340 style tagged hash of the message.
+pub fn message_hash(message: &[u8]) -> sha256::Hash {
+    let tag = sha256::Hash::hash(TAG);
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag[..]);
+    engine.input(&tag[..]);
+    engine.input(message);
+    sha256::Hash::from_engine(engine)
+}
+
+pub fn to_spend(script_pubkey: &Script, message: &[u8]) -> Transaction {
+    Transaction {
+        version: 0,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: Builder::new()
+                .push_int(0)
+                .push_slice(&message_hash(message)[..])
+                .into_script(),
+            sequence: 0,
+            witness: Default::default(),
+        }],
+        output: vec![TxOut {
+            value: 0,
+            script_pubkey: script_pubkey.clone(),
+        }],
+    }
+}
+
+pub fn to_sign(to_spend: &Transaction) -> Transaction {
+    Transaction {
+        version: 0,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(to_spend.txid(), 0),
+            script_sig: Script::new(),
+            sequence: 0,
+            witness: Default::default(),
+        }],
+        output: vec![TxOut {
+            value: 0,
+            script_pubkey: Builder::new()
+                .push_opcode(opcodes::all::OP_RETURN)
+                .into_script(),
+        }],
+    }
+}
+
+/// The `to_sign` transaction as a PSBT ready to be handed to the wallet's signers.
+pub fn to_sign_psbt(address: &Address, message: &[u8]) -> anyhow::Result<Psbt> {
+    check_supported(address)?;
+    let to_spend = to_spend(&address.script_pubkey(), message);
+    let mut psbt = Psbt::from_unsigned_tx(to_sign(&to_spend))?;
+    psbt.inputs[0].witness_utxo = Some(to_spend.output[0].clone());
+    Ok(psbt)
+}
+
+/// Extract the "simple" signature from a signed and finalized `to_sign` PSBT.
+pub fn simple_signature(psbt: &Psbt) -> anyhow::Result<String> {
+    let witness = psbt.inputs[0]
+        .final_script_witness
+        .as_ref()
+        .ok_or(anyhow!("the message wasn't fully signed"))?;
+    Ok(base64::encode(encode::serialize(witness)))
+}
+
+/// Check a "simple" signature on `message` from `address`.
+pub fn verify_simple(address: &Address, message: &[u8], signature: &str) -> anyhow::Result<()> {
+    let script_pubkey = address.script_pubkey();
+    if !script_pubkey.is_v0_p2wpkh() && !script_pubkey.is_v1_p2tr() {
+        return Err(anyhow!(
+            "only signatures from p2wpkh (bc1q...) and p2tr (bc1p...) addresses can be checked"
+        ));
+    }
+    let witness = base64::decode(signature.trim())
+        .context("signature is not valid base64")
+        .and_then(|bytes| {
+            encode::deserialize::<Vec<Vec<u8>>>(&bytes).context("signature is not a witness")
+        })?;
+
+    if script_pubkey.is_v1_p2tr() {
+        return verify_p2tr(&script_pubkey, message, &witness);
+    }
+    let (signature, public_key) = match &witness[..] {
+        [signature, public_key] => (signature, public_key),
+        _ => return Err(anyhow!("a p2wpkh signature should have two witness items")),
+    };
+    let (sighash_type, der) = signature
+        .split_last()
+        .ok_or(anyhow!("signature is empty"))?;
+    if *sighash_type != 0x01 {
+        return Err(anyhow!("only SIGHASH_ALL signatures are valid"));
+    }
+    let pubkey_hash = hash160::Hash::hash(public_key);
+    if address.script_pubkey().as_bytes()[2..] != pubkey_hash[..] {
+        return Err(anyhow!(
+            "the signature's public key doesn't match the address"
+        ));
+    }
+    let public_key = secp256k1::PublicKey::from_slice(public_key)?;
+    let signature = secp256k1::ecdsa::Signature::from_der(der)?;
+
+    let to_spend = to_spend(&address.script_pubkey(), message);
+    let sighash = to_sign_sighash(&to_sign(&to_spend), &pubkey_hash);
+    Secp256k1::verification_only()
+        .verify_ecdsa(
+            &Message::from_slice(&sighash[..]).unwrap(),
+            &signature,
+            &public_key,
+        )
+        .map_err(|_| anyhow!("signature is invalid"))
+}
+
+/// Check a signature from a p2tr address. It's a BIP-340 signature on the BIP-341 sighash of
+/// `to_sign` spending `to_spend` through the key path.
+fn verify_p2tr(script_pubkey: &Script, message: &[u8], witness: &[Vec<u8>]) -> anyhow::Result<()> {
+    let signature = match witness {
+        [signature] => signature,
+        _ => return Err(anyhow!("a p2tr signature should have one witness item")),
+    };
+    let (signature, sighash_type) = match signature.len() {
+        64 => (&signature[..], SchnorrSigHashType::Default),
+        65 if signature[64] == 0x01 => (&signature[..64], SchnorrSigHashType::All),
+        65 => return Err(anyhow!("only SIGHASH_ALL signatures are valid")),
+        _ => return Err(anyhow!("a p2tr signature should be 64 or 65 bytes")),
+    };
+    let key = XOnlyPublicKey::from_slice(&script_pubkey.as_bytes()[2..])?;
+
+    let to_spend = to_spend(script_pubkey, message);
+    let to_sign = to_sign(&to_spend);
+    let sighash = SigHashCache::new(&to_sign).taproot_key_spend_signature_hash(
+        0,
+        &Prevouts::All(&to_spend.output[..]),
+        sighash_type,
+    )?;
+    Secp256k1::verification_only()
+        .verify_schnorr(
+            &schnorr::Signature::from_slice(signature)?,
+            &Message::from_slice(&sighash[..])?,
+            &key,
+        )
+        .map_err(|_| anyhow!("signature is invalid"))
+}
+
+fn check_supported(address: &Address) -> anyhow::Result<()> {
+    if !address.script_pubkey().is_v0_p2wpkh() {
+        return Err(anyhow!(
+            "only p2wpkh (bc1q...) addresses can sign messages right now"
+        ));
+    }
+    Ok(())
+}
+
+/// The BIP-143 SIGHASH_ALL digest for spending the p2wpkh `to_spend` output. `to_sign` always has
+/// one input and one output so we just write it out.
+fn to_sign_sighash(to_sign: &Transaction, pubkey_hash: &hash160::Hash) -> sha256d::Hash {
+    let txin = &to_sign.input[0];
+    let mut outpoint = vec![];
+    let mut sequence = vec![];
+    let mut outputs = vec![];
+    let _ = encode::Encodable::consensus_encode(&txin.previous_output, &mut outpoint);
+    let _ = encode::Encodable::consensus_encode(&txin.sequence, &mut sequence);
+    let _ = encode::Encodable::consensus_encode(&to_sign.output[0], &mut outputs);
+    let script_code = Builder::new()
+        .push_opcode(opcodes::all::OP_DUP)
+        .push_opcode(opcodes::all::OP_HASH160)
+        .push_slice(&pubkey_hash[..])
+        .push_opcode(opcodes::all::OP_EQUALVERIFY)
+        .push_opcode(opcodes::all::OP_CHECKSIG)
+        .into_script();
+
+    let mut engine = sha256d::Hash::engine();
+    engine.input(&to_sign.version.to_le_bytes());
+    engine.input(&sha256d::Hash::hash(&outpoint)[..]);
+    engine.input(&sha256d::Hash::hash(&sequence)[..]);
+    engine.input(&outpoint);
+    engine.input(&encode::serialize(&script_code));
+    engine.input(&0u64.to_le_bytes());
+    engine.input(&sequence);
+    engine.input(&sha256d::Hash::hash(&outputs)[..]);
+    engine.input(&to_sign.lock_time.to_le_bytes());
+    engine.input(&1u32.to_le_bytes());
+    sha256d::Hash::from_engine(engine)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    // test vectors from BIP-322
+    const ADDRESS: &str = "bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l";
+
+    #[test]
+    fn message_hashes() {
+        assert_eq!(
+            message_hash(b"").to_string(),
+            "c90c269c4f8fcbe6880f72a721ddfbf1914268a794cbb21cfafee13770ae19f1"
+        );
+        assert_eq!(
+            message_hash(b"Hello World").to_string(),
+            "f0eb03b1a75ac6d9847f55c624a99169b5dccba2a31f5b23bea77ba270de0a7a"
+        );
+    }
+
+    #[test]
+    fn virtual_transactions() {
+        let script_pubkey = Address::from_str(ADDRESS).unwrap().script_pubkey();
+        for (message, to_spend_txid, to_sign_txid) in [
+            (
+                &b""[..],
+                "c5680aa69bb8d860bf82d4e9cd3504b55dde018de765a91bb566283c545a99a7",
+                "1e9654e951a5ba44c8604c4de6c67fd78a27e81dcadcfe1edf638ba3aaebaed6",
+            ),
+            (
+                &b"Hello World"[..],
+                "b79d196740ad5217771c1098fc4a4b51e0535c32236c71f1ea4d61a2d603352b",
+                "88737ae86f2077145f93cc4b153ae9a1cb8d56afa511988c149c5c8c9d93bddf",
+            ),
+        ] {
+            let to_spend = to_spend(&script_pubkey, message);
+            assert_eq!(to_spend.txid().to_string(), to_spend_txid);
+            assert_eq!(to_sign(&to_spend).txid().to_string(), to_sign_txid);
+        }
+    }
+
+    #[test]
+    fn verify_simple_signature() {
+        let address = Address::from_str(ADDRESS).unwrap();
+        let signature = "AkcwRAIgZRfIY3p7/DoVTty6YZbWS71bc5Vct9p9Fia83eRmw2QCICK/ENGfwLtptFluMGs2KsqoNSk89pO7F29zJLUx9a/sASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=";
+        verify_simple(&address, b"Hello World", signature).unwrap();
+        assert!(verify_simple(&address, b"Hello World!", signature).is_err());
+        assert!(verify_simple(&address, b"", signature).is_err());
+    }
+
+    #[test]
+    fn verify_p2tr_signature() {
+        // from BIP-322 (SIGHASH_ALL)
+        let address =
+            Address::from_str("bc1ppv609nr0vr25u07u95waq5lucwfm6tde4nydujnu8npg4q75mr5sxq8lt3")
+                .unwrap();
+        let signature = "AUHd69PrJQEv+oKTfZ8l+WROBHuy9HKrbFCJu7U1iK2iiEy1vMU5EfMtjc+VSHM7aU0SDbak5IUZRVno2P5mjSafAQ==";
+        verify_simple(&address, b"Hello World", signature).unwrap();
+        assert!(verify_simple(&address, b"Hello World!", signature).is_err());
+
+        // SIGHASH_DEFAULT signatures made with the secret key 0x0707..07 as the output key
+        let address =
+            Address::from_str("bc1pnzwqkakt2cuhrlwfhme3asrvx4s0xfyadm57tkpu2a39t9hqtahsafvc08")
+                .unwrap();
+        let empty = "AUCWZ/0xd7rFg/dvtAV2ffiT+eATOZ/NB07c7x5GqSiyVhUPiPB4/9dF2u/1qreyDyVHqY5BKJuRQJ0yWu81hUOa";
+        let hello = "AUCmC/9HKPPRGvWjQTqqZ220emCurqPvTqauSqlG+jLbHvwfLrKlJf5tU0TUypbNNavqriUIgy33a7/SN6eUXsCj";
+        verify_simple(&address, b"", empty).unwrap();
+        verify_simple(&address, b"Hello World", hello).unwrap();
+        assert!(verify_simple(&address, b"Hello World", empty).is_err());
+    }
+}
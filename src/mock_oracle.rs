@@ -0,0 +1,262 @@
+//! An oracle for trying out bets locally (`gun dev oracle`).
+//!
+//! It serves enough of the olivia HTTP API for gun: the oracle's keys at `/` and an announcement
+//! for any two outcome event at the event's path. Nonces are derived from the oracle's seed and the
+//! event id so every event is announced without having to create it first. Events are attested
+//! once an outcome has been set with `gun dev oracle attest`. The seed and outcomes are kept in a
+//! state file that's read again for every request so attesting doesn't need the server to restart.
+use anyhow::{anyhow, Context};
+use olivia_core::{
+    announce, attest,
+    chrono::Utc,
+    http::{EventResponse, RootResponse},
+    AnnouncementSchemes, Attestation, AttestationSchemes, Event, EventId, Group, OracleEvent,
+    OracleInfo, OracleKeys, Outcome, RawAnnouncement,
+};
+use olivia_secp256k1::{
+    fun::{marker::*, Scalar},
+    Secp256k1,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MockOracleState {
+    pub seed: [u8; 32],
+    /// The outcome each attested event was attested to
+    #[serde(default)]
+    pub outcomes: BTreeMap<String, String>,
+}
+
+impl MockOracleState {
+    pub fn path(dir: &Path) -> PathBuf {
+        dir.join("oracle.json")
+    }
+
+    /// Load the state from `dir` or start a new oracle there.
+    pub fn load_or_create(dir: &Path) -> anyhow::Result<Self> {
+        let path = Self::path(dir);
+        if path.exists() {
+            let json = std::fs::read_to_string(&path)
+                .with_context(|| format!("reading {}", path.display()))?;
+            Ok(serde_json::from_str(&json)
+                .with_context(|| format!("decoding {}", path.display()))?)
+        } else {
+            let mut seed = [0u8; 32];
+            rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut seed);
+            let state = MockOracleState {
+                seed,
+                outcomes: BTreeMap::new(),
+            };
+            state.save(dir)?;
+            Ok(state)
+        }
+    }
+
+    pub fn save(&self, dir: &Path) -> anyhow::Result<()> {
+        std::fs::create_dir_all(dir)?;
+        let path = Self::path(dir);
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("writing {}", path.display()))
+    }
+}
+
+/// The oracle's keys and how it announces and attests to events.
+pub struct MockOracle {
+    seed: [u8; 32],
+}
+
+impl MockOracle {
+    pub fn new(seed: [u8; 32]) -> Self {
+        Self { seed }
+    }
+
+    fn derive_keypair(&self, tag: &str) -> <Secp256k1 as Group>::KeyPair {
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(
+            &Sha256::new()
+                .chain(self.seed)
+                .chain(tag.as_bytes())
+                .finalize(),
+        );
+        let secret_key = Scalar::from_bytes_mod_order(bytes)
+            .mark::<NonZero>()
+            .expect("hash is not zero");
+        olivia_secp256k1::SCHNORR.new_keypair(secret_key)
+    }
+
+    pub fn keys(&self) -> OracleKeys<Secp256k1> {
+        OracleKeys {
+            olivia_v1: Some(self.derive_keypair("attest").public_key().clone().into()),
+            ecdsa_v1: None,
+            announcement: self.derive_keypair("announce").public_key().clone().into(),
+            group: Secp256k1,
+        }
+    }
+
+    pub fn info(&self, id: String) -> OracleInfo {
+        OracleInfo {
+            id,
+            oracle_keys: self.keys(),
+        }
+    }
+
+    fn nonce_keypair(&self, event_id: &EventId) -> <Secp256k1 as Group>::KeyPair {
+        self.derive_keypair(&format!("nonce{}", event_id))
+    }
+
+    /// Only two outcome events can be bet on so those are the only ones the oracle announces.
+    pub fn oracle_event(&self, event_id: &EventId) -> anyhow::Result<OracleEvent<Secp256k1>> {
+        if event_id.n_outcomes() != 2 {
+            return Err(anyhow!(
+                "{} has {} outcomes but gun can only bet on events with two",
+                event_id,
+                event_id.n_outcomes()
+            ));
+        }
+        let nonce_keypair = self.nonce_keypair(event_id);
+        Ok(OracleEvent {
+            event: Event {
+                id: event_id.clone(),
+                expected_outcome_time: None,
+            },
+            schemes: AnnouncementSchemes {
+                olivia_v1: Some(announce::OliviaV1 {
+                    nonces: vec![nonce_keypair.public_key().clone().into()],
+                }),
+                ..Default::default()
+            },
+        })
+    }
+
+    pub fn announcement(&self, event_id: &EventId) -> anyhow::Result<RawAnnouncement<Secp256k1>> {
+        Ok(RawAnnouncement::create(
+            self.oracle_event(event_id)?,
+            &self.derive_keypair("announce"),
+        ))
+    }
+
+    pub fn attest(&self, outcome: &Outcome) -> Attestation<Secp256k1> {
+        Attestation {
+            outcome: outcome.outcome_string(),
+            schemes: AttestationSchemes {
+                olivia_v1: Some(attest::OliviaV1 {
+                    scalars: vec![Secp256k1::reveal_attest_scalar(
+                        &self.derive_keypair("attest"),
+                        self.nonce_keypair(&outcome.id).into(),
+                        outcome.value as _,
+                    )
+                    .into()],
+                }),
+                ..Default::default()
+            },
+            time: Utc::now().naive_utc(),
+        }
+    }
+}
+
+/// The response to a GET of `path` and its HTTP status.
+fn respond(state: &MockOracleState, path: &str) -> (u16, String) {
+    let oracle = MockOracle::new(state.seed);
+    if path == "/" || path.is_empty() {
+        let root = RootResponse {
+            public_keys: oracle.keys(),
+        };
+        return (200, serde_json::to_string(&root).unwrap());
+    }
+    let (announcement, event_id) = match EventId::from_str(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|event_id| Ok((oracle.announcement(&event_id)?, event_id)))
+    {
+        Ok(found) => found,
+        Err(e) => {
+            return (
+                404,
+                serde_json::json!({ "error": e.to_string() }).to_string(),
+            )
+        }
+    };
+    let attestation = state
+        .outcomes
+        .get(&event_id.to_string())
+        .and_then(|outcome| {
+            Outcome::try_from_id_and_outcome(event_id.clone(), outcome)
+                .ok()
+                .map(|outcome| oracle.attest(&outcome))
+        });
+    let response = EventResponse {
+        announcement,
+        attestation,
+    };
+    (200, serde_json::to_string(&response).unwrap())
+}
+
+fn handle(dir: &Path, stream: TcpStream) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let path = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET", path, _] => path.trim_end_matches('/').to_string(),
+        _ => return Err(anyhow!("unsupported request '{}'", request_line.trim())),
+    };
+    let state = MockOracleState::load_or_create(dir)?;
+    let (status, body) = respond(&state, &path);
+    log::info!("GET {} {}", path, status);
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        if status == 200 { "OK" } else { "Not Found" },
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+/// Serve the oracle kept in `dir` until the process is killed.
+pub fn serve(listen: &str, dir: &Path) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(listen).with_context(|| format!("listening on {}", listen))?;
+    for stream in listener.incoming() {
+        if let Err(e) = stream
+            .map_err(anyhow::Error::from)
+            .and_then(|stream| handle(dir, stream))
+        {
+            log::warn!("mock oracle request failed: {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn attestations_verify() {
+        let oracle = MockOracle::new([7u8; 32]);
+        let event_id = EventId::from_str("/test/red_blue.winner").unwrap();
+        let oracle_event = oracle
+            .announcement(&event_id)
+            .unwrap()
+            .verify_against_id(&event_id, &oracle.keys().announcement)
+            .expect("announcement is signed with the announcement key");
+        assert_eq!(oracle_event, oracle.oracle_event(&event_id).unwrap());
+        let outcome = Outcome::try_from_id_and_outcome(event_id.clone(), "red").unwrap();
+        let attestation = oracle.attest(&outcome);
+        assert!(attestation
+            .verify_olivia_v1_attestation(&oracle_event, &oracle.keys())
+            .is_ok());
+        let (status, _) = respond(&MockOracleState::default(), "/test/red_blue.winner");
+        assert_eq!(status, 200);
+        let (status, _) = respond(&MockOracleState::default(), "/not an event");
+        assert_eq!(status, 404);
+    }
+}
@@ -0,0 +1,124 @@
+//! Async HTTP for the network-bound parts of gun (oracles, esplora health checks and polling bets).
+//!
+//! Requests run on a tokio runtime and share one reqwest client so connections to the same server
+//! are reused. A semaphore bounds how many are in flight at once so polling a lot of bets doesn't
+//! hammer the servers. The CLI is synchronous so it goes through [`Net::block_on`]. BDK's esplora
+//! client is still blocking so its calls are run on tokio's blocking threads (under the same limit)
+//! with [`Net::run_blocking`].
+use crate::elog;
+use anyhow::anyhow;
+use once_cell::sync::OnceCell;
+use std::{future::Future, sync::Arc, time::Duration};
+use tokio::{runtime::Runtime, sync::Semaphore};
+
+/// How many requests to have in flight at once.
+pub const MAX_CONCURRENT_REQUESTS: usize = 8;
+
+pub struct Net {
+    runtime: OnceCell<Runtime>,
+    client: reqwest::Client,
+    limit: Arc<Semaphore>,
+    retries: u32,
+}
+
+fn is_transient(error: &reqwest::Error) -> bool {
+    error.is_timeout()
+        || error.is_connect()
+        || matches!(error.status(), Some(status) if status.as_u16() == 429 || status.is_server_error())
+}
+
+impl Net {
+    pub fn new(timeout: Duration, retries: u32) -> Self {
+        Net {
+            runtime: OnceCell::new(),
+            client: reqwest::Client::builder()
+                .timeout(timeout)
+                .build()
+                .expect("the TLS backend is built in"),
+            limit: Arc::new(Semaphore::new(MAX_CONCURRENT_REQUESTS)),
+            retries,
+        }
+    }
+
+    /// Run `future` to completion. This is the way into the async code from the CLI so it must
+    /// not be called from inside the runtime.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime
+            .get_or_init(|| {
+                tokio::runtime::Builder::new_multi_thread()
+                    .worker_threads(2)
+                    .enable_all()
+                    .build()
+                    .expect("creating the tokio runtime")
+            })
+            .block_on(future)
+    }
+
+    /// GET `url` as a string retrying if the server couldn't be reached or had an internal error.
+    pub async fn get_text(&self, url: &str) -> anyhow::Result<String> {
+        let mut attempt = 0;
+        loop {
+            let result = {
+                let _permit = self.limit.acquire().await.expect("never closed");
+                self.get_once(url).await
+            };
+            match result {
+                Err(e) if attempt < self.retries && is_transient(&e) => {
+                    attempt += 1;
+                    elog!(@recoverable_error "GET {} failed ({}), retrying ({}/{})", url, e, attempt, self.retries);
+                    tokio::time::sleep(crate::wallet::retry_delay(attempt)).await;
+                }
+                result => return Ok(result?),
+            }
+        }
+    }
+
+    async fn get_once(&self, url: &str) -> Result<String, reqwest::Error> {
+        self.client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await
+    }
+
+    /// Run a blocking call (e.g. to BDK's esplora client) without holding up the other requests.
+    pub async fn run_blocking<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let _permit = self.limit.acquire().await.expect("never closed");
+        tokio::task::spawn_blocking(f)
+            .await
+            .map_err(|e| anyhow!("blocking network call panicked: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn requests_are_bounded() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let net = Net::new(Duration::from_secs(1), 0);
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let calls = (0..MAX_CONCURRENT_REQUESTS * 3).map(|_| {
+            let in_flight = in_flight.clone();
+            let max_in_flight = max_in_flight.clone();
+            net.run_blocking(move || {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(20));
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+            })
+        });
+        let results = net.block_on(futures::future::join_all(calls));
+        assert!(results.iter().all(Result::is_ok));
+        assert!(max_in_flight.load(Ordering::SeqCst) <= MAX_CONCURRENT_REQUESTS);
+        assert!(max_in_flight.load(Ordering::SeqCst) > 1);
+    }
+}
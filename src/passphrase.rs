@@ -0,0 +1,168 @@
+//! Getting the wallet passphrase without asking for it.
+//!
+//! A [`GunSigner::SeedWordsFile`](crate::config::GunSigner::SeedWordsFile) signer can be given a
+//! [`PassphraseSource`] so things like `gun bet claim` from cron can sign without anyone at the
+//! keyboard. The passphrase itself never goes in the config: it comes from a command (e.g. a
+//! password manager) or the OS keyring.
+use crate::{interaction, secret::Secret};
+use anyhow::anyhow;
+use bdk::{
+    bitcoin::{
+        secp256k1::Secp256k1,
+        util::bip32::{ExtendedPrivKey, Fingerprint},
+        Network,
+    },
+    keys::bip39::Mnemonic,
+};
+use std::process::{Command, Stdio};
+
+/// Where to get the wallet passphrase from instead of asking for it.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PassphraseSource {
+    /// Run a command with `sh -c` and use what it prints (minus the trailing newline) e.g. `pass
+    /// show gun`.
+    Command(String),
+    /// An entry in the OS keyring (Secret Service, macOS Keychain or Windows Credential Manager).
+    Keyring { service: String, user: String },
+}
+
+impl core::fmt::Display for PassphraseSource {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PassphraseSource::Command(command) => write!(f, "the command '{}'", command),
+            PassphraseSource::Keyring { service, user } => {
+                write!(f, "the keyring entry for {} in {}", user, service)
+            }
+        }
+    }
+}
+
+impl PassphraseSource {
+    pub fn read(&self) -> anyhow::Result<Secret<String>> {
+        match self {
+            PassphraseSource::Command(command) => run_command(command),
+            PassphraseSource::Keyring { service, user } => keyring_get(service, user),
+        }
+        .map_err(|e| anyhow!("couldn't get the passphrase from {}: {}", self, e))
+    }
+}
+
+/// Read the passphrase from `source` or ask for it with `prompt` if there isn't one.
+pub fn read_passphrase(
+    source: Option<&PassphraseSource>,
+    prompt: &str,
+) -> anyhow::Result<Secret<String>> {
+    match source {
+        Some(source) => source.read(),
+        None => Ok(Secret::new(interaction::current().read_secret(prompt)?)),
+    }
+}
+
+/// The wallet's master key if `passphrase` is the one that goes with `mnemonic` to make the wallet
+/// with `fingerprint`.
+pub fn master_xpriv(
+    mnemonic: &Mnemonic,
+    network: Network,
+    fingerprint: Fingerprint,
+    passphrase: &Secret<String>,
+) -> Option<ExtendedPrivKey> {
+    let seed = Secret::new(mnemonic.to_seed(passphrase.expose_secret().as_str()));
+    let xpriv = ExtendedPrivKey::new_master(network, seed.expose_secret()).ok()?;
+    if xpriv.fingerprint(&Secp256k1::signing_only()) == fingerprint {
+        Some(xpriv)
+    } else {
+        None
+    }
+}
+
+fn run_command(command: &str) -> anyhow::Result<Secret<String>> {
+    // stderr is left alone so the command can ask for a PIN or complain
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()?;
+    let stdout = Secret::new(output.stdout);
+    if !output.status.success() {
+        return Err(anyhow!("it exited with {}", output.status));
+    }
+    let passphrase = core::str::from_utf8(stdout.expose_secret())
+        .map_err(|_| anyhow!("it didn't print valid UTF-8"))?;
+    Ok(Secret::new(
+        passphrase
+            .strip_suffix('\n')
+            .map(|passphrase| passphrase.strip_suffix('\r').unwrap_or(passphrase))
+            .unwrap_or(passphrase)
+            .to_string(),
+    ))
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_get(service: &str, user: &str) -> anyhow::Result<Secret<String>> {
+    Ok(Secret::new(
+        keyring::Entry::new(service, user).get_password()?,
+    ))
+}
+
+#[cfg(not(feature = "keyring"))]
+fn keyring_get(_service: &str, _user: &str) -> anyhow::Result<Secret<String>> {
+    Err(no_keyring())
+}
+
+/// Save `passphrase` in the OS keyring so [`PassphraseSource::Keyring`] can find it.
+#[cfg(feature = "keyring")]
+pub fn keyring_set(service: &str, user: &str, passphrase: &Secret<String>) -> anyhow::Result<()> {
+    Ok(keyring::Entry::new(service, user).set_password(passphrase.expose_secret())?)
+}
+
+#[cfg(not(feature = "keyring"))]
+pub fn keyring_set(
+    _service: &str,
+    _user: &str,
+    _passphrase: &Secret<String>,
+) -> anyhow::Result<()> {
+    Err(no_keyring())
+}
+
+#[cfg(not(feature = "keyring"))]
+fn no_keyring() -> anyhow::Error {
+    anyhow!("this gun was built without keyring support (build it with --features keyring)")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn config_format() {
+        let sources = serde_json::from_str::<Vec<PassphraseSource>>(
+            r#"[{"command": "pass show gun"}, {"keyring": {"service": "gun", "user": "d34db33f"}}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            sources,
+            vec![
+                PassphraseSource::Command("pass show gun".into()),
+                PassphraseSource::Keyring {
+                    service: "gun".into(),
+                    user: "d34db33f".into()
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn command_output_is_the_passphrase() {
+        let passphrase = PassphraseSource::Command("printf 'hunter 2\\n'".into())
+            .read()
+            .unwrap();
+        assert_eq!(passphrase.expose_secret(), "hunter 2");
+        let passphrase = PassphraseSource::Command("printf 'trailing  '".into())
+            .read()
+            .unwrap();
+        assert_eq!(passphrase.expose_secret(), "trailing  ");
+        assert!(PassphraseSource::Command("exit 1".into()).read().is_err());
+    }
+}
@@ -0,0 +1,362 @@
+//! Sending with [BIP-78] payjoin.
+//!
+//! We sign a normal transaction (the "original"), post it to the receiver's payjoin endpoint and
+//! get back a "proposal" with some of the receiver's inputs added. The proposal is only signed if
+//! it passes the sender checks in BIP-78. If anything goes wrong the original can still be
+//! broadcast as a regular payment.
+//!
+//! [BIP-78]: https://github.com/bitcoin/bips/blob/master/bip-0078.mediawiki
+use crate::{psbt_ext::PsbtFeeRate, wallet::GunWallet};
+use anyhow::{anyhow, Context};
+use bdk::{
+    bitcoin::{
+        util::{amount::Denomination, psbt::PartiallySignedTransaction as Psbt},
+        Address, Amount, Script,
+    },
+    FeeRate, SignOptions,
+};
+use std::{collections::HashMap, str::FromStr, time::Duration};
+use url::Url;
+
+/// The most vbytes the receiver can expect us to pay for. This is about what a p2wpkh input
+/// costs.
+const MAX_ADDITIONAL_VSIZE: f32 = 68.0;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A BIP-21 URI with a `pj` parameter.
+#[derive(Debug, Clone)]
+pub struct PayjoinUri {
+    pub address: Address,
+    pub amount: Option<Amount>,
+    pub endpoint: Url,
+}
+
+impl FromStr for PayjoinUri {
+    type Err = anyhow::Error;
+
+    fn from_str(uri: &str) -> anyhow::Result<Self> {
+        let url = Url::parse(uri).context("invalid BIP-21 URI")?;
+        if url.scheme() != "bitcoin" {
+            return Err(anyhow!("BIP-21 URIs must start with 'bitcoin:'"));
+        }
+        let address = Address::from_str(url.path()).context("invalid address in BIP-21 URI")?;
+        let mut amount = None;
+        let mut endpoint = None;
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "amount" => {
+                    amount = Some(
+                        Amount::from_str_in(&value, Denomination::Bitcoin)
+                            .context("invalid amount in BIP-21 URI")?,
+                    )
+                }
+                "pj" => endpoint = Some(Url::parse(&value).context("invalid payjoin endpoint")?),
+                _ => {}
+            }
+        }
+        let endpoint = endpoint.ok_or(anyhow!("BIP-21 URI doesn't have a payjoin endpoint"))?;
+        let is_onion = endpoint
+            .host_str()
+            .map(|host| host.ends_with(".onion"))
+            .unwrap_or(false);
+        if endpoint.scheme() != "https" && !is_onion {
+            return Err(anyhow!(
+                "payjoin endpoint must be https or a .onion address"
+            ));
+        }
+        Ok(PayjoinUri {
+            address,
+            amount,
+            endpoint,
+        })
+    }
+}
+
+/// What we told the receiver they could do with our transaction.
+#[derive(Debug, Clone)]
+pub struct PayjoinParams {
+    /// The index of our change output in the original which the receiver may take fees from
+    pub fee_output_index: Option<usize>,
+    pub max_additional_fee_contribution: Amount,
+    pub min_feerate: FeeRate,
+}
+
+impl PayjoinParams {
+    pub fn new(original: &Psbt, change_script: Option<&Script>) -> Self {
+        let (_, feerate, _) = original.fee();
+        let fee_output_index = change_script.and_then(|change_script| {
+            original
+                .unsigned_tx
+                .output
+                .iter()
+                .position(|output| &output.script_pubkey == change_script)
+        });
+        let max_additional_fee_contribution = match fee_output_index {
+            Some(index) => Amount::from_sat(
+                ((feerate.as_sat_vb() * MAX_ADDITIONAL_VSIZE).ceil() as u64)
+                    .min(original.unsigned_tx.output[index].value),
+            ),
+            None => Amount::ZERO,
+        };
+        PayjoinParams {
+            fee_output_index,
+            max_additional_fee_contribution,
+            min_feerate: feerate,
+        }
+    }
+
+    fn endpoint(&self, endpoint: &Url) -> Url {
+        let mut endpoint = endpoint.clone();
+        {
+            let mut query = endpoint.query_pairs_mut();
+            query.append_pair("v", "1");
+            // we don't let the receiver change where the payment goes
+            query.append_pair("disableoutputsubstitution", "true");
+            query.append_pair("minfeerate", &self.min_feerate.as_sat_vb().to_string());
+            if let Some(index) = self.fee_output_index {
+                query.append_pair("additionalfeeoutputindex", &index.to_string());
+                query.append_pair(
+                    "maxadditionalfeecontribution",
+                    &self.max_additional_fee_contribution.as_sat().to_string(),
+                );
+            }
+        }
+        endpoint
+    }
+}
+
+/// The original PSBT without key origin information which the receiver doesn't need to know.
+fn original_for_receiver(original: &Psbt) -> Psbt {
+    let mut original = original.clone();
+    for input in &mut original.inputs {
+        input.bip32_derivation.clear();
+    }
+    for output in &mut original.outputs {
+        output.bip32_derivation.clear();
+    }
+    original
+}
+
+/// Post the original PSBT to the receiver and get their proposal back.
+pub fn request_proposal(
+    client: &ureq::Agent,
+    uri: &PayjoinUri,
+    original: &Psbt,
+    params: &PayjoinParams,
+) -> anyhow::Result<Psbt> {
+    let endpoint = params.endpoint(&uri.endpoint);
+    let response = client
+        .post(endpoint.as_str())
+        .timeout(REQUEST_TIMEOUT)
+        .set("Content-Type", "text/plain")
+        .send_string(&original_for_receiver(original).to_string());
+
+    let body = match response {
+        Ok(response) => response.into_string()?,
+        Err(ureq::Error::Status(code, response)) => {
+            let body = response.into_string().unwrap_or_default();
+            let message = serde_json::from_str::<serde_json::Value>(&body)
+                .ok()
+                .and_then(|error| {
+                    Some(format!(
+                        "{}: {}",
+                        error.get("errorCode")?.as_str()?,
+                        error.get("message")?.as_str()?
+                    ))
+                })
+                .unwrap_or(body);
+            return Err(anyhow!("payjoin receiver returned {}: {}", code, message));
+        }
+        Err(e) => return Err(e).context("contacting payjoin receiver"),
+    };
+
+    Psbt::from_str(body.trim()).context("payjoin receiver returned an invalid PSBT")
+}
+
+/// Put back what we know about our own inputs (the receiver is supposed to strip it) and remove
+/// our old signatures so they can be signed again.
+fn restore_our_inputs(original: &Psbt, proposal: &mut Psbt) {
+    let originals = original
+        .unsigned_tx
+        .input
+        .iter()
+        .map(|txin| txin.previous_output)
+        .zip(original.inputs.iter())
+        .collect::<HashMap<_, _>>();
+
+    for (txin, input) in proposal
+        .unsigned_tx
+        .input
+        .iter()
+        .zip(proposal.inputs.iter_mut())
+    {
+        if let Some(original_input) = originals.get(&txin.previous_output) {
+            *input = (*original_input).clone();
+            input.final_script_sig = None;
+            input.final_script_witness = None;
+            input.partial_sigs.clear();
+        }
+    }
+}
+
+/// The sender checks from BIP-78. `is_mine` tells whether a script belongs to our wallet.
+pub fn check_proposal(
+    original: &Psbt,
+    proposal: &Psbt,
+    payee: &Script,
+    params: &PayjoinParams,
+    is_mine: impl Fn(&Script) -> anyhow::Result<bool>,
+) -> anyhow::Result<()> {
+    let (original_tx, proposal_tx) = (&original.unsigned_tx, &proposal.unsigned_tx);
+    if original_tx.version != proposal_tx.version || original_tx.lock_time != proposal_tx.lock_time
+    {
+        return Err(anyhow!(
+            "receiver changed the transaction version or locktime"
+        ));
+    }
+
+    let original_sequence = original_tx.input[0].sequence;
+    let original_outpoints = original_tx
+        .input
+        .iter()
+        .map(|txin| txin.previous_output)
+        .collect::<Vec<_>>();
+
+    for outpoint in &original_outpoints {
+        if !proposal_tx
+            .input
+            .iter()
+            .any(|txin| txin.previous_output == *outpoint)
+        {
+            return Err(anyhow!("receiver removed our input {}", outpoint));
+        }
+    }
+
+    for (txin, input) in proposal_tx.input.iter().zip(proposal.inputs.iter()) {
+        if txin.sequence != original_sequence {
+            return Err(anyhow!(
+                "receiver used a different nSequence on {}",
+                txin.previous_output
+            ));
+        }
+        if original_outpoints.contains(&txin.previous_output) {
+            continue;
+        }
+        let txout = input.witness_utxo.as_ref().ok_or(anyhow!(
+            "receiver input {} is missing its UTXO",
+            txin.previous_output
+        ))?;
+        if !txout.script_pubkey.is_v0_p2wpkh() {
+            return Err(anyhow!(
+                "receiver input {} is not p2wpkh",
+                txin.previous_output
+            ));
+        }
+        if input.final_script_witness.is_none() {
+            return Err(anyhow!(
+                "receiver input {} is not signed",
+                txin.previous_output
+            ));
+        }
+        if is_mine(&txout.script_pubkey)? {
+            return Err(anyhow!("receiver added one of our coins as their input"));
+        }
+    }
+
+    let mut our_contribution = Amount::ZERO;
+    for (i, original_output) in original_tx.output.iter().enumerate() {
+        let proposal_output = proposal_tx
+            .output
+            .iter()
+            .find(|output| output.script_pubkey == original_output.script_pubkey)
+            .ok_or(anyhow!("receiver removed output {}", i))?;
+        let decrease = original_output.value.saturating_sub(proposal_output.value);
+        if decrease == 0 {
+            continue;
+        }
+        if Some(i) != params.fee_output_index || &original_output.script_pubkey == payee {
+            return Err(anyhow!("receiver reduced the value of output {}", i));
+        }
+        our_contribution += Amount::from_sat(decrease);
+    }
+
+    if our_contribution > params.max_additional_fee_contribution {
+        return Err(anyhow!(
+            "receiver wants us to pay {} more in fees but we only allowed {}",
+            our_contribution,
+            params.max_additional_fee_contribution
+        ));
+    }
+
+    let (original_fee, _, _) = original.fee();
+    let (proposal_fee, proposal_feerate, _) = proposal.fee();
+    if proposal_fee < original_fee + our_contribution {
+        return Err(anyhow!("receiver took our fee contribution for themselves"));
+    }
+    if proposal_feerate.as_sat_vb() < params.min_feerate.as_sat_vb() {
+        return Err(anyhow!(
+            "the payjoin's feerate ({:.3} sat/vb) is lower than the original's",
+            proposal_feerate.as_sat_vb()
+        ));
+    }
+
+    Ok(())
+}
+
+impl GunWallet {
+    /// Do a payjoin round trip with the receiver and sign the proposal. `original` must be signed.
+    pub fn payjoin(&self, uri: &PayjoinUri, original: &Psbt) -> anyhow::Result<Psbt> {
+        let bdk_wallet = self.bdk_wallet();
+        let payee = uri.address.script_pubkey();
+        let change_script = original
+            .unsigned_tx
+            .output
+            .iter()
+            .map(|output| &output.script_pubkey)
+            .find(|script| **script != payee && bdk_wallet.is_mine(script).unwrap_or(false));
+        let params = PayjoinParams::new(original, change_script);
+
+        let mut proposal = request_proposal(self.http_client(), uri, original, &params)?;
+        restore_our_inputs(original, &mut proposal);
+        check_proposal(original, &proposal, &payee, &params, |script| {
+            Ok(bdk_wallet.is_mine(script)?)
+        })?;
+
+        let finalized = bdk_wallet.sign(
+            &mut proposal,
+            SignOptions {
+                // the receiver's inputs only come with witness_utxo
+                trust_witness_utxo: true,
+                ..Default::default()
+            },
+        )?;
+        if !finalized {
+            return Err(anyhow!("couldn't sign the payjoin proposal"));
+        }
+
+        Ok(proposal)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_payjoin_uri() {
+        let uri = PayjoinUri::from_str("bitcoin:tb1q6d3a2w975yny0asuvd9a67ner4nks58ff0q8g4?amount=0.01&pj=https://example.com/pj%3Fid%3D1").unwrap();
+        assert_eq!(uri.amount, Some(Amount::from_sat(1_000_000)));
+        assert_eq!(uri.endpoint.as_str(), "https://example.com/pj?id=1");
+        assert!(
+            PayjoinUri::from_str("bitcoin:tb1q6d3a2w975yny0asuvd9a67ner4nks58ff0q8g4").is_err()
+        );
+        assert!(PayjoinUri::from_str(
+            "bitcoin:tb1q6d3a2w975yny0asuvd9a67ner4nks58ff0q8g4?pj=http://example.com"
+        )
+        .is_err());
+        assert!(PayjoinUri::from_str(
+            "bitcoin:tb1q6d3a2w975yny0asuvd9a67ner4nks58ff0q8g4?pj=http://abcd.onion/pj"
+        )
+        .is_ok());
+    }
+}
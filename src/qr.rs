@@ -0,0 +1,191 @@
+//! Moving PSBTs to and from airgapped signers (SeedSigner, Keystone etc) with QR codes.
+//!
+//! PSBTs are encoded as [`crypto-psbt` URs][UR] which are split up into a sequence of QR codes that
+//! we animate in the terminal. Signed PSBTs come back as UR strings (one line per part) or plain
+//! base64.
+//!
+//! [UR]: https://github.com/BlockchainCommons/Research/blob/master/papers/bcr-2020-006-urtypes.md
+use crate::interaction::Interaction;
+use anyhow::{anyhow, Context};
+use bdk::bitcoin::{consensus::encode, util::psbt::PartiallySignedTransaction as Psbt};
+use qrcode::{render::unicode::Dense1x2, QrCode};
+use std::{
+    io::{self, Write},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+pub const UR_TYPE: &str = "crypto-psbt";
+/// How many bytes of the PSBT go in each QR code. Smaller is easier to scan.
+pub const DEFAULT_FRAGMENT_LEN: usize = 200;
+const FRAME_INTERVAL: Duration = Duration::from_millis(300);
+
+/// The UR payload is the PSBT as a CBOR byte string.
+fn cbor_wrap(bytes: &[u8]) -> Vec<u8> {
+    let len = bytes.len();
+    let mut out = match len {
+        0..=23 => vec![0x40 | len as u8],
+        24..=0xff => vec![0x58, len as u8],
+        0x100..=0xffff => {
+            let mut header = vec![0x59];
+            header.extend((len as u16).to_be_bytes());
+            header
+        }
+        _ => {
+            let mut header = vec![0x5a];
+            header.extend((len as u32).to_be_bytes());
+            header
+        }
+    };
+    out.extend(bytes);
+    out
+}
+
+fn cbor_unwrap(cbor: &[u8]) -> anyhow::Result<&[u8]> {
+    let (&first, rest) = cbor.split_first().ok_or(anyhow!("empty UR payload"))?;
+    if first & 0xe0 != 0x40 {
+        return Err(anyhow!("UR payload is not a CBOR byte string"));
+    }
+    let (len, rest) = match first & 0x1f {
+        len @ 0..=23 => (len as usize, rest),
+        24 if !rest.is_empty() => (rest[0] as usize, &rest[1..]),
+        25 if rest.len() >= 2 => (u16::from_be_bytes([rest[0], rest[1]]) as usize, &rest[2..]),
+        26 if rest.len() >= 4 => (
+            u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize,
+            &rest[4..],
+        ),
+        _ => return Err(anyhow!("invalid CBOR byte string header")),
+    };
+    if rest.len() != len {
+        return Err(anyhow!(
+            "CBOR byte string should be {} bytes but is {}",
+            len,
+            rest.len()
+        ));
+    }
+    Ok(rest)
+}
+
+pub fn psbt_ur_encoder(psbt: &Psbt, fragment_len: usize) -> anyhow::Result<ur::Encoder> {
+    let payload = cbor_wrap(&encode::serialize(psbt));
+    ur::Encoder::new(&payload, fragment_len, UR_TYPE)
+        .map_err(|e| anyhow!("encoding PSBT as UR: {:?}", e))
+}
+
+pub fn render(data: &str) -> anyhow::Result<String> {
+    // QR codes are more compact when everything is uppercase (alphanumeric mode)
+    let code = QrCode::new(data.to_uppercase().as_bytes()).context("rendering QR code")?;
+    Ok(code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .quiet_zone(true)
+        .build())
+}
+
+/// Cycle through the QR codes for the PSBT until `interaction` says the user is done.
+pub fn show_animated(
+    psbt: &Psbt,
+    fragment_len: usize,
+    interaction: Arc<dyn Interaction>,
+) -> anyhow::Result<()> {
+    let mut encoder = psbt_ur_encoder(psbt, fragment_len)?;
+    let n_parts = encoder.fragment_count();
+    let done = Arc::new(AtomicBool::new(false));
+    {
+        let done = done.clone();
+        std::thread::spawn(move || {
+            interaction.wait();
+            done.store(true, Ordering::SeqCst);
+        });
+    }
+
+    let stderr = io::stderr();
+    while !done.load(Ordering::SeqCst) {
+        let part = encoder
+            .next_part()
+            .map_err(|e| anyhow!("encoding UR part: {:?}", e))?;
+        let frame = render(&part)?;
+        let mut stderr = stderr.lock();
+        // clear the screen and draw from the top left
+        write!(stderr, "\x1b[2J\x1b[H{}", frame)?;
+        writeln!(
+            stderr,
+            "{} part(s). Scan with your signing device then press enter.",
+            n_parts
+        )?;
+        stderr.flush()?;
+        drop(stderr);
+        if n_parts == 1 {
+            while !done.load(Ordering::SeqCst) {
+                std::thread::sleep(FRAME_INTERVAL);
+            }
+        } else {
+            std::thread::sleep(FRAME_INTERVAL);
+        }
+    }
+    Ok(())
+}
+
+/// Accumulates UR parts until the PSBT can be decoded. A line of base64 is also accepted.
+#[derive(Default)]
+pub struct PsbtReceiver {
+    decoder: ur::Decoder,
+}
+
+impl PsbtReceiver {
+    /// Returns the PSBT once enough parts have been received.
+    pub fn receive(&mut self, part: &str) -> anyhow::Result<Option<Psbt>> {
+        let part = part.trim();
+        if part.is_empty() {
+            return Ok(None);
+        }
+        if !part.to_lowercase().starts_with("ur:") {
+            return Ok(Some(Psbt::from_str(part).context("parsing base64 PSBT")?));
+        }
+        self.decoder
+            .receive(&part.to_lowercase())
+            .map_err(|e| anyhow!("invalid UR part: {:?}", e))?;
+        if !self.decoder.complete() {
+            return Ok(None);
+        }
+        let message = self
+            .decoder
+            .message()
+            .map_err(|e| anyhow!("decoding UR: {:?}", e))?
+            .ok_or(anyhow!("UR decoder is complete but has no message"))?;
+        Ok(Some(encode::deserialize(cbor_unwrap(&message)?)?))
+    }
+}
+
+/// Read UR parts (or a base64 PSBT) one per line until we have a complete PSBT.
+pub fn read_psbt(interaction: &dyn Interaction) -> anyhow::Result<Psbt> {
+    let mut receiver = PsbtReceiver::default();
+    while let Some(line) = interaction.read_line("") {
+        match receiver.receive(&line)? {
+            Some(psbt) => return Ok(psbt),
+            None => crate::elog!(@info "Got a part. Keep going..."),
+        }
+    }
+    Err(anyhow!("input ended before the PSBT was complete"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cbor_byte_string_roundtrip() {
+        for len in [0usize, 1, 23, 24, 255, 256, 65_535, 65_536] {
+            let bytes = vec![7u8; len];
+            assert_eq!(cbor_unwrap(&cbor_wrap(&bytes)).unwrap(), &bytes[..]);
+        }
+        assert_eq!(cbor_wrap(&[1, 2]), vec![0x42, 1, 2]);
+        assert_eq!(&cbor_wrap(&[0u8; 24])[..2], &[0x58, 24]);
+        assert!(cbor_unwrap(&[0x43, 1, 2]).is_err());
+    }
+}
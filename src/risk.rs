@@ -0,0 +1,347 @@
+//! How much is at risk in unfinished bets (`gun risk`) and the limits in
+//! [`Config::tag_limits`](crate::config::Config::tag_limits).
+use crate::{
+    betting::{BetId, BetOrProp, BetState, OfferedBet},
+    chrono::{Duration, NaiveDateTime},
+    config::TagLimit,
+};
+use bdk::bitcoin::{Amount, SignedAmount};
+use std::collections::BTreeMap;
+
+/// What we stand to lose and win on an unfinished bet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exposure {
+    pub bet_id: BetId,
+    pub oracle: String,
+    pub expected_outcome_time: Option<NaiveDateTime>,
+    pub tags: Vec<String>,
+    pub at_risk: Amount,
+    /// `None` for proposals since we don't know what will be offered
+    pub to_win: Option<Amount>,
+}
+
+/// The exposure of each bet that hasn't finished yet.
+pub fn exposures(bets: impl IntoIterator<Item = (BetId, BetState)>) -> Vec<Exposure> {
+    bets.into_iter()
+        .filter_map(|(bet_id, bet_state)| {
+            let at_risk = bet_state.at_risk()?;
+            let tags = bet_state.tags().to_vec();
+            Some(match bet_state.into_bet_or_prop() {
+                BetOrProp::Proposal(local_proposal) => Exposure {
+                    bet_id,
+                    oracle: local_proposal.proposal.oracle,
+                    expected_outcome_time: local_proposal.oracle_event.event.expected_outcome_time,
+                    tags,
+                    at_risk,
+                    to_win: None,
+                },
+                BetOrProp::Bet(bet)
+                | BetOrProp::OfferedBet {
+                    bet: OfferedBet(bet),
+                    ..
+                } => Exposure {
+                    bet_id,
+                    expected_outcome_time: bet.oracle_event.event.expected_outcome_time,
+                    to_win: bet.joint_output_value.checked_sub(bet.local_value),
+                    oracle: bet.oracle_id,
+                    tags,
+                    at_risk,
+                },
+            })
+        })
+        .collect()
+}
+
+/// The total at risk in unfinished bets with each tag. A bet with several tags counts towards
+/// each of them.
+pub fn at_risk_by_tag(
+    bets: impl IntoIterator<Item = (BetId, BetState)>,
+) -> BTreeMap<String, Amount> {
+    let mut by_tag = BTreeMap::<String, Amount>::new();
+    for exposure in exposures(bets) {
+        for tag in exposure.tags {
+            *by_tag.entry(tag).or_insert(Amount::ZERO) += exposure.at_risk;
+        }
+    }
+    by_tag
+}
+
+/// When a bet's outcome is expected relative to now.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TimeBucket {
+    Overdue,
+    Day,
+    Week,
+    Month,
+    Later,
+    Unknown,
+}
+
+impl TimeBucket {
+    pub fn of(expected_outcome_time: Option<NaiveDateTime>, now: NaiveDateTime) -> Self {
+        let time = match expected_outcome_time {
+            Some(time) => time,
+            None => return TimeBucket::Unknown,
+        };
+        if time < now {
+            TimeBucket::Overdue
+        } else if time < now + Duration::days(1) {
+            TimeBucket::Day
+        } else if time < now + Duration::weeks(1) {
+            TimeBucket::Week
+        } else if time < now + Duration::days(30) {
+            TimeBucket::Month
+        } else {
+            TimeBucket::Later
+        }
+    }
+}
+
+impl core::fmt::Display for TimeBucket {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            TimeBucket::Overdue => "overdue",
+            TimeBucket::Day => "within a day",
+            TimeBucket::Week => "within a week",
+            TimeBucket::Month => "within 30 days",
+            TimeBucket::Later => "later",
+            TimeBucket::Unknown => "no outcome time",
+        })
+    }
+}
+
+/// How likely we are to win a bet going by its tags.
+#[derive(Clone, Debug)]
+pub struct WinProbabilities<'a> {
+    /// From [`Config::win_probabilities`](crate::config::Config::win_probabilities)
+    pub by_tag: &'a BTreeMap<String, f64>,
+    /// For bets without a tag in `by_tag`
+    pub default: f64,
+}
+
+impl WinProbabilities<'_> {
+    /// The probability of the bet's first tag that has one.
+    pub fn of(&self, tags: &[String]) -> f64 {
+        tags.iter()
+            .find_map(|tag| self.by_tag.get(tag))
+            .cloned()
+            .unwrap_or(self.default)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Totals {
+    pub bets: u64,
+    /// What we lose in the worst case
+    pub at_risk: Amount,
+    /// What we win in the best case (not counting proposals)
+    pub to_win: Amount,
+    /// The expected gain (or loss) from bets that have a counterparty
+    pub expected_value: SignedAmount,
+}
+
+impl Default for Totals {
+    fn default() -> Self {
+        Totals {
+            bets: 0,
+            at_risk: Amount::ZERO,
+            to_win: Amount::ZERO,
+            expected_value: SignedAmount::ZERO,
+        }
+    }
+}
+
+impl Totals {
+    fn add(&mut self, exposure: &Exposure, win_probability: f64) {
+        self.bets += 1;
+        self.at_risk += exposure.at_risk;
+        if let Some(to_win) = exposure.to_win {
+            self.to_win += to_win;
+            let expected = win_probability * to_win.as_sat() as f64
+                - (1.0 - win_probability) * exposure.at_risk.as_sat() as f64;
+            self.expected_value += SignedAmount::from_sat(expected.round() as i64);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RiskReport {
+    pub total: Totals,
+    pub by_oracle: BTreeMap<String, Totals>,
+    pub by_time: BTreeMap<TimeBucket, Totals>,
+}
+
+/// Add up the exposures by oracle and by when their outcomes are expected.
+pub fn report(
+    exposures: &[Exposure],
+    win_probabilities: &WinProbabilities<'_>,
+    now: NaiveDateTime,
+) -> RiskReport {
+    let mut report = RiskReport::default();
+    for exposure in exposures {
+        let win_probability = win_probabilities.of(&exposure.tags);
+        report.total.add(exposure, win_probability);
+        report
+            .by_oracle
+            .entry(exposure.oracle.clone())
+            .or_default()
+            .add(exposure, win_probability);
+        report
+            .by_time
+            .entry(TimeBucket::of(exposure.expected_outcome_time, now))
+            .or_default()
+            .add(exposure, win_probability);
+    }
+    report
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TagLimitBreach {
+    pub tag: String,
+    /// What's at risk with the tag without the new bet
+    pub at_risk: Amount,
+    pub limit: Amount,
+    pub strict: bool,
+}
+
+impl core::fmt::Display for TagLimitBreach {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "bets tagged ‘{}’ already have {} at risk and this bet would take it over the limit of {}",
+            self.tag, self.at_risk, self.limit
+        )
+    }
+}
+
+/// The limits a new bet risking `value` with `tags` would go over.
+pub fn tag_limit_breaches(
+    limits: &BTreeMap<String, TagLimit>,
+    at_risk_by_tag: &BTreeMap<String, Amount>,
+    tags: &[String],
+    value: Amount,
+) -> Vec<TagLimitBreach> {
+    let mut tags = tags.iter().collect::<Vec<_>>();
+    tags.sort();
+    tags.dedup();
+    tags.into_iter()
+        .filter_map(|tag| {
+            let limit = limits.get(tag)?;
+            let at_risk = at_risk_by_tag.get(tag).cloned().unwrap_or(Amount::ZERO);
+            if at_risk + value > Amount::from_sat(limit.max_at_risk) {
+                Some(TagLimitBreach {
+                    tag: tag.clone(),
+                    at_risk,
+                    limit: Amount::from_sat(limit.max_at_risk),
+                    strict: limit.strict,
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn report_totals() {
+        let now = NaiveDateTime::from_str("2021-08-20T12:00:00").unwrap();
+        let exposure = |bet_id, oracle: &str, hours, tag: &str, at_risk, to_win| Exposure {
+            bet_id,
+            oracle: oracle.into(),
+            expected_outcome_time: hours.map(|hours| now + Duration::hours(hours)),
+            tags: vec![tag.into()],
+            at_risk: Amount::from_sat(at_risk),
+            to_win: to_win.map(Amount::from_sat),
+        };
+        let exposures = vec![
+            exposure(1, "h00.ooo", Some(2), "sports", 10_000, Some(20_000)),
+            exposure(2, "h00.ooo", Some(-2), "fun", 5_000, Some(5_000)),
+            exposure(3, "other.com", None, "fun", 7_000, None),
+        ];
+        let by_tag = BTreeMap::from([("sports".to_string(), 0.25)]);
+        let report = report(
+            &exposures,
+            &WinProbabilities {
+                by_tag: &by_tag,
+                default: 0.5,
+            },
+            now,
+        );
+        assert_eq!(report.total.bets, 3);
+        assert_eq!(report.total.at_risk, Amount::from_sat(22_000));
+        assert_eq!(report.total.to_win, Amount::from_sat(25_000));
+        // 0.25 * 20_000 - 0.75 * 10_000 + 0.5 * 5_000 - 0.5 * 5_000
+        assert_eq!(report.total.expected_value, SignedAmount::from_sat(-2_500));
+        assert_eq!(
+            report.by_oracle["h00.ooo"].at_risk,
+            Amount::from_sat(15_000)
+        );
+        assert_eq!(report.by_oracle["other.com"].to_win, Amount::ZERO);
+        assert_eq!(
+            report.by_time.keys().cloned().collect::<Vec<_>>(),
+            vec![TimeBucket::Overdue, TimeBucket::Day, TimeBucket::Unknown]
+        );
+    }
+
+    #[test]
+    fn breaches_only_limited_tags_over_the_limit() {
+        let limits = BTreeMap::from([
+            (
+                "sports".to_string(),
+                TagLimit {
+                    max_at_risk: 50_000,
+                    strict: false,
+                },
+            ),
+            (
+                "politics".to_string(),
+                TagLimit {
+                    max_at_risk: 100_000,
+                    strict: true,
+                },
+            ),
+        ]);
+        let at_risk = BTreeMap::from([
+            ("sports".to_string(), Amount::from_sat(40_000)),
+            ("fun".to_string(), Amount::from_sat(1_000_000)),
+        ]);
+        let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>();
+
+        assert!(tag_limit_breaches(
+            &limits,
+            &at_risk,
+            &tags(&["sports", "fun"]),
+            Amount::from_sat(10_000)
+        )
+        .is_empty());
+
+        let breaches = tag_limit_breaches(
+            &limits,
+            &at_risk,
+            &tags(&["sports", "sports", "politics"]),
+            Amount::from_sat(100_001),
+        );
+        assert_eq!(
+            breaches,
+            vec![
+                TagLimitBreach {
+                    tag: "politics".into(),
+                    at_risk: Amount::ZERO,
+                    limit: Amount::from_sat(100_000),
+                    strict: true,
+                },
+                TagLimitBreach {
+                    tag: "sports".into(),
+                    at_risk: Amount::from_sat(40_000),
+                    limit: Amount::from_sat(50_000),
+                    strict: false,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,120 @@
+//! Recurring payments that are made with `gun schedule run`.
+use anyhow::anyhow;
+use bdk::bitcoin::{Address, Amount, Txid};
+use std::{fmt, str::FromStr};
+
+pub type ScheduleId = u32;
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+const WEEK: u64 = 7 * DAY;
+
+/// How often a payment repeats (in seconds). Written like `12h`, `30d` or `2w`.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct Interval(pub u64);
+
+impl FromStr for Interval {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> anyhow::Result<Self> {
+        let split = string
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or(anyhow!("'{}' needs a unit (h, d or w)", string))?;
+        let (n, unit) = string.split_at(split);
+        let n = u64::from_str(n).map_err(|_| anyhow!("'{}' is not a valid interval", string))?;
+        let unit = match unit {
+            "h" => HOUR,
+            "d" => DAY,
+            "w" => WEEK,
+            _ => return Err(anyhow!("'{}' is not a unit. Use h, d or w", unit)),
+        };
+        match n.checked_mul(unit) {
+            Some(0) | None => Err(anyhow!("'{}' is not a valid interval", string)),
+            Some(secs) => Ok(Interval(secs)),
+        }
+    }
+}
+
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.0;
+        if secs % WEEK == 0 {
+            write!(f, "{}w", secs / WEEK)
+        } else if secs % DAY == 0 {
+            write!(f, "{}d", secs / DAY)
+        } else if secs % HOUR == 0 {
+            write!(f, "{}h", secs / HOUR)
+        } else {
+            write!(f, "{}s", secs)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Schedule {
+    pub to: Address,
+    #[serde(with = "bdk::bitcoin::util::amount::serde::as_sat")]
+    pub value: Amount,
+    pub every: Interval,
+    /// Unix time the next payment should be made
+    pub next_due: u64,
+    pub label: Option<String>,
+    /// Broadcast without asking
+    pub auto_confirm: bool,
+    /// Transactions that have made payments so far
+    pub payments: Vec<Txid>,
+}
+
+impl Schedule {
+    /// How many payments should have been made by `now` but haven't.
+    pub fn payments_due(&self, now: u64) -> u64 {
+        if now < self.next_due {
+            0
+        } else {
+            (now - self.next_due) / self.every.0 + 1
+        }
+    }
+
+    /// Record a payment. The next due date is counted from the last one so the schedule doesn't
+    /// drift if it's run late.
+    pub fn paid(&mut self, txid: Txid) {
+        self.payments.push(txid);
+        self.next_due += self.every.0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_interval() {
+        assert_eq!(Interval::from_str("30d").unwrap(), Interval(30 * DAY));
+        assert_eq!(Interval::from_str("2w").unwrap(), Interval(14 * DAY));
+        assert_eq!(Interval::from_str("12h").unwrap().to_string(), "12h");
+        assert_eq!(Interval::from_str("14d").unwrap().to_string(), "2w");
+        assert!(Interval::from_str("0d").is_err());
+        assert!(Interval::from_str("30").is_err());
+        assert!(Interval::from_str("1y").is_err());
+    }
+
+    #[test]
+    fn payments_due() {
+        let mut schedule = Schedule {
+            to: Address::from_str("tb1q6d3a2w975yny0asuvd9a67ner4nks58ff0q8g4").unwrap(),
+            value: Amount::from_sat(1_000),
+            every: Interval(DAY),
+            next_due: 1_000_000,
+            label: None,
+            auto_confirm: false,
+            payments: vec![],
+        };
+        assert_eq!(schedule.payments_due(999_999), 0);
+        assert_eq!(schedule.payments_due(1_000_000), 1);
+        assert_eq!(schedule.payments_due(1_000_000 + 2 * DAY + 1), 3);
+        schedule.paid(Txid::default());
+        assert_eq!(schedule.next_due, 1_000_000 + DAY);
+        assert_eq!(schedule.payments_due(1_000_000 + 2 * DAY + 1), 2);
+    }
+}
@@ -0,0 +1,489 @@
+//! JSON Schemas for the serde representation of the bet protocol types.
+//!
+//! These describe what you get when the types are serialized with `serde_json` (e.g. `gun --json
+//! bet show --raw`). The compact strings that get posted publicly (proposals and ciphertexts) are
+//! the same types serialized with bincode and then base2048 encoded.
+use serde_json::{json, Value};
+
+const DRAFT: &str = "http://json-schema.org/draft-07/schema#";
+
+fn hex(description: &str) -> Value {
+    json!({ "type": "string", "pattern": "^([0-9a-f]{2})*$", "description": description })
+}
+
+fn sats(description: &str) -> Value {
+    json!({ "type": "integer", "minimum": 0, "description": description })
+}
+
+fn outpoint() -> Value {
+    json!({
+        "type": "string",
+        "pattern": "^[0-9a-f]{64}:[0-9]+$",
+        "description": "<txid>:<vout>"
+    })
+}
+
+fn definitions() -> Value {
+    json!({
+        "outpoint": outpoint(),
+        "public_key": hex("32 byte x-only public key with even y-coordinate"),
+        "change": {
+            "type": "object",
+            "required": ["value", "script_pubkey"],
+            "additionalProperties": false,
+            "properties": {
+                "value": sats("value of the change output in satoshis"),
+                "script_pubkey": hex("the change output's script pubkey")
+            }
+        },
+        "witness": {
+            "type": "object",
+            "description": "externally tagged by witness type",
+            "oneOf": [{
+                "required": ["P2wpkh"],
+                "additionalProperties": false,
+                "properties": {
+                    "P2wpkh": {
+                        "type": "object",
+                        "required": ["key", "signature"],
+                        "additionalProperties": false,
+                        "properties": {
+                            "key": hex("33 byte compressed public key"),
+                            "signature": hex("64 byte compact ECDSA signature")
+                        }
+                    }
+                }
+            }, {
+                "required": ["P2trKeySpend"],
+                "additionalProperties": false,
+                "properties": {
+                    "P2trKeySpend": {
+                        "type": "object",
+                        "required": ["signature", "sighash"],
+                        "additionalProperties": false,
+                        "properties": {
+                            "signature": hex("64 byte BIP340 signature"),
+                            "sighash": {
+                                "type": ["integer", "null"],
+                                "description": "the sighash flag if it isn't SIGHASH_DEFAULT"
+                            }
+                        }
+                    }
+                }
+            }]
+        },
+        "signed_input": {
+            "type": "object",
+            "required": ["outpoint", "witness"],
+            "additionalProperties": false,
+            "properties": {
+                "outpoint": { "$ref": "#/definitions/outpoint" },
+                "witness": { "$ref": "#/definitions/witness" }
+            }
+        }
+    })
+}
+
+pub fn proposal() -> Value {
+    json!({
+        "$schema": DRAFT,
+        "title": "Proposal",
+        "type": "object",
+        "required": ["oracle", "event_id", "value", "inputs", "public_key", "change_script"],
+        "additionalProperties": false,
+        "properties": {
+            "oracle": { "type": "string", "description": "the oracle's id (its domain)" },
+            "event_id": { "type": "string", "description": "the olivia event id e.g. /EPL/match/2021-08-22/ARS_CHE.vs=CHE_win" },
+            "value": sats("the value the proposer is risking"),
+            "inputs": { "type": "array", "items": { "$ref": "#/definitions/outpoint" } },
+            "public_key": { "$ref": "#/definitions/public_key" },
+            "change_script": { "oneOf": [hex("the proposer's change script pubkey"), { "type": "null" }] },
+            "refund_after": { "type": "integer", "minimum": 0, "description": "the height after which both parties can refund the bet if the oracle never attests. Left out if there isn't one." }
+        },
+        "definitions": definitions()
+    })
+}
+
+pub fn offer() -> Value {
+    json!({
+        "$schema": DRAFT,
+        "title": "Offer",
+        "type": "object",
+        "required": ["inputs", "change", "choose_right", "value", "locktime"],
+        "additionalProperties": false,
+        "properties": {
+            "inputs": { "type": "array", "items": { "$ref": "#/definitions/signed_input" } },
+            "change": { "oneOf": [{ "$ref": "#/definitions/change" }, { "type": "null" }] },
+            "choose_right": { "type": "boolean", "description": "whether the offerer chose the second outcome" },
+            "value": sats("the value the offerer is risking"),
+            "locktime": { "type": "integer", "minimum": 0, "description": "the bet transaction's nLockTime. Offers from before it was added have 0." }
+        },
+        "definitions": definitions()
+    })
+}
+
+pub fn ciphertext() -> Value {
+    json!({
+        "$schema": DRAFT,
+        "title": "Ciphertext",
+        "type": "object",
+        "required": ["public_key", "encrypted_bytes"],
+        "additionalProperties": false,
+        "properties": {
+            "public_key": { "$ref": "#/definitions/public_key" },
+            "encrypted_bytes": {
+                "type": "array",
+                "items": { "type": "integer", "minimum": 0, "maximum": 255 },
+                "description": "ChaCha20 encrypted bincode encoding of a Plaintext"
+            }
+        },
+        "definitions": definitions()
+    })
+}
+
+pub fn plaintext() -> Value {
+    let bytes = |description: &str| {
+        json!({
+            "type": "array",
+            "items": { "type": "integer", "minimum": 0, "maximum": 255 },
+            "description": description
+        })
+    };
+    let mut offer = offer();
+    let offer = offer.as_object_mut().unwrap();
+    offer.remove("$schema");
+    offer.remove("definitions");
+    json!({
+        "$schema": DRAFT,
+        "title": "Plaintext",
+        "description": "the decrypted contents of a Ciphertext, externally tagged by version",
+        "oneOf": [
+            {
+                "type": "object",
+                "required": ["Offerv1"],
+                "additionalProperties": false,
+                "properties": {
+                    "Offerv1": {
+                        "type": "object",
+                        "required": ["offer", "message"],
+                        "additionalProperties": false,
+                        "properties": {
+                            "offer": offer,
+                            "message": { "type": ["string", "null"] }
+                        }
+                    }
+                }
+            },
+            {
+                "type": "object",
+                "required": ["Messagev1"],
+                "additionalProperties": false,
+                "properties": { "Messagev1": { "type": "string" } }
+            },
+            {
+                "type": "object",
+                "required": ["CancelRequestv1"],
+                "additionalProperties": false,
+                "properties": {
+                    "CancelRequestv1": {
+                        "type": "object",
+                        "required": ["psbt", "message"],
+                        "additionalProperties": false,
+                        "properties": {
+                            "psbt": bytes("the consensus encoded refund PSBT with the sender's signature"),
+                            "message": { "type": ["string", "null"] }
+                        }
+                    }
+                }
+            },
+            {
+                "type": "object",
+                "required": ["BetMessagev1"],
+                "additionalProperties": false,
+                "properties": {
+                    "BetMessagev1": {
+                        "type": "object",
+                        "required": ["seq", "text", "mac"],
+                        "additionalProperties": false,
+                        "properties": {
+                            "seq": { "type": "integer", "minimum": 0, "description": "the sender's message number" },
+                            "text": { "type": "string" },
+                            "mac": bytes("HMAC-SHA256 of the sender's key, seq and text")
+                        }
+                    }
+                }
+            }
+        ],
+        "definitions": definitions()
+    })
+}
+
+pub fn bet_state() -> Value {
+    let states = [
+        ("Proposed", vec!["local_proposal"]),
+        ("Offered", vec!["bet", "encrypted_offer"]),
+        ("Included", vec!["bet", "height"]),
+        ("Won", vec!["bet", "secret_key", "attestation"]),
+        ("Lost", vec!["bet", "attestation"]),
+        (
+            "Claimed",
+            vec!["bet", "txid", "height", "secret_key", "attestation"],
+        ),
+        ("Refunded", vec!["bet", "txid", "height"]),
+        (
+            "Canceled",
+            vec![
+                "pre_cancel",
+                "bet_spent_vin",
+                "cancel_txid",
+                "cancel_vin",
+                "height",
+                "i_intend_cancel",
+            ],
+        ),
+    ];
+    let variants = states
+        .iter()
+        .map(|(state, fields)| {
+            let mut required = vec!["state"];
+            required.extend(fields.iter());
+            let mut properties = serde_json::Map::new();
+            properties.insert("state".into(), json!({ "const": state }));
+            for field in fields {
+                properties.insert(field.to_string(), json!({}));
+            }
+            json!({
+                "type": "object",
+                "required": required,
+                "additionalProperties": false,
+                "properties": properties
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "$schema": DRAFT,
+        "title": "BetState",
+        "description": "a bet record in the gun database, internally tagged by \"state\"",
+        "oneOf": variants
+    })
+}
+
+/// All the schemas keyed by the versions of the protocol they apply to.
+pub fn all() -> Value {
+    json!({
+        "proposal": { "versions": ["One"], "schema": proposal() },
+        "offer": { "versions": ["Offerv1"], "schema": offer() },
+        "ciphertext": { "versions": ["Offerv1", "Messagev1", "CancelRequestv1", "BetMessagev1"], "schema": ciphertext() },
+        "plaintext": { "versions": ["Offerv1", "Messagev1", "CancelRequestv1", "BetMessagev1"], "schema": plaintext() },
+        "bet-state": { "versions": [crate::database::DB_VERSION], "schema": bet_state() },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        betting::*,
+        change::Change,
+        keychain::{DerivationVersion, Keychain},
+    };
+    use bdk::bitcoin::{hashes::Hash, Amount, OutPoint, Script, Txid};
+    use olivia_core::{Event, EventId};
+    use olivia_secp256k1::{
+        ecdsa_fun,
+        fun::{marker::*, s, Point, G},
+        schnorr_fun,
+    };
+    use std::str::FromStr;
+
+    /// Checks `value` against the parts of JSON Schema the schemas here use. `$ref`s are looked up
+    /// in `root`.
+    fn validate(value: &Value, schema: &Value, root: &Value) -> Result<(), String> {
+        if let Some(reference) = schema["$ref"].as_str() {
+            let name = reference.strip_prefix("#/definitions/").unwrap();
+            return validate(value, &root["definitions"][name], root);
+        }
+        if let Some(types) = schema.get("type") {
+            let types = match types {
+                Value::Array(types) => types.clone(),
+                single => vec![single.clone()],
+            };
+            let actual = match value {
+                Value::Null => "null",
+                Value::Bool(_) => "boolean",
+                Value::Number(number) if number.is_u64() || number.is_i64() => "integer",
+                Value::Number(_) => "number",
+                Value::String(_) => "string",
+                Value::Array(_) => "array",
+                Value::Object(_) => "object",
+            };
+            if !types.iter().any(|ty| ty == actual) {
+                return Err(format!("{} should be one of {:?}", value, types));
+            }
+        }
+        if let Some(expected) = schema.get("const") {
+            if value != expected {
+                return Err(format!("{} should be {}", value, expected));
+            }
+        }
+        if let (Some(minimum), Some(number)) = (schema["minimum"].as_u64(), value.as_u64()) {
+            if number < minimum {
+                return Err(format!("{} is below {}", number, minimum));
+            }
+        }
+        if let (Some(maximum), Some(number)) = (schema["maximum"].as_u64(), value.as_u64()) {
+            if number > maximum {
+                return Err(format!("{} is above {}", number, maximum));
+            }
+        }
+        if let Some(object) = value.as_object() {
+            for key in schema["required"].as_array().into_iter().flatten() {
+                if !object.contains_key(key.as_str().unwrap()) {
+                    return Err(format!("missing {}", key));
+                }
+            }
+            for (key, item) in object {
+                match schema["properties"].get(key) {
+                    Some(property) => {
+                        validate(item, property, root).map_err(|e| format!("{}: {}", key, e))?
+                    }
+                    None if schema["additionalProperties"] == false => {
+                        return Err(format!("{} isn't in the schema", key))
+                    }
+                    None => {}
+                }
+            }
+        }
+        if let (Some(items), Some(array)) = (schema.get("items"), value.as_array()) {
+            for item in array {
+                validate(item, items, root)?;
+            }
+        }
+        if let Some(one_of) = schema["oneOf"].as_array() {
+            let matches = one_of
+                .iter()
+                .filter(|schema| validate(value, schema, root).is_ok())
+                .count();
+            if matches != 1 {
+                return Err(format!("{} matches {} of oneOf", value, matches));
+            }
+        }
+        Ok(())
+    }
+
+    fn assert_matches_schema<T: serde::Serialize>(value: &T, schema: Value) {
+        let value = serde_json::to_value(value).unwrap();
+        if let Err(e) = validate(&value, &schema, &schema) {
+            panic!("{} doesn't match {}: {}", value, schema["title"], e);
+        }
+    }
+
+    fn proposal(refund_after: Option<u32>) -> Proposal {
+        let event_id = EventId::from_str("/random/2020-09-25T08:00:00/heads_tails.winner").unwrap();
+        let mut proposal = Proposal {
+            oracle: "h00.ooo".into(),
+            event_id,
+            value: Amount::from_sat(1_000),
+            inputs: vec![OutPoint::default()],
+            public_key: crate::placeholder_point(),
+            change_script: Some(Script::from(vec![0u8; 22]).into()),
+            refund_after,
+        };
+        proposal.public_key = Keychain::new([1u8; 64])
+            .get_key_for_proposal(&proposal)
+            .public_key;
+        proposal
+    }
+
+    fn offer() -> Offer {
+        let key = Point::<EvenY>::from_scalar_mul(G, &mut s!(7));
+        let mut schnorr_signature = [0u8; 64];
+        schnorr_signature[..32].copy_from_slice(&key.to_xonly().as_bytes()[..]);
+        schnorr_signature[63] = 1;
+        Offer {
+            inputs: vec![
+                SignedInput {
+                    outpoint: OutPoint {
+                        txid: Txid::from_inner([3u8; 32]),
+                        vout: 1,
+                    },
+                    witness: Witness::P2wpkh {
+                        key: key.mark::<Normal>().into(),
+                        signature: ecdsa_fun::Signature::from_bytes([43u8; 64]).unwrap(),
+                    },
+                },
+                SignedInput {
+                    outpoint: OutPoint::default(),
+                    witness: Witness::P2trKeySpend {
+                        signature: schnorr_fun::Signature::from_bytes(schnorr_signature).unwrap(),
+                        sighash: None,
+                    },
+                },
+            ],
+            change: Some(Change::new(5_000, Script::from(vec![0u8; 22]))),
+            choose_right: false,
+            value: Amount::from_sat(1_000),
+            locktime: 700_000,
+        }
+    }
+
+    #[test]
+    fn schemas_match_serde_output() {
+        assert_matches_schema(&proposal(None), super::proposal());
+        assert_matches_schema(&proposal(Some(800_000)), super::proposal());
+        assert_matches_schema(&offer(), super::offer());
+
+        let ciphertext = Ciphertext {
+            public_key: crate::placeholder_point(),
+            encrypted_bytes: vec![1, 2, 3],
+        };
+        assert_matches_schema(&ciphertext, super::ciphertext());
+
+        for plaintext in [
+            Plaintext::Offerv1 {
+                offer: offer(),
+                message: Some("hi".into()),
+            },
+            Plaintext::Messagev1("hi".into()),
+            Plaintext::CancelRequestv1 {
+                psbt: vec![1, 2, 3],
+                message: None,
+            },
+            Plaintext::BetMessagev1 {
+                seq: 1,
+                text: "hi".into(),
+                mac: [7u8; 32],
+            },
+        ] {
+            assert_matches_schema(&plaintext, super::plaintext());
+        }
+
+        let proposal = proposal(None);
+        let bet_state = BetState::Proposed {
+            local_proposal: LocalProposal {
+                oracle_event: OracleEvent {
+                    event: Event {
+                        id: proposal.event_id.clone(),
+                        expected_outcome_time: None,
+                    },
+                    schemes: Default::default(),
+                },
+                proposal,
+                change: None,
+                tags: vec![],
+                derivation: DerivationVersion::V0,
+                secret: 0,
+                dust_fee: None,
+            },
+        };
+        assert_matches_schema(&bet_state, super::bet_state());
+    }
+
+    #[test]
+    fn unknown_fields_are_caught() {
+        let mut value = serde_json::to_value(&offer()).unwrap();
+        value["surprise"] = json!(1);
+        assert!(validate(&value, &super::offer(), &super::offer()).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+//! What kinds of output scripts gun will pay to.
+//!
+//! Addresses for future segwit versions (v2 to v16, and v1 programs that aren't 32 bytes) parse
+//! fine and can be sent to but nothing can spend them until a soft fork gives them a meaning. A
+//! typo or a wallet bug that hands out one of them would lock the coins up for good so gun refuses
+//! to pay to them unless `--allow-future-segwit` is given.
+use anyhow::anyhow;
+use bdk::bitcoin::{Address, Network, Script};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ALLOW_FUTURE_SEGWIT: AtomicBool = AtomicBool::new(false);
+
+/// Let gun pay to future segwit versions (`--allow-future-segwit`).
+pub fn allow_future_segwit() {
+    ALLOW_FUTURE_SEGWIT.store(true, Ordering::SeqCst);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScriptKind {
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    /// A witness program gun doesn't know how anything could spend
+    FutureSegwit {
+        version: u8,
+        program_len: usize,
+    },
+    OpReturn,
+    Other,
+}
+
+impl ScriptKind {
+    pub fn of(script: &Script) -> Self {
+        let bytes = script.as_bytes();
+        if script.is_p2pkh() {
+            return ScriptKind::P2pkh;
+        }
+        if script.is_p2sh() {
+            return ScriptKind::P2sh;
+        }
+        if script.is_op_return() {
+            return ScriptKind::OpReturn;
+        }
+        // a version opcode then a single push of 2 to 40 bytes
+        let is_witness_program = (4..=42).contains(&bytes.len())
+            && (bytes[0] == 0x00 || (0x51..=0x60).contains(&bytes[0]))
+            && bytes[1] as usize == bytes.len() - 2;
+        if !is_witness_program {
+            return ScriptKind::Other;
+        }
+        let version = match bytes[0] {
+            0x00 => 0,
+            opcode => opcode - 0x50,
+        };
+        match (version, bytes.len() - 2) {
+            (0, 20) => ScriptKind::P2wpkh,
+            (0, 32) => ScriptKind::P2wsh,
+            // v0 programs of other lengths are invalid rather than reserved for the future
+            (0, _) => ScriptKind::Other,
+            (1, 32) => ScriptKind::P2tr,
+            (version, program_len) => ScriptKind::FutureSegwit {
+                version,
+                program_len,
+            },
+        }
+    }
+}
+
+impl core::fmt::Display for ScriptKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ScriptKind::P2pkh => write!(f, "p2pkh"),
+            ScriptKind::P2sh => write!(f, "p2sh"),
+            ScriptKind::P2wpkh => write!(f, "p2wpkh"),
+            ScriptKind::P2wsh => write!(f, "p2wsh"),
+            ScriptKind::P2tr => write!(f, "p2tr"),
+            ScriptKind::FutureSegwit {
+                version,
+                program_len,
+            } => write!(f, "segwit v{} ({} byte program)", version, program_len),
+            ScriptKind::OpReturn => write!(f, "OP_RETURN"),
+            ScriptKind::Other => write!(f, "non-standard"),
+        }
+    }
+}
+
+fn check_kind(kind: ScriptKind, what: impl core::fmt::Display) -> anyhow::Result<()> {
+    match kind {
+        ScriptKind::P2pkh
+        | ScriptKind::P2sh
+        | ScriptKind::P2wpkh
+        | ScriptKind::P2wsh
+        | ScriptKind::P2tr => Ok(()),
+        ScriptKind::FutureSegwit { .. } if ALLOW_FUTURE_SEGWIT.load(Ordering::SeqCst) => Ok(()),
+        ScriptKind::FutureSegwit { .. } => Err(anyhow!(
+            "{} is a {} output which nothing can spend yet. Use --allow-future-segwit if you're sure.",
+            what,
+            kind
+        )),
+        _ => Err(anyhow!("gun won't pay to {} ({})", what, kind)),
+    }
+}
+
+/// Check coins paid to `script` could be spent by whoever it belongs to.
+pub fn check_recipient(script: &Script) -> anyhow::Result<()> {
+    check_kind(ScriptKind::of(script), format!("the script {}", script))
+}
+
+/// Whether `address` can be used on `network`. Addresses don't say which test network they're for
+/// (a signet address parses as testnet) so only mainnet is told apart and, for segwit addresses,
+/// regtest's `bcrt` prefix.
+pub fn is_for_network(address: &Address, network: Network) -> bool {
+    if (address.network == Network::Bitcoin) != (network == Network::Bitcoin) {
+        return false;
+    }
+    !address.script_pubkey().is_witness_program()
+        || (address.network == Network::Regtest) == (network == Network::Regtest)
+}
+
+/// Check an address the user wants to pay is for the wallet's network and can be spent from.
+pub fn check_address(address: &Address, network: Network) -> anyhow::Result<()> {
+    if !is_for_network(address, network) {
+        return Err(anyhow!("{} is not a {} address", address, network));
+    }
+    check_kind(ScriptKind::of(&address.script_pubkey()), address)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn script_kinds_round_trip() {
+        // from BIP-173, BIP-350 and BIP-86
+        let addresses = [
+            ("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2", ScriptKind::P2pkh),
+            ("3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy", ScriptKind::P2sh),
+            (
+                "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4",
+                ScriptKind::P2wpkh,
+            ),
+            (
+                "bc1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qccfmv3",
+                ScriptKind::P2wsh,
+            ),
+            (
+                "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0",
+                ScriptKind::P2tr,
+            ),
+            (
+                "bc1zw508d6qejxtdg4y5r3zarvaryvaxxpcs",
+                ScriptKind::FutureSegwit {
+                    version: 2,
+                    program_len: 16,
+                },
+            ),
+            (
+                "bc1sw50qgdz25j",
+                ScriptKind::FutureSegwit {
+                    version: 16,
+                    program_len: 2,
+                },
+            ),
+        ];
+        for (string, kind) in addresses {
+            let address = Address::from_str(string).unwrap();
+            let script = address.script_pubkey();
+            assert_eq!(ScriptKind::of(&script), kind, "{}", string);
+            assert_eq!(
+                Address::from_script(&script, Network::Bitcoin)
+                    .unwrap()
+                    .to_string(),
+                string
+            );
+            let supported = !matches!(kind, ScriptKind::FutureSegwit { .. });
+            assert_eq!(
+                check_address(&address, Network::Bitcoin).is_ok(),
+                supported,
+                "{}",
+                string
+            );
+            assert!(check_address(&address, Network::Testnet).is_err());
+        }
+        assert_eq!(
+            ScriptKind::of(&Script::new_op_return(&[1, 2])),
+            ScriptKind::OpReturn
+        );
+        assert!(check_recipient(&Script::new_op_return(&[1, 2])).is_err());
+    }
+
+    #[test]
+    fn test_networks_share_addresses() {
+        let testnet_segwit =
+            Address::from_str("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx").unwrap();
+        let testnet_base58 = Address::from_str("mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn").unwrap();
+        let regtest_segwit =
+            Address::from_str("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080").unwrap();
+
+        for network in [Network::Testnet, Network::Signet] {
+            assert!(check_address(&testnet_segwit, network).is_ok());
+            assert!(check_address(&testnet_base58, network).is_ok());
+            assert!(check_address(&regtest_segwit, network).is_err());
+        }
+        assert!(check_address(&testnet_segwit, Network::Regtest).is_err());
+        assert!(check_address(&testnet_base58, Network::Regtest).is_ok());
+        assert!(check_address(&regtest_segwit, Network::Regtest).is_ok());
+        for address in [&testnet_segwit, &testnet_base58, &regtest_segwit] {
+            assert!(check_address(address, Network::Bitcoin).is_err());
+        }
+    }
+}
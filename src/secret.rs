@@ -0,0 +1,81 @@
+//! A wrapper for secret material that is wiped when dropped and never shows up in debug output.
+//!
+//! Use [`Secret`] for secrets that get stored in structs or passed around (keys, seeds,
+//! passphrases) since those are the ones that end up in a `{:?}` in a log or error message.
+//! Scratch buffers inside a single function can just be [`Zeroizing`](zeroize::Zeroizing).
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+/// Holds a `T` that is zeroized on drop. Getting at the value takes an explicit
+/// [`expose_secret`](Secret::expose_secret) so it's easy to find everywhere it's used.
+pub struct Secret<T: Zeroize>(T);
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(secret: T) -> Self {
+        Secret(secret)
+    }
+
+    pub fn expose_secret(&self) -> &T {
+        &self.0
+    }
+
+    pub fn expose_secret_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Zeroize> From<T> for Secret<T> {
+    fn from(secret: T) -> Self {
+        Secret::new(secret)
+    }
+}
+
+impl<T: Zeroize + Clone> Clone for Secret<T> {
+    fn clone(&self) -> Self {
+        Secret(self.0.clone())
+    }
+}
+
+/// Compares in constant time (apart from the length) so it doesn't leak how much of a guess was
+/// right.
+impl<T: Zeroize + AsRef<[u8]>> PartialEq for Secret<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_ref().ct_eq(other.0.as_ref()).into()
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<T: Zeroize> core::fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("Secret(REDACTED)")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn debug_is_redacted() {
+        let secret = Secret::new(String::from("correct horse battery staple"));
+        let debug = format!("{:?}", secret);
+        assert!(!debug.contains("horse"), "{}", debug);
+        assert_eq!(debug, "Secret(REDACTED)");
+        assert_eq!(secret.expose_secret(), "correct horse battery staple");
+    }
+
+    #[test]
+    fn eq_compares_the_bytes() {
+        let secret = Secret::new([7u8; 32]);
+        assert!(secret == Secret::new([7u8; 32]));
+        let mut other = [7u8; 32];
+        other[31] = 8;
+        assert!(secret != Secret::new(other));
+        assert!(Secret::new(String::from("abc")) != Secret::new(String::from("abcd")));
+    }
+}
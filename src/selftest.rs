@@ -0,0 +1,150 @@
+//! Checks that this build derives the same keys and produces the same ciphertexts as every other
+//! version of gun.
+//!
+//! Bets only work if both parties agree byte for byte on [`ecdh`](crate::ecdh), the
+//! [`encode`](crate::encode) format and the keychain derivation, and an accidental change to any of
+//! them (e.g. from a dependency upgrade) wouldn't show up until someone couldn't decrypt an offer or
+//! find the keys to claim a bet. The expected values are in `test_vectors/keys.json` which is
+//! compiled in so `gun selftest` can check the binary it's running.
+use crate::{
+    betting::{Ciphertext, Plaintext},
+    cipher::StreamCipher,
+    ecdh, hex,
+    keychain::{KeyPair, Keychain, ProtocolSecret},
+    rand_core::RngCore,
+};
+use anyhow::{anyhow, Context};
+use olivia_secp256k1::fun::{marker::*, Point};
+use std::str::FromStr;
+
+pub const TEST_VECTORS: &str = include_str!("../test_vectors/keys.json");
+
+/// Everything is hex except `message`.
+#[derive(Debug, Clone, serde::Deserialize)]
+struct TestVectors {
+    protocol_secret: String,
+    escrow_public_key: String,
+    local_secret_key: String,
+    local_public_key: String,
+    remote_public_key: String,
+    /// The first 64 bytes of the offer cipher from `ecdh(local, remote)`
+    cipher_keystream: String,
+    /// The first 32 bytes of the rng from `ecdh(local, remote)`
+    rng_bytes: String,
+    message: String,
+    /// `message` as a `Plaintext::Messagev1` encrypted with the offer cipher
+    message_ciphertext: String,
+    bet_thread_mac_key: String,
+}
+
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub result: anyhow::Result<()>,
+}
+
+fn expect(what: &str, got: &[u8], expected: &str) -> anyhow::Result<()> {
+    let got = hex::encode(got);
+    if got == expected {
+        Ok(())
+    } else {
+        Err(anyhow!("{} was {} but should be {}", what, got, expected))
+    }
+}
+
+fn keystream(cipher: &mut impl StreamCipher, len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    cipher.apply_keystream(&mut bytes);
+    bytes
+}
+
+/// Run all the checks against the compiled in test vectors.
+pub fn run() -> anyhow::Result<Vec<Check>> {
+    let vectors: TestVectors =
+        serde_json::from_str(TEST_VECTORS).context("test_vectors/keys.json is invalid")?;
+    let protocol_secret = ProtocolSecret::from_str(&vectors.protocol_secret)
+        .map_err(|e| anyhow!("invalid protocol_secret: {}", e))?;
+    let local_secret_key = hex::decode(&vectors.local_secret_key)
+        .map_err(|e| anyhow!("invalid local_secret_key: {}", e))?;
+    let local =
+        KeyPair::from_slice(&local_secret_key).ok_or(anyhow!("local_secret_key is zero"))?;
+    let remote = Point::<EvenY>::from_str(&vectors.remote_public_key)
+        .map_err(|e| anyhow!("invalid remote_public_key: {}", e))?;
+    let (mut cipher, mut rng) = ecdh::ecdh(&local, &remote);
+    let (mut message_cipher, _) = ecdh::ecdh(&local, &remote);
+
+    let mut checks = vec![];
+    let mut check =
+        |name: &'static str, result: anyhow::Result<()>| checks.push(Check { name, result });
+
+    check(
+        "keychain escrow key",
+        expect(
+            "the escrow public key",
+            Keychain::from(protocol_secret)
+                .escrow_keypair()
+                .public_key
+                .to_xonly()
+                .as_bytes(),
+            &vectors.escrow_public_key,
+        ),
+    );
+    check(
+        "keypair",
+        expect(
+            "the public key",
+            local.public_key.to_xonly().as_bytes(),
+            &vectors.local_public_key,
+        ),
+    );
+    check(
+        "ecdh cipher",
+        expect(
+            "the keystream",
+            &keystream(&mut cipher, 64),
+            &vectors.cipher_keystream,
+        ),
+    );
+    check("ecdh rng", {
+        let mut bytes = [0u8; 32];
+        rng.fill_bytes(&mut bytes);
+        expect("the rng output", &bytes, &vectors.rng_bytes)
+    });
+    check(
+        "encode and encrypt",
+        expect(
+            "the ciphertext",
+            &Ciphertext::create(
+                local.public_key,
+                &mut message_cipher,
+                Plaintext::Messagev1(vectors.message.clone()),
+            )
+            .encrypted_bytes,
+            &vectors.message_ciphertext,
+        ),
+    );
+    check(
+        "bet thread mac key",
+        expect(
+            "the mac key",
+            &ecdh::bet_thread_mac_key(&local.secret_key, &remote.mark::<Normal>()),
+            &vectors.bet_thread_mac_key,
+        ),
+    );
+
+    Ok(checks)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_vectors_reproduce() {
+        for check in run().unwrap() {
+            if let Err(e) = check.result {
+                panic!("{}: {}", check.name, e);
+            }
+        }
+    }
+}
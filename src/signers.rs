@@ -1,4 +1,4 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{path::PathBuf, str::FromStr, sync::Arc};
 
 use bdk::{
     bitcoin::{
@@ -9,22 +9,33 @@ use bdk::{
         },
         Network,
     },
-    keys::{bip39::Mnemonic, DerivableKey, ExtendedKey},
+    keys::bip39::Mnemonic,
     wallet::signer::{Signer, SignerError, SignerId},
 };
 use miniscript::bitcoin::{PrivateKey, PublicKey};
 
 use crate::{
-    cmd::{display_psbt, read_yn},
-    elog,
+    cmd::display_psbt,
+    elog, hwi,
+    interaction::Interaction,
+    passphrase::{self, PassphraseSource},
+    qr,
+    secret::Secret,
 };
 
-#[derive(Debug)]
 pub struct XKeySigner {
     /// The extended key
     pub master_xkey: ExtendedPrivKey,
 }
 
+impl core::fmt::Debug for XKeySigner {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("XKeySigner")
+            .field("network", &self.master_xkey.network)
+            .finish_non_exhaustive()
+    }
+}
+
 impl Signer for XKeySigner {
     fn sign(
         &self,
@@ -80,7 +91,6 @@ impl Signer for XKeySigner {
     }
 }
 
-#[derive(Debug)]
 pub struct PwSeedSigner {
     /// Seed Mnemonic (without passphrase)
     pub mnemonic: Mnemonic,
@@ -88,6 +98,62 @@ pub struct PwSeedSigner {
     pub network: Network,
     /// The expected external wallet descriptor
     pub master_fingerprint: Fingerprint,
+    /// Where to get the passphrase from instead of asking for it
+    pub passphrase_source: Option<PassphraseSource>,
+    /// Asks for the passphrase
+    pub interaction: Arc<dyn Interaction>,
+}
+
+impl core::fmt::Debug for PwSeedSigner {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PwSeedSigner")
+            .field("network", &self.network)
+            .field("master_fingerprint", &self.master_fingerprint)
+            .field("passphrase_source", &self.passphrase_source)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PwSeedSigner {
+    /// Asks for the passphrase until it gives the wallet's master key.
+    pub fn master_xkey(&self) -> Result<ExtendedPrivKey, SignerError> {
+        loop {
+            let p = match &self.passphrase_source {
+                Some(source) => source.read(),
+                None => self
+                    .interaction
+                    .read_secret("Enter your wallet passphrase: ")
+                    .map(Secret::new),
+            };
+            let passphrase = match p {
+                Ok(passphrase) => passphrase,
+                Err(e) => {
+                    elog!(@recoverable_error "Failed to read in password: {}", e);
+                    return Err(SignerError::InvalidKey);
+                }
+            };
+
+            match (
+                passphrase::master_xpriv(
+                    &self.mnemonic,
+                    self.network,
+                    self.master_fingerprint,
+                    &passphrase,
+                ),
+                &self.passphrase_source,
+            ) {
+                (Some(master_xkey), _) => return Ok(master_xkey),
+                (None, Some(source)) => {
+                    // asking again would get the same answer
+                    elog!(@recoverable_error "The passphrase from {} doesn't match the wallet.", source);
+                    return Err(SignerError::InvalidKey);
+                }
+                (None, None) => {
+                    elog!(@recoverable_error "Invalid passphrase, derived fingerprint does not match. Try again.")
+                }
+            }
+        }
+    }
 }
 
 pub const PSBT_SIGNER_ID: u64 = 3735928559;
@@ -99,32 +165,14 @@ impl Signer for PwSeedSigner {
         _input_index: Option<usize>,
         secp: &Secp256k1<All>,
     ) -> Result<(), SignerError> {
-        if !read_yn(&format!(
+        if !self.interaction.confirm(&format!(
             "This is the transaction you're about to sign.\n{}Ok",
             display_psbt(self.network, psbt)
         )) {
             return Err(SignerError::UserCanceled);
         }
 
-        let master_xkey = loop {
-            let p = rpassword::prompt_password_stderr("Enter your wallet passphrase: ");
-            let passphrase = match p {
-                Ok(passphrase) => passphrase,
-                Err(e) => {
-                    elog!(@recoverable_error "Failed to read in password: {}", e);
-                    return Err(SignerError::InvalidKey);
-                }
-            };
-            let full_seed = self.mnemonic.to_seed(passphrase);
-            let xkey: ExtendedKey = full_seed.into_extended_key().unwrap();
-            let master_xkey = xkey.into_xprv(self.network).unwrap();
-
-            if master_xkey.fingerprint(secp) != self.master_fingerprint {
-                elog!(@recoverable_error "Invalid passphrase, derived fingerprint does not match. Try again.");
-            } else {
-                break master_xkey;
-            }
-        };
+        let master_xkey = self.master_xkey()?;
 
         let signer = XKeySigner { master_xkey };
         for i in 0..psbt.inputs.len() {
@@ -143,17 +191,40 @@ impl Signer for PwSeedSigner {
     }
 }
 
+/// Where to get the wallet's master key when something other than signing needs it (e.g. working
+/// out a silent payment output). Only wallets with their seed words have one.
+#[derive(Clone, Debug)]
+pub enum MasterKeySource {
+    Xkey(Arc<XKeySigner>),
+    Passphrase(Arc<PwSeedSigner>),
+}
+
+impl MasterKeySource {
+    pub fn master_xkey(&self) -> Result<ExtendedPrivKey, SignerError> {
+        match self {
+            MasterKeySource::Xkey(signer) => Ok(signer.master_xkey),
+            MasterKeySource::Passphrase(signer) => signer.master_xkey(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct PsbtDirSigner {
     path: PathBuf,
     network: Network,
+    interaction: Arc<dyn Interaction>,
 }
 
 impl PsbtDirSigner {
-    pub fn create(psbt_signer_dir: PathBuf, network: Network) -> Self {
+    pub fn create(
+        psbt_signer_dir: PathBuf,
+        network: Network,
+        interaction: Arc<dyn Interaction>,
+    ) -> Self {
         PsbtDirSigner {
             path: psbt_signer_dir,
             network,
+            interaction,
         }
     }
 }
@@ -165,7 +236,7 @@ impl Signer for PsbtDirSigner {
         _input_index: Option<usize>,
         _secp: &Secp256k1<All>,
     ) -> Result<(), SignerError> {
-        if !read_yn(&format!(
+        if !self.interaction.confirm(&format!(
             "This is the transaction that will be saved for signing.\n{}Ok",
             display_psbt(self.network, psbt)
         )) {
@@ -181,7 +252,7 @@ impl Signer for PsbtDirSigner {
                     "PSBT directory '{}' does not exist (maybe you need to insert your SD card?).\nPress enter to try again.",
                     self.path.display()
                 );
-                let _ = std::io::stdin().read_line(&mut String::new());
+                self.interaction.wait();
             } else if let Err(e) = std::fs::write(&psbt_file, psbt.to_string()) {
                 elog!(
                     @recoverable_error
@@ -189,7 +260,7 @@ impl Signer for PsbtDirSigner {
                     psbt_file.display(),
                     e
                 );
-                let _ = std::io::stdin().read_line(&mut String::new());
+                self.interaction.wait();
             } else {
                 break;
             }
@@ -207,7 +278,7 @@ impl Signer for PsbtDirSigner {
         }
         elog!(@suggestion "Press enter once signed.");
         let (signed_psbt_path, contents) = loop {
-            let _ = std::io::stdin().read_line(&mut String::new());
+            self.interaction.wait();
             let mut file_contents = file_locations
                 .iter()
                 .map(|location| (location.clone(), std::fs::read_to_string(&location)))
@@ -237,9 +308,14 @@ impl Signer for PsbtDirSigner {
                 Err(SignerError::UserCanceled)
             }
             Ok(read_psbt) => {
+                // merge rather than replace so the signer can't drop anything we put in the PSBT
+                // (like the other party's signatures in a bet).
+                if let Err(e) = psbt.merge(read_psbt) {
+                    elog!(@recoverable_error "The PSBT in {} isn't the one we asked to be signed: {}", signed_psbt_path.display(), e);
+                    return Err(SignerError::UserCanceled);
+                }
                 let _ = std::fs::remove_file(psbt_file);
                 let _ = std::fs::remove_file(signed_psbt_path);
-                *psbt = read_psbt;
                 Ok(())
             }
         }
@@ -254,3 +330,169 @@ impl Signer for PsbtDirSigner {
         true
     }
 }
+
+/// Shows the PSBT as animated QR codes for an airgapped device to scan and reads the signed PSBT
+/// back in as UR parts.
+#[derive(Debug)]
+pub struct QrSigner {
+    network: Network,
+    interaction: Arc<dyn Interaction>,
+}
+
+impl QrSigner {
+    pub fn new(network: Network, interaction: Arc<dyn Interaction>) -> Self {
+        QrSigner {
+            network,
+            interaction,
+        }
+    }
+}
+
+impl Signer for QrSigner {
+    fn sign(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        _input_index: Option<usize>,
+        _secp: &Secp256k1<All>,
+    ) -> Result<(), SignerError> {
+        if !self.interaction.confirm(&format!(
+            "This is the transaction that will be shown as a QR code for signing.\n{}Ok",
+            display_psbt(self.network, psbt)
+        )) {
+            return Err(SignerError::UserCanceled);
+        }
+
+        if let Err(e) = qr::show_animated(psbt, qr::DEFAULT_FRAGMENT_LEN, self.interaction.clone())
+        {
+            elog!(@recoverable_error "Failed to display QR code: {}", e);
+            return Err(SignerError::UserCanceled);
+        }
+
+        loop {
+            elog!(@suggestion "Paste the signed PSBT from your device (UR parts one per line, or base64):");
+            match qr::read_psbt(&*self.interaction) {
+                Ok(signed_psbt) => {
+                    if signed_psbt.unsigned_tx.txid() != psbt.unsigned_tx.txid() {
+                        elog!(@recoverable_error "That PSBT is for a different transaction. Try again.");
+                        continue;
+                    }
+                    *psbt = signed_psbt;
+                    return Ok(());
+                }
+                Err(e) => {
+                    elog!(@recoverable_error "Couldn't read signed PSBT: {}", e);
+                    if !self.interaction.confirm("Try again") {
+                        return Err(SignerError::UserCanceled);
+                    }
+                }
+            }
+        }
+    }
+
+    fn id(&self, _secp: &Secp256k1<All>) -> SignerId {
+        // Like the PSBT dir signer it's meant to be able to sign everything
+        SignerId::Dummy(PSBT_SIGNER_ID)
+    }
+
+    fn sign_whole_tx(&self) -> bool {
+        true
+    }
+}
+
+/// Signs by handing the PSBT to a hardware wallet over USB (through HWI).
+#[derive(Debug)]
+pub struct HwiSigner {
+    fingerprint: Fingerprint,
+    network: Network,
+    interaction: Arc<dyn Interaction>,
+}
+
+impl HwiSigner {
+    pub fn new(
+        fingerprint: Fingerprint,
+        network: Network,
+        interaction: Arc<dyn Interaction>,
+    ) -> Self {
+        HwiSigner {
+            fingerprint,
+            network,
+            interaction,
+        }
+    }
+}
+
+impl Signer for HwiSigner {
+    fn sign(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        _input_index: Option<usize>,
+        _secp: &Secp256k1<All>,
+    ) -> Result<(), SignerError> {
+        if !self.interaction.confirm(&format!(
+            "This is the transaction that will be sent to your hardware wallet ({}).\n{}Ok",
+            self.fingerprint,
+            display_psbt(self.network, psbt)
+        )) {
+            return Err(SignerError::UserCanceled);
+        }
+
+        loop {
+            elog!(@info "Confirm the transaction on your hardware wallet.");
+            match hwi::sign_psbt(self.fingerprint, self.network, psbt) {
+                Ok(signed_psbt) => {
+                    *psbt = signed_psbt;
+                    return Ok(());
+                }
+                Err(e) => {
+                    elog!(@recoverable_error "Signing with hardware wallet failed: {}", e);
+                    if !self.interaction.confirm("Try again") {
+                        return Err(SignerError::UserCanceled);
+                    }
+                }
+            }
+        }
+    }
+
+    fn id(&self, _secp: &Secp256k1<All>) -> SignerId {
+        SignerId::from(self.fingerprint)
+    }
+
+    fn sign_whole_tx(&self) -> bool {
+        true
+    }
+}
+
+/// Wraps a signer that isn't required to sign so that its failures don't stop the others.
+#[derive(Debug)]
+pub struct OptionalSigner {
+    inner: Arc<dyn Signer>,
+    index: usize,
+}
+
+impl OptionalSigner {
+    pub fn new(inner: Arc<dyn Signer>, index: usize) -> Self {
+        OptionalSigner { inner, index }
+    }
+}
+
+impl Signer for OptionalSigner {
+    fn sign(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        input_index: Option<usize>,
+        secp: &Secp256k1<All>,
+    ) -> Result<(), SignerError> {
+        if let Err(e) = self.inner.sign(psbt, input_index, secp) {
+            elog!(@recoverable_error "Optional signer {} didn't sign: {:?}", self.index, e);
+        }
+        Ok(())
+    }
+
+    fn sign_whole_tx(&self) -> bool {
+        self.inner.sign_whole_tx()
+    }
+
+    fn id(&self, secp: &Secp256k1<All>) -> SignerId {
+        self.inner.id(secp)
+    }
+}
@@ -0,0 +1,34 @@
+//! Signets are test networks where a block is only valid if it's signed by the keys in the
+//! network's challenge script. Every signet shares the same address format and genesis block so
+//! the challenge is the only thing that tells them apart. Nodes use the first four bytes of its
+//! double SHA256 as the network magic.
+use bdk::bitcoin::{
+    consensus::encode::serialize,
+    hashes::{hex::FromHex, sha256d, Hash},
+    Script,
+};
+
+/// The challenge of the default signet (the one run by Kalle Alm and AJ Towns).
+pub const DEFAULT_CHALLENGE: &str = "512103ad5e0edad18cb1f0fc0d28a3d4f1f3e445640337489abb10404f2d1e086be430210359ef5021964fe22d6f8e05b2463c9540ce96883fe3b278760f048f5189f2e6c452ae";
+
+pub fn default_challenge() -> Script {
+    Script::from(Vec::from_hex(DEFAULT_CHALLENGE).expect("valid hex"))
+}
+
+/// The network magic nodes on the signet with this challenge use.
+pub fn magic(challenge: &Script) -> [u8; 4] {
+    let hash = sha256d::Hash::hash(&serialize(challenge));
+    let mut magic = [0u8; 4];
+    magic.copy_from_slice(&hash[..4]);
+    magic
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_signet_magic() {
+        assert_eq!(magic(&default_challenge()), [0x0a, 0x03, 0xcf, 0x40]);
+    }
+}
@@ -0,0 +1,456 @@
+//! Silent payments (BIP-352). Receiving them is in [`scan`].
+//!
+//! A silent payment address is a scan key and a spend key. The output paying it is worked out from
+//! the secret keys of the transaction's inputs so every payment to the same address goes to a
+//! different taproot output. That means the transaction is built with a stand-in output which
+//! [`GunWallet::set_silent_payment_output`] swaps for the real one before anything is signed.
+//!
+//! Only P2WPKH, P2SH-P2WPKH and P2PKH inputs count towards the output. Bet outputs are P2WSH so they
+//! don't and claiming to a silent payment address has to spend one of the wallet's coins with them.
+use crate::{
+    cmd::{self, WithErrorKind},
+    wallet::GunWallet,
+};
+use anyhow::{anyhow, Context};
+use bdk::bitcoin::{
+    bech32::{self, u5, FromBase32, ToBase32, Variant},
+    consensus::encode::serialize,
+    secp256k1::{self, Secp256k1},
+    util::psbt::{raw, PartiallySignedTransaction as Psbt},
+    Address, Network, OutPoint, PublicKey, Script,
+};
+use olivia_secp256k1::fun::{g, marker::*, s, Point, Scalar, G};
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+
+pub mod scan;
+
+/// The BIP-375 PSBT output field recording the silent payment address an output pays.
+pub const PSBT_OUT_SP_V0_INFO: u8 = 0x09;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SilentPaymentAddress {
+    /// Bitcoin for `sp` addresses and testnet for `tsp` ones (which are also for signet and regtest)
+    pub network: Network,
+    pub scan: Point,
+    pub spend: Point,
+}
+
+impl SilentPaymentAddress {
+    fn hrp(&self) -> &'static str {
+        match self.network {
+            Network::Bitcoin => "sp",
+            _ => "tsp",
+        }
+    }
+
+    pub fn is_for_network(&self, network: Network) -> bool {
+        (self.network == Network::Bitcoin) == (network == Network::Bitcoin)
+    }
+
+    /// The scan key followed by the spend key.
+    pub fn to_bytes(&self) -> [u8; 66] {
+        let mut bytes = [0u8; 66];
+        bytes[..33].copy_from_slice(&self.scan.to_bytes());
+        bytes[33..].copy_from_slice(&self.spend.to_bytes());
+        bytes
+    }
+
+    /// What pays the address while the transaction is being built. It's the same size as the real
+    /// output so the fee comes out right.
+    pub fn placeholder_script(&self) -> Script {
+        p2tr_script(&self.spend)
+    }
+
+    /// The key of the `k`th output paying the address from a transaction whose smallest outpoint
+    /// is `smallest_outpoint` and whose eligible inputs' secret keys add up to `input_secret`.
+    pub fn output_key(
+        &self,
+        input_secret: &Scalar,
+        smallest_outpoint: OutPoint,
+        k: u32,
+    ) -> anyhow::Result<Point> {
+        let input_public_key = g!(input_secret * G).mark::<Normal>();
+        let input_hash = input_hash(smallest_outpoint, &input_public_key);
+        let shared_secret = s!(input_hash * input_secret)
+            .mark::<NonZero>()
+            .ok_or(anyhow!("the input hash was zero"))?;
+        let shared_secret = g!(shared_secret * self.scan).mark::<Normal>();
+        let (_, output_key) = shared_secret_output(&shared_secret, &self.spend, k)?;
+        Ok(output_key)
+    }
+}
+
+impl FromStr for SilentPaymentAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> anyhow::Result<Self> {
+        let (hrp, data, variant) = bech32::decode(string)?;
+        let network = match hrp.as_str() {
+            "sp" => Network::Bitcoin,
+            "tsp" => Network::Testnet,
+            _ => return Err(anyhow!("'{}' is not a silent payment address", string)),
+        };
+        if variant != Variant::Bech32m {
+            return Err(anyhow!("silent payment addresses are bech32m"));
+        }
+        let (version, data) = data.split_first().ok_or(anyhow!("'{}' is empty", string))?;
+        let bytes = Vec::<u8>::from_base32(data)?;
+        // later versions may add data after the keys
+        let keys = match version.to_u8() {
+            0 if bytes.len() == 66 => &bytes[..],
+            1..=30 if bytes.len() >= 66 => &bytes[..66],
+            31 => return Err(anyhow!("silent payment address version 31 is reserved")),
+            _ => {
+                return Err(anyhow!(
+                    "'{}' doesn't have a scan key and a spend key in it",
+                    string
+                ))
+            }
+        };
+        let point = |bytes: &[u8]| {
+            let mut point = [0u8; 33];
+            point.copy_from_slice(bytes);
+            Point::from_bytes(point).ok_or(anyhow!("'{}' has an invalid key in it", string))
+        };
+        Ok(SilentPaymentAddress {
+            network,
+            scan: point(&keys[..33])?,
+            spend: point(&keys[33..])?,
+        })
+    }
+}
+
+impl core::fmt::Display for SilentPaymentAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut data = vec![u5::try_from_u8(0).expect("0 is a u5")];
+        data.extend(self.to_bytes().to_base32());
+        let encoded =
+            bech32::encode(self.hrp(), data, Variant::Bech32m).expect("sp and tsp are valid hrps");
+        write!(f, "{}", encoded)
+    }
+}
+
+/// Where to send coins: a regular address or a silent payment one.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Destination {
+    Address(Address),
+    SilentPayment(SilentPaymentAddress),
+}
+
+impl Destination {
+    /// The output script to build the transaction with. For a silent payment it's
+    /// [`SilentPaymentAddress::placeholder_script`].
+    pub fn script_pubkey(&self) -> Script {
+        match self {
+            Destination::Address(address) => address.script_pubkey(),
+            Destination::SilentPayment(address) => address.placeholder_script(),
+        }
+    }
+
+    pub fn silent_payment(&self) -> Option<&SilentPaymentAddress> {
+        match self {
+            Destination::SilentPayment(address) => Some(address),
+            Destination::Address(_) => None,
+        }
+    }
+}
+
+impl FromStr for Destination {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> anyhow::Result<Self> {
+        let lower = string.to_lowercase();
+        if lower.starts_with("sp1") || lower.starts_with("tsp1") {
+            Ok(Destination::SilentPayment(string.parse()?))
+        } else {
+            Ok(Destination::Address(Address::from_str(string)?))
+        }
+    }
+}
+
+impl core::fmt::Display for Destination {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Destination::Address(address) => write!(f, "{}", address),
+            Destination::SilentPayment(address) => write!(f, "{}", address),
+        }
+    }
+}
+
+fn tagged_hash(tag: &str) -> Sha256 {
+    let tag = Sha256::digest(tag.as_bytes());
+    Sha256::new().chain(&tag).chain(&tag)
+}
+
+fn hash_to_scalar(hash: Sha256) -> Scalar<Secret, Zero> {
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(&hash.finalize());
+    Scalar::from_bytes_mod_order(bytes)
+}
+
+fn p2tr_script(output_key: &Point) -> Script {
+    let mut script = vec![0x51, 0x20];
+    script.extend_from_slice(output_key.to_xonly().as_bytes());
+    Script::from(script)
+}
+
+/// The outpoint that comes first when they're serialized. The input hash commits to it.
+pub fn smallest_outpoint(outpoints: impl IntoIterator<Item = OutPoint>) -> Option<OutPoint> {
+    outpoints
+        .into_iter()
+        .min_by_key(|outpoint| serialize(outpoint))
+}
+
+pub fn input_hash(smallest_outpoint: OutPoint, input_public_key: &Point) -> Scalar<Secret, Zero> {
+    hash_to_scalar(
+        tagged_hash("BIP0352/Inputs")
+            .chain(serialize(&smallest_outpoint))
+            .chain(input_public_key.to_bytes()),
+    )
+}
+
+/// The tweak and output key for the `k`th output from the ECDH shared secret. The sender and the
+/// receiver get to the same shared secret from different sides.
+pub fn shared_secret_output(
+    shared_secret: &Point,
+    spend: &Point,
+    k: u32,
+) -> anyhow::Result<(Scalar<Secret, Zero>, Point)> {
+    let tweak = hash_to_scalar(
+        tagged_hash("BIP0352/SharedSecret")
+            .chain(shared_secret.to_bytes())
+            .chain(k.to_be_bytes()),
+    );
+    let output_key = g!(tweak * G + spend)
+        .mark::<(Normal, NonZero)>()
+        .ok_or(anyhow!("the output key was the point at infinity"))?;
+    Ok((tweak, output_key))
+}
+
+/// Whether an input spending `script_pubkey` counts towards the silent payment output.
+pub fn is_eligible(script_pubkey: &Script, redeem_script: Option<&Script>) -> anyhow::Result<bool> {
+    let bytes = script_pubkey.as_bytes();
+    if bytes.len() == 34 && bytes[0] == 0x51 && bytes[1] == 0x20 {
+        return Err(anyhow!(
+            "gun can't work out silent payments from taproot inputs yet"
+        ));
+    }
+    if script_pubkey.is_p2sh() {
+        return match redeem_script {
+            Some(redeem_script) => Ok(redeem_script.is_v0_p2wpkh()),
+            None => Err(anyhow!(
+                "a P2SH input doesn't have its redeem script so it's unknown whether it counts"
+            )),
+        };
+    }
+    Ok(script_pubkey.is_v0_p2wpkh() || script_pubkey.is_p2pkh())
+}
+
+impl GunWallet {
+    /// Swaps the placeholder output paying `address` for the output the silent payment really goes
+    /// to and records the address in the PSBT. It has to happen before any input is signed.
+    ///
+    /// Outputs that were in BIP-69 order are put back in order afterwards.
+    pub(crate) fn set_silent_payment_output(
+        &self,
+        psbt: &mut Psbt,
+        address: &SilentPaymentAddress,
+    ) -> anyhow::Result<()> {
+        let network = self.bdk_wallet().network();
+        if !address.is_for_network(network) {
+            return Err(anyhow!("{} is not a {} address", address, network))
+                .error_kind(cmd::ErrorKind::User);
+        }
+        let placeholder = address.placeholder_script();
+        let index = psbt
+            .unsigned_tx
+            .output
+            .iter()
+            .position(|txout| txout.script_pubkey == placeholder)
+            .expect("the transaction was built paying the placeholder");
+        let master_xkey = self
+            .master_key()
+            .ok_or(anyhow!("Sending to a silent payment address needs the wallet's private keys to work out the output. Only wallets set up with seed words can do it."))
+            .error_kind(cmd::ErrorKind::User)?
+            .master_xkey()?;
+
+        let secp = Secp256k1::new();
+        let fingerprint = master_xkey.fingerprint(&secp);
+        let mut input_secret = Scalar::zero();
+        for (i, (txin, input)) in psbt.unsigned_tx.input.iter().zip(&psbt.inputs).enumerate() {
+            let script_pubkey = match (&input.witness_utxo, &input.non_witness_utxo) {
+                (Some(txout), _) => &txout.script_pubkey,
+                (None, Some(tx)) => &tx.output[txin.previous_output.vout as usize].script_pubkey,
+                (None, None) => return Err(anyhow!("input {} is missing the output it spends", i)),
+            };
+            if !is_eligible(script_pubkey, input.redeem_script.as_ref())
+                .with_context(|| format!("input {}", i))?
+            {
+                continue;
+            }
+            let (public_key, (_, path)) = input
+                .bip32_derivation
+                .iter()
+                .find(|(_, (input_fingerprint, _))| *input_fingerprint == fingerprint)
+                .ok_or(anyhow!(
+                    "input {} isn't the wallet's so its key can't be used for the silent payment",
+                    i
+                ))?;
+            if !public_key.compressed {
+                continue;
+            }
+            let derived = master_xkey.derive_priv(&secp, path)?;
+            if &PublicKey::new(secp256k1::PublicKey::from_secret_key(
+                &secp,
+                &derived.private_key,
+            )) != public_key
+            {
+                return Err(anyhow!("the wallet's key for input {} doesn't match", i));
+            }
+            let secret_key = Scalar::from(derived.private_key);
+            input_secret = s!(input_secret + secret_key);
+        }
+        let input_secret = input_secret.mark::<NonZero>().ok_or(anyhow!(
+            "none of the transaction's inputs can be used to work out a silent payment"
+        ))?;
+        let smallest_outpoint = smallest_outpoint(
+            psbt.unsigned_tx
+                .input
+                .iter()
+                .map(|txin| txin.previous_output),
+        )
+        .expect("the transaction has inputs");
+        let output_key = address.output_key(&input_secret, smallest_outpoint, 0)?;
+
+        let was_sorted = psbt.unsigned_tx.output.windows(2).all(|pair| {
+            (pair[0].value, &pair[0].script_pubkey) <= (pair[1].value, &pair[1].script_pubkey)
+        });
+        psbt.unsigned_tx.output[index].script_pubkey = p2tr_script(&output_key);
+        psbt.outputs[index].unknown.insert(
+            raw::Key {
+                type_value: PSBT_OUT_SP_V0_INFO,
+                key: vec![],
+            },
+            address.to_bytes().to_vec(),
+        );
+        if was_sorted {
+            let mut outputs = psbt
+                .unsigned_tx
+                .output
+                .drain(..)
+                .zip(psbt.outputs.drain(..))
+                .collect::<Vec<_>>();
+            outputs.sort_by(|(a, _), (b, _)| {
+                (a.value, &a.script_pubkey).cmp(&(b.value, &b.script_pubkey))
+            });
+            let (txouts, outputs): (Vec<_>, Vec<_>) = outputs.into_iter().unzip();
+            psbt.unsigned_tx.output = txouts;
+            psbt.outputs = outputs;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn keypair(byte: u8) -> (Scalar, Point) {
+        let secret_key = Scalar::from_bytes_mod_order([byte; 32])
+            .mark::<NonZero>()
+            .unwrap();
+        let public_key = g!(secret_key * G).mark::<Normal>();
+        (secret_key, public_key)
+    }
+
+    #[test]
+    fn address_round_trip() {
+        let (_, scan) = keypair(1);
+        let (_, spend) = keypair(2);
+        let address = SilentPaymentAddress {
+            network: Network::Bitcoin,
+            scan,
+            spend,
+        };
+        let encoded = address.to_string();
+        assert!(encoded.starts_with("sp1q"));
+        assert_eq!(SilentPaymentAddress::from_str(&encoded).unwrap(), address);
+        assert_eq!(
+            Destination::from_str(&encoded).unwrap(),
+            Destination::SilentPayment(address.clone())
+        );
+
+        let testnet = SilentPaymentAddress {
+            network: Network::Testnet,
+            ..address
+        };
+        assert!(testnet.to_string().starts_with("tsp1q"));
+        assert!(testnet.is_for_network(Network::Regtest));
+        assert!(!testnet.is_for_network(Network::Bitcoin));
+        assert!(matches!(
+            Destination::from_str("bc1qvkswtx2t4y8t6237q753htu4hl4mxm5a9swfjw").unwrap(),
+            Destination::Address(_)
+        ));
+    }
+
+    #[test]
+    fn bip352_address_vector() {
+        // receiving key material and address from the BIP-352 test vectors
+        let secret = |hex: &str| {
+            Scalar::from_bytes_mod_order(olivia_secp256k1::hex::decode_array(hex).unwrap())
+                .mark::<NonZero>()
+                .unwrap()
+        };
+        let point = |hex: &str| {
+            Point::from_bytes(olivia_secp256k1::hex::decode_array(hex).unwrap()).unwrap()
+        };
+        let scan_secret =
+            secret("0f694e068028a717f8af6b9411f9a133dd3565258714cc226594b34db90c1f2c");
+        let spend_secret =
+            secret("9d6ad855ce3417ef84e836892e5a56392bfba05fa5d97ccea30e266f540e08b3");
+        let encoded = "sp1qqgste7k9hx0qftg6qmwlkqtwuy6cycyavzmzj85c6qdfhjdpdjtdgqjuexzk6murw56suy3e0rd2cgqvycxttddwsvgxe2usfpxumr70xc9pkqwv";
+
+        let address = SilentPaymentAddress::from_str(encoded).unwrap();
+        assert_eq!(address.network, Network::Bitcoin);
+        assert_eq!(
+            address.scan,
+            point("0220bcfac5b99e04ad1a06ddfb016ee13582609d60b6291e98d01a9bc9a16c96d4")
+        );
+        assert_eq!(
+            address.spend,
+            point("025cc9856d6f8375350e123978daac200c260cb5b5ae83106cab90484dcd8fcf36")
+        );
+        assert_eq!(address.scan, g!(scan_secret * G).mark::<Normal>());
+        assert_eq!(address.spend, g!(spend_secret * G).mark::<Normal>());
+        assert_eq!(address.to_string(), encoded);
+    }
+
+    #[test]
+    fn receiver_finds_the_same_output() {
+        let (scan_secret, scan) = keypair(3);
+        let (_, spend) = keypair(4);
+        let address = SilentPaymentAddress {
+            network: Network::Regtest,
+            scan,
+            spend,
+        };
+        let (a1, _) = keypair(5);
+        let (a2, _) = keypair(6);
+        let input_secret = s!(a1 + a2).mark::<NonZero>().unwrap();
+        let outpoint = OutPoint::default();
+        let output_key = address.output_key(&input_secret, outpoint, 0).unwrap();
+
+        // the receiver only sees the inputs' public keys
+        let input_public_key = g!(input_secret * G).mark::<Normal>();
+        let input_hash = input_hash(outpoint, &input_public_key);
+        let scan_tweak = s!(input_hash * scan_secret).mark::<NonZero>().unwrap();
+        let shared_secret = g!(scan_tweak * input_public_key).mark::<Normal>();
+        let (_, found) = shared_secret_output(&shared_secret, &spend, 0).unwrap();
+        assert_eq!(found, output_key);
+        assert_ne!(
+            address.output_key(&input_secret, outpoint, 1).unwrap(),
+            output_key
+        );
+    }
+}
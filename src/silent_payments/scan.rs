@@ -0,0 +1,608 @@
+//! Finding silent payments to the wallet (`gun silent-payment scan`) and spending them.
+//!
+//! Nothing on chain links a silent payment to its address so every transaction with a taproot
+//! output in every block since the wallet started receiving them has to be checked. Blocks are
+//! fetched from esplora 25 transactions at a time (with the outputs their inputs spend) which is
+//! slow on mainnet. Using your own esplora server is a good idea.
+//!
+//! The scan secret key is kept in the database so scanning doesn't need the wallet's passphrase.
+//! The spend key is only needed to spend what's found so just its public key is kept.
+//!
+//! BDK can't sign for the outputs that are found so they aren't put in its database where coin
+//! selection would pick them. They are counted in `gun balance` and spent together with
+//! [`spend_found_outputs`] (`gun silent-payment spend`).
+use super::{input_hash, shared_secret_output, smallest_outpoint, SilentPaymentAddress};
+use crate::{
+    betting::satisfaction_weight, elog, wallet::GunWallet, EstimateFee, FeeSpec, FeeTarget,
+};
+use anyhow::{anyhow, Context};
+use bdk::bitcoin::{
+    blockdata::script::Instruction,
+    hashes::{hash160, hex::FromHex, Hash},
+    secp256k1::{self, Message, Secp256k1},
+    util::{
+        bip32::{ChildNumber, DerivationPath, ExtendedPrivKey},
+        psbt::PartiallySignedTransaction as Psbt,
+        sighash::{Prevouts, SigHashCache},
+    },
+    Amount, Network, OutPoint, SchnorrSigHashType, Script, Transaction, TxIn, TxOut, Txid,
+};
+use olivia_secp256k1::fun::{g, marker::*, s, Point, Scalar, G};
+
+/// BIP-341's provably unspendable internal key. Script path spends with it don't count.
+const NUMS_H: [u8; 32] = [
+    0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60, 0x35, 0xe9, 0x7a, 0x5e,
+    0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5, 0x47, 0xbf, 0xee, 0x9a, 0xce, 0x80, 0x3a, 0xc0,
+];
+/// How many transactions esplora gives back for each page of a block
+const ESPLORA_PAGE: usize = 25;
+
+/// What the wallet needs to find silent payments to it. It's stored once scanning is turned on
+/// with `gun silent-payment enable`.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ScanKeys {
+    pub network: Network,
+    pub scan_secret: Scalar,
+    pub spend: Point,
+    /// The next block to scan
+    pub next_height: u32,
+}
+
+impl ScanKeys {
+    /// The keys at BIP-352's derivation paths from the wallet's master key.
+    pub fn derive(
+        master_xkey: &ExtendedPrivKey,
+        network: Network,
+        next_height: u32,
+    ) -> anyhow::Result<Self> {
+        let spend_secret = Self::spend_secret(master_xkey, network)?;
+        Ok(ScanKeys {
+            network,
+            scan_secret: derive_key(master_xkey, network, 1)?,
+            spend: g!(spend_secret * G).mark::<Normal>(),
+            next_height,
+        })
+    }
+
+    /// The secret key found outputs are spent with (before adding their tweak).
+    pub fn spend_secret(master_xkey: &ExtendedPrivKey, network: Network) -> anyhow::Result<Scalar> {
+        derive_key(master_xkey, network, 0)
+    }
+
+    pub fn address(&self) -> SilentPaymentAddress {
+        let scan_secret = &self.scan_secret;
+        SilentPaymentAddress {
+            network: self.network,
+            scan: g!(scan_secret * G).mark::<Normal>(),
+            spend: self.spend,
+        }
+    }
+
+    /// The outputs of `tx` that pay us and the tweak to add to the spend key to spend each.
+    pub fn scan_transaction(&self, tx: &EsploraTx) -> Vec<FoundOutput> {
+        let taproot_outputs = tx
+            .vout
+            .iter()
+            .enumerate()
+            .filter_map(|(vout, output)| {
+                let txout = output.txout().ok()?;
+                let bytes = txout.script_pubkey.as_bytes();
+                if bytes.len() == 34 && bytes[0] == 0x51 && bytes[1] == 0x20 {
+                    let mut xonly = [0u8; 32];
+                    xonly.copy_from_slice(&bytes[2..]);
+                    Some((vout as u32, xonly, txout))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        // BIP-352 leaves transactions spending segwit versions after taproot for later versions
+        if taproot_outputs.is_empty()
+            || tx
+                .vin
+                .iter()
+                .any(|input| input.is_coinbase || input.spends_future_segwit())
+        {
+            return vec![];
+        }
+
+        // the point at infinity is None
+        let mut input_public_key: Option<Point> = None;
+        for input in &tx.vin {
+            if let Some(public_key) = input.public_key() {
+                input_public_key = match input_public_key {
+                    Some(sum) => g!(sum + public_key).mark::<(Normal, NonZero)>(),
+                    None => Some(public_key),
+                };
+            }
+        }
+        let input_public_key = match input_public_key {
+            Some(input_public_key) => input_public_key,
+            None => return vec![],
+        };
+        let smallest_outpoint = smallest_outpoint(tx.vin.iter().map(|input| OutPoint {
+            txid: input.txid,
+            vout: input.vout,
+        }))
+        .expect("it's not a coinbase so it has inputs");
+        let input_hash = input_hash(smallest_outpoint, &input_public_key);
+        let scan_secret = &self.scan_secret;
+        let ecdh_secret = match s!(input_hash * scan_secret).mark::<NonZero>() {
+            Some(ecdh_secret) => ecdh_secret,
+            None => return vec![],
+        };
+        let shared_secret = g!(ecdh_secret * input_public_key).mark::<Normal>();
+
+        let mut found = vec![];
+        for k in 0.. {
+            let (tweak, output_key) = match shared_secret_output(&shared_secret, &self.spend, k) {
+                Ok(output) => output,
+                Err(_) => break,
+            };
+            match taproot_outputs
+                .iter()
+                .find(|(_, xonly, _)| xonly == output_key.to_xonly().as_bytes())
+            {
+                Some((vout, _, txout)) => found.push(FoundOutput {
+                    outpoint: OutPoint {
+                        txid: tx.txid,
+                        vout: *vout,
+                    },
+                    txout: txout.clone(),
+                    tweak,
+                    height: tx.status.block_height,
+                    spent_by: None,
+                }),
+                None => break,
+            }
+        }
+        found
+    }
+}
+
+/// The key at BIP-352's derivation path for `branch` (0 for spending and 1 for scanning).
+fn derive_key(
+    master_xkey: &ExtendedPrivKey,
+    network: Network,
+    branch: u32,
+) -> anyhow::Result<Scalar> {
+    let secp = Secp256k1::new();
+    let coin_type = match network {
+        Network::Bitcoin => 0,
+        _ => 1,
+    };
+    let path = DerivationPath::from(vec![
+        ChildNumber::from_hardened_idx(352)?,
+        ChildNumber::from_hardened_idx(coin_type)?,
+        ChildNumber::from_hardened_idx(0)?,
+        ChildNumber::from_hardened_idx(branch)?,
+        ChildNumber::from_normal_idx(0)?,
+    ]);
+    Ok(Scalar::from(
+        master_xkey.derive_priv(&secp, &path)?.private_key,
+    ))
+}
+
+/// A silent payment to us. Its secret key is the spend secret key plus `tweak`.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FoundOutput {
+    pub outpoint: OutPoint,
+    pub txout: TxOut,
+    pub tweak: Scalar<Secret, Zero>,
+    pub height: Option<u32>,
+    /// The transaction we spent it in
+    #[serde(default)]
+    pub spent_by: Option<Txid>,
+}
+
+/// A transaction spending all of `found` to `to` signed with `spend_secret` (see
+/// [`ScanKeys::spend_secret`]). The fee comes out of the output.
+pub fn spend_found_outputs(
+    found: &[FoundOutput],
+    spend_secret: &Scalar,
+    to: Script,
+    fee: &FeeSpec,
+    estimator: &impl EstimateFee,
+) -> anyhow::Result<Psbt> {
+    if found.is_empty() {
+        return Err(anyhow!("There are no silent payments to spend"));
+    }
+    let mut tx = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: found
+            .iter()
+            .map(|output| TxIn {
+                previous_output: output.outpoint,
+                script_sig: Script::new(),
+                sequence: 0xFFFF_FFFD,
+                witness: Default::default(),
+            })
+            .collect(),
+        output: vec![TxOut {
+            value: 0,
+            script_pubkey: to,
+        }],
+    };
+    // the segwit marker and flag plus a signature for each input
+    let weight = tx.weight()
+        + 2
+        + found
+            .iter()
+            .map(|output| satisfaction_weight(&output.txout.script_pubkey).unwrap_or(1 + 1 + 65))
+            .sum::<usize>();
+    let fee_paid = match &fee.target {
+        FeeTarget::Absolute(fee) => fee.as_sat(),
+        _ => {
+            let feerate = fee
+                .feerate(estimator)?
+                .expect("only absolute fees don't have a feerate");
+            (feerate.as_sat_vb() * ((weight + 3) / 4) as f32).ceil() as u64
+        }
+    };
+    fee.check_fee(Amount::from_sat(fee_paid))?;
+    let total = found.iter().map(|output| output.txout.value).sum::<u64>();
+    tx.output[0].value = total
+        .checked_sub(fee_paid)
+        .filter(|value| *value >= crate::change::DEFAULT_DUST_THRESHOLD)
+        .ok_or(anyhow!(
+            "The silent payments ({} sats) aren't worth enough to pay the fee of {} sats",
+            total,
+            fee_paid
+        ))?;
+
+    let secp = Secp256k1::new();
+    let prevouts = found
+        .iter()
+        .map(|output| output.txout.clone())
+        .collect::<Vec<_>>();
+    let mut witnesses = vec![];
+    {
+        let mut cache = SigHashCache::new(&tx);
+        for (i, output) in found.iter().enumerate() {
+            let tweak = &output.tweak;
+            let secret_key = s!(spend_secret + tweak)
+                .mark::<NonZero>()
+                .ok_or(anyhow!("the key for {} is zero", output.outpoint))?;
+            // libsecp256k1 negates the key if its public key has an odd y
+            let keypair = secp256k1::KeyPair::from_seckey_slice(&secp, &secret_key.to_bytes())?;
+            let sighash = cache.taproot_key_spend_signature_hash(
+                i,
+                &Prevouts::All(&prevouts),
+                SchnorrSigHashType::Default,
+            )?;
+            let signature = secp.sign_schnorr_with_aux_rand(
+                &Message::from_slice(&sighash[..])?,
+                &keypair,
+                &rand::random(),
+            );
+            witnesses.push(vec![signature[..].to_vec()]);
+        }
+    }
+
+    let mut psbt = Psbt::from_unsigned_tx(tx)?;
+    for ((input, output), witness) in psbt.inputs.iter_mut().zip(found).zip(witnesses) {
+        input.witness_utxo = Some(output.txout.clone());
+        input.final_script_witness = Some(witness);
+    }
+    Ok(psbt)
+}
+
+/// A transaction the way esplora's `/block/:hash/txs` gives it.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct EsploraTx {
+    pub txid: Txid,
+    pub vin: Vec<EsploraInput>,
+    pub vout: Vec<EsploraOutput>,
+    #[serde(default)]
+    pub status: EsploraStatus,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct EsploraStatus {
+    #[serde(default)]
+    pub block_height: Option<u32>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct EsploraInput {
+    pub txid: Txid,
+    pub vout: u32,
+    #[serde(default)]
+    pub prevout: Option<EsploraOutput>,
+    #[serde(default)]
+    pub scriptsig: String,
+    #[serde(default)]
+    pub witness: Vec<String>,
+    #[serde(default)]
+    pub is_coinbase: bool,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct EsploraOutput {
+    pub scriptpubkey: String,
+    pub value: u64,
+}
+
+impl EsploraOutput {
+    fn txout(&self) -> anyhow::Result<TxOut> {
+        Ok(TxOut {
+            script_pubkey: Script::from(Vec::<u8>::from_hex(&self.scriptpubkey)?),
+            value: self.value,
+        })
+    }
+}
+
+impl EsploraInput {
+    /// Whether the output it spends is segwit v2 or later.
+    fn spends_future_segwit(&self) -> bool {
+        let spent = match self
+            .prevout
+            .as_ref()
+            .and_then(|prevout| prevout.txout().ok())
+        {
+            Some(txout) => txout.script_pubkey,
+            None => return false,
+        };
+        // OP_2 to OP_16
+        spent.is_witness_program() && spent.as_bytes()[0] >= 0x52
+    }
+
+    /// The public key the input counts towards silent payments with. `None` if it doesn't.
+    fn public_key(&self) -> Option<Point> {
+        let spent = self.prevout.as_ref()?.txout().ok()?.script_pubkey;
+        let script_sig = Script::from(Vec::<u8>::from_hex(&self.scriptsig).ok()?);
+        let mut witness = self
+            .witness
+            .iter()
+            .map(|item| Vec::<u8>::from_hex(item).ok())
+            .collect::<Option<Vec<_>>>()?;
+        let compressed = |bytes: &[u8]| {
+            if bytes.len() != 33 {
+                return None;
+            }
+            let mut point = [0u8; 33];
+            point.copy_from_slice(bytes);
+            Point::from_bytes(point)
+        };
+
+        let bytes = spent.as_bytes();
+        if bytes.len() == 34 && bytes[0] == 0x51 && bytes[1] == 0x20 {
+            // drop the annex
+            if witness.len() > 1 && witness.last()?.first() == Some(&0x50) {
+                witness.pop();
+            }
+            if witness.len() > 1 {
+                let control_block = witness.last()?;
+                if control_block.get(1..33)? == NUMS_H {
+                    return None;
+                }
+            }
+            let mut xonly = [0u8; 32];
+            xonly.copy_from_slice(&bytes[2..]);
+            return Some(Point::<EvenY>::from_xonly_bytes(xonly)?.mark::<Normal>());
+        }
+        if spent.is_v0_p2wpkh() {
+            return compressed(witness.get(1)?);
+        }
+        if spent.is_p2sh() {
+            let redeem_script = match script_sig.instructions().next()? {
+                Ok(Instruction::PushBytes(redeem_script)) => Script::from(redeem_script.to_vec()),
+                _ => return None,
+            };
+            if redeem_script.is_v0_p2wpkh() {
+                return compressed(witness.get(1)?);
+            }
+            return None;
+        }
+        if spent.is_p2pkh() {
+            let pubkey_hash = &bytes[3..23];
+            return script_sig
+                .instructions()
+                .filter_map(|instruction| match instruction {
+                    Ok(Instruction::PushBytes(push)) => Some(push),
+                    _ => None,
+                })
+                .filter(|push| hash160::Hash::hash(push).as_inner()[..] == *pubkey_hash)
+                .last()
+                .and_then(compressed);
+        }
+        None
+    }
+}
+
+impl GunWallet {
+    /// Scan blocks from `keys.next_height` up to `to_height` (inclusive) on the esplora server at
+    /// `base_url`. `on_block` is called after each block with what was found in it so progress can
+    /// be saved as it goes.
+    pub fn scan_silent_payments(
+        &self,
+        base_url: &str,
+        keys: &ScanKeys,
+        to_height: u32,
+        mut on_block: impl FnMut(u32, Vec<FoundOutput>) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let base = base_url.trim_end_matches('/');
+        let net = self.net();
+        let get = |path: String| -> anyhow::Result<String> {
+            net.block_on(net.get_text(&format!("{}/{}", base, path)))
+                .with_context(|| format!("fetching {} from esplora", path))
+        };
+        for height in keys.next_height..=to_height {
+            let block_hash = get(format!("block-height/{}", height))?;
+            let block_hash = block_hash.trim();
+            let block: serde_json::Value =
+                serde_json::from_str(&get(format!("block/{}", block_hash))?)?;
+            let tx_count = block["tx_count"].as_u64().ok_or(anyhow!(
+                "esplora didn't say how many transactions are in block {}",
+                height
+            ))? as usize;
+            let mut found = vec![];
+            for start in (0..tx_count).step_by(ESPLORA_PAGE) {
+                let txs: Vec<EsploraTx> =
+                    serde_json::from_str(&get(format!("block/{}/txs/{}", block_hash, start))?)?;
+                for tx in txs {
+                    found.extend(keys.scan_transaction(&tx));
+                }
+            }
+            for output in &found {
+                elog!(@celebration "Found a silent payment of {} sats in {}", output.txout.value, output.outpoint);
+            }
+            on_block(height, found)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::psbt_ext::PsbtFeeRate;
+    use bdk::bitcoin::hashes::hex::ToHex;
+
+    fn p2wpkh_input(secret_key: &Scalar, txid: Txid) -> EsploraInput {
+        let public_key = g!(secret_key * G).mark::<Normal>();
+        let pubkey_hash = hash160::Hash::hash(&public_key.to_bytes());
+        let mut spent = vec![0x00, 0x14];
+        spent.extend_from_slice(&pubkey_hash[..]);
+        EsploraInput {
+            txid,
+            vout: 0,
+            prevout: Some(EsploraOutput {
+                scriptpubkey: spent.to_hex(),
+                value: 100_000,
+            }),
+            scriptsig: String::new(),
+            witness: vec!["30".into(), public_key.to_bytes().to_hex()],
+            is_coinbase: false,
+        }
+    }
+
+    struct FixedFee;
+
+    impl EstimateFee for FixedFee {
+        fn estimate_feerate(&self, _in_blocks: u32) -> anyhow::Result<bdk::FeeRate> {
+            Ok(bdk::FeeRate::from_sat_per_vb(2.0))
+        }
+    }
+
+    /// A transaction paying the wallet with `master_xkey` a silent payment of 50_000 sats at vout 1
+    /// and the output key it's paid to.
+    fn payment(master_xkey: &ExtendedPrivKey) -> (EsploraTx, Point) {
+        let address = ScanKeys::derive(master_xkey, Network::Regtest, 0)
+            .unwrap()
+            .address();
+
+        let a1 = Scalar::from_bytes_mod_order([1u8; 32])
+            .mark::<NonZero>()
+            .unwrap();
+        let a2 = Scalar::from_bytes_mod_order([2u8; 32])
+            .mark::<NonZero>()
+            .unwrap();
+        let vin = vec![
+            p2wpkh_input(&a1, Txid::from_slice(&[3u8; 32]).unwrap()),
+            p2wpkh_input(&a2, Txid::from_slice(&[4u8; 32]).unwrap()),
+        ];
+        let smallest_outpoint = smallest_outpoint(vin.iter().map(|input| OutPoint {
+            txid: input.txid,
+            vout: input.vout,
+        }))
+        .unwrap();
+        let input_secret = s!(a1 + a2).mark::<NonZero>().unwrap();
+        let output_key = address
+            .output_key(&input_secret, smallest_outpoint, 0)
+            .unwrap();
+        let mut script = vec![0x51, 0x20];
+        script.extend_from_slice(output_key.to_xonly().as_bytes());
+        let tx = EsploraTx {
+            txid: Txid::from_slice(&[5u8; 32]).unwrap(),
+            vin,
+            vout: vec![
+                EsploraOutput {
+                    scriptpubkey: "0014".to_string() + &[6u8; 20].to_hex(),
+                    value: 1_000,
+                },
+                EsploraOutput {
+                    scriptpubkey: script.to_hex(),
+                    value: 50_000,
+                },
+            ],
+            status: EsploraStatus {
+                block_height: Some(7),
+            },
+        };
+        (tx, output_key)
+    }
+
+    #[test]
+    fn finds_payments_to_us() {
+        let master_xkey = ExtendedPrivKey::new_master(Network::Regtest, &[9u8; 64]).unwrap();
+        let keys = ScanKeys::derive(&master_xkey, Network::Regtest, 0).unwrap();
+        let (tx, output_key) = payment(&master_xkey);
+
+        let found = keys.scan_transaction(&tx);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].outpoint.vout, 1);
+        assert_eq!(found[0].txout.value, 50_000);
+        // the tweak takes the spend key to the output key
+        let spend = keys.spend;
+        let tweak = &found[0].tweak;
+        assert_eq!(
+            g!(tweak * G + spend).mark::<(Normal, NonZero)>(),
+            Some(output_key)
+        );
+
+        let other = ScanKeys::derive(&master_xkey, Network::Bitcoin, 0).unwrap();
+        assert!(other.scan_transaction(&tx).is_empty());
+    }
+
+    #[test]
+    fn skips_transactions_spending_future_segwit_versions() {
+        let master_xkey = ExtendedPrivKey::new_master(Network::Regtest, &[9u8; 64]).unwrap();
+        let keys = ScanKeys::derive(&master_xkey, Network::Regtest, 0).unwrap();
+        let (mut tx, _) = payment(&master_xkey);
+        tx.vin.push(EsploraInput {
+            txid: Txid::from_slice(&[8u8; 32]).unwrap(),
+            vout: 0,
+            prevout: Some(EsploraOutput {
+                scriptpubkey: "5220".to_string() + &[7u8; 32].to_hex(),
+                value: 10_000,
+            }),
+            scriptsig: String::new(),
+            witness: vec![],
+            is_coinbase: false,
+        });
+        assert!(keys.scan_transaction(&tx).is_empty());
+    }
+
+    #[test]
+    fn spends_found_outputs() {
+        let master_xkey = ExtendedPrivKey::new_master(Network::Regtest, &[9u8; 64]).unwrap();
+        let keys = ScanKeys::derive(&master_xkey, Network::Regtest, 0).unwrap();
+        let (tx, _) = payment(&master_xkey);
+        let found = keys.scan_transaction(&tx);
+        let spend_secret = ScanKeys::spend_secret(&master_xkey, Network::Regtest).unwrap();
+        let to = Script::new_v0_wpkh(&bdk::bitcoin::WPubkeyHash::from_inner([6u8; 20]));
+
+        let psbt = spend_found_outputs(
+            &found,
+            &spend_secret,
+            to.clone(),
+            &FeeSpec::default(),
+            &FixedFee,
+        )
+        .unwrap();
+        let (fee, _, _) = psbt.fee();
+        assert!(fee.as_sat() > 0 && fee.as_sat() < 1_000);
+        let spend_tx = psbt.extract_tx();
+        assert_eq!(spend_tx.output[0].script_pubkey, to);
+        assert_eq!(spend_tx.output[0].value + fee.as_sat(), 50_000);
+        crate::betting::verify_signatures(&spend_tx, &[found[0].txout.clone()])
+            .expect("the signature is valid");
+
+        let too_much = FeeSpec::from(FeeTarget::Absolute(Amount::from_sat(50_000)));
+        assert!(spend_found_outputs(&found, &spend_secret, to, &too_much, &FixedFee).is_err());
+    }
+}
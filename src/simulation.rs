@@ -0,0 +1,292 @@
+//! A whole bet played out between two wallets on a private regtest chain with a fake oracle
+//! (`gun bet simulate`).
+//!
+//! It's for seeing how propose, offer, take, attest and claim fit together without risking
+//! anything. The end to end tests are built from the same parts. It needs the `simulation` feature
+//! which starts `bitcoind` and `electrs` like BDK's tests do (set `BITCOIND_EXE` and `ELECTRS_EXE`
+//! to use your own binaries).
+use crate::{
+    betting::*,
+    config::Config,
+    database::{CachedEvent, GunDatabase},
+    elog,
+    esplora::LazyEsplora,
+    keychain::Keychain,
+    mock_oracle::MockOracle,
+    wallet::GunWallet,
+    FeeSpec, ValueChoice,
+};
+use anyhow::{anyhow, Context};
+use bdk::{
+    bitcoin::{util::bip32::ExtendedPrivKey, Amount, Network},
+    blockchain::{esplora::EsploraBlockchainConfig, AnyBlockchainConfig, Broadcast},
+    testutils::blockchain_tests::TestClient,
+    wallet::AddressIndex,
+    Wallet,
+};
+use olivia_core::{Attestation, EventId, OracleEvent, OracleInfo, Outcome};
+use olivia_secp256k1::Secp256k1;
+use rand::Rng;
+use std::{str::FromStr, time::Duration};
+
+/// A wallet on the simulation's chain funded with a coinbase output.
+pub fn create_party(test_client: &mut TestClient, id: u8) -> anyhow::Result<(GunWallet, Keychain)> {
+    let mut r = [0u8; 64];
+    rand::thread_rng().fill(&mut r);
+    let keychain = Keychain::new(r);
+    let xprv = ExtendedPrivKey::new_master(Network::Regtest, &r)?;
+    let descriptor = format!("wpkh({}/84'/1'/0'/0/*)", xprv);
+    let db = bdk::sled::Config::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .open()?
+        .open_tree("simulation")?;
+    let esplora_url = format!(
+        "http://{}",
+        test_client
+            .electrsd
+            .esplora_url
+            .as_ref()
+            .ok_or(anyhow!("electrs was started without esplora"))?
+    );
+    let mut config = Config::default_config(Network::Regtest);
+    config.blockchain = AnyBlockchainConfig::Esplora(EsploraBlockchainConfig::new(esplora_url, 5));
+
+    let wallet = Wallet::new_offline(&descriptor, None, Network::Regtest, db)
+        .context("Initializing wallet failed")?;
+    let wallet = GunWallet::new(
+        wallet,
+        (descriptor, None),
+        LazyEsplora::new(config.clone()),
+        GunDatabase::test_new(),
+        config.http_client(),
+        0,
+    );
+    wallet.sync().context("syncing wallet failed")?;
+
+    let funding_address = wallet.bdk_wallet().get_address(AddressIndex::New)?.address;
+
+    test_client.generate(1, Some(funding_address));
+    test_client.generate(100, None);
+
+    while wallet.bdk_wallet().get_balance()? < 100_000 {
+        std::thread::sleep(Duration::from_millis(1_000));
+        wallet.sync()?;
+        log::debug!("syncing done on party {} -- checking balance", id);
+    }
+
+    Ok((wallet, keychain))
+}
+
+/// An oracle that has announced a single red or blue event and can attest to either outcome.
+pub struct FakeOracle {
+    pub id: String,
+    pub info: OracleInfo,
+    pub event: OracleEvent<Secp256k1>,
+    oracle: MockOracle,
+}
+
+impl FakeOracle {
+    pub fn new() -> Self {
+        let mut seed = [0u8; 32];
+        rand::thread_rng().fill(&mut seed);
+        let oracle = MockOracle::new(seed);
+        let id = "non-existent-oracle.com".to_string();
+        let event_id = EventId::from_str("/test/red_blue.winner").unwrap();
+        Self {
+            info: oracle.info(id.clone()),
+            event: oracle
+                .oracle_event(&event_id)
+                .expect("red_blue.winner has two outcomes"),
+            id,
+            oracle,
+        }
+    }
+
+    /// Tell the wallet about the oracle so it doesn't try to look it up.
+    pub fn register(&self, wallet: &GunWallet) -> anyhow::Result<()> {
+        wallet
+            .gun_db()
+            .insert_entity(self.id.clone(), self.info.clone())
+    }
+
+    /// Attest to `value` and give it to `wallet` as if it had fetched the attestation from the
+    /// oracle.
+    pub fn publish(
+        &self,
+        wallet: &GunWallet,
+        value: u64,
+    ) -> anyhow::Result<Attestation<Secp256k1>> {
+        let attestation = self.attest(value);
+        let response = EventResponse {
+            announcement: self.oracle.announcement(&self.event.event.id)?,
+            attestation: Some(attestation.clone()),
+        };
+        let gun_db = wallet.gun_db();
+        gun_db.insert_entity(
+            gun_db.oracle_event_url(&self.id, &self.event.event.id),
+            CachedEvent {
+                json: serde_json::to_string(&response)?,
+                fetched_at: crate::unix_now(),
+            },
+        )?;
+        Ok(attestation)
+    }
+
+    /// The attestation to the outcome with index `value` (0 is red and 1 is blue).
+    pub fn attest(&self, value: u64) -> Attestation<Secp256k1> {
+        self.oracle.attest(&Outcome {
+            id: self.event.event.id.clone(),
+            value,
+        })
+    }
+}
+
+impl Default for FakeOracle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Poke the bet until it gets to `state` or give up after ten tries.
+pub fn wait_for_state(party: &GunWallet, bet_id: BetId, state: &str) -> anyhow::Result<()> {
+    for _ in 0..10 {
+        let bet_state = party
+            .gun_db()
+            .get_entity::<BetState>(bet_id)?
+            .ok_or(anyhow!("bet {} is gone", bet_id))?;
+        if bet_state.name() == state {
+            return Ok(());
+        }
+        party.take_next_action(bet_id, false)?;
+        std::thread::sleep(Duration::from_secs(1));
+    }
+    let bet_state = party.gun_db().get_entity::<BetState>(bet_id)?;
+    Err(anyhow!(
+        "bet {} failed to reach state {}. It ended up {:?}",
+        bet_id,
+        state,
+        bet_state.map(|bet_state| bet_state.name())
+    ))
+}
+
+/// How things ended up for one side of the simulated bet.
+pub struct SimulatedParty {
+    pub name: &'static str,
+    pub bet_id: BetId,
+    pub state: &'static str,
+    pub balance_before: Amount,
+    pub balance_after: Amount,
+}
+
+/// Play a bet out between two wallets from proposal to claim.
+pub fn run_against_self() -> anyhow::Result<Vec<SimulatedParty>> {
+    elog!(@info "Starting a regtest bitcoind and electrs");
+    let mut test_client = TestClient::default();
+    let (alice, alice_keychain) = create_party(&mut test_client, 1)?;
+    let (bob, bob_keychain) = create_party(&mut test_client, 2)?;
+    let oracle = FakeOracle::new();
+    oracle.register(&alice)?;
+    oracle.register(&bob)?;
+    let alice_before = Amount::from_sat(alice.bdk_wallet().get_balance()?);
+    let bob_before = Amount::from_sat(bob.bdk_wallet().get_balance()?);
+    elog!(@info "alice and bob have {} each. The oracle has announced {}", alice_before, oracle.event.event.id);
+
+    let local_proposal = alice.make_proposal(
+        oracle.id.clone(),
+        oracle.event.clone(),
+        BetArgs {
+            value: ValueChoice::Amount(Amount::from_str_with_denomination("0.01 BTC")?),
+            ..Default::default()
+        },
+        &alice_keychain,
+        None,
+    )?;
+    let proposal_string = local_proposal.proposal.clone().into_versioned().to_string();
+    let alice_bet_id = alice.insert_proposal(local_proposal)?;
+    elog!(@info "alice proposes risking 0.01 BTC:\n{}", proposal_string);
+
+    let proposal = VersionedProposal::from_str(&proposal_string)?;
+    let (bet, local_public_key, mut cipher) = bob.generate_offer_with_oracle_event(OfferArgs {
+        proposal: proposal.into(),
+        oracle_event: oracle.event.clone(),
+        oracle_info: oracle.info.clone(),
+        choose_right: true,
+        args: BetArgs {
+            value: ValueChoice::Amount(Amount::from_str_with_denomination("0.02 BTC")?),
+            ..Default::default()
+        },
+        fee_spec: FeeSpec::default(),
+        keychain: &bob_keychain,
+    })?;
+    let (bob_bet_id, encrypted_offer, _) =
+        bob.sign_save_and_encrypt_offer(bet, None, local_public_key, &mut cipher)?;
+    wait_for_state(&bob, bob_bet_id, "offered")?;
+    elog!(@info "bob offers 0.02 BTC that blue wins:\n{}", encrypted_offer.to_base2048_string());
+
+    let (plaintext, offer_public_key, rng) =
+        alice.decrypt_offer(alice_bet_id, encrypted_offer, &alice_keychain)?;
+    let mut validated_offer = alice.validate_offer(
+        alice_bet_id,
+        plaintext.into_offer(),
+        offer_public_key,
+        rng,
+        &alice_keychain,
+    )?;
+    alice.sign_validated_offer(&mut validated_offer)?;
+    Broadcast::broadcast(
+        alice.blockchain()?,
+        validated_offer.bet.psbt.clone().extract_tx(),
+    )?;
+    alice.set_offer_taken(validated_offer)?;
+    wait_for_state(&alice, alice_bet_id, "unconfirmed")?;
+    elog!(@info "alice takes the offer and broadcasts the bet transaction");
+    test_client.generate(1, None);
+    wait_for_state(&alice, alice_bet_id, "confirmed")?;
+    wait_for_state(&bob, bob_bet_id, "confirmed")?;
+    elog!(@info "The bet transaction is confirmed");
+
+    let index = rand::thread_rng().gen_range(0..2);
+    let attestation = oracle.attest(index);
+    elog!(@info "The oracle attests that {} won", attestation.outcome);
+    let (winner, winner_id, loser, loser_id) = match index {
+        0 => (&alice, alice_bet_id, &bob, bob_bet_id),
+        _ => (&bob, bob_bet_id, &alice, alice_bet_id),
+    };
+    winner.learn_outcome(winner_id, attestation.clone())?;
+    wait_for_state(winner, winner_id, "won")?;
+    loser.learn_outcome(loser_id, attestation)?;
+    wait_for_state(loser, loser_id, "lost")?;
+
+    let (_, claim_psbt) = winner
+        .claim(FeeSpec::default(), false)?
+        .ok_or(anyhow!("the winner has nothing to claim"))?;
+    winner.bdk_wallet().broadcast(&claim_psbt.extract_tx())?;
+    wait_for_state(winner, winner_id, "claiming")?;
+    elog!(@info "The winner broadcasts a transaction claiming the bet output");
+    test_client.generate(1, None);
+    wait_for_state(winner, winner_id, "claimed")?;
+    alice.sync()?;
+    bob.sync()?;
+    elog!(@celebration "The bet is over");
+
+    [
+        ("alice", &alice, alice_bet_id, alice_before),
+        ("bob", &bob, bob_bet_id, bob_before),
+    ]
+    .into_iter()
+    .map(|(name, party, bet_id, balance_before)| {
+        Ok(SimulatedParty {
+            name,
+            bet_id,
+            state: party
+                .gun_db()
+                .get_entity::<BetState>(bet_id)?
+                .ok_or(anyhow!("bet {} is gone", bet_id))?
+                .name(),
+            balance_before,
+            balance_after: Amount::from_sat(party.bdk_wallet().get_balance()?),
+        })
+    })
+    .collect()
+}
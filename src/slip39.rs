@@ -0,0 +1,635 @@
+//! SLIP-39 Shamir backups of the seed (`gun backup shamir` and `gun setup seed --from-slip39`).
+//!
+//! [SLIP-39] splits a secret into share mnemonics so that any `threshold` of them recover it but
+//! fewer reveal nothing about it. gun splits the BIP39 entropy (rather than the seed) into a single
+//! group of shares so restoring gives back the same seed words. The wallet's BIP39 passphrase (if
+//! any) is still needed on top and the SLIP-39 passphrase is left empty.
+//!
+//! Everything derived from the secret is kept in [`Zeroizing`] buffers so it's wiped when dropped.
+//!
+//! [SLIP-39]: https://github.com/satoshilabs/slips/blob/master/slip-0039.md
+use anyhow::anyhow;
+use bdk::bitcoin::hashes::{sha256, Hash, HashEngine, Hmac, HmacEngine};
+use once_cell::sync::Lazy;
+use rand::RngCore;
+use std::collections::BTreeMap;
+use zeroize::{Zeroize, Zeroizing};
+
+const WORDLIST: &str = include_str!("slip39_wordlist.txt");
+const RADIX_BITS: usize = 10;
+/// The identifier, extendable flag and iteration exponent take two words and the group and member
+/// parameters another two.
+const HEADER_WORDS: usize = 4;
+const CHECKSUM_WORDS: usize = 3;
+const BASE_ITERATION_COUNT: u32 = 10_000;
+const ROUND_COUNT: u8 = 4;
+const SECRET_INDEX: u8 = 255;
+const DIGEST_INDEX: u8 = 254;
+const DIGEST_LEN: usize = 4;
+
+pub const MIN_SECRET_LEN: usize = 16;
+/// BIP39 entropy is at most 32 bytes. It also keeps the round function to one PBKDF2 block.
+pub const MAX_SECRET_LEN: usize = 32;
+pub const MAX_SHARES: u8 = 16;
+/// New shares are encrypted with 10000 × 2^1 PBKDF2 iterations.
+pub const ITERATION_EXPONENT: u8 = 1;
+
+static WORDS: Lazy<Vec<&'static str>> = Lazy::new(|| WORDLIST.lines().collect());
+
+/// Words are unique in their first four letters so that's all you have to type.
+fn word_index(word: &str) -> Option<u16> {
+    match WORDS.binary_search(&word) {
+        Ok(index) => Some(index as u16),
+        Err(_) if word.len() >= 4 => WORDS
+            .iter()
+            .position(|candidate| candidate.starts_with(word))
+            .map(|index| index as u16),
+        Err(_) => None,
+    }
+}
+
+fn customization(extendable: bool) -> &'static [u8] {
+    if extendable {
+        b"shamir_extendable"
+    } else {
+        b"shamir"
+    }
+}
+
+/// The RS1024 checksum polynomial.
+fn polymod(values: impl Iterator<Item = u32>) -> u32 {
+    const GEN: [u32; 10] = [
+        0xE0E040, 0x1C1C080, 0x3838100, 0x7070200, 0xE0E0009, 0x1C0C2412, 0x38086C24, 0x3090FC48,
+        0x21B1F890, 0x3F3F120,
+    ];
+    let mut chk = 1u32;
+    for value in values {
+        let b = chk >> 20;
+        chk = ((chk & 0xFFFFF) << 10) ^ value;
+        for (i, gen) in GEN.iter().enumerate() {
+            if (b >> i) & 1 == 1 {
+                chk ^= gen;
+            }
+        }
+    }
+    chk
+}
+
+fn checksum_values<'a>(extendable: bool, words: &'a [u16]) -> impl Iterator<Item = u32> + 'a {
+    customization(extendable)
+        .iter()
+        .map(|byte| *byte as u32)
+        .chain(words.iter().map(|word| *word as u32))
+}
+
+fn create_checksum(extendable: bool, words: &[u16]) -> [u16; CHECKSUM_WORDS] {
+    let chk = polymod(checksum_values(extendable, words).chain([0, 0, 0])) ^ 1;
+    [
+        ((chk >> 20) & 0x3FF) as u16,
+        ((chk >> 10) & 0x3FF) as u16,
+        (chk & 0x3FF) as u16,
+    ]
+}
+
+/// Pack bytes into 10 bit words with zero bits padding the front.
+fn bytes_to_words(bytes: &[u8]) -> Zeroizing<Vec<u16>> {
+    let bits = bytes.len() * 8;
+    let padding = (RADIX_BITS - bits % RADIX_BITS) % RADIX_BITS;
+    let mut words = Zeroizing::new(Vec::with_capacity((bits + padding) / RADIX_BITS));
+    let mut acc = 0u32;
+    let mut acc_bits = padding;
+    for byte in bytes {
+        acc = (acc << 8) | *byte as u32;
+        acc_bits += 8;
+        while acc_bits >= RADIX_BITS {
+            acc_bits -= RADIX_BITS;
+            words.push(((acc >> acc_bits) & 0x3FF) as u16);
+        }
+        acc &= (1 << acc_bits) - 1;
+    }
+    acc.zeroize();
+    words
+}
+
+fn words_to_bytes(words: &[u16]) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+    let padding = words.len() * RADIX_BITS % 16;
+    if padding > 8 {
+        return Err(anyhow!("the share has the wrong number of words"));
+    }
+    let mut bytes = Zeroizing::new(Vec::with_capacity(words.len() * RADIX_BITS / 8));
+    let mut acc = 0u32;
+    let mut acc_bits = 0;
+    let mut to_skip = padding;
+    for word in words {
+        acc = (acc << RADIX_BITS) | *word as u32;
+        acc_bits += RADIX_BITS;
+        if to_skip > 0 {
+            acc_bits -= to_skip;
+            to_skip = 0;
+            if acc >> acc_bits != 0 {
+                return Err(anyhow!("the share's padding bits aren't zero"));
+            }
+        }
+        while acc_bits >= 8 {
+            acc_bits -= 8;
+            bytes.push((acc >> acc_bits) as u8);
+        }
+        acc &= (1 << acc_bits) - 1;
+    }
+    acc.zeroize();
+    Ok(bytes)
+}
+
+/// Log and exp tables for GF(256) with the Rijndael polynomial.
+struct Gf256 {
+    exp: [u8; 255],
+    log: [u8; 256],
+}
+
+static GF256: Lazy<Gf256> = Lazy::new(|| {
+    let mut exp = [0u8; 255];
+    let mut log = [0u8; 256];
+    let mut poly = 1u16;
+    for i in 0..255 {
+        exp[i] = poly as u8;
+        log[poly as usize] = i as u8;
+        // multiply by the generator x + 1
+        poly ^= poly << 1;
+        if poly & 0x100 != 0 {
+            poly ^= 0x11B;
+        }
+    }
+    Gf256 { exp, log }
+});
+
+/// Evaluate the polynomial going through `shares` at `x` (byte by byte).
+fn interpolate(shares: &[(u8, &[u8])], x: u8) -> Zeroizing<Vec<u8>> {
+    if let Some((_, value)) = shares.iter().find(|(index, _)| *index == x) {
+        return Zeroizing::new(value.to_vec());
+    }
+    let gf = &*GF256;
+    let log = |value: u8| gf.log[value as usize] as i64;
+    let log_prod: i64 = shares.iter().map(|(index, _)| log(index ^ x)).sum();
+    let mut result = Zeroizing::new(vec![0u8; shares[0].1.len()]);
+    for (index, value) in shares {
+        let log_basis = (log_prod
+            - log(index ^ x)
+            - shares
+                .iter()
+                .map(|(other, _)| log(index ^ other))
+                .sum::<i64>())
+        .rem_euclid(255);
+        for (result, byte) in result.iter_mut().zip(value.iter()) {
+            if *byte != 0 {
+                *result ^= gf.exp[((log(*byte) + log_basis) % 255) as usize];
+            }
+        }
+    }
+    result
+}
+
+fn digest(random_part: &[u8], secret: &[u8]) -> [u8; DIGEST_LEN] {
+    let mut engine = HmacEngine::<sha256::Hash>::new(random_part);
+    engine.input(secret);
+    let mut digest = [0u8; DIGEST_LEN];
+    digest.copy_from_slice(&Hmac::<sha256::Hash>::from_engine(engine).into_inner()[..DIGEST_LEN]);
+    digest
+}
+
+fn split_secret(
+    threshold: u8,
+    count: u8,
+    secret: &[u8],
+    rng: &mut impl RngCore,
+) -> Vec<(u8, Zeroizing<Vec<u8>>)> {
+    if threshold == 1 {
+        return (0..count)
+            .map(|index| (index, Zeroizing::new(secret.to_vec())))
+            .collect();
+    }
+    let random_count = threshold - 2;
+    let mut shares = (0..random_count)
+        .map(|index| {
+            let mut value = Zeroizing::new(vec![0u8; secret.len()]);
+            rng.fill_bytes(&mut value);
+            (index, value)
+        })
+        .collect::<Vec<_>>();
+    let mut digest_share = Zeroizing::new(vec![0u8; secret.len()]);
+    rng.fill_bytes(&mut digest_share[DIGEST_LEN..]);
+    let digest = digest(&digest_share[DIGEST_LEN..], secret);
+    digest_share[..DIGEST_LEN].copy_from_slice(&digest);
+
+    let derived = {
+        let mut base = shares
+            .iter()
+            .map(|(index, value)| (*index, &value[..]))
+            .collect::<Vec<_>>();
+        base.push((DIGEST_INDEX, &digest_share[..]));
+        base.push((SECRET_INDEX, secret));
+        (random_count..count)
+            .map(|index| (index, interpolate(&base, index)))
+            .collect::<Vec<_>>()
+    };
+    shares.extend(derived);
+    shares
+}
+
+fn recover_secret(threshold: u8, shares: &[(u8, &[u8])]) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+    if threshold == 1 {
+        return Ok(Zeroizing::new(shares[0].1.to_vec()));
+    }
+    let secret = interpolate(shares, SECRET_INDEX);
+    let digest_share = interpolate(shares, DIGEST_INDEX);
+    if digest(&digest_share[DIGEST_LEN..], &secret)[..] != digest_share[..DIGEST_LEN] {
+        return Err(anyhow!("the shares don't fit together"));
+    }
+    Ok(secret)
+}
+
+/// PBKDF2-HMAC-SHA256 of `round || passphrase` truncated to the length of `r` (which fits in one
+/// block).
+fn round_function(
+    round: u8,
+    passphrase: &[u8],
+    iteration_exponent: u8,
+    salt: &[u8],
+    r: &[u8],
+) -> Zeroizing<Vec<u8>> {
+    let mut password = Zeroizing::new(Vec::with_capacity(1 + passphrase.len()));
+    password.push(round);
+    password.extend_from_slice(passphrase);
+    let iterations = (BASE_ITERATION_COUNT << iteration_exponent) / ROUND_COUNT as u32;
+
+    let mut engine = HmacEngine::<sha256::Hash>::new(&password);
+    engine.input(salt);
+    engine.input(r);
+    engine.input(&1u32.to_be_bytes());
+    let mut u = Zeroizing::new(Hmac::<sha256::Hash>::from_engine(engine).into_inner());
+    let mut output = u.clone();
+    for _ in 1..iterations {
+        let mut engine = HmacEngine::<sha256::Hash>::new(&password);
+        engine.input(&u[..]);
+        *u = Hmac::<sha256::Hash>::from_engine(engine).into_inner();
+        for (o, u) in output.iter_mut().zip(u.iter()) {
+            *o ^= u;
+        }
+    }
+    Zeroizing::new(output[..r.len()].to_vec())
+}
+
+/// The four round Feistel network that encrypts (rounds 0 to 3) or decrypts (3 to 0) the master
+/// secret.
+fn feistel(
+    secret: &[u8],
+    passphrase: &[u8],
+    iteration_exponent: u8,
+    identifier: u16,
+    extendable: bool,
+    rounds: impl Iterator<Item = u8>,
+) -> Zeroizing<Vec<u8>> {
+    let half = secret.len() / 2;
+    let mut l = Zeroizing::new(secret[..half].to_vec());
+    let mut r = Zeroizing::new(secret[half..].to_vec());
+    let mut salt = vec![];
+    if !extendable {
+        salt.extend_from_slice(customization(false));
+        salt.extend_from_slice(&identifier.to_be_bytes());
+    }
+    for round in rounds {
+        let f = round_function(round, passphrase, iteration_exponent, &salt, &r);
+        let new_r = Zeroizing::new(l.iter().zip(f.iter()).map(|(l, f)| l ^ f).collect());
+        l = std::mem::replace(&mut r, new_r);
+    }
+    let mut output = Zeroizing::new(Vec::with_capacity(secret.len()));
+    output.extend_from_slice(&r);
+    output.extend_from_slice(&l);
+    output
+}
+
+/// One share mnemonic decoded.
+#[derive(Clone)]
+pub struct Share {
+    pub identifier: u16,
+    pub extendable: bool,
+    pub iteration_exponent: u8,
+    pub group_index: u8,
+    pub group_threshold: u8,
+    pub group_count: u8,
+    pub member_index: u8,
+    pub member_threshold: u8,
+    value: Zeroizing<Vec<u8>>,
+}
+
+impl core::fmt::Debug for Share {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Share")
+            .field("identifier", &self.identifier)
+            .field("group_index", &self.group_index)
+            .field("member_index", &self.member_index)
+            .field("member_threshold", &self.member_threshold)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Share {
+    pub fn to_mnemonic(&self) -> Zeroizing<String> {
+        let mut words = Zeroizing::new(Vec::<u16>::new());
+        words.push(self.identifier >> 5);
+        words.push(
+            ((self.identifier & 0x1F) << 5)
+                | ((self.extendable as u16) << 4)
+                | self.iteration_exponent as u16,
+        );
+        let params = ((self.group_index as u32) << 16)
+            | ((self.group_threshold as u32 - 1) << 12)
+            | ((self.group_count as u32 - 1) << 8)
+            | ((self.member_index as u32) << 4)
+            | (self.member_threshold as u32 - 1);
+        words.push((params >> 10) as u16);
+        words.push((params & 0x3FF) as u16);
+        words.extend_from_slice(&bytes_to_words(&self.value));
+        let checksum = create_checksum(self.extendable, &words);
+        words.extend_from_slice(&checksum);
+        Zeroizing::new(
+            words
+                .iter()
+                .map(|word| WORDS[*word as usize])
+                .collect::<Vec<_>>()
+                .join(" "),
+        )
+    }
+
+    pub fn from_mnemonic(mnemonic: &str) -> anyhow::Result<Self> {
+        let words = Zeroizing::new(
+            mnemonic
+                .split_whitespace()
+                .enumerate()
+                .map(|(i, word)| {
+                    word_index(&word.to_lowercase())
+                        .ok_or_else(|| anyhow!("word #{} isn't a SLIP-39 word", i + 1))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        );
+        let min_value_words = (MIN_SECRET_LEN * 8 + RADIX_BITS - 1) / RADIX_BITS;
+        if words.len() < HEADER_WORDS + min_value_words + CHECKSUM_WORDS {
+            return Err(anyhow!(
+                "a share has at least {} words but this has {}",
+                HEADER_WORDS + min_value_words + CHECKSUM_WORDS,
+                words.len()
+            ));
+        }
+        let extendable = (words[1] >> 4) & 1 == 1;
+        if polymod(checksum_values(extendable, &words)) != 1 {
+            return Err(anyhow!(
+                "the share's checksum is wrong (check it for mistyped words)"
+            ));
+        }
+        let params = ((words[2] as u32) << 10) | words[3] as u32;
+        let share = Share {
+            identifier: (words[0] << 5) | (words[1] >> 5),
+            extendable,
+            iteration_exponent: (words[1] & 0xF) as u8,
+            group_index: ((params >> 16) & 0xF) as u8,
+            group_threshold: ((params >> 12) & 0xF) as u8 + 1,
+            group_count: ((params >> 8) & 0xF) as u8 + 1,
+            member_index: ((params >> 4) & 0xF) as u8,
+            member_threshold: (params & 0xF) as u8 + 1,
+            value: words_to_bytes(&words[HEADER_WORDS..words.len() - CHECKSUM_WORDS])?,
+        };
+        if share.group_threshold > share.group_count {
+            return Err(anyhow!(
+                "the share's group threshold is more than its group count"
+            ));
+        }
+        if share.value.len() % 2 != 0 || share.value.len() > MAX_SECRET_LEN {
+            return Err(anyhow!("the share is for a secret gun can't restore"));
+        }
+        Ok(share)
+    }
+}
+
+/// Split `master_secret` into `count` share mnemonics any `threshold` of which recover it.
+pub fn split_mnemonics(
+    master_secret: &[u8],
+    threshold: u8,
+    count: u8,
+    passphrase: &[u8],
+    rng: &mut impl RngCore,
+) -> anyhow::Result<Vec<Zeroizing<String>>> {
+    if master_secret.len() < MIN_SECRET_LEN
+        || master_secret.len() > MAX_SECRET_LEN
+        || master_secret.len() % 2 != 0
+    {
+        return Err(anyhow!(
+            "SLIP-39 can't split a {} byte secret",
+            master_secret.len()
+        ));
+    }
+    if count == 0 || count > MAX_SHARES {
+        return Err(anyhow!("there can be 1 to {} shares", MAX_SHARES));
+    }
+    if threshold == 0 || threshold > count {
+        return Err(anyhow!(
+            "the threshold must be from 1 to the number of shares ({})",
+            count
+        ));
+    }
+    if threshold == 1 && count > 1 {
+        return Err(anyhow!(
+            "with a threshold of 1 every share would be the same so make copies of a single share instead"
+        ));
+    }
+
+    let identifier = (rng.next_u32() & 0x7FFF) as u16;
+    let encrypted = feistel(
+        master_secret,
+        passphrase,
+        ITERATION_EXPONENT,
+        identifier,
+        false,
+        0..ROUND_COUNT,
+    );
+    // one group which is all that's needed
+    Ok(split_secret(threshold, count, &encrypted, rng)
+        .into_iter()
+        .map(|(member_index, value)| {
+            Share {
+                identifier,
+                extendable: false,
+                iteration_exponent: ITERATION_EXPONENT,
+                group_index: 0,
+                group_threshold: 1,
+                group_count: 1,
+                member_index,
+                member_threshold: threshold,
+                value,
+            }
+            .to_mnemonic()
+        })
+        .collect())
+}
+
+/// Recover the master secret from `shares`. Returns `None` if more shares are needed.
+pub fn combine_shares(
+    shares: &[Share],
+    passphrase: &[u8],
+) -> anyhow::Result<Option<Zeroizing<Vec<u8>>>> {
+    let first = match shares.first() {
+        Some(first) => first,
+        None => return Ok(None),
+    };
+    let backup_params = |share: &Share| {
+        (
+            share.identifier,
+            share.extendable,
+            share.iteration_exponent,
+            share.group_threshold,
+            share.group_count,
+            share.value.len(),
+        )
+    };
+    if shares
+        .iter()
+        .any(|share| backup_params(share) != backup_params(first))
+    {
+        return Err(anyhow!("the shares aren't all from the same backup"));
+    }
+
+    let mut groups = BTreeMap::<u8, Vec<(u8, &[u8])>>::new();
+    for share in shares {
+        if shares.iter().any(|other| {
+            other.group_index == share.group_index
+                && other.member_threshold != share.member_threshold
+        }) {
+            return Err(anyhow!("the shares aren't all from the same backup"));
+        }
+        let members = groups.entry(share.group_index).or_default();
+        match members
+            .iter()
+            .find(|(index, _)| *index == share.member_index)
+        {
+            Some((_, value)) if *value != &share.value[..] => {
+                return Err(anyhow!(
+                    "two different shares have the same index {}",
+                    share.member_index + 1
+                ))
+            }
+            Some(_) => {}
+            None => members.push((share.member_index, &share.value[..])),
+        }
+    }
+
+    let mut group_secrets = vec![];
+    for (group_index, members) in &groups {
+        let threshold = shares
+            .iter()
+            .find(|share| share.group_index == *group_index)
+            .expect("the group came from a share")
+            .member_threshold as usize;
+        if members.len() >= threshold {
+            group_secrets.push((
+                *group_index,
+                recover_secret(threshold as u8, &members[..threshold])?,
+            ));
+        }
+    }
+    if group_secrets.len() < first.group_threshold as usize {
+        return Ok(None);
+    }
+    let group_shares = group_secrets
+        .iter()
+        .take(first.group_threshold as usize)
+        .map(|(index, value)| (*index, &value[..]))
+        .collect::<Vec<_>>();
+    let encrypted = recover_secret(first.group_threshold, &group_shares)?;
+    Ok(Some(feistel(
+        &encrypted,
+        passphrase,
+        first.iteration_exponent,
+        first.identifier,
+        first.extendable,
+        (0..ROUND_COUNT).rev(),
+    )))
+}
+
+/// Recover the master secret from share mnemonics.
+pub fn combine_mnemonics<S: AsRef<str>>(
+    mnemonics: &[S],
+    passphrase: &[u8],
+) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+    let shares = mnemonics
+        .iter()
+        .map(|mnemonic| Share::from_mnemonic(mnemonic.as_ref()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    combine_shares(&shares, passphrase)?.ok_or(anyhow!("more shares are needed"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hex;
+
+    #[test]
+    fn wordlist() {
+        assert_eq!(WORDS.len(), 1024);
+        assert!(WORDS.windows(2).all(|pair| pair[0] < pair[1]));
+        let mut prefixes = WORDS.iter().map(|word| &word[..4]).collect::<Vec<_>>();
+        prefixes.dedup();
+        assert_eq!(prefixes.len(), 1024);
+        assert_eq!(word_index("acad"), Some(0));
+        assert_eq!(word_index("zero"), Some(1023));
+    }
+
+    // from the SLIP-39 test vectors
+    #[test]
+    fn test_vectors() {
+        let secret = combine_mnemonics(
+            &["duckling enlarge academic academic agency result length solution fridge kidney coal piece deal husband erode duke ajar critical decision keyboard"],
+            b"TREZOR",
+        )
+        .unwrap();
+        assert_eq!(hex::encode(&secret), "bb54aac4b89dc868ba37d9cc21b2cece");
+
+        let secret = combine_mnemonics(
+            &[
+                "shadow pistol academic always adequate wildlife fancy gross oasis cylinder mustang wrist rescue view short owner flip making coding armed",
+                "shadow pistol academic acid actress prayer class unknown daughter sweater depict flip twice unkind craft early superior advocate guest smoking",
+            ],
+            b"TREZOR",
+        )
+        .unwrap();
+        assert_eq!(hex::encode(&secret), "b43ceb7e57a0ea8766221624d01b0864");
+
+        assert!(combine_mnemonics(
+            &["duckling enlarge academic academic agency result length solution fridge kidney coal piece deal husband erode duke ajar critical decision kidney"],
+            b"TREZOR",
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn split_and_combine() {
+        for len in [16, 32] {
+            let mut secret = vec![0u8; len];
+            rand::thread_rng().fill_bytes(&mut secret);
+            let mnemonics = split_mnemonics(&secret, 3, 5, b"", &mut rand::thread_rng()).unwrap();
+            assert_eq!(mnemonics.len(), 5);
+            let shares = mnemonics
+                .iter()
+                .map(|mnemonic| Share::from_mnemonic(mnemonic).unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(shares[2].to_mnemonic(), mnemonics[2]);
+            for (a, b, c) in [(0, 1, 2), (4, 2, 0), (1, 3, 4)] {
+                let subset = [shares[a].clone(), shares[b].clone(), shares[c].clone()];
+                assert!(combine_shares(&subset[..2], b"").unwrap().is_none());
+                assert_eq!(
+                    &combine_shares(&subset, b"").unwrap().unwrap()[..],
+                    &secret[..]
+                );
+            }
+        }
+        assert!(split_mnemonics(&[1u8; 16], 1, 3, b"", &mut rand::thread_rng()).is_err());
+        assert!(split_mnemonics(&[1u8; 16], 4, 3, b"", &mut rand::thread_rng()).is_err());
+    }
+}
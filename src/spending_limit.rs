@@ -0,0 +1,170 @@
+//! Limits on how much `gun send` and friends can send out of the wallet in a rolling day or week.
+//!
+//! Every transaction they make is recorded in the gun database with when it was sent and how much
+//! left the wallet (including the fee) so the limit holds across runs. Records older than a week
+//! are dropped since they can't count towards either limit.
+use crate::wallet::GunWallet;
+use bdk::bitcoin::{Amount, Txid};
+
+pub const DAY_SECS: u64 = 24 * 60 * 60;
+pub const WEEK_SECS: u64 = 7 * DAY_SECS;
+
+/// e.g. `"spending-limit": { "daily": 1000000, "weekly": 5000000 }` (in sats).
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SpendingLimit {
+    /// The most that can be sent in any 24 hours
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily: Option<u64>,
+    /// The most that can be sent in any 7 days
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weekly: Option<u64>,
+}
+
+/// A transaction we sent that counts towards the [`SpendingLimit`].
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct OutgoingSpend {
+    /// When it was sent (unix time)
+    pub time: u64,
+    /// What left the wallet including the fee (sats)
+    pub value: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LimitBreach {
+    pub window: &'static str,
+    /// What was already sent in the window
+    pub spent: Amount,
+    pub limit: Amount,
+}
+
+impl core::fmt::Display for LimitBreach {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{} has been sent in the last {} and this would take it over the limit of {}",
+            self.spent, self.window, self.limit
+        )
+    }
+}
+
+fn spent_since(spends: &[OutgoingSpend], since: u64) -> Amount {
+    Amount::from_sat(
+        spends
+            .iter()
+            .filter(|spend| spend.time > since)
+            .map(|spend| spend.value)
+            .sum(),
+    )
+}
+
+impl SpendingLimit {
+    /// The limits sending `value` at `now` would go over given what has already been sent.
+    pub fn breaches(&self, spends: &[OutgoingSpend], value: Amount, now: u64) -> Vec<LimitBreach> {
+        [
+            ("day", self.daily, DAY_SECS),
+            ("week", self.weekly, WEEK_SECS),
+        ]
+        .iter()
+        .filter_map(|(window, limit, secs)| {
+            let limit = Amount::from_sat((*limit)?);
+            let spent = spent_since(spends, now.saturating_sub(*secs));
+            if spent + value > limit {
+                Some(LimitBreach {
+                    window: *window,
+                    spent,
+                    limit,
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+    }
+}
+
+impl GunWallet {
+    /// What's been sent in the last week.
+    pub fn recent_spends(&self) -> anyhow::Result<Vec<OutgoingSpend>> {
+        let week_ago = crate::unix_now().saturating_sub(WEEK_SECS);
+        Ok(self
+            .gun_db()
+            .list_entities::<OutgoingSpend>()
+            .map(|entry| entry.map(|(_, spend)| spend))
+            .collect::<anyhow::Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|spend| spend.time > week_ago)
+            .collect())
+    }
+
+    /// Remember that `txid` sent `value` out of the wallet and forget spends too old to count.
+    pub fn record_spend(&self, txid: Txid, value: Amount) -> anyhow::Result<()> {
+        let now = crate::unix_now();
+        let old = self
+            .gun_db()
+            .list_entities::<OutgoingSpend>()
+            .filter_map(|entry| entry.ok())
+            .filter(|(_, spend)| spend.time <= now.saturating_sub(WEEK_SECS))
+            .map(|(txid, _)| txid)
+            .collect::<Vec<_>>();
+        for txid in old {
+            self.gun_db().remove_entity::<OutgoingSpend>(txid)?;
+        }
+        self.gun_db().insert_entity(
+            txid,
+            OutgoingSpend {
+                time: now,
+                value: value.as_sat(),
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rolling_windows() {
+        let now = 1_700_000_000;
+        let spends = vec![
+            OutgoingSpend {
+                time: now - 60,
+                value: 40_000,
+            },
+            OutgoingSpend {
+                time: now - 2 * DAY_SECS,
+                value: 100_000,
+            },
+            OutgoingSpend {
+                time: now - 8 * DAY_SECS,
+                value: 1_000_000,
+            },
+        ];
+        let limit = SpendingLimit {
+            daily: Some(50_000),
+            weekly: Some(150_000),
+        };
+        assert!(limit
+            .breaches(&spends, Amount::from_sat(10_000), now)
+            .is_empty());
+        assert_eq!(
+            limit.breaches(&spends, Amount::from_sat(10_001), now),
+            vec![
+                LimitBreach {
+                    window: "day",
+                    spent: Amount::from_sat(40_000),
+                    limit: Amount::from_sat(50_000),
+                },
+                LimitBreach {
+                    window: "week",
+                    spent: Amount::from_sat(140_000),
+                    limit: Amount::from_sat(150_000),
+                }
+            ]
+        );
+        assert!(SpendingLimit::default()
+            .breaches(&spends, Amount::from_sat(u32::MAX as u64), now)
+            .is_empty());
+    }
+}
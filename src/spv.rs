@@ -0,0 +1,267 @@
+//! SPV proofs that a transaction is in a block.
+//!
+//! A [`TxProof`] has everything needed to check a transaction was confirmed without asking a
+//! block explorer: the transaction, the header of the block it's in and the merkle branch
+//! connecting them. To be convinced the block is actually in the chain you check the header
+//! against a chain of headers you got somewhere you trust.
+use crate::hex;
+use anyhow::{anyhow, Context};
+use bdk::bitcoin::{
+    consensus::encode,
+    hashes::{sha256d, Hash, HashEngine},
+    BlockHash, BlockHeader, Transaction, Txid,
+};
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TxProof {
+    pub txid: Txid,
+    pub block_height: u32,
+    /// The block header in hex
+    pub block_header: String,
+    /// The merkle branch from the transaction to the root (as esplora returns it)
+    pub merkle: Vec<Txid>,
+    /// The position of the transaction in the block
+    pub pos: usize,
+    /// The transaction in hex
+    pub tx: String,
+}
+
+#[derive(serde::Deserialize)]
+struct EsploraMerkleProof {
+    block_height: u32,
+    merkle: Vec<Txid>,
+    pos: usize,
+}
+
+fn decode_hex<T: encode::Decodable>(hex_str: &str) -> anyhow::Result<T> {
+    let bytes = hex::decode(hex_str.trim()).map_err(|e| anyhow!("invalid hex: {}", e))?;
+    Ok(encode::deserialize(&bytes)?)
+}
+
+/// Compute the merkle root from the transaction's branch.
+pub fn merkle_root(txid: Txid, merkle: &[Txid], pos: usize) -> sha256d::Hash {
+    let mut current = txid.as_hash();
+    for (depth, sibling) in merkle.iter().enumerate() {
+        let mut engine = sha256d::Hash::engine();
+        if (pos >> depth) & 1 == 1 {
+            engine.input(&sibling[..]);
+            engine.input(&current[..]);
+        } else {
+            engine.input(&current[..]);
+            engine.input(&sibling[..]);
+        }
+        current = sha256d::Hash::from_engine(engine);
+    }
+    current
+}
+
+impl TxProof {
+    /// Fetch the proof from an esplora server.
+    pub fn fetch(client: &ureq::Agent, esplora_url: &str, txid: Txid) -> anyhow::Result<Self> {
+        let base = esplora_url.trim_end_matches('/');
+        let get = |path: String| -> anyhow::Result<ureq::Response> {
+            client
+                .get(&format!("{}/{}", base, path))
+                .call()
+                .with_context(|| format!("fetching {} from esplora", path))
+        };
+        let proof: EsploraMerkleProof = get(format!("tx/{}/merkle-proof", txid))
+            .map_err(|e| anyhow!("{:#} -- is the transaction confirmed?", e))?
+            .into_json()?;
+        let block_hash = get(format!("block-height/{}", proof.block_height))?.into_string()?;
+        let block_header = get(format!("block/{}/header", block_hash.trim()))?.into_string()?;
+        let tx = get(format!("tx/{}/hex", txid))?.into_string()?;
+
+        let tx_proof = TxProof {
+            txid,
+            block_height: proof.block_height,
+            block_header: block_header.trim().to_string(),
+            merkle: proof.merkle,
+            pos: proof.pos,
+            tx: tx.trim().to_string(),
+        };
+        // don't hand out a proof that doesn't work
+        tx_proof.verify()?;
+        Ok(tx_proof)
+    }
+
+    /// Check the proof is internally consistent: the transaction hashes to the txid, the merkle
+    /// branch leads to the header's merkle root and the header has valid proof of work for the
+    /// target it claims.
+    pub fn verify(&self) -> anyhow::Result<(Transaction, BlockHeader)> {
+        let tx: Transaction = decode_hex(&self.tx).context("decoding transaction")?;
+        if tx.txid() != self.txid {
+            return Err(anyhow!(
+                "the transaction in the proof doesn't match the txid"
+            ));
+        }
+        // A 64 byte transaction can pass for an inner node of the merkle tree
+        let mut stripped = tx.clone();
+        for input in &mut stripped.input {
+            input.witness.clear();
+        }
+        if encode::serialize(&stripped).len() == 64 {
+            return Err(anyhow!(
+                "64 byte transactions can't be proven with a merkle branch"
+            ));
+        }
+        // otherwise high bits of the position that aren't used by the branch could be anything
+        if self.pos.checked_shr(self.merkle.len() as u32).unwrap_or(0) != 0 {
+            return Err(anyhow!(
+                "position {} is too big for a merkle branch of length {}",
+                self.pos,
+                self.merkle.len()
+            ));
+        }
+        let header: BlockHeader = decode_hex(&self.block_header).context("decoding header")?;
+        if merkle_root(self.txid, &self.merkle, self.pos) != header.merkle_root.as_hash() {
+            return Err(anyhow!(
+                "merkle branch doesn't lead to the block's merkle root"
+            ));
+        }
+        header
+            .validate_pow(&header.target())
+            .map_err(|e| anyhow!("block header has invalid proof of work: {}", e))?;
+        Ok((tx, header))
+    }
+
+    /// Check the proof and that its block is in a chain of headers starting at `first_height`.
+    /// Returns the number of confirmations the transaction has in that chain.
+    pub fn verify_in_chain(&self, headers: &[String], first_height: u32) -> anyhow::Result<u32> {
+        let (_, header) = self.verify()?;
+        let block_hash = header.block_hash();
+        let chain = verify_header_chain(headers)?;
+        let position = chain
+            .iter()
+            .position(|hash| *hash == block_hash)
+            .ok_or(anyhow!("block {} is not in the header chain", block_hash))?;
+        let height = first_height + position as u32;
+        if height != self.block_height {
+            return Err(anyhow!(
+                "the proof says the block is at height {} but it's at {} in the header chain",
+                self.block_height,
+                height
+            ));
+        }
+        Ok((chain.len() - position) as u32)
+    }
+}
+
+/// Check a list of hex block headers links together with valid proof of work and return their
+/// hashes in order.
+pub fn verify_header_chain(headers: &[String]) -> anyhow::Result<Vec<BlockHash>> {
+    let mut hashes: Vec<BlockHash> = vec![];
+    for (i, header) in headers.iter().enumerate() {
+        let header: BlockHeader =
+            decode_hex(header).with_context(|| format!("decoding header #{}", i + 1))?;
+        if let Some(prev) = hashes.last() {
+            if header.prev_blockhash != *prev {
+                return Err(anyhow!(
+                    "header #{} doesn't follow on from the one before it",
+                    i + 1
+                ));
+            }
+        }
+        let hash = header
+            .validate_pow(&header.target())
+            .map_err(|e| anyhow!("header #{} has invalid proof of work: {}", i + 1, e))?;
+        hashes.push(hash);
+    }
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bdk::bitcoin::{
+        blockdata::constants::genesis_block, Network, OutPoint, Script, TxIn, TxOut,
+    };
+    use std::str::FromStr;
+
+    fn genesis_proof() -> TxProof {
+        let block = genesis_block(Network::Bitcoin);
+        TxProof {
+            txid: block.txdata[0].txid(),
+            block_height: 0,
+            block_header: hex::encode(&encode::serialize(&block.header)),
+            merkle: vec![],
+            pos: 0,
+            tx: hex::encode(&encode::serialize(&block.txdata[0])),
+        }
+    }
+
+    #[test]
+    fn proof_in_chain() {
+        let proof = genesis_proof();
+        let headers = vec![proof.block_header.clone()];
+        assert_eq!(proof.verify_in_chain(&headers, 0).unwrap(), 1);
+        assert!(proof
+            .verify_in_chain(&headers, 1)
+            .unwrap_err()
+            .to_string()
+            .contains("height"));
+    }
+
+    #[test]
+    fn position_must_fit_the_branch() {
+        let proof = TxProof {
+            pos: 1,
+            ..genesis_proof()
+        };
+        assert!(proof.verify().unwrap_err().to_string().contains("too big"));
+    }
+
+    #[test]
+    fn reject_64_byte_transactions() {
+        let tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: Default::default(),
+            }],
+            output: vec![TxOut {
+                value: 1_000,
+                script_pubkey: Script::from(vec![0x51; 4]),
+            }],
+        };
+        assert_eq!(encode::serialize(&tx).len(), 64);
+        let proof = TxProof {
+            txid: tx.txid(),
+            tx: hex::encode(&encode::serialize(&tx)),
+            ..genesis_proof()
+        };
+        assert!(proof.verify().unwrap_err().to_string().contains("64 byte"));
+    }
+
+    #[test]
+    fn merkle_branch() {
+        let txids = [
+            Txid::from_str("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b")
+                .unwrap(),
+            Txid::from_str("0e3e2357e806b6cdb1f70b54c3a3a17b6714ee1f0e68bebb44a74b1efd512098")
+                .unwrap(),
+            Txid::from_str("9b0fc92260312ce44e74ef369f5c66bbb85848f2eddd5a7a1cde251e54ccfdd5")
+                .unwrap(),
+        ];
+        let hash_pair = |a: &[u8], b: &[u8]| {
+            let mut engine = sha256d::Hash::engine();
+            engine.input(a);
+            engine.input(b);
+            sha256d::Hash::from_engine(engine)
+        };
+        // an odd number of leaves means the last one is paired with itself
+        let left = hash_pair(&txids[0][..], &txids[1][..]);
+        let right = hash_pair(&txids[2][..], &txids[2][..]);
+        let root = hash_pair(&left[..], &right[..]);
+
+        let right_txid = Txid::from_hash(right);
+        let left_txid = Txid::from_hash(left);
+        assert_eq!(merkle_root(txids[0], &[txids[1], right_txid], 0), root);
+        assert_eq!(merkle_root(txids[1], &[txids[0], right_txid], 1), root);
+        assert_eq!(merkle_root(txids[2], &[txids[2], left_txid], 2), root);
+        assert_ne!(merkle_root(txids[1], &[txids[0], right_txid], 0), root);
+    }
+}
@@ -0,0 +1,120 @@
+//! The wallet's coins written down so that a machine which never goes online can choose inputs
+//! from them (e.g. to make a bet proposal).
+use crate::{database::CachedEvent, wallet::GunWallet};
+use anyhow::{anyhow, Context};
+use bdk::{
+    bitcoin::Network,
+    blockchain::Blockchain,
+    database::{BatchOperations, Database, MemoryDatabase},
+    KeychainKind, LocalUtxo,
+};
+use std::collections::BTreeMap;
+
+/// A snapshot older than this is probably missing coins or has some that are already spent.
+pub const STALE_AFTER_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct UtxoSnapshot {
+    pub network: Network,
+    /// The height of the chain when the snapshot was taken
+    pub height: u32,
+    /// unix timestamp of when the snapshot was taken
+    pub created_at: u64,
+    /// The last change address index that has been used. Without it the offline wallet would
+    /// reuse change addresses.
+    pub last_internal_index: Option<u32>,
+    pub utxos: Vec<LocalUtxo>,
+    /// Oracle responses (by event url) for events the offline machine is going to bet on
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub events: BTreeMap<String, String>,
+}
+
+impl UtxoSnapshot {
+    /// Take a snapshot of a (freshly synced) wallet including the oracle's announcements for
+    /// `event_urls`.
+    pub fn take(wallet: &GunWallet, event_urls: &[String]) -> anyhow::Result<Self> {
+        let bdk_wallet = wallet.bdk_wallet();
+        let in_use = wallet.gun_db().currently_used_utxos(&[])?;
+        let mut events = BTreeMap::new();
+        for url in event_urls {
+            wallet.get_oracle_event(url, false, true)?;
+            let cached = wallet
+                .gun_db()
+                .get_entity::<CachedEvent>(url.clone())?
+                .expect("we just fetched it");
+            events.insert(url.clone(), cached.json);
+        }
+        Ok(UtxoSnapshot {
+            network: bdk_wallet.network(),
+            height: wallet.blockchain()?.get_height()?,
+            created_at: crate::unix_now(),
+            last_internal_index: bdk_wallet
+                .database()
+                .get_last_index(KeychainKind::Internal)?,
+            utxos: bdk_wallet
+                .list_unspent()?
+                .into_iter()
+                .filter(|utxo| !in_use.contains(&utxo.outpoint))
+                .collect(),
+            events,
+        })
+    }
+
+    pub fn from_file(path: &std::path::Path) -> anyhow::Result<Self> {
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("reading utxo snapshot {}", path.display()))?;
+        serde_json::from_str(&json)
+            .with_context(|| format!("{} isn't a utxo snapshot", path.display()))
+    }
+
+    /// Checks the snapshot is from this wallet.
+    pub fn check(&self, wallet: &GunWallet) -> anyhow::Result<()> {
+        let bdk_wallet = wallet.bdk_wallet();
+        if self.network != bdk_wallet.network() {
+            return Err(anyhow!(
+                "the snapshot is for {} but this wallet is for {}",
+                self.network,
+                bdk_wallet.network()
+            ));
+        }
+        for utxo in &self.utxos {
+            if !bdk_wallet.is_mine(&utxo.txout.script_pubkey)? {
+                return Err(anyhow!(
+                    "the snapshot has coin {} which doesn't belong to this wallet",
+                    utxo.outpoint
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Put the snapshot's oracle responses in the cache so they can be used without going online.
+    pub fn cache_events(&self, wallet: &GunWallet) -> anyhow::Result<()> {
+        for (url, json) in &self.events {
+            wallet.gun_db().insert_entity(
+                url.clone(),
+                CachedEvent {
+                    json: json.clone(),
+                    fetched_at: self.created_at,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn is_stale(&self) -> bool {
+        crate::unix_now().saturating_sub(self.created_at) > STALE_AFTER_SECS
+    }
+
+    /// A database with just the coins in the snapshot for a wallet to select from.
+    pub fn memory_db(&self) -> anyhow::Result<MemoryDatabase> {
+        let mut db = MemoryDatabase::default();
+        for utxo in &self.utxos {
+            db.set_utxo(utxo)?;
+        }
+        if let Some(index) = self.last_internal_index {
+            db.set_last_index(KeychainKind::Internal, index)?;
+        }
+        Ok(db)
+    }
+}
@@ -1,19 +1,28 @@
 use crate::{
-    betting::*, database::GunDatabase, elog, signers::PSBT_SIGNER_ID, FeeSpec, OracleInfo,
+    betting::*,
+    cmd::{ErrorKind, WithErrorKind},
+    database::{CachedEvent, GunDatabase},
+    elog,
+    esplora::LazyEsplora,
+    hooks::Hooks,
+    locktime::LocktimePolicy,
+    net::Net,
+    signers::{MasterKeySource, PSBT_SIGNER_ID},
+    EstimateFee, FeeSpec, OracleInfo, RecommendedFees,
 };
 use anyhow::{anyhow, Context};
 use bdk::{
     bitcoin::{
         util::psbt::{self, PartiallySignedTransaction as Psbt},
-        OutPoint,
+        Amount, OutPoint,
     },
-    blockchain::{noop_progress, Blockchain, EsploraBlockchain},
+    blockchain::{noop_progress, Blockchain, EsploraBlockchain, Progress},
     database::Database,
     descriptor::policy::Satisfaction,
     signer::SignerId,
     sled,
     wallet::AddressIndex,
-    KeychainKind, SignOptions,
+    FeeRate, KeychainKind, SignOptions,
 };
 use miniscript::DescriptorTrait;
 use olivia_core::{Attestation, Outcome};
@@ -22,27 +31,226 @@ use olivia_secp256k1::{
     Secp256k1,
 };
 
-type BdkWallet = bdk::Wallet<EsploraBlockchain, sled::Tree>;
+fn is_transient(error: &ureq::Error) -> bool {
+    match error {
+        ureq::Error::Transport(_) => true,
+        ureq::Error::Status(code, _) => *code == 429 || *code >= 500,
+    }
+}
+
+pub(crate) fn retry_delay(attempt: u32) -> std::time::Duration {
+    std::time::Duration::from_secs(1 << attempt.min(5))
+}
+
+/// How long to trust that an event hasn't been attested yet.
+const UNATTESTED_EVENT_CACHE_SECS: u64 = 10 * 60;
+
+pub(crate) fn cached_oracle_event(
+    gun_db: &GunDatabase,
+    url: &str,
+    need_attestation: bool,
+) -> anyhow::Result<Option<EventResponse>> {
+    let cached = match gun_db.get_entity::<CachedEvent>(url.to_string())? {
+        Some(cached) => cached,
+        None => return Ok(None),
+    };
+    let event_response = serde_json::from_str::<EventResponse>(&cached.json)
+        .with_context(|| format!("decoding cached response for {}", url))?;
+    let age = crate::unix_now().saturating_sub(cached.fetched_at);
+    let fresh = event_response.attestation.is_some()
+        || !need_attestation
+        || age < UNATTESTED_EVENT_CACHE_SECS;
+    Ok(if fresh { Some(event_response) } else { None })
+}
+
+pub(crate) async fn fetch_oracle_event(
+    net: &Net,
+    gun_db: &GunDatabase,
+    url: &str,
+) -> anyhow::Result<EventResponse> {
+    let json = net
+        .get_text(url)
+        .await
+        .with_context(|| format!("while getting {}", url))?;
+    let event_response = serde_json::from_str::<EventResponse>(&json)
+        .with_context(|| {
+            format!(
+                "while decoding the response from {}. Are you sure this is a valid event url?",
+                url
+            )
+        })
+        .error_kind(ErrorKind::Oracle)?;
+    gun_db.insert_entity(
+        url.to_string(),
+        CachedEvent {
+            json,
+            fetched_at: crate::unix_now(),
+        },
+    )?;
+    Ok(event_response)
+}
+
+pub(crate) fn http_get(
+    client: &ureq::Agent,
+    retries: u32,
+    url: &str,
+) -> Result<ureq::Response, ureq::Error> {
+    let mut attempt = 0;
+    loop {
+        match client.get(url).call() {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                attempt += 1;
+                elog!(@recoverable_error "GET {} failed ({}), retrying ({}/{})", url, e, attempt, retries);
+                std::thread::sleep(retry_delay(attempt));
+            }
+            result => return result,
+        }
+    }
+}
+
+impl EstimateFee for GunWallet {
+    fn estimate_feerate(&self, in_blocks: u32) -> anyhow::Result<FeeRate> {
+        if let Some(url) = &self.fee_estimator {
+            let recommended = http_get(
+                &self.client,
+                self.retries,
+                &format!("{}/v1/fees/recommended", url.trim_end_matches('/')),
+            )
+            .map_err(anyhow::Error::from)
+            .and_then(|response| Ok(response.into_json::<RecommendedFees>()?))
+            .and_then(|recommended| recommended.for_blocks(in_blocks));
+            match recommended {
+                Ok(feerate) => return Ok(feerate),
+                Err(e) => {
+                    elog!(@recoverable_error "Couldn't get a fee estimate from {} ({}) -- using esplora's", url, e)
+                }
+            }
+        }
+        Ok(self.blockchain()?.estimate_fee(in_blocks as usize)?)
+    }
+}
+
+/// The wallet without a blockchain so it can be used offline. See [`GunWallet::blockchain`].
+type BdkWallet = bdk::Wallet<(), sled::Tree>;
 
 pub struct GunWallet {
     wallet: BdkWallet,
+    /// The external and internal descriptors (to make an online wallet to sync with)
+    descriptors: (String, Option<String>),
+    blockchain: LazyEsplora,
     client: ureq::Agent,
+    net: Net,
     db: GunDatabase,
+    retries: u32,
+    fee_estimator: Option<String>,
+    locktime_policy: LocktimePolicy,
+    /// Change worth this much or less goes to the fee (see `dust-threshold`)
+    dust_threshold: u64,
+    /// Set when transactions should be built the same way every time (see `deterministic-tx-seed`)
+    tx_seed: Option<u64>,
+    hooks: Hooks,
+    master_key: Option<MasterKeySource>,
 }
 
 impl GunWallet {
-    pub fn new(wallet: BdkWallet, db: GunDatabase) -> Self {
+    pub fn new(
+        wallet: BdkWallet,
+        descriptors: (String, Option<String>),
+        blockchain: LazyEsplora,
+        db: GunDatabase,
+        client: ureq::Agent,
+        retries: u32,
+    ) -> Self {
         Self {
             wallet,
+            descriptors,
+            blockchain,
             db,
-            client: ureq::Agent::new(),
+            client,
+            net: Net::new(
+                std::time::Duration::from_secs(crate::config::DEFAULT_TIMEOUT_SECS),
+                retries,
+            ),
+            retries,
+            fee_estimator: None,
+            locktime_policy: LocktimePolicy::default(),
+            dust_threshold: crate::change::DEFAULT_DUST_THRESHOLD,
+            tx_seed: None,
+            hooks: Hooks::default(),
+            master_key: None,
         }
     }
 
+    /// Get `in-blocks:<n>` feerates from a mempool.space style API at `url` rather than from
+    /// esplora.
+    pub fn with_fee_estimator(mut self, url: Option<String>) -> Self {
+        self.fee_estimator = url;
+        self
+    }
+
+    /// Use `net` for async requests (e.g. to set its timeout).
+    pub fn with_net(mut self, net: Net) -> Self {
+        self.net = net;
+        self
+    }
+
+    pub fn with_locktime_policy(mut self, policy: LocktimePolicy) -> Self {
+        self.locktime_policy = policy;
+        self
+    }
+
+    pub fn locktime_policy(&self) -> LocktimePolicy {
+        self.locktime_policy
+    }
+
+    pub fn with_dust_threshold(mut self, dust_threshold: u64) -> Self {
+        self.dust_threshold = dust_threshold;
+        self
+    }
+
+    /// Whether change of `value` is too small to be worth its own output.
+    pub fn is_dust_change(&self, value: Amount) -> bool {
+        value.as_sat() <= self.dust_threshold
+    }
+
+    /// Build transactions deterministically with `seed` standing in for randomness.
+    pub fn with_tx_seed(mut self, seed: Option<u64>) -> Self {
+        self.tx_seed = seed;
+        self
+    }
+
+    pub fn tx_seed(&self) -> Option<u64> {
+        self.tx_seed
+    }
+
+    pub fn with_hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    pub fn hooks(&self) -> &Hooks {
+        &self.hooks
+    }
+
+    /// Let the wallet get at its master key when signing isn't enough (see [`MasterKeySource`]).
+    pub fn with_master_key(mut self, master_key: Option<MasterKeySource>) -> Self {
+        self.master_key = master_key;
+        self
+    }
+
+    pub fn master_key(&self) -> Option<&MasterKeySource> {
+        self.master_key.as_ref()
+    }
+
     pub fn bdk_wallet(&self) -> &BdkWallet {
         &self.wallet
     }
 
+    /// The esplora client. Nothing talks to the server until this is first called.
+    pub fn blockchain(&self) -> anyhow::Result<&EsploraBlockchain> {
+        self.blockchain.get()
+    }
+
     pub fn gun_db(&self) -> &GunDatabase {
         &self.db
     }
@@ -51,6 +259,34 @@ impl GunWallet {
         &self.client
     }
 
+    pub fn net(&self) -> &Net {
+        &self.net
+    }
+
+    /// GET `url` retrying if the server couldn't be reached or had an internal error.
+    pub fn http_get(&self, url: &str) -> Result<ureq::Response, ureq::Error> {
+        http_get(&self.client, self.retries, url)
+    }
+
+    /// Get the oracle's response for an event, from the cache if it's still good.
+    ///
+    /// Set `need_attestation` if you're waiting for the outcome (otherwise the announcement is
+    /// all that matters and it never changes). `refresh` always asks the oracle.
+    pub fn get_oracle_event(
+        &self,
+        url: &str,
+        need_attestation: bool,
+        refresh: bool,
+    ) -> anyhow::Result<EventResponse> {
+        if !refresh {
+            if let Some(event_response) = cached_oracle_event(&self.db, url, need_attestation)? {
+                return Ok(event_response);
+            }
+        }
+        self.net
+            .block_on(fetch_oracle_event(&self.net, &self.db, url))
+    }
+
     pub fn learn_outcome(
         &self,
         bet_id: BetId,
@@ -77,7 +313,8 @@ impl GunWallet {
                                 &bet.oracle_event,
                                 &oracle_info.oracle_keys,
                             )
-                            .context("Oracle gave invalid attestation")?;
+                            .context("Oracle gave invalid attestation")
+                            .error_kind(ErrorKind::Oracle)?;
                     }
 
                     let joint_output = &bet.joint_output;
@@ -187,7 +424,8 @@ impl GunWallet {
             .manually_selected_only()
             .enable_rbf()
             .only_witness_utxo();
-        feespec.apply_to_builder(self.wallet.client(), &mut builder)?;
+        feespec.apply_to_builder(self, &mut builder)?;
+        let tx_locktime = self.apply_locktime_policy(&mut builder)?;
 
         for utxo in utxos_that_need_canceling {
             // we have to add these as foreign UTXOs because BDK doesn't let you spend
@@ -222,6 +460,8 @@ impl GunWallet {
             Ok(res) => res,
             e => e?,
         };
+        feespec.check_psbt(&psbt)?;
+        self.record_locktime(&psbt, tx_locktime)?;
         let finalized = self.wallet.sign(
             &mut psbt,
             SignOptions {
@@ -233,12 +473,11 @@ impl GunWallet {
         Ok(Some(psbt))
     }
 
-    // the reason we require p2wpkh inputs here is so the witness is non-malleable.
-    // What to do about TR outputs in the future I haven't decided.
-    pub fn p2wpkh_outpoint_to_psbt_input(&self, outpoint: OutPoint) -> anyhow::Result<psbt::Input> {
+    // the reason we require p2wpkh or p2tr (key spend) inputs here is so the witness is
+    // non-malleable.
+    pub fn outpoint_to_psbt_input(&self, outpoint: OutPoint) -> anyhow::Result<psbt::Input> {
         let tx = self
-            .wallet
-            .client()
+            .blockchain()?
             .get_tx(&outpoint.txid)?
             .ok_or(anyhow!("txid not found {}", outpoint.txid))?;
 
@@ -252,8 +491,8 @@ impl GunWallet {
             ))?
             .clone();
 
-        if !txout.script_pubkey.is_v0_p2wpkh() {
-            return Err(anyhow!("outpoint {} was not p2wpkh", outpoint));
+        if crate::betting::satisfaction_weight(&txout.script_pubkey).is_none() {
+            return Err(anyhow!("outpoint {} was not p2wpkh or p2tr", outpoint));
         }
 
         let psbt_input = psbt::Input {
@@ -266,13 +505,72 @@ impl GunWallet {
 
     // convenience methods
     pub fn sync(&self) -> anyhow::Result<()> {
-        self.wallet.sync(noop_progress(), None)?;
-        Ok(())
+        self.sync_with_progress(noop_progress())
+    }
+
+    pub fn sync_with_progress<P: Progress + Clone>(&self, progress: P) -> anyhow::Result<()> {
+        // syncing needs a wallet with a blockchain. It shares the database with ours.
+        let (external, internal) = &self.descriptors;
+        let stop_gap = self.blockchain.stop_gap();
+        let sync_gap = self.internal_sync_gap(stop_gap)?;
+        let (blockchain, max_addresses) = if sync_gap > stop_gap {
+            elog!(@info "Looking through {} change addresses because proposals gave some out", sync_gap);
+            let first_unused = self
+                .last_used_index(KeychainKind::Internal)?
+                .map(|index| index + 1)
+                .unwrap_or(0);
+            // make sure there are enough addresses cached to look through
+            let max_addresses = (first_unused as usize + sync_gap).max(100) as u32;
+            (
+                self.blockchain.connect_with_stop_gap(sync_gap)?,
+                Some(max_addresses),
+            )
+        } else {
+            (self.blockchain.connect()?, None)
+        };
+        let online_wallet = bdk::Wallet::new(
+            external,
+            internal.as_ref(),
+            self.wallet.network(),
+            sled::Tree::clone(&self.wallet.database()),
+            blockchain,
+        )
+        .context("connecting wallet to esplora")?;
+        let mut attempt = 0;
+        loop {
+            match online_wallet.sync(progress.clone(), max_addresses) {
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+                    elog!(@recoverable_error "sync failed ({}), retrying ({}/{})", e, attempt, self.retries);
+                    std::thread::sleep(retry_delay(attempt));
+                }
+                result => return Ok(result?),
+            }
+        }
     }
 
     pub fn poke_bets(&self) {
-        for (bet_id, _) in self.gun_db().list_entities_print_error::<BetState>() {
-            match self.take_next_action(bet_id, true) {
+        // the prefetch hands the client to other threads so it needs its own
+        let blockchain = match self.blockchain.connect() {
+            Ok(blockchain) => std::sync::Arc::new(blockchain),
+            Err(e) => {
+                elog!(@recoverable_error "Can't update bets without esplora: {}", e);
+                return;
+            }
+        };
+        let bets = self
+            .gun_db()
+            .list_entities_print_error::<BetState>()
+            .collect::<Vec<_>>();
+        let prefetched = Prefetched::fetch(
+            &self.net,
+            blockchain,
+            &self.db,
+            bets.iter().map(|(_, bet_state)| bet_state),
+            true,
+        );
+        for (bet_id, _) in bets {
+            match self.take_next_action_prefetched(bet_id, true, &prefetched) {
                 Ok(_updated) => {}
                 Err(e) => {
                     elog!(@recoverable_error "Error trying to take action on bet {}: {:?}", bet_id, e);
@@ -282,10 +580,12 @@ impl GunWallet {
     }
 
     pub fn is_watch_only(&self) -> bool {
-        let (external, _) = self.bdk_wallet().signers();
+        let (external, internal) = self.bdk_wallet().signers();
 
         // PSBT signers are meant to sign everything so if we've got one of them we can sign anything.
-        if external.ids().contains(&&SignerId::Dummy(PSBT_SIGNER_ID)) {
+        if external.ids().contains(&&SignerId::Dummy(PSBT_SIGNER_ID))
+            || internal.ids().contains(&&SignerId::Dummy(PSBT_SIGNER_ID))
+        {
             return false;
         }
 
@@ -295,9 +595,27 @@ impl GunWallet {
             .expect("extracting policy should not have error")
             .expect("policy for external wallet exists");
 
+        // For multisig we only need to be able to contribute one of the signatures
         !matches!(
             policy.contribution,
             Satisfaction::Complete { .. } | Satisfaction::PartialComplete { .. }
+        ) && !matches!(&policy.contribution, Satisfaction::Partial { items, .. } if !items.is_empty())
+    }
+
+    /// Betting needs our inputs to have non-malleable p2wpkh witnesses.
+    pub fn is_p2wpkh(&self) -> bool {
+        matches!(
+            self.wallet
+                .get_descriptor_for_keychain(KeychainKind::External),
+            miniscript::Descriptor::Wpkh(_)
+        )
+    }
+
+    pub fn is_multisig(&self) -> bool {
+        matches!(
+            self.wallet
+                .get_descriptor_for_keychain(KeychainKind::External),
+            miniscript::Descriptor::Wsh(_) | miniscript::Descriptor::Sh(_)
         )
     }
 }
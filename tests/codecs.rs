@@ -0,0 +1,229 @@
+//! Property tests for the string and binary encodings of proposals and offers.
+//!
+//! These strings come straight from counterparties so as well as round tripping, decoding
+//! anything (especially something that's nearly right) has to return an error rather than panic.
+//! See also the fuzz targets in `fuzz/`.
+use bdk::bitcoin::{hashes::Hash, Amount, OutPoint, Script, Txid};
+use chacha20::{cipher::NewCipher, ChaCha20};
+use gun_wallet::{
+    betting::{compact, *},
+    change::Change,
+    encode,
+};
+use olivia_core::EventId;
+use olivia_secp256k1::{
+    ecdsa_fun,
+    fun::{marker::*, Point, Scalar, G},
+};
+use proptest::prelude::*;
+use std::str::FromStr;
+
+const EVENT_IDS: [&str; 2] = [
+    "/random/2020-09-25T08:00:00/heads_tails.winner",
+    "/EPL/match/2021-08-22/ARS_CHE.vs=CHE_win",
+];
+
+fn public_key() -> impl Strategy<Value = Point<EvenY>> {
+    any::<[u8; 32]>().prop_filter_map("zero scalar", |bytes| {
+        let mut scalar = Scalar::from_bytes_mod_order(bytes).mark::<NonZero>()?;
+        Some(Point::<EvenY>::from_scalar_mul(G, &mut scalar))
+    })
+}
+
+fn outpoint() -> impl Strategy<Value = OutPoint> {
+    (any::<[u8; 32]>(), any::<u32>())
+        .prop_map(|(txid, vout)| OutPoint::new(Txid::from_inner(txid), vout))
+}
+
+fn script() -> impl Strategy<Value = Script> {
+    prop_oneof![
+        any::<[u8; 20]>().prop_map(|hash| {
+            let mut bytes = vec![0x00, 0x14];
+            bytes.extend_from_slice(&hash);
+            Script::from(bytes)
+        }),
+        any::<[u8; 32]>().prop_map(|hash| {
+            let mut bytes = vec![0x51, 0x20];
+            bytes.extend_from_slice(&hash);
+            Script::from(bytes)
+        }),
+        proptest::collection::vec(any::<u8>(), 0..80).prop_map(Script::from),
+    ]
+}
+
+fn proposal() -> impl Strategy<Value = Proposal> {
+    (
+        "[a-z0-9]{1,12}\\.[a-z]{2,4}",
+        proptest::sample::select(&EVENT_IDS[..]),
+        1..=21_000_000_00_000_000u64,
+        proptest::collection::vec(outpoint(), 0..5),
+        public_key(),
+        proptest::option::of(script()),
+        proptest::option::of(0..LOCKTIME_THRESHOLD),
+    )
+        .prop_map(
+            |(oracle, event_id, value, inputs, public_key, change_script, refund_after)| Proposal {
+                oracle,
+                event_id: EventId::from_str(event_id).unwrap(),
+                value: Amount::from_sat(value),
+                inputs,
+                public_key,
+                change_script: change_script.map(Into::into),
+                refund_after,
+            },
+        )
+}
+
+fn offer() -> impl Strategy<Value = Offer> {
+    let input = (outpoint(), public_key()).prop_map(|(outpoint, key)| SignedInput {
+        outpoint,
+        witness: Witness::P2wpkh {
+            key: key.mark::<Normal>().into(),
+            signature: ecdsa_fun::Signature::from_bytes([43u8; 64]).unwrap(),
+        },
+    });
+    (
+        proptest::collection::vec(input, 1..4),
+        proptest::option::of((any::<u32>(), script())),
+        any::<bool>(),
+        1..=21_000_000_00_000_000u64,
+        prop_oneof![Just(0u32), any::<u32>()],
+    )
+        .prop_map(|(inputs, change, choose_right, value, locktime)| Offer {
+            inputs,
+            change: change.map(|(value, script)| Change::new(value as u64, script)),
+            choose_right,
+            value: Amount::from_sat(value),
+            locktime,
+        })
+}
+
+/// Something near to `string`: a character replaced, removed or the end cut off.
+fn mutate(string: &str, index: usize, replacement: char, kind: u8) -> String {
+    let mut chars = string.chars().collect::<Vec<_>>();
+    if chars.is_empty() {
+        return replacement.to_string();
+    }
+    let index = index % chars.len();
+    match kind % 3 {
+        0 => chars[index] = replacement,
+        1 => {
+            chars.remove(index);
+        }
+        _ => chars.truncate(index),
+    }
+    chars.into_iter().collect()
+}
+
+proptest! {
+    #[test]
+    fn proposal_roundtrip(proposal in proposal()) {
+        let encoded = proposal.clone().into_versioned().to_string();
+        let decoded: Proposal = VersionedProposal::from_str(&encoded).unwrap().into();
+        prop_assert_eq!(decoded, proposal);
+    }
+
+    #[test]
+    fn mutated_proposal_doesnt_panic(
+        proposal in proposal(),
+        index in any::<usize>(),
+        replacement in any::<char>(),
+        kind in any::<u8>(),
+    ) {
+        let encoded = proposal.into_versioned().to_string();
+        let _ = VersionedProposal::from_str(&mutate(&encoded, index, replacement, kind));
+    }
+
+    #[test]
+    fn compact_payload_roundtrip(proposal in proposal()) {
+        let payload = Payload {
+            public_key: proposal.public_key,
+            inputs: proposal.inputs,
+            change_script: proposal.change_script,
+        };
+        let encoded = compact::encode_payload(&payload);
+        prop_assert_eq!(compact::decode_payload(&encoded).unwrap(), payload);
+        for len in 0..encoded.len() {
+            prop_assert!(compact::decode_payload(&encoded[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn compact_offer_roundtrip(offer in offer(), message in proptest::option::of(".{0,200}")) {
+        let encoded = compact::encode_offer(&offer, message.as_deref());
+        prop_assert_eq!(compact::decode_offer(&encoded).unwrap(), (offer, message));
+    }
+
+    #[test]
+    fn mutated_compact_offer_doesnt_panic(
+        offer in offer(),
+        index in any::<usize>(),
+        byte in any::<u8>(),
+    ) {
+        let mut encoded = compact::encode_offer(&offer, None);
+        let index = index % encoded.len();
+        encoded[index] ^= byte;
+        let _ = compact::decode_offer(&encoded);
+        let _ = compact::decode_offer(&encoded[..index]);
+    }
+
+    #[test]
+    fn ciphertext_roundtrip(
+        offer in offer(),
+        public_key in public_key(),
+        key in any::<[u8; 32]>(),
+        pad_to in 0usize..600,
+    ) {
+        let mut cipher = ChaCha20::new(&key.into(), &[0u8; 12].into());
+        let ciphertext = Ciphertext::create(
+            public_key,
+            &mut cipher,
+            Plaintext::Offerv1 { offer: offer.clone(), message: None },
+        );
+        let (padded, _) = ciphertext.to_string_padded(pad_to, &mut cipher);
+        for string in [ciphertext.to_base2048_string(), padded] {
+            let decoded = Ciphertext::from_str(&string).unwrap();
+            prop_assert_eq!(&decoded, &ciphertext);
+            let mut cipher = ChaCha20::new(&key.into(), &[0u8; 12].into());
+            prop_assert_eq!(decoded.decrypt(&mut cipher).unwrap().into_offer(), offer.clone());
+        }
+    }
+
+    #[test]
+    fn mutated_ciphertext_doesnt_panic(
+        offer in offer(),
+        public_key in public_key(),
+        index in any::<usize>(),
+        replacement in any::<char>(),
+        kind in any::<u8>(),
+    ) {
+        let mut cipher = ChaCha20::new(&[1u8; 32].into(), &[0u8; 12].into());
+        let string = Ciphertext::create(
+            public_key,
+            &mut cipher,
+            Plaintext::Offerv1 { offer, message: None },
+        )
+        .to_base2048_string();
+        if let Ok(ciphertext) = Ciphertext::from_str(&mutate(&string, index, replacement, kind)) {
+            let mut cipher = ChaCha20::new(&[1u8; 32].into(), &[0u8; 12].into());
+            let _ = ciphertext.decrypt(&mut cipher);
+        }
+    }
+
+    #[test]
+    fn chunks_roundtrip(bytes in proptest::collection::vec(any::<u8>(), 0..500), max_len in 20usize..100) {
+        let string = encode::serialize_base2048(&bytes);
+        let chunks = encode::chunk(&string, max_len).unwrap();
+        prop_assert_eq!(encode::assemble_chunks(&chunks.join(" ")).unwrap().unwrap(), string);
+    }
+
+    #[test]
+    fn arbitrary_input_doesnt_panic(string in ".{0,300}", bytes in proptest::collection::vec(any::<u8>(), 0..300)) {
+        let _ = VersionedProposal::from_str(&string);
+        let _ = Ciphertext::from_str(&string);
+        let _ = encode::assemble_chunks(&string);
+        let _ = compact::decode_payload(&bytes);
+        let _ = compact::decode_offer(&bytes);
+        let _ = encode::deserialize::<Plaintext>(&bytes);
+    }
+}
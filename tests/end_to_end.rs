@@ -1,117 +1,36 @@
-use anyhow::Context;
 use bdk::{
-    bitcoin::{util::bip32::ExtendedPrivKey, Amount, Network},
-    blockchain::{noop_progress, Broadcast, EsploraBlockchain},
+    bitcoin::Amount,
+    blockchain::{Blockchain, Broadcast},
     testutils::blockchain_tests::TestClient,
     wallet::AddressIndex,
-    FeeRate, Wallet,
+    FeeRate,
 };
 use gun_wallet::{
-    betting::*, database::GunDatabase, keychain::Keychain, wallet::GunWallet, FeeSpec, ValueChoice,
+    betting::*,
+    keychain::Keychain,
+    locktime::TxLocktime,
+    simulation::{create_party, FakeOracle},
+    wallet::GunWallet,
+    FeeSpec, ValueChoice,
 };
-use olivia_core::{
-    announce, attest, AnnouncementSchemes, Attestation, AttestationSchemes, Event, EventId, Group,
-    OracleEvent, OracleInfo, OracleKeys,
-};
-use olivia_secp256k1::{fun::Scalar, Secp256k1};
-use rand::Rng;
-use std::{str::FromStr, time::Duration};
-
-fn create_party(test_client: &mut TestClient, id: u8) -> anyhow::Result<(GunWallet, Keychain)> {
-    let mut r = [0u8; 64];
-    rand::thread_rng().fill(&mut r);
-    let keychain = Keychain::new(r);
-    let xprv = ExtendedPrivKey::new_master(Network::Regtest, &r).unwrap();
-    let descriptor = bdk::template::Bip84(xprv, bdk::KeychainKind::External);
-    let db = bdk::sled::Config::new()
-        .temporary(true)
-        .flush_every_ms(None)
-        .open()
-        .unwrap()
-        .open_tree("test")
-        .unwrap();
-    let esplora_url = format!(
-        "http://{}",
-        test_client.electrsd.esplora_url.as_ref().unwrap()
-    );
-    let esplora = EsploraBlockchain::new(&esplora_url, 5);
-
-    let wallet = Wallet::new(descriptor, None, Network::Regtest, db, esplora)
-        .context("Initializing wallet failed")?;
-    wallet
-        .sync(noop_progress(), None)
-        .context("syncing wallet failed")?;
-
-    let gun_db = GunDatabase::test_new();
-
-    let funding_address = wallet.get_address(AddressIndex::New).unwrap().address;
-
-    test_client.generate(1, Some(funding_address));
-    test_client.generate(100, None);
-
-    while wallet.get_balance()? < 100_000 {
-        std::thread::sleep(Duration::from_millis(1_000));
-        wallet.sync(noop_progress(), None)?;
-        println!("syncing done on party {} -- checking balance", id);
-    }
-
-    let wallet = GunWallet::new(wallet, gun_db);
-    Ok((wallet, keychain))
-}
+use std::str::FromStr;
 
 macro_rules! setup_test {
     () => {{
         let mut test_client = TestClient::default();
         let (party_1, keychain_1) = create_party(&mut test_client, 1).unwrap();
         let (party_2, keychain_2) = create_party(&mut test_client, 2).unwrap();
-        let nonce_secret_key = Scalar::random(&mut rand::thread_rng());
-        let announce_keypair =
-            olivia_secp256k1::SCHNORR.new_keypair(Scalar::random(&mut rand::thread_rng()));
-        let attest_keypair =
-            olivia_secp256k1::SCHNORR.new_keypair(Scalar::random(&mut rand::thread_rng()));
-        let oracle_nonce_keypair = olivia_secp256k1::SCHNORR.new_keypair(nonce_secret_key);
-        let event_id = EventId::from_str("/test/red_blue.winner").unwrap();
-        let oracle_id = "non-existent-oracle.com".to_string();
-        let oracle_info = OracleInfo {
-            id: oracle_id.clone(),
-            oracle_keys: OracleKeys {
-                olivia_v1: Some(attest_keypair.public_key().clone().into()),
-                ecdsa_v1: None,
-                announcement: announce_keypair.public_key().clone().into(),
-                group: Secp256k1,
-            },
-        };
-
-        party_1
-            .gun_db()
-            .insert_entity(oracle_id.clone(), oracle_info.clone())
-            .unwrap();
-        party_2
-            .gun_db()
-            .insert_entity(oracle_id.clone(), oracle_info.clone())
-            .unwrap();
-
-        let oracle_event = OracleEvent {
-            event: Event {
-                id: event_id.clone(),
-                expected_outcome_time: None,
-            },
-            schemes: AnnouncementSchemes {
-                olivia_v1: Some(announce::OliviaV1 {
-                    nonces: vec![oracle_nonce_keypair.public_key().clone().into()],
-                }),
-                ..Default::default()
-            },
-        };
+        let oracle = FakeOracle::new();
+        oracle.register(&party_1).unwrap();
+        oracle.register(&party_2).unwrap();
         (
             test_client,
             (party_1, keychain_1),
             (party_2, keychain_2),
-            oracle_info,
-            attest_keypair,
-            oracle_nonce_keypair,
-            oracle_id,
-            oracle_event,
+            oracle.info.clone(),
+            oracle.id.clone(),
+            oracle.event.clone(),
+            oracle,
         )
     }};
 }
@@ -152,10 +71,9 @@ pub fn test_happy_path() {
         (party_1, keychain_1),
         (party_2, keychain_2),
         oracle_info,
-        attest_keypair,
-        oracle_nonce_keypair,
         oracle_id,
         oracle_event,
+        oracle,
     ) = setup_test!();
 
     let local_proposal = party_1
@@ -167,6 +85,7 @@ pub fn test_happy_path() {
                 ..Default::default()
             },
             &keychain_1,
+            None,
         )
         .unwrap();
 
@@ -215,7 +134,7 @@ pub fn test_happy_path() {
     party_1.sign_validated_offer(&mut validated_offer).unwrap();
 
     Broadcast::broadcast(
-        party_1.bdk_wallet().client(),
+        party_1.blockchain().unwrap(),
         validated_offer.bet.psbt.clone().extract_tx(),
     )
     .unwrap();
@@ -226,28 +145,14 @@ pub fn test_happy_path() {
     wait_for_state!(party_1, p1_bet_id, "confirmed");
     wait_for_state!(party_2, p2_bet_id, "confirmed");
 
-    let (outcome, index, winner, winner_id, loser, loser_id) = match rand::random() {
-        false => ("red", 0, &party_1, p1_bet_id, party_2, p2_bet_id),
-        true => ("blue", 1, &party_2, p2_bet_id, party_1, p1_bet_id),
+    let (index, winner, winner_id, loser, loser_id) = match rand::random() {
+        false => (0, &party_1, p1_bet_id, party_2, p2_bet_id),
+        true => (1, &party_2, p2_bet_id, party_1, p1_bet_id),
     };
 
     let winner_initial_balance = winner.bdk_wallet().get_balance().unwrap();
 
-    let attestation = Attestation {
-        outcome: outcome.into(),
-        schemes: AttestationSchemes {
-            olivia_v1: Some(attest::OliviaV1 {
-                scalars: vec![Secp256k1::reveal_attest_scalar(
-                    &attest_keypair,
-                    oracle_nonce_keypair.into(),
-                    index,
-                )
-                .into()],
-            }),
-            ..Default::default()
-        },
-        time: olivia_core::chrono::Utc::now().naive_utc(),
-    };
+    let attestation = oracle.attest(index);
 
     winner
         .learn_outcome(winner_id, attestation.clone())
@@ -268,16 +173,237 @@ pub fn test_happy_path() {
 
     let winner_claim_tx = winner_claim_psbt.extract_tx();
 
-    winner.bdk_wallet().broadcast(&winner_claim_tx).unwrap();
+    Broadcast::broadcast(winner.blockchain().unwrap(), winner_claim_tx).unwrap();
     wait_for_state!(winner, winner_id, "claiming");
     test_client.generate(1, None);
     wait_for_state!(winner, winner_id, "claimed");
-    winner.bdk_wallet().sync(noop_progress(), None).unwrap();
+    winner.sync().unwrap();
 
     assert!(winner.bdk_wallet().get_balance().unwrap() > winner_initial_balance);
     wait_for_state!(winner, winner_id, "claimed");
 }
 
+/// Make a confirmed 0.01 BTC vs 0.02 BTC bet with a refund height between the parties. party_1
+/// chooses left.
+fn make_refundable_bet(
+    test_client: &mut TestClient,
+    (party_1, keychain_1): (&GunWallet, &Keychain),
+    (party_2, keychain_2): (&GunWallet, &Keychain),
+    oracle: &FakeOracle,
+) -> (BetId, BetId) {
+    let refund_after = party_1.blockchain().unwrap().get_height().unwrap() + 100;
+    let local_proposal = party_1
+        .make_proposal(
+            oracle.id.clone(),
+            oracle.event.clone(),
+            BetArgs {
+                value: ValueChoice::Amount(Amount::from_str_with_denomination("0.01 BTC").unwrap()),
+                refund_after: Some(refund_after),
+                ..Default::default()
+            },
+            &keychain_1,
+            None,
+        )
+        .unwrap();
+
+    let proposal_string = local_proposal.proposal.clone().into_versioned().to_string();
+    let p1_bet_id = party_1
+        .gun_db()
+        .insert_bet(BetState::Proposed { local_proposal })
+        .unwrap();
+
+    let (p2_bet_id, encrypted_offer, _) = {
+        let proposal = VersionedProposal::from_str(&proposal_string).unwrap();
+        let (bet, local_public_key, mut cipher) = party_2
+            .generate_offer_with_oracle_event(OfferArgs {
+                proposal: proposal.into(),
+                oracle_event: oracle.event.clone(),
+                oracle_info: oracle.info.clone(),
+                choose_right: true,
+                args: BetArgs {
+                    value: ValueChoice::Amount(
+                        Amount::from_str_with_denomination("0.02 BTC").unwrap(),
+                    ),
+                    ..Default::default()
+                },
+                fee_spec: FeeSpec::default(),
+                keychain: &keychain_2,
+            })
+            .unwrap();
+        party_2
+            .sign_save_and_encrypt_offer(bet, None, local_public_key, &mut cipher)
+            .unwrap()
+    };
+    wait_for_state!(party_2, p2_bet_id, "offered");
+
+    let (decrypted_offer, offer_public_key, rng) = party_1
+        .decrypt_offer(p1_bet_id, encrypted_offer, &keychain_1)
+        .unwrap();
+    let mut validated_offer = party_1
+        .validate_offer(
+            p1_bet_id,
+            decrypted_offer.into_offer(),
+            offer_public_key,
+            rng,
+            &keychain_1,
+        )
+        .unwrap();
+    party_1.sign_validated_offer(&mut validated_offer).unwrap();
+
+    Broadcast::broadcast(
+        party_1.blockchain().unwrap(),
+        validated_offer.bet.psbt.clone().extract_tx(),
+    )
+    .unwrap();
+    party_1.set_offer_taken(validated_offer).unwrap();
+    test_client.generate(1, None);
+    wait_for_state!(party_1, p1_bet_id, "confirmed");
+    wait_for_state!(party_2, p2_bet_id, "confirmed");
+
+    (p1_bet_id, p2_bet_id)
+}
+
+#[test]
+pub fn claim_before_loser_learns_outcome() {
+    let (mut test_client, (party_1, keychain_1), (party_2, keychain_2), _, _, _, oracle) =
+        setup_test!();
+    let (p1_bet_id, p2_bet_id) = make_refundable_bet(
+        &mut test_client,
+        (&party_1, &keychain_1),
+        (&party_2, &keychain_2),
+        &oracle,
+    );
+
+    // party_1 chose left so they win and claim before party_2 hears from the oracle
+    let attestation = oracle.attest(0);
+    party_1
+        .learn_outcome(p1_bet_id, attestation.clone())
+        .unwrap();
+    let (_, claim_psbt) = party_1
+        .claim(FeeSpec::default(), false)
+        .unwrap()
+        .expect("winner should return a tx here");
+    Broadcast::broadcast(party_1.blockchain().unwrap(), claim_psbt.extract_tx()).unwrap();
+    test_client.generate(1, None);
+    wait_for_state!(party_1, p1_bet_id, "claimed");
+
+    party_2.take_next_action(p2_bet_id, false).unwrap();
+    wait_for_state!(party_2, p2_bet_id, "confirmed");
+
+    party_2.learn_outcome(p2_bet_id, attestation).unwrap();
+    wait_for_state!(party_2, p2_bet_id, "lost");
+}
+
+#[test]
+pub fn mutual_cancel() {
+    let (mut test_client, (party_1, keychain_1), (party_2, keychain_2), _, _, _, oracle) =
+        setup_test!();
+    let (p1_bet_id, p2_bet_id) = make_refundable_bet(
+        &mut test_client,
+        (&party_1, &keychain_1),
+        (&party_2, &keychain_2),
+        &oracle,
+    );
+
+    let their_script = party_2
+        .bdk_wallet()
+        .get_address(AddressIndex::New)
+        .unwrap()
+        .script_pubkey();
+    let (request, _) = party_1
+        .generate_cancel_request(
+            p1_bet_id,
+            their_script,
+            FeeSpec::default(),
+            Some("let's call it off".into()),
+        )
+        .unwrap();
+    let (mut psbt, message) = party_2.decrypt_cancel_request(p2_bet_id, &request).unwrap();
+    assert_eq!(message.as_deref(), Some("let's call it off"));
+    assert!(
+        party_2
+            .sign_cancel_request(p2_bet_id, &mut psbt, &FeeSpec::default())
+            .unwrap(),
+        "a cancel doesn't have to wait for the refund height"
+    );
+
+    Broadcast::broadcast(party_2.blockchain().unwrap(), psbt.extract_tx()).unwrap();
+    wait_for_state!(party_2, p2_bet_id, "refunding");
+    wait_for_state!(party_1, p1_bet_id, "refunding");
+    test_client.generate(1, None);
+    wait_for_state!(party_1, p1_bet_id, "refunded");
+    wait_for_state!(party_2, p2_bet_id, "refunded");
+}
+
+#[test]
+pub fn no_cancel_after_attestation() {
+    let (mut test_client, (party_1, keychain_1), (party_2, keychain_2), _, _, _, oracle) =
+        setup_test!();
+    let (p1_bet_id, p2_bet_id) = make_refundable_bet(
+        &mut test_client,
+        (&party_1, &keychain_1),
+        (&party_2, &keychain_2),
+        &oracle,
+    );
+
+    let their_script = party_2
+        .bdk_wallet()
+        .get_address(AddressIndex::New)
+        .unwrap()
+        .script_pubkey();
+    let (request, _) = party_1
+        .generate_cancel_request(p1_bet_id, their_script, FeeSpec::default(), None)
+        .unwrap();
+    let (mut psbt, _) = party_2.decrypt_cancel_request(p2_bet_id, &request).unwrap();
+    let stingy = FeeSpec {
+        max: Some(Amount::from_sat(1)),
+        ..Default::default()
+    };
+    assert!(
+        party_2
+            .sign_cancel_request(p2_bet_id, &mut psbt, &stingy)
+            .is_err(),
+        "the fee is over the maximum"
+    );
+
+    // party_2 chose right so they've won and shouldn't give back party_1's stake
+    oracle.publish(&party_2, 1).unwrap();
+    assert!(party_2
+        .sign_cancel_request(p2_bet_id, &mut psbt, &FeeSpec::default())
+        .is_err());
+    wait_for_state!(party_2, p2_bet_id, "won");
+}
+
+#[test]
+pub fn sweep_refund_alone() {
+    let (mut test_client, (party_1, keychain_1), (party_2, keychain_2), _, _, _, oracle) =
+        setup_test!();
+    let (p1_bet_id, p2_bet_id) = make_refundable_bet(
+        &mut test_client,
+        (&party_1, &keychain_1),
+        (&party_2, &keychain_2),
+        &oracle,
+    );
+
+    assert!(
+        party_1
+            .generate_sweep(p1_bet_id, FeeSpec::default())
+            .is_err(),
+        "can't sweep before the sweep height"
+    );
+
+    // party_2 has disappeared so party_1 waits until past the sweep height and takes it all
+    test_client.generate(100 + REFUND_SWEEP_DELAY as u64, None);
+    let psbt = party_1
+        .generate_sweep(p1_bet_id, FeeSpec::default())
+        .unwrap();
+    Broadcast::broadcast(party_1.blockchain().unwrap(), psbt.extract_tx()).unwrap();
+    wait_for_state!(party_1, p1_bet_id, "refunding");
+    test_client.generate(1, None);
+    wait_for_state!(party_1, p1_bet_id, "refunded");
+    wait_for_state!(party_2, p2_bet_id, "refunded");
+}
+
 #[test]
 pub fn cancel_proposal() {
     let (
@@ -285,10 +411,9 @@ pub fn cancel_proposal() {
         (party_1, keychain_1),
         (party_2, keychain_2),
         oracle_info,
-        _,
-        _,
         oracle_id,
         oracle_event,
+        _,
     ) = setup_test!();
 
     let local_proposal_1 = party_1
@@ -300,6 +425,7 @@ pub fn cancel_proposal() {
                 ..Default::default()
             },
             &keychain_1,
+            None,
         )
         .unwrap();
 
@@ -326,6 +452,7 @@ pub fn cancel_proposal() {
                 ..Default::default()
             },
             &keychain_2,
+            None,
         )
         .unwrap();
 
@@ -364,12 +491,12 @@ pub fn cancel_proposal() {
         .unwrap()
         .expect("should be able to cancel");
     let tx = psbt.extract_tx();
-    Broadcast::broadcast(party_1.bdk_wallet().client(), tx).unwrap();
+    Broadcast::broadcast(party_1.blockchain().unwrap(), tx).unwrap();
     wait_for_state!(party_1, p1_bet_id, "canceling");
     test_client.generate(1, None);
     wait_for_state!(party_1, bet_id_overlap, "canceled");
     //     wait_for_state!(party_1, p1_bet_id, "canceled");
-    wait_for_state!(party_2, p2_bet_id, "canceled");
+    wait_for_state!(party_2, p2_bet_id, "counterparty-canceled");
 }
 
 #[test]
@@ -379,10 +506,9 @@ pub fn test_cancel_offer() {
         (party_1, keychain_1),
         (party_2, keychain_2),
         oracle_info,
-        _,
-        _,
         oracle_id,
         oracle_event,
+        _,
     ) = setup_test!();
 
     let local_proposal = party_1
@@ -394,6 +520,7 @@ pub fn test_cancel_offer() {
                 ..Default::default()
             },
             &keychain_1,
+            None,
         )
         .unwrap();
 
@@ -427,7 +554,7 @@ pub fn test_cancel_offer() {
         .unwrap()
         .expect("should be able to cancel");
     let tx = psbt.extract_tx();
-    Broadcast::broadcast(party_2.bdk_wallet().client(), tx).unwrap();
+    Broadcast::broadcast(party_2.blockchain().unwrap(), tx).unwrap();
 
     wait_for_state!(party_2, p2_bet_id, "canceling");
     test_client.generate(1, None);
@@ -441,10 +568,9 @@ pub fn cancel_offer_after_offer_taken() {
         (party_1, keychain_1),
         (party_2, keychain_2),
         oracle_info,
-        _,
-        _,
         oracle_id,
         oracle_event,
+        _,
     ) = setup_test!();
 
     let local_proposal = party_1
@@ -456,6 +582,7 @@ pub fn cancel_offer_after_offer_taken() {
                 ..Default::default()
             },
             &keychain_1,
+            None,
         )
         .unwrap();
 
@@ -527,7 +654,7 @@ pub fn cancel_offer_after_offer_taken() {
         .sign_validated_offer(&mut second_validated_offer)
         .unwrap();
 
-    Broadcast::broadcast(party_1.bdk_wallet().client(), second_validated_offer.tx()).unwrap();
+    Broadcast::broadcast(party_1.blockchain().unwrap(), second_validated_offer.tx()).unwrap();
     party_1.set_offer_taken(second_validated_offer).unwrap();
 
     wait_for_state!(party_1, p1_bet_id, "unconfirmed");
@@ -539,7 +666,7 @@ pub fn cancel_offer_after_offer_taken() {
         .unwrap()
         .expect("should be able to cancel");
     let tx = psbt.extract_tx();
-    Broadcast::broadcast(party_2.bdk_wallet().client(), tx).unwrap();
+    Broadcast::broadcast(party_2.blockchain().unwrap(), tx).unwrap();
 
     wait_for_state!(party_2, second_p2_bet_id, "canceling");
     wait_for_state!(party_2, first_p2_bet_id, "canceling");
@@ -555,10 +682,9 @@ fn create_proposal_with_dust_change() {
         (party_1, keychain_1),
         (party_2, keychain_2),
         oracle_info,
-        _,
-        _,
         oracle_id,
         oracle_event,
+        _,
     ) = setup_test!();
 
     let balance = party_1.bdk_wallet().get_balance().unwrap();
@@ -573,6 +699,7 @@ fn create_proposal_with_dust_change() {
                 ..Default::default()
             },
             &keychain_1,
+            None,
         )
         .unwrap();
 
@@ -657,7 +784,7 @@ fn create_proposal_with_dust_change() {
     party_1.sign_validated_offer(&mut validated_offer).unwrap();
 
     Broadcast::broadcast(
-        party_1.bdk_wallet().client(),
+        party_1.blockchain().unwrap(),
         validated_offer.bet.psbt.clone().extract_tx(),
     )
     .unwrap();
@@ -667,3 +794,37 @@ fn create_proposal_with_dust_change() {
     wait_for_state!(party_1, p1_bet_id, "confirmed");
     wait_for_state!(party_2, p2_bet_id, "confirmed");
 }
+
+#[test]
+pub fn seeded_locktime_is_recorded() {
+    let (_test_client, (party_1, _), (party_2, _), _, _, _, _) = setup_test!();
+    let party_1 = party_1.with_tx_seed(Some(42));
+    let their_script = party_2
+        .bdk_wallet()
+        .get_address(AddressIndex::New)
+        .unwrap()
+        .script_pubkey();
+    let build = || {
+        let mut builder = party_1.bdk_wallet().build_tx();
+        builder.add_recipient(their_script.clone(), 10_000);
+        let tx_locktime = party_1.apply_locktime_policy(&mut builder).unwrap();
+        let (psbt, _) = builder.finish().unwrap();
+        (psbt, tx_locktime)
+    };
+
+    let (psbt, tx_locktime) = build();
+    assert_eq!(psbt.unsigned_tx.lock_time, tx_locktime.locktime);
+    assert!(tx_locktime.locktime <= tx_locktime.height);
+    party_1.record_locktime(&psbt, tx_locktime.clone()).unwrap();
+    assert_eq!(
+        party_1
+            .gun_db()
+            .get_entity::<TxLocktime>(psbt.unsigned_tx.txid())
+            .unwrap(),
+        Some(tx_locktime.clone())
+    );
+
+    // the same seed at the same height picks the same locktime
+    let (_, again) = build();
+    assert_eq!(again, tx_locktime);
+}